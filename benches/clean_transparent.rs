@@ -0,0 +1,51 @@
+//! Compares the size of a sprite with junk color in its transparent pixels
+//! when encoded as it is and with each [`TransparentCleanup`].
+//!
+//! Run with `cargo bench --bench clean_transparent`.
+
+use sqp::{options::{EncodeOptions, TransparentCleanup}, CompressionType, SquishyPicture};
+
+fn main() {
+    let logo = sqp::open("test_images/test-lossless.sqp").unwrap();
+    let (width, height) = (logo.width(), logo.height());
+    let transparent = logo.as_raw().chunks_exact(4).filter(|p| p[3] == 0).count();
+    println!(
+        "{width}x{height}, {:.0}% fully transparent",
+        transparent as f64 * 100.0 / (width * height) as f64,
+    );
+
+    // Junk color left under the transparent pixels, as editors often do
+    let mut state = 0x2545F491u32;
+    let mut dirty = logo.as_raw().clone();
+    for pixel in dirty.chunks_exact_mut(4).filter(|p| p[3] == 0) {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        pixel[..3].copy_from_slice(&state.to_le_bytes()[..3]);
+    }
+
+    println!("| Compression | Dirty | Constant(0) | Bleed |");
+    println!("|-------------|-------|-------------|-------|");
+    for (name, compression_type, quality) in [
+        ("LZW", CompressionType::Lossless, None),
+        ("LZ77", CompressionType::LosslessV2, None),
+        ("Lossy 80", CompressionType::LossyDct, Some(80)),
+    ] {
+        let mut sqp = SquishyPicture::from_raw_lossless(width, height, logo.color_format(), dirty.clone());
+        sqp.set_compression(compression_type, quality);
+
+        let mut sizes = Vec::new();
+        for cleanup in [None, Some(TransparentCleanup::Constant(0)), Some(TransparentCleanup::Bleed)] {
+            let options = match cleanup {
+                Some(cleanup) => EncodeOptions::new().clean_transparent(cleanup),
+                None => EncodeOptions::new(),
+            };
+
+            let mut encoded = Vec::new();
+            sqp.encode_with_options(&mut encoded, &options).unwrap();
+            sizes.push(format!("{} B", encoded.len()));
+        }
+
+        println!("| {name} | {} |", sizes.join(" | "));
+    }
+}
@@ -0,0 +1,91 @@
+//! Measures decoding a lossy 1080p RGBA image at reduced sizes and as a
+//! preview, compared to decoding it in full and then shrinking it.
+//!
+//! Run with `cargo bench --bench decode_scaled`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, CompressionType, SquishyPicture};
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+fn main() {
+    // A gradient with some noise, so the blocks aren't trivial
+    let mut state = 0x2545F491u32;
+    let mut sqp = SquishyPicture::from_fn(WIDTH, HEIGHT, ColorFormat::Rgba8, |x, y, pixel| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        let noise = state & 0xF;
+        pixel.copy_from_slice(&[
+            ((x + noise) / 8) as u8,
+            ((y + noise) / 5) as u8,
+            ((x + y) / 12) as u8,
+            255,
+        ]);
+    });
+    sqp.set_compression(CompressionType::LossyDct, Some(80));
+    let encoded = sqp.encode_to_vec().unwrap();
+
+    let start = Instant::now();
+    let full = SquishyPicture::decode(encoded.as_slice()).unwrap();
+    let full_time = start.elapsed();
+    println!("decode {WIDTH}x{HEIGHT}: {full_time:>9.2?}");
+
+    for denominator in [2, 4, 8] {
+        let start = Instant::now();
+        let resized = downscale(&full, denominator as usize);
+        let resized_time = full_time + start.elapsed();
+
+        let start = Instant::now();
+        let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), denominator).unwrap();
+        let scaled_time = start.elapsed();
+
+        println!(
+            "1/{denominator} ({}x{}): decode and resize {resized_time:>9.2?}, decode_scaled {scaled_time:>9.2?} ({:.1}x faster), PSNR {:.2} dB",
+            scaled.width(),
+            scaled.height(),
+            resized_time.as_secs_f64() / scaled_time.as_secs_f64(),
+            psnr(&resized, scaled.as_raw()),
+        );
+    }
+
+    let resized = downscale(&full, 8);
+    let start = Instant::now();
+    let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+    let preview_time = start.elapsed();
+    println!(
+        "preview ({}x{}): decode_preview {preview_time:>9.2?}, PSNR {:.2} dB",
+        preview.width(),
+        preview.height(),
+        psnr(&resized, preview.as_raw()),
+    );
+}
+
+/// Shrink an RGBA picture by averaging each square of pixels, the same way
+/// `decode_scaled` does for lossless images.
+fn downscale(sqp: &SquishyPicture, denominator: usize) -> Vec<u8> {
+    let (width, height) = (sqp.width() as usize, sqp.height() as usize);
+    let (new_width, new_height) = (width.div_ceil(denominator), height.div_ceil(denominator));
+
+    let mut sums = vec![(0u32, 0u32); new_width * new_height * 4];
+    for (i, value) in sqp.as_raw().iter().enumerate() {
+        let (pixel, channel) = (i / 4, i % 4);
+        let (x, y) = ((pixel % width) / denominator, (pixel / width) / denominator);
+
+        let sum = &mut sums[(y * new_width + x) * 4 + channel];
+        *sum = (sum.0 + *value as u32, sum.1 + 1);
+    }
+
+    sums.iter().map(|(sum, count)| ((sum + count / 2) / count) as u8).collect()
+}
+
+/// Peak signal to noise ratio between two images in decibels.
+fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    let squared_error: f64 = a.iter().zip(b).map(|(a, b)| (*a as f64 - *b as f64).powi(2)).sum();
+    let mse = squared_error / a.len() as f64;
+
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
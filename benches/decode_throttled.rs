@@ -0,0 +1,72 @@
+//! Compares decoding from a slow reader with reading everything first.
+//!
+//! `decode` starts decompressing each chunk as soon as it has been read, so
+//! its time should approach the larger of the read time and the decompress
+//! time, rather than their sum.
+//!
+//! Run with `cargo bench --bench decode_throttled`.
+
+use std::{
+    io::Read,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use sqp::{ColorFormat, SquishyPicture};
+
+/// A reader which delivers data at a fixed rate in small pieces, like a
+/// slow disk or network connection.
+struct Throttled<'a> {
+    data: &'a [u8],
+    bytes_per_second: usize,
+}
+
+impl Read for Throttled<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(16 * 1024);
+        sleep(Duration::from_secs_f64(len as f64 / self.bytes_per_second as f64));
+
+        self.data.read(&mut buf[..len])
+    }
+}
+
+fn main() {
+    const SIZE: u32 = 1024;
+
+    let mut state = 0x2545F491u32;
+    let noise: Vec<u8> = (0..SIZE * SIZE * 4)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8 & 0xF0
+        })
+        .collect();
+
+    let encoded = SquishyPicture::from_raw_lossless(SIZE, SIZE, ColorFormat::Rgba8, noise)
+        .encode_to_vec()
+        .unwrap();
+
+    for bytes_per_second in [4 << 20, 16 << 20] {
+        let throttled = || Throttled { data: &encoded, bytes_per_second };
+
+        let start = Instant::now();
+        let mut buffered = Vec::new();
+        throttled().read_to_end(&mut buffered).unwrap();
+        let read_time = start.elapsed();
+        SquishyPicture::decode_slice(&buffered).unwrap();
+        let read_first = start.elapsed();
+
+        let start = Instant::now();
+        SquishyPicture::decode(throttled()).unwrap();
+        let pipelined = start.elapsed();
+
+        println!(
+            "{:.1} MiB at {} MiB/s (reading alone takes {read_time:.2?})",
+            encoded.len() as f64 / (1024.0 * 1024.0),
+            bytes_per_second >> 20,
+        );
+        println!("  read, then decode: {read_first:>10.2?}");
+        println!("  decode:            {pipelined:>10.2?}");
+    }
+}
@@ -0,0 +1,45 @@
+//! Measures encode throughput on the test images and on generated content.
+//!
+//! Run with `cargo bench --bench encode`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, CompressionType, SquishyPicture};
+
+fn main() {
+    let photo = sqp::open("test_images/test-lossless.sqp").unwrap();
+
+    let mut state = 0x2545F491u32;
+    let noise: Vec<u8> = (0..1024 * 1024 * 4)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let flat = vec![0x80; 1024 * 1024 * 4];
+
+    let images = [
+        ("photo", photo.width(), photo.height(), photo.as_raw().clone()),
+        ("noise", 1024, 1024, noise),
+        ("flat", 1024, 1024, flat),
+    ];
+
+    for (name, width, height, bitmap) in images {
+        for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+            let sqp = SquishyPicture::from_raw(width, height, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+
+            let start = Instant::now();
+            let encoded = sqp.encode_to_vec().unwrap();
+            let time = start.elapsed();
+
+            let throughput = bitmap.len() as f64 / (1024.0 * 1024.0) / time.as_secs_f64();
+            println!(
+                "{name:<6} {compression_type:<8?} {width}x{height}: {time:>9.2?}, {throughput:>6.2} MiB/s, {} bytes",
+                encoded.len(),
+            );
+        }
+    }
+}
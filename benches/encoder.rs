@@ -0,0 +1,66 @@
+//! Compares encoding many small images one at a time against reusing an
+//! `SqpEncoder`.
+//!
+//! Run with `cargo bench --bench encoder`.
+
+use std::time::Instant;
+
+use sqp::{encoder::SqpEncoder, options::EncodeOptions, ColorFormat, CompressionType, SquishyPicture};
+
+const COUNT: usize = 1000;
+const SIZE: u32 = 256;
+
+fn main() {
+    let mut state = 0x2545F491u32;
+    let images: Vec<Vec<u8>> = (0..COUNT)
+        .map(|i| {
+            // A gradient with some noise, different for every image
+            let mut bitmap = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+
+                    let noise = state & 0x7;
+                    bitmap.extend_from_slice(&[
+                        (x + noise + i as u32) as u8,
+                        (y + noise) as u8,
+                        ((x + y) / 2) as u8,
+                        255,
+                    ]);
+                }
+            }
+            bitmap
+        })
+        .collect();
+
+    for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+        let pictures: Vec<_> = images.iter()
+            .map(|bitmap| SquishyPicture::from_raw(SIZE, SIZE, ColorFormat::Rgba8, compression_type, quality, bitmap.clone()))
+            .collect();
+
+        let mut output = Vec::new();
+        let start = Instant::now();
+        for sqp in &pictures {
+            output.clear();
+            sqp.encode(&mut output).unwrap();
+        }
+        report("encode", compression_type, start.elapsed());
+
+        let mut encoder = SqpEncoder::new(EncodeOptions::new());
+        let start = Instant::now();
+        for sqp in &pictures {
+            output.clear();
+            encoder.encode(sqp, &mut output).unwrap();
+        }
+        report("SqpEncoder", compression_type, start.elapsed());
+    }
+}
+
+fn report(name: &str, compression_type: CompressionType, time: std::time::Duration) {
+    let images_per_sec = COUNT as f64 / time.as_secs_f64();
+    println!(
+        "{name:<10} {compression_type:<8?} {COUNT}x {SIZE}x{SIZE}: {time:>9.2?}, {images_per_sec:>7.1} images/s"
+    );
+}
@@ -0,0 +1,51 @@
+//! Compares the lossless backends: LZW used by `Lossless`, LZ77 used by
+//! `LosslessV2`, the Burrows–Wheeler transform used by `LosslessBwt` and
+//! the predictive coder used by `Predictive`, on the test images and on
+//! generated content.
+//!
+//! Run with `cargo bench --bench lossless`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, CompressionType, SquishyPicture};
+
+fn main() {
+    let photo = sqp::open("test_images/test-lossless.sqp").unwrap();
+    let smooth = sqp::open("test_images/test-lossy.sqp").unwrap();
+
+    let mut state = 0x2545F491u32;
+    let noise: Vec<u8> = (0..1024 * 1024 * 4)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let images = [
+        ("photo", photo.width(), photo.height(), photo.as_raw().clone()),
+        ("smooth", smooth.width(), smooth.height(), smooth.as_raw().clone()),
+        ("noise", 1024, 1024, noise),
+    ];
+
+    for (name, width, height, bitmap) in images {
+        for compression_type in [CompressionType::Lossless, CompressionType::LosslessV2, CompressionType::LosslessBwt, CompressionType::Predictive] {
+            let sqp = SquishyPicture::from_raw(width, height, ColorFormat::Rgba8, compression_type, None, bitmap.clone());
+
+            let start = Instant::now();
+            let encoded = sqp.encode_to_vec().unwrap();
+            let encode_time = start.elapsed();
+
+            let start = Instant::now();
+            let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let decode_time = start.elapsed();
+            assert_eq!(decoded.as_raw(), &bitmap);
+
+            println!(
+                "{name:<6} {compression_type:<11?} {width}x{height}: {} bytes, encode {encode_time:>9.2?}, decode {decode_time:>9.2?}",
+                encoded.len(),
+            );
+        }
+    }
+}
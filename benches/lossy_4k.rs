@@ -0,0 +1,42 @@
+//! Measures lossy encoding and decoding of a 4K RGBA image.
+//!
+//! Run with `cargo bench --bench lossy_4k`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, CompressionType, SquishyPicture};
+
+const WIDTH: u32 = 3840;
+const HEIGHT: u32 = 2160;
+
+fn main() {
+    // A gradient with some noise, so the blocks aren't trivial
+    let mut state = 0x2545F491u32;
+    let start = Instant::now();
+    let mut sqp = SquishyPicture::from_fn(WIDTH, HEIGHT, ColorFormat::Rgba8, |x, y, pixel| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        let noise = state & 0xF;
+        pixel.copy_from_slice(&[
+            ((x + noise) / 16) as u8,
+            ((y + noise) / 9) as u8,
+            ((x + y) / 24) as u8,
+            255,
+        ]);
+    });
+    sqp.set_compression(CompressionType::LossyDct, Some(80));
+    let time = start.elapsed();
+    println!("generate {WIDTH}x{HEIGHT}: {time:>9.2?}");
+
+    let start = Instant::now();
+    let encoded = sqp.encode_to_vec().unwrap();
+    let time = start.elapsed();
+    println!("encode {WIDTH}x{HEIGHT}: {time:>9.2?}, {} bytes", encoded.len());
+
+    let start = Instant::now();
+    let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+    let time = start.elapsed();
+    println!("decode {WIDTH}x{HEIGHT}: {time:>9.2?}, {} bytes", decoded.as_raw().len());
+}
@@ -0,0 +1,49 @@
+//! Measures lossy encoding and decoding of a large Gray8 scan with rayon
+//! pools of different sizes.
+//!
+//! Run with `cargo bench --bench lossy_gray`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, SquishyPicture};
+
+/// An A4 page scanned at 300 DPI.
+const WIDTH: u32 = 2480;
+const HEIGHT: u32 = 3508;
+
+fn main() {
+    // Paper with lines of noisy text, so the blocks aren't trivial
+    let mut state = 0x2545F491u32;
+    let sqp = SquishyPicture::from_fn(WIDTH, HEIGHT, ColorFormat::Gray8, |x, y, pixel| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        let ink = y % 96 < 48 && (x / 24 + y / 96) % 5 != 0 && state.is_multiple_of(3);
+        pixel[0] = if ink { 30 } else { 235 } + (state >> 28) as u8;
+    });
+
+    let mut threads = vec![1, 2, 4];
+    let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if !threads.contains(&available) {
+        threads.push(available);
+    }
+
+    for threads in threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+        let lossy = SquishyPicture::from_raw_lossy(WIDTH, HEIGHT, ColorFormat::Gray8, 80, sqp.as_raw().clone());
+        pool.install(|| {
+
+            let start = Instant::now();
+            let encoded = lossy.encode_to_vec().unwrap();
+            let time = start.elapsed();
+            println!("{threads} threads, encode {WIDTH}x{HEIGHT}: {time:>9.2?}, {} bytes", encoded.len());
+
+            let start = Instant::now();
+            let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let time = start.elapsed();
+            println!("{threads} threads, decode {WIDTH}x{HEIGHT}: {time:>9.2?}, {} bytes", decoded.as_raw().len());
+        });
+    }
+}
@@ -0,0 +1,47 @@
+//! Measures lossy encoding at low and high qualities, with and without
+//! range coding, on the test images.
+//!
+//! Run with `cargo bench --bench lossy_quality`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, SquishyPicture};
+
+/// How many times each image is encoded, taking the fastest.
+const RUNS: usize = 5;
+
+fn main() {
+    let photo = sqp::open("test_images/test-lossless.sqp").unwrap();
+    let smooth = sqp::open("test_images/test-lossy.sqp").unwrap();
+
+    for (name, image) in [("photo", &photo), ("smooth", &smooth)] {
+        for quality in [10, 25, 50, 80] {
+            for range_coded in [false, true] {
+                let mut sqp = SquishyPicture::from_raw_lossy(
+                    image.width(),
+                    image.height(),
+                    ColorFormat::Rgba8,
+                    quality,
+                    image.as_raw().clone(),
+                );
+                sqp.set_range_coding(range_coded).unwrap();
+
+                let mut best = None;
+                let mut encoded = Vec::new();
+                for _ in 0..RUNS {
+                    let start = Instant::now();
+                    encoded = sqp.encode_to_vec().unwrap();
+                    let time = start.elapsed();
+                    best = Some(best.map_or(time, |b: std::time::Duration| b.min(time)));
+                }
+
+                let coding = if range_coded { "range" } else { "varint" };
+                println!(
+                    "{name:>6} q{quality:<2} {coding:>6}: encode {:>9.2?}, {} bytes",
+                    best.unwrap(),
+                    encoded.len(),
+                );
+            }
+        }
+    }
+}
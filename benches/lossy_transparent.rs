@@ -0,0 +1,46 @@
+//! Measures lossy encoding of a sprite sheet which is mostly fully
+//! transparent, against the same sheet with its background at an alpha of
+//! 1, whose blocks are all transformed.
+//!
+//! Run with `cargo bench --bench lossy_transparent`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, SquishyPicture};
+
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 2048;
+
+/// Sprites in a grid of 128x128 cells, leaving about 60% transparent.
+const CELL: u32 = 128;
+const SPRITE: u32 = 80;
+
+fn sheet(background_alpha: u8) -> SquishyPicture {
+    let mut state = 0x2545F491u32;
+    SquishyPicture::from_fn(WIDTH, HEIGHT, ColorFormat::Rgba8, |x, y, pixel| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        let (cx, cy) = (x % CELL, y % CELL);
+        if cx < SPRITE && cy < SPRITE {
+            pixel.copy_from_slice(&[(cx * 3) as u8, (cy * 3) as u8, (x / CELL * 16) as u8, 255]);
+        } else {
+            // Leftover color in the background, as editors often leave
+            let [r, g, b, _] = state.to_le_bytes();
+            pixel.copy_from_slice(&[r, g, b, background_alpha]);
+        }
+    })
+}
+
+fn main() {
+    for (name, background_alpha) in [("alpha 1", 1), ("alpha 0", 0)] {
+        let sheet = sheet(background_alpha);
+        let lossy = SquishyPicture::from_raw_lossy(WIDTH, HEIGHT, ColorFormat::Rgba8, 80, sheet.as_raw().clone());
+
+        let start = Instant::now();
+        let encoded = lossy.encode_to_vec().unwrap();
+        let time = start.elapsed();
+        println!("{name}, encode {WIDTH}x{HEIGHT}: {time:>9.2?}, {} bytes", encoded.len());
+    }
+}
@@ -0,0 +1,108 @@
+//! Measures the peak heap usage, number of allocations, and time taken to
+//! encode and decode a large image, including [`SquishyPicture::encode`]
+//! against [`SquishyPicture::encode_streaming`] and the low memory options.
+//!
+//! Run with `cargo bench --bench memory`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::sink,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use sqp::{
+    options::{DecodeOptions, EncodeOptions},
+    ColorFormat, CompressionType, SquishyPicture,
+};
+
+/// Wraps the system allocator to track the current and peak heap size, and
+/// the number of allocations.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(current, Ordering::Relaxed);
+
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Run `f`, printing the peak heap growth and number of allocations over
+/// the course of it, and how long it took.
+fn measure<F: FnOnce()>(name: &str, f: F) {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+
+    let start = Instant::now();
+    f();
+    let time: Duration = start.elapsed();
+
+    let peak = (PEAK.load(Ordering::Relaxed) - base) as f64 / (1024.0 * 1024.0);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    println!("  {name:<19} {peak:>7.1} MiB peak, {allocations:>9} allocations, {time:>9.2?}");
+}
+
+fn main() {
+    const SIZE: u32 = 2048;
+
+    // A smooth gradient, and noise which barely compresses at all
+    let gradient: Vec<u8> = (0..SIZE * SIZE * 4)
+        .map(|i| ((i / 4 % SIZE + i / 4 / SIZE) / 16) as u8 ^ (i.wrapping_mul(2654435761) >> 29) as u8)
+        .collect();
+
+    let mut state = 0x2545F491u32;
+    let noise: Vec<u8> = (0..SIZE * SIZE * 4)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    for (name, bitmap, compression_type, quality) in [
+        ("gradient", &gradient, CompressionType::Lossless, None),
+        ("noise", &noise, CompressionType::Lossless, None),
+        ("gradient", &gradient, CompressionType::LossyDct, Some(80)),
+        ("noise", &noise, CompressionType::LossyDct, Some(80)),
+    ] {
+        let sqp = SquishyPicture::from_raw(SIZE, SIZE, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+
+        println!("{compression_type:?} {name} {SIZE}x{SIZE} ({:.1} MiB bitmap)", sqp.as_raw().len() as f64 / (1024.0 * 1024.0));
+
+        measure("encode:", || {
+            sqp.encode(sink()).unwrap();
+        });
+        measure("encode_streaming:", || {
+            sqp.encode_streaming(sink()).unwrap();
+        });
+        measure("encode low memory:", || {
+            sqp.encode_with_options(sink(), &EncodeOptions::new().low_memory(true)).unwrap();
+        });
+
+        let encoded = sqp.encode_to_vec().unwrap();
+        measure("decode:", || {
+            SquishyPicture::decode_slice(&encoded).unwrap();
+        });
+        measure("decode low memory:", || {
+            SquishyPicture::decode_with_options(encoded.as_slice(), &DecodeOptions::new().low_memory(true)).unwrap();
+        });
+    }
+}
@@ -0,0 +1,34 @@
+//! Compares opening an image with [`sqp::open`] against [`sqp::open_mmap`].
+//!
+//! Run with `cargo bench --features mmap`.
+
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    // Warm up the page cache and the thread pool
+    f();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    for path in ["test_images/test-lossless.sqp", "test_images/test-lossy.sqp"] {
+        let read = time(|| {
+            sqp::open(path).unwrap();
+        });
+        let mapped = time(|| {
+            sqp::open_mmap(path).unwrap();
+        });
+
+        println!("{path}");
+        println!("  open:      {read:>10.2?}");
+        println!("  open_mmap: {mapped:>10.2?}");
+    }
+}
@@ -0,0 +1,37 @@
+//! Compares the size of flat art encoded as RGBA against the same image
+//! reduced to a palette, and times the reduction.
+//!
+//! Run with `cargo bench --bench palette`.
+
+use std::time::Instant;
+
+use sqp::{palette::PaletteAlpha, CompressionType};
+
+fn main() {
+    let mut logo = sqp::open("test_images/test-lossless.sqp").unwrap();
+
+    println!("| Backend | Rgba8 | 256 colors | 64 colors | 16 colors |");
+    println!("|---------|-------|------------|-----------|-----------|");
+    for (name, compression_type) in [("LZW", CompressionType::Lossless), ("LZ77", CompressionType::LosslessV2)] {
+        logo.set_compression(compression_type, None);
+
+        let mut sizes = vec![logo.encode_to_vec().unwrap().len()];
+        for max_colors in [256, 64, 16] {
+            let reduced = logo.quantize_to_palette(max_colors).unwrap();
+            sizes.push(reduced.encode_to_vec().unwrap().len());
+        }
+
+        let sizes: Vec<_> = sizes.iter().map(|s| format!("{s} B")).collect();
+        println!("| {name} | {} |", sizes.join(" | "));
+    }
+
+    for alpha in [PaletteAlpha::Quantize, PaletteAlpha::Threshold(128)] {
+        let start = Instant::now();
+        let indexed = logo.quantize(256, alpha).unwrap();
+        println!(
+            "{alpha:?}: {} colors in {:.1} ms",
+            indexed.palette().len(),
+            start.elapsed().as_secs_f64() * 1000.0,
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Measures the throughput of each row filter and its reverse, against the
+//! byte at a time versions they replaced.
+//!
+//! The kernels aren't public, so their source is included directly.
+//!
+//! Run with `cargo bench --bench row_filter_throughput`.
+
+use std::{hint::black_box, time::Instant};
+
+#[allow(dead_code)]
+#[path = "../src/row_filter.rs"]
+mod row_filter;
+
+use row_filter::scalar;
+
+const WIDTH: usize = 4096;
+const HEIGHT: usize = 1024;
+const PASSES: usize = 4;
+
+type Filter = fn(&[u8], &[u8], usize, &mut [u8]);
+type Undo = fn(&mut [u8], &[u8], usize);
+
+fn main() {
+    let mut state = 0x2545F491u32;
+    let rows: Vec<u8> = (0..WIDTH * 4 * (HEIGHT + 1))
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let filters: [(&str, Filter, Filter); 4] = [
+        ("Sub", |r, _, b, o| row_filter::sub_left(r, b, o), |r, _, b, o| scalar::sub_left(r, b, o)),
+        ("Up", |r, p, _, o| row_filter::up(r, p, o), |r, p, _, o| scalar::up(r, p, o)),
+        ("Average", row_filter::average_left_up, scalar::average_left_up),
+        ("Paeth", row_filter::paeth, scalar::paeth),
+    ];
+    let undos: [(&str, Undo, Undo); 4] = [
+        ("Sub", |r, _, b| row_filter::undo_sub_left(r, b), |r, _, b| scalar::undo_sub_left(r, b)),
+        ("Up", |r, p, _| row_filter::undo_up(r, p), |r, p, _| scalar::undo_up(r, p)),
+        ("Average", row_filter::undo_average_left_up, scalar::undo_average_left_up),
+        ("Paeth", row_filter::undo_paeth, scalar::undo_paeth),
+    ];
+
+    for bpp in [1, 3, 4] {
+        let line_len = WIDTH * bpp;
+        let mut output = vec![0; line_len];
+
+        for (name, fast, reference) in filters {
+            let throughput = |filter: Filter, output: &mut [u8]| {
+                let start = Instant::now();
+                for _ in 0..PASSES {
+                    for y in 1..=HEIGHT {
+                        let row = &rows[y * line_len..][..line_len];
+                        filter(black_box(row), &rows[(y - 1) * line_len..][..line_len], bpp, output);
+                        black_box(&output);
+                    }
+                }
+                (line_len * HEIGHT * PASSES) as f64 / start.elapsed().as_secs_f64() / 1e9
+            };
+
+            let before = throughput(reference, &mut output);
+            let after = throughput(fast, &mut output);
+            println!("filter {name:<7} bpp {bpp}: {before:>5.2} GB/s before, {after:>5.2} GB/s after");
+        }
+
+        for (name, fast, reference) in undos {
+            let throughput = |undo: Undo| {
+                let mut image = rows[..line_len * (HEIGHT + 1)].to_vec();
+                let start = Instant::now();
+                for _ in 0..PASSES {
+                    for y in 1..=HEIGHT {
+                        let (done, rest) = image.split_at_mut(y * line_len);
+                        undo(black_box(&mut rest[..line_len]), &done[(y - 1) * line_len..], bpp);
+                    }
+                }
+                black_box(&image);
+                (line_len * HEIGHT * PASSES) as f64 / start.elapsed().as_secs_f64() / 1e9
+            };
+
+            let before = throughput(reference);
+            let after = throughput(fast);
+            println!("undo   {name:<7} bpp {bpp}: {before:>5.2} GB/s before, {after:>5.2} GB/s after");
+        }
+    }
+}
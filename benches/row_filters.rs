@@ -0,0 +1,69 @@
+//! Compares the sizes of lossless images with every row filtered by Up
+//! against choosing a filter for each row, on the test images and on
+//! generated content.
+//!
+//! Run with `cargo bench --bench row_filters`.
+
+use std::time::Instant;
+
+use sqp::{
+    options::{EncodeOptions, Filter, FilterStrategy},
+    ColorFormat, CompressionType, SquishyPicture,
+};
+
+fn main() {
+    let photo = sqp::open("test_images/test-lossless.sqp").unwrap();
+    let smooth = sqp::open("test_images/test-lossy.sqp").unwrap();
+
+    // Gradients with a little texture, which Up alone filters poorly
+    let gradient = SquishyPicture::from_fn(1024, 1024, ColorFormat::Rgb8, |x, y, pixel| {
+        let texture = ((x * 7) ^ (y * 13)) as u8 % 4;
+        pixel.copy_from_slice(&[(x / 4) as u8 + texture, (y / 4) as u8, ((x + y) / 8) as u8]);
+    });
+
+    // Stripes of text-like detail on a flat background
+    let text = SquishyPicture::from_fn(1024, 512, ColorFormat::Gray8, |x, y, pixel| {
+        pixel[0] = if (y / 12) % 2 == 0 && (x * 31 + y * 17) % 23 < 5 { 0 } else { 230 };
+    });
+
+    let images = [
+        ("photo", photo.width(), photo.height(), ColorFormat::Rgba8, photo.as_raw().clone()),
+        ("smooth", smooth.width(), smooth.height(), ColorFormat::Rgba8, smooth.as_raw().clone()),
+        ("gradient", gradient.width(), gradient.height(), ColorFormat::Rgb8, gradient.as_raw().clone()),
+        ("text", text.width(), text.height(), ColorFormat::Gray8, text.as_raw().clone()),
+    ];
+
+    let strategies = [
+        ("default", None),
+        ("Up", Some(FilterStrategy::Fixed(Filter::Up))),
+        ("MinSumAbs", Some(FilterStrategy::MinSumAbs)),
+        ("TryAll", Some(FilterStrategy::TryAllMeasureCompressed)),
+    ];
+
+    for (name, width, height, color_format, bitmap) in images {
+        for compression_type in [CompressionType::Lossless, CompressionType::LosslessV2] {
+            let sqp = SquishyPicture::from_raw(width, height, color_format, compression_type, None, bitmap.clone());
+
+            for (strategy_name, strategy) in strategies {
+                let options = match strategy {
+                    Some(strategy) => EncodeOptions::new().filter_strategy(strategy),
+                    None => EncodeOptions::new(),
+                };
+
+                let start = Instant::now();
+                let mut encoded = Vec::new();
+                sqp.encode_with_options(&mut encoded, &options).unwrap();
+                let encode_time = start.elapsed();
+
+                let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+                assert_eq!(decoded.as_raw(), &bitmap);
+
+                println!(
+                    "{name:<8} {:<10} {strategy_name:<9}: {:>9} bytes, encode {encode_time:>9.2?}",
+                    format!("{compression_type:?}"),
+                    encoded.len(),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Compares the total size of the 32x32 tiles of an image when each is
+//! encoded on its own and with a shared dictionary trained on half of them.
+//!
+//! Run with `cargo bench --bench shared_dictionary`.
+
+use sqp::{lossless::Dictionary, options::EncodeOptions, SquishyPicture};
+
+fn main() {
+    let logo = sqp::open("test_images/test-lossless.sqp").unwrap();
+    let pbc = logo.color_format().pbc();
+
+    let mut tiles = Vec::new();
+    for tile_y in 0..logo.height() / 32 {
+        for tile_x in 0..logo.width() / 32 {
+            tiles.push(SquishyPicture::from_fn(32, 32, logo.color_format(), |x, y, pixel| {
+                let i = ((tile_y * 32 + y) * logo.width() + tile_x * 32 + x) as usize * pbc;
+                pixel.copy_from_slice(&logo.as_raw()[i..i + pbc]);
+            }));
+        }
+    }
+
+    // Trained on every other tile, and measured on the rest
+    let trained: Vec<_> = tiles.iter().step_by(2).collect();
+    let measured: Vec<_> = tiles.iter().skip(1).step_by(2).collect();
+    let dictionary = Dictionary::train_from_pictures(&trained);
+    println!("{} tiles, dictionary of {} bytes", tiles.len(), dictionary.as_bytes().len());
+
+    let total = |options: &EncodeOptions| -> usize {
+        measured.iter().map(|tile| {
+            let mut encoded = Vec::new();
+            tile.encode_with_options(&mut encoded, options).unwrap()
+        }).sum()
+    };
+
+    let options = EncodeOptions::new().omit_encoder_info(true);
+    let independent = total(&options);
+    let shared = total(&options.clone().shared_dictionary(&dictionary));
+
+    println!("| Tiles | Independent | Shared dictionary |");
+    println!("|-------|-------------|-------------------|");
+    println!(
+        "| {} | {independent} B | {shared} B ({:.0}%) |",
+        measured.len(),
+        shared as f64 * 100.0 / independent as f64,
+    );
+}
@@ -0,0 +1,36 @@
+//! Measures encoding and decoding a large uncompressed image, which is
+//! stored as it is, against the same image compressed with LZW.
+//!
+//! Run with `cargo bench --bench uncompressed`.
+
+use std::time::Instant;
+
+use sqp::{ColorFormat, CompressionType, SquishyPicture};
+
+fn main() {
+    let (width, height) = (4096, 4096);
+    let bitmap: Vec<u8> = (0..width * height * 4).map(|i| (i * 37 % 251) as u8).collect();
+    let mib = bitmap.len() as f64 / (1024.0 * 1024.0);
+
+    println!("| Compression | Encode | Decode | Size |");
+    println!("|-------------|--------|--------|------|");
+    for compression_type in [CompressionType::None, CompressionType::Lossless] {
+        let sqp = SquishyPicture::from_raw(width, height, ColorFormat::Rgba8, compression_type, None, bitmap.clone());
+
+        let start = Instant::now();
+        let encoded = sqp.encode_to_vec().unwrap();
+        let encode = start.elapsed();
+
+        let start = Instant::now();
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        let decode = start.elapsed();
+        assert_eq!(decoded.as_raw(), &bitmap);
+
+        println!(
+            "| {compression_type:?} | {encode:.2?} ({:.0} MiB/s) | {decode:.2?} ({:.0} MiB/s) | {} B |",
+            mib / encode.as_secs_f64(),
+            mib / decode.as_secs_f64(),
+            encoded.len(),
+        );
+    }
+}
@@ -0,0 +1,15 @@
+//! Builds the C interface of sqp into `libsqp_capi.a` and
+//! `libsqp_capi.so`, or the platform's equivalents, for use with
+//! `include/sqp.h`:
+//!
+//! ```sh
+//! cargo build -p sqp-capi --release
+//! cc main.c -I include target/release/libsqp_capi.a -lpthread -ldl -lm
+//! ```
+//!
+//! The functions themselves live in sqp's `capi` module. This crate always
+//! links the standard library, so it can't be in the same crate as sqp,
+//! which may be built without it.
+
+#[cfg(feature = "capi")]
+pub use sqp::capi::*;
@@ -0,0 +1,33 @@
+//! Compile `round_trip.c` against `include/sqp.h` and the static library,
+//! and run it.
+
+#![cfg(all(feature = "capi", unix))]
+
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+fn c_round_trip() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+    // The test binary is built in `deps` next to the libraries
+    let deps_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let library = deps_dir.join("libsqp_capi.a");
+    assert!(library.exists(), "{} was not built", library.display());
+
+    let program = deps_dir.join(format!("sqp-capi-round-trip-{}", std::process::id()));
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(manifest_dir.join("tests/round_trip.c"))
+        .arg("-I")
+        .arg(manifest_dir.join("../include"))
+        .arg(&library)
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status()
+        .expect("no C compiler found, set CC to one");
+    assert!(status.success(), "compiling round_trip.c failed");
+
+    let output = Command::new(&program).output().unwrap();
+    std::fs::remove_file(&program).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"round trip ok\n");
+}
@@ -0,0 +1,52 @@
+/* Encodes and decodes an image through include/sqp.h, run by
+ * tests/c_program.rs against the static library. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "sqp.h"
+
+#define WIDTH 6
+#define HEIGHT 5
+
+int main(void) {
+  uint8_t pixels[WIDTH * HEIGHT * 3];
+  for (size_t i = 0; i < sizeof(pixels); i++) {
+    pixels[i] = (uint8_t)(i * 7);
+  }
+
+  uint8_t *data = NULL;
+  size_t len = 0;
+  SqpErrorCode code = sqp_encode(pixels, WIDTH, HEIGHT, SqpColorFormat_Rgb8, SqpCompression_Lossless, 0, &data, &len);
+  if (code != SqpErrorCode_Ok) {
+    fprintf(stderr, "encode failed: %s\n", sqp_last_error_message());
+    return 1;
+  }
+
+  SqpImageInfo info;
+  uint8_t *decoded = NULL;
+  code = sqp_decode(data, len, &info, &decoded);
+  sqp_free(data);
+  if (code != SqpErrorCode_Ok) {
+    fprintf(stderr, "decode failed: %s\n", sqp_last_error_message());
+    return 1;
+  }
+
+  int same = info.width == WIDTH && info.height == HEIGHT && info.color_format == SqpColorFormat_Rgb8
+      && info.pixels_len == sizeof(pixels) && memcmp(decoded, pixels, sizeof(pixels)) == 0;
+  sqp_free(decoded);
+  if (!same) {
+    fprintf(stderr, "decoded image differs\n");
+    return 1;
+  }
+
+  /* Values outside the enums are rejected rather than trusted */
+  code = sqp_encode(pixels, WIDTH, HEIGHT, 42, SqpCompression_Lossless, 0, &data, &len);
+  if (code != SqpErrorCode_InvalidArgument || sqp_last_error_message() == NULL) {
+    fprintf(stderr, "unknown color format was accepted\n");
+    return 1;
+  }
+
+  printf("round trip ok\n");
+  return 0;
+}
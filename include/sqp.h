@@ -0,0 +1,114 @@
+/* C interface for the sqp crate, built with the `capi` feature.
+ *
+ * Mirrors src/capi.rs, and can be regenerated from it with:
+ *   cbindgen --config cbindgen.toml --output include/sqp.h
+ */
+
+#ifndef SQP_H
+#define SQP_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+/* Result of a C API call. */
+typedef enum SqpErrorCode {
+  /* The call succeeded. */
+  SqpErrorCode_Ok = 0,
+  /* A required pointer argument was null. */
+  SqpErrorCode_NullPointer = 1,
+  /* An argument was out of range, such as dimensions which overflow. */
+  SqpErrorCode_InvalidArgument = 2,
+  /* The input could not be decoded. */
+  SqpErrorCode_DecodeFailed = 3,
+  /* The image could not be encoded. */
+  SqpErrorCode_EncodeFailed = 4,
+  /* The library panicked. This is always a bug. */
+  SqpErrorCode_Panic = 5,
+} SqpErrorCode;
+
+/* The format of bytes in the image.
+ *
+ * The values are part of the ABI, and never change. */
+typedef enum SqpColorFormat {
+  /* RGBA, 8 bits per channel. */
+  SqpColorFormat_Rgba8 = 0,
+  /* RGB, 8 bits per channel. */
+  SqpColorFormat_Rgb8 = 1,
+  /* Grayscale with alpha, 8 bits per channel. */
+  SqpColorFormat_GrayA8 = 2,
+  /* Grayscale, 8 bits per channel. */
+  SqpColorFormat_Gray8 = 3,
+  /* Grayscale, 4 bits per channel, two pixels to a byte. */
+  SqpColorFormat_Gray4 = 4,
+} SqpColorFormat;
+
+/* The type of compression used in the image.
+ *
+ * The values are part of the ABI, and never change. */
+typedef enum SqpCompression {
+  /* No compression, the raw bitmap. */
+  SqpCompression_None = 0,
+  /* Lossless LZW compression. */
+  SqpCompression_Lossless = 1,
+  /* Lossy Discrete Cosine Transform compression. */
+  SqpCompression_LossyDct = 2,
+  /* Lossless compression with a sliding window. */
+  SqpCompression_LosslessV2 = 3,
+  /* Lossless compression with the Burrows–Wheeler transform. */
+  SqpCompression_LosslessBwt = 4,
+  /* Prediction from neighbouring pixels, lossless when `near` is 0. */
+  SqpCompression_Predictive = 5,
+} SqpCompression;
+
+/* Information about a decoded image. */
+typedef struct SqpImageInfo {
+  /* Width of the image in pixels. */
+  uint32_t width;
+  /* Height of the image in pixels. */
+  uint32_t height;
+  /* Format of the decoded pixels. */
+  SqpColorFormat color_format;
+  /* Compression the image was stored with. */
+  SqpCompression compression;
+  /* Quality of lossy images, `near` of predictive ones, 0 otherwise. */
+  uint8_t quality;
+  /* Length of the decoded pixel buffer in bytes. */
+  size_t pixels_len;
+} SqpImageInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Decode an SQP image from memory. On success, `out_pixels` must be
+ * released with `sqp_free`. */
+SqpErrorCode sqp_decode(const uint8_t *data,
+                        size_t len,
+                        SqpImageInfo *out_info,
+                        uint8_t **out_pixels);
+
+/* Encode raw pixels into an SQP image in memory. `fmt` is an
+ * `SqpColorFormat` and `comp` an `SqpCompression`, and unknown values give
+ * `SqpErrorCode_InvalidArgument`. `quality` is only used for lossy
+ * compression, and as `near` for predictive compression. On success,
+ * `out_data` must be released with `sqp_free`. */
+SqpErrorCode sqp_encode(const uint8_t *pixels,
+                        uint32_t w,
+                        uint32_t h,
+                        uint32_t fmt,
+                        uint32_t comp,
+                        uint8_t quality,
+                        uint8_t **out_data,
+                        size_t *out_len);
+
+/* Release a buffer returned by `sqp_decode` or `sqp_encode`. */
+void sqp_free(uint8_t *buffer);
+
+/* Description of the last error on this thread, or NULL. */
+const char *sqp_last_error_message(void);
+
+#ifdef __cplusplus
+}  /* extern "C" */
+#endif
+
+#endif  /* SQP_H */
@@ -0,0 +1,759 @@
+//! Analysis of what an image's content actually needs to be stored, of
+//! how the lossy encoder stores it, and of the structure of encoded files.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{
+    compression::{dct::DctParameters, lossless::{decompress, total_size_raw, Backend, ChunkInfo}},
+    container::{footer_matches, pixel_data_len, skip, ChunkTable, ChunkTableReader, SectionInfo, FOOTER_LEN},
+    header::Header,
+    io::{self, count_remaining, Read},
+    math,
+    options::Limits,
+    picture::{quantized_blocks_from_stream, read_quantized_blocks, read_table_parts, DecodeWarning, Error, FileSection},
+    threads, ColorFormat, CompressionType, SquishyPicture,
+};
+
+/// Number of rows scanned together when analyzing an image in parallel.
+const BAND_HEIGHT: usize = 64;
+
+/// What an image's content needs, returned by
+/// [`SquishyPicture::analyze_content`].
+///
+/// [`SquishyPicture::analyze_content`]: crate::SquishyPicture::analyze_content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ContentReport {
+    /// Whether the red, green and blue of every pixel are equal, within
+    /// the tolerance. Always true for gray formats.
+    pub is_grayscale: bool,
+
+    /// Whether every pixel has an alpha of 255. Always true for formats
+    /// without alpha.
+    pub is_opaque: bool,
+
+    /// The smallest format which can store the image without losing
+    /// anything beyond the tolerance.
+    pub suggested_format: ColorFormat,
+}
+
+impl ContentReport {
+    /// Scan a bitmap in the given format.
+    pub(crate) fn from_bitmap(color_format: ColorFormat, width: u32, bitmap: &[u8], tolerance: u8) -> Self {
+        if color_format == ColorFormat::Gray4 {
+            return Self {
+                is_grayscale: true,
+                is_opaque: true,
+                suggested_format: color_format,
+            }
+        }
+
+        let is_grayscale = AtomicBool::new(matches!(color_format, ColorFormat::Rgba8 | ColorFormat::Rgb8));
+        let is_opaque = AtomicBool::new(color_format.alpha_channel().is_some());
+
+        let band_len = (width as usize * color_format.pbc() * BAND_HEIGHT).max(1);
+
+        #[cfg(feature = "parallel")]
+        let bands = bitmap.par_chunks(band_len);
+        #[cfg(not(feature = "parallel"))]
+        let mut bands = bitmap.chunks(band_len);
+
+        // Stop as soon as there is nothing left to disprove
+        let _ = threads::install(|| {
+            bands.try_for_each(|band| {
+                let (gray, opaque) = scan(
+                    color_format,
+                    band,
+                    tolerance,
+                    is_grayscale.load(Ordering::Relaxed),
+                    is_opaque.load(Ordering::Relaxed),
+                );
+
+                if !gray {
+                    is_grayscale.store(false, Ordering::Relaxed);
+                }
+                if !opaque {
+                    is_opaque.store(false, Ordering::Relaxed);
+                }
+
+                if is_grayscale.load(Ordering::Relaxed) || is_opaque.load(Ordering::Relaxed) {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            })
+        });
+
+        // Properties which didn't need checking hold trivially
+        let is_grayscale = is_grayscale.into_inner() || matches!(color_format, ColorFormat::GrayA8 | ColorFormat::Gray8);
+        let is_opaque = is_opaque.into_inner() || color_format.alpha_channel().is_none();
+
+        Self {
+            is_grayscale,
+            is_opaque,
+            suggested_format: match (is_grayscale, is_opaque) {
+                (true, true) => ColorFormat::Gray8,
+                (true, false) => ColorFormat::GrayA8,
+                (false, true) => ColorFormat::Rgb8,
+                (false, false) => ColorFormat::Rgba8,
+            },
+        }
+    }
+}
+
+/// One 8x8 block of quantized DCT coefficients of a lossy image, from
+/// [`dct_blocks`] or [`stored_dct_blocks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DctBlock {
+    /// The channel the block belongs to, in the order the color format
+    /// interleaves them.
+    pub channel: usize,
+
+    /// The column of the block, counting in blocks from the left.
+    pub x: u32,
+
+    /// The row of the block, counting in blocks from the top.
+    pub y: u32,
+
+    /// The quantized coefficients, in rows from the DC coefficient at the
+    /// top left to the highest frequencies at the bottom right.
+    pub coefficients: [i16; 64],
+}
+
+impl DctBlock {
+    /// The number of coefficients which aren't zero.
+    pub fn nonzero_count(&self) -> usize {
+        self.coefficients.iter().filter(|c| **c != 0).count()
+    }
+
+    /// Whether every coefficient but the DC is zero, so the block decodes
+    /// to a single flat color.
+    pub fn is_dc_only(&self) -> bool {
+        self.coefficients[1..].iter().all(|c| *c == 0)
+    }
+}
+
+/// The blocks the lossy encoder would store for the picture at `quality`,
+/// computed the same way as when encoding it with
+/// [`CompressionType::LossyDct`]. Any quantization matrices the picture has
+/// are ignored in favor of the quality's.
+///
+/// The blocks of each channel are given in turn, each channel in rows of
+/// blocks from the top left. The image is padded out to the block grid,
+/// so there are [`ColorFormat::channels`] times the number of blocks
+/// across and down, including those which are only padding.
+///
+/// Fails with [`Error::LossyUnsupported`] for [`ColorFormat::Gray4`]
+/// images, and [`Error::SizeMismatch`] if the bitmap is the wrong size.
+///
+/// # Example
+/// ```
+/// use sqp::{analysis::dct_blocks, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(16, 8, ColorFormat::Gray8, vec![200; 16 * 8]);
+///
+/// let blocks: Vec<_> = dct_blocks(&sqp, 80).unwrap().collect();
+/// assert_eq!(blocks.len(), 3 * 2);
+/// assert!(blocks.iter().all(|block| block.is_dc_only()));
+/// ```
+///
+/// [`CompressionType::LossyDct`]: crate::CompressionType::LossyDct
+pub fn dct_blocks(picture: &SquishyPicture, quality: u8) -> Result<impl Iterator<Item = DctBlock>, Error> {
+    let (parameters, channels) = picture.quantized_blocks(quality)?;
+
+    Ok(blocks(parameters, channels))
+}
+
+/// The blocks stored in an encoded lossy image, read from anything that
+/// implements [`Read`], in the same order as [`dct_blocks`].
+///
+/// The coefficients are only decompressed, and aren't dequantized or
+/// transformed back into pixels. Images split into tiles have their
+/// blocks put back in place, so the order doesn't depend on the tiles.
+///
+/// Fails with [`Error::NoDctBlocks`] for images which aren't lossy.
+/// [`Limits::default`] applies, see [`stored_dct_blocks_with_limits`] for
+/// others.
+///
+/// # Example
+/// ```
+/// use sqp::{analysis::{dct_blocks, stored_dct_blocks}, ColorFormat, CompressionType, SquishyPicture};
+///
+/// let mut sqp = SquishyPicture::from_fn(40, 30, ColorFormat::Rgb8, |x, y, pixel| {
+///     pixel.copy_from_slice(&[x as u8 * 6, y as u8 * 8, 90]);
+/// });
+/// let blocks: Vec<_> = dct_blocks(&sqp, 60).unwrap().collect();
+///
+/// sqp.set_compression(CompressionType::LossyDct, Some(60));
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let stored: Vec<_> = stored_dct_blocks(encoded.as_slice()).unwrap().collect();
+/// assert_eq!(stored, blocks);
+/// ```
+pub fn stored_dct_blocks<I: Read>(input: I) -> Result<impl Iterator<Item = DctBlock>, Error> {
+    stored_dct_blocks_with_limits(input, Limits::default())
+}
+
+/// The blocks stored in an encoded lossy image like [`stored_dct_blocks`],
+/// with different [`Limits`] than the default.
+pub fn stored_dct_blocks_with_limits<I: Read>(input: I, limits: Limits) -> Result<impl Iterator<Item = DctBlock>, Error> {
+    let (parameters, channels) = read_quantized_blocks(input, limits)?;
+
+    Ok(blocks(parameters, channels))
+}
+
+/// Split the coefficients of each channel, as
+/// [`dct_compress`](crate::compression::dct::dct_compress) produces them,
+/// into blocks.
+fn blocks(parameters: DctParameters, channels: Vec<Vec<i16>>) -> impl Iterator<Item = DctBlock> {
+    let (blocks_wide, blocks_high) = parameters.blocks();
+    let per_channel = blocks_wide * blocks_high;
+
+    (0..channels.len() * per_channel).map(move |i| {
+        let (channel, block) = (i / per_channel, i % per_channel);
+
+        DctBlock {
+            channel,
+            x: (block % blocks_wide) as u32,
+            y: (block / blocks_wide) as u32,
+            coefficients: channels[channel][block * 64..][..64].try_into().unwrap(),
+        }
+    })
+}
+
+/// The structure of an encoded file, returned by [`analyze`], along with
+/// statistics about its data from [`analyze_with_statistics`].
+///
+/// Everything which could be read before a problem is still reported, with
+/// the problem in [`FileReport::error`].
+#[derive(Debug)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileReport {
+    /// The header of the file.
+    pub header: Header,
+
+    /// The length of the whole input in bytes, including anything after the
+    /// end of the image.
+    pub total_size: u64,
+
+    /// Offset of the first compressed chunk from the start of the file, if
+    /// the chunk table could be read.
+    pub data_offset: Option<u64>,
+
+    /// Every section found, in file order. Files from before format
+    /// version 3 don't have any.
+    pub sections: Vec<SectionInfo>,
+
+    /// The compressed chunks, in order, as the chunk table lists them.
+    pub chunks: Vec<ChunkReport>,
+
+    /// The compression ratios of the chunks, if there are any.
+    pub chunk_ratios: Option<RatioSummary>,
+
+    /// The size of the image from the start of the file to the end of the
+    /// last chunk, in bits for each pixel. [`None`] if the chunk table
+    /// couldn't be read or the image has no pixels.
+    pub bits_per_pixel: Option<f64>,
+
+    /// Statistics about the decompressed data, from
+    /// [`analyze_with_statistics`].
+    pub statistics: Option<DataStatistics>,
+
+    /// Problems which wouldn't stop the file from being decoded.
+    pub warnings: Vec<DecodeWarning>,
+
+    /// The problem which stopped the rest of the file from being read.
+    pub error: Option<Error>,
+}
+
+/// The sizes of one compressed chunk, from [`FileReport::chunks`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChunkReport {
+    /// Offset of the chunk from the start of the file.
+    pub offset: u64,
+
+    /// The size of the chunk in the file.
+    pub size_compressed: usize,
+
+    /// The size of the chunk once decompressed.
+    pub size_raw: usize,
+
+    /// How many times smaller the chunk is compressed, the raw size
+    /// divided by the compressed size.
+    pub ratio: f64,
+}
+
+impl ChunkReport {
+    fn new(chunk: &ChunkInfo, offset: u64) -> Self {
+        Self {
+            offset,
+            size_compressed: chunk.size_compressed,
+            size_raw: chunk.size_raw,
+            ratio: chunk.size_raw as f64 / chunk.size_compressed.max(1) as f64,
+        }
+    }
+}
+
+/// The smallest, mean and largest of the chunks' compression ratios, from
+/// [`FileReport::chunk_ratios`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RatioSummary {
+    /// The ratio of the chunk which compressed the least.
+    pub min: f64,
+
+    /// The mean of every chunk's ratio.
+    pub mean: f64,
+
+    /// The ratio of the chunk which compressed the most.
+    pub max: f64,
+}
+
+impl RatioSummary {
+    fn of(chunks: &[ChunkReport]) -> Option<Self> {
+        if chunks.is_empty() {
+            return None
+        }
+
+        let ratios = chunks.iter().map(|c| c.ratio);
+        Some(Self {
+            min: ratios.clone().fold(f64::INFINITY, f64::min),
+            mean: ratios.clone().sum::<f64>() / chunks.len() as f64,
+            max: ratios.fold(0.0, f64::max),
+        })
+    }
+}
+
+/// Statistics about a file's decompressed data, from
+/// [`FileReport::statistics`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DataStatistics {
+    /// The Shannon entropy of the bytes of the decompressed data, in bits
+    /// per byte. For lossless images this is the row filtered bitmap, and
+    /// for lossy ones the stream of coefficients.
+    pub entropy: f64,
+
+    /// The fraction of the quantized DCT coefficients which are zero, for
+    /// lossy images whose coefficients could all be read.
+    pub coefficient_sparsity: Option<f64>,
+}
+
+/// Report on the structure of an encoded file, read from anything that
+/// implements [`Read`], without decompressing it.
+///
+/// Only a header which can't be read fails. Anything wrong after the header
+/// is given in [`FileReport::error`], and the rest of the report holds what
+/// was read up to that point. The input is read to its end either way, to
+/// find the [`FileReport::total_size`].
+///
+/// # Example
+/// ```
+/// use sqp::{ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(64, 64, ColorFormat::Gray8, vec![7; 64 * 64]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let report = sqp::analyze(encoded.as_slice()).unwrap();
+/// assert_eq!(report.total_size, encoded.len() as u64);
+/// assert!(report.chunk_ratios.unwrap().min > 10.0);
+/// assert!(report.error.is_none());
+/// ```
+pub fn analyze<I: Read>(input: I) -> Result<FileReport, Error> {
+    analyze_file(input, false)
+}
+
+/// Like [`analyze`], but also decompresses the file to fill in
+/// [`FileReport::statistics`], along with any warnings about the chunks.
+///
+/// The decompressed data is checked against the default [`Limits`] before
+/// anything is allocated for it.
+///
+/// # Example
+/// ```
+/// use sqp::{analysis::analyze_with_statistics, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossy(64, 64, ColorFormat::Gray8, 50, vec![7; 64 * 64]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let statistics = analyze_with_statistics(encoded.as_slice()).unwrap().statistics.unwrap();
+/// assert!(statistics.coefficient_sparsity.unwrap() > 0.9);
+/// ```
+pub fn analyze_with_statistics<I: Read>(input: I) -> Result<FileReport, Error> {
+    analyze_file(input, true)
+}
+
+fn analyze_file<I: Read>(input: I, statistics: bool) -> Result<FileReport, Error> {
+    let mut input = Counted { inner: input, count: 0 };
+    let header = Header::read_from(&mut input)?;
+
+    let mut report = FileReport {
+        header,
+        total_size: 0,
+        data_offset: None,
+        sections: Vec::new(),
+        chunks: Vec::new(),
+        chunk_ratios: None,
+        bits_per_pixel: None,
+        statistics: None,
+        warnings: Vec::new(),
+        error: None,
+    };
+
+    if let Err(e) = read_structure(&mut input, &mut report, statistics) {
+        report.error = Some(e);
+    }
+
+    // Whatever is left after a problem still counts towards the size
+    let _ = count_remaining(&mut input);
+    report.total_size = input.count;
+
+    Ok(report)
+}
+
+/// Fill in everything in the report after the header, stopping at the
+/// first problem.
+fn read_structure<I: Read>(input: &mut I, report: &mut FileReport, statistics: bool) -> Result<(), Error> {
+    let header = report.header;
+
+    // Nothing is allocated for the chunks without the statistics, so the
+    // size of the image doesn't matter
+    let mut reader = ChunkTableReader::new(&header, &Limits::unlimited())?;
+    let read = read_table_parts(input, &mut reader);
+    report.sections = reader.sections().to_vec();
+    read?;
+
+    let ChunkTable { compression_info, data_offset, .. } = reader.finish();
+    let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+    let file_len = image_len + header.footer_len() as u64;
+    let pixels = header.width as u64 * header.height as u64;
+
+    report.data_offset = Some(data_offset);
+    report.chunks = compression_info.chunks.iter()
+        .zip(&compression_info.offsets)
+        .map(|(chunk, offset)| ChunkReport::new(chunk, *offset))
+        .collect();
+    report.chunk_ratios = RatioSummary::of(&report.chunks);
+    report.bits_per_pixel = (pixels > 0).then(|| file_len as f64 * 8.0 / pixels as f64);
+
+    if statistics {
+        Limits::default().check_alloc(total_size_raw(&compression_info.chunks))?;
+        let pre_bitmap = decompress(input, &compression_info, Backend::from(&header), &mut report.warnings)
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+
+        let statistics = report.statistics.insert(DataStatistics {
+            entropy: entropy(&pre_bitmap),
+            coefficient_sparsity: None,
+        });
+        if header.compression_type == CompressionType::LossyDct {
+            let (_, channels) = quantized_blocks_from_stream(&header, &pre_bitmap)?;
+            let count = channels.iter().map(Vec::len).sum::<usize>();
+            let zeroes = channels.iter().flatten().filter(|c| **c == 0).count();
+            statistics.coefficient_sparsity = Some(zeroes as f64 / count.max(1) as f64);
+        }
+    } else {
+        for chunk in &compression_info.chunks {
+            skip(input, chunk.size_compressed as u32).map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        }
+    }
+
+    if header.footer_len() > 0 {
+        let mut footer = [0; FOOTER_LEN];
+        input.read_exact(&mut footer).map_err(|e| Error::from_read(e, FileSection::Footer))?;
+        if !footer_matches(&footer, image_len) {
+            report.warnings.push(DecodeWarning::DamagedFooter { offset: image_len });
+        }
+    }
+
+    let trailing = count_remaining(input)?;
+    if trailing > 0 {
+        report.warnings.push(DecodeWarning::TrailingBytes { offset: file_len, len: trailing });
+    }
+
+    Ok(())
+}
+
+/// The Shannon entropy of the bytes of `data`, in bits per byte.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+
+    counts.iter().filter(|c| **c > 0).map(|c| {
+        let p = *c as f64 / data.len() as f64;
+        -p * math::log2(p)
+    }).sum()
+}
+
+/// Counts the bytes read through it.
+struct Counted<I> {
+    inner: I,
+    count: u64,
+}
+
+impl<I: Read> Read for Counted<I> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+
+        Ok(len)
+    }
+}
+
+/// Check whether some whole pixels are grayscale and opaque, only checking
+/// the properties which are asked for. Returns as soon as neither holds.
+fn scan(
+    color_format: ColorFormat,
+    bitmap: &[u8],
+    tolerance: u8,
+    mut gray: bool,
+    mut opaque: bool,
+) -> (bool, bool) {
+    for pixel in bitmap.chunks_exact(color_format.pbc()) {
+        if !gray && !opaque {
+            break
+        }
+
+        if gray {
+            let min = pixel[0].min(pixel[1]).min(pixel[2]);
+            let max = pixel[0].max(pixel[1]).max(pixel[2]);
+            gray = max - min <= tolerance;
+        }
+
+        if opaque {
+            opaque = color_format.alpha_channel().is_some_and(|a| pixel[a] == 0xFF);
+        }
+    }
+
+    (gray, opaque)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use crate::{container::SectionType, picture::Error, CompressionType};
+
+    use super::*;
+
+    #[test]
+    fn suggested_formats() {
+        let cases: [(ColorFormat, &[u8], u8, ColorFormat); 10] = [
+            (ColorFormat::Rgba8, &[9, 9, 9, 255, 200, 200, 200, 255], 0, ColorFormat::Gray8),
+            (ColorFormat::Rgba8, &[9, 9, 9, 255, 200, 200, 200, 254], 0, ColorFormat::GrayA8),
+            (ColorFormat::Rgba8, &[9, 9, 9, 255, 200, 201, 200, 255], 0, ColorFormat::Rgb8),
+            (ColorFormat::Rgba8, &[9, 9, 9, 0, 200, 201, 200, 255], 0, ColorFormat::Rgba8),
+            (ColorFormat::Rgba8, &[9, 9, 9, 255, 200, 202, 200, 255], 2, ColorFormat::Gray8),
+            (ColorFormat::Rgba8, &[9, 9, 9, 255, 200, 203, 200, 255], 2, ColorFormat::Rgb8),
+            (ColorFormat::Rgb8, &[9, 9, 9, 200, 200, 200], 0, ColorFormat::Gray8),
+            (ColorFormat::Rgb8, &[9, 8, 9, 200, 200, 200], 0, ColorFormat::Rgb8),
+            (ColorFormat::GrayA8, &[9, 255, 200, 128], 0, ColorFormat::GrayA8),
+            (ColorFormat::Gray8, &[9, 200], 0, ColorFormat::Gray8),
+        ];
+
+        for (color_format, bitmap, tolerance, expected) in cases {
+            let report = ContentReport::from_bitmap(color_format, 2, bitmap, tolerance);
+            assert_eq!(report.suggested_format, expected, "{color_format:?} {bitmap:?}");
+            assert_eq!(report.is_grayscale, matches!(expected, ColorFormat::Gray8 | ColorFormat::GrayA8));
+            assert_eq!(report.is_opaque, expected.alpha_channel().is_none());
+        }
+    }
+
+    #[test]
+    fn later_bands_are_scanned() {
+        // Gray and opaque everywhere except near the end
+        let mut bitmap: Vec<u8> = (0..100u32 * 200).flat_map(|i| [i as u8, i as u8, i as u8, 255]).collect();
+        let report = ContentReport::from_bitmap(ColorFormat::Rgba8, 100, &bitmap, 0);
+        assert_eq!(report.suggested_format, ColorFormat::Gray8);
+
+        let last = bitmap.len() - 4;
+        bitmap[last + 3] = 254;
+        let report = ContentReport::from_bitmap(ColorFormat::Rgba8, 100, &bitmap, 0);
+        assert_eq!(report.suggested_format, ColorFormat::GrayA8);
+
+        bitmap[last - 4] = 1;
+        let report = ContentReport::from_bitmap(ColorFormat::Rgba8, 100, &bitmap, 0);
+        assert_eq!(report.suggested_format, ColorFormat::Rgba8);
+    }
+
+    #[test]
+    fn dct_block_grid() {
+        // Channels times the blocks of the padded image, which always
+        // reaches past the edge, so 8 pixels is 2 blocks
+        let cases = [
+            (ColorFormat::Gray8, 8, 8, 4),
+            (ColorFormat::GrayA8, 17, 5, 6),
+            (ColorFormat::Rgb8, 30, 31, 48),
+            (ColorFormat::Rgba8, 1, 40, 24),
+        ];
+
+        for (color_format, width, height, expected) in cases {
+            let bitmap = vec![0x60; (width * height) as usize * color_format.pbc()];
+            let sqp = SquishyPicture::from_raw_lossless(width, height, color_format, bitmap);
+            let blocks: Vec<DctBlock> = dct_blocks(&sqp, 80).unwrap().collect();
+            assert_eq!(blocks.len(), expected, "{color_format:?} {width}x{height}");
+
+            // Every channel in turn, in rows of blocks
+            let blocks_wide = width / 8 + 1;
+            let per_channel = blocks.len() / color_format.channels() as usize;
+            for (i, block) in blocks.iter().enumerate() {
+                assert_eq!(block.channel, i / per_channel);
+                assert_eq!((block.x, block.y), ((i % per_channel) as u32 % blocks_wide, (i % per_channel) as u32 / blocks_wide));
+            }
+        }
+    }
+
+    #[test]
+    fn solid_color_is_dc_only() {
+        let bitmap: Vec<u8> = [30, 140, 250, 255].repeat(24 * 16);
+        let sqp = SquishyPicture::from_raw_lossless(24, 16, ColorFormat::Rgba8, bitmap);
+
+        // The padding is solid too, just a different color
+        for block in dct_blocks(&sqp, 90).unwrap() {
+            assert!(block.is_dc_only(), "{block:?}");
+            assert!(block.nonzero_count() <= 1);
+        }
+    }
+
+    #[test]
+    fn stored_blocks_match_encoder() {
+        let bitmap: Vec<u8> = (0..50u32 * 40).flat_map(|i| [(i * 7) as u8, (i / 50 * 5) as u8, (i % 50 * 3) as u8]).collect();
+        let expected: Vec<DctBlock> = {
+            let sqp = SquishyPicture::from_raw_lossless(50, 40, ColorFormat::Rgb8, bitmap.clone());
+            dct_blocks(&sqp, 70).unwrap().collect()
+        };
+        assert!(expected.iter().any(|block| !block.is_dc_only()));
+
+        for (tile_size, range_coded) in [(None, false), (None, true), (Some(16), false), (Some(16), true)] {
+            let mut sqp = SquishyPicture::from_raw_lossy(50, 40, ColorFormat::Rgb8, 70, bitmap.clone());
+            sqp.set_tile_size(tile_size).unwrap();
+            sqp.set_range_coding(range_coded).unwrap();
+            let encoded = sqp.encode_to_vec().unwrap();
+
+            let stored: Vec<DctBlock> = stored_dct_blocks(encoded.as_slice()).unwrap().collect();
+            assert!(stored == expected, "{tile_size:?} {range_coded}");
+        }
+    }
+
+    #[test]
+    fn dct_blocks_errors() {
+        let sqp = SquishyPicture::from_raw_lossless(4, 4, ColorFormat::Gray4, vec![0; 8]);
+        assert!(matches!(dct_blocks(&sqp, 80), Err(Error::LossyUnsupported(ColorFormat::Gray4))));
+
+        let sqp = SquishyPicture::from_raw_lossless(4, 4, ColorFormat::Gray8, vec![0; 16]);
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert!(matches!(
+            stored_dct_blocks(encoded.as_slice()),
+            Err(Error::NoDctBlocks(CompressionType::Lossless))
+        ));
+    }
+
+    #[test]
+    fn file_reports() {
+        let bitmap: Vec<u8> = (0..48u32 * 32).flat_map(|i| [(i * 5) as u8, (i / 48 * 3) as u8, 70]).collect();
+
+        for lossy in [false, true] {
+            let sqp = match lossy {
+                false => SquishyPicture::from_raw_lossless(48, 32, ColorFormat::Rgb8, bitmap.clone()),
+                true => SquishyPicture::from_raw_lossy(48, 32, ColorFormat::Rgb8, 60, bitmap.clone()),
+            };
+            let mut encoded = sqp.encode_to_vec().unwrap();
+            let image_len = encoded.len() as u64;
+            encoded.extend_from_slice(b"more");
+
+            let report = analyze_with_statistics(encoded.as_slice()).unwrap();
+            assert!(report.error.is_none(), "{:?}", report.error);
+            assert_eq!(report.total_size, image_len + 4);
+            assert_eq!(report.warnings, [DecodeWarning::TrailingBytes { offset: image_len, len: 4 }]);
+            assert_eq!(report.bits_per_pixel, Some(image_len as f64 * 8.0 / (48.0 * 32.0)));
+
+            // The pixel data section runs from the first chunk to the end of the image
+            let data_offset = report.data_offset.unwrap();
+            let pixel_data = report.sections.iter().find(|s| s.kind == SectionType::PIXEL_DATA).unwrap();
+            assert_eq!(pixel_data.offset + 8, data_offset);
+            assert_eq!(data_offset + pixel_data.len as u64 + FOOTER_LEN as u64, image_len);
+            assert!(report.sections.iter().any(|s| s.kind == SectionType::CHUNK_TABLE));
+
+            let total: usize = report.chunks.iter().map(|c| c.size_compressed).sum();
+            assert_eq!(total, pixel_data.len as usize);
+            assert_eq!(report.chunks[0].offset, data_offset);
+            let ratios = report.chunk_ratios.unwrap();
+            assert!(ratios.min <= ratios.mean && ratios.mean <= ratios.max);
+
+            let statistics = report.statistics.unwrap();
+            assert!(statistics.entropy > 0.0 && statistics.entropy <= 8.0);
+            assert_eq!(statistics.coefficient_sparsity.is_some(), lossy);
+
+            // The structure alone is the same
+            let structure = analyze(encoded.as_slice()).unwrap();
+            assert!(structure.statistics.is_none());
+            assert_eq!(structure.chunks, report.chunks);
+            assert_eq!(structure.warnings, report.warnings);
+        }
+    }
+
+    #[test]
+    fn truncated_file_reports() {
+        let bitmap: Vec<u8> = (0..40u32 * 40).map(|i| (i * 13 % 251) as u8).collect();
+        let sqp = SquishyPicture::from_raw_lossless(40, 40, ColorFormat::Gray8, bitmap);
+        let encoded = sqp.encode_to_vec().unwrap();
+
+        // Cut off partway through the chunks, the table is still there
+        let cut = &encoded[..encoded.len() - FOOTER_LEN - 10];
+        for report in [analyze(cut).unwrap(), analyze_with_statistics(cut).unwrap()] {
+            assert!(matches!(report.error, Some(Error::TruncatedFile { section: FileSection::ChunkData })));
+            assert_eq!(report.total_size, cut.len() as u64);
+            assert!(!report.chunks.is_empty());
+            assert!(report.statistics.is_none());
+        }
+
+        // Cut off right after the header, only it is left
+        let header_len = Header::read_from(&mut &encoded[..]).unwrap().len();
+        let report = analyze(&encoded[..header_len + 3]).unwrap();
+        assert!(report.error.is_some());
+        assert_eq!(report.header.width, 40);
+        assert!(report.chunks.is_empty() && report.data_offset.is_none());
+
+        // Cut off within the footer, after every chunk
+        let cut = &encoded[..encoded.len() - 1];
+        for report in [analyze(cut).unwrap(), analyze_with_statistics(cut).unwrap()] {
+            assert!(matches!(report.error, Some(Error::TruncatedFile { section: FileSection::Footer })));
+        }
+
+        let mut damaged = encoded.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let report = analyze(damaged.as_slice()).unwrap();
+        assert!(report.error.is_none());
+        assert!(matches!(report.warnings[..], [DecodeWarning::DamagedFooter { .. }]));
+
+        assert!(analyze(&encoded[..5]).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn file_report_json() {
+        let sqp = SquishyPicture::from_raw_lossy(20, 12, ColorFormat::GrayA8, 80, vec![90; 20 * 12 * 2]);
+        let mut encoded = sqp.encode_to_vec().unwrap();
+        encoded.truncate(encoded.len() - FOOTER_LEN - 1);
+
+        let json = serde_json::to_value(analyze(encoded.as_slice()).unwrap()).unwrap();
+        assert_eq!(json["header"]["width"], 20);
+        assert_eq!(json["header"]["color_format"], "GrayA8");
+        assert_eq!(json["header"]["compression_type"], "LossyDct");
+        assert_eq!(json["sections"][0]["kind"], "CINF");
+        assert_eq!(json["error"], "file is truncated, it ends within the chunk data");
+        assert!(json["chunks"][0]["ratio"].is_f64());
+    }
+}
@@ -0,0 +1,396 @@
+//! Encoding and decoding over tokio's [`AsyncRead`] and [`AsyncWrite`].
+
+use std::io;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    task::spawn_blocking,
+};
+
+use crate::{
+    compression::lossless::{decompress_chunk_with_primer, total_size_raw, Backend},
+    container::{pixel_data_len, write_chunk_table, write_footer, ChunkTable, ChunkTableReader, TablePart},
+    header::{Header, MAX_HEADER_LEN},
+    lossless::Dictionary,
+    options::{DecodeOptions, EncodeOptions},
+    picture::{DecodeWarning, Error, FileSection, Warnings},
+    SquishyPicture,
+};
+
+impl SquishyPicture {
+    /// Decode the image from anything that implements [`AsyncRead`].
+    ///
+    /// Each compressed chunk is handed to a blocking task as soon as it has
+    /// been read, so decompression overlaps with reading the rest of the
+    /// stream and never runs on the async executor's threads.
+    ///
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn example() -> Result<(), sqp::picture::Error> {
+    /// let file = tokio::fs::File::open("my_image.sqp").await?;
+    /// let image = sqp::SquishyPicture::decode_async(file).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn decode_async<I: AsyncRead + Unpin>(input: I) -> Result<Self, Error> {
+        Self::decode_async_with_options(input, &DecodeOptions::default()).await
+    }
+
+    /// Decode the image from anything that implements [`AsyncRead`], using
+    /// the given [`DecodeOptions`].
+    ///
+    /// The options apply as they do to
+    /// [`SquishyPicture::decode_with_options`], except that the whole image
+    /// is always decompressed at once, on tokio's blocking threads, so
+    /// [`DecodeOptions::low_memory`] and [`DecodeOptions::threads`] are
+    /// ignored.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub async fn decode_async_with_options<I: AsyncRead + Unpin>(mut input: I, options: &DecodeOptions) -> Result<Self, Error> {
+        let mut warnings = Warnings::new(options, false);
+
+        let mut header_bytes = [0u8; MAX_HEADER_LEN];
+        let mut header_len = 0;
+        loop {
+            let remaining = Header::remaining_len(&header_bytes[..header_len]);
+            if remaining == 0 {
+                break
+            }
+
+            input.read_exact(&mut header_bytes[header_len..header_len + remaining]).await
+                .map_err(|e| Error::from_read(e, FileSection::Header))?;
+            header_len += remaining;
+        }
+        let header = Header::parse(&header_bytes[..header_len])?;
+
+        let mut reader = ChunkTableReader::new(&header, &options.limits)?
+            .with_shared_dictionary(options.shared_dictionary.as_ref());
+        read_table_parts(&mut input, &mut reader).await?;
+        let ChunkTable { compression_info, data_offset, encoder_info, content_hash, shared_dictionary } = reader.finish();
+        options.limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+        // The dictionary has been checked against the one the image needs
+        let dictionary = shared_dictionary.and(options.shared_dictionary.clone());
+        let backend = Backend::from(&header);
+        let mut tasks = Vec::with_capacity(compression_info.chunks.len());
+        for (i, chunk) in compression_info.chunks.iter().enumerate() {
+            let data = read_vec_async(&mut input, chunk.size_compressed).await
+                .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+            let size_raw = chunk.size_raw;
+            let dictionary = dictionary.clone();
+
+            tasks.push(spawn_blocking(move || {
+                decompress_chunk_with_primer(&data, size_raw, i, backend, dictionary.as_ref().map(Dictionary::primer))
+            }));
+        }
+        let footer = read_vec_async(&mut input, header.footer_len()).await
+            .map_err(|e| Error::from_read(e, FileSection::Footer))?;
+
+        let mut pre_bitmap = Vec::with_capacity(total_size_raw(&compression_info.chunks));
+        for task in tasks {
+            let (chunk, warning) = task.await.map_err(io::Error::from)?;
+            pre_bitmap.extend_from_slice(&chunk);
+            if let Some(warning) = warning {
+                warnings.add(warning)?;
+            }
+        }
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        let footer_len = warnings.check_footer(&mut footer.as_slice(), &header, image_len)?;
+
+        let deblock = options.deblock;
+        let (mut picture, mut warnings) = spawn_blocking(move || {
+            let mut picture = Self::from_decompressed(header, pre_bitmap, &mut warnings)?;
+            warnings.check_content_hash(&picture, content_hash)?;
+            picture.deblock(deblock)?;
+
+            Ok::<_, Error>((picture, warnings))
+        })
+        .await
+        .map_err(io::Error::from)??;
+        picture.encoder_info = encoder_info;
+
+        if warnings.wanted() {
+            let len = tokio::io::copy(&mut input, &mut tokio::io::sink()).await?;
+            if len > 0 {
+                let offset = image_len + footer_len;
+                warnings.add(DecodeWarning::TrailingBytes { offset, len })?;
+            }
+        }
+
+        Ok(picture)
+    }
+
+    /// Encode the image into anything that implements [`AsyncWrite`].
+    ///
+    /// Compression runs on a blocking task using a copy of the bitmap, so
+    /// the executor is not blocked. Returns the number of bytes written.
+    ///
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Cancellation
+    /// Nothing is written until compression has finished. If the future is
+    /// dropped after that, the output may contain an incomplete prefix of
+    /// the encoded image which will not decode.
+    pub async fn encode_async<O: AsyncWrite + Unpin>(&self, mut output: O) -> Result<usize, Error> {
+        let picture = Self {
+            header: self.header,
+            bitmap: self.bitmap.clone(),
+            encoder_info: None,
+        };
+
+        let (compressed_data, compression_info) = spawn_blocking(move || picture.compress_bitmap(&EncodeOptions::default()))
+            .await
+            .map_err(io::Error::from)??;
+
+        let mut preamble = Vec::new();
+        self.header.write_into(&mut preamble)?;
+        write_chunk_table(&mut preamble, self, &compression_info, &EncodeOptions::default())?;
+        let image_len = preamble.len() + compressed_data.len();
+        let mut footer = Vec::new();
+        write_footer(&mut footer, &self.header, image_len as u64)?;
+
+        output.write_all(&preamble).await?;
+        output.write_all(&compressed_data).await?;
+        output.write_all(&footer).await?;
+        output.flush().await?;
+
+        Ok(image_len + footer.len())
+    }
+}
+
+/// Read the chunk table which follows the header, and the sections around
+/// it from version 3 on, giving each part to the same [`ChunkTableReader`]
+/// the other decoders use. Leaves the input at the first compressed chunk.
+async fn read_table_parts<I: AsyncRead + Unpin>(input: &mut I, reader: &mut ChunkTableReader) -> Result<(), Error> {
+    loop {
+        match reader.next_part() {
+            TablePart::Read(len) => {
+                let bytes = read_vec_async(input, len).await
+                    .map_err(|e| Error::from_read(e, reader.file_section()))?;
+                reader.advance(&bytes)?;
+            },
+            TablePart::Skip(len) => {
+                let skipped = tokio::io::copy(&mut (&mut *input).take(len as u64), &mut tokio::io::sink()).await
+                    .map_err(|e| Error::from_read(e, reader.file_section()))?;
+                if skipped != len as u64 {
+                    return Err(Error::TruncatedFile { section: reader.file_section() })
+                }
+                reader.advance(&[])?;
+            },
+            TablePart::Done => return Ok(()),
+        }
+    }
+}
+
+/// Read exactly `len` bytes into a new [`Vec`], growing it only as data
+/// arrives.
+async fn read_vec_async<I: AsyncRead + Unpin>(input: &mut I, len: usize) -> Result<Vec<u8>, io::Error> {
+    let mut buffer = Vec::new();
+    (&mut *input).take(len as u64).read_to_end(&mut buffer).await?;
+
+    if buffer.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into())
+    }
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{container::{ChunkIndex, SectionHeader, SectionType, FOOTER_LEN}, ColorFormat, CompressionType};
+
+    fn test_image(compression_type: CompressionType, quality: Option<u8>) -> SquishyPicture {
+        let bitmap = (0..67 * 41 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        SquishyPicture::from_raw(67, 41, ColorFormat::Rgba8, compression_type, quality, bitmap)
+    }
+
+    #[tokio::test]
+    async fn decode_fixture_matches_sync() {
+        let data = std::fs::read("test_images/test-lossless.sqp").unwrap();
+
+        let sync = SquishyPicture::decode(data.as_slice()).unwrap();
+        let asynchronous = SquishyPicture::decode_async(data.as_slice()).await.unwrap();
+
+        assert_eq!(sync.as_raw(), asynchronous.as_raw());
+    }
+
+    #[tokio::test]
+    async fn round_trip_through_duplex() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let sqp = test_image(compression_type, quality);
+
+            let mut expected = Vec::new();
+            sqp.encode(&mut expected).unwrap();
+
+            // A tiny buffer forces both sides to wait on each other
+            let (writer, reader) = duplex(7);
+            let (written, decoded) = tokio::join!(
+                sqp.encode_async(writer),
+                SquishyPicture::decode_async(reader),
+            );
+
+            let decoded = decoded.unwrap();
+            assert_eq!(written.unwrap(), expected.len());
+            assert_eq!(decoded.as_raw(), SquishyPicture::decode(expected.as_slice()).unwrap().as_raw());
+        }
+    }
+
+    #[tokio::test]
+    async fn encode_matches_sync() {
+        let sqp = test_image(CompressionType::Lossless, None);
+
+        let mut expected = Vec::new();
+        sqp.encode(&mut expected).unwrap();
+
+        let (writer, mut reader) = duplex(64);
+        let (written, read) = tokio::join!(
+            async move { sqp.encode_async(writer).await },
+            async {
+                let mut output = Vec::new();
+                reader.read_to_end(&mut output).await.map(|_| output)
+            },
+        );
+
+        assert_eq!(written.unwrap(), expected.len());
+        assert_eq!(read.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn decode_truncated_fails() {
+        let mut encoded = Vec::new();
+        test_image(CompressionType::Lossless, None).encode(&mut encoded).unwrap();
+
+        for (len, section) in [
+            (0, FileSection::Header),
+            (19, FileSection::Header),
+            (20, FileSection::Header),
+            (28, FileSection::Header),
+            (29, FileSection::Sections),
+            (37, FileSection::ChunkTable),
+            (51, FileSection::ChunkTable),
+            (57, FileSection::Sections),
+            (encoded.len() - FOOTER_LEN - 1, FileSection::ChunkData),
+            (encoded.len() - 1, FileSection::Footer),
+        ] {
+            match SquishyPicture::decode_async(&encoded[..len]).await {
+                Err(Error::TruncatedFile { section: s }) => assert_eq!(s, section),
+                other => panic!("expected a truncated {section}, got {:?}", other.err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_skips_ancillary_sections() {
+        let sqp = test_image(CompressionType::Lossless, None);
+        let mut encoded = Vec::new();
+        sqp.encode(&mut encoded).unwrap();
+
+        let mut section = b"teXt".to_vec();
+        section.extend_from_slice(&5000u32.to_le_bytes());
+        section.resize(section.len() + 5000, 0xAA);
+        let mut with_section = [&encoded[..29], &section, &encoded[29..]].concat();
+        with_section[21..29].copy_from_slice(&(29 + section.len() as u64).to_le_bytes());
+
+        // The chunk moves along too, and its offset with it
+        let entry = 29 + section.len() + SectionHeader::LEN + 4 + 8;
+        let chunk_offset = u64::from_le_bytes(with_section[entry..entry + 8].try_into().unwrap());
+        with_section[entry..entry + 8].copy_from_slice(&(chunk_offset + section.len() as u64).to_le_bytes());
+
+        let decoded = SquishyPicture::decode_async(with_section.as_slice()).await.unwrap();
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+
+        section[..4].copy_from_slice(b"XTRA");
+        let with_critical = [&encoded[..29], &section, &encoded[29..]].concat();
+        match SquishyPicture::decode_async(with_critical.as_slice()).await {
+            Err(Error::UnknownCriticalSection(kind)) => assert_eq!(kind, SectionType(*b"XTRA")),
+            other => panic!("expected an unknown critical section, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_with_options_matches_sync() {
+        // A smooth gradient shows the edges between blocks at a low quality
+        let mut sqp = SquishyPicture::from_fn(64, 64, ColorFormat::Rgb8, |x, y, pixel| pixel.fill((x + y) as u8));
+        sqp.set_compression(CompressionType::LossyDct, Some(10));
+        let encoded = sqp.encode_to_vec().unwrap();
+        let options = DecodeOptions::new().deblock(8);
+
+        let sync = SquishyPicture::decode_with_options(encoded.as_slice(), &options).unwrap();
+        let asynchronous = SquishyPicture::decode_async_with_options(encoded.as_slice(), &options).await.unwrap();
+        assert_eq!(sync.as_raw(), asynchronous.as_raw());
+        assert_ne!(asynchronous.as_raw(), SquishyPicture::decode(encoded.as_slice()).unwrap().as_raw());
+    }
+
+    #[tokio::test]
+    async fn decode_strict() {
+        let mut encoded = test_image(CompressionType::Lossless, None).encode_to_vec().unwrap();
+        let end = encoded.len() as u64;
+        encoded.extend_from_slice(b"extra");
+
+        // Trailing bytes are only looked for when something comes of them
+        assert!(SquishyPicture::decode_async(encoded.as_slice()).await.is_ok());
+
+        let strict = DecodeOptions::new().strict(true);
+        match SquishyPicture::decode_async_with_options(encoded.as_slice(), &strict).await {
+            Err(Error::Strict(DecodeWarning::TrailingBytes { offset, len: 5 })) => assert_eq!(offset, end),
+            other => panic!("expected trailing bytes, got {:?}", other.err()),
+        }
+
+        // The first code of the only chunk becomes one far past the end of
+        // the dictionary
+        let mut corrupt = test_image(CompressionType::Lossless, None).encode_to_vec().unwrap();
+        let start = ChunkIndex::read_from(corrupt.as_slice()).unwrap().chunk_range(0).unwrap().start as usize;
+        corrupt[start..start + 3].fill(0xFF);
+        match SquishyPicture::decode_async_with_options(corrupt.as_slice(), &strict).await {
+            Err(Error::Strict(DecodeWarning::CorruptChunk { chunk: 0, .. })) => (),
+            other => panic!("expected a corrupt chunk, got {:?}", other.err()),
+        }
+    }
+
+    #[cfg(feature = "content-hash")]
+    #[tokio::test]
+    async fn decode_checks_content_hash() {
+        let sqp = test_image(CompressionType::None, None);
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &EncodeOptions::new().content_hash(true)).unwrap();
+
+        // Uncompressed pixels are stored as they are
+        let last = encoded.len() - FOOTER_LEN - 1;
+        encoded[last] ^= 1;
+
+        assert!(SquishyPicture::decode_async(encoded.as_slice()).await.is_ok());
+        match SquishyPicture::decode_async_with_options(encoded.as_slice(), &DecodeOptions::new().strict(true)).await {
+            Err(Error::Strict(DecodeWarning::ContentHashMismatch { .. })) => (),
+            other => panic!("expected a content hash mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_shared_dictionary() {
+        let sqp = test_image(CompressionType::Lossless, None);
+        let dictionary = Dictionary::train_from_pictures(&[&sqp]);
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &EncodeOptions::new().shared_dictionary(&dictionary)).unwrap();
+
+        match SquishyPicture::decode_async(encoded.as_slice()).await {
+            Err(Error::MissingDictionary { id }) => assert_eq!(id, dictionary.id()),
+            other => panic!("expected a missing dictionary, got {:?}", other.err()),
+        }
+
+        let options = DecodeOptions::new().shared_dictionary(&dictionary);
+        let decoded = SquishyPicture::decode_async_with_options(encoded.as_slice(), &options).await.unwrap();
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+    }
+}
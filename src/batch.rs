@@ -0,0 +1,262 @@
+//! Encoding many images at once.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    vec::Vec,
+};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{
+    encoder::SqpEncoder,
+    options::EncodeOptions,
+    picture::{EncodeStats, Error},
+    SquishyPicture,
+};
+
+/// How a batch of images is spread across threads by [`encode_many`] and
+/// [`encode_many_to`].
+///
+/// Each image's own work, like transforming its channels, already runs in
+/// parallel on rayon's pool. Work from nested parallel loops is shared out
+/// among the pool's existing threads rather than spawning new ones, so
+/// encoding several images at once doesn't oversubscribe the machine.
+///
+/// Without the `parallel` feature, every choice encodes one image at a
+/// time on the calling thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parallelism {
+    /// Encode one image at a time, letting each use the whole global pool.
+    ///
+    /// Best for a few large images.
+    Inner,
+
+    /// Encode many images at once on the global pool.
+    ///
+    /// Best for many small images, which have little work to split up.
+    #[default]
+    Outer,
+
+    /// Encode many images at once on a dedicated pool of this many threads,
+    /// which also runs each image's own work.
+    ///
+    /// Use this to leave the rest of the machine free. At least one thread
+    /// is always used.
+    Bounded {
+        threads: usize,
+    },
+}
+
+/// Encode each picture into a new file at its path, returning the result of
+/// each one in the same order as the input.
+///
+/// A failure only affects its own item, the rest of the batch is still
+/// encoded. Files which fail to encode may be left partly written.
+///
+/// # Example
+/// ```no_run
+/// use sqp::{batch::Parallelism, options::EncodeOptions, ColorFormat, SquishyPicture};
+///
+/// let items = (0..4).map(|i| {
+///     let sqp = SquishyPicture::from_raw_lossless(16, 16, ColorFormat::Gray8, vec![i * 60; 16 * 16]);
+///     (sqp, format!("shade-{i}.sqp").into())
+/// });
+///
+/// for result in sqp::encode_many(items, &EncodeOptions::new(), Parallelism::Outer) {
+///     println!("{} bytes", result.unwrap().bytes_written);
+/// }
+/// ```
+pub fn encode_many<I: IntoIterator<Item = (SquishyPicture, PathBuf)>>(
+    items: I,
+    options: &EncodeOptions,
+    parallelism: Parallelism,
+) -> Vec<Result<EncodeStats, Error>> {
+    let items: Vec<_> = items.into_iter().collect();
+
+    run(items, options, parallelism, |encoder, (picture, path)| {
+        let mut output = BufWriter::new(File::create(path)?);
+        let stats = encoder.encode_with_stats(&picture, &mut output)?;
+        output.flush()?;
+
+        Ok(stats)
+    })
+}
+
+/// Encode each picture into its writer, returning the result of each one
+/// in the same order as the input.
+///
+/// A failure only affects its own item, the rest of the batch is still
+/// encoded.
+pub fn encode_many_to<W, I>(
+    items: I,
+    options: &EncodeOptions,
+    parallelism: Parallelism,
+) -> Vec<Result<EncodeStats, Error>>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = (SquishyPicture, W)>,
+{
+    let items: Vec<_> = items.into_iter().collect();
+
+    run(items, options, parallelism, |encoder, (picture, output)| {
+        encoder.encode_with_stats(&picture, output)
+    })
+}
+
+/// Call `encode` on every item with the given parallelism, giving each
+/// thread its own [`SqpEncoder`].
+#[cfg(feature = "parallel")]
+fn run<T, F>(
+    items: Vec<T>,
+    options: &EncodeOptions,
+    parallelism: Parallelism,
+    encode: F,
+) -> Vec<Result<EncodeStats, Error>>
+where
+    T: Send,
+    F: Fn(&mut SqpEncoder, T) -> Result<EncodeStats, Error> + Sync,
+{
+    let outer = |items: Vec<T>| {
+        items.into_par_iter()
+            .map_init(|| SqpEncoder::new(options.clone()), &encode)
+            .collect()
+    };
+
+    match parallelism {
+        Parallelism::Inner => {
+            let mut encoder = SqpEncoder::new(options.clone());
+            items.into_iter().map(|item| encode(&mut encoder, item)).collect()
+        },
+        Parallelism::Outer => outer(items),
+        Parallelism::Bounded { threads } => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads.max(1))
+                .build();
+
+            match pool {
+                Ok(pool) => pool.install(|| outer(items)),
+                // Creating threads can fail, so fall back on the global pool
+                Err(_) => outer(items),
+            }
+        },
+    }
+}
+
+/// Call `encode` on every item, one at a time on the calling thread.
+#[cfg(not(feature = "parallel"))]
+fn run<T, F>(
+    items: Vec<T>,
+    options: &EncodeOptions,
+    _parallelism: Parallelism,
+    encode: F,
+) -> Vec<Result<EncodeStats, Error>>
+where
+    F: Fn(&mut SqpEncoder, T) -> Result<EncodeStats, Error>,
+{
+    let mut encoder = SqpEncoder::new(options.clone());
+    items.into_iter().map(|item| encode(&mut encoder, item)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, vec};
+
+    use super::*;
+    use crate::{picture::Error, ColorFormat};
+
+    const ALL: [Parallelism; 4] = [
+        Parallelism::Inner,
+        Parallelism::Outer,
+        Parallelism::Bounded { threads: 2 },
+        Parallelism::Bounded { threads: 0 },
+    ];
+
+    /// Pictures of different sizes, so their encoded sizes differ.
+    fn pictures() -> Vec<SquishyPicture> {
+        (1..=12u32).map(|i| {
+            let bitmap = (0..i * i * 3).map(|b| (b * 37 / i) as u8).collect();
+            SquishyPicture::from_raw_lossy(i, i, ColorFormat::Rgb8, 60, bitmap)
+        }).collect()
+    }
+
+    #[test]
+    fn results_in_input_order() {
+        let expected: Vec<_> = pictures().iter().map(|p| p.encode_to_vec().unwrap()).collect();
+
+        for parallelism in ALL {
+            let mut outputs = vec![Vec::new(); expected.len()];
+            let results = encode_many_to(
+                pictures().into_iter().zip(outputs.iter_mut()),
+                &EncodeOptions::new(),
+                parallelism,
+            );
+
+            assert_eq!(results.len(), expected.len());
+            for ((result, output), expected) in results.into_iter().zip(&outputs).zip(&expected) {
+                assert_eq!(result.unwrap().bytes_written, expected.len());
+                assert_eq!(output, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_success_and_failure() {
+        for parallelism in ALL {
+            let mut pictures = pictures();
+            let widths: Vec<_> = pictures.iter().map(|p| p.width()).collect();
+
+            // Break every third picture
+            for picture in pictures.iter_mut().step_by(3) {
+                picture.bitmap.pop();
+            }
+
+            let mut outputs = vec![Vec::new(); pictures.len()];
+            let results = encode_many_to(
+                pictures.into_iter().zip(outputs.iter_mut()),
+                &EncodeOptions::new(),
+                parallelism,
+            );
+
+            for (i, (result, output)) in results.into_iter().zip(&outputs).enumerate() {
+                if i % 3 == 0 {
+                    assert!(matches!(result, Err(Error::SizeMismatch { .. })));
+                    assert!(output.is_empty());
+                } else {
+                    assert_eq!(result.unwrap().bytes_written, output.len());
+                    assert_eq!(SquishyPicture::decode(output.as_slice()).unwrap().width(), widths[i]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn files() {
+        let dir = env::temp_dir().join(format!("sqp-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let items = pictures().into_iter().enumerate().map(|(i, p)| {
+            // Odd items go into a directory which doesn't exist
+            let path = match i % 2 {
+                0 => dir.join(format!("{i}.sqp")),
+                _ => dir.join("missing").join(format!("{i}.sqp")),
+            };
+            (p, path)
+        });
+
+        let results = encode_many(items, &EncodeOptions::new(), Parallelism::Outer);
+        for (i, (result, picture)) in results.into_iter().zip(pictures()).enumerate() {
+            if i % 2 == 0 {
+                let written = fs::read(dir.join(format!("{i}.sqp"))).unwrap();
+                assert_eq!(result.unwrap().bytes_written, written.len());
+                assert_eq!(written, picture.encode_to_vec().unwrap());
+            } else {
+                assert!(matches!(result, Err(Error::IoError(_))));
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
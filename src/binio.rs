@@ -1,20 +1,19 @@
-use std::io::{Read, Write};
+use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use core::cmp::Reverse;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use crate::io::{self, Read, ReadExt, Write, WriteExt};
 
 /// A simple way to write individual bits to an input implementing [Write].
-pub struct BitWriter<'a, O: Write + WriteBytesExt> {
+pub struct BitWriter<'a, O: Write> {
     output: &'a mut O,
 
     current_byte: u8,
 
     byte_offset: usize,
     bit_offset: usize,
-
-    byte_size: usize,
 }
 
-impl<'a, O: Write + WriteBytesExt> BitWriter<'a, O> {
+impl<'a, O: Write> BitWriter<'a, O> {
     /// Create a new BitWriter wrapper around something which
     /// implements [Write].
     pub fn new(output: &'a mut O) -> Self {
@@ -25,29 +24,22 @@ impl<'a, O: Write + WriteBytesExt> BitWriter<'a, O> {
 
             byte_offset: 0,
             bit_offset: 0,
-
-            byte_size: 0,
         }
     }
 
-    /// Get the number of whole bytes written to the stream.
-    pub fn byte_size(&self) -> usize {
-        self.byte_size
-    }
-
-    /// Get the bit offset within the current byte.
-    pub fn bit_offset(&self) -> u8 {
-        self.bit_offset as u8
-    }
-
-    /// Check if the stream is aligned to a byte.
-    pub fn aligned(&self) -> bool {
-        self.bit_offset() == 0
+    /// Get the total number of bits written since the writer was created,
+    /// including any padding from [`BitWriter::flush`].
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn bit_position(&self) -> u64 {
+        self.byte_offset as u64 * 8 + self.bit_offset as u64
     }
 
     /// Align the writer to the nearest byte by padding with zero bits.
+    ///
+    /// This always writes the current byte, even if it is empty.
     pub fn flush(&mut self) {
         self.byte_offset += 1;
+        self.bit_offset = 0;
 
         // Write out the current byte unfinished
         self.output.write_u8(self.current_byte).unwrap();
@@ -62,7 +54,7 @@ impl<'a, O: Write + WriteBytesExt> BitWriter<'a, O> {
             panic!("Must write 1 or more bits.")
         }
 
-        if bit_len % 8 == 0 && self.bit_offset == 0 {
+        if bit_len.is_multiple_of(8) && self.bit_offset == 0 {
             self.write(data, bit_len / 8);
             return;
         }
@@ -83,8 +75,6 @@ impl<'a, O: Write + WriteBytesExt> BitWriter<'a, O> {
                 self.current_byte = 0;
             }
         }
-
-        self.byte_size = self.byte_offset + (self.bit_offset + 7) / 8;
     }
 
     /// Write some bytes to the output.
@@ -99,30 +89,64 @@ impl<'a, O: Write + WriteBytesExt> BitWriter<'a, O> {
             .write_all(&data.to_le_bytes()[..byte_len])
             .unwrap();
         self.byte_offset += byte_len;
+    }
+
+    /// Write a Huffman code from `canonical_codes` of the given length,
+    /// starting from its most significant bit.
+    pub fn write_huffman(&mut self, code: u16, len: u8) {
+        self.write_bit((code.reverse_bits() >> (16 - len)) as u64, len as usize);
+    }
+
+    /// Write `n` in unary, as `n` zero bits followed by a one bit.
+    pub fn write_unary(&mut self, n: u64) {
+        let mut zeroes = n;
+        while zeroes >= 64 {
+            self.write_bit(0, 64);
+            zeroes -= 64;
+        }
+
+        self.write_bit(1 << zeroes, zeroes as usize + 1);
+    }
 
-        self.byte_size = self.byte_offset + (self.bit_offset + 7) / 8;
+    /// Write a Golomb–Rice code with parameter `k`, which must be below 64.
+    ///
+    /// This is the quotient `value >> k` in unary, see
+    /// [`BitWriter::write_unary`], followed by the remainder, the low `k`
+    /// bits of `value`, in the same order as [`BitWriter::write_bit`]. A
+    /// `k` of 0 writes no remainder.
+    pub fn write_rice(&mut self, value: u64, k: u32) {
+        self.write_unary(value >> k);
+        if k > 0 {
+            self.write_bit(value & ((1 << k) - 1), k as usize);
+        }
     }
 }
 
 /// A simple way to read individual bits from an input implementing [Read].
-pub struct BitReader<'a, I: Read + ReadBytesExt> {
+pub struct BitReader<'a, I: Read> {
     input: &'a mut I,
 
+    /// The whole input, for readers which can seek, see
+    /// [`BitReader::seekable`].
+    #[cfg(any(test, feature = "unstable"))]
+    origin: Option<I>,
+
     current_byte: Option<u8>,
 
     byte_offset: usize,
     bit_offset: usize,
 }
 
-impl<'a, I: Read + ReadBytesExt> BitReader<'a, I> {
+impl<'a, I: Read> BitReader<'a, I> {
     /// Create a new BitReader wrapper around something which
-    /// implements [Write].
+    /// implements [Read].
     pub fn new(input: &'a mut I) -> Self {
-        let first = input.read_u8().unwrap();
         Self {
             input,
+            #[cfg(any(test, feature = "unstable"))]
+            origin: None,
 
-            current_byte: Some(first),
+            current_byte: None,
 
             byte_offset: 0,
             bit_offset: 0,
@@ -134,51 +158,739 @@ impl<'a, I: Read + ReadBytesExt> BitReader<'a, I> {
         self.byte_offset
     }
 
+    /// Get the total number of bits read since the reader was created, or
+    /// the position last sought to.
+    pub fn bit_position(&self) -> u64 {
+        self.byte_offset as u64 * 8 + self.bit_offset as u64
+    }
+
     /// Read some bits from the input.
-    pub fn read_bit(&mut self, bit_len: usize) -> u64 {
+    pub fn read_bit(&mut self, bit_len: usize) -> Result<u64, io::Error> {
         if bit_len > 64 {
             panic!("Cannot read more than 64 bits at once.")
         } else if bit_len == 0 {
             panic!("Must read 1 or more bits.")
         }
 
-        if bit_len % 8 == 0 && self.bit_offset == 0 {
+        if bit_len.is_multiple_of(8) && self.bit_offset == 0 {
             return self.read(bit_len / 8);
         }
 
         let mut result = 0;
         for i in 0..bit_len {
-            let bit_value = ((self.current_byte.unwrap() as usize >> self.bit_offset) & 1) as u64;
+            let current_byte = match self.current_byte {
+                Some(byte) => byte,
+                None => *self.current_byte.insert(self.input.read_u8()?),
+            };
+
+            let bit_value = ((current_byte as usize >> self.bit_offset) & 1) as u64;
             self.bit_offset += 1;
 
             if self.bit_offset == 8 {
                 self.byte_offset += 1;
                 self.bit_offset = 0;
 
-                self.current_byte = Some(self.input.read_u8().unwrap());
+                self.current_byte = None;
             }
 
             result |= bit_value << i;
         }
 
-        result
+        Ok(result)
     }
 
     /// Read some bytes from the input.
-    pub fn read(&mut self, byte_len: usize) -> u64 {
+    pub fn read(&mut self, byte_len: usize) -> Result<u64, io::Error> {
         if byte_len > 8 {
             panic!("Cannot read more than 8 bytes at once.")
         } else if byte_len == 0 {
             panic!("Must read 1 or more bytes")
         }
 
-        let mut padded_slice = vec![0u8; byte_len];
-        self.input.read_exact(&mut padded_slice).unwrap();
+        let mut padded_slice = [0u8; 8];
+        self.input.read_exact(&mut padded_slice[..byte_len])?;
         self.byte_offset += byte_len;
 
-        let extra_length = padded_slice.len() - byte_len;
-        padded_slice.extend_from_slice(&vec![0u8; extra_length]);
+        Ok(u64::from_le_bytes(padded_slice))
+    }
+
+    /// Read a number written by [`BitWriter::write_unary`], counting zero
+    /// bits up to the first one bit.
+    pub fn read_unary(&mut self) -> Result<u64, io::Error> {
+        let mut n = 0;
+        while self.read_bit(1)? == 0 {
+            n += 1;
+        }
+
+        Ok(n)
+    }
+
+    /// Read a Golomb–Rice code written by [`BitWriter::write_rice`] with
+    /// the same `k`, which must be below 64.
+    ///
+    /// Quotient bits which don't fit in a [`u64`] are lost, so callers which
+    /// need to reject oversized values should read the quotient with
+    /// [`BitReader::read_unary`] and check it first.
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn read_rice(&mut self, k: u32) -> Result<u64, io::Error> {
+        let quotient = self.read_unary()?;
+        let remainder = if k > 0 { self.read_bit(k as usize)? } else { 0 };
+
+        Ok(quotient << k | remainder)
+    }
+}
+
+impl<'a, 'b> BitReader<'a, &'b [u8]> {
+    /// Create a BitReader over a byte slice which can move to any bit with
+    /// [`BitReader::seek_to_bit`]. Positions are from the start of the
+    /// slice as it is now.
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn seekable(input: &'a mut &'b [u8]) -> Self {
+        let origin = *input;
+        Self {
+            origin: Some(origin),
+            ..Self::new(input)
+        }
+    }
+
+    /// Move to `position` bits from the start, so the next bit read is the
+    /// one there. Seeking past the end of the input fails, and leaves the
+    /// reader where it was.
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] if the reader wasn't made
+    /// with [`BitReader::seekable`].
+    #[cfg(any(test, feature = "unstable"))]
+    pub fn seek_to_bit(&mut self, position: u64) -> Result<(), io::Error> {
+        let Some(origin) = self.origin else {
+            return Err(io::ErrorKind::Unsupported.into())
+        };
+
+        let byte = usize::try_from(position / 8).unwrap_or(usize::MAX);
+        let bit = (position % 8) as usize;
+        let Some(mut rest) = origin.get(byte..) else {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        };
+
+        // Part way through a byte, it has to be read already
+        let current_byte = match bit {
+            0 => None,
+            _ => Some(rest.read_u8()?),
+        };
+
+        *self.input = rest;
+        self.current_byte = current_byte;
+        self.byte_offset = byte;
+        self.bit_offset = bit;
+
+        Ok(())
+    }
+}
+
+/// Picks the Golomb–Rice parameter for values whose average drifts, as the
+/// log of the running mean of the values so far. The encoder and decoder
+/// each keep one and update it with the same values, so `k` is never
+/// stored.
+///
+/// The totals are halved every [`AdaptiveRice::RESET`] values, so older
+/// values count for less.
+#[cfg(any(test, feature = "unstable"))]
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveRice {
+    total: u64,
+    count: u64,
+}
+
+#[cfg(any(test, feature = "unstable"))]
+impl AdaptiveRice {
+    /// How many values are seen before the totals are halved.
+    pub const RESET: u64 = 64;
+
+    /// Start from a guess at the mean, as if one value of it had been seen.
+    pub fn new(mean: u64) -> Self {
+        Self { total: mean, count: 1 }
+    }
+
+    /// The parameter for the next value, the smallest `k` for which the
+    /// count shifted left by `k` is at least the total, so `1 << k` is
+    /// about the mean.
+    pub fn k(&self) -> u32 {
+        let mut k = 0;
+        while k < 63 && self.count << k < self.total {
+            k += 1;
+        }
+
+        k
+    }
+
+    /// Count a value towards the mean.
+    pub fn update(&mut self, value: u64) {
+        self.total = self.total.saturating_add(value);
+        if self.count == Self::RESET {
+            self.total >>= 1;
+            self.count >>= 1;
+        }
+        self.count += 1;
+    }
+
+    /// Write a value with the current parameter, then update the mean.
+    pub fn write<O: Write>(&mut self, bit_io: &mut BitWriter<O>, value: u64) {
+        bit_io.write_rice(value, self.k());
+        self.update(value);
+    }
+
+    /// Read a value written by [`AdaptiveRice::write`], then update the
+    /// mean.
+    pub fn read<I: Read>(&mut self, bit_io: &mut BitReader<I>) -> Result<u64, io::Error> {
+        let value = bit_io.read_rice(self.k())?;
+        self.update(value);
+
+        Ok(value)
+    }
+}
+
+/// The longest code [`huffman_lengths`] gives a symbol, so every length
+/// fits in four bits.
+pub const MAX_HUFFMAN_LEN: u8 = 15;
+
+/// The code lengths of a Huffman code for symbols with the given
+/// frequencies, none longer than [`MAX_HUFFMAN_LEN`]. Symbols which never
+/// occur get a length of 0, and a lone symbol gets a length of 1.
+///
+/// When the code would be too long, the frequencies are halved and the code
+/// is built again, which flattens it until it fits. There must be no more
+/// than `1 << MAX_HUFFMAN_LEN` symbols.
+pub fn huffman_lengths(frequencies: &[u64]) -> Vec<u8> {
+    let mut frequencies = frequencies.to_vec();
+    loop {
+        let depths = huffman_depths(&frequencies);
+        if depths.iter().all(|d| *d <= MAX_HUFFMAN_LEN as usize) {
+            return depths.into_iter().map(|d| d as u8).collect()
+        }
+
+        for frequency in frequencies.iter_mut().filter(|f| **f > 0) {
+            *frequency = (*frequency / 2).max(1);
+        }
+    }
+}
+
+/// The depth of each symbol in a Huffman tree built from the frequencies,
+/// without any limit.
+fn huffman_depths(frequencies: &[u64]) -> Vec<usize> {
+    let mut heap: BinaryHeap<_> = frequencies.iter()
+        .enumerate()
+        .filter(|(_, f)| **f > 0)
+        .map(|(i, f)| Reverse((*f, i)))
+        .collect();
+
+    let mut depths = vec![0; frequencies.len()];
+    if heap.len() == 1 {
+        depths[heap.peek().unwrap().0.1] = 1;
+    }
+    if heap.len() <= 1 {
+        return depths
+    }
+
+    // The parent of each node, with the symbols first and then the other
+    // nodes in the order they're made, so parents always come later
+    let mut parents = vec![None; frequencies.len()];
+    while let (Some(Reverse((a, i))), Some(Reverse((b, j)))) = (heap.pop(), heap.pop()) {
+        let node = parents.len();
+        parents.push(None);
+        parents[i] = Some(node);
+        parents[j] = Some(node);
+        heap.push(Reverse((a + b, node)));
+    }
+
+    depths.resize(parents.len(), 0);
+    for node in (0..parents.len()).rev() {
+        if let Some(parent) = parents[node] {
+            depths[node] = depths[parent] + 1;
+        }
+    }
+    depths.truncate(frequencies.len());
+
+    depths
+}
+
+/// The canonical Huffman code of each symbol, given the code lengths.
+///
+/// Codes are assigned in order of length and then symbol, each one more
+/// than the one before, so only the lengths need to be stored to rebuild
+/// them. Symbols with a length of 0 get no code.
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut codes = vec![0; lengths.len()];
+    let mut code = 0u32;
+    for len in 1..=MAX_HUFFMAN_LEN {
+        for (symbol, _) in lengths.iter().enumerate().filter(|(_, l)| **l == len) {
+            codes[symbol] = code as u16;
+            code += 1;
+        }
+        code <<= 1;
+    }
+
+    codes
+}
+
+/// Write Huffman code lengths of up to [`MAX_HUFFMAN_LEN`] in four bits
+/// each. A length of 0 is followed by four more bits counting how many of
+/// the following symbols also have a length of 0, so unused symbols take
+/// little space.
+pub fn write_huffman_lengths<O: Write>(bit_io: &mut BitWriter<O>, lengths: &[u8]) {
+    let mut i = 0;
+    while i < lengths.len() {
+        bit_io.write_bit(lengths[i] as u64, 4);
+        i += 1;
+
+        if lengths[i - 1] == 0 {
+            let run = lengths[i..].iter().take(15).take_while(|l| **l == 0).count();
+            bit_io.write_bit(run as u64, 4);
+            i += run;
+        }
+    }
+}
+
+/// Read `count` code lengths written by [`write_huffman_lengths`], or
+/// [`None`] if the input ends or a run of zeroes goes past `count`.
+pub fn read_huffman_lengths<I: Read>(bit_io: &mut BitReader<I>, count: usize) -> Option<Vec<u8>> {
+    let mut lengths = Vec::with_capacity(count);
+    while lengths.len() < count {
+        let len = bit_io.read_bit(4).ok()? as u8;
+        lengths.push(len);
+
+        if len == 0 {
+            let run = bit_io.read_bit(4).ok()? as usize;
+            if lengths.len() + run > count {
+                return None
+            }
+            lengths.resize(lengths.len() + run, 0);
+        }
+    }
+
+    Some(lengths)
+}
+
+/// Reads symbols written with a canonical Huffman code.
+pub struct HuffmanDecoder {
+    /// The number of codes of each length.
+    counts: [u16; MAX_HUFFMAN_LEN as usize + 1],
+
+    /// The symbols in the order of their codes.
+    symbols: Vec<u16>,
+}
+
+impl HuffmanDecoder {
+    /// Create a decoder for the code with the given lengths, or return
+    /// [`None`] if any are longer than [`MAX_HUFFMAN_LEN`] or there are
+    /// more codes of some length than can fit.
+    ///
+    /// The code doesn't need to be complete, reading a code which is not
+    /// assigned to any symbol fails instead.
+    pub fn new(lengths: &[u8]) -> Option<Self> {
+        let mut counts = [0u16; MAX_HUFFMAN_LEN as usize + 1];
+        for len in lengths {
+            *counts.get_mut(*len as usize)? += 1;
+        }
+        counts[0] = 0;
+
+        // The number of codes still free at each length
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = left * 2 - *count as i32;
+            if left < 0 {
+                return None
+            }
+        }
+
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for len in 1..=MAX_HUFFMAN_LEN {
+            symbols.extend((0..lengths.len()).filter(|s| lengths[*s] == len).map(|s| s as u16));
+        }
+
+        Some(Self { counts, symbols })
+    }
+
+    /// Read one symbol, or return [`None`] if the input ends or the code
+    /// is not assigned to any symbol.
+    pub fn read_symbol<I: Read>(&self, bit_io: &mut BitReader<I>) -> Option<usize> {
+        // The code read so far, and the first code and symbol index of the
+        // current length
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for count in &self.counts[1..] {
+            code |= bit_io.read_bit(1).ok()? as usize;
+            let count = *count as usize;
+            if code - first < count {
+                return Some(self.symbols[index + code - first] as usize)
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        None
+    }
+}
+
+/// Append a signed integer to the output as a zigzag encoded
+/// [LEB128](https://en.wikipedia.org/wiki/LEB128) varint.
+pub fn write_varint(output: &mut Vec<u8>, value: i16) {
+    let mut n = (((value as i64) << 1) ^ ((value as i64) >> 63)) as u64;
+
+    while n >= 0x80 {
+        output.push(0x80 | n as u8);
+        n >>= 7;
+    }
+
+    output.push(n as u8);
+}
+
+/// Read a signed integer written by [`write_varint`] from the start of the
+/// input, returning it and the number of bytes it took up.
+///
+/// Returns [`None`] if the input ends inside the varint or it does not fit
+/// in an [`i16`].
+pub fn read_varint(input: &[u8]) -> Option<(i16, usize)> {
+    let mut result = 0u64;
+
+    for (i, byte) in input.iter().enumerate() {
+        // The tenth byte can only hold the single remaining bit of a u64
+        if i == 9 && *byte >= 2 {
+            return None
+        }
+
+        result |= ((byte & 0x7F) as u64) << (7 * i);
+
+        if byte & 0x80 == 0 || i == 9 {
+            let value = (result >> 1) as i64 ^ -((result & 1) as i64);
+            return i16::try_from(value).ok().map(|v| (v, i + 1))
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_known_values() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (-64, &[0x7F]),
+            (64, &[0x80, 0x01]),
+            (i16::MIN, &[0xFF, 0xFF, 0x03]),
+        ] {
+            let mut output = Vec::new();
+            write_varint(&mut output, value);
+            assert_eq!(output, encoded);
+        }
+    }
+
+    #[test]
+    fn varint_small_values_are_one_byte() {
+        // Coefficients near zero are the common case, so negative ones must
+        // be as cheap as positive ones
+        for value in -64..=63 {
+            let mut output = Vec::new();
+            write_varint(&mut output, value);
+            assert_eq!(output.len(), 1, "{value} took {} bytes", output.len());
+        }
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut output = Vec::new();
+        for value in i16::MIN..=i16::MAX {
+            write_varint(&mut output, value);
+        }
+
+        let mut offset = 0;
+        for value in i16::MIN..=i16::MAX {
+            let (decoded, len) = read_varint(&output[offset..]).unwrap();
+            assert_eq!(decoded, value);
+            offset += len;
+        }
+        assert_eq!(offset, output.len());
+    }
+
+    #[test]
+    fn varint_invalid() {
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0xFF, 0xFF, 0x04]), None);
+    }
+
+    #[test]
+    fn rice_known_layout() {
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+
+        // Three zeroes and a one, then the quotient 2 as two zeroes and a
+        // one, and the remainder 1 in one bit
+        bit_io.write_unary(3);
+        bit_io.write_rice(5, 1);
+        bit_io.flush();
+        assert_eq!(output[0], 0b1100_1000);
+
+        // A quotient of 70 crosses a whole 64 bit word of zeroes
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        bit_io.write_rice(70 << 3 | 0b101, 3);
+        bit_io.flush();
+        assert_eq!(output, [0, 0, 0, 0, 0, 0, 0, 0, 0b1100_0000, 0b10]);
+    }
+
+    #[test]
+    fn rice_round_trip_exhaustive() {
+        for k in 0..=10 {
+            let mut output = Vec::new();
+            let mut bit_io = BitWriter::new(&mut output);
+            for value in 0..2000 {
+                bit_io.write_rice(value, k);
+            }
+            bit_io.write_unary(200);
+            bit_io.flush();
+
+            let mut input = output.as_slice();
+            let mut bit_io = BitReader::new(&mut input);
+            for value in 0..2000 {
+                assert_eq!(bit_io.read_rice(k).unwrap(), value, "k = {k}");
+            }
+            assert_eq!(bit_io.read_unary().unwrap(), 200);
+        }
+    }
+
+    #[test]
+    fn rice_round_trip_random() {
+        // Random values and parameters, with the quotient kept short
+        let mut state = 0x2545F491u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let codes: Vec<(u64, u32)> = (0..5000).map(|_| {
+            let k = (next() % 64) as u32;
+            let value = next() >> (64 - (k + 6).min(64));
+            (value, k)
+        }).collect();
+
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        for (value, k) in &codes {
+            bit_io.write_rice(*value, *k);
+        }
+        bit_io.flush();
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        for (value, k) in &codes {
+            assert_eq!(bit_io.read_rice(*k).unwrap(), *value, "k = {k}");
+        }
+    }
+
+    #[test]
+    fn rice_truncated() {
+        let mut input: &[u8] = &[0];
+        assert!(BitReader::new(&mut input).read_unary().is_err());
+
+        let mut input: &[u8] = &[0b1];
+        let mut bit_io = BitReader::new(&mut input);
+        assert!(bit_io.read_rice(10).is_err());
+    }
+
+    #[test]
+    fn adaptive_rice() {
+        // The parameter follows the mean up and back down
+        let mut rice = AdaptiveRice::new(2);
+        assert_eq!(rice.k(), 1);
+        for _ in 0..200 {
+            rice.update(1000);
+        }
+        assert_eq!(rice.k(), 10);
+        for _ in 0..1000 {
+            rice.update(3);
+        }
+        assert_eq!(rice.k(), 2);
+
+        let values: Vec<u64> = (0..3000).map(|i: u64| (i * 7919) % (1 + i / 10)).collect();
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        let mut rice = AdaptiveRice::new(4);
+        for value in &values {
+            rice.write(&mut bit_io, *value);
+        }
+        bit_io.flush();
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        let mut rice = AdaptiveRice::new(4);
+        for value in &values {
+            assert_eq!(rice.read(&mut bit_io).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn bit_positions() {
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        assert_eq!(bit_io.bit_position(), 0);
+        bit_io.write_bit(0b101, 3);
+        assert_eq!(bit_io.bit_position(), 3);
+        bit_io.write_bit(0xABCD, 16);
+        assert_eq!(bit_io.bit_position(), 19);
+        bit_io.flush();
+        assert_eq!(bit_io.bit_position(), 24);
+        bit_io.write(0x12, 1);
+        assert_eq!(bit_io.bit_position(), 32);
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        assert_eq!(bit_io.read_bit(3).unwrap(), 0b101);
+        assert_eq!(bit_io.bit_position(), 3);
+        assert_eq!(bit_io.read_bit(16).unwrap(), 0xABCD);
+        assert_eq!(bit_io.bit_position(), 19);
+    }
+
+    #[test]
+    fn seek_matches_reference() {
+        let mut state = 0x2545F491u32;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let data: Vec<u8> = (0..64).map(|_| next() as u8).collect();
+        let bits: Vec<u64> = (0..data.len() * 8).map(|i| (data[i / 8] >> (i % 8)) as u64 & 1).collect();
+
+        // Interleave reads of random lengths with seeks to random bits
+        let mut input = data.as_slice();
+        let mut bit_io = BitReader::seekable(&mut input);
+        let mut position = 0;
+        for _ in 0..2000 {
+            if next() % 3 == 0 {
+                position = next() as usize % (bits.len() + 1);
+                bit_io.seek_to_bit(position as u64).unwrap();
+            }
+
+            let len = 1 + next() as usize % 24;
+            let expected = (position + len <= bits.len())
+                .then(|| (0..len).map(|i| bits[position + i] << i).sum::<u64>());
+            match (bit_io.read_bit(len), expected) {
+                (Ok(value), Some(expected)) => {
+                    assert_eq!(value, expected, "{len} bits at {position}");
+                    position += len;
+                },
+                (Err(_), None) => bit_io.seek_to_bit(position as u64).unwrap(),
+                (value, expected) => panic!("{value:?} vs {expected:?} reading {len} bits at {position}"),
+            }
+            assert_eq!(bit_io.bit_position(), position as u64);
+        }
+
+        // Only up to the end of the input
+        assert!(bit_io.seek_to_bit(bits.len() as u64 + 1).is_err());
+        assert!(bit_io.seek_to_bit(bits.len() as u64 + 8).is_err());
+        assert_eq!(bit_io.bit_position(), position as u64);
+        bit_io.seek_to_bit(bits.len() as u64).unwrap();
+        assert!(bit_io.read_bit(1).is_err());
+
+        // Only readers made to seek can
+        let mut input = data.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        assert_eq!(bit_io.seek_to_bit(0).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn huffman_known_lengths() {
+        assert_eq!(huffman_lengths(&[5, 9, 12, 13, 16, 45]), [4, 4, 3, 3, 3, 1]);
+        assert_eq!(huffman_lengths(&[0, 7, 0]), [0, 1, 0]);
+        assert_eq!(huffman_lengths(&[0, 0]), [0, 0]);
+        assert_eq!(huffman_lengths(&[1, 1, 1, 1]), [2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn huffman_lengths_limited() {
+        // Fibonacci frequencies make the deepest possible tree
+        let mut frequencies = vec![1u64, 1];
+        while frequencies.len() < 40 {
+            frequencies.push(frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2]);
+        }
+
+        let lengths = huffman_lengths(&frequencies);
+        assert_eq!(*lengths.iter().max().unwrap(), MAX_HUFFMAN_LEN);
+
+        // The code must still be prefix free
+        let kraft: u64 = lengths.iter().map(|l| 1 << (MAX_HUFFMAN_LEN - l)).sum();
+        assert!(kraft <= 1 << MAX_HUFFMAN_LEN);
+    }
+
+    #[test]
+    fn canonical_known_codes() {
+        // The example from RFC 1951, section 3.2.2
+        let lengths = [3, 3, 3, 3, 3, 2, 4, 4];
+        assert_eq!(
+            canonical_codes(&lengths),
+            [0b010, 0b011, 0b100, 0b101, 0b110, 0b00, 0b1110, 0b1111],
+        );
+
+        // Unused symbols don't take up a code
+        assert_eq!(canonical_codes(&[2, 0, 1, 0, 2]), [0b10, 0, 0b0, 0, 0b11]);
+        assert_eq!(canonical_codes(&[0, 1]), [0, 0]);
+    }
+
+    #[test]
+    fn huffman_round_trip() {
+        let symbols: Vec<usize> = (0..2000).map(|i: usize| (i * i) % 37 % (i % 11 + 1)).collect();
+        let mut frequencies = vec![0; 40];
+        for symbol in &symbols {
+            frequencies[*symbol] += 1;
+        }
+        let lengths = huffman_lengths(&frequencies);
+        let codes = canonical_codes(&lengths);
+
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        write_huffman_lengths(&mut bit_io, &lengths);
+        for symbol in &symbols {
+            bit_io.write_huffman(codes[*symbol], lengths[*symbol]);
+        }
+        bit_io.flush();
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        assert_eq!(read_huffman_lengths(&mut bit_io, lengths.len()).unwrap(), lengths);
+        let decoder = HuffmanDecoder::new(&lengths).unwrap();
+        for symbol in &symbols {
+            assert_eq!(decoder.read_symbol(&mut bit_io), Some(*symbol));
+        }
+    }
 
-        u64::from_le_bytes(padded_slice.try_into().unwrap())
+    #[test]
+    fn huffman_invalid() {
+        // Three codes of length 1, and a length too long to store
+        assert!(HuffmanDecoder::new(&[1, 1, 1]).is_none());
+        assert!(HuffmanDecoder::new(&[16, 1]).is_none());
+
+        // A lone code of length 1 leaves the other code unassigned
+        let decoder = HuffmanDecoder::new(&[0, 1]).unwrap();
+        let mut input: &[u8] = &[0b10];
+        let mut bit_io = BitReader::new(&mut input);
+        assert_eq!(decoder.read_symbol(&mut bit_io), Some(1));
+        assert_eq!(decoder.read_symbol(&mut bit_io), None);
+
+        // A run of zeroes past the end
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        write_huffman_lengths(&mut bit_io, &[0; 10]);
+        bit_io.flush();
+        let mut input = output.as_slice();
+        assert_eq!(read_huffman_lengths(&mut BitReader::new(&mut input), 5), None);
     }
 }
@@ -0,0 +1,515 @@
+//! A C interface for encoding and decoding SQP images from non-Rust code.
+//!
+//! The matching header is in `include/sqp.h`, and can be regenerated with
+//! `cbindgen --config cbindgen.toml --output include/sqp.h`. The `sqp-capi`
+//! crate in `capi/` builds this module into a static and a shared library.
+//!
+//! Every function returns an [`SqpErrorCode`]. When it is not
+//! [`SqpErrorCode::Ok`], a description of the error can be retrieved with
+//! [`sqp_last_error_message`]. Panics never cross the boundary; they are
+//! caught and reported as [`SqpErrorCode::Panic`].
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{ColorFormat, CompressionType, SquishyPicture};
+
+/// Result of a C API call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqpErrorCode {
+    /// The call succeeded.
+    Ok = 0,
+
+    /// A required pointer argument was null.
+    NullPointer = 1,
+
+    /// An argument was out of range, such as dimensions which overflow.
+    InvalidArgument = 2,
+
+    /// The input could not be decoded.
+    DecodeFailed = 3,
+
+    /// The image could not be encoded.
+    EncodeFailed = 4,
+
+    /// The library panicked. This is always a bug.
+    Panic = 5,
+}
+
+/// The format of bytes in the image. Mirrors [`ColorFormat`].
+///
+/// The values are part of the ABI, and never change.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqpColorFormat {
+    /// RGBA, 8 bits per channel.
+    Rgba8 = 0,
+
+    /// RGB, 8 bits per channel.
+    Rgb8 = 1,
+
+    /// Grayscale with alpha, 8 bits per channel.
+    GrayA8 = 2,
+
+    /// Grayscale, 8 bits per channel.
+    Gray8 = 3,
+
+    /// Grayscale, 4 bits per channel, two pixels to a byte.
+    Gray4 = 4,
+}
+
+/// The type of compression used in the image. Mirrors [`CompressionType`].
+///
+/// The values are part of the ABI, and never change.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqpCompression {
+    /// No compression, the raw bitmap.
+    None = 0,
+
+    /// Lossless LZW compression.
+    Lossless = 1,
+
+    /// Lossy Discrete Cosine Transform compression.
+    LossyDct = 2,
+
+    /// Lossless compression with a sliding window.
+    LosslessV2 = 3,
+
+    /// Lossless compression with the Burrows–Wheeler transform.
+    LosslessBwt = 4,
+
+    /// Prediction from neighbouring pixels, lossless when `near` is 0.
+    Predictive = 5,
+}
+
+/// Information about a decoded image.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SqpImageInfo {
+    /// Width of the image in pixels.
+    pub width: u32,
+
+    /// Height of the image in pixels.
+    pub height: u32,
+
+    /// Format of the decoded pixels.
+    pub color_format: SqpColorFormat,
+
+    /// Compression the image was stored with.
+    pub compression: SqpCompression,
+
+    /// Quality of lossy images, `near` of predictive ones, 0 otherwise.
+    pub quality: u8,
+
+    /// Length of the decoded pixel buffer in bytes.
+    pub pixels_len: usize,
+}
+
+impl From<ColorFormat> for SqpColorFormat {
+    fn from(value: ColorFormat) -> Self {
+        match value {
+            ColorFormat::Rgba8 => Self::Rgba8,
+            ColorFormat::Rgb8 => Self::Rgb8,
+            ColorFormat::GrayA8 => Self::GrayA8,
+            ColorFormat::Gray8 => Self::Gray8,
+            ColorFormat::Gray4 => Self::Gray4,
+        }
+    }
+}
+
+impl From<SqpColorFormat> for ColorFormat {
+    fn from(value: SqpColorFormat) -> Self {
+        match value {
+            SqpColorFormat::Rgba8 => Self::Rgba8,
+            SqpColorFormat::Rgb8 => Self::Rgb8,
+            SqpColorFormat::GrayA8 => Self::GrayA8,
+            SqpColorFormat::Gray8 => Self::Gray8,
+            SqpColorFormat::Gray4 => Self::Gray4,
+        }
+    }
+}
+
+impl TryFrom<u32> for SqpColorFormat {
+    type Error = u32;
+
+    /// Check a value passed in from C, which may be any integer.
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Rgba8,
+            1 => Self::Rgb8,
+            2 => Self::GrayA8,
+            3 => Self::Gray8,
+            4 => Self::Gray4,
+            v => return Err(v),
+        })
+    }
+}
+
+impl From<CompressionType> for SqpCompression {
+    fn from(value: CompressionType) -> Self {
+        match value {
+            CompressionType::None => Self::None,
+            CompressionType::Lossless => Self::Lossless,
+            CompressionType::LossyDct => Self::LossyDct,
+            CompressionType::LosslessV2 => Self::LosslessV2,
+            CompressionType::LosslessBwt => Self::LosslessBwt,
+            CompressionType::Predictive => Self::Predictive,
+        }
+    }
+}
+
+impl From<SqpCompression> for CompressionType {
+    fn from(value: SqpCompression) -> Self {
+        match value {
+            SqpCompression::None => Self::None,
+            SqpCompression::Lossless => Self::Lossless,
+            SqpCompression::LossyDct => Self::LossyDct,
+            SqpCompression::LosslessV2 => Self::LosslessV2,
+            SqpCompression::LosslessBwt => Self::LosslessBwt,
+            SqpCompression::Predictive => Self::Predictive,
+        }
+    }
+}
+
+impl TryFrom<u32> for SqpCompression {
+    type Error = u32;
+
+    /// Check a value passed in from C, which may be any integer.
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::None,
+            1 => Self::Lossless,
+            2 => Self::LossyDct,
+            3 => Self::LosslessV2,
+            4 => Self::LosslessBwt,
+            5 => Self::Predictive,
+            v => return Err(v),
+        })
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Record an error message for [`sqp_last_error_message`] and return its code.
+fn set_error(code: SqpErrorCode, message: String) -> SqpErrorCode {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+
+    code
+}
+
+/// Describe an error along with each error which caused it.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+
+    let mut source = error.source();
+    while let Some(e) = source {
+        message = format!("{message}: {e}");
+        source = e.source();
+    }
+
+    message
+}
+
+/// Run a closure, converting any panic into [`SqpErrorCode::Panic`].
+fn guard<F: FnOnce() -> SqpErrorCode>(f: F) -> SqpErrorCode {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(code) => code,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+
+            set_error(SqpErrorCode::Panic, format!("panic: {message}"))
+        },
+    }
+}
+
+/// Size of the length prefix stored in front of buffers handed out to C.
+const PREFIX_LEN: usize = size_of::<usize>();
+
+/// Hand a buffer over to C. It must be released with [`sqp_free`].
+fn into_c_buffer(data: &[u8]) -> *mut u8 {
+    let mut buffer = Vec::with_capacity(PREFIX_LEN + data.len());
+    buffer.extend_from_slice(&data.len().to_ne_bytes());
+    buffer.extend_from_slice(data);
+
+    let buffer = Box::into_raw(buffer.into_boxed_slice()) as *mut u8;
+
+    // SAFETY: The buffer is at least `PREFIX_LEN` bytes long
+    unsafe { buffer.add(PREFIX_LEN) }
+}
+
+/// Decode an SQP image from memory.
+///
+/// On success, `out_info` is filled in and `out_pixels` points to the
+/// decoded pixels, which must be released with [`sqp_free`].
+///
+/// # Safety
+/// `data` must point to `len` readable bytes, and `out_info` and
+/// `out_pixels` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sqp_decode(
+    data: *const u8,
+    len: usize,
+    out_info: *mut SqpImageInfo,
+    out_pixels: *mut *mut u8,
+) -> SqpErrorCode {
+    guard(|| {
+        if data.is_null() || out_info.is_null() || out_pixels.is_null() {
+            return set_error(SqpErrorCode::NullPointer, "null pointer argument".to_string())
+        }
+
+        // SAFETY: The caller guarantees `data` points to `len` bytes
+        let input = unsafe { slice::from_raw_parts(data, len) };
+
+        let picture = match SquishyPicture::decode(input) {
+            Ok(p) => p,
+            Err(e) => return set_error(SqpErrorCode::DecodeFailed, error_chain(&e)),
+        };
+
+        let info = SqpImageInfo {
+            width: picture.width(),
+            height: picture.height(),
+            color_format: picture.color_format().into(),
+            compression: picture.compression_type().into(),
+            quality: picture.quality().or(picture.near()).unwrap_or(0),
+            pixels_len: picture.as_raw().len(),
+        };
+
+        // SAFETY: The caller guarantees the output pointers are writable
+        unsafe {
+            out_info.write(info);
+            out_pixels.write(into_c_buffer(picture.as_raw()));
+        }
+
+        SqpErrorCode::Ok
+    })
+}
+
+/// Encode raw pixels into an SQP image in memory.
+///
+/// `fmt` is an [`SqpColorFormat`] and `comp` an [`SqpCompression`], taken
+/// as plain integers so an unknown value gives
+/// [`SqpErrorCode::InvalidArgument`] rather than undefined behavior.
+/// `quality` is only used when `comp` is [`SqpCompression::LossyDct`], or
+/// as `near` when it is [`SqpCompression::Predictive`]. On success,
+/// `out_data` points to the encoded image, which must be released with
+/// [`sqp_free`], and `out_len` holds its length.
+///
+/// # Safety
+/// `pixels` must point to `w * h * bytes per pixel` readable bytes, and
+/// `out_data` and `out_len` must be valid for writes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn sqp_encode(
+    pixels: *const u8,
+    w: u32,
+    h: u32,
+    fmt: u32,
+    comp: u32,
+    quality: u8,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> SqpErrorCode {
+    guard(|| {
+        if pixels.is_null() || out_data.is_null() || out_len.is_null() {
+            return set_error(SqpErrorCode::NullPointer, "null pointer argument".to_string())
+        }
+
+        let color_format = match SqpColorFormat::try_from(fmt) {
+            Ok(fmt) => ColorFormat::from(fmt),
+            Err(v) => return set_error(SqpErrorCode::InvalidArgument, format!("unknown color format {v}")),
+        };
+        let compression_type = match SqpCompression::try_from(comp) {
+            Ok(comp) => CompressionType::from(comp),
+            Err(v) => return set_error(SqpErrorCode::InvalidArgument, format!("unknown compression {v}")),
+        };
+
+        let Some(len) = color_format
+            .bitmap_len(w, h)
+            .filter(|l| *l <= isize::MAX as usize)
+        else {
+            return set_error(SqpErrorCode::InvalidArgument, format!("dimensions {w}x{h} are too large"))
+        };
+
+        // SAFETY: The caller guarantees `pixels` points to `len` bytes
+        let bitmap = unsafe { slice::from_raw_parts(pixels, len) }.to_vec();
+
+        let quality = matches!(compression_type, CompressionType::LossyDct | CompressionType::Predictive).then_some(quality);
+        let picture = SquishyPicture::from_raw(w, h, color_format, compression_type, quality, bitmap);
+
+        let mut output = Vec::new();
+        if let Err(e) = picture.encode(&mut output) {
+            return set_error(SqpErrorCode::EncodeFailed, error_chain(&e))
+        }
+
+        // SAFETY: The caller guarantees the output pointers are writable
+        unsafe {
+            out_len.write(output.len());
+            out_data.write(into_c_buffer(&output));
+        }
+
+        SqpErrorCode::Ok
+    })
+}
+
+/// Release a buffer returned by [`sqp_decode`] or [`sqp_encode`]. Passing
+/// null does nothing.
+///
+/// # Safety
+/// `buffer` must be null or a pointer returned by this library which has
+/// not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn sqp_free(buffer: *mut u8) {
+    if buffer.is_null() {
+        return
+    }
+
+    // SAFETY: The buffer was created by `into_c_buffer`, which stores the
+    // data length in the `PREFIX_LEN` bytes before it
+    unsafe {
+        let start = buffer.sub(PREFIX_LEN);
+        let len = usize::from_ne_bytes(*(start as *const [u8; PREFIX_LEN]));
+
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(start, PREFIX_LEN + len)));
+    }
+}
+
+/// Get a description of the last error which occurred on this thread, or
+/// null if the last call succeeded.
+///
+/// The string is owned by the library and is valid until the next call
+/// into it on the same thread.
+#[no_mangle]
+pub extern "C" fn sqp_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|e| match &*e.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let pixels: Vec<u8> = (0..6 * 5 * 3).map(|i| i as u8).collect();
+
+        let mut data = ptr::null_mut();
+        let mut len = 0;
+        let code = unsafe {
+            sqp_encode(pixels.as_ptr(), 6, 5, SqpColorFormat::Rgb8 as u32, SqpCompression::Lossless as u32, 0, &mut data, &mut len)
+        };
+        assert_eq!(code, SqpErrorCode::Ok);
+        assert!(sqp_last_error_message().is_null());
+
+        let mut info = SqpImageInfo {
+            width: 0,
+            height: 0,
+            color_format: SqpColorFormat::Rgba8,
+            compression: SqpCompression::None,
+            quality: 0,
+            pixels_len: 0,
+        };
+        let mut decoded = ptr::null_mut();
+        let code = unsafe { sqp_decode(data, len, &mut info, &mut decoded) };
+        assert_eq!(code, SqpErrorCode::Ok);
+
+        assert_eq!((info.width, info.height), (6, 5));
+        assert_eq!(info.color_format, SqpColorFormat::Rgb8);
+        assert_eq!(info.compression, SqpCompression::Lossless);
+        assert_eq!(unsafe { slice::from_raw_parts(decoded, info.pixels_len) }, pixels);
+
+        unsafe {
+            sqp_free(data);
+            sqp_free(decoded);
+        }
+    }
+
+    #[test]
+    fn decode_garbage_sets_error() {
+        let garbage = b"not an sqp image at all";
+
+        let mut info = std::mem::MaybeUninit::uninit();
+        let mut decoded = ptr::null_mut();
+        let code = unsafe { sqp_decode(garbage.as_ptr(), garbage.len(), info.as_mut_ptr(), &mut decoded) };
+        assert_eq!(code, SqpErrorCode::DecodeFailed);
+        assert!(decoded.is_null());
+
+        let message = unsafe { CStr::from_ptr(sqp_last_error_message()) };
+        assert!(message.to_str().unwrap().contains("signature"));
+    }
+
+    #[test]
+    fn encode_unknown_enum_values() {
+        let pixels = [0u8; 4];
+        let mut data = ptr::null_mut();
+        let mut len = 0;
+
+        for (fmt, comp, expected) in [(5, 1, "unknown color format 5"), (3, 6, "unknown compression 6"), (u32::MAX, 1, "unknown color format")] {
+            let code = unsafe { sqp_encode(pixels.as_ptr(), 2, 2, fmt, comp, 0, &mut data, &mut len) };
+            assert_eq!(code, SqpErrorCode::InvalidArgument);
+            assert!(data.is_null());
+
+            let message = unsafe { CStr::from_ptr(sqp_last_error_message()) };
+            assert!(message.to_str().unwrap().starts_with(expected));
+        }
+    }
+
+    #[test]
+    fn enum_values() {
+        let color_formats = [
+            (SqpColorFormat::Rgba8, 0),
+            (SqpColorFormat::Rgb8, 1),
+            (SqpColorFormat::GrayA8, 2),
+            (SqpColorFormat::Gray8, 3),
+            (SqpColorFormat::Gray4, 4),
+        ];
+        for (color_format, value) in color_formats {
+            assert_eq!(color_format as u32, value);
+            assert_eq!(SqpColorFormat::try_from(value), Ok(color_format));
+        }
+
+        let compressions = [
+            (SqpCompression::None, 0),
+            (SqpCompression::Lossless, 1),
+            (SqpCompression::LossyDct, 2),
+            (SqpCompression::LosslessV2, 3),
+            (SqpCompression::LosslessBwt, 4),
+            (SqpCompression::Predictive, 5),
+        ];
+        for (compression, value) in compressions {
+            assert_eq!(compression as u32, value);
+            assert_eq!(SqpCompression::try_from(value), Ok(compression));
+        }
+    }
+
+    #[test]
+    fn null_pointers() {
+        let code = unsafe { sqp_decode(ptr::null(), 0, ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(code, SqpErrorCode::NullPointer);
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        let code = guard(|| panic!("boom"));
+        assert_eq!(code, SqpErrorCode::Panic);
+
+        let message = unsafe { CStr::from_ptr(sqp_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "panic: boom");
+    }
+}
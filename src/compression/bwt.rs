@@ -0,0 +1,473 @@
+//! A block sorting compressor in the style of bzip2, used by
+//! [`CompressionType::LosslessBwt`].
+//!
+//! Each chunk goes through three stages:
+//!
+//! 1. The Burrows–Wheeler transform, which sorts every rotation of the chunk
+//!    and keeps the last byte of each, along with the index of the unrotated
+//!    chunk among them. Bytes which come before similar contexts end up next
+//!    to each other.
+//! 2. Move-to-front coding, which turns those runs of similar bytes into
+//!    runs of small numbers, mostly zeroes.
+//! 3. Run-length coding of the zeroes, as a number in bijective base 2 with
+//!    the digits [`RUN_A`] and [`RUN_B`], like bzip2.
+//!
+//! A chunk starts with a byte which is [`TRANSFORMED`] or [`STORED`]. The
+//! primary index and then the symbols of a transformed chunk are written
+//! with a [`BitWriter`] as Elias gamma style codes, see [`write_gamma`], with
+//! each other value `v` of the move-to-front stage as the symbol `v + 1`.
+//! Chunks which would grow, like noise, are stored as they are instead.
+//!
+//! Sorting is done by prefix doubling, which needs a few words of memory
+//! per byte and is much slower than the other backends, so chunks are
+//! limited to [`CHUNK_LEN`] bytes.
+//!
+//! [`CompressionType::LosslessBwt`]: crate::CompressionType::LosslessBwt
+
+use alloc::{vec, vec::Vec};
+
+use super::lossless::CompressionError;
+use crate::binio::{BitReader, BitWriter};
+
+/// The uncompressed size of each chunk, except the last.
+///
+/// The sort packs two ranks and a position into a `u64`, so this must be
+/// at most `1 << 21`.
+pub(crate) const CHUNK_LEN: usize = 1 << 18;
+
+/// The number of bits in a position or rank within a chunk.
+const POSITION_BITS: u32 = CHUNK_LEN.trailing_zeros();
+
+/// Marks a chunk which went through every stage.
+const TRANSFORMED: u8 = 0;
+
+/// Marks a chunk which is stored uncompressed.
+const STORED: u8 = 1;
+
+/// The run-length digit worth one times its place.
+const RUN_A: usize = 0;
+
+/// The run-length digit worth two times its place.
+const RUN_B: usize = 1;
+
+/// The order of every rotation of `data`, sorted by prefix doubling: each
+/// round sorts the rotations by their first `2k` bytes, given the ranks by
+/// their first `k`.
+fn sort_rotations(data: &[u8]) -> Vec<usize> {
+    let n = data.len();
+    let mask = (1 << POSITION_BITS) - 1;
+
+    let mut rank: Vec<u64> = data.iter().map(|b| *b as u64).collect();
+    let mut keys = vec![0u64; n];
+    let mut len = 1;
+    loop {
+        for (i, key) in keys.iter_mut().enumerate() {
+            *key = (rank[i] << (2 * POSITION_BITS)) | (rank[(i + len) % n] << POSITION_BITS) | i as u64;
+        }
+        keys.sort_unstable();
+
+        // Rotations with the same pair of ranks get the same new rank
+        let mut distinct = 0;
+        let mut previous = keys[0] >> POSITION_BITS;
+        for key in &keys {
+            if key >> POSITION_BITS != previous {
+                distinct += 1;
+                previous = key >> POSITION_BITS;
+            }
+            rank[(key & mask) as usize] = distinct;
+        }
+
+        len *= 2;
+        if distinct as usize == n - 1 || len >= n {
+            break
+        }
+    }
+
+    keys.iter().map(|key| (key & mask) as usize).collect()
+}
+
+/// The Burrows–Wheeler transform of `data`, which must not be empty,
+/// returning the last byte of each sorted rotation and where the unrotated
+/// data is among them.
+fn bwt(data: &[u8]) -> (Vec<u8>, usize) {
+    let n = data.len();
+    let order = sort_rotations(data);
+
+    let primary = order.iter().position(|i| *i == 0).unwrap();
+    let last = order.iter().map(|i| data[(i + n - 1) % n]).collect();
+
+    (last, primary)
+}
+
+/// Undo [`bwt`], or return [`None`] if the primary index is out of range.
+fn inverse_bwt(last: &[u8], primary: usize) -> Option<Vec<u8>> {
+    let n = last.len();
+    if primary >= n {
+        return None
+    }
+
+    // Where the rotations starting with each byte begin in the sorted order
+    let mut starts = [0usize; 256];
+    for byte in last {
+        starts[*byte as usize] += 1;
+    }
+    let mut total = 0;
+    for start in &mut starts {
+        total += *start;
+        *start = total - *start;
+    }
+
+    // For each rotation, the rotation starting one byte earlier
+    let mut previous = vec![0usize; n];
+    for (i, byte) in last.iter().enumerate() {
+        previous[i] = starts[*byte as usize];
+        starts[*byte as usize] += 1;
+    }
+
+    let mut output = vec![0u8; n];
+    let mut row = primary;
+    for byte in output.iter_mut().rev() {
+        *byte = last[row];
+        row = previous[row];
+    }
+
+    Some(output)
+}
+
+/// Replace each byte with its position in a list of recently seen bytes,
+/// moving it to the front.
+fn move_to_front(data: &[u8]) -> Vec<u8> {
+    let mut order: [u8; 256] = core::array::from_fn(|i| i as u8);
+
+    data.iter().map(|byte| {
+        let index = order.iter().position(|b| b == byte).unwrap();
+        order.copy_within(..index, 1);
+        order[0] = *byte;
+        index as u8
+    }).collect()
+}
+
+/// Undo [`move_to_front`].
+fn inverse_move_to_front(indices: &[u8]) -> Vec<u8> {
+    let mut order: [u8; 256] = core::array::from_fn(|i| i as u8);
+
+    indices.iter().map(|index| {
+        let byte = order[*index as usize];
+        order.copy_within(..*index as usize, 1);
+        order[0] = byte;
+        byte
+    }).collect()
+}
+
+/// Write a number of at least 1 as the count of bits after its highest set
+/// bit in unary, followed by those bits.
+fn write_gamma(bit_io: &mut BitWriter<Vec<u8>>, value: usize) {
+    debug_assert!(value > 0);
+
+    let bits = value.ilog2() as usize;
+    bit_io.write_bit((1 << bits) - 1, bits + 1);
+    if bits > 0 {
+        bit_io.write_bit(value as u64 & ((1 << bits) - 1), bits);
+    }
+}
+
+/// Read a number written by [`write_gamma`].
+fn read_gamma(bit_io: &mut BitReader<&[u8]>) -> Option<usize> {
+    let mut bits = 0;
+    while bit_io.read_bit(1).ok()? == 1 {
+        bits += 1;
+        if bits >= usize::BITS as usize {
+            return None
+        }
+    }
+
+    let low = match bits {
+        0 => 0,
+        _ => bit_io.read_bit(bits).ok()? as usize,
+    };
+
+    Some((1 << bits) | low)
+}
+
+/// Write a symbol, which is a run-length digit or a nonzero move-to-front
+/// index plus one.
+fn write_symbol(bit_io: &mut BitWriter<Vec<u8>>, symbol: usize) {
+    write_gamma(bit_io, symbol + 1);
+}
+
+/// Write a run of `len` zeroes in bijective base 2, least significant digit
+/// first.
+fn write_run(bit_io: &mut BitWriter<Vec<u8>>, mut len: usize) {
+    while len > 0 {
+        let digit = match len % 2 {
+            1 => RUN_A,
+            _ => RUN_B,
+        };
+        write_symbol(bit_io, digit);
+        len = (len - digit - 1) / 2;
+    }
+}
+
+/// Compress a chunk of up to [`CHUNK_LEN`] bytes from the start of `data`,
+/// appending it to `output`.
+///
+/// Returns the number of bytes of `data` which were consumed.
+pub(crate) fn compress_bwt(data: &[u8], output: &mut Vec<u8>) -> usize {
+    let data = &data[..data.len().min(CHUNK_LEN)];
+    if data.is_empty() {
+        return 0
+    }
+
+    let (last, primary) = bwt(data);
+    let indices = move_to_front(&last);
+
+    let start = output.len();
+    output.push(TRANSFORMED);
+    let mut bit_io = BitWriter::new(output);
+    write_gamma(&mut bit_io, primary + 1);
+
+    let mut run = 0;
+    for index in indices {
+        if index == 0 {
+            run += 1;
+            continue
+        }
+
+        write_run(&mut bit_io, run);
+        run = 0;
+        write_symbol(&mut bit_io, index as usize + 1);
+    }
+    write_run(&mut bit_io, run);
+    bit_io.flush();
+
+    if output.len() - start > data.len() + 1 {
+        output.truncate(start);
+        output.push(STORED);
+        output.extend_from_slice(data);
+    }
+
+    data.len()
+}
+
+/// Decompress a chunk written by [`compress_bwt`] which is `size` bytes long
+/// when uncompressed.
+///
+/// The last stage needs the whole chunk, so on an error the data is lost.
+pub(crate) fn decompress_bwt(input: &[u8], size: usize) -> Result<Vec<u8>, CompressionError> {
+    let mut data = match input.split_first() {
+        Some((&TRANSFORMED, rest)) => rest,
+        Some((&STORED, rest)) => return Ok(rest.to_vec()),
+        Some((kind, _)) => return Err(CompressionError::BadElement(Vec::new(), *kind as u64, 0)),
+        None => return Err(CompressionError::BadElement(Vec::new(), 0, 0)),
+    };
+    let mut bit_io = BitReader::new(&mut data);
+    let bad_element = |element, bit_io: &BitReader<&[u8]>| {
+        CompressionError::BadElement(Vec::new(), element, 8 + bit_io.bit_position())
+    };
+
+    let Some(primary) = read_gamma(&mut bit_io) else {
+        return Err(bad_element(0, &bit_io))
+    };
+
+    let mut indices = Vec::with_capacity(size);
+
+    // The run of zeroes being read, and the value of its next digit
+    let mut run = 0;
+    let mut place = 1;
+    while indices.len() + run < size {
+        let Some(symbol) = read_gamma(&mut bit_io).map(|s| s - 1) else {
+            return Err(bad_element(0, &bit_io))
+        };
+
+        if symbol == RUN_A || symbol == RUN_B {
+            run += place * (symbol + 1);
+            place *= 2;
+            if indices.len() + run > size {
+                return Err(bad_element(symbol as u64, &bit_io))
+            }
+            continue
+        }
+
+        indices.resize(indices.len() + run, 0);
+        run = 0;
+        place = 1;
+
+        match u8::try_from(symbol - 1) {
+            Ok(index) if indices.len() < size => indices.push(index),
+            _ => return Err(bad_element(symbol as u64, &bit_io)),
+        }
+    }
+    indices.resize(size, 0);
+
+    if size == 0 {
+        return Ok(Vec::new())
+    }
+
+    inverse_bwt(&inverse_move_to_front(&indices), primary - 1)
+        .ok_or_else(|| bad_element(primary as u64, &bit_io))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> usize {
+        let mut compressed = Vec::new();
+        let mut offset = 0;
+        let mut chunks = Vec::new();
+        loop {
+            let start = compressed.len();
+            let count = compress_bwt(&data[offset..], &mut compressed);
+            if count == 0 {
+                break
+            }
+            chunks.push((start, compressed.len(), count));
+            offset += count;
+        }
+
+        let mut decompressed = Vec::new();
+        for (start, end, count) in chunks {
+            decompressed.extend(decompress_bwt(&compressed[start..end], count).unwrap());
+        }
+        assert_eq!(decompressed, data);
+
+        compressed.len()
+    }
+
+    /// Every rotation of `data`, sorted, to check against.
+    fn naive_bwt(data: &[u8]) -> (Vec<u8>, usize) {
+        let n = data.len();
+        let mut rotations: Vec<usize> = (0..n).collect();
+        rotations.sort_by(|a, b| {
+            let a = data[*a..].iter().chain(&data[..*a]);
+            let b = data[*b..].iter().chain(&data[..*b]);
+            a.cmp(b)
+        });
+
+        let primary = rotations.iter().position(|i| *i == 0).unwrap();
+        (rotations.iter().map(|i| data[(i + n - 1) % n]).collect(), primary)
+    }
+
+    fn random(len: usize, state: &mut u32) -> Vec<u8> {
+        (0..len).map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            *state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn bwt_known_output() {
+        assert_eq!(bwt(b"banana"), (b"nnbaaa".to_vec(), 3));
+        assert_eq!(inverse_bwt(b"nnbaaa", 3).unwrap(), b"banana");
+        assert_eq!(inverse_bwt(b"nnbaaa", 6), None);
+
+        assert_eq!(move_to_front(b"bbaac"), [98, 0, 98, 0, 99]);
+        assert_eq!(inverse_move_to_front(&[98, 0, 98, 0, 99]), b"bbaac");
+    }
+
+    #[test]
+    fn bwt_exhaustive() {
+        // Every string of up to 8 bytes from a three letter alphabet
+        for len in 1..=8u32 {
+            for mut n in 0..3usize.pow(len) {
+                let data: Vec<u8> = (0..len).map(|_| {
+                    let byte = b'a' + (n % 3) as u8;
+                    n /= 3;
+                    byte
+                }).collect();
+
+                let (last, primary) = bwt(&data);
+                let (expected_last, _) = naive_bwt(&data);
+
+                // Repeating strings have equal rotations, so the primary
+                // index can be any of them
+                assert_eq!(last, expected_last, "{data:?}");
+                assert_eq!(inverse_bwt(&last, primary).unwrap(), data);
+                round_trip(&data);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_pathological() {
+        // All equal bytes are one long run of zeroes
+        for len in [1, 2, 3, 1000, CHUNK_LEN, CHUNK_LEN + 1] {
+            assert!(round_trip(&vec![0x55; len]) < 20 * len.div_ceil(CHUNK_LEN));
+        }
+
+        let increasing: Vec<u8> = (0..=255).collect();
+        round_trip(&increasing);
+
+        let increasing: Vec<u8> = (0..CHUNK_LEN + 1000).map(|i| i as u8).collect();
+        assert!(round_trip(&increasing) < 2000);
+
+        let decreasing: Vec<u8> = (0..=255).rev().collect();
+        round_trip(&decreasing);
+
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"ab");
+        round_trip(b"abababababab");
+    }
+
+    #[test]
+    fn round_trip_random() {
+        let mut state = 0x2545F491;
+
+        // Lengths, alphabets and repetition vary, to cover both long runs
+        // and data which barely compresses
+        for i in 0..200 {
+            let len = random(2, &mut state);
+            let len = (u16::from_le_bytes([len[0], len[1]]) as usize) >> (i % 8);
+            let alphabet = (i % 7) as u8 * 40 + 1;
+            let mut data: Vec<u8> = random(len, &mut state).iter().map(|b| b % alphabet).collect();
+            if i % 3 == 0 {
+                data = data.repeat(3);
+            }
+
+            round_trip(&data);
+        }
+
+        // Noise is stored as it is
+        let data = random(CHUNK_LEN * 2 + 77, &mut state);
+        assert_eq!(round_trip(&data), data.len() + 3);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = b"banana".repeat(20);
+        let mut compressed = Vec::new();
+        compress_bwt(&data, &mut compressed);
+        assert_eq!(compressed[0], TRANSFORMED);
+        assert_eq!(decompress_bwt(&compressed, data.len()).unwrap(), data);
+
+        // Truncated, and with more output than there should be
+        assert!(decompress_bwt(&compressed[..2], data.len()).is_err());
+        assert!(decompress_bwt(&compressed, 3).is_err());
+
+        // A primary index past the end
+        let mut bad = vec![TRANSFORMED];
+        let mut bit_io = BitWriter::new(&mut bad);
+        write_gamma(&mut bit_io, 7);
+        write_run(&mut bit_io, 6);
+        bit_io.flush();
+        assert!(decompress_bwt(&bad, 6).is_err());
+
+        // A run longer than the chunk
+        let mut bad = vec![TRANSFORMED];
+        let mut bit_io = BitWriter::new(&mut bad);
+        write_gamma(&mut bit_io, 1);
+        write_run(&mut bit_io, 7);
+        bit_io.flush();
+        assert!(decompress_bwt(&bad, 6).is_err());
+
+        // Stored chunks are returned whatever their size, for the caller to
+        // check, but unknown kinds of chunk are errors
+        assert_eq!(decompress_bwt(&[STORED, 1, 2, 3], 3).unwrap(), [1, 2, 3]);
+        assert_eq!(decompress_bwt(&[STORED, 1, 2], 3).unwrap(), [1, 2]);
+        assert!(decompress_bwt(&[2, 1, 2, 3], 3).is_err());
+        assert!(decompress_bwt(&[], 3).is_err());
+    }
+}
@@ -1,53 +1,118 @@
-use std::{f32::consts::{PI, SQRT_2}, sync::{Arc, Mutex}};
+use core::f32::consts::{PI, SQRT_2};
 
+use alloc::{vec, vec::Vec};
+
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
-use crate::header::ColorFormat;
+use crate::{header::{clamp_quality, ColorFormat, QUALITY_RANGE}, math, threads};
 
 /// Perform a Discrete Cosine Transform on the input matrix.
+#[allow(dead_code)]
 pub fn dct(input: &[u8], width: usize, height: usize) -> Vec<f32> {
+    let mut output = vec![0.0; width * height];
+    dct_into(input, width, height, &mut output);
+
+    output
+}
+
+/// Perform a Discrete Cosine Transform on the input matrix, writing the
+/// result into `output`.
+fn dct_into(input: &[u8], width: usize, height: usize, output: &mut [f32]) {
     if input.len() != width * height {
         panic!("Input matrix size must be width * height, got {}", input.len())
     }
 
-    let sqrt_width_zero = 1.0 / (width as f32).sqrt();
-    let sqrt_width = SQRT_2 / (width as f32).sqrt();
-
-    let sqrt_height_zero = 1.0 / (height as f32).sqrt();
-    let sqrt_height = SQRT_2 / (height as f32).sqrt();
-
-    let mut output = Vec::new();
+    let mut output = output.iter_mut();
     for u in 0..width {
         for v in 0..height {
+            *output.next().unwrap() = dct_coefficient(input, width, height, u, v);
+        }
+    }
+}
 
-            let cu = if u == 0 {
-                sqrt_width_zero
-            } else {
-                sqrt_width
-            };
-
-            let cv = if v == 0 {
-                sqrt_height_zero
-            } else {
-                sqrt_height
-            };
+/// The coefficient at `(u, v)` of the Discrete Cosine Transform of the
+/// input matrix.
+fn dct_coefficient(input: &[u8], width: usize, height: usize, u: usize, v: usize) -> f32 {
+    let cu = if u == 0 {
+        1.0 / math::sqrt(width as f32)
+    } else {
+        SQRT_2 / math::sqrt(width as f32)
+    };
 
-            let mut tmp_sum = 0.0;
-            for x in 0..width {
-                for y in 0..height {
-                    let dct = (input[x * width + y] as f32 - 128.0) *
-                        f32::cos((2.0 * x as f32 + 1.0) * u as f32 * PI / (2.0 * width as f32)) *
-                        f32::cos((2.0 * y as f32 + 1.0) * v as f32 * PI / (2.0 * height as f32));
+    let cv = if v == 0 {
+        1.0 / math::sqrt(height as f32)
+    } else {
+        SQRT_2 / math::sqrt(height as f32)
+    };
 
-                    tmp_sum += dct;
-                }
-            }
+    let mut tmp_sum = 0.0;
+    for x in 0..width {
+        for y in 0..height {
+            let dct = (input[x * width + y] as f32 - 128.0) *
+                math::cos((2.0 * x as f32 + 1.0) * u as f32 * PI / (2.0 * width as f32)) *
+                math::cos((2.0 * y as f32 + 1.0) * v as f32 * PI / (2.0 * height as f32));
 
-            output.push(cu * cv * tmp_sum)
+            tmp_sum += dct;
         }
     }
 
-    output
+    cu * cv * tmp_sum
+}
+
+/// Pruning ranges which never leave out any coefficients, for transforms
+/// which aren't quantized straight away.
+const NO_PRUNING: [f32; 64] = [0.0; 64];
+
+/// How far below half a quantization step a coefficient's bound must be
+/// for it to be pruned, which covers any float error in computing it.
+const PRUNING_MARGIN: f32 = 0.05;
+
+/// For each coefficient of an 8x8 block, how large the range of the block's
+/// values, its maximum less its minimum, can be before the coefficient might
+/// quantize to something other than zero with the given matrix. Blocks with
+/// a smaller range leave it out, see [`dct_block_pruned`].
+///
+/// The weights of an AC coefficient sum to zero, so subtracting the middle
+/// of the block's range from every value doesn't change it. Every value is
+/// then within half the range of zero, which bounds the coefficient by half
+/// the range times the sum of the magnitudes of its weights. Below half the
+/// quantization step it always rounds to zero. The DC coefficient is never
+/// left out.
+fn pruning_ranges(quant_matrix: [u16; 64]) -> [f32; 64] {
+    // The sum of the magnitudes of the weights along one axis
+    let weights: [f32; 8] = core::array::from_fn(|u| {
+        let scale = if u == 0 { 1.0 / math::sqrt(8.0) } else { SQRT_2 / math::sqrt(8.0) };
+        let sum: f32 = (0..8)
+            .map(|x| math::cos((2.0 * x as f32 + 1.0) * u as f32 * PI / 16.0).abs())
+            .sum();
+
+        scale * sum
+    });
+
+    core::array::from_fn(|i| match i {
+        0 => 0.0,
+        i => (quant_matrix[i] as f32 - 2.0 * PRUNING_MARGIN) / (weights[i / 8] * weights[i % 8]),
+    })
+}
+
+/// Perform DCT on an 8x8 block like [`dct_into`], leaving out the
+/// coefficients which always quantize to zero for a block with its range of
+/// values, going by the [`pruning_ranges`]. Those are set to zero.
+///
+/// Flat and smooth blocks at low qualities skip most of the work, and the
+/// coefficients which are computed are exactly the same.
+fn dct_block_pruned(block: &[u8; 64], ranges: &[f32; 64], output: &mut [f32; 64]) {
+    let (min, max) = block.iter().fold((u8::MAX, u8::MIN), |(min, max), v| (min.min(*v), max.max(*v)));
+    let range = (max - min) as f32;
+
+    for (i, coefficient) in output.iter_mut().enumerate() {
+        *coefficient = if range < ranges[i] {
+            0.0
+        } else {
+            dct_coefficient(block, 8, 8, i / 8, i % 8)
+        };
+    }
 }
 
 /// Perform an inverse Discrete Cosine Transform on the input matrix.
@@ -56,11 +121,11 @@ pub fn idct(input: &[f32], width: usize, height: usize) -> Vec<u8> {
         panic!("Input matrix size must be width * height, got {}", input.len())
     }
 
-    let sqrt_width_zero = 1.0 / (width as f32).sqrt();
-    let sqrt_width = SQRT_2 / (width as f32).sqrt();
+    let sqrt_width_zero = 1.0 / math::sqrt(width as f32);
+    let sqrt_width = SQRT_2 / math::sqrt(width as f32);
 
-    let sqrt_height_zero = 1.0 / (height as f32).sqrt();
-    let sqrt_height = SQRT_2 / (height as f32).sqrt();
+    let sqrt_height_zero = 1.0 / math::sqrt(height as f32);
+    let sqrt_height = SQRT_2 / math::sqrt(height as f32);
 
     let mut output = Vec::new();
     for x in 0..width {
@@ -82,14 +147,14 @@ pub fn idct(input: &[f32], width: usize, height: usize) -> Vec<u8> {
                     };
 
                     let idct = input[u * width + v] *
-                        f32::cos((2.0 * x as f32 + 1.0) * u as f32 * PI / (2.0 * width as f32)) *
-                        f32::cos((2.0 * y as f32 + 1.0) * v as f32 * PI / (2.0 * height as f32));
+                        math::cos((2.0 * x as f32 + 1.0) * u as f32 * PI / (2.0 * width as f32)) *
+                        math::cos((2.0 * y as f32 + 1.0) * v as f32 * PI / (2.0 * height as f32));
 
                     tmp_sum += cu * cv * idct
                 }
             }
 
-            output.push((tmp_sum + 128.0).round() as u8)
+            output.push(math::round(tmp_sum + 128.0) as u8)
         }
     }
 
@@ -115,7 +180,12 @@ const BASE_QUANTIZATION_MATRIX: [u16; 64] = [
 ];
 
 /// Generate the 8x8 quantization matrix for the given quality level.
+///
+/// The quality is clamped into [`QUALITY_RANGE`].
+///
+/// [`QUALITY_RANGE`]: crate::header::QUALITY_RANGE
 pub fn quantization_matrix(quality: u32) -> [u16; 64] {
+    let quality = clamp_quality(quality);
     let factor = if quality < 50 {
         5000.0 / quality as f32
     } else {
@@ -123,133 +193,920 @@ pub fn quantization_matrix(quality: u32) -> [u16; 64] {
     };
 
     let new_matrix = BASE_QUANTIZATION_MATRIX.map(|i|
-        f32::floor((factor * i as f32 + 50.0) / 100.0) as u16
+        math::floor((factor * i as f32 + 50.0) / 100.0) as u16
     );
     new_matrix.map(|i| if i == 0 { 1 } else { i })
 }
 
 /// Quantize an input matrix, returning the result.
+#[allow(dead_code)]
 pub fn quantize(input: &[f32], quant_matrix: [u16; 64]) -> Vec<i16> {
     input.iter()
         .zip(quant_matrix)
-        .map(|(v, q)| (v / q as f32).round() as i16)
+        .map(|(v, q)| math::round(v / q as f32) as i16)
         .collect()
 }
 
+/// Quantize an input matrix, appending the result to `output`.
+fn quantize_into(input: &[f32], quant_matrix: [u16; 64], output: &mut Vec<i16>) {
+    output.extend(
+        input.iter()
+            .zip(quant_matrix)
+            .map(|(v, q)| math::round(v / q as f32) as i16)
+    )
+}
+
+/// Quantize a single block, overwriting `output`.
+fn quantize_block(input: &[f32], quant_matrix: [u16; 64], output: &mut [i16]) {
+    output.iter_mut()
+        .zip(input)
+        .zip(quant_matrix)
+        .for_each(|((out, v), q)| *out = math::round(v / q as f32) as i16)
+}
+
 /// Dequantize an input matrix, returning an approximation of the original.
 pub fn dequantize(input: &[i16], quant_matrix: [u16; 64]) -> Vec<f32> {
     input.iter()
         .zip(quant_matrix)
-        .map(|(v, q)| (*v * q as i16) as f32)
+        .map(|(v, q)| *v as f32 * q as f32)
         .collect()
 }
 
 /// Take in an image encoded in some [`ColorFormat`] and perform DCT on it,
 /// returning the modified data. This function also pads the image dimensions
 /// to a multiple of 8, which must be reversed when decoding.
+///
+/// In formats with alpha, the color channels of blocks which are entirely
+/// transparent aren't transformed, and all their coefficients are zero.
+/// They decode as flat gray, which can't be seen under the alpha. This is
+/// only done at qualities where the alpha of such a block decodes to at
+/// most [`MAX_HIDDEN_ALPHA`].
 pub fn dct_compress(input: &[u8], parameters: DctParameters) -> Vec<Vec<i16>> {
+    let mut output = Vec::new();
+    dct_compress_into(input, parameters, &mut Vec::new(), &mut output);
+
+    output
+}
+
+/// Like [`dct_compress`], but reuses the allocations of `planes`, which
+/// holds the de-interleaved image, and of `output`, which is overwritten
+/// with the coefficients of each channel.
+pub(crate) fn dct_compress_into(
+    input: &[u8],
+    parameters: DctParameters,
+    planes: &mut Vec<u8>,
+    output: &mut Vec<Vec<i16>>,
+) {
     let new_width = parameters.width + (8 - parameters.width % 8);
     let new_height = parameters.height + (8 - parameters.height % 8);
-    let quantization_matrix = quantization_matrix(parameters.quality);
-
-    let mut dct_image = Vec::with_capacity(input.len());
-    let channels: Vec<Vec<i16>> = (0..parameters.format.channels()).into_par_iter().map(|ch| {
-        let channel: Vec<u8> = input.iter()
-            .skip(ch as usize)
-            .step_by(parameters.format.channels() as usize)
-            .copied()
-            .collect();
+    let channels = parameters.format.channels() as usize;
+
+    if channels == 1 {
+        trace!("transforming channel 0 of 1");
+        let quantization_matrix = parameters.quantization_matrix(0);
+        let ranges = pruning_ranges(quantization_matrix);
+
+        output.resize_with(1, Vec::new);
+        output[0].clear();
+        output[0].resize(new_width * new_height, 0);
+        transform_single_channel(input, parameters, &ranges, &mut output[0], |dct_block, block| {
+            quantize_block(dct_block, quantization_matrix, block)
+        });
+
+        return
+    }
+
+    deinterleave_into(input, parameters.width, channels, new_width, new_height, planes);
+    let planes = &*planes;
+    let transparent = parameters.format.alpha_channel()
+        .and_then(|alpha| transparent_blocks(&planes[alpha * new_width..], channels * new_width, parameters));
+
+    output.resize_with(channels, Vec::new);
+
+    #[cfg(feature = "parallel")]
+    let dct_channels = output.par_iter_mut().enumerate();
+    #[cfg(not(feature = "parallel"))]
+    let dct_channels = output.iter_mut().enumerate();
+
+    threads::install(|| {
+        dct_channels.for_each(|(ch, dct_channel)| {
+            trace!("transforming channel {ch} of {channels}");
+            dct_channel.clear();
+            dct_channel.reserve(new_width * new_height);
+            compress_plane(
+                &planes[ch * new_width..],
+                channels * new_width,
+                new_width,
+                new_height,
+                parameters.quantization_matrix(ch),
+                skipped_blocks(transparent.as_deref(), parameters, ch),
+                dct_channel,
+            );
+        })
+    });
+}
+
+/// Like [`dct_compress`], but transforms one channel at a time on the
+/// calling thread, passing each channel's coefficients to `output` before
+/// starting the next.
+///
+/// Only a single channel's plane and coefficients are held in memory at
+/// once.
+pub fn dct_compress_sequential<F: FnMut(&[i16])>(
+    input: &[u8],
+    parameters: DctParameters,
+    mut output: F,
+) {
+    let new_width = parameters.width + (8 - parameters.width % 8);
+    let new_height = parameters.height + (8 - parameters.height % 8);
+    let channels = parameters.format.channels() as usize;
+
+    let mut plane = vec![0u8; new_width * new_height];
+    let extract_channel = |plane: &mut [u8], ch: usize| {
+        plane
+            .chunks_exact_mut(new_width)
+            .zip(input.chunks_exact(parameters.width * channels))
+            .for_each(|(plane_row, input_row)| {
+                plane_row.iter_mut()
+                    .zip(input_row.iter().skip(ch).step_by(channels))
+                    .for_each(|(p, i)| *p = *i);
+            });
+    };
 
-        // Create 2d array of the channel for ease of processing
-        let mut img_2d: Vec<Vec<u8>> =
-            channel.windows(parameters.width)
-                .step_by(parameters.width)
-                .map(|r| r.to_vec())
-                .collect();
+    // Alpha comes last, so find the transparent blocks up front
+    let transparent = parameters.format.alpha_channel().and_then(|alpha| {
+        extract_channel(&mut plane, alpha);
+        transparent_blocks(&plane, new_width, parameters)
+    });
+
+    let mut dct_channel = Vec::with_capacity(new_width * new_height);
+    for ch in 0..channels {
+        trace!("transforming channel {ch} of {channels}");
+        extract_channel(&mut plane, ch);
+
+        dct_channel.clear();
+        compress_plane(
+            &plane,
+            new_width,
+            new_width,
+            new_height,
+            parameters.quantization_matrix(ch),
+            skipped_blocks(transparent.as_deref(), parameters, ch),
+            &mut dct_channel,
+        );
+
+        output(&dct_channel);
+    }
+}
 
-        img_2d.iter_mut().for_each(|r| r.resize(new_width, 0));
-        img_2d.resize(new_height, vec![0u8; new_width]);
+/// Perform DCT on every block of a plane and quantize the result, appending
+/// it to `output`. Row `y` of the plane starts at `y * stride`.
+///
+/// Coefficients which can only quantize to zero aren't computed, and
+/// neither are blocks marked in `skip`.
+fn compress_plane(
+    plane: &[u8],
+    stride: usize,
+    new_width: usize,
+    new_height: usize,
+    quantization_matrix: [u16; 64],
+    skip: Option<&[bool]>,
+    output: &mut Vec<i16>,
+) {
+    let ranges = pruning_ranges(quantization_matrix);
+    transform_plane(plane, stride, new_width, new_height, &ranges, skip, |dct_block| {
+        quantize_into(dct_block, quantization_matrix, output)
+    });
+}
 
-        let mut dct_channel = Vec::new();
-        for x in 0..((new_height / 8) * (new_width / 8)) {
-            let h = x / (new_width / 8);
-            let w = x % (new_width / 8);
+/// Perform DCT on every block of a plane in order, passing each result to
+/// `f`. Row `y` of the plane starts at `y * stride`. Coefficients are
+/// pruned according to `ranges`, see [`dct_block_pruned`].
+///
+/// Blocks marked in `skip`, in the same order, aren't transformed and are
+/// passed on as all zeroes.
+fn transform_plane<F: FnMut(&[f32; 64])>(
+    plane: &[u8],
+    stride: usize,
+    new_width: usize,
+    new_height: usize,
+    ranges: &[f32; 64],
+    skip: Option<&[bool]>,
+    mut f: F,
+) {
+    // Scratch space reused for every block
+    let mut block = [0u8; 64];
+    let mut dct_block = [0f32; 64];
+
+    for h in 0..new_height / 8 {
+        for w in 0..new_width / 8 {
+            if skip.is_some_and(|skip| skip[h * (new_width / 8) + w]) {
+                f(&[0.0; 64]);
+                continue
+            }
 
-            let mut chunk = Vec::new();
             for i in 0..8 {
-                let row = &img_2d[(h * 8) + i][w * 8..(w * 8) + 8];
-                chunk.extend_from_slice(row);
+                let start = (h * 8 + i) * stride + w * 8;
+                block[i * 8..i * 8 + 8].copy_from_slice(&plane[start..start + 8]);
             }
 
             // Perform the DCT on the image section
-            let dct: Vec<f32> = dct(&chunk, 8, 8);
-            let quantized_dct = quantize(&dct, quantization_matrix);
+            dct_block_pruned(&block, ranges, &mut dct_block);
+            f(&dct_block);
+        }
+    }
+}
+
+/// For each block of the alpha channel of an image, in the order
+/// [`transform_plane`] visits them, whether its color channels can be left
+/// out. Row `y` of the alpha plane starts at `y * stride`.
+///
+/// Returns [`None`] if transparent blocks wouldn't decode with an alpha of
+/// at most [`MAX_HIDDEN_ALPHA`] with the alpha channel's quantization
+/// matrix, as then the color under them can still be seen.
+fn transparent_blocks(alpha: &[u8], stride: usize, parameters: DctParameters) -> Option<Vec<bool>> {
+    let alpha_channel = parameters.format.alpha_channel()?;
+    if !decodes_transparent(parameters.quantization_matrix(alpha_channel)) {
+        return None
+    }
+
+    Some(fully_transparent_blocks(alpha, stride, parameters))
+}
+
+/// The highest alpha which an entirely transparent block can decode to for
+/// its color to be left out. Color under an alpha of 1 moves a composited
+/// pixel by at most one level.
+pub const MAX_HIDDEN_ALPHA: u8 = 1;
+
+/// Whether an entirely transparent block of alpha decodes to at most
+/// [`MAX_HIDDEN_ALPHA`] with the given quantization matrix. At low
+/// qualities the DC coefficient can be too coarse for that.
+fn decodes_transparent(quantization_matrix: [u16; 64]) -> bool {
+    let mut coefficients = [0.0; 64];
+    dct_block_pruned(&[0; 64], &pruning_ranges(quantization_matrix), &mut coefficients);
+    let mut quantized = [0; 64];
+    quantize_block(&coefficients, quantization_matrix, &mut quantized);
 
-            dct_channel.extend_from_slice(&quantized_dct);
+    idct(&dequantize(&quantized, quantization_matrix), 8, 8).iter().all(|a| *a <= MAX_HIDDEN_ALPHA)
+}
+
+/// For each block of the alpha channel of an image, whether it is entirely
+/// transparent, like [`transparent_blocks`] but regardless of how it
+/// decodes. The padding counts as transparent.
+fn fully_transparent_blocks(alpha: &[u8], stride: usize, parameters: DctParameters) -> Vec<bool> {
+    let (blocks_x, blocks_y) = parameters.blocks();
+    let mut transparent = vec![true; blocks_x * blocks_y];
+    for (y, row) in alpha.chunks(stride).take(parameters.height).enumerate() {
+        for (x, a) in row[..parameters.width].iter().enumerate() {
+            if *a != 0 {
+                transparent[y / 8 * blocks_x + x / 8] = false;
+            }
+        }
+    }
+
+    transparent
+}
+
+/// The blocks of channel `ch` which aren't transformed, given the
+/// [`transparent_blocks`] of the image. Alpha itself is never skipped.
+fn skipped_blocks(transparent: Option<&[bool]>, parameters: DctParameters, ch: usize) -> Option<&[bool]> {
+    transparent.filter(|_| parameters.format.alpha_channel() != Some(ch))
+}
+
+/// Perform DCT on every block of an image with a single channel, reading
+/// each block straight from `input` rather than a padded copy of it.
+///
+/// `output` holds 64 values for each block, in the same order as
+/// [`transform_plane`], and `f` fills them in from the block's transform.
+/// Rows of blocks are transformed in parallel, and coefficients are pruned
+/// according to `ranges`.
+fn transform_single_channel<T, F>(
+    input: &[u8],
+    parameters: DctParameters,
+    ranges: &[f32; 64],
+    output: &mut [T],
+    f: F,
+)
+where
+    T: Send,
+    F: Fn(&[f32; 64], &mut [T]) + Sync,
+{
+    let DctParameters { width, height, .. } = parameters;
+    let new_width = width + (8 - width % 8);
+
+    #[cfg(feature = "parallel")]
+    let block_rows = output.par_chunks_exact_mut(new_width * 8).enumerate();
+    #[cfg(not(feature = "parallel"))]
+    let block_rows = output.chunks_exact_mut(new_width * 8).enumerate();
+
+    threads::install(|| {
+        block_rows.for_each(|(h, block_row)| {
+            // Scratch space reused for every block in the row
+            let mut block = [0u8; 64];
+            let mut dct_block = [0f32; 64];
+
+            for (w, output) in block_row.chunks_exact_mut(64).enumerate() {
+                let visible_width = width.saturating_sub(w * 8).min(8);
+                for (i, block_line) in block.chunks_exact_mut(8).enumerate() {
+                    // Anything past the edge of the image is padding
+                    let y = h * 8 + i;
+                    if y >= height {
+                        block_line.fill(0);
+                        continue
+                    }
+
+                    let start = y * width + w * 8;
+                    block_line[..visible_width].copy_from_slice(&input[start..start + visible_width]);
+                    block_line[visible_width..].fill(0);
+                }
+
+                dct_block_pruned(&block, ranges, &mut dct_block);
+                f(&dct_block, output);
+            }
+        })
+    });
+}
+
+/// The DCT of an image before quantization, which can be quantized at any
+/// quality without transforming the image again.
+///
+/// This takes four times the memory of the coefficients [`dct_compress`]
+/// produces, but trying several qualities costs little more than
+/// quantizing for each.
+pub struct DctCoefficients {
+    channels: Vec<Vec<f32>>,
+
+    /// The alpha channel and its [`fully_transparent_blocks`], whose color
+    /// is left out at qualities where they decode as transparent.
+    transparent: Option<(usize, Vec<bool>)>,
+}
+
+impl DctCoefficients {
+    /// Perform DCT on an image. The quality in `parameters` is ignored.
+    pub fn new(input: &[u8], parameters: DctParameters) -> Self {
+        let new_width = parameters.width + (8 - parameters.width % 8);
+        let new_height = parameters.height + (8 - parameters.height % 8);
+        let channels = parameters.format.channels() as usize;
+
+        if channels == 1 {
+            let mut channel = vec![0.0; new_width * new_height];
+            transform_single_channel(input, parameters, &NO_PRUNING, &mut channel, |dct_block, block| {
+                block.copy_from_slice(dct_block)
+            });
+
+            return Self { channels: vec![channel], transparent: None }
         }
 
-        dct_channel
-    }).collect();
+        let planes = deinterleave(input, parameters.width, channels, new_width, new_height);
+        let transparent = parameters.format.alpha_channel().map(|alpha| {
+            (alpha, fully_transparent_blocks(&planes[alpha * new_width..], channels * new_width, parameters))
+        });
+
+        #[cfg(feature = "parallel")]
+        let channel_nums = (0..channels).into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let channel_nums = 0..channels;
+
+        let channels = threads::install(|| {
+            channel_nums.map(|ch| {
+                let mut dct_channel = Vec::with_capacity(new_width * new_height);
+                transform_plane(
+                    &planes[ch * new_width..],
+                    channels * new_width,
+                    new_width,
+                    new_height,
+                    &NO_PRUNING,
+                    None,
+                    |dct_block| dct_channel.extend_from_slice(dct_block),
+                );
+
+                dct_channel
+            }).collect()
+        });
+
+        Self { channels, transparent }
+    }
+
+    /// Quantize the coefficients at the given quality, giving the same
+    /// result as [`dct_compress`].
+    pub fn quantize(&self, quality: u32) -> Vec<Vec<i16>> {
+        let quantization_matrix = quantization_matrix(quality);
+        let transparent = self.transparent.as_ref()
+            .filter(|_| decodes_transparent(quantization_matrix));
 
-    channels.into_iter().for_each(|c| dct_image.push(c));
+        #[cfg(feature = "parallel")]
+        let channels = self.channels.par_iter().enumerate();
+        #[cfg(not(feature = "parallel"))]
+        let channels = self.channels.iter().enumerate();
 
-    dct_image
+        threads::install(|| {
+            channels.map(|(ch, channel)| {
+                let skip = transparent.filter(|(alpha, _)| *alpha != ch).map(|(_, skip)| skip);
+
+                let mut output = Vec::with_capacity(channel.len());
+                channel
+                    .chunks_exact(64)
+                    .enumerate()
+                    .for_each(|(i, block)| match skip {
+                        Some(skip) if skip[i] => output.extend_from_slice(&[0; 64]),
+                        _ => quantize_into(block, quantization_matrix, &mut output),
+                    });
+
+                output
+            }).collect()
+        })
+    }
+}
+
+/// Split interleaved pixels into one plane per channel, padded with zeroes
+/// to `new_width` by `new_height`, in a single pass over the input.
+///
+/// The planes are stored row by row, with each row of the output holding
+/// that row of every channel in turn. Row `y` of channel `ch` starts at
+/// `(y * channels + ch) * new_width`.
+fn deinterleave(
+    input: &[u8],
+    width: usize,
+    channels: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<u8> {
+    let mut planes = Vec::new();
+    deinterleave_into(input, width, channels, new_width, new_height, &mut planes);
+
+    planes
+}
+
+/// Like [`deinterleave`], but overwrites `planes` in place.
+fn deinterleave_into(
+    input: &[u8],
+    width: usize,
+    channels: usize,
+    new_width: usize,
+    new_height: usize,
+    planes: &mut Vec<u8>,
+) {
+    // The padding must be zero, so clear out anything left from before
+    planes.clear();
+    planes.resize(new_width * new_height * channels, 0);
+
+    #[cfg(feature = "parallel")]
+    let rows = planes.par_chunks_exact_mut(new_width * channels)
+        .zip(input.par_chunks_exact(width * channels));
+    #[cfg(not(feature = "parallel"))]
+    let rows = planes.chunks_exact_mut(new_width * channels)
+        .zip(input.chunks_exact(width * channels));
+
+    threads::install(|| {
+        rows.for_each(|(plane_rows, input_row)| {
+            for (x, pixel) in input_row.chunks_exact(channels).enumerate() {
+                for (ch, value) in pixel.iter().enumerate() {
+                    plane_rows[ch * new_width + x] = *value;
+                }
+            }
+        })
+    });
 }
 
 /// Take in an image encoded with DCT and quantized and perform IDCT on it,
 /// returning an approximation of the original data.
-pub fn dct_decompress(input: &[i16], parameters: DctParameters) -> Vec<u8> {
-    let new_width = parameters.width + (8 - parameters.width % 8);
-    let new_height = parameters.height + (8 - parameters.height % 8);
+///
+/// The quantized coefficients are taken one 8x8 block at a time from
+/// `blocks`, in the order [`dct_compress`] produces them. Returns [`None`]
+/// if `blocks` runs out before the image is complete.
+pub fn dct_decompress<I: Iterator<Item = [i16; 64]>>(
+    blocks: &mut I,
+    parameters: DctParameters
+) -> Option<Vec<u8>> {
+    dct_decompress_scaled(blocks, parameters, 1)
+}
 
-    // Precalculate the quantization matrix
-    let quantization_matrix = quantization_matrix(parameters.quality);
+/// Like [`dct_decompress`], but produces the image scaled down by
+/// `denominator`, which must be 1, 2, 4 or 8.
+///
+/// Each 8x8 block becomes a smaller block, found with an inverse DCT of
+/// just its lowest frequency coefficients, so the full size image is never
+/// produced. The output is [`scaled_size`] of the image.
+///
+/// Blocks cut off by the right or bottom edge of the image are decoded in
+/// full and averaged over their visible pixels instead, as their low
+/// frequencies blend the image into the zeroes padding it out.
+pub fn dct_decompress_scaled<I: Iterator<Item = [i16; 64]>>(
+    blocks: &mut I,
+    parameters: DctParameters,
+    denominator: usize,
+) -> Option<Vec<u8>> {
+    dct_decompress_region(blocks, parameters, BlockRegion::all(parameters), denominator)
+}
 
-    let final_img = Arc::new(Mutex::new(vec![0u8; (new_width * new_height) * parameters.format.channels() as usize]));
-    input.par_chunks(new_width * new_height).enumerate().for_each(|(chan_num, channel)| {
-        let decoded_image = Arc::new(Mutex::new(vec![0u8; parameters.width * parameters.height]));
-        channel.par_chunks(64).enumerate().for_each(|(i, chunk)| {
-            let dequantized_dct = dequantize(chunk, quantization_matrix);
-            let original = idct(&dequantized_dct, 8, 8);
+/// Like [`dct_decompress_scaled`], but decodes only a region of the image,
+/// whose blocks are taken from `blocks` in the same order as for a whole
+/// image of that size.
+///
+/// The output is the part of the scaled image the region covers, see
+/// [`BlockRegion::scaled_size`].
+pub fn dct_decompress_region<I: Iterator<Item = [i16; 64]>>(
+    blocks: &mut I,
+    parameters: DctParameters,
+    region: BlockRegion,
+    denominator: usize,
+) -> Option<Vec<u8>> {
+    let channels = parameters.format.channels() as usize;
 
-            // Write rows of blocks
-            let start_x = (i * 8) % new_width;
-            let start_y = ((i * 8) / new_width) * 8;
-            let start = start_x + (start_y * parameters.width);
+    let block_size = 8 / denominator;
+    let (width, height) = region.scaled_size(parameters, denominator);
+    let (origin_x, origin_y) = (region.x * block_size, region.y * block_size);
 
-            for row_num in 0..8 {
-                if start_y + row_num >= parameters.height {
-                    break;
+    let mut final_img = Vec::new();
+    let mut row_blocks = Vec::with_capacity(region.width);
+    for channel in 0..channels {
+        trace!("decoding channel {channel} of {channels}");
+        let quantization_matrix = parameters.quantization_matrix(channel);
+        for block_row in region.y..region.y + region.height {
+            // Decode one row of blocks at a time, so only the coefficients
+            // for that row are ever held in memory
+            row_blocks.clear();
+            row_blocks.extend(blocks.by_ref().take(region.width));
+            if row_blocks.len() != region.width {
+                return None
+            }
+
+            // Only allocate the output once the first blocks are ready, as
+            // producing them may need a lot of memory of its own
+            if final_img.is_empty() {
+                final_img = vec![0u8; width * height * channels];
+            }
+
+            #[cfg(feature = "parallel")]
+            let row_iter = row_blocks.par_iter();
+            #[cfg(not(feature = "parallel"))]
+            let row_iter = row_blocks.iter();
+
+            let visible_height = parameters.height.saturating_sub(block_row * 8).min(8);
+            let decoded_blocks: Vec<Vec<u8>> = threads::install(|| {
+                row_iter.enumerate().map(|(i, block)| {
+                    let dequantized_dct = dequantize(block, quantization_matrix);
+                    let visible_width = parameters.width.saturating_sub((region.x + i) * 8).min(8);
+
+                    if block_size == 8 {
+                        idct(&dequantized_dct, 8, 8)
+                    } else if visible_width < 8 || visible_height < 8 {
+                        let block = idct(&dequantized_dct, 8, 8);
+                        shrink_block(&block, visible_width, visible_height, denominator)
+                    } else {
+                        idct(&low_frequencies(&dequantized_dct, block_size), block_size, block_size)
+                    }
+                }).collect()
+            });
+
+            // Write the visible part of each block into its channel. Blocks
+            // on the right and bottom edges are clamped to the image, and
+            // blocks entirely in the padding are skipped.
+            let block_height = height.saturating_sub(block_row * block_size - origin_y).min(block_size);
+            for (i, decoded) in decoded_blocks.iter().enumerate() {
+                let x = (region.x + i) * block_size - origin_x;
+                let block_width = width.saturating_sub(x).min(block_size);
+
+                for row_num in 0..block_height {
+                    let y = block_row * block_size - origin_y + row_num;
+                    let start = (y * width + x) * channels;
+                    let row = &decoded[row_num * block_size..][..block_width];
+
+                    if channels == 1 {
+                        final_img[start..start + block_width].copy_from_slice(row);
+                        continue
+                    }
+
+                    final_img[start..start + block_width * channels]
+                        .chunks_exact_mut(channels)
+                        .zip(row)
+                        .for_each(|(pixel, value)| pixel[channel] = *value);
                 }
+            }
+        }
+    }
+
+    Some(final_img)
+}
 
-                let row_offset = row_num * parameters.width;
+/// Like [`dct_decompress_region`] at full size, but decodes only the given
+/// channel of the region, as a plane of its own.
+///
+/// The blocks of every channel are still taken from `blocks`, but those of
+/// the other channels are skipped without any inverse DCT.
+pub fn dct_decompress_channel<I: Iterator<Item = [i16; 64]>>(
+    blocks: &mut I,
+    parameters: DctParameters,
+    region: BlockRegion,
+    channel: usize,
+) -> Option<Vec<u8>> {
+    let channels = parameters.format.channels() as usize;
+    let region_blocks = region.width * region.height;
+    let skip = |blocks: &mut I, count: usize| (blocks.take(count).count() == count).then_some(());
 
-                let offset = if start_x + 8 > parameters.width {
-                    parameters.width % 8
-                } else {
-                    8
-                };
+    skip(blocks, channel * region_blocks)?;
+
+    // The channel alone is an image with one channel and its own matrix
+    let plane_parameters = DctParameters {
+        format: ColorFormat::Gray8,
+        matrices: Some([parameters.quantization_matrix(channel); 4]),
+        ..parameters
+    };
+    let plane = dct_decompress_region(blocks, plane_parameters, region, 1)?;
+
+    skip(blocks, (channels - channel - 1) * region_blocks)?;
+
+    Some(plane)
+}
 
-                let row_data = &original[row_num * 8..(row_num * 8) + offset];
-                decoded_image.lock().unwrap()[start + row_offset..start + row_offset + offset].copy_from_slice(row_data);
+/// Build a preview of a region of the image with one pixel per 8x8 block,
+/// which is the part of [`scaled_size`] of the image with a denominator of
+/// 8 that the region covers. The blocks are taken from `blocks` like
+/// [`dct_decompress_region`].
+///
+/// Each pixel is the average of its block, taken from the DC coefficient
+/// alone without any inverse DCT. Blocks cut off by the right or bottom
+/// edge of the image are averaged over just their visible pixels, as the
+/// padding is known to be zero.
+pub fn dct_preview<I: Iterator<Item = [i16; 64]>>(
+    blocks: &mut I,
+    parameters: DctParameters,
+    region: BlockRegion,
+) -> Option<Vec<u8>> {
+    let channels = parameters.format.channels() as usize;
+    let (width, height) = region.scaled_size(parameters, 8);
+
+    let mut final_img = vec![0u8; width * height * channels];
+    for channel in 0..channels {
+        let dc_quantizer = parameters.quantization_matrix(channel)[0] as f32;
+        for block_row in 0..region.height {
+            let visible_height = parameters.height.saturating_sub((region.y + block_row) * 8).min(8);
+
+            for block_column in 0..region.width {
+                let block = blocks.next()?;
+                let visible_width = parameters.width.saturating_sub((region.x + block_column) * 8).min(8);
+                if block_row >= height || block_column >= width {
+                    continue
+                }
+
+                // The transform is orthonormal, so the DC coefficient is
+                // eight times the average of the level shifted block
+                let block_sum = (block[0] as f32 * dc_quantizer / 8.0 + 128.0) * 64.0;
+                let average = block_sum / (visible_width * visible_height) as f32;
+
+                final_img[(block_row * width + block_column) * channels + channel] =
+                    math::round(average).clamp(0.0, 255.0) as u8;
             }
-        });
+        }
+    }
+
+    Some(final_img)
+}
 
-        final_img.lock().unwrap().par_iter_mut()
-            .skip(chan_num)
-            .step_by(parameters.format.channels() as usize)
-            .zip(decoded_image.lock().unwrap().par_iter())
-            .for_each(|(c, n)| *c = *n);
+/// A rectangle of the 8x8 blocks an image is split into, measured in
+/// blocks, including the blocks which are only padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl BlockRegion {
+    /// Every block of the image.
+    pub fn all(parameters: DctParameters) -> Self {
+        let (width, height) = parameters.blocks();
+        Self { x: 0, y: 0, width, height }
+    }
+
+    /// The square tiles of `tile_size` blocks which cover the image, in
+    /// rows from the top left. Tiles on the right and bottom edges are cut
+    /// short by the edge of the padding.
+    pub fn tiles(parameters: DctParameters, tile_size: usize) -> impl Iterator<Item = Self> {
+        let (blocks_wide, blocks_high) = parameters.blocks();
+
+        (0..blocks_high).step_by(tile_size).flat_map(move |y| {
+            (0..blocks_wide).step_by(tile_size).map(move |x| Self {
+                x,
+                y,
+                width: tile_size.min(blocks_wide - x),
+                height: tile_size.min(blocks_high - y),
+            })
+        })
+    }
+
+    /// The number of tiles [`BlockRegion::tiles`] gives, without making
+    /// them.
+    pub fn tile_count(parameters: DctParameters, tile_size: usize) -> usize {
+        let (blocks_wide, blocks_high) = parameters.blocks();
+        blocks_wide.div_ceil(tile_size).saturating_mul(blocks_high.div_ceil(tile_size))
+    }
+
+    /// Whether any block is in both regions.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+
+    /// The coefficients of each row of the region's blocks in a channel,
+    /// as [`dct_compress`] produces them for the whole image.
+    pub fn rows<'a>(&self, parameters: DctParameters, channel: &'a [i16]) -> impl Iterator<Item = &'a [i16]> {
+        let (blocks_wide, _) = parameters.blocks();
+        let (x, width) = (self.x, self.width);
+
+        (self.y..self.y + self.height).map(move |y| &channel[(y * blocks_wide + x) * 64..][..width * 64])
+    }
+
+    /// The width and height of the part of the image scaled down by
+    /// `denominator` which the region covers, leaving out the padding.
+    pub fn scaled_size(&self, parameters: DctParameters, denominator: usize) -> (usize, usize) {
+        let block_size = 8 / denominator;
+        let visible = |start: usize, len: usize, size: usize| {
+            let size = scaled_size(size, denominator);
+            size.min((start + len) * block_size).saturating_sub(start * block_size)
+        };
+
+        (
+            visible(self.x, self.width, parameters.width),
+            visible(self.y, self.height, parameters.height),
+        )
+    }
+}
+
+/// The length of a side of an image `size` pixels long, scaled down by
+/// `denominator` and rounded up.
+pub fn scaled_size(size: usize, denominator: usize) -> usize {
+    size.div_ceil(denominator)
+}
+
+/// The top left `size` by `size` coefficients of a dequantized 8x8 block,
+/// scaled so that their inverse DCT is the block shrunk to that size.
+///
+/// The transform is orthonormal, so a smaller transform of the same
+/// coefficients needs them scaled by `size / 8` to keep the block's
+/// average.
+fn low_frequencies(block: &[f32], size: usize) -> Vec<f32> {
+    let scale = size as f32 / 8.0;
+
+    block.chunks_exact(8)
+        .take(size)
+        .flat_map(|row| &row[..size])
+        .map(|c| c * scale)
+        .collect()
+}
+
+/// Shrink a decoded 8x8 block by `denominator` on each side, averaging
+/// only the pixels within the top left `width` by `height`.
+///
+/// Anything made entirely of pixels outside of that is left as zero.
+fn shrink_block(block: &[u8], width: usize, height: usize, denominator: usize) -> Vec<u8> {
+    let size = 8 / denominator;
+
+    let mut output = vec![0; size * size];
+    for (i, value) in output.iter_mut().enumerate() {
+        let (row, column) = ((i / size) * denominator, (i % size) * denominator);
+        let rows = row..height.min(row + denominator);
+        let columns = column..width.min(column + denominator);
+
+        let count = rows.len() * columns.len();
+        if count == 0 {
+            continue
+        }
+
+        let sum: usize = rows
+            .flat_map(|y| &block[y * 8 + columns.start..y * 8 + columns.end])
+            .map(|v| *v as usize)
+            .sum();
+        *value = ((sum + count / 2) / count) as u8;
+    }
+
+    output
+}
+
+/// Smooth the edges between the 8x8 blocks of a decoded image in place,
+/// hiding the steps quantization leaves between them.
+///
+/// A step across an edge which is less than `strength` times half the
+/// average of the channel's first horizontal and vertical AC quantization
+/// steps is taken to come from quantization, as long as both sides of it
+/// are flat. The two pixels on either side are blended into a ramp. Larger
+/// steps are real edges and left alone, and alpha is never changed.
+/// Nothing is done with a `strength` of 0, or where the quantization is
+/// fine enough that the limit rounds down to 0.
+pub fn dct_deblock(data: &mut [u8], parameters: DctParameters, strength: u8) {
+    let channels = parameters.format.channels() as usize;
+    let (width, height) = (parameters.width, parameters.height);
+
+    for ch in 0..channels {
+        if parameters.format.alpha_channel() == Some(ch) {
+            continue
+        }
+
+        let matrix = parameters.quantization_matrix(ch);
+        let limit = strength as i32 * (matrix[1] as i32 + matrix[8] as i32) / 4;
+        if limit == 0 {
+            continue
+        }
+
+        // Edges between columns of blocks, then between rows of them. Each
+        // needs two pixels on either side.
+        for y in 0..height {
+            for x in (8..width.saturating_sub(1)).step_by(8) {
+                deblock_edge(data, ((y * width) + x - 2) * channels + ch, channels, limit);
+            }
+        }
+
+        for y in (8..height.saturating_sub(1)).step_by(8) {
+            for x in 0..width {
+                deblock_edge(data, ((y - 2) * width + x) * channels + ch, width * channels, limit);
+            }
+        }
+    }
+}
+
+/// Blend the four values starting at `start`, `step` apart, which straddle
+/// an edge between blocks, if the step between the middle two is under
+/// `limit` and the values on either side are within half of that.
+fn deblock_edge(data: &mut [u8], start: usize, step: usize, limit: i32) {
+    let [p1, p0, q0, q1]: [i32; 4] = core::array::from_fn(|i| data[start + i * step] as i32);
+
+    let difference = q0 - p0;
+    if difference == 0 || difference.abs() >= limit || (p1 - p0).abs() > limit / 2 || (q1 - q0).abs() > limit / 2 {
+        return
+    }
+
+    // Eighths of the difference, rounded away from zero
+    let eighths = |n: i32| (difference * n + 4 * difference.signum()) / 8;
+    let filtered = [p1 + eighths(1), p0 + eighths(3), q0 - eighths(3), q1 - eighths(1)];
+    for (i, value) in filtered.into_iter().enumerate() {
+        data[start + i * step] = value.clamp(0, 255) as u8;
+    }
+}
+
+/// Peak signal to noise ratio between two images in decibels. Higher is
+/// better, and it is infinite if they are identical.
+pub fn psnr(original: &[u8], decoded: &[u8]) -> f64 {
+    let squared_error: f64 = original.iter()
+        .zip(decoded)
+        .map(|(a, b)| (*a as f64 - *b as f64) * (*a as f64 - *b as f64))
+        .sum();
+    let mse = squared_error / original.len() as f64;
+
+    10.0 * math::log10(255.0 * 255.0 / mse)
+}
+
+/// The [`psnr`] of an image after a round trip through [`dct_compress`]
+/// and [`dct_decompress`].
+pub fn dct_round_trip_psnr(input: &[u8], parameters: DctParameters) -> f64 {
+    let coefficients: Vec<i16> = dct_compress(input, parameters).concat();
+    let mut blocks = coefficients.chunks_exact(64).map(|b| b.try_into().unwrap());
+
+    match dct_decompress(&mut blocks, parameters) {
+        Some(output) => psnr(input, &output),
+        None => 0.0,
+    }
+}
+
+/// Find the lowest quality level whose [`dct_round_trip_psnr`] is at least
+/// `target`, using a binary search over the quality range.
+///
+/// Returns the quality and the PSNR it reaches. If even the highest
+/// quality falls short, that is returned along with its PSNR.
+pub(crate) fn quality_for_psnr(input: &[u8], parameters: DctParameters, target: f64) -> (u8, f64) {
+    let psnr_at = |quality: u8| dct_round_trip_psnr(input, DctParameters {
+        quality: quality as u32,
+        matrices: None,
+        ..parameters
     });
 
-    Arc::try_unwrap(final_img).unwrap().into_inner().unwrap()
+    let (mut low, mut high) = (*QUALITY_RANGE.start(), *QUALITY_RANGE.end());
+    let mut best = psnr_at(high);
+    if best < target {
+        return (high, best)
+    }
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let psnr = psnr_at(middle);
+        if psnr >= target {
+            high = middle;
+            best = psnr;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    (high, best)
 }
 
 /// Parameters to pass to the [`dct_compress`] function.
 #[derive(Debug, Clone, Copy)]
 pub struct DctParameters {
-    /// A quality level from 1-100. Higher values provide better results.
-    /// Default value is 80.
+    /// A quality level from 1-100. Higher values provide better results,
+    /// and values outside that range are clamped. Default value is 80.
     pub quality: u32,
 
     /// The color format of the input bytes.
@@ -263,6 +1120,40 @@ pub struct DctParameters {
 
     /// Height of the input image
     pub height: usize,
+
+    /// The quantization matrix for each channel, in the order they are
+    /// interleaved, replacing the one [`quantization_matrix`] gives for the
+    /// quality. Entries past the last channel of the format are unused.
+    ///
+    /// With [`None`], every channel uses the matrix for the quality.
+    pub matrices: Option<[[u16; 64]; 4]>,
+}
+
+impl DctParameters {
+    /// The quantization matrix for the given channel.
+    pub fn quantization_matrix(&self, channel: usize) -> [u16; 64] {
+        match self.matrices {
+            Some(matrices) => matrices[channel],
+            None => quantization_matrix(self.quality),
+        }
+    }
+
+    /// The number of 8x8 blocks across and down each channel of the image,
+    /// including those which are only padding.
+    pub fn blocks(&self) -> (usize, usize) {
+        ((self.width + (8 - self.width % 8)) / 8, (self.height + (8 - self.height % 8)) / 8)
+    }
+
+    /// The number of quantized coefficients [`dct_compress`] produces for
+    /// these parameters, or [`None`] if it would overflow.
+    pub fn coefficient_count(&self) -> Option<usize> {
+        let new_width = self.width.checked_add(8 - self.width % 8)?;
+        let new_height = self.height.checked_add(8 - self.height % 8)?;
+
+        new_width
+            .checked_mul(new_height)?
+            .checked_mul(self.format.channels() as usize)
+    }
 }
 
 impl Default for DctParameters {
@@ -272,6 +1163,7 @@ impl Default for DctParameters {
             format: ColorFormat::Rgba8,
             width: 0,
             height: 0,
+            matrices: None,
         }
     }
 }
@@ -326,6 +1218,344 @@ mod tests {
         );
     }
 
+    #[test]
+    fn dct_compress_blocks() {
+        // 9x7 gray pads to 16x8, which is two blocks side by side
+        let input: Vec<u8> = (0..9 * 7).map(|i| (i * 37 % 251) as u8).collect();
+        let parameters = DctParameters {
+            quality: 80,
+            format: ColorFormat::Gray8,
+            width: 9,
+            height: 7,
+            matrices: None,
+        };
+
+        let mut blocks = [[0u8; 64]; 2];
+        for y in 0..7 {
+            for x in 0..9 {
+                blocks[x / 8][y * 8 + x % 8] = input[y * 9 + x];
+            }
+        }
+
+        let expected: Vec<i16> = blocks.iter()
+            .flat_map(|b| quantize(&dct(b, 8, 8), quantization_matrix(80)))
+            .collect();
+
+        assert_eq!(dct_compress(&input, parameters), [expected]);
+    }
+
+    #[test]
+    fn dct_round_trip_odd_sizes() {
+        for width in 7..=17 {
+            for height in 7..=17 {
+                let parameters = DctParameters {
+                    quality: 90,
+                    format: ColorFormat::Rgb8,
+                    width,
+                    height,
+                    matrices: None,
+                };
+
+                // A smooth gradient which is different in each channel, so
+                // any misplaced block or channel stands out
+                let input: Vec<u8> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .flat_map(|(x, y)| [
+                        (x * 255 / width) as u8,
+                        (y * 255 / height) as u8,
+                        ((x + y) * 100 / (width + height)) as u8 + 50,
+                    ])
+                    .collect();
+
+                let coefficients: Vec<i16> = dct_compress(&input, parameters).concat();
+                let mut blocks = coefficients.chunks_exact(64).map(|b| b.try_into().unwrap());
+                let output = dct_decompress(&mut blocks, parameters).unwrap();
+
+                assert_eq!(output.len(), input.len(), "{width}x{height}");
+                let psnr = psnr(&input, &output);
+                assert!(psnr > 33.0, "{width}x{height} has a PSNR of {psnr:.2}");
+            }
+        }
+    }
+
+    #[test]
+    fn coefficients_match_dct_compress() {
+        let input: Vec<u8> = (0..13 * 11 * 3).map(|i| (i * 37 % 251) as u8).collect();
+        let parameters = DctParameters {
+            quality: 80,
+            format: ColorFormat::Rgb8,
+            width: 13,
+            height: 11,
+            matrices: None,
+        };
+
+        let coefficients = DctCoefficients::new(&input, parameters);
+        for quality in [1, 37, 80, 100] {
+            assert_eq!(
+                coefficients.quantize(quality),
+                dct_compress(&input, DctParameters { quality, ..parameters })
+            );
+        }
+    }
+
+    #[test]
+    fn pruned_coefficients_quantize_to_zero() {
+        let mut state = 0x2545_F491u32;
+        let mut pruned_any = false;
+        for quality in [1, 3, 10, 25, 50, 80, 100] {
+            let quantization_matrix = quantization_matrix(quality);
+            let ranges = pruning_ranges(quantization_matrix);
+
+            for amplitude in [0u32, 1, 2, 4, 8, 16, 40, 255] {
+                for _ in 0..20 {
+                    let base = state % (256 - amplitude);
+                    let block: [u8; 64] = core::array::from_fn(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 17;
+                        state ^= state << 5;
+                        (base + state % (amplitude + 1)) as u8
+                    });
+
+                    let full = dct(&block, 8, 8);
+                    let mut pruned = [0.0; 64];
+                    dct_block_pruned(&block, &ranges, &mut pruned);
+
+                    // Everything computed is exact, and everything left out
+                    // would have quantized to zero anyway
+                    for i in 0..64 {
+                        if pruned[i] != full[i] {
+                            assert_eq!(pruned[i], 0.0);
+                            assert_eq!(math::round(full[i] / quantization_matrix[i] as f32), 0.0);
+                            pruned_any = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        assert!(pruned_any);
+    }
+
+    #[test]
+    fn pruned_compression_matches_coefficients() {
+        // Smooth content, where low qualities leave out most coefficients
+        let (width, height) = (37, 29);
+        let input: Vec<u8> = (0..width * height * 3)
+            .map(|i| ((i / 3 % width + i / 3 / width) * 2 + i % 3 * 20) as u8)
+            .collect();
+
+        for format in [ColorFormat::Gray8, ColorFormat::Rgb8] {
+            let input = &input[..width * height * format.channels() as usize];
+            let parameters = DctParameters { quality: 80, format, width, height, matrices: None };
+
+            let coefficients = DctCoefficients::new(input, parameters);
+            for quality in [1, 3, 10, 25, 50, 80] {
+                let parameters = DctParameters { quality, ..parameters };
+                let expected = coefficients.quantize(quality);
+                assert_eq!(dct_compress(input, parameters), expected);
+
+                let mut sequential = Vec::new();
+                dct_compress_sequential(input, parameters, |c| sequential.push(c.to_vec()));
+                assert_eq!(sequential, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_blocks_are_skipped() {
+        // A sprite on a transparent background, with noise in the color of
+        // the transparent pixels. The sprite covers blocks partly.
+        let (width, height) = (45, 37);
+        let mut state = 0x2545_F491u32;
+        let input: Vec<u8> = (0..width * height).flat_map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            let (x, y) = (i % width, i / width);
+            let alpha = if (12..30).contains(&x) && (5..20).contains(&y) { 255 } else { 0 };
+            [(state >> 24) as u8, (x * 5) as u8, (y * 6) as u8, alpha]
+        }).collect();
+
+        let new_width = width + (8 - width % 8);
+        let new_height = height + (8 - height % 8);
+        let planes = deinterleave(&input, width, 4, new_width, new_height);
+        let transparent = fully_transparent_blocks(&planes[3 * new_width..], 4 * new_width, DctParameters {
+            quality: 80,
+            format: ColorFormat::Rgba8,
+            width,
+            height,
+            matrices: None,
+        });
+        assert!(transparent.iter().any(|t| *t) && transparent.iter().any(|t| !*t));
+
+        let mut skipped_any = false;
+        for quality in [1, 10, 50, 80, 100] {
+            let parameters = DctParameters { quality, format: ColorFormat::Rgba8, width, height, matrices: None };
+            let skipped = decodes_transparent(quantization_matrix(quality));
+            skipped_any |= skipped;
+
+            // Every block transformed
+            let full: Vec<Vec<i16>> = (0..4).map(|ch| {
+                let mut channel = Vec::new();
+                compress_plane(&planes[ch * new_width..], 4 * new_width, new_width, new_height, quantization_matrix(quality), None, &mut channel);
+                channel
+            }).collect();
+
+            let coefficients = dct_compress(&input, parameters);
+            assert_eq!(coefficients[3], full[3]);
+            for ch in 0..3 {
+                for (i, (block, full_block)) in coefficients[ch].chunks_exact(64).zip(full[ch].chunks_exact(64)).enumerate() {
+                    if skipped && transparent[i] {
+                        assert_eq!(block, [0; 64]);
+                    } else {
+                        assert_eq!(block, full_block);
+                    }
+                }
+            }
+
+            let mut sequential = Vec::new();
+            dct_compress_sequential(&input, parameters, |c| sequential.push(c.to_vec()));
+            assert_eq!(sequential, coefficients);
+            assert_eq!(DctCoefficients::new(&input, parameters).quantize(quality), coefficients);
+
+            // Alpha decodes exactly the same, as do the visible pixels
+            let decode = |coefficients: &[Vec<i16>]| {
+                let mut blocks = coefficients.iter().flat_map(|c| c.chunks_exact(64)).map(|b| b.try_into().unwrap());
+                dct_decompress(&mut blocks, parameters).unwrap()
+            };
+            let (decoded, decoded_full) = (decode(&coefficients), decode(&full));
+            for (pixel, full_pixel) in decoded.chunks_exact(4).zip(decoded_full.chunks_exact(4)) {
+                assert_eq!(pixel[3], full_pixel[3]);
+                if pixel[3] > MAX_HIDDEN_ALPHA {
+                    assert_eq!(pixel, full_pixel);
+                }
+            }
+
+            if skipped {
+                let nonzero = |c: &[Vec<i16>]| c.iter().flatten().filter(|v| **v != 0).count();
+                assert!(nonzero(&coefficients) < nonzero(&full), "quality {quality}");
+            }
+        }
+
+        assert!(skipped_any);
+        assert!(!decodes_transparent(quantization_matrix(1)));
+    }
+
+    #[test]
+    fn single_channel_matches_planes() {
+        for (width, height) in [(1, 1), (8, 8), (13, 11), (16, 3), (67, 45)] {
+            let input: Vec<u8> = (0..width * height).map(|i| (i * 37 % 251) as u8).collect();
+            let parameters = DctParameters {
+                quality: 80,
+                format: ColorFormat::Gray8,
+                width,
+                height,
+                matrices: None,
+            };
+
+            // The general path, through a padded plane
+            let new_width = width + (8 - width % 8);
+            let new_height = height + (8 - height % 8);
+            let plane = deinterleave(&input, width, 1, new_width, new_height);
+            let mut expected = Vec::new();
+            compress_plane(&plane, new_width, new_width, new_height, quantization_matrix(80), None, &mut expected);
+
+            let coefficients = dct_compress(&input, parameters);
+            assert_eq!(coefficients, [expected.clone()]);
+            assert_eq!(DctCoefficients::new(&input, parameters).quantize(80), [expected]);
+
+            // Decoding writes whole rows, so compare against a gray channel
+            // of a two channel image
+            let mut blocks = coefficients[0].chunks_exact(64).map(|b| b.try_into().unwrap());
+            let decoded = dct_decompress(&mut blocks, parameters).unwrap();
+
+            let gray_alpha: Vec<u8> = input.iter().flat_map(|v| [*v, 255]).collect();
+            let parameters = DctParameters { format: ColorFormat::GrayA8, ..parameters };
+            let two_channels = dct_compress(&gray_alpha, parameters);
+            let mut blocks = two_channels.iter().flat_map(|c| c.chunks_exact(64)).map(|b| b.try_into().unwrap());
+            let expected: Vec<u8> = dct_decompress(&mut blocks, parameters).unwrap().into_iter().step_by(2).collect();
+            assert_eq!(decoded, expected, "{width}x{height}");
+        }
+    }
+
+    /// The sum of the steps across every edge between blocks in a channel.
+    fn block_edge_steps(data: &[u8], width: usize, height: usize, channels: usize, ch: usize) -> u32 {
+        let value = |x: usize, y: usize| data[(y * width + x) * channels + ch] as i32;
+        let columns = (0..height).flat_map(|y| (8..width).step_by(8).map(move |x| (value(x, y) - value(x - 1, y)).unsigned_abs()));
+        let rows = (8..height).step_by(8).flat_map(|y| (0..width).map(move |x| (value(x, y) - value(x, y - 1)).unsigned_abs()));
+
+        columns.chain(rows).sum()
+    }
+
+    #[test]
+    fn deblock_flat_and_edges() {
+        let (width, height) = (37, 29);
+        let parameters = DctParameters { quality: 20, format: ColorFormat::Rgba8, width, height, matrices: None };
+
+        let flat = vec![90u8; width * height * 4];
+        let mut deblocked = flat.clone();
+        dct_deblock(&mut deblocked, parameters, 4);
+        assert_eq!(deblocked, flat);
+
+        // A step much larger than the quantization is a real edge
+        let edge: Vec<u8> = (0..width * height).flat_map(|i| if i % width < 16 { [20; 4] } else { [220; 4] }).collect();
+        let mut deblocked = edge.clone();
+        dct_deblock(&mut deblocked, parameters, 1);
+        assert_eq!(deblocked, edge);
+
+        // Nothing is done at the highest quality, or with no strength
+        let steps: Vec<u8> = (0..width * height * 4).map(|i| (i / 4 % width / 8 * 3) as u8).collect();
+        for (quality, strength) in [(100, 4), (20, 0)] {
+            let mut deblocked = steps.clone();
+            dct_deblock(&mut deblocked, DctParameters { quality, ..parameters }, strength);
+            assert_eq!(deblocked, steps);
+        }
+    }
+
+    #[test]
+    fn deblock_blocky_gradient() {
+        // Every block is flat, stepping up a little from each to the next,
+        // as a smooth gradient comes out at a low quality
+        let (width, height) = (45, 37);
+        let input: Vec<u8> = (0..width * height).flat_map(|i| {
+            let (bx, by) = ((i % width / 8) as u8, (i / width / 8) as u8);
+            [40 + bx * 5, 90 + by * 4, 200 - bx * 3 - by * 3, 100 + bx * 20]
+        }).collect();
+
+        let parameters = DctParameters { quality: 20, format: ColorFormat::Rgba8, width, height, matrices: None };
+        let mut deblocked = input.clone();
+        dct_deblock(&mut deblocked, parameters, 1);
+
+        for ch in 0..3 {
+            let before = block_edge_steps(&input, width, height, 4, ch);
+            let after = block_edge_steps(&deblocked, width, height, 4, ch);
+            assert!(after * 2 < before, "channel {ch} went from {before} to {after}");
+        }
+
+        // Alpha steps by more, but is never touched
+        assert!(deblocked.iter().zip(&input).skip(3).step_by(4).all(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn deinterleave_planes() {
+        // 3x2 RGB pads to 8x8
+        let input: Vec<u8> = (0..3 * 2 * 3).collect();
+        let planes = deinterleave(&input, 3, 3, 8, 8);
+
+        assert_eq!(planes.len(), 8 * 8 * 3);
+        for ch in 0..3 {
+            for y in 0..8 {
+                let row = &planes[(y * 3 + ch) * 8..][..8];
+                let expected: Vec<u8> = (0..8).map(|x| {
+                    if x < 3 && y < 2 { input[(y * 3 + x) * 3 + ch] } else { 0 }
+                }).collect();
+
+                assert_eq!(row, expected);
+            }
+        }
+    }
+
     #[test]
     fn create_quantization_matrix_q80() {
         let result = quantization_matrix(80);
@@ -1,16 +1,103 @@
-use std::{
-    collections::HashMap,
-    io::{Cursor, Read, Write},
-};
+use core::ops::Range;
+
+use alloc::{vec, vec::Vec};
 
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use rayon::iter::{IntoParallelRefIterator, ParallelExtend, ParallelIterator};
+#[cfg(feature = "parallel")]
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+#[cfg(feature = "parallel")]
+use crate::threads;
 use thiserror::Error;
 
-use crate::binio::{BitReader, BitWriter};
+use super::{
+    bwt::{compress_bwt, decompress_bwt},
+    lz77::{compress_lz77, decompress_lz77},
+    predictive::{compress_predictive, decompress_predictive, PredictiveParameters},
+};
+use crate::{
+    binio::{canonical_codes, huffman_lengths, read_huffman_lengths, write_huffman_lengths, BitReader, BitWriter, HuffmanDecoder},
+    header::{CompressionType, Header, FIRST_HUFFMAN_VERSION, FIRST_STORED_NONE_VERSION},
+    io::{self, read_vec, Read, ReadExt, Write, WriteExt},
+    picture::DecodeWarning,
+};
+
+#[cfg(feature = "std")]
+pub(crate) type Dictionary = std::collections::HashMap<u64, u64, core::hash::BuildHasherDefault<FxHasher>>;
+#[cfg(not(feature = "std"))]
+pub(crate) type Dictionary = alloc::collections::BTreeMap<u64, u64>;
+
+/// The largest number of codes in a chunk's dictionary.
+const DICTIONARY_LIMIT: u64 = 0x3FFFE;
+
+/// Marks an LZW chunk whose codes are written as they are.
+const PLAIN_CODES: u8 = 0;
+
+/// Marks an LZW chunk whose codes are Huffman coded.
+const HUFFMAN_CODES: u8 = 1;
+
+/// The number of symbols LZW codes are split into for Huffman coding, see
+/// [`code_symbol`].
+const CODE_SYMBOLS: usize = 256 + 2 * 10;
+
+/// The largest number of bytes in a chunk which is stored as it is.
+const STORED_CHUNK_LEN: usize = 1 << 18;
+
+/// The most content a [`Primer`] is built from, which keeps the primed
+/// codes well within [`DICTIONARY_LIMIT`].
+pub(crate) const MAX_PRIMER_LEN: usize = 1 << 16;
+
+/// The hash function used by rustc, which is much faster than the default
+/// SipHash for short keys. The dictionary is never exposed to untrusted
+/// lookups, so collision resistance isn't needed.
+///
+/// Codes are assigned in insertion order, so the hash function has no
+/// effect on the compressed output.
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub(crate) struct FxHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "std")]
+impl FxHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::hash::Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add_to_hash(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+
+        for byte in chunks.remainder() {
+            self.add_to_hash(*byte as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
 
 /// The size of compressed data in each chunk
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ChunkInfo {
     /// The size of the data when compressed
     pub size_compressed: usize,
@@ -27,75 +114,283 @@ pub struct CompressionInfo {
 
     /// The compression chunk information
     pub chunks: Vec<ChunkInfo>,
+
+    /// Offset of each chunk from the start of the file, once the chunk
+    /// table has been read from one. Empty for chunks which were only just
+    /// compressed.
+    pub offsets: Vec<u64>,
 }
 
 impl CompressionInfo {
-    pub fn write_into<T: WriteBytesExt + Write>(
+    pub fn write_into<T: Write>(
         &self,
         output: &mut T,
-    ) -> Result<usize, std::io::Error> {
+    ) -> Result<usize, io::Error> {
         let mut size = 0;
-        output.write_u32::<LE>(self.chunk_count as u32)?;
+        output.write_u32_le(self.chunk_count as u32)?;
         size += 4;
 
         for chunk in &self.chunks {
-            output.write_u32::<LE>(chunk.size_compressed as u32)?;
-            output.write_u32::<LE>(chunk.size_raw as u32)?;
+            output.write_u32_le(chunk.size_compressed as u32)?;
+            output.write_u32_le(chunk.size_raw as u32)?;
             size += 8;
         }
 
         Ok(size)
     }
 
-    pub fn read_from<T: Read + ReadBytesExt>(input: &mut T) -> Self {
+    /// Like [`CompressionInfo::write_into`], but with the offset of each
+    /// chunk from the start of the file as a little endian `u64` after its
+    /// sizes, as stored from format version 8 on. The chunks are laid out
+    /// one after another from `data_offset`.
+    pub fn write_with_offsets<T: Write>(&self, output: &mut T, data_offset: u64) -> Result<usize, io::Error> {
+        output.write_u32_le(self.chunk_count as u32)?;
+
+        let mut offset = data_offset;
+        for chunk in &self.chunks {
+            output.write_u32_le(chunk.size_compressed as u32)?;
+            output.write_u32_le(chunk.size_raw as u32)?;
+            output.write_u64_le(offset)?;
+            offset += chunk.size_compressed as u64;
+        }
+
+        Ok(self.len_with_offsets())
+    }
+
+    /// Size of the chunk table in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        4 + self.chunks.len() * 8
+    }
+
+    /// Size of the chunk table in bytes when it stores the offset of each
+    /// chunk.
+    pub fn len_with_offsets(&self) -> usize {
+        4 + self.chunks.len() * 16
+    }
+
+    pub fn read_from<T: Read>(input: &mut T) -> Result<Self, io::Error> {
+        let chunk_count = input.read_u32_le()? as usize;
+
+        Self::read_chunks(input, chunk_count)
+    }
+
+    /// Read the rest of the chunk table, after the chunk count.
+    pub fn read_chunks<T: Read>(input: &mut T, chunk_count: usize) -> Result<Self, io::Error> {
         let mut compression_info = CompressionInfo {
-            chunk_count: input.read_u32::<LE>().unwrap() as usize,
-            chunks: Vec::new(),
+            chunk_count,
+            ..Default::default()
         };
 
         for _ in 0..compression_info.chunk_count {
             compression_info.chunks.push(ChunkInfo {
-                size_compressed: input.read_u32::<LE>().unwrap() as usize,
-                size_raw: input.read_u32::<LE>().unwrap() as usize,
+                size_compressed: input.read_u32_le()? as usize,
+                size_raw: input.read_u32_le()? as usize,
             });
         }
 
-        compression_info
+        Ok(compression_info)
+    }
+
+    /// Like [`CompressionInfo::read_chunks`], for a chunk table which stores
+    /// the offset of each chunk.
+    pub fn read_chunks_with_offsets<T: Read>(input: &mut T, chunk_count: usize) -> Result<Self, io::Error> {
+        let mut compression_info = CompressionInfo {
+            chunk_count,
+            ..Default::default()
+        };
+
+        for _ in 0..compression_info.chunk_count {
+            compression_info.chunks.push(ChunkInfo {
+                size_compressed: input.read_u32_le()? as usize,
+                size_raw: input.read_u32_le()? as usize,
+            });
+            compression_info.offsets.push(input.read_u64_le()?);
+        }
+
+        Ok(compression_info)
+    }
+
+    /// The bytes of the file which chunk `i` takes up.
+    ///
+    /// Without [`CompressionInfo::offsets`], the range is from the start of
+    /// the first chunk instead.
+    ///
+    /// # Panics
+    /// If there is no chunk `i`.
+    pub fn chunk_range(&self, i: usize) -> Range<u64> {
+        let start = match self.offsets.get(i) {
+            Some(offset) => *offset,
+            None => self.chunks[..i].iter().map(|c| c.size_compressed as u64).sum(),
+        };
+
+        start..start + self.chunks[i].size_compressed as u64
+    }
+}
+
+/// The algorithm which compresses each chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// LZW with a dictionary of up to [`DICTIONARY_LIMIT`] codes per chunk.
+    Lzw,
+
+    /// LZW, with each chunk starting with a byte saying whether its codes
+    /// are Huffman coded, see [`write_flagged_codes`]. This is used from
+    /// format version 5 on.
+    LzwHuffman,
+
+    /// Matches within a sliding window, see [`super::lz77`].
+    Lz77,
+
+    /// The Burrows–Wheeler transform with move-to-front and run-length
+    /// coding, see [`super::bwt`].
+    Bwt,
+
+    /// Chunks of up to [`STORED_CHUNK_LEN`] bytes stored as they are, for
+    /// data which is already entropy coded, such as [range coded]
+    /// coefficients, and uncompressed images from format version 9 on.
+    ///
+    /// [range coded]: Header::range_coded
+    Stored,
+
+    /// Bands of rows coded from predictions of each sample, see
+    /// [`super::predictive`].
+    Predictive(PredictiveParameters),
+}
+
+impl From<&Header> for Backend {
+    fn from(header: &Header) -> Self {
+        match header.compression_type {
+            CompressionType::LosslessV2 => Self::Lz77,
+            CompressionType::LosslessBwt => Self::Bwt,
+            CompressionType::Predictive => Self::Predictive(PredictiveParameters::new(header)),
+            CompressionType::LossyDct if header.range_coded => Self::Stored,
+            CompressionType::None if header.version >= FIRST_STORED_NONE_VERSION => Self::Stored,
+            CompressionType::None | CompressionType::Lossless | CompressionType::LossyDct => {
+                if header.version >= FIRST_HUFFMAN_VERSION {
+                    Self::LzwHuffman
+                } else {
+                    Self::Lzw
+                }
+            },
+        }
     }
 }
 
 #[derive(Debug, Error)]
 pub enum CompressionError {
-    #[error("bad compressed element \"{1}\" at byte {2}")]
-    BadElement(Vec<u8>, u64, usize),
+    /// The data decompressed before the problem, the element which was
+    /// bad, and the bit offset in the chunk where it starts.
+    #[error("bad compressed element \"{1}\" at bit {2}")]
+    BadElement(Vec<u8>, u64, u64),
 
     #[error("no chunks compressed")]
     NoChunks,
 }
 
-pub fn compress(data: &[u8]) -> Result<(Vec<u8>, CompressionInfo), CompressionError> {
-    let mut part_data;
+/// The LZW dictionary built from the content of a shared dictionary, which
+/// every chunk starts from in place of one holding only single bytes.
+///
+/// The content is compressed like a chunk, without keeping its codes, so
+/// the primed strings take the codes from 257 on. The first code of a chunk
+/// then adds an entry which is never used, as it does without priming, so
+/// a primed chunk's own strings start one code after the primed ones.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Primer {
+    /// The code of each primed string, keyed as in a chunk's dictionary.
+    codes: Dictionary,
 
-    let mut offset = 0;
-    let mut count;
-    let mut last: Vec<u8> = Vec::new();
+    /// Each primed string, in code order.
+    strings: Vec<Vec<u8>>,
+}
+
+impl Primer {
+    /// Build the dictionary left by compressing up to [`MAX_PRIMER_LEN`]
+    /// bytes of `content`.
+    pub fn new(content: &[u8]) -> Self {
+        let mut primer = Self::default();
+
+        let mut element = None;
+        for c in &content[..content.len().min(MAX_PRIMER_LEN)] {
+            element = match element {
+                None => Some(*c as u64),
+                Some(prefix) => match primer.codes.get(&dictionary_key(prefix, *c)) {
+                    Some(code) => Some(*code),
+                    None => {
+                        let mut string = match prefix {
+                            0..256 => vec![prefix as u8],
+                            _ => primer.strings[prefix as usize - 257].clone(),
+                        };
+                        string.push(*c);
+
+                        primer.codes.insert(dictionary_key(prefix, *c), 257 + primer.strings.len() as u64);
+                        primer.strings.push(string);
+                        Some(*c as u64)
+                    },
+                },
+            };
+        }
+
+        primer
+    }
+
+    /// The code of the first string a chunk adds to the dictionary.
+    fn first_code(&self) -> u64 {
+        258 + self.strings.len() as u64
+    }
+}
+
+#[allow(dead_code)]
+pub fn compress(data: &[u8], backend: Backend) -> Result<(Vec<u8>, CompressionInfo), CompressionError> {
+    compress_with_primer(data, backend, None)
+}
+
+/// Like [`compress`], but starting each LZW chunk from the dictionary of
+/// `primer`. Other backends ignore it.
+pub(crate) fn compress_with_primer(
+    data: &[u8],
+    backend: Backend,
+    primer: Option<&Primer>,
+) -> Result<(Vec<u8>, CompressionInfo), CompressionError> {
+    let mut output_buf = Vec::new();
+    let output_info = compress_into(data, backend, &mut Dictionary::default(), &mut output_buf, primer)?;
+
+    Ok((output_buf, output_info))
+}
 
-    let mut output_buf: Vec<u8> = Vec::new();
+/// Like [`compress_with_primer`], but appends the compressed data to
+/// `output` and reuses the allocations of `dictionary`.
+pub(crate) fn compress_into(
+    data: &[u8],
+    backend: Backend,
+    dictionary: &mut Dictionary,
+    output: &mut Vec<u8>,
+    primer: Option<&Primer>,
+) -> Result<CompressionInfo, CompressionError> {
+    let mut offset = 0;
     let mut output_info = CompressionInfo {
         ..Default::default()
     };
 
     loop {
-        (count, part_data, last) = compress_lzw(&data[offset..], last);
+        let start = output.len();
+        let count = match backend {
+            Backend::Lzw => compress_lzw(&data[offset..], dictionary, output, false, primer),
+            Backend::LzwHuffman => compress_lzw(&data[offset..], dictionary, output, true, primer),
+            Backend::Lz77 => compress_lz77(&data[offset..], output),
+            Backend::Bwt => compress_bwt(&data[offset..], output),
+            Backend::Stored => compress_stored(&data[offset..], output),
+            Backend::Predictive(parameters) => compress_predictive(&data[offset..], output, parameters),
+        };
         if count == 0 {
+            output.truncate(start);
             break;
         }
         offset += count;
 
-        output_buf.write_all(&part_data).unwrap();
-
+        trace!("chunk {} compressed {} bytes into {}", output_info.chunk_count, count, output.len() - start);
         output_info.chunks.push(ChunkInfo {
-            size_compressed: part_data.len(),
+            size_compressed: output.len() - start,
             size_raw: count,
         });
 
@@ -106,147 +401,527 @@ pub fn compress(data: &[u8]) -> Result<(Vec<u8>, CompressionInfo), CompressionEr
         return Err(CompressionError::NoChunks)
     }
 
-    Ok((output_buf, output_info))
+    debug!(
+        "compressed {} bytes into {} chunks of {} bytes with {:?}",
+        data.len(),
+        output_info.chunk_count,
+        output_info.chunks.iter().map(|c| c.size_compressed).sum::<usize>(),
+        backend,
+    );
+    Ok(output_info)
 }
 
-fn compress_lzw(data: &[u8], last: Vec<u8>) -> (usize, Vec<u8>, Vec<u8>) {
-    let mut count = 0;
-    let mut dictionary: HashMap<Vec<u8>, u64> = HashMap::from_iter((0..=255).map(|i| (vec![i], i as u64)));
-    let mut dictionary_count = (dictionary.len() + 1) as u64;
+/// Compress a single chunk from the start of `data`.
+///
+/// Returns the number of bytes of `data` which were consumed, and the
+/// compressed chunk. Calling this at each chunk boundary produces the same
+/// chunks as [`compress`].
+pub fn compress_chunk(data: &[u8], backend: Backend) -> (usize, Vec<u8>) {
+    compress_chunk_with_primer(data, backend, None)
+}
 
-    let mut element = Vec::new();
-    if last.is_empty() {
-        element = last
-    }
+/// Like [`compress_chunk`], but starting an LZW chunk from the dictionary
+/// of `primer`.
+pub(crate) fn compress_chunk_with_primer(data: &[u8], backend: Backend, primer: Option<&Primer>) -> (usize, Vec<u8>) {
+    let mut output = Vec::new();
+    let count = match backend {
+        Backend::Lzw => compress_lzw(data, &mut Dictionary::default(), &mut output, false, primer),
+        Backend::LzwHuffman => compress_lzw(data, &mut Dictionary::default(), &mut output, true, primer),
+        Backend::Lz77 => compress_lz77(data, &mut output),
+        Backend::Bwt => compress_bwt(data, &mut output),
+        Backend::Stored => compress_stored(data, &mut output),
+        Backend::Predictive(parameters) => compress_predictive(data, &mut output, parameters),
+    };
 
-    let mut output_buf = Vec::new();
-    let mut bit_io = BitWriter::new(&mut output_buf);
-    let write_bit = |bit_io: &mut BitWriter<Vec<u8>>, code: u64| {
-        if code > 0x7FFF {
-            bit_io.write_bit(1, 1);
-            bit_io.write_bit(code, 18);
-        } else {
-            bit_io.write_bit(0, 1);
-            bit_io.write_bit(code, 15);
-        }
+    (count, output)
+}
+
+/// Copy a chunk of up to [`STORED_CHUNK_LEN`] bytes from the start of
+/// `data` to `output` as it is.
+///
+/// Returns the number of bytes of `data` which were consumed.
+fn compress_stored(data: &[u8], output: &mut Vec<u8>) -> usize {
+    let data = &data[..data.len().min(STORED_CHUNK_LEN)];
+    output.extend_from_slice(data);
+
+    data.len()
+}
+
+/// Empty a dictionary, keeping room for the largest number of codes a chunk
+/// can use.
+///
+/// The dictionary keeps its allocation, so reusing one across chunks and
+/// images avoids allocating it again.
+fn reset_dictionary(dictionary: &mut Dictionary) {
+    dictionary.clear();
+
+    #[cfg(feature = "std")]
+    dictionary.reserve(DICTIONARY_LIMIT as usize);
+}
+
+/// The dictionary key for the string with code `prefix` followed by `byte`.
+///
+/// Every prefix of a string in the dictionary is also in it, so strings are
+/// stored this way rather than in full. Single bytes are never stored, their
+/// code is the byte itself.
+fn dictionary_key(prefix: u64, byte: u8) -> u64 {
+    (prefix << 8) | byte as u64
+}
+
+/// Compress a chunk from the start of `data`, appending it to `output`.
+/// With `flagged`, the codes are written by [`write_flagged_codes`], and
+/// with a `primer` the dictionary starts from its strings.
+///
+/// Returns the number of bytes of `data` which were consumed.
+fn compress_lzw(data: &[u8], dictionary: &mut Dictionary, output: &mut Vec<u8>, flagged: bool, primer: Option<&Primer>) -> usize {
+    let mut count = 0;
+    let mut dictionary_count = match primer {
+        Some(primer) => {
+            dictionary.clone_from(&primer.codes);
+            primer.first_code()
+        },
+        None => {
+            reset_dictionary(dictionary);
+            257
+        },
     };
 
-    for c in data.iter() {
-        let mut entry = element.clone();
-        entry.push(*c);
+    // The code of the string being matched, which ends at the current byte
+    let mut element = None;
 
-        if dictionary.contains_key(&entry) {
-            element = entry
-        } else {
-            write_bit(&mut bit_io, *dictionary.get(&element).unwrap());
-            dictionary.insert(entry, dictionary_count);
-            element = vec![*c];
-            dictionary_count += 1;
+    let mut codes = Vec::new();
+
+    for c in data {
+        let entry = match element {
+            Some(prefix) => dictionary.get(&dictionary_key(prefix, *c)).copied(),
+            None => Some(*c as u64),
+        };
+
+        match (entry, element) {
+            (Some(code), _) => element = Some(code),
+            (None, Some(prefix)) => {
+                codes.push(prefix);
+                dictionary.insert(dictionary_key(prefix, *c), dictionary_count);
+                element = Some(*c as u64);
+                dictionary_count += 1;
+            },
+            (None, None) => unreachable!(),
         }
 
         count += 1;
 
-        if dictionary_count >= 0x3FFFE {
+        if dictionary_count >= DICTIONARY_LIMIT {
             count -= 1;
             break;
         }
     }
 
-    let last_element = element;
-    if bit_io.byte_size() == 0 {
-        if !last_element.is_empty() {
-            for c in last_element {
-                write_bit(&mut bit_io, *dictionary.get(&vec![c]).unwrap());
-            }
-        }
+    // When the dictionary fills up, the last element's byte starts the next
+    // chunk instead
+    if codes.is_empty() || dictionary_count < DICTIONARY_LIMIT {
+        codes.extend(element);
+    }
 
-        bit_io.flush();
-        return (count, output_buf, Vec::new());
-    } else if dictionary_count < 0x3FFFE {
-        if !last_element.is_empty() {
-            write_bit(&mut bit_io, *dictionary.get(&last_element).unwrap());
+    if flagged {
+        write_flagged_codes(&codes, output);
+    } else {
+        write_plain_codes(&codes, output);
+    }
+
+    count
+}
+
+/// Write each code in 16 bits, or 19 if it doesn't fit in 15.
+fn write_plain_codes(codes: &[u64], output: &mut Vec<u8>) {
+    let mut bit_io = BitWriter::new(output);
+    for code in codes {
+        if *code > 0x7FFF {
+            bit_io.write_bit(1, 1);
+            bit_io.write_bit(*code, 18);
+        } else {
+            bit_io.write_bit(0, 1);
+            bit_io.write_bit(*code, 15);
         }
+    }
 
-        bit_io.flush();
-        return (count, output_buf, Vec::new());
+    bit_io.flush();
+}
+
+/// Read a code written by [`write_plain_codes`].
+fn read_plain_code(bit_io: &mut BitReader<&[u8]>) -> Option<u64> {
+    match bit_io.read_bit(1).ok()? {
+        0 => bit_io.read_bit(15).ok(),
+        _ => bit_io.read_bit(18).ok(),
+    }
+}
+
+/// The Huffman symbol for an LZW code, and the number of extra bits which
+/// follow it.
+///
+/// Codes for single bytes are their own symbol. Larger codes are split by
+/// their length and the bit after their highest, with the bits below
+/// those as they are.
+fn code_symbol(code: u64) -> (usize, usize) {
+    if code < 256 {
+        return (code as usize, 0)
+    }
+
+    let bits = code.ilog2() as usize;
+    (256 + (bits - 8) * 2 + ((code >> (bits - 1)) & 1) as usize, bits - 1)
+}
+
+/// Read a code written by [`write_flagged_codes`] with a Huffman code.
+fn read_huffman_code(decoder: &HuffmanDecoder, bit_io: &mut BitReader<&[u8]>) -> Option<u64> {
+    let symbol = decoder.read_symbol(bit_io)?;
+    if symbol < 256 {
+        return Some(symbol as u64)
+    }
+
+    let extra = (symbol - 256) / 2 + 7;
+    let top = 2 | ((symbol - 256) & 1) as u64;
+    Some((top << extra) | bit_io.read_bit(extra).ok()?)
+}
+
+/// Write the codes after a byte which is [`HUFFMAN_CODES`] or
+/// [`PLAIN_CODES`].
+///
+/// Huffman coded chunks start with the code lengths of each symbol from
+/// [`code_symbol`] and the number of codes in 32 bits, then each symbol's
+/// code is followed by its extra bits.
+/// Chunks where that would be larger than [`write_plain_codes`], like
+/// noise, are written plainly instead.
+fn write_flagged_codes(codes: &[u64], output: &mut Vec<u8>) {
+    let start = output.len();
+
+    let mut frequencies = [0; CODE_SYMBOLS];
+    for code in codes {
+        frequencies[code_symbol(*code).0] += 1;
     }
+    let lengths = huffman_lengths(&frequencies);
+    let huffman_codes = canonical_codes(&lengths);
 
+    output.push(HUFFMAN_CODES);
+    let mut bit_io = BitWriter::new(output);
+    write_huffman_lengths(&mut bit_io, &lengths);
+    bit_io.write_bit(codes.len() as u64, 32);
+    for code in codes {
+        let (symbol, extra) = code_symbol(*code);
+        bit_io.write_huffman(huffman_codes[symbol], lengths[symbol]);
+        if extra > 0 {
+            bit_io.write_bit(*code, extra);
+        }
+    }
     bit_io.flush();
-    (count, output_buf, last_element)
+
+    let plain_bits: usize = codes.iter().map(|c| if *c > 0x7FFF { 19 } else { 16 }).sum();
+    if output.len() - start > 1 + plain_bits / 8 + 1 {
+        output.truncate(start);
+        output.push(PLAIN_CODES);
+        write_plain_codes(codes, output);
+    }
 }
 
-pub fn decompress<T: ReadBytesExt + Read>(
+/// Read and decompress the chunks described by `compression_info`.
+///
+/// With the `parallel` feature, each chunk starts decompressing as soon as
+/// it has been read, while the following chunks are still being read. At
+/// most a few chunks per thread are held in memory waiting to be
+/// decompressed.
+///
+/// Any problems with the chunks are added to `warnings`, in chunk order.
+pub fn decompress<T: Read>(
     input: &mut T,
-    compression_info: &CompressionInfo
-) -> Vec<u8> {
-    // Read the compressd chunks from the input stream into memory
-    let mut compressed_chunks = Vec::new();
-    let mut total_size_raw = 0;
-    for (i, block_info) in compression_info.chunks.iter().enumerate() {
-        let mut buffer = vec![0u8; block_info.size_compressed];
-        input.read_exact(&mut buffer).unwrap();
+    compression_info: &CompressionInfo,
+    backend: Backend,
+    warnings: &mut Vec<DecodeWarning>,
+) -> Result<Vec<u8>, io::Error> {
+    decompress_with_primer(input, compression_info, backend, None, warnings)
+}
+
+/// Like [`decompress`], for chunks compressed by [`compress_with_primer`]
+/// with the same `primer`.
+pub(crate) fn decompress_with_primer<T: Read>(
+    input: &mut T,
+    compression_info: &CompressionInfo,
+    backend: Backend,
+    primer: Option<&Primer>,
+    warnings: &mut Vec<DecodeWarning>,
+) -> Result<Vec<u8>, io::Error> {
+    let mut output_buf = vec![0; total_size_raw(&compression_info.chunks)];
 
-        compressed_chunks.push((buffer, block_info.size_raw, i));
-        total_size_raw += block_info.size_raw;
+    // Stored chunks follow each other as they are, so they can be read
+    // straight into place all at once
+    if backend == Backend::Stored && compression_info.chunks.iter().all(|c| c.size_compressed == c.size_raw) {
+        input.read_exact(&mut output_buf)?;
+        return Ok(output_buf)
     }
 
+    let outputs = split_outputs(&mut output_buf, compression_info.chunks.iter().map(|c| c.size_raw));
+    let mut problems = vec![None; compression_info.chunks.len()];
+
+    #[cfg(feature = "parallel")]
+    {
+        let max_in_flight = threads::current_num_threads() * 2;
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+
+        threads::in_place_scope(|scope| {
+            let mut in_flight = 0;
+            let chunks = compression_info.chunks.iter().zip(outputs).zip(&mut problems);
+            for (i, ((block_info, output), problem)) in chunks.enumerate() {
+                in_flight -= done_rx.try_iter().count();
+
+                // Don't read further ahead than the pool can keep up with
+                while in_flight >= max_in_flight {
+                    match rayon::yield_now() {
+                        // Not on a pool thread, so wait for a chunk to finish
+                        None => in_flight -= done_rx.recv().map_or(0, |_| 1),
+                        Some(rayon::Yield::Executed) => (),
+                        Some(rayon::Yield::Idle) => std::thread::yield_now(),
+                    }
+
+                    in_flight -= done_rx.try_iter().count();
+                }
+
+                let buffer = read_vec(input, block_info.size_compressed)?;
+
+                in_flight += 1;
+                let done_tx = done_tx.clone();
+                scope.spawn(move |_| {
+                    *problem = decompress_chunk_into(&buffer, output, i, backend, primer);
+                    let _ = done_tx.send(());
+                });
+            }
+
+            Ok::<_, io::Error>(())
+        })?;
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    {
+        let chunks = compression_info.chunks.iter().zip(outputs).zip(&mut problems);
+        for (i, ((block_info, output), problem)) in chunks.enumerate() {
+            let buffer = read_vec(input, block_info.size_compressed)?;
+            *problem = decompress_chunk_into(&buffer, output, i, backend, primer);
+        }
+    }
+
+    warnings.extend(problems.into_iter().flatten());
+
+    Ok(output_buf)
+}
+
+/// Decompress chunks which are already in memory, given as pairs of the
+/// compressed data and its uncompressed size.
+///
+/// Any problems with the chunks are added to `warnings`, in chunk order.
+pub fn decompress_chunks(
+    compressed_chunks: &[(&[u8], usize)],
+    backend: Backend,
+    warnings: &mut Vec<DecodeWarning>,
+) -> Vec<u8> {
+    let mut output_buf = vec![0; compressed_chunks.iter().map(|c| c.1).fold(0, usize::saturating_add)];
+    let outputs = split_outputs(&mut output_buf, compressed_chunks.iter().map(|c| c.1));
+    let mut problems = vec![None; compressed_chunks.len()];
+
     // Process the compressed chunks in parallel
-    let mut output_buf: Vec<u8> = Vec::with_capacity(total_size_raw);
-    output_buf.par_extend(
+    #[cfg(feature = "parallel")]
+    threads::install(|| {
         compressed_chunks
             .par_iter()
-            .flat_map(|chunk| {
-                let error = match decompress_lzw(&chunk.0, chunk.1) {
-                    Ok(result) => return result,
-                    Err(err) => err,
-                };
+            .zip(outputs)
+            .zip(&mut problems)
+            .enumerate()
+            .for_each(|(i, ((chunk, output), problem))| *problem = decompress_chunk_into(chunk.0, output, i, backend, None))
+    });
 
-                println!("{} in block {}", error, chunk.2);
+    #[cfg(not(feature = "parallel"))]
+    compressed_chunks
+        .iter()
+        .zip(outputs)
+        .zip(&mut problems)
+        .enumerate()
+        .for_each(|(i, ((chunk, output), problem))| *problem = decompress_chunk_into(chunk.0, output, i, backend, None));
 
-                let partial = match error {
-                    CompressionError::BadElement(partial, _, _) => partial,
-                    _ => vec![],
-                };
+    warnings.extend(problems.into_iter().flatten());
 
-                let mut out = vec![0; chunk.1];
+    output_buf
+}
 
-                out[..partial.len()].copy_from_slice(&partial);
+/// Decompress a single chunk into a new buffer of its uncompressed size,
+/// along with any problem with the chunk.
+///
+/// If the chunk is corrupted, whatever could be decompressed is kept and
+/// the rest of the buffer is filled with zeroes.
+pub fn decompress_chunk(
+    compressed: &[u8],
+    size_raw: usize,
+    index: usize,
+    backend: Backend,
+) -> (Vec<u8>, Option<DecodeWarning>) {
+    decompress_chunk_with_primer(compressed, size_raw, index, backend, None)
+}
 
-                out
-            })
-    );
+/// Like [`decompress_chunk`], for a chunk compressed by
+/// [`compress_chunk_with_primer`] with the same `primer`.
+pub(crate) fn decompress_chunk_with_primer(
+    compressed: &[u8],
+    size_raw: usize,
+    index: usize,
+    backend: Backend,
+    primer: Option<&Primer>,
+) -> (Vec<u8>, Option<DecodeWarning>) {
+    let (mut output, problem) = decompress_partial(compressed, size_raw, index, backend, primer);
+    output.resize(size_raw, 0);
 
-    output_buf
+    (output, problem)
+}
+
+/// The total uncompressed size of all chunks.
+pub(crate) fn total_size_raw(chunks: &[ChunkInfo]) -> usize {
+    chunks.iter().map(|c| c.size_raw).fold(0, usize::saturating_add)
+}
+
+/// Split the output buffer into the region each chunk decompresses into.
+fn split_outputs(mut output: &mut [u8], sizes: impl Iterator<Item = usize>) -> Vec<&mut [u8]> {
+    let mut outputs = Vec::new();
+    for size in sizes {
+        let (chunk, rest) = output.split_at_mut(size);
+        outputs.push(chunk);
+        output = rest;
+    }
+
+    outputs
+}
+
+/// Decompress a single chunk into its place in the output, returning any
+/// problem with the chunk.
+///
+/// If the chunk is corrupted, whatever could be decompressed is kept and
+/// the rest of its region is left as zeroes.
+fn decompress_chunk_into(
+    compressed: &[u8],
+    output: &mut [u8],
+    index: usize,
+    backend: Backend,
+    primer: Option<&Primer>,
+) -> Option<DecodeWarning> {
+    let (result, problem) = decompress_partial(compressed, output.len(), index, backend, primer);
+
+    let len = result.len().min(output.len());
+    output[..len].copy_from_slice(&result[..len]);
+
+    problem
+}
+
+/// Decompress a single chunk, returning as much as could be decompressed
+/// if it is corrupted, along with the problem.
+///
+/// A chunk which decompresses to other than `size` bytes is also reported,
+/// but its data is returned as it is.
+fn decompress_partial(
+    compressed: &[u8],
+    size: usize,
+    index: usize,
+    backend: Backend,
+    primer: Option<&Primer>,
+) -> (Vec<u8>, Option<DecodeWarning>) {
+    let result = match backend {
+        Backend::Lzw => decompress_lzw(compressed, size, false, primer),
+        Backend::LzwHuffman => decompress_lzw(compressed, size, true, primer),
+        Backend::Lz77 => decompress_lz77(compressed, size),
+        Backend::Bwt => decompress_bwt(compressed, size),
+        Backend::Stored => Ok(compressed.to_vec()),
+        Backend::Predictive(parameters) => decompress_predictive(compressed, size, parameters),
+    };
+
+    match result {
+        Ok(result) if result.len() == size => (result, None),
+        Ok(result) => {
+            let problem = DecodeWarning::ChunkSizeMismatch { chunk: index, expected: size, actual: result.len() };
+            trace!("{problem}, keeping it as it is");
+            (result, Some(problem))
+        },
+        Err(CompressionError::BadElement(partial, _, bit)) => {
+            let offset = usize::try_from(bit / 8).unwrap_or(usize::MAX);
+            trace!("chunk {index} is corrupt at byte {offset}, keeping the {} bytes before it", partial.len());
+            (partial, Some(DecodeWarning::CorruptChunk { chunk: index, offset }))
+        },
+        Err(_) => {
+            trace!("chunk {index} could not be decompressed at all");
+            (vec![], Some(DecodeWarning::CorruptChunk { chunk: index, offset: 0 }))
+        },
+    }
 }
 
-fn decompress_lzw(input_data: &[u8], size: usize) -> Result<Vec<u8>, CompressionError> {
-    let mut data = Cursor::new(input_data);
+/// Decompress a chunk written by [`compress_lzw`], which starts with a
+/// byte saying how the codes are written if it is `flagged`.
+fn decompress_lzw(input_data: &[u8], size: usize, flagged: bool, primer: Option<&Primer>) -> Result<Vec<u8>, CompressionError> {
+    // The offset of the codes in bytes
+    let (kind, mut data, start): (_, _, u64) = match (flagged, input_data.split_first()) {
+        (false, _) => (PLAIN_CODES, input_data, 0),
+        (true, Some((kind, rest))) => (*kind, rest, 1),
+        (true, None) => return Err(CompressionError::BadElement(Vec::new(), 0, 0)),
+    };
+    let data_size = data.len();
+    let mut bit_io = BitReader::new(&mut data);
+
+    // The Huffman decoder and the number of codes left to read
+    let mut decoder = match kind {
+        PLAIN_CODES => None,
+        HUFFMAN_CODES => {
+            let decoder = read_huffman_lengths(&mut bit_io, CODE_SYMBOLS).and_then(|l| HuffmanDecoder::new(&l));
+            match (decoder, bit_io.read_bit(32)) {
+                (Some(decoder), Ok(count)) => Some((decoder, count)),
+                _ => return Err(CompressionError::BadElement(Vec::new(), 0, start * 8 + bit_io.bit_position())),
+            }
+        },
+        kind => return Err(CompressionError::BadElement(Vec::new(), kind as u64, 0)),
+    };
 
     // Build the initial dictionary of 256 values
     let mut dictionary = Vec::new();
     for i in 0..256 {
         dictionary.push(vec![i as u8]);
     }
+
+    // Code 256 is skipped by the first code of a chunk, so it is never used
+    // and the primed strings follow it
+    if let Some(primer) = primer {
+        dictionary.push(vec![0]);
+        dictionary.extend(primer.strings.iter().cloned());
+    }
     let mut dictionary_count = dictionary.len() as u64;
 
     let mut result = Vec::with_capacity(size);
-    let data_size = input_data.len();
-
-    let mut bit_io = BitReader::new(&mut data);
     let mut w = dictionary.first().unwrap().clone();
 
-    let mut element;
+    let mut element = 0;
     loop {
-        if bit_io.byte_offset() >= data_size - 1 {
+        // Huffman codes can be shorter than a byte, so the padding at the
+        // end can't be told apart from them and they're counted instead
+        let done = match &decoder {
+            None => bit_io.byte_offset() >= data_size.saturating_sub(1),
+            Some((_, count)) => *count == 0,
+        };
+        if done {
             break;
         }
 
-        let flag = bit_io.read_bit(1);
-        if flag == 0 {
-            element = bit_io.read_bit(15);
-        } else {
-            element = bit_io.read_bit(18);
-        }
+        // Report where the bad code starts rather than where reading it
+        // stopped
+        let code_start = start * 8 + bit_io.bit_position();
+        let code = match &mut decoder {
+            None => read_plain_code(&mut bit_io),
+            Some((decoder, count)) => {
+                *count -= 1;
+                read_huffman_code(decoder, &mut bit_io)
+            },
+        };
+
+        element = match code {
+            Some(c) => c,
+            None => return Err(CompressionError::BadElement(result, element, code_start)),
+        };
 
         let mut entry;
         if let Some(x) = dictionary.get(element as usize) {
@@ -256,7 +931,7 @@ fn decompress_lzw(input_data: &[u8], size: usize) -> Result<Vec<u8>, Compression
             entry = w.clone();
             entry.push(w[0])
         } else {
-            return Err(CompressionError::BadElement(result, element, bit_io.byte_offset()))
+            return Err(CompressionError::BadElement(result, element, code_start))
         }
 
         result.write_all(&entry).unwrap();
@@ -268,3 +943,171 @@ fn decompress_lzw(input_data: &[u8], size: usize) -> Result<Vec<u8>, Compression
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FNV-1a, to compare large outputs without storing them.
+    fn fnv(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+    }
+
+    #[test]
+    fn compress_known_output() {
+        let (compressed, info) = compress(b"TOBEORNOTTOBEORTOBEORNOT", Backend::Lzw).unwrap();
+
+        assert_eq!(compressed, [
+            168, 0, 158, 0, 132, 0, 138, 0, 158, 0, 164, 0, 156, 0, 158, 0, 168,
+            0, 2, 2, 6, 2, 10, 2, 20, 2, 8, 2, 12, 2, 16, 2, 0,
+        ]);
+        assert_eq!(info.chunks, [ChunkInfo { size_compressed: 33, size_raw: 24 }]);
+    }
+
+    #[test]
+    fn compress_known_output_multiple_chunks() {
+        // Random data fills the dictionary quickly, so this needs two chunks
+        let mut state = 0x2545F491u32;
+        let data: Vec<u8> = (0..600_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+
+        let (compressed, info) = compress(&data, Backend::Lzw).unwrap();
+
+        assert_eq!(compressed.len(), 756089);
+        assert_eq!(fnv(&compressed), 0x2225e4f02e953021);
+        assert_eq!(info.chunks, [
+            ChunkInfo { size_compressed: 561794, size_raw: 460753 },
+            ChunkInfo { size_compressed: 194295, size_raw: 139247 },
+        ]);
+    }
+
+    #[test]
+    fn code_symbols() {
+        assert_eq!(code_symbol(0), (0, 0));
+        assert_eq!(code_symbol(255), (255, 0));
+        assert_eq!(code_symbol(256), (256, 7));
+        assert_eq!(code_symbol(383), (256, 7));
+        assert_eq!(code_symbol(384), (257, 7));
+        assert_eq!(code_symbol(512), (258, 8));
+        assert_eq!(code_symbol(DICTIONARY_LIMIT - 1), (CODE_SYMBOLS - 1, 16));
+    }
+
+    #[test]
+    fn huffman_round_trip() {
+        // Mostly small differences, like filtered image data
+        let mut state = 0x2545F491u32;
+        let skewed: Vec<u8> = (0..700_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 7) as u8 * (state >> 28) as u8
+        }).collect();
+
+        for data in [&b"TOBEORNOTTOBEORTOBEORNOT"[..], b"a", &[0; 5000], &skewed] {
+            let (plain, plain_info) = compress(data, Backend::Lzw).unwrap();
+            let (compressed, info) = compress(data, Backend::LzwHuffman).unwrap();
+            assert_eq!(info.chunk_count, plain_info.chunk_count);
+            assert!(compressed.len() <= plain.len() + info.chunk_count);
+
+            let mut warnings = Vec::new();
+            let decompressed = decompress(&mut compressed.as_slice(), &info, Backend::LzwHuffman, &mut warnings).unwrap();
+            assert!(warnings.is_empty());
+            assert_eq!(decompressed, data);
+        }
+
+        let (compressed, _) = compress(&skewed, Backend::LzwHuffman).unwrap();
+        assert_eq!(compressed[0], HUFFMAN_CODES);
+    }
+
+    #[test]
+    fn huffman_skipped_when_larger() {
+        // A single code is smaller written plainly than with a table
+        let (compressed, info) = compress(b"a", Backend::LzwHuffman).unwrap();
+        assert_eq!(compressed, [PLAIN_CODES, 194, 0, 0]);
+        assert_eq!(info.chunks, [ChunkInfo { size_compressed: 4, size_raw: 1 }]);
+
+        let (data, problem) = decompress_chunk(&compressed, 1, 0, Backend::LzwHuffman);
+        assert_eq!(data, b"a");
+        assert_eq!(problem, None);
+    }
+
+    #[test]
+    fn bad_code_bit_offset() {
+        // A byte, then a code past the end of the dictionary
+        let mut data = Vec::new();
+        let mut bit_io = BitWriter::new(&mut data);
+        for code in [b'a' as u64, 300, b'b' as u64] {
+            bit_io.write_bit(0, 1);
+            bit_io.write_bit(code, 15);
+        }
+        bit_io.flush();
+
+        match decompress_lzw(&data, 3, false, None) {
+            Err(CompressionError::BadElement(partial, 300, 16)) => assert_eq!(partial, b"a"),
+            result => panic!("{result:?}"),
+        }
+
+        // The same code after the byte saying how codes are written
+        let flagged: Vec<u8> = [PLAIN_CODES].into_iter().chain(data.iter().copied()).collect();
+        assert!(matches!(decompress_lzw(&flagged, 3, true, None), Err(CompressionError::BadElement(_, 300, 24))));
+
+        let (_, problem) = decompress_chunk(&flagged, 3, 0, Backend::LzwHuffman);
+        assert_eq!(problem, Some(DecodeWarning::CorruptChunk { chunk: 0, offset: 3 }));
+    }
+
+    #[test]
+    fn huffman_corrupt() {
+        let data = b"TOBEORNOT".repeat(200);
+        let (compressed, info) = compress(&data, Backend::LzwHuffman).unwrap();
+        assert_eq!(compressed[0], HUFFMAN_CODES);
+        let size = info.chunks[0].size_raw;
+
+        // Truncated, with an unknown kind of chunk, and empty
+        for bad in [&compressed[..compressed.len() / 2], &[2, 0, 0], &[]] {
+            let (_, problem) = decompress_chunk(bad, size, 0, Backend::LzwHuffman);
+            assert!(matches!(problem, Some(DecodeWarning::CorruptChunk { chunk: 0, .. })));
+        }
+    }
+
+    #[test]
+    fn primed_round_trip() {
+        let content = b"TOBEORNOTTOBEORTOBEORNOT".repeat(40);
+        let primer = Primer::new(&content);
+
+        // Like the content, then random enough to fill the dictionary and
+        // start another chunk
+        let mut state = 0x2545F491u32;
+        let noise = (0..600_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        });
+        let mut data = b"TOBEORTOBEORNOTTOBE".repeat(30);
+        let similar = data.len();
+        data.extend(noise);
+
+        for backend in [Backend::Lzw, Backend::LzwHuffman] {
+            for data in [&data[..similar], &data, b"NOT", b"N"] {
+                let (compressed, info) = compress_with_primer(data, backend, Some(&primer)).unwrap();
+
+                let mut warnings = Vec::new();
+                let decompressed =
+                    decompress_with_primer(&mut compressed.as_slice(), &info, backend, Some(&primer), &mut warnings).unwrap();
+                assert!(warnings.is_empty());
+                assert_eq!(decompressed, data);
+            }
+
+            // The primed strings are already known, so there's much less to
+            // write, and without them the chunk can't be decoded
+            let (unprimed, _) = compress(&data[..similar], backend).unwrap();
+            let (primed, info) = compress_with_primer(&data[..similar], backend, Some(&primer)).unwrap();
+            assert!(primed.len() * 3 < unprimed.len() * 2, "{} vs {}", primed.len(), unprimed.len());
+            assert_ne!(decompress(&mut primed.as_slice(), &info, backend, &mut Vec::new()).unwrap(), &data[..similar]);
+        }
+    }
+}
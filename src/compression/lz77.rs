@@ -0,0 +1,533 @@
+//! A match based compressor in the style of LZ77, used by
+//! [`CompressionType::LosslessV2`].
+//!
+//! Each chunk is a stream of tokens written with a [`BitWriter`]. A token
+//! starts with a flag bit, and is either a run of literal bytes or a copy of
+//! earlier data in the same chunk:
+//!
+//! - `0`, the number of literals, then `0` and the literals as gamma codes
+//!   or `1` and the literals as they are
+//! - `1`, the length of the match minus [`MIN_MATCH`] plus one, then either
+//!   `0` and the index of one of the distances of the last few matches, see
+//!   [`Recent`], or `1` and the distance back to where the match starts, see
+//!   [`write_distance`]
+//!
+//! Other numbers are Elias gamma style codes, see [`write_gamma`]. Literals
+//! are usually stored as the gamma code of the byte taken as a signed
+//! difference, as the data has been through row filtering and is mostly
+//! small differences, but runs of larger ones are left as they are. Matches are found in a window of the previous
+//! [`WINDOW_LEN`] bytes with a hash chain, out of the first [`MAX_CHAIN`]
+//! candidates, picking the one which saves the most bits over writing
+//! literals. A match is put off by a byte if the one after it saves more.
+//! Chunks are independent, so they can be decompressed in parallel like the
+//! LZW chunks.
+//!
+//! Compared to LZW, with `cargo bench --bench lossless`, images are
+//! about 4% smaller for the photograph in the test images, and about the
+//! same size for the smoother image decoded from the lossy one. Without
+//! the Huffman stage LZW has before format version 5, they were 9% and 6%
+//! smaller. Noise stays within a few hundred bytes of its raw size, where
+//! LZW makes it a tenth larger. Encoding takes about as long, and decoding
+//! is around 20% faster, or four times as fast for noise.
+//!
+//! [`CompressionType::LosslessV2`]: crate::CompressionType::LosslessV2
+
+use alloc::{vec, vec::Vec};
+
+use super::lossless::CompressionError;
+use crate::binio::{BitReader, BitWriter};
+
+/// The uncompressed size of each chunk, except the last.
+pub(crate) const CHUNK_LEN: usize = 1 << 18;
+
+/// The length of the window, which matches can reach back less than.
+pub(crate) const WINDOW_LEN: usize = 1 << 16;
+
+/// The shortest match which is written as a match rather than literals.
+pub(crate) const MIN_MATCH: usize = 4;
+
+/// The longest match, so its length code fits in a few bits.
+const MAX_MATCH: usize = 1 << 16;
+
+/// The most earlier positions with the same hash which are compared
+/// against when looking for a match.
+const MAX_CHAIN: usize = 32;
+
+const HASH_BITS: u32 = 16;
+
+/// Marks an empty slot of the hash chain.
+const NONE: u32 = u32::MAX;
+
+/// Hash the [`MIN_MATCH`] bytes at the start of `data`.
+fn hash(data: &[u8]) -> usize {
+    let word = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    (word.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// The number of bits [`write_gamma`] uses for `value`.
+fn gamma_len(value: usize) -> usize {
+    value.ilog2() as usize * 2 + 1
+}
+
+/// The number of bits [`write_distance`] uses for `distance`.
+fn distance_len(distance: usize) -> usize {
+    DISTANCE_BITS + distance.ilog2() as usize
+}
+
+/// The value a literal byte is written as, interpreting it as a signed
+/// difference so that small ones in either direction are small.
+fn literal_value(byte: u8) -> usize {
+    let value = byte as i8 as i16;
+    ((value << 1) ^ (value >> 15)) as usize + 1
+}
+
+/// The inverse of [`literal_value`].
+fn literal_byte(value: usize) -> Option<u8> {
+    let value = value.checked_sub(1).filter(|v| *v <= 0xFF)? as i16;
+    Some(((value >> 1) ^ -(value & 1)) as u8)
+}
+
+/// The number of bits a match takes up.
+fn match_len(len: usize, distance: usize, recent: &Recent) -> usize {
+    let distance_len = match recent.contains(&distance) {
+        true => RECENT_BITS,
+        false => distance_len(distance),
+    };
+
+    2 + gamma_len(len - MIN_MATCH + 1) + distance_len
+}
+
+/// The distances of the most recent matches, most recent first, which can
+/// be referred to by their index.
+type Recent = [usize; 1 << RECENT_BITS];
+
+/// The number of bits giving the length of a distance.
+const DISTANCE_BITS: usize = 4;
+
+/// The number of bits in an index into [`Recent`].
+const RECENT_BITS: usize = 3;
+
+/// Move `distance` to the front of the recent distances.
+fn remember(recent: &mut Recent, distance: usize) {
+    let end = recent.iter().position(|d| *d == distance).unwrap_or(recent.len() - 1);
+    recent[..=end].rotate_right(1);
+    recent[0] = distance;
+}
+
+/// A copy of `len` bytes from `distance` bytes back.
+#[derive(Debug, Clone, Copy)]
+struct Match {
+    len: usize,
+    distance: usize,
+
+    /// The number of bits saved over writing literals.
+    saving: usize,
+}
+
+/// The hash chain over the window, holding for each hash the most recent
+/// position it was seen at, and for each position the one before it with
+/// the same hash.
+struct Matcher {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl Matcher {
+    fn new() -> Self {
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_LEN],
+        }
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH > data.len() {
+            return
+        }
+
+        let hash = hash(&data[pos..]);
+        self.prev[pos % WINDOW_LEN] = self.head[hash];
+        self.head[hash] = pos as u32;
+    }
+
+    /// Find the match for the data at `pos` which saves the most bits over
+    /// writing the same bytes as literals, if any saves bits at all.
+    ///
+    /// `literal_lens` holds the total length of the literals before each
+    /// position.
+    fn find(&self, data: &[u8], pos: usize, literal_lens: &[usize], recent: &Recent) -> Option<Match> {
+        if pos + MIN_MATCH > data.len() {
+            return None
+        }
+
+        let max_len = (data.len() - pos).min(MAX_MATCH);
+        let mut best = None;
+        let mut best_saving = 0;
+        let mut consider = |start: usize| {
+            let len = data[start..pos + max_len].iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len < MIN_MATCH {
+                return
+            }
+
+            let distance = pos - start;
+            let saving = (literal_lens[pos + len] - literal_lens[pos])
+                .saturating_sub(match_len(len, distance, recent));
+            if saving > best_saving {
+                best_saving = saving;
+                best = Some(Match { len, distance, saving });
+            }
+        };
+
+        // Recent distances are cheap to reuse, and often come up again in
+        // image data, so always try them
+        for distance in recent {
+            if (1..=pos).contains(distance) {
+                consider(pos - distance);
+            }
+        }
+
+        let mut candidate = self.head[hash(&data[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE {
+                break
+            }
+
+            let start = candidate as usize;
+            if start >= pos || pos - start >= WINDOW_LEN {
+                break
+            }
+
+            consider(start);
+            candidate = self.prev[start % WINDOW_LEN];
+        }
+
+        best
+    }
+}
+
+/// Write a number of at least 1 as the count of bits after its highest set
+/// bit in unary, followed by those bits.
+fn write_gamma(bit_io: &mut BitWriter<Vec<u8>>, value: usize) {
+    debug_assert!(value > 0);
+
+    let bits = value.ilog2() as usize;
+    bit_io.write_bit((1 << bits) - 1, bits + 1);
+    if bits > 0 {
+        bit_io.write_bit(value as u64 & ((1 << bits) - 1), bits);
+    }
+}
+
+/// Write a distance of at least 1 and less than [`WINDOW_LEN`] as the
+/// position of its highest set bit, followed by the bits below it.
+fn write_distance(bit_io: &mut BitWriter<Vec<u8>>, distance: usize) {
+    let bits = distance.ilog2() as usize;
+    bit_io.write_bit(bits as u64, DISTANCE_BITS);
+    if bits > 0 {
+        bit_io.write_bit(distance as u64 & ((1 << bits) - 1), bits);
+    }
+}
+
+/// Read a distance written by [`write_distance`].
+fn read_distance(bit_io: &mut BitReader<&[u8]>) -> Option<usize> {
+    let bits = bit_io.read_bit(DISTANCE_BITS).ok()? as usize;
+    let low = match bits {
+        0 => 0,
+        _ => bit_io.read_bit(bits).ok()? as usize,
+    };
+
+    Some((1 << bits) | low)
+}
+
+/// Read a number written by [`write_gamma`].
+fn read_gamma(bit_io: &mut BitReader<&[u8]>) -> Option<usize> {
+    let mut bits = 0;
+    while bit_io.read_bit(1).ok()? == 1 {
+        bits += 1;
+        if bits >= usize::BITS as usize {
+            return None
+        }
+    }
+
+    let low = match bits {
+        0 => 0,
+        _ => bit_io.read_bit(bits).ok()? as usize,
+    };
+
+    Some((1 << bits) | low)
+}
+
+/// Write a run of literals, as gamma codes or as they are, whichever is
+/// shorter.
+fn write_literals(bit_io: &mut BitWriter<Vec<u8>>, literals: &[u8]) {
+    if literals.is_empty() {
+        return
+    }
+
+    bit_io.write_bit(0, 1);
+    write_gamma(bit_io, literals.len());
+
+    let coded_len: usize = literals.iter().map(|b| gamma_len(literal_value(*b))).sum();
+    if coded_len <= literals.len() * 8 {
+        bit_io.write_bit(0, 1);
+        for byte in literals {
+            write_gamma(bit_io, literal_value(*byte));
+        }
+    } else {
+        bit_io.write_bit(1, 1);
+        for byte in literals {
+            bit_io.write_bit(*byte as u64, 8);
+        }
+    }
+}
+
+/// Compress a chunk of up to [`CHUNK_LEN`] bytes from the start of `data`,
+/// appending it to `output`.
+///
+/// Returns the number of bytes of `data` which were consumed.
+pub(crate) fn compress_lz77(data: &[u8], output: &mut Vec<u8>) -> usize {
+    let data = &data[..data.len().min(CHUNK_LEN)];
+    if data.is_empty() {
+        return 0
+    }
+
+    let mut literal_lens = Vec::with_capacity(data.len() + 1);
+    literal_lens.push(0);
+    for byte in data {
+        literal_lens.push(literal_lens.last().unwrap() + gamma_len(literal_value(*byte)).min(8));
+    }
+
+    let mut matcher = Matcher::new();
+    let mut bit_io = BitWriter::new(output);
+
+    let mut recent = Recent::default();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some(Match { len, distance, saving }) = matcher.find(data, pos, &literal_lens, &recent) else {
+            matcher.insert(data, pos);
+            pos += 1;
+            continue
+        };
+
+        // Write a literal instead if the match starting at the next byte
+        // saves more
+        matcher.insert(data, pos);
+        let next = matcher.find(data, pos + 1, &literal_lens, &recent);
+        if next.is_some_and(|next| next.saving > saving) {
+            pos += 1;
+            continue
+        }
+
+        write_literals(&mut bit_io, &data[literal_start..pos]);
+        bit_io.write_bit(1, 1);
+        write_gamma(&mut bit_io, len - MIN_MATCH + 1);
+        if let Some(index) = recent.iter().position(|d| *d == distance) {
+            bit_io.write_bit(0, 1);
+            bit_io.write_bit(index as u64, RECENT_BITS);
+        } else {
+            bit_io.write_bit(1, 1);
+            write_distance(&mut bit_io, distance);
+        }
+
+        for i in pos + 1..pos + len {
+            matcher.insert(data, i);
+        }
+        remember(&mut recent, distance);
+        pos += len;
+        literal_start = pos;
+    }
+    write_literals(&mut bit_io, &data[literal_start..]);
+
+    bit_io.flush();
+    data.len()
+}
+
+/// Decompress a chunk written by [`compress_lz77`] which is `size` bytes
+/// long when uncompressed.
+///
+/// On a bad token, the error holds the data decompressed before it.
+pub(crate) fn decompress_lz77(input: &[u8], size: usize) -> Result<Vec<u8>, CompressionError> {
+    let mut data = input;
+    let mut bit_io = BitReader::new(&mut data);
+    let mut result = Vec::with_capacity(size);
+    let mut recent = Recent::default();
+
+    while result.len() < size {
+        let remaining = size - result.len();
+        let token = bit_io.read_bit(1).ok().and_then(|flag| {
+            let len = read_gamma(&mut bit_io)?;
+            if flag == 0 {
+                return Some((len, None))
+            }
+
+            let distance = match bit_io.read_bit(1).ok()? {
+                0 => recent[bit_io.read_bit(RECENT_BITS).ok()? as usize],
+                _ => read_distance(&mut bit_io)?,
+            };
+            Some((len + MIN_MATCH - 1, Some(distance)))
+        });
+
+        match token {
+            Some((len, None)) if len <= remaining => {
+                let raw = bit_io.read_bit(1).ok() == Some(1);
+                for _ in 0..len {
+                    let byte = match raw {
+                        true => bit_io.read_bit(8).ok().map(|b| b as u8),
+                        false => read_gamma(&mut bit_io).and_then(literal_byte),
+                    };
+
+                    match byte {
+                        Some(byte) => result.push(byte),
+                        None => return Err(CompressionError::BadElement(result, 0, bit_io.bit_position())),
+                    }
+                }
+            },
+            Some((len, Some(distance))) if len <= remaining && (1..=result.len()).contains(&distance) => {
+                let start = result.len() - distance;
+                if distance >= len {
+                    result.extend_from_within(start..start + len);
+                } else {
+                    // The match overlaps what it produces, so copy it a byte
+                    // at a time
+                    for i in start..start + len {
+                        result.push(result[i]);
+                    }
+                }
+                remember(&mut recent, distance);
+            },
+            Some((len, _)) => return Err(CompressionError::BadElement(result, len as u64, bit_io.bit_position())),
+            None => return Err(CompressionError::BadElement(result, 0, bit_io.bit_position())),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> usize {
+        let mut compressed = Vec::new();
+        let mut offset = 0;
+        let mut chunks = Vec::new();
+        loop {
+            let start = compressed.len();
+            let count = compress_lz77(&data[offset..], &mut compressed);
+            if count == 0 {
+                break
+            }
+            chunks.push((start, compressed.len(), offset, count));
+            offset += count;
+        }
+
+        let mut decompressed = Vec::new();
+        for (start, end, _, count) in chunks {
+            decompressed.extend(decompress_lz77(&compressed[start..end], count).unwrap());
+        }
+        assert_eq!(decompressed, data);
+
+        compressed.len()
+    }
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut state = 0x2545F491u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn gamma_round_trip() {
+        let values = [1, 2, 3, 4, 7, 8, 255, 256, 65535, 65536, 1 << 20];
+
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        for value in values {
+            write_gamma(&mut bit_io, value);
+        }
+        bit_io.flush();
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        for value in values {
+            assert_eq!(read_gamma(&mut bit_io), Some(value));
+        }
+    }
+
+    #[test]
+    fn known_output() {
+        let mut compressed = Vec::new();
+        assert_eq!(compress_lz77(b"abcabcabcabcx", &mut compressed), 13);
+
+        // Three literals left as they are, a match of 9 at distance 3, then
+        // one more literal
+        assert_eq!(compressed, [58, 76, 108, 236, 28, 137, 7]);
+        assert_eq!(decompress_lz77(&compressed, 13).unwrap(), b"abcabcabcabcx");
+    }
+
+    #[test]
+    fn round_trip_random() {
+        // Random data doesn't compress, but shouldn't grow much either
+        let data = random(CHUNK_LEN + 1000);
+        let len = round_trip(&data);
+        assert!(len < data.len() + data.len() / 50, "{len}");
+    }
+
+    #[test]
+    fn round_trip_small_differences() {
+        // Like filtered image data, where literals are stored as gamma codes
+        let data: Vec<u8> = random(CHUNK_LEN + 1000).iter().map(|b| (b % 5).wrapping_sub(2)).collect();
+        let len = round_trip(&data);
+        assert!(len < data.len() / 2, "{len}");
+    }
+
+    #[test]
+    fn round_trip_repetitive() {
+        assert!(round_trip(&vec![0x55; CHUNK_LEN * 2 + 17]) < 100);
+
+        let pattern = random(3000);
+        let data: Vec<u8> = pattern.iter().cycle().take(CHUNK_LEN + 50_000).copied().collect();
+        // Each chunk starts over, so the pattern is stored once in each of them
+        assert!(round_trip(&data) < 6100);
+
+        // Repeats further apart than the window can't be found
+        let pattern = random(WINDOW_LEN + 1);
+        let data: Vec<u8> = pattern.iter().cycle().take(WINDOW_LEN * 3).copied().collect();
+        assert!(round_trip(&data) > WINDOW_LEN * 3);
+
+        round_trip(b"");
+        round_trip(b"abc");
+        round_trip(b"aaaaaaaa");
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut compressed = Vec::new();
+        compress_lz77(b"abcabcabcabcx", &mut compressed);
+
+        // Ends in the middle of the match
+        let Err(CompressionError::BadElement(partial, _, _)) = decompress_lz77(&compressed[..4], 13) else {
+            panic!("decompressed a truncated chunk")
+        };
+        assert_eq!(partial, b"abc");
+
+        // A match which reaches back before the start
+        let mut bad = Vec::new();
+        let mut bit_io = BitWriter::new(&mut bad);
+        bit_io.write_bit(1, 1);
+        write_gamma(&mut bit_io, 1);
+        write_gamma(&mut bit_io, 2);
+        bit_io.flush();
+        assert!(decompress_lz77(&bad, 4).is_err());
+
+        // A literal run longer than the chunk
+        assert!(decompress_lz77(&compressed, 2).is_err());
+    }
+}
@@ -0,0 +1,878 @@
+//! A predictive coder in the style of JPEG-LS (LOCO-I), used by
+//! [`CompressionType::Predictive`].
+//!
+//! Each sample is predicted from the decoded samples to its left, above it
+//! and above on either side, in the same channel, with the median edge
+//! detector, see [`med`]. The differences between those neighbours are
+//! quantized into one of 365 contexts, see [`Coder::context`], each of which
+//! learns how large its residuals are, to pick the parameter of the
+//! Golomb–Rice code they are written with, and how biased its predictions
+//! are, to correct them. Where the neighbours are all the same, samples are
+//! instead coded as the length of the run of them, see [`Coder::encode_run`].
+//!
+//! With a `near` above 0, residuals are quantized to steps of `2 * near + 1`,
+//! and prediction is from the decoded samples rather than the original ones,
+//! so every sample decodes to within `near` of where it started. At 0 the
+//! image is exactly lossless.
+//!
+//! Chunks are bands of whole rows of up to [`CHUNK_LEN`] bytes, coded one
+//! channel at a time. The row above the first in a chunk is taken to be
+//! zero, so chunks are independent and can be decompressed in parallel. A
+//! chunk starts with a byte which is [`CODED`] or [`STORED`], and chunks
+//! which would grow, like noise, are stored as they are instead.
+//!
+//! [`CompressionType::Predictive`]: crate::CompressionType::Predictive
+
+use alloc::{vec, vec::Vec};
+
+use super::lossless::CompressionError;
+use crate::{
+    binio::{BitReader, BitWriter},
+    header::{ColorFormat, Header},
+};
+
+/// The most uncompressed bytes in each chunk. Chunks hold at least one row,
+/// however long it is.
+pub(crate) const CHUNK_LEN: usize = 1 << 18;
+
+/// Marks a chunk which is predictively coded.
+const CODED: u8 = 0;
+
+/// Marks a chunk which is stored uncompressed.
+const STORED: u8 = 1;
+
+/// The largest value of a sample.
+const MAX_VALUE: i32 = 255;
+
+/// The number of ways the three gradients around a sample can be quantized.
+/// Only the 365 which start with a positive gradient are used, the rest are
+/// flipped onto them.
+const CONTEXTS: usize = 9 * 9 * 9;
+
+/// How many samples a context sees before its totals are halved, so it
+/// keeps adapting.
+const RESET: u32 = 64;
+
+/// The longest a Golomb–Rice code may be in bits, after which the value is
+/// written as it is.
+const LIMIT: u32 = 32;
+
+/// The number of bits the rest of an interrupted run is written with, for
+/// each run index. The index goes up with each full segment of a run, and
+/// down after an interruption.
+const RUN_BITS: [u32; 32] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3,
+    4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// How the bitmap is laid out, and how far samples may move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PredictiveParameters {
+    /// The number of bytes in each row.
+    pub row_len: usize,
+
+    /// The number of interleaved channels. Packed formats are coded a byte
+    /// at a time, as one channel.
+    pub channels: usize,
+
+    /// The most any sample may differ from the original.
+    pub near: u8,
+}
+
+impl PredictiveParameters {
+    pub(crate) fn new(header: &Header) -> Self {
+        let channels = match header.color_format {
+            ColorFormat::Gray4 => 1,
+            format => format.channels() as usize,
+        };
+
+        Self {
+            row_len: header.color_format.row_len(header.width),
+            channels,
+            near: header.quality,
+        }
+    }
+}
+
+/// Where the samples of one channel are within a chunk.
+struct Layout {
+    row_len: usize,
+    channels: usize,
+
+    /// Width and height in samples.
+    width: usize,
+    height: usize,
+}
+
+impl Layout {
+    fn new(parameters: PredictiveParameters, len: usize) -> Self {
+        Self {
+            row_len: parameters.row_len,
+            channels: parameters.channels,
+            width: parameters.row_len / parameters.channels,
+            height: len / parameters.row_len,
+        }
+    }
+
+    fn index(&self, x: usize, y: usize, channel: usize) -> usize {
+        y * self.row_len + x * self.channels + channel
+    }
+
+    /// The samples to the left, above, above left and above right of a
+    /// sample. Those above the first row are zero, those past the left edge
+    /// are the one above, and those past the right edge are the one above.
+    fn neighbours(&self, decoded: &[u8], x: usize, y: usize, channel: usize) -> [i32; 4] {
+        let at = |x, y| decoded[self.index(x, y, channel)] as i32;
+
+        let above = if y > 0 { at(x, y - 1) } else { 0 };
+        let left = if x > 0 { at(x - 1, y) } else { above };
+        let above_left = if x > 0 && y > 0 { at(x - 1, y - 1) } else { above };
+        let above_right = if x + 1 < self.width && y > 0 { at(x + 1, y - 1) } else { above };
+
+        [left, above, above_left, above_right]
+    }
+}
+
+/// The median edge detector: where the upper left sample is beyond both of
+/// the others there is probably an edge, and the nearer of them to it is
+/// taken to be on the other side, otherwise the three make a plane.
+fn med(left: i32, above: i32, above_left: i32) -> i32 {
+    if above_left >= left.max(above) {
+        left.min(above)
+    } else if above_left <= left.min(above) {
+        left.max(above)
+    } else {
+        left + above - above_left
+    }
+}
+
+/// What a context has learned from its residuals.
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    /// The total of the magnitudes of the residuals.
+    magnitude: u32,
+
+    /// The total of the residuals, kept between the negative of `count` and
+    /// 0 by moving `correction`.
+    bias: i32,
+
+    /// How much is added to predictions.
+    correction: i32,
+
+    count: u32,
+}
+
+impl Context {
+    fn new(magnitude: u32) -> Self {
+        Self { magnitude, bias: 0, correction: 0, count: 1 }
+    }
+
+    /// The Golomb–Rice parameter, the log of the average magnitude.
+    fn k(&self) -> u32 {
+        let mut k = 0;
+        while self.count << k < self.magnitude {
+            k += 1;
+        }
+
+        k
+    }
+
+    /// Interleave the residual's sign into its lowest bit. Where the
+    /// context leans negative in lossless coding, negative values come
+    /// first.
+    fn map(&self, error: i32, k: u32, lossless: bool) -> u32 {
+        let flipped = lossless && k == 0 && 2 * self.bias <= -(self.count as i32);
+        let mapped = match (flipped, error >= 0) {
+            (false, true) => 2 * error,
+            (false, false) => -2 * error - 1,
+            (true, true) => 2 * error + 1,
+            (true, false) => -2 * (error + 1),
+        };
+
+        mapped as u32
+    }
+
+    fn unmap(&self, mapped: u32, k: u32, lossless: bool) -> i32 {
+        let flipped = lossless && k == 0 && 2 * self.bias <= -(self.count as i32);
+        let mapped = mapped as i32;
+        match (flipped, mapped & 1 == 0) {
+            (false, true) => mapped / 2,
+            (false, false) => -(mapped + 1) / 2,
+            (true, true) => -(mapped / 2) - 1,
+            (true, false) => (mapped - 1) / 2,
+        }
+    }
+
+    fn update(&mut self, error: i32, step: i32) {
+        self.bias += error * step;
+        self.magnitude += error.unsigned_abs();
+        if self.count == RESET {
+            self.magnitude >>= 1;
+            self.bias >>= 1;
+            self.count >>= 1;
+        }
+        self.count += 1;
+
+        let count = self.count as i32;
+        if self.bias <= -count {
+            self.bias = (self.bias + count).max(-count + 1);
+            self.correction = (self.correction - 1).max(-128);
+        } else if self.bias > 0 {
+            self.bias = (self.bias - count).min(0);
+            self.correction = (self.correction + 1).min(127);
+        }
+    }
+}
+
+/// What the contexts for the samples which interrupt runs have learned.
+#[derive(Debug, Clone, Copy)]
+struct RunContext {
+    /// The total of the magnitudes of the residuals.
+    magnitude: u32,
+
+    count: u32,
+
+    /// How many of the residuals were negative.
+    negative: u32,
+}
+
+impl RunContext {
+    fn k(&self, kind: usize) -> u32 {
+        let total = self.magnitude + if kind == 1 { self.count >> 1 } else { 0 };
+        let mut k = 0;
+        while self.count << k < total {
+            k += 1;
+        }
+
+        k
+    }
+
+    /// Whether the residual's sign is the less likely one, which makes its
+    /// code one larger.
+    fn flips(&self, error: i32, k: u32) -> bool {
+        let mostly_negative = 2 * self.negative >= self.count;
+        (k == 0 && error > 0 && !mostly_negative) || (error < 0 && (mostly_negative || k != 0))
+    }
+
+    fn update(&mut self, error: i32, mapped: u32, kind: usize) {
+        if error < 0 {
+            self.negative += 1;
+        }
+        self.magnitude += (mapped + 1 - kind as u32) >> 1;
+        if self.count == RESET {
+            self.magnitude >>= 1;
+            self.count >>= 1;
+            self.negative >>= 1;
+        }
+        self.count += 1;
+    }
+}
+
+/// The state shared by the encoder and decoder of a chunk.
+struct Coder {
+    near: i32,
+
+    /// The number of values a quantized residual can take.
+    range: i32,
+
+    /// The number of bits any mapped residual fits in.
+    range_bits: u32,
+
+    /// Where the gradients between neighbours are split into regions.
+    thresholds: [i32; 3],
+
+    contexts: Vec<Context>,
+
+    /// For interrupting samples whose neighbours differ, and those whose
+    /// neighbours are the same.
+    run_contexts: [RunContext; 2],
+    run_index: usize,
+}
+
+impl Coder {
+    fn new(near: u8) -> Self {
+        let near = near as i32;
+        let range = (MAX_VALUE + 2 * near) / (2 * near + 1) + 1;
+        let magnitude = 2.max((range + 32) / 64) as u32;
+
+        let t1 = (3 + 3 * near).min(MAX_VALUE).max(near + 1);
+        let t2 = (7 + 5 * near).min(MAX_VALUE).max(t1);
+        let t3 = (21 + 7 * near).min(MAX_VALUE).max(t2);
+
+        Self {
+            near,
+            range,
+            range_bits: u32::BITS - (range as u32 - 1).leading_zeros(),
+            thresholds: [t1, t2, t3],
+            contexts: vec![Context::new(magnitude); CONTEXTS],
+            run_contexts: [RunContext { magnitude, count: 1, negative: 0 }; 2],
+            run_index: 0,
+        }
+    }
+
+    fn quantize_gradient(&self, gradient: i32) -> i32 {
+        let [t1, t2, t3] = self.thresholds;
+        match gradient {
+            g if g <= -t3 => -4,
+            g if g <= -t2 => -3,
+            g if g <= -t1 => -2,
+            g if g < -self.near => -1,
+            g if g <= self.near => 0,
+            g if g < t1 => 1,
+            g if g < t2 => 2,
+            g if g < t3 => 3,
+            _ => 4,
+        }
+    }
+
+    /// The context of a sample and the sign its residual is flipped by, or
+    /// [`None`] if its neighbours are all within `near` of each other, which
+    /// starts a run.
+    fn context(&self, [left, above, above_left, above_right]: [i32; 4]) -> Option<(usize, i32)> {
+        let mut gradients = [
+            self.quantize_gradient(above_right - above),
+            self.quantize_gradient(above - above_left),
+            self.quantize_gradient(above_left - left),
+        ];
+
+        let first = gradients.iter().find(|g| **g != 0)?;
+        let sign = first.signum();
+        gradients.iter_mut().for_each(|g| *g *= sign);
+
+        let [g1, g2, g3] = gradients.map(|g| (g + 4) as usize);
+        Some(((g1 * 9 + g2) * 9 + g3, sign))
+    }
+
+    /// Quantize a residual to steps of `2 * near + 1`, and wrap it into
+    /// [`Coder::range`] values around 0.
+    fn reduce(&self, error: i32) -> i32 {
+        let step = 2 * self.near + 1;
+        let mut error = match error {
+            e if e > 0 => (e + self.near) / step,
+            e => -((self.near - e) / step),
+        };
+
+        if error < 0 {
+            error += self.range;
+        }
+        if error >= (self.range + 1) / 2 {
+            error -= self.range;
+        }
+
+        error
+    }
+
+    /// Whether a residual could have come from [`Coder::reduce`].
+    fn in_range(&self, error: i32) -> bool {
+        (-(self.range / 2)..(self.range + 1) / 2).contains(&error)
+    }
+
+    /// The decoded sample for a prediction and a residual from
+    /// [`Coder::reduce`], unwrapping the residual if it went past either
+    /// end.
+    fn reconstruct(&self, prediction: i32, error: i32) -> u8 {
+        let step = 2 * self.near + 1;
+        let mut value = prediction + error * step;
+        if value < -self.near {
+            value += self.range * step;
+        } else if value > MAX_VALUE + self.near {
+            value -= self.range * step;
+        }
+
+        value.clamp(0, MAX_VALUE) as u8
+    }
+
+    fn predict(&self, context: usize, sign: i32, [left, above, above_left, _]: [i32; 4]) -> i32 {
+        let prediction = med(left, above, above_left) + sign * self.contexts[context].correction;
+        prediction.clamp(0, MAX_VALUE)
+    }
+
+    fn encode_regular(&mut self, bit_io: &mut BitWriter<Vec<u8>>, sample: u8, neighbours: [i32; 4], (context, sign): (usize, i32)) -> u8 {
+        let prediction = self.predict(context, sign, neighbours);
+        let error = self.reduce(sign * (sample as i32 - prediction));
+
+        let lossless = self.near == 0;
+        let context = &mut self.contexts[context];
+        let k = context.k();
+        write_golomb(bit_io, context.map(error, k, lossless), k, LIMIT, self.range_bits);
+        context.update(error, 2 * self.near + 1);
+
+        self.reconstruct(prediction, sign * error)
+    }
+
+    fn decode_regular(&mut self, bit_io: &mut BitReader<&[u8]>, neighbours: [i32; 4], (context, sign): (usize, i32)) -> Option<u8> {
+        let prediction = self.predict(context, sign, neighbours);
+
+        let lossless = self.near == 0;
+        let step = 2 * self.near + 1;
+        let k = self.contexts[context].k();
+        let mapped = read_golomb(bit_io, k, LIMIT, self.range_bits)?;
+        let error = self.contexts[context].unmap(mapped, k, lossless);
+        if !self.in_range(error) {
+            return None
+        }
+        self.contexts[context].update(error, step);
+
+        Some(self.reconstruct(prediction, sign * error))
+    }
+
+    /// Write a run of `len` samples within `near` of the one to the left,
+    /// which either reaches the end of the row or is interrupted by a sample
+    /// which isn't. Each bit 1 is a full segment of `1 << RUN_BITS[index]`
+    /// samples, or the rest of the row, and a 0 is followed by the rest of
+    /// an interrupted run.
+    fn encode_run(&mut self, bit_io: &mut BitWriter<Vec<u8>>, mut len: usize, end_of_row: bool) {
+        while len >= 1 << RUN_BITS[self.run_index] {
+            bit_io.write_bit(1, 1);
+            len -= 1 << RUN_BITS[self.run_index];
+            self.run_index = (self.run_index + 1).min(RUN_BITS.len() - 1);
+        }
+
+        if end_of_row {
+            if len > 0 {
+                bit_io.write_bit(1, 1);
+            }
+        } else {
+            bit_io.write_bit(0, 1);
+            if RUN_BITS[self.run_index] > 0 {
+                bit_io.write_bit(len as u64, RUN_BITS[self.run_index] as usize);
+            }
+        }
+    }
+
+    /// Read a run written by [`Coder::encode_run`] in a row with `remaining`
+    /// samples left, returning its length and whether it was interrupted.
+    fn decode_run(&mut self, bit_io: &mut BitReader<&[u8]>, remaining: usize) -> Option<(usize, bool)> {
+        let mut len = 0;
+        while bit_io.read_bit(1).ok()? == 1 {
+            let segment = 1 << RUN_BITS[self.run_index];
+            if remaining - len < segment {
+                return Some((remaining, false))
+            }
+
+            len += segment;
+            self.run_index = (self.run_index + 1).min(RUN_BITS.len() - 1);
+            if len == remaining {
+                return Some((len, false))
+            }
+        }
+
+        if RUN_BITS[self.run_index] > 0 {
+            len += bit_io.read_bit(RUN_BITS[self.run_index] as usize).ok()? as usize;
+        }
+
+        // There must be a sample left to interrupt the run
+        (len < remaining).then_some((len, true))
+    }
+
+    /// The prediction and sign of a sample which interrupts a run, and
+    /// which of the two run contexts it uses.
+    fn interruption(&self, left: i32, above: i32) -> (i32, i32, usize) {
+        if (left - above).abs() <= self.near {
+            (left, 1, 1)
+        } else if left > above {
+            (above, -1, 0)
+        } else {
+            (above, 1, 0)
+        }
+    }
+
+    fn encode_interruption(&mut self, bit_io: &mut BitWriter<Vec<u8>>, sample: u8, left: i32, above: i32) -> u8 {
+        let (prediction, sign, kind) = self.interruption(left, above);
+        let error = self.reduce(sign * (sample as i32 - prediction));
+
+        let limit = LIMIT - RUN_BITS[self.run_index] - 1;
+        let context = &mut self.run_contexts[kind];
+        let k = context.k(kind);
+        let mapped = 2 * error.unsigned_abs() - kind as u32 - context.flips(error, k) as u32;
+        write_golomb(bit_io, mapped, k, limit, self.range_bits);
+        context.update(error, mapped, kind);
+        self.run_index = self.run_index.saturating_sub(1);
+
+        self.reconstruct(prediction, sign * error)
+    }
+
+    fn decode_interruption(&mut self, bit_io: &mut BitReader<&[u8]>, left: i32, above: i32) -> Option<u8> {
+        let (prediction, sign, kind) = self.interruption(left, above);
+
+        let limit = LIMIT - RUN_BITS[self.run_index] - 1;
+        let context = self.run_contexts[kind];
+        let k = context.k(kind);
+        let mapped = read_golomb(bit_io, k, limit, self.range_bits)?;
+
+        // The flip is the lowest bit, and says which sign it was
+        let total = mapped + kind as u32;
+        let flipped = total & 1 == 1;
+        let magnitude = ((total + flipped as u32) / 2) as i32;
+        let negative = if k == 0 && 2 * context.negative < context.count { !flipped } else { flipped };
+        let error = if negative { -magnitude } else { magnitude };
+        if !self.in_range(error) {
+            return None
+        }
+
+        self.run_contexts[kind].update(error, mapped, kind);
+        self.run_index = self.run_index.saturating_sub(1);
+
+        Some(self.reconstruct(prediction, sign * error))
+    }
+}
+
+/// Write a value as a Golomb–Rice code with parameter `k`, see
+/// [`BitWriter::write_rice`]. Codes which would be longer than `limit` bits
+/// are instead written as the longest quotient which is allowed, then the
+/// value less one in `value_bits` bits.
+fn write_golomb(bit_io: &mut BitWriter<Vec<u8>>, value: u32, k: u32, limit: u32, value_bits: u32) {
+    let escape = limit - value_bits - 1;
+    if value >> k < escape {
+        bit_io.write_rice(value as u64, k);
+    } else {
+        bit_io.write_unary(escape as u64);
+        bit_io.write_bit((value - 1) as u64, value_bits as usize);
+    }
+}
+
+fn read_golomb(bit_io: &mut BitReader<&[u8]>, k: u32, limit: u32, value_bits: u32) -> Option<u32> {
+    let escape = limit - value_bits - 1;
+    let high = bit_io.read_unary().ok()?;
+    if high > escape as u64 {
+        return None
+    } else if high == escape as u64 {
+        return Some(bit_io.read_bit(value_bits as usize).ok()? as u32 + 1)
+    }
+
+    let low = if k > 0 { bit_io.read_bit(k as usize).ok()? as u32 } else { 0 };
+    Some((high as u32) << k | low)
+}
+
+/// Compress a chunk of whole rows, up to [`CHUNK_LEN`] bytes, from the start
+/// of `data`, appending it to `output`.
+///
+/// Returns the number of bytes of `data` which were consumed, which is
+/// none for an image without width.
+pub(crate) fn compress_predictive(data: &[u8], output: &mut Vec<u8>, parameters: PredictiveParameters) -> usize {
+    if parameters.row_len == 0 {
+        return 0
+    }
+
+    let rows = (CHUNK_LEN / parameters.row_len).max(1);
+    let data = &data[..data.len().min(rows * parameters.row_len)];
+    if data.is_empty() {
+        return 0
+    }
+
+    let start = output.len();
+    output.push(CODED);
+    let mut bit_io = BitWriter::new(output);
+
+    let layout = Layout::new(parameters, data.len());
+    let mut coder = Coder::new(parameters.near);
+    let mut decoded = vec![0; data.len()];
+    for channel in 0..layout.channels {
+        for y in 0..layout.height {
+            let mut x = 0;
+            while x < layout.width {
+                let neighbours = layout.neighbours(&decoded, x, y, channel);
+                let index = layout.index(x, y, channel);
+                if let Some(context) = coder.context(neighbours) {
+                    decoded[index] = coder.encode_regular(&mut bit_io, data[index], neighbours, context);
+                    x += 1;
+                    continue
+                }
+
+                let value = neighbours[0];
+                let len = (x..layout.width)
+                    .take_while(|x| (data[layout.index(*x, y, channel)] as i32 - value).abs() <= coder.near)
+                    .count();
+                for x in x..x + len {
+                    decoded[layout.index(x, y, channel)] = value as u8;
+                }
+                x += len;
+                coder.encode_run(&mut bit_io, len, x == layout.width);
+
+                if x < layout.width {
+                    let [left, above, ..] = layout.neighbours(&decoded, x, y, channel);
+                    let index = layout.index(x, y, channel);
+                    decoded[index] = coder.encode_interruption(&mut bit_io, data[index], left, above);
+                    x += 1;
+                }
+            }
+        }
+    }
+    bit_io.flush();
+
+    if output.len() - start > data.len() + 1 {
+        output.truncate(start);
+        output.push(STORED);
+        output.extend_from_slice(data);
+    }
+
+    data.len()
+}
+
+/// Decompress a chunk written by [`compress_predictive`] which is `size`
+/// bytes long when uncompressed.
+///
+/// On an error the channels decoded so far are kept.
+pub(crate) fn decompress_predictive(
+    input: &[u8],
+    size: usize,
+    parameters: PredictiveParameters,
+) -> Result<Vec<u8>, CompressionError> {
+    let mut data = match input.split_first() {
+        Some((&CODED, rest)) => rest,
+        Some((&STORED, rest)) => return Ok(rest.to_vec()),
+        Some((kind, _)) => return Err(CompressionError::BadElement(Vec::new(), *kind as u64, 0)),
+        None => return Err(CompressionError::BadElement(Vec::new(), 0, 0)),
+    };
+
+    // Chunks are always whole rows
+    if !size.is_multiple_of(parameters.row_len) {
+        return Err(CompressionError::BadElement(Vec::new(), size as u64, 0))
+    }
+
+    let mut bit_io = BitReader::new(&mut data);
+    let mut decoded = vec![0; size];
+    match decode_samples(&mut bit_io, &mut decoded, parameters) {
+        Some(()) => Ok(decoded),
+        None => {
+            let offset = 8 + bit_io.bit_position();
+            Err(CompressionError::BadElement(decoded, 0, offset))
+        },
+    }
+}
+
+fn decode_samples(bit_io: &mut BitReader<&[u8]>, decoded: &mut [u8], parameters: PredictiveParameters) -> Option<()> {
+    let layout = Layout::new(parameters, decoded.len());
+    let mut coder = Coder::new(parameters.near);
+    for channel in 0..layout.channels {
+        for y in 0..layout.height {
+            let mut x = 0;
+            while x < layout.width {
+                let neighbours = layout.neighbours(decoded, x, y, channel);
+                let index = layout.index(x, y, channel);
+                if let Some(context) = coder.context(neighbours) {
+                    decoded[index] = coder.decode_regular(bit_io, neighbours, context)?;
+                    x += 1;
+                    continue
+                }
+
+                let (len, interrupted) = coder.decode_run(bit_io, layout.width - x)?;
+                for x in x..x + len {
+                    decoded[layout.index(x, y, channel)] = neighbours[0] as u8;
+                }
+                x += len;
+
+                if interrupted {
+                    let [left, above, ..] = layout.neighbours(decoded, x, y, channel);
+                    let index = layout.index(x, y, channel);
+                    decoded[index] = coder.decode_interruption(bit_io, left, above)?;
+                    x += 1;
+                }
+            }
+        }
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(row_len: usize, channels: usize, near: u8) -> PredictiveParameters {
+        PredictiveParameters { row_len, channels, near }
+    }
+
+    /// Compress and decompress `data` a chunk at a time, returning the
+    /// decompressed data and its compressed size.
+    fn round_trip(data: &[u8], parameters: PredictiveParameters) -> (Vec<u8>, usize) {
+        let mut compressed = Vec::new();
+        let mut offset = 0;
+        let mut chunks = Vec::new();
+        loop {
+            let start = compressed.len();
+            let count = compress_predictive(&data[offset..], &mut compressed, parameters);
+            if count == 0 {
+                break
+            }
+            chunks.push((start, compressed.len(), count));
+            offset += count;
+        }
+
+        let mut decompressed = Vec::new();
+        for (start, end, count) in chunks {
+            decompressed.extend(decompress_predictive(&compressed[start..end], count, parameters).unwrap());
+        }
+
+        (decompressed, compressed.len())
+    }
+
+    fn random(len: usize, state: &mut u32) -> Vec<u8> {
+        (0..len).map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            *state as u8
+        }).collect()
+    }
+
+    /// A smooth image with some noise, edges and flat areas.
+    fn photo_like(width: usize, height: usize, channels: usize, state: &mut u32) -> Vec<u8> {
+        let noise = random(width * height * channels, state);
+        (0..width * height * channels).map(|i| {
+            let (x, y, channel) = ((i / channels) % width, i / channels / width, i % channels);
+            match (x / 16 + y / 16) % 3 {
+                0 => 200,
+                1 => (x + y * 2 + channel * 40) as u8 / 2 + noise[i] % 8,
+                _ => ((x * x + y * 3) / 7 + channel * 10) as u8,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn med_picks_the_edge() {
+        assert_eq!(med(10, 50, 60), 10);
+        assert_eq!(med(10, 50, 0), 50);
+        assert_eq!(med(10, 50, 30), 30);
+        assert_eq!(med(7, 7, 7), 7);
+    }
+
+    #[test]
+    fn golomb_round_trip() {
+        let values = [(0, 0), (1, 0), (5, 2), (22, 0), (23, 0), (255, 0), (255, 7), (1000, 3)];
+
+        let mut output = Vec::new();
+        let mut bit_io = BitWriter::new(&mut output);
+        for (value, k) in values {
+            write_golomb(&mut bit_io, value.min(256), k, LIMIT, 8);
+        }
+        bit_io.flush();
+
+        let mut input = output.as_slice();
+        let mut bit_io = BitReader::new(&mut input);
+        for (value, k) in values {
+            assert_eq!(read_golomb(&mut bit_io, k, LIMIT, 8), Some(value.min(256)));
+        }
+    }
+
+    #[test]
+    fn reduce_and_reconstruct() {
+        // Every sample and prediction comes back within `near`
+        for near in [0, 1, 2, 5, 127] {
+            let coder = Coder::new(near);
+            for sample in 0..=255 {
+                for prediction in 0..=255 {
+                    let error = coder.reduce(sample - prediction);
+                    assert!(coder.in_range(error));
+
+                    let decoded = coder.reconstruct(prediction, error) as i32;
+                    assert!((decoded - sample).abs() <= near as i32, "{sample} from {prediction} at {near}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_lossless() {
+        let mut state = 0x2545F491;
+        for (width, height, channels) in [(1, 1, 1), (1, 40, 3), (37, 23, 4), (64, 64, 2), (300, 900, 3)] {
+            let data = photo_like(width, height, channels, &mut state);
+            let (decoded, size) = round_trip(&data, parameters(width * channels, channels, 0));
+            assert_eq!(decoded, data);
+
+            if data.len() > 1000 {
+                assert!(size < data.len() / 2, "{size} of {}", data.len());
+            }
+        }
+
+        // Runs of every length, along with things which interrupt them
+        let mut flat = vec![128u8; 200 * 50];
+        for (i, pixel) in flat.iter_mut().enumerate() {
+            if i % 97 == 0 || i % 1013 < 3 {
+                *pixel = (i % 251) as u8;
+            }
+        }
+        assert_eq!(round_trip(&flat, parameters(200, 1, 0)).0, flat);
+        assert!(round_trip(&vec![0; 1000 * 1000], parameters(1000, 1, 0)).1 < 3000);
+    }
+
+    #[test]
+    fn round_trip_near_is_bounded() {
+        let mut state = 0x1234567;
+        let data = photo_like(123, 77, 3, &mut state);
+        let mut previous = usize::MAX;
+        for near in [1, 2, 3, 7, 20, 127] {
+            let (decoded, size) = round_trip(&data, parameters(123 * 3, 3, near));
+            assert_eq!(decoded.len(), data.len());
+            for (decoded, original) in decoded.iter().zip(&data) {
+                assert!(decoded.abs_diff(*original) <= near, "{decoded} vs {original} at {near}");
+            }
+
+            assert!(size < previous, "{size} at {near} isn't smaller than {previous}");
+            previous = size;
+        }
+
+        let noise = random(64 * 64 * 4, &mut state);
+        for near in [1, 4, 100] {
+            let (decoded, _) = round_trip(&noise, parameters(64 * 4, 4, near));
+            assert!(decoded.iter().zip(&noise).all(|(d, o)| d.abs_diff(*o) <= near));
+        }
+    }
+
+    #[test]
+    fn noise_is_stored() {
+        let mut state = 0xBEEF;
+        let data = random(CHUNK_LEN + 5000, &mut state);
+        let (decoded, size) = round_trip(&data, parameters(1000, 4, 0));
+        assert_eq!(decoded, data);
+        assert_eq!(size, data.len() + 2);
+    }
+
+    #[test]
+    fn chunks_are_whole_rows() {
+        let row_len = 3 * 1000;
+        let data = vec![9; row_len * 200];
+        let mut output = Vec::new();
+        let count = compress_predictive(&data, &mut output, parameters(row_len, 3, 0));
+        assert_eq!(count, (CHUNK_LEN / row_len) * row_len);
+
+        // A row longer than a chunk is still coded whole
+        let long = vec![9; CHUNK_LEN * 2];
+        assert_eq!(compress_predictive(&long, &mut output, parameters(CHUNK_LEN * 2, 1, 0)), long.len());
+
+        // Rows without width have nothing to code
+        let len = output.len();
+        assert_eq!(compress_predictive(&data, &mut output, parameters(0, 3, 0)), 0);
+        assert_eq!(output.len(), len);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut state = 0x51;
+        let data = photo_like(50, 50, 3, &mut state);
+        let parameters = parameters(150, 3, 0);
+        let mut compressed = Vec::new();
+        compress_predictive(&data, &mut compressed, parameters);
+
+        assert!(decompress_predictive(&[], data.len(), parameters).is_err());
+        assert!(decompress_predictive(&[7], data.len(), parameters).is_err());
+        assert!(decompress_predictive(&compressed, data.len() - 1, parameters).is_err());
+
+        // Truncated chunks keep what decoded, and nothing panics
+        for len in [1, 2, compressed.len() / 2, compressed.len() - 1] {
+            match decompress_predictive(&compressed[..len], data.len(), parameters) {
+                Err(CompressionError::BadElement(partial, _, offset)) => {
+                    assert_eq!(partial.len(), data.len());
+                    assert!(offset <= len as u64 * 8);
+                },
+                result => panic!("{result:?}"),
+            }
+        }
+
+        for i in 0..compressed.len() {
+            let mut bad = compressed.clone();
+            bad[i] ^= 0x5A;
+            let _ = decompress_predictive(&bad, data.len(), parameters);
+        }
+    }
+}
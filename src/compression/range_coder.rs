@@ -0,0 +1,650 @@
+//! A binary range coder for the quantized DCT coefficients of lossy images
+//! which are [range coded], in place of varints compressed with LZW.
+//!
+//! The coder is carry-less, after Dmitry Subbotin's: the interval is never
+//! allowed to straddle a byte boundary which is about to be written, by
+//! shrinking it when it would, so bytes are final as soon as they are
+//! written. Each bit is coded with an adaptive [`Probability`] chosen by its
+//! context, and all of the state is integers, so the output is the same on
+//! every platform.
+//!
+//! Each 8x8 block is coded with [`CoefficientEncoder`] as:
+//!
+//! 1. The difference of its DC coefficient from the previous block's, as a
+//!    zero flag, a sign and a magnitude, see [`Magnitude`].
+//! 2. Whether it has any nonzero AC coefficients, in the context of whether
+//!    the previous block did.
+//! 3. For each AC coefficient in zigzag order, whether it is nonzero, in the
+//!    context of its [`band`] and how many of the coefficient before it and
+//!    the one in the same place in the previous block are nonzero. Nonzero
+//!    coefficients are followed by whether they are the last in the block,
+//!    then their sign and magnitude in the context of their band.
+//!
+//! The stream ends with the last four bytes of the interval.
+//!
+//! [range coded]: crate::header::Header::range_coded
+
+use alloc::vec::Vec;
+
+/// The number of bits in a [`Probability`].
+const PROBABILITY_BITS: u32 = 12;
+
+/// How quickly probabilities adapt, as a shift of the distance to the bit
+/// which was coded.
+const ADAPT_SHIFT: u32 = 5;
+
+/// While the top byte of the interval is the same at both ends, it can be
+/// written.
+const TOP: u32 = 1 << 24;
+
+/// The smallest the range may be before a new byte is shifted in.
+const BOTTOM: u32 = 1 << 16;
+
+/// The number of bands of AC coefficients which share contexts.
+const BANDS: usize = 7;
+
+/// How many magnitudes are coded in unary before switching to an Exp-Golomb
+/// style code.
+const UNARY_LIMIT: u32 = 14;
+
+/// The number of bits the Exp-Golomb style part of a magnitude can have,
+/// which is enough for the difference of two DC coefficients.
+const PREFIX_LIMIT: usize = 16;
+
+/// The natural position in a block of each coefficient in zigzag order,
+/// from the lowest frequencies to the highest.
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// The band of the AC coefficient at a zigzag position. Nearby frequencies
+/// behave alike, and bands get wider as they're more often zero.
+fn band(position: usize) -> usize {
+    match position {
+        1..=2 => 0,
+        3..=5 => 1,
+        6..=9 => 2,
+        10..=14 => 3,
+        15..=20 => 4,
+        21..=35 => 5,
+        _ => 6,
+    }
+}
+
+/// The probability that the next bit in a context is 0, out of
+/// `1 << PROBABILITY_BITS`, which moves towards each bit coded.
+#[derive(Debug, Clone, Copy)]
+struct Probability(u16);
+
+impl Default for Probability {
+    fn default() -> Self {
+        Self(1 << (PROBABILITY_BITS - 1))
+    }
+}
+
+impl Probability {
+    /// Adapt to a bit which was just coded. The probability never reaches 0
+    /// or 1, since the distance shifted away is then less than one.
+    fn update(&mut self, bit: bool) {
+        if bit {
+            self.0 -= self.0 >> ADAPT_SHIFT;
+        } else {
+            self.0 += ((1 << PROBABILITY_BITS) - self.0) >> ADAPT_SHIFT;
+        }
+    }
+}
+
+/// Encodes bits into the interval `low..low + range`, appending finished
+/// bytes to the output.
+struct RangeEncoder<'a> {
+    output: &'a mut Vec<u8>,
+    low: u32,
+    range: u32,
+}
+
+impl<'a> RangeEncoder<'a> {
+    fn new(output: &'a mut Vec<u8>) -> Self {
+        Self { output, low: 0, range: u32::MAX }
+    }
+
+    /// Encode a bit with a probability, then adapt it.
+    fn encode(&mut self, probability: &mut Probability, bit: bool) {
+        let bound = (self.range >> PROBABILITY_BITS) * probability.0 as u32;
+        if bit {
+            self.low = self.low.wrapping_add(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+
+        probability.update(bit);
+        self.normalize();
+    }
+
+    /// Encode the lowest `bits` bits of `value`, each as likely to be 0 as
+    /// 1, from the highest down.
+    fn encode_direct(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 == 1 {
+                self.low = self.low.wrapping_add(self.range);
+            }
+
+            self.normalize();
+        }
+    }
+
+    /// Write out the top byte while it's settled, shrinking the interval
+    /// so it is when the range gets too small.
+    fn normalize(&mut self) {
+        loop {
+            if (self.low ^ self.low.wrapping_add(self.range)) >= TOP {
+                if self.range >= BOTTOM {
+                    break
+                }
+
+                self.range = self.low.wrapping_neg() & (BOTTOM - 1);
+            }
+
+            self.output.push((self.low >> 24) as u8);
+            self.low <<= 8;
+            self.range <<= 8;
+        }
+    }
+
+    /// Write out the rest of the interval.
+    fn finish(mut self) {
+        for _ in 0..4 {
+            self.output.push((self.low >> 24) as u8);
+            self.low <<= 8;
+        }
+    }
+}
+
+/// Decodes bits written by [`RangeEncoder`], reading bytes as it needs them
+/// from a function which returns [`None`] once they run out.
+#[derive(Debug, Clone)]
+struct RangeDecoder {
+    low: u32,
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder {
+    fn new<F: FnMut() -> Option<u8>>(next_byte: &mut F) -> Option<Self> {
+        let mut code = 0;
+        for _ in 0..4 {
+            code = (code << 8) | next_byte()? as u32;
+        }
+
+        Some(Self { low: 0, range: u32::MAX, code })
+    }
+
+    /// Decode a bit with a probability, then adapt it.
+    fn decode<F: FnMut() -> Option<u8>>(&mut self, probability: &mut Probability, next_byte: &mut F) -> Option<bool> {
+        let bound = (self.range >> PROBABILITY_BITS) * probability.0 as u32;
+        let bit = self.code.wrapping_sub(self.low) >= bound;
+        if bit {
+            self.low = self.low.wrapping_add(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+
+        probability.update(bit);
+        self.normalize(next_byte)?;
+        Some(bit)
+    }
+
+    /// Decode bits written by [`RangeEncoder::encode_direct`].
+    fn decode_direct<F: FnMut() -> Option<u8>>(&mut self, bits: u32, next_byte: &mut F) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            self.range >>= 1;
+            let bit = self.code.wrapping_sub(self.low) >= self.range;
+            if bit {
+                self.low = self.low.wrapping_add(self.range);
+            }
+            value = (value << 1) | bit as u32;
+
+            self.normalize(next_byte)?;
+        }
+
+        Some(value)
+    }
+
+    /// Shift in a byte for each one [`RangeEncoder::normalize`] wrote.
+    fn normalize<F: FnMut() -> Option<u8>>(&mut self, next_byte: &mut F) -> Option<()> {
+        loop {
+            if (self.low ^ self.low.wrapping_add(self.range)) >= TOP {
+                if self.range >= BOTTOM {
+                    return Some(())
+                }
+
+                self.range = self.low.wrapping_neg() & (BOTTOM - 1);
+            }
+
+            self.code = (self.code << 8) | next_byte()? as u32;
+            self.low <<= 8;
+            self.range <<= 8;
+        }
+    }
+}
+
+/// The contexts for a magnitude of at least 0: it is coded in unary up to
+/// [`UNARY_LIMIT`], and the rest as the number of bits after its highest in
+/// unary, followed by those bits.
+#[derive(Debug, Clone, Default)]
+struct Magnitude {
+    unary: [Probability; UNARY_LIMIT as usize],
+    prefix: [Probability; PREFIX_LIMIT],
+}
+
+impl Magnitude {
+    fn encode(&mut self, coder: &mut RangeEncoder, magnitude: u32) {
+        for (i, probability) in self.unary.iter_mut().enumerate() {
+            let more = magnitude > i as u32;
+            coder.encode(probability, more);
+            if !more {
+                return
+            }
+        }
+
+        let rest = magnitude - UNARY_LIMIT + 1;
+        let bits = rest.ilog2();
+        for probability in &mut self.prefix[..bits as usize] {
+            coder.encode(probability, true);
+        }
+        coder.encode(&mut self.prefix[bits as usize], false);
+        coder.encode_direct(rest, bits);
+    }
+
+    fn decode<F: FnMut() -> Option<u8>>(&mut self, coder: &mut RangeDecoder, next_byte: &mut F) -> Option<u32> {
+        for (i, probability) in self.unary.iter_mut().enumerate() {
+            if !coder.decode(probability, next_byte)? {
+                return Some(i as u32)
+            }
+        }
+
+        let mut bits = 0;
+        while coder.decode(&mut self.prefix[bits], next_byte)? {
+            bits += 1;
+            if bits == PREFIX_LIMIT {
+                return None
+            }
+        }
+
+        let rest = (1 << bits) | coder.decode_direct(bits as u32, next_byte)?;
+        Some(rest + UNARY_LIMIT - 1)
+    }
+}
+
+/// The contexts for a nonzero value's sign and magnitude.
+#[derive(Debug, Clone, Default)]
+struct Signed {
+    sign: Probability,
+    magnitude: Magnitude,
+}
+
+impl Signed {
+    fn encode(&mut self, coder: &mut RangeEncoder, value: i32) {
+        coder.encode(&mut self.sign, value < 0);
+        self.magnitude.encode(coder, value.unsigned_abs() - 1);
+    }
+
+    fn decode<F: FnMut() -> Option<u8>>(&mut self, coder: &mut RangeDecoder, next_byte: &mut F) -> Option<i32> {
+        let negative = coder.decode(&mut self.sign, next_byte)?;
+        let magnitude = self.magnitude.decode(coder, next_byte)? as i32 + 1;
+
+        Some(if negative { -magnitude } else { magnitude })
+    }
+}
+
+/// The adaptive contexts shared by the encoder and decoder, and what they
+/// remember of the previous block.
+#[derive(Debug, Clone)]
+struct Contexts {
+    dc_zero: Probability,
+    dc: Signed,
+
+    /// Whether a block has nonzero AC coefficients, by whether the
+    /// previous block did.
+    coded: [Probability; 2],
+
+    /// Whether a coefficient is nonzero, by band and how many of its
+    /// neighbours are.
+    significant: [[Probability; 3]; BANDS],
+
+    /// Whether a nonzero coefficient is the last in its block, by zigzag
+    /// position.
+    last: [Probability; 64],
+
+    ac: [Signed; BANDS],
+
+    previous_dc: i16,
+
+    /// The zigzag positions of the nonzero AC coefficients in the previous
+    /// block, as bits.
+    previous_nonzero: u64,
+}
+
+impl Default for Contexts {
+    fn default() -> Self {
+        Self {
+            dc_zero: Probability::default(),
+            dc: Signed::default(),
+            coded: Default::default(),
+            significant: Default::default(),
+            last: [Probability::default(); 64],
+            ac: Default::default(),
+            previous_dc: 0,
+            previous_nonzero: 0,
+        }
+    }
+}
+
+impl Contexts {
+    /// The neighbourhood context of a coefficient, given the positions
+    /// which are nonzero so far in this block.
+    fn neighbours(&self, position: usize, nonzero: u64) -> usize {
+        ((nonzero >> (position - 1)) & 1) as usize + ((self.previous_nonzero >> position) & 1) as usize
+    }
+}
+
+/// Range codes the coefficients of a lossy image one block at a time,
+/// appending the output to a [`Vec`].
+pub(crate) struct CoefficientEncoder<'a> {
+    coder: RangeEncoder<'a>,
+    contexts: Contexts,
+}
+
+impl<'a> CoefficientEncoder<'a> {
+    pub(crate) fn new(output: &'a mut Vec<u8>) -> Self {
+        Self { coder: RangeEncoder::new(output), contexts: Contexts::default() }
+    }
+
+    /// Encode coefficients in blocks of 64, in the order [`dct_compress`]
+    /// produces them.
+    ///
+    /// [`dct_compress`]: super::dct::dct_compress
+    pub(crate) fn encode(&mut self, coefficients: &[i16]) {
+        for block in coefficients.chunks_exact(64) {
+            self.encode_block(block);
+        }
+    }
+
+    fn encode_block(&mut self, block: &[i16]) {
+        let coder = &mut self.coder;
+        let contexts = &mut self.contexts;
+
+        let difference = block[0] as i32 - contexts.previous_dc as i32;
+        coder.encode(&mut contexts.dc_zero, difference == 0);
+        if difference != 0 {
+            contexts.dc.encode(coder, difference);
+        }
+        contexts.previous_dc = block[0];
+
+        let last = (1..64).rev().find(|p| block[ZIGZAG[*p]] != 0);
+        let previous_coded = contexts.previous_nonzero != 0;
+        coder.encode(&mut contexts.coded[previous_coded as usize], last.is_some());
+
+        let mut nonzero = 0u64;
+        for position in 1..=last.unwrap_or(0) {
+            let value = block[ZIGZAG[position]];
+            let band = band(position);
+            let neighbours = contexts.neighbours(position, nonzero);
+            coder.encode(&mut contexts.significant[band][neighbours], value != 0);
+            if value == 0 {
+                continue
+            }
+
+            nonzero |= 1 << position;
+            coder.encode(&mut contexts.last[position], Some(position) == last);
+            contexts.ac[band].encode(coder, value as i32);
+        }
+        contexts.previous_nonzero = nonzero;
+    }
+
+    /// Write out the end of the stream.
+    pub(crate) fn finish(self) {
+        self.coder.finish();
+    }
+}
+
+/// Decodes coefficients written by [`CoefficientEncoder`] one block at a
+/// time.
+#[derive(Debug, Clone)]
+pub(crate) struct CoefficientDecoder {
+    coder: RangeDecoder,
+    contexts: Contexts,
+}
+
+impl CoefficientDecoder {
+    /// Start decoding, or return [`None`] if the stream is too short.
+    pub(crate) fn new<F: FnMut() -> Option<u8>>(next_byte: &mut F) -> Option<Self> {
+        Some(Self { coder: RangeDecoder::new(next_byte)?, contexts: Contexts::default() })
+    }
+
+    /// Decode the next block, or return [`None`] if the stream ends or a
+    /// coefficient doesn't fit in an [`i16`].
+    pub(crate) fn decode_block<F: FnMut() -> Option<u8>>(&mut self, next_byte: &mut F) -> Option<[i16; 64]> {
+        let coder = &mut self.coder;
+        let contexts = &mut self.contexts;
+        let mut block = [0; 64];
+
+        let mut dc = contexts.previous_dc as i32;
+        if !coder.decode(&mut contexts.dc_zero, next_byte)? {
+            dc += contexts.dc.decode(coder, next_byte)?;
+        }
+        block[0] = i16::try_from(dc).ok()?;
+        contexts.previous_dc = block[0];
+
+        let previous_coded = contexts.previous_nonzero != 0;
+        let coded = coder.decode(&mut contexts.coded[previous_coded as usize], next_byte)?;
+
+        let mut nonzero = 0u64;
+        let end = if coded { 64 } else { 1 };
+        for position in 1..end {
+            let band = band(position);
+            let neighbours = contexts.neighbours(position, nonzero);
+            if !coder.decode(&mut contexts.significant[band][neighbours], next_byte)? {
+                continue
+            }
+
+            nonzero |= 1 << position;
+            let last = coder.decode(&mut contexts.last[position], next_byte)?;
+            let value = contexts.ac[band].decode(coder, next_byte)?;
+            block[ZIGZAG[position]] = i16::try_from(value).ok()?;
+            if last {
+                break
+            }
+        }
+        contexts.previous_nonzero = nonzero;
+
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn encode_coefficients(coefficients: &[i16]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = CoefficientEncoder::new(&mut output);
+        encoder.encode(coefficients);
+        encoder.finish();
+
+        output
+    }
+
+    /// Decode `blocks` blocks from the start of `data`, returning them and
+    /// the number of bytes left over.
+    fn decode_coefficients(data: &[u8], blocks: usize) -> Option<(Vec<i16>, usize)> {
+        let mut input = data;
+        let mut next_byte = || {
+            let (byte, rest) = input.split_first()?;
+            input = rest;
+            Some(*byte)
+        };
+
+        let mut decoder = CoefficientDecoder::new(&mut next_byte)?;
+        let mut coefficients = Vec::new();
+        for _ in 0..blocks {
+            coefficients.extend(decoder.decode_block(&mut next_byte)?);
+        }
+
+        Some((coefficients, input.len()))
+    }
+
+    fn random(len: usize, state: &mut u32) -> Vec<u32> {
+        (0..len).map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            *state
+        }).collect()
+    }
+
+    fn round_trip(coefficients: &[i16]) -> usize {
+        let encoded = encode_coefficients(coefficients);
+        let (decoded, left) = decode_coefficients(&encoded, coefficients.len() / 64).unwrap();
+        assert_eq!(decoded, coefficients);
+
+        // Every byte written is needed to decode
+        assert_eq!(left, 0);
+
+        encoded.len()
+    }
+
+    #[test]
+    fn zigzag_is_a_permutation() {
+        let mut seen = [false; 64];
+        for position in ZIGZAG {
+            assert!(!seen[position]);
+            seen[position] = true;
+        }
+
+        // Neighbours along each anti-diagonal
+        assert_eq!(ZIGZAG[..6], [0, 1, 8, 16, 9, 2]);
+    }
+
+    #[test]
+    fn probability_stays_in_range() {
+        for bit in [false, true] {
+            let mut probability = Probability::default();
+            for _ in 0..10_000 {
+                probability.update(bit);
+            }
+            assert!((1..1 << PROBABILITY_BITS).contains(&probability.0), "{}", probability.0);
+        }
+    }
+
+    #[test]
+    fn direct_bits_round_trip() {
+        let values = random(500, &mut 0x2545F491);
+
+        let mut output = Vec::new();
+        let mut coder = RangeEncoder::new(&mut output);
+        let mut probability = Probability::default();
+        for (i, value) in values.iter().enumerate() {
+            coder.encode_direct(*value, (i % 33) as u32);
+            coder.encode(&mut probability, value & 1 == 0);
+        }
+        coder.finish();
+
+        let mut input = output.as_slice();
+        let mut next_byte = || {
+            let (byte, rest) = input.split_first()?;
+            input = rest;
+            Some(*byte)
+        };
+        let mut decoder = RangeDecoder::new(&mut next_byte).unwrap();
+        let mut probability = Probability::default();
+        for (i, value) in values.iter().enumerate() {
+            let bits = (i % 33) as u32;
+            let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+            assert_eq!(decoder.decode_direct(bits, &mut next_byte), Some(value & mask));
+            assert_eq!(decoder.decode(&mut probability, &mut next_byte), Some(value & 1 == 0));
+        }
+    }
+
+    #[test]
+    fn round_trip_extremes() {
+        // Blocks of the largest and smallest values, and empty blocks, so DC
+        // differences cover the whole range
+        let mut coefficients = vec![0; 64];
+        coefficients.extend([i16::MAX; 64]);
+        coefficients.extend([i16::MIN; 64]);
+        coefficients.extend([0; 64]);
+        coefficients.extend((0..64).map(|i| if i % 2 == 0 { i16::MIN } else { i16::MAX }));
+        coefficients.extend((0..64).map(|i| if i == 63 { -1 } else { 0 }));
+        round_trip(&coefficients);
+
+        // A lone block, and nothing at all
+        round_trip(&[5; 64]);
+        assert_eq!(round_trip(&[]), 4);
+    }
+
+    #[test]
+    fn round_trip_random() {
+        let mut state = 0x2545F491;
+
+        for i in 0..50 {
+            let blocks = random(1, &mut state)[0] as usize % 40 + 1;
+            let values = random(blocks * 64, &mut state);
+
+            // Mostly zeroes with smaller values at higher frequencies, like
+            // real coefficients, then increasingly wild
+            let coefficients: Vec<i16> = values.iter().enumerate().map(|(j, v)| {
+                let scale = 1 << (i % 16);
+                let value = (*v as i32 % scale) / (j as i32 % 64 + 1);
+                if v % 3 == 0 { 0 } else { value as i16 }
+            }).collect();
+
+            round_trip(&coefficients);
+        }
+
+        let noise: Vec<i16> = random(64 * 100, &mut state).iter().map(|v| *v as i16).collect();
+        round_trip(&noise);
+    }
+
+    #[test]
+    fn smaller_than_varints() {
+        // A smooth gradient's blocks: a changing DC and a few small ACs
+        let coefficients: Vec<i16> = (0..64 * 200).map(|i| match i % 64 {
+            0 => (i / 64 % 50) as i16 - 25,
+            1 | 8 => -3,
+            9 => (i / 64 % 3) as i16,
+            _ => 0,
+        }).collect();
+
+        // Varints take a byte for each of these coefficients
+        assert!(round_trip(&coefficients) * 20 < coefficients.len());
+    }
+
+    #[test]
+    fn truncated_and_corrupt() {
+        let coefficients: Vec<i16> = (0..64 * 20).map(|i| (i * 7 % 13) as i16 - 6).collect();
+        let encoded = encode_coefficients(&coefficients);
+
+        for len in [0, 3, encoded.len() / 2, encoded.len() - 1] {
+            assert_eq!(decode_coefficients(&encoded[..len], 20), None);
+        }
+
+        // Garbage decodes to something or runs out, but never panics
+        let garbage: Vec<u8> = random(4000, &mut 1).iter().map(|v| *v as u8).collect();
+        for start in 0..100 {
+            let _ = decode_coefficients(&garbage[start..], 50);
+        }
+    }
+}
@@ -0,0 +1,981 @@
+//! The typed sections which follow the header from format version 3 on.
+//!
+//! Each section is a four byte [`SectionType`], the length of its payload
+//! as a little endian `u32`, and then the payload itself. Decoders skip
+//! over sections they don't know, unless the type marks them as critical.
+//!
+//! The chunk table is stored in a [`SectionType::CHUNK_TABLE`] section, and
+//! the compressed chunks in a [`SectionType::PIXEL_DATA`] section. The pixel
+//! data comes after the chunk table and is always the last section, so
+//! anything after it is not part of the image. Other sections may come
+//! before or between them.
+//!
+//! From version 8 on, each entry of the chunk table gives the offset of its
+//! chunk from the start of the file after the sizes, see [`ChunkIndex`].
+//!
+//! Encoders write a [`SectionType::ENCODER_INFO`] section between the chunk
+//! table and pixel data, unless told not to, and can be asked to write a
+//! [`SectionType::CONTENT_HASH`] section after it. Images compressed with a
+//! shared dictionary have a [`SectionType::SHARED_DICTIONARY`] section
+//! straight after the chunk table.
+//!
+//! From version 10 on, the pixel data is followed by a footer, which is
+//! [`FOOTER_MAGIC`] and the length of the file up to the footer as a little
+//! endian `u64`. A file which ends before its footer was cut short, even if
+//! it ends between two chunks. Older versions have no footer, so they are
+//! complete once their last chunk has been read.
+//!
+//! Older versions have the chunk table and chunks directly after the header,
+//! with nothing around them.
+
+use core::{fmt, ops::Range};
+
+#[cfg(feature = "std")]
+use std::io::{Seek, SeekFrom};
+
+use alloc::{format, string::String, vec::Vec};
+
+#[cfg(feature = "std")]
+use crate::compression::lossless::{decompress_chunks, total_size_raw, Backend};
+use crate::{
+    compression::lossless::CompressionInfo,
+    header::{CompressionType, Header},
+    io::{self, read_vec, Read, ReadExt, Write, WriteExt},
+    lossless::Dictionary,
+    options::{EncodeOptions, Limits},
+    picture::{check_chunk_table, read_chunk_table, Error, FileSection},
+    SquishyPicture,
+};
+
+/// The first format version which stores the image in sections.
+pub(crate) const FIRST_SECTION_VERSION: u8 = 3;
+
+/// The longest encoder info which is written or read, in bytes.
+pub(crate) const MAX_ENCODER_INFO_LEN: usize = 256;
+
+/// The algorithm byte of a content hash which is an xxHash64 with a seed
+/// of 0.
+pub(crate) const CONTENT_HASH_XXH64: u8 = 1;
+
+/// The length of a content hash section's payload, the algorithm byte
+/// followed by the hash as a little endian `u64`.
+pub(crate) const CONTENT_HASH_LEN: usize = 9;
+
+/// The length of a shared dictionary section's payload, the dictionary's
+/// ID as a little endian `u64`.
+pub(crate) const SHARED_DICTIONARY_LEN: usize = 8;
+
+/// The bytes which start the footer at the end of the file.
+pub const FOOTER_MAGIC: [u8; 4] = *b"SQPE";
+
+/// The length of the footer, [`FOOTER_MAGIC`] followed by the length of the
+/// file before it as a little endian `u64`.
+pub(crate) const FOOTER_LEN: usize = 12;
+
+/// The type of a section, four bytes which are usually ASCII letters.
+///
+/// Like chunk types in PNG, a section whose type starts with a lowercase
+/// letter is ancillary, and can be skipped by decoders which don't know it.
+/// Any other section is critical, and decoders must fail on critical
+/// sections they don't know rather than decode the image wrongly.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectionType(pub [u8; 4]);
+
+impl SectionType {
+    /// The chunk table, giving the compressed and raw size of each chunk.
+    pub const CHUNK_TABLE: Self = Self(*b"CINF");
+
+    /// The compressed chunks of image data, in the order of the chunk
+    /// table.
+    pub const PIXEL_DATA: Self = Self(*b"PIXD");
+
+    /// UTF-8 text naming the encoder which wrote the file, see
+    /// [`SquishyPicture::encoder_info`].
+    ///
+    /// [`SquishyPicture::encoder_info`]: crate::SquishyPicture::encoder_info
+    pub const ENCODER_INFO: Self = Self(*b"encI");
+
+    /// A hash of the raw bitmap, see
+    /// [`SquishyPicture::read_content_hash`].
+    ///
+    /// [`SquishyPicture::read_content_hash`]: crate::SquishyPicture::read_content_hash
+    pub const CONTENT_HASH: Self = Self(*b"hasH");
+
+    /// The ID of the shared dictionary every chunk was compressed from, see
+    /// [`Dictionary`]. Decoding without that dictionary would give the
+    /// wrong pixels, so the section is critical.
+    ///
+    /// [`Dictionary`]: crate::lossless::Dictionary
+    pub const SHARED_DICTIONARY: Self = Self(*b"DICT");
+
+    /// Whether decoders must understand the section to decode the image.
+    pub fn is_critical(&self) -> bool {
+        self.0[0] & 0x20 == 0
+    }
+}
+
+impl fmt::Display for SectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.escape_ascii())
+    }
+}
+
+impl fmt::Debug for SectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SectionType(\"{self}\")")
+    }
+}
+
+/// Serialized as a string, with any bytes which aren't printable ASCII
+/// escaped.
+#[cfg(feature = "serde")]
+impl serde::Serialize for SectionType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where a section is in a file, as found by [`analyze`].
+///
+/// [`analyze`]: crate::analysis::analyze
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SectionInfo {
+    /// The type of the section.
+    pub kind: SectionType,
+
+    /// Offset of the start of the section from the start of the file.
+    pub offset: u64,
+
+    /// Length of the section's payload in bytes, not counting the type and
+    /// length before it.
+    pub len: u32,
+}
+
+/// The type and payload length which start every section.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SectionHeader {
+    pub kind: SectionType,
+    pub len: u32,
+}
+
+impl SectionHeader {
+    /// Length of a section header in bytes.
+    pub const LEN: usize = 8;
+
+    /// Create the header of a section with a payload of `len` bytes.
+    pub fn new(kind: SectionType, len: usize) -> Result<Self, Error> {
+        let Ok(len) = u32::try_from(len) else {
+            return Err(Error::SectionTooLarge { section: kind, len: len as u64 })
+        };
+
+        Ok(Self { kind, len })
+    }
+
+    /// Read a section header from a byte stream implementing [`Read`].
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self, io::Error> {
+        let mut bytes = [0u8; Self::LEN];
+        input.read_exact(&mut bytes)?;
+
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Parse a section header from its bytes.
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        let [a, b, c, d, len @ ..] = bytes;
+
+        Self {
+            kind: SectionType([a, b, c, d]),
+            len: u32::from_le_bytes(len),
+        }
+    }
+
+    /// Write the section header into a byte stream implementing [`Write`].
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_into<W: Write>(&self, output: &mut W) -> Result<usize, io::Error> {
+        output.write_all(&self.kind.0)?;
+        output.write_u32_le(self.len)?;
+
+        Ok(Self::LEN)
+    }
+
+    /// Check the payload length against the length of its contents.
+    pub fn check_len(&self, expected: usize) -> Result<(), Error> {
+        if self.len as usize != expected {
+            return Err(Error::SectionSizeMismatch {
+                section: self.kind,
+                expected: expected as u64,
+                actual: self.len as u64,
+            })
+        }
+
+        Ok(())
+    }
+
+    /// Check that a section can be skipped, as it isn't one the decoder is
+    /// waiting for.
+    ///
+    /// The chunk table and pixel data can't be skipped, so seeing them here
+    /// means they are repeated or out of order.
+    pub fn check_skippable(&self) -> Result<(), Error> {
+        match self.kind {
+            SectionType::CHUNK_TABLE | SectionType::PIXEL_DATA => Err(Error::UnexpectedSection(self.kind)),
+            kind if kind.is_critical() => Err(Error::UnknownCriticalSection(kind)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The total length of the compressed chunks in bytes.
+pub(crate) fn pixel_data_len(compression_info: &CompressionInfo) -> usize {
+    compression_info.chunks.iter().map(|c| c.size_compressed).fold(0, usize::saturating_add)
+}
+
+/// The encoder info to write with the given options, if any.
+///
+/// The application name is shortened, at a character boundary, until the
+/// whole record fits in [`MAX_ENCODER_INFO_LEN`].
+pub(crate) fn encoder_info(header: &Header, options: &EncodeOptions) -> Option<String> {
+    if options.omit_encoder_info || header.version < FIRST_SECTION_VERSION {
+        return None
+    }
+
+    let library = concat!("sqp ", env!("CARGO_PKG_VERSION"));
+    let Some(name) = &options.encoder_name else {
+        return Some(library.into())
+    };
+
+    let mut name_len = name.len().min(MAX_ENCODER_INFO_LEN - library.len() - 3);
+    while !name.is_char_boundary(name_len) {
+        name_len -= 1;
+    }
+
+    Some(format!("{} ({library})", &name[..name_len]))
+}
+
+/// Read the payload of an encoder info section, which is at most
+/// [`MAX_ENCODER_INFO_LEN`] bytes long.
+///
+/// Encoder info which isn't UTF-8 is ignored, as it doesn't affect the
+/// image. Longer sections are skipped like any other ancillary section.
+pub(crate) fn read_encoder_info<R: Read>(input: &mut R, len: u32) -> Result<Option<String>, io::Error> {
+    Ok(String::from_utf8(read_vec(input, len as usize)?).ok())
+}
+
+/// Read the payload of a content hash section, which is
+/// [`CONTENT_HASH_LEN`] bytes long.
+///
+/// Hashes made with an unknown algorithm are ignored.
+pub(crate) fn read_content_hash_section<R: Read>(input: &mut R) -> Result<Option<u64>, io::Error> {
+    let algorithm = input.read_u8()?;
+    let mut hash = [0u8; 8];
+    input.read_exact(&mut hash)?;
+
+    Ok((algorithm == CONTENT_HASH_XXH64).then(|| u64::from_le_bytes(hash)))
+}
+
+/// The hash of a raw bitmap stored in a content hash section.
+#[cfg(feature = "content-hash")]
+pub(crate) fn content_hash(bitmap: &[u8]) -> u64 {
+    xxhash_rust::xxh64::xxh64(bitmap, 0)
+}
+
+/// The shared dictionary to compress an image with the given header from,
+/// if the options give one which it can use.
+///
+/// Only lossless images are compressed with LZW from version 9 on, and
+/// sections to store the dictionary's ID in start at version 3.
+pub(crate) fn shared_dictionary<'a>(header: &Header, options: &'a EncodeOptions) -> Option<&'a Dictionary> {
+    let usable = header.compression_type == CompressionType::Lossless && header.version >= FIRST_SECTION_VERSION;
+
+    options.shared_dictionary.as_ref().filter(|_| usable)
+}
+
+/// The length in bytes of everything between the header and the first
+/// compressed chunk.
+pub(crate) fn chunk_table_len(header: &Header, compression_info: &CompressionInfo, options: &EncodeOptions) -> usize {
+    if header.version < FIRST_SECTION_VERSION {
+        return compression_info.len()
+    }
+
+    let mut len = SectionHeader::LEN + table_len(header, compression_info) + SectionHeader::LEN;
+    if shared_dictionary(header, options).is_some() {
+        len += SectionHeader::LEN + SHARED_DICTIONARY_LEN;
+    }
+    if let Some(info) = encoder_info(header, options) {
+        len += SectionHeader::LEN + info.len();
+    }
+
+    #[cfg(feature = "content-hash")]
+    if options.content_hash {
+        len += SectionHeader::LEN + CONTENT_HASH_LEN;
+    }
+
+    len
+}
+
+/// The length of the chunk table itself, which gives the offset of each
+/// chunk from version 8 on.
+pub(crate) fn table_len(header: &Header, compression_info: &CompressionInfo) -> usize {
+    match header.stored_chunk_offsets() {
+        true => compression_info.len_with_offsets(),
+        false => compression_info.len(),
+    }
+}
+
+/// Write everything between the header of `picture` and the first
+/// compressed chunk, which is the chunk table and, from version 3 on, the
+/// ancillary sections chosen by `options` and the headers of the sections.
+///
+/// Returns the number of bytes written.
+pub(crate) fn write_chunk_table<W: Write>(
+    output: &mut W,
+    picture: &SquishyPicture,
+    compression_info: &CompressionInfo,
+    options: &EncodeOptions,
+) -> Result<usize, Error> {
+    let header = &picture.header;
+    if header.version < FIRST_SECTION_VERSION {
+        return Ok(compression_info.write_into(output)?)
+    }
+
+    let mut count = SectionHeader::new(SectionType::CHUNK_TABLE, table_len(header, compression_info))?
+        .write_into(output)?;
+    count += match header.stored_chunk_offsets() {
+        true => {
+            let data_offset = header.len() + chunk_table_len(header, compression_info, options);
+            compression_info.write_with_offsets(output, data_offset as u64)?
+        },
+        false => compression_info.write_into(output)?,
+    };
+
+    if let Some(dictionary) = shared_dictionary(header, options) {
+        count += SectionHeader::new(SectionType::SHARED_DICTIONARY, SHARED_DICTIONARY_LEN)?.write_into(output)?;
+        output.write_all(&dictionary.id().to_le_bytes())?;
+        count += SHARED_DICTIONARY_LEN;
+    }
+
+    if let Some(info) = encoder_info(header, options) {
+        count += SectionHeader::new(SectionType::ENCODER_INFO, info.len())?.write_into(output)?;
+        output.write_all(info.as_bytes())?;
+        count += info.len();
+    }
+
+    #[cfg(feature = "content-hash")]
+    if options.content_hash {
+        count += SectionHeader::new(SectionType::CONTENT_HASH, CONTENT_HASH_LEN)?.write_into(output)?;
+        output.write_u8(CONTENT_HASH_XXH64)?;
+        output.write_all(&content_hash(&picture.bitmap).to_le_bytes())?;
+        count += CONTENT_HASH_LEN;
+    }
+
+    count += SectionHeader::new(SectionType::PIXEL_DATA, pixel_data_len(compression_info))?
+        .write_into(output)?;
+
+    Ok(count)
+}
+
+/// Write the footer which ends the file from version 10 on, after an image
+/// of `image_len` bytes.
+///
+/// Returns the number of bytes written.
+pub(crate) fn write_footer<W: Write>(output: &mut W, header: &Header, image_len: u64) -> Result<usize, Error> {
+    if header.footer_len() == 0 {
+        return Ok(0)
+    }
+
+    output.write_all(&FOOTER_MAGIC)?;
+    output.write_all(&image_len.to_le_bytes())?;
+
+    Ok(FOOTER_LEN)
+}
+
+/// Check that a footer read from the end of an image of `image_len` bytes
+/// is the one which was written there.
+pub(crate) fn footer_matches(footer: &[u8; FOOTER_LEN], image_len: u64) -> bool {
+    footer[..4] == FOOTER_MAGIC && footer[4..] == image_len.to_le_bytes()
+}
+
+/// Check the offset at which the chunk table section was found against the
+/// one stored in the header, if there is one.
+pub(crate) fn check_data_offset(header: &Header, offset: u64) -> Result<(), Error> {
+    match header.data_offset {
+        Some(expected) if expected != offset => Err(Error::DataOffsetMismatch { expected, actual: offset }),
+        _ => Ok(()),
+    }
+}
+
+/// Check the offsets stored in the chunk table, if there are any, against
+/// the chunk sizes. The chunks must follow each other from `data_offset`
+/// with nothing between them, so none of them can overlap.
+pub(crate) fn check_chunk_offsets(compression_info: &CompressionInfo, data_offset: u64) -> Result<(), Error> {
+    let mut expected = data_offset;
+    for (chunk, (info, actual)) in compression_info.chunks.iter().zip(&compression_info.offsets).enumerate() {
+        if *actual != expected {
+            return Err(Error::ChunkOffsetMismatch { chunk, expected, actual: *actual })
+        }
+        expected += info.size_compressed as u64;
+    }
+
+    Ok(())
+}
+
+/// The chunk table, along with what was found in the sections around it.
+pub(crate) struct ChunkTable {
+    pub compression_info: CompressionInfo,
+
+    /// Offset of the first compressed chunk from the start of the file.
+    pub data_offset: u64,
+
+    pub encoder_info: Option<String>,
+
+    /// The stored hash of the raw bitmap.
+    pub content_hash: Option<u64>,
+
+    /// The ID of the shared dictionary the chunks were compressed from,
+    /// which has been checked against the one given.
+    pub shared_dictionary: Option<u64>,
+}
+
+impl ChunkTable {
+    /// Check that every compressed chunk the table lists ends within an
+    /// input of `input_len` bytes, so a table which claims more data than
+    /// there is fails before anything is allocated for it.
+    pub fn check_input_len(&self, input_len: u64) -> Result<(), Error> {
+        let end = self.data_offset.saturating_add(pixel_data_len(&self.compression_info) as u64);
+        if end > input_len {
+            return Err(Error::TruncatedFile { section: FileSection::ChunkData })
+        }
+
+        Ok(())
+    }
+}
+
+/// Where each compressed chunk of an encoded image is, so that only the
+/// chunks which are needed have to be read from input which can seek.
+///
+/// From format version 8 on, the chunk table gives the offset of each
+/// chunk, which is checked against the sizes of the chunks before it when
+/// the table is read. Older files have the offsets worked out from the
+/// sizes instead.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use std::io::Cursor;
+/// use sqp::{container::ChunkIndex, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(64, 64, ColorFormat::Gray8, vec![50; 64 * 64]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let index = ChunkIndex::read_from(encoded.as_slice()).unwrap();
+/// let range = index.chunk_range(0).unwrap();
+/// assert!(range.end as usize <= encoded.len());
+///
+/// let data = index.read_chunks(Cursor::new(&encoded), 0..1).unwrap();
+/// assert_eq!(data.len(), index.raw_range(0).unwrap().len());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChunkIndex {
+    header: Header,
+    compression_info: CompressionInfo,
+}
+
+impl ChunkIndex {
+    /// Read the header and chunk table of an image from anything that
+    /// implements [`Read`], stopping at the first compressed chunk.
+    /// [`Limits::default`] applies.
+    pub fn read_from<I: Read>(mut input: I) -> Result<Self, Error> {
+        let header = Header::read_from(&mut input)?;
+        let ChunkTable { compression_info, .. } = read_chunk_table(&mut input, &header, &Limits::default())?;
+
+        Ok(Self { header, compression_info })
+    }
+
+    /// The header of the image.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The number of compressed chunks.
+    pub fn chunk_count(&self) -> usize {
+        self.compression_info.chunks.len()
+    }
+
+    /// The bytes of the file which the given chunk takes up, or [`None`]
+    /// if there is no such chunk.
+    pub fn chunk_range(&self, chunk: usize) -> Option<Range<u64>> {
+        (chunk < self.chunk_count()).then(|| self.compression_info.chunk_range(chunk))
+    }
+
+    /// The part of the decompressed data which the given chunk holds, or
+    /// [`None`] if there is no such chunk.
+    ///
+    /// For lossless images the decompressed data is the row filtered
+    /// bitmap, and for lossy ones the stream of coefficients.
+    pub fn raw_range(&self, chunk: usize) -> Option<Range<usize>> {
+        let chunks = &self.compression_info.chunks;
+        let size_raw = chunks.get(chunk)?.size_raw;
+        let start = chunks[..chunk].iter().map(|c| c.size_raw).sum::<usize>();
+
+        Some(start..start + size_raw)
+    }
+
+    /// Read and decompress a run of chunks from anything that implements
+    /// [`Read`] and [`Seek`], seeking straight to the first of them. The
+    /// input is the whole file, from the start of the header.
+    ///
+    /// Returns the decompressed data of the chunks one after another, which
+    /// is the part of the data from the start of [`ChunkIndex::raw_range`]
+    /// of the first chunk to the end of that of the last. Like decoding,
+    /// whatever could be decompressed from a corrupted chunk is kept and
+    /// the rest of it is zeroes.
+    ///
+    /// Fails with [`Error::InvalidChunkRange`] if the range is empty or
+    /// goes past the last chunk, and [`Error::TruncatedFile`] if the chunks
+    /// reach past the end of the input. [`Limits::default`] applies to the
+    /// decompressed data.
+    ///
+    /// [`Seek`]: std::io::Seek
+    #[cfg(feature = "std")]
+    pub fn read_chunks<I: Read + Seek>(&self, mut input: I, chunks: Range<usize>) -> Result<Vec<u8>, Error> {
+        let info = &self.compression_info;
+        if chunks.is_empty() || chunks.end > self.chunk_count() {
+            return Err(Error::InvalidChunkRange { start: chunks.start, end: chunks.end, chunk_count: self.chunk_count() })
+        }
+
+        let start = info.chunk_range(chunks.start).start;
+        let end = info.chunk_range(chunks.end - 1).end;
+        if end > input.seek(SeekFrom::End(0))? {
+            return Err(Error::TruncatedFile { section: FileSection::ChunkData })
+        }
+        Limits::default().check_alloc(total_size_raw(&info.chunks[chunks.clone()]))?;
+
+        input.seek(SeekFrom::Start(start))?;
+        let data = read_vec(&mut input, (end - start) as usize)
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+
+        let compressed: Vec<(&[u8], usize)> = chunks
+            .map(|i| {
+                let range = info.chunk_range(i);
+                (&data[(range.start - start) as usize..(range.end - start) as usize], info.chunks[i].size_raw)
+            })
+            .collect();
+
+        Ok(decompress_chunks(&compressed, Backend::from(&self.header), &mut Vec::new()))
+    }
+}
+
+/// The next part of the input which a [`ChunkTableReader`] needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TablePart {
+    /// This many bytes, to be given to [`ChunkTableReader::advance`].
+    Read(usize),
+
+    /// This many bytes of a section which isn't needed, to be thrown away
+    /// before calling [`ChunkTableReader::advance`] with none.
+    Skip(usize),
+
+    /// The whole chunk table has been read, and the input is at the first
+    /// compressed chunk.
+    Done,
+}
+
+/// What a [`ChunkTableReader`] is reading.
+#[derive(Debug, Clone, Copy)]
+enum TableStep {
+    SectionHeader,
+
+    /// The chunk count, which is in the given section from version 3 on.
+    ChunkCount(Option<SectionHeader>),
+
+    /// The size of each chunk.
+    Chunks { count: usize, section: Option<SectionHeader> },
+
+    EncoderInfo(u32),
+    ContentHash,
+    SharedDictionary,
+    Skip(u32),
+    Done,
+}
+
+/// Reads the chunk table which follows the header one part at a time, and
+/// the sections around it from version 3 on, checking each part against the
+/// header and limits as it is read.
+///
+/// Like [`Header::remaining_len`], the length of each part is known before
+/// it is read, so reading can stop wherever the input runs out and carry on
+/// once there is more.
+pub(crate) struct ChunkTableReader {
+    header: Header,
+    limits: Limits,
+    step: TableStep,
+
+    /// Offset of the next part from the start of the file.
+    offset: u64,
+
+    compression_info: Option<CompressionInfo>,
+    encoder_info: Option<String>,
+    content_hash: Option<u64>,
+
+    /// The ID of the shared dictionary which was given, and of the one the
+    /// image was found to need.
+    given_dictionary: Option<u64>,
+    shared_dictionary: Option<u64>,
+
+    /// Every section header read so far.
+    sections: Vec<SectionInfo>,
+}
+
+impl ChunkTableReader {
+    /// Start reading the chunk table of an image with the given header.
+    ///
+    /// Also checks the header against the limits, as reading the chunk
+    /// table is the last step before anything large is allocated.
+    pub(crate) fn new(header: &Header, limits: &Limits) -> Result<Self, Error> {
+        limits.check_header(header)?;
+
+        let step = if header.version < FIRST_SECTION_VERSION {
+            TableStep::ChunkCount(None)
+        } else {
+            TableStep::SectionHeader
+        };
+
+        Ok(Self {
+            header: *header,
+            limits: *limits,
+            step,
+            offset: header.len() as u64,
+            compression_info: None,
+            encoder_info: None,
+            content_hash: None,
+            given_dictionary: None,
+            shared_dictionary: None,
+            sections: Vec::new(),
+        })
+    }
+
+    /// Give the shared dictionary to check a
+    /// [`SectionType::SHARED_DICTIONARY`] section against. Without one,
+    /// images which need a dictionary fail to read.
+    pub(crate) fn with_shared_dictionary(mut self, dictionary: Option<&Dictionary>) -> Self {
+        self.given_dictionary = dictionary.map(Dictionary::id);
+        self
+    }
+
+    /// The header of the image the chunk table is for.
+    pub(crate) fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The sections whose headers have been read so far, in file order.
+    pub(crate) fn sections(&self) -> &[SectionInfo] {
+        &self.sections
+    }
+
+    /// The part of the input to read next.
+    pub(crate) fn next_part(&self) -> TablePart {
+        match self.step {
+            TableStep::SectionHeader => TablePart::Read(SectionHeader::LEN),
+            TableStep::ChunkCount(_) => TablePart::Read(4),
+            TableStep::Chunks { count, .. } => match self.header.stored_chunk_offsets() {
+                true => TablePart::Read(count.saturating_mul(16)),
+                false => TablePart::Read(count.saturating_mul(8)),
+            },
+            TableStep::EncoderInfo(len) => TablePart::Read(len as usize),
+            TableStep::ContentHash => TablePart::Read(CONTENT_HASH_LEN),
+            TableStep::SharedDictionary => TablePart::Read(SHARED_DICTIONARY_LEN),
+            TableStep::Skip(len) => TablePart::Skip(len as usize),
+            TableStep::Done => TablePart::Done,
+        }
+    }
+
+    /// The section of the file the next part is in, for when the input ends
+    /// before it.
+    pub(crate) fn file_section(&self) -> FileSection {
+        match self.step {
+            TableStep::ChunkCount(_) | TableStep::Chunks { .. } => FileSection::ChunkTable,
+            _ => FileSection::Sections,
+        }
+    }
+
+    /// Take the bytes of the part asked for by
+    /// [`ChunkTableReader::next_part`], or none if it was to be skipped.
+    pub(crate) fn advance(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        self.step = match self.step {
+            TableStep::SectionHeader => {
+                let section = SectionHeader::read_from(&mut bytes)
+                    .map_err(|e| Error::from_read(e, FileSection::Sections))?;
+                let start = self.offset;
+                self.offset += SectionHeader::LEN as u64;
+                self.sections.push(SectionInfo { kind: section.kind, offset: start, len: section.len });
+
+                match (section.kind, &self.compression_info) {
+                    (SectionType::CHUNK_TABLE, None) => {
+                        check_data_offset(&self.header, start)?;
+                        TableStep::ChunkCount(Some(section))
+                    },
+                    (SectionType::PIXEL_DATA, Some(compression_info)) => {
+                        section.check_len(pixel_data_len(compression_info))?;
+                        check_chunk_offsets(compression_info, self.offset)?;
+                        TableStep::Done
+                    },
+                    (SectionType::ENCODER_INFO, _) if section.len as usize <= MAX_ENCODER_INFO_LEN => {
+                        TableStep::EncoderInfo(section.len)
+                    },
+                    (SectionType::CONTENT_HASH, _) if section.len as usize == CONTENT_HASH_LEN => {
+                        TableStep::ContentHash
+                    },
+                    (SectionType::SHARED_DICTIONARY, _) => {
+                        section.check_len(SHARED_DICTIONARY_LEN)?;
+                        TableStep::SharedDictionary
+                    },
+                    _ => {
+                        section.check_skippable()?;
+                        trace!("skipping {} bytes of section {}", section.len, section.kind);
+                        TableStep::Skip(section.len)
+                    },
+                }
+            },
+            TableStep::ChunkCount(section) => {
+                let count = bytes.read_u32_le()
+                    .map_err(|e| Error::from_read(e, FileSection::ChunkTable))? as usize;
+                self.limits.check_chunk_count(count)?;
+                self.offset += 4;
+
+                TableStep::Chunks { count, section }
+            },
+            TableStep::Chunks { count, section } => {
+                let compression_info = match self.header.stored_chunk_offsets() {
+                    true => CompressionInfo::read_chunks_with_offsets(&mut bytes, count),
+                    false => CompressionInfo::read_chunks(&mut bytes, count),
+                }.map_err(|e| Error::from_read(e, FileSection::ChunkTable))?;
+                check_chunk_table(&self.header, &compression_info.chunks)?;
+                self.offset += (table_len(&self.header, &compression_info) - 4) as u64;
+                debug!(
+                    "chunk table lists {count} chunks, {} bytes decompressing to {}",
+                    pixel_data_len(&compression_info),
+                    compression_info.chunks.iter().map(|c| c.size_raw).sum::<usize>(),
+                );
+
+                let next = match section {
+                    Some(section) => {
+                        section.check_len(table_len(&self.header, &compression_info))?;
+                        TableStep::SectionHeader
+                    },
+                    None => TableStep::Done,
+                };
+                self.compression_info = Some(compression_info);
+
+                next
+            },
+            TableStep::EncoderInfo(len) => {
+                self.encoder_info = read_encoder_info(&mut bytes, len)
+                    .map_err(|e| Error::from_read(e, FileSection::Sections))?;
+                self.offset += len as u64;
+
+                TableStep::SectionHeader
+            },
+            TableStep::ContentHash => {
+                self.content_hash = read_content_hash_section(&mut bytes)
+                    .map_err(|e| Error::from_read(e, FileSection::Sections))?;
+                self.offset += CONTENT_HASH_LEN as u64;
+
+                TableStep::SectionHeader
+            },
+            TableStep::SharedDictionary => {
+                let id = bytes.read_u64_le()
+                    .map_err(|e| Error::from_read(e, FileSection::Sections))?;
+                check_shared_dictionary(id, self.given_dictionary)?;
+                self.shared_dictionary = Some(id);
+                self.offset += SHARED_DICTIONARY_LEN as u64;
+
+                TableStep::SectionHeader
+            },
+            TableStep::Skip(len) => {
+                self.offset += len as u64;
+                TableStep::SectionHeader
+            },
+            TableStep::Done => TableStep::Done,
+        };
+
+        Ok(())
+    }
+
+    /// The chunk table which was read, once [`ChunkTableReader::next_part`]
+    /// is [`TablePart::Done`].
+    ///
+    /// Chunk tables which don't store the offset of each chunk have them
+    /// worked out from the sizes.
+    pub(crate) fn finish(self) -> ChunkTable {
+        let mut compression_info = self.compression_info.expect("the chunk table is read before the pixel data");
+        if compression_info.offsets.is_empty() {
+            compression_info.offsets = compression_info.chunks.iter()
+                .scan(self.offset, |offset, chunk| {
+                    let start = *offset;
+                    *offset += chunk.size_compressed as u64;
+                    Some(start)
+                })
+                .collect();
+        }
+
+        ChunkTable {
+            compression_info,
+            data_offset: self.offset,
+            encoder_info: self.encoder_info,
+            content_hash: self.content_hash,
+            shared_dictionary: self.shared_dictionary,
+        }
+    }
+}
+
+/// Check the ID of the shared dictionary an image needs against the one
+/// which was given, if any.
+pub(crate) fn check_shared_dictionary(id: u64, given: Option<u64>) -> Result<(), Error> {
+    match given {
+        None => Err(Error::MissingDictionary { id }),
+        Some(actual) if actual != id => Err(Error::DictionaryMismatch { expected: id, actual }),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Read and throw away `len` bytes of input.
+pub(crate) fn skip<R: Read>(input: &mut R, len: u32) -> Result<(), io::Error> {
+    let mut buffer = [0u8; 4096];
+    let mut remaining = len as usize;
+    while remaining > 0 {
+        let step = remaining.min(buffer.len());
+        input.read_exact(&mut buffer[..step])?;
+        remaining -= step;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        compression::lossless::{compress_chunk, Backend, ChunkInfo},
+        header::CURRENT_VERSION,
+        ColorFormat,
+    };
+
+    #[test]
+    fn section_types() {
+        assert!(SectionType::CHUNK_TABLE.is_critical());
+        assert!(SectionType::PIXEL_DATA.is_critical());
+        assert!(!SectionType(*b"meTA").is_critical());
+
+        assert_eq!(alloc::format!("{}", SectionType(*b"te\0t")), "te\\x00t");
+        assert_eq!(alloc::format!("{:?}", SectionType::CHUNK_TABLE), "SectionType(\"CINF\")");
+    }
+
+    #[test]
+    fn section_header_round_trip() {
+        let header = SectionHeader::new(SectionType(*b"abcd"), 0x01020304).unwrap();
+
+        let mut bytes = alloc::vec::Vec::new();
+        assert_eq!(header.write_into(&mut bytes).unwrap(), SectionHeader::LEN);
+        assert_eq!(bytes, b"abcd\x04\x03\x02\x01");
+
+        let read = SectionHeader::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!((read.kind, read.len), (header.kind, header.len));
+    }
+
+    /// A lossless image compressed in chunks of 100 bytes, so it has
+    /// several, along with its filtered data.
+    fn chunked_image(version: u8) -> (Vec<u8>, Vec<u8>) {
+        let bitmap: Vec<u8> = (0..32 * 24).map(|i| (i * 29 % 251) as u8).collect();
+        let mut sqp = SquishyPicture::from_raw_lossless(32, 24, ColorFormat::Gray8, bitmap);
+        sqp.header.version = version;
+
+        let filtered = sqp.filtered_bitmap(&EncodeOptions::default()).into_owned();
+        let mut compression_info = CompressionInfo::default();
+        let mut data = Vec::new();
+        for part in filtered.chunks(100) {
+            let (count, compressed) = compress_chunk(part, Backend::from(&sqp.header));
+            compression_info.chunks.push(ChunkInfo { size_compressed: compressed.len(), size_raw: count });
+            data.extend_from_slice(&compressed);
+        }
+        compression_info.chunk_count = compression_info.chunks.len();
+
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+
+        (encoded, filtered)
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn chunk_index_seeks_to_chunks() {
+        for version in [2, 7, CURRENT_VERSION] {
+            let (encoded, filtered) = chunked_image(version);
+            assert!(SquishyPicture::decode_slice(&encoded).is_ok());
+
+            let index = ChunkIndex::read_from(encoded.as_slice()).unwrap();
+            assert_eq!(index.header().version, version);
+            assert_eq!(index.chunk_count(), 8);
+            assert_eq!(index.chunk_range(8), None);
+
+            // The chunks follow each other up to the footer
+            let ranges: Vec<_> = (0..8).map(|i| index.chunk_range(i).unwrap()).collect();
+            assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+            assert_eq!(ranges[7].end + index.header().footer_len() as u64, encoded.len() as u64);
+
+            for chunks in [0..1, 2..4, 7..8, 0..8] {
+                let data = index.read_chunks(Cursor::new(&encoded), chunks.clone()).unwrap();
+                let raw = index.raw_range(chunks.start).unwrap().start..index.raw_range(chunks.end - 1).unwrap().end;
+                assert_eq!(data, filtered[raw], "version {version}, chunks {chunks:?}");
+            }
+
+            // The index of a cut off file still has the chunks it doesn't
+            // hold any more, but they can't be read
+            let cut = &encoded[..encoded.len() - index.header().footer_len() - 1];
+            assert!(index.read_chunks(Cursor::new(cut), 0..7).is_ok());
+            assert!(matches!(
+                index.read_chunks(Cursor::new(cut), 6..8),
+                Err(Error::TruncatedFile { section: FileSection::ChunkData })
+            ));
+
+            for chunks in [3..3, 7..9] {
+                assert!(matches!(
+                    index.read_chunks(Cursor::new(&encoded), chunks.clone()),
+                    Err(Error::InvalidChunkRange { start, end, chunk_count: 8 }) if (start..end) == chunks
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_offsets_checked() {
+        let (encoded, _) = chunked_image(CURRENT_VERSION);
+        let header_len = Header::read_from(&mut encoded.as_slice()).unwrap().len();
+        let entry = |chunk: usize| header_len + SectionHeader::LEN + 4 + chunk * 16 + 8;
+        let offset_of = |chunk| u64::from_le_bytes(encoded[entry(chunk)..][..8].try_into().unwrap());
+
+        // Overlapping the chunk before, leaving a gap after it, and past the
+        // end of the file
+        for (chunk, offset) in [(1, offset_of(0)), (1, offset_of(1) + 1), (5, u64::MAX)] {
+            let mut file = encoded.clone();
+            file[entry(chunk)..][..8].copy_from_slice(&offset.to_le_bytes());
+
+            assert!(matches!(
+                ChunkIndex::read_from(file.as_slice()),
+                Err(Error::ChunkOffsetMismatch { chunk: c, expected, actual }) if (c, expected, actual) == (chunk, offset_of(chunk), offset)
+            ));
+            assert!(matches!(SquishyPicture::decode_slice(&file), Err(Error::ChunkOffsetMismatch { .. })));
+            assert!(matches!(SquishyPicture::decode(file.as_slice()), Err(Error::ChunkOffsetMismatch { .. })));
+        }
+    }
+}
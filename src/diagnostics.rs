@@ -0,0 +1,32 @@
+//! Internal diagnostics, emitted through the [log](https://docs.rs/log)
+//! crate with the `log` feature, and compiled away to nothing without it.
+//!
+//! Everything is logged under the `sqp` target, at the debug level for
+//! things which happen once per image and the trace level for those which
+//! happen once per chunk or channel. Nothing is logged as an error or
+//! warning, since anything that bad is returned as an [`Error`] instead.
+//!
+//! The arguments are only evaluated when the feature is enabled, so they
+//! shouldn't do anything the code around them relies on.
+//!
+//! [`Error`]: crate::picture::Error
+
+/// Log a message about the image as a whole, like [`log::debug`].
+///
+/// [`log::debug`]: https://docs.rs/log/latest/log/macro.debug.html
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        ::log::debug!(target: "sqp", $($arg)+)
+    };
+}
+
+/// Log a message about one chunk or channel, like [`log::trace`].
+///
+/// [`log::trace`]: https://docs.rs/log/latest/log/macro.trace.html
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        ::log::trace!(target: "sqp", $($arg)+)
+    };
+}
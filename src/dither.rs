@@ -0,0 +1,342 @@
+//! Error diffusion dithering, for reducing the colors of an image without
+//! banding.
+
+use alloc::vec;
+
+use crate::{header::ColorFormat, palette::Palette, picture::Error, SquishyPicture};
+
+/// The values each pixel is reduced to by [`floyd_steinberg`].
+#[derive(Debug, Clone, Copy)]
+pub enum DitherTarget<'a> {
+    /// The nearest color of a palette, such as one from
+    /// [`SquishyPicture::quantize`]. Only RGB and RGBA images can be
+    /// dithered to a palette, and alpha is dithered along with color.
+    Palette(&'a Palette),
+
+    /// This many bits in each color channel, from 1 to 8, as evenly spaced
+    /// 8 bit values. Alpha is left alone.
+    ///
+    /// At 4 bits the values are the 16 levels of [`ColorFormat::Gray4`],
+    /// so a gray image converts to it without any further rounding.
+    BitDepth(u8),
+}
+
+impl DitherTarget<'_> {
+    /// The nearest value of the target to `pixel`, which has a value for
+    /// each of the `channels` being dithered.
+    fn nearest(&self, pixel: &[u8], channels: usize) -> [u8; 4] {
+        match self {
+            Self::Palette(palette) => {
+                let mut color = [u8::MAX; 4];
+                color[..channels].copy_from_slice(pixel);
+                palette.colors()[palette.nearest(color) as usize]
+            },
+            Self::BitDepth(bits) => {
+                let top = (1u32 << bits) - 1;
+                let mut nearest = [0; 4];
+                for (nearest, value) in nearest.iter_mut().zip(pixel) {
+                    let level = (*value as u32 * top + 127) / 255;
+                    *nearest = ((level * 255 + top / 2) / top) as u8;
+                }
+                nearest
+            },
+        }
+    }
+}
+
+/// Reduce every pixel of the image to the target, spreading the difference
+/// onto the pixels which haven't been reduced yet with Floyd–Steinberg
+/// error diffusion.
+///
+/// Rows are scanned in a serpentine, alternating direction each row, which
+/// avoids the diagonal streaks of always scanning the same way. Each
+/// channel's error is found after the incoming error has been clamped to a
+/// valid value, so the error from a bright edge can't build up beyond what
+/// a pixel can show and bloom into the pixels after it.
+///
+/// Only integer arithmetic is used, so the same input always gives the same
+/// output, on any target and with or without the `parallel` feature.
+///
+/// Fails with [`Error::PaletteUnsupported`] when dithering an image other
+/// than RGB or RGBA to a palette, or [`Error::InvalidBitDepth`] for a bit
+/// depth other than 1 to 8.
+///
+/// # Example
+/// ```
+/// use sqp::{dither::{floyd_steinberg, DitherTarget}, ColorFormat, SquishyPicture};
+///
+/// let mut sqp = SquishyPicture::from_fn(64, 8, ColorFormat::Gray8, |x, _, pixel| pixel[0] = x as u8 * 4);
+/// floyd_steinberg(&mut sqp, DitherTarget::BitDepth(1)).unwrap();
+///
+/// assert!(sqp.as_raw().iter().all(|v| *v == 0 || *v == 255));
+/// ```
+pub fn floyd_steinberg(picture: &mut SquishyPicture, target: DitherTarget) -> Result<(), Error> {
+    let color_format = picture.header.color_format;
+    match target {
+        DitherTarget::Palette(_) if !matches!(color_format, ColorFormat::Rgb8 | ColorFormat::Rgba8) => {
+            return Err(Error::PaletteUnsupported(color_format))
+        },
+        DitherTarget::BitDepth(bits) if !(1..=8).contains(&bits) => return Err(Error::InvalidBitDepth(bits)),
+        _ => (),
+    }
+
+    if let Some(mut gray) = picture.unpacked() {
+        floyd_steinberg(&mut gray, target)?;
+        *picture = gray.convert_color_format(ColorFormat::Gray4);
+
+        return Ok(())
+    }
+
+    // Alpha is only dithered towards a palette
+    let pbc = color_format.pbc();
+    let channels = match (target, color_format.alpha_channel()) {
+        (DitherTarget::BitDepth(_), Some(alpha)) => alpha,
+        _ => pbc,
+    };
+
+    let width = picture.header.width as usize;
+    diffuse(&mut picture.bitmap, width, pbc, channels, |pixel| target.nearest(pixel, channels));
+
+    Ok(())
+}
+
+/// Floyd–Steinberg dither a bitmap with a serpentine scan, where the first
+/// `channels` of each pixel of `pbc` bytes are replaced with the result of
+/// `nearest`.
+fn diffuse<F: Fn(&[u8]) -> [u8; 4]>(bitmap: &mut [u8], width: usize, pbc: usize, channels: usize, nearest: F) {
+    let row_len = width * pbc;
+    if row_len == 0 {
+        return
+    }
+
+    // The error waiting to be added to each channel of the current and next
+    // rows, in sixteenths. Each row has a pixel of padding at either end,
+    // so error can be spread past the edges without checking for them.
+    let padded_len = (width + 2) * channels;
+    let mut current = vec![0i32; padded_len];
+    let mut next = vec![0i32; padded_len];
+
+    let mut pixel = [0u8; 4];
+    for (y, row) in bitmap.chunks_exact_mut(row_len).enumerate() {
+        let forwards = y % 2 == 0;
+        for i in 0..width {
+            let x = if forwards { i } else { width - 1 - i };
+
+            // Neighbours in the direction of the scan, in padded positions
+            let here = x + 1;
+            let (ahead, behind) = if forwards { (here + 1, here - 1) } else { (here - 1, here + 1) };
+
+            let bytes = &mut row[x * pbc..x * pbc + channels];
+            for (c, value) in bytes.iter().enumerate() {
+                let error = current[here * channels + c];
+                pixel[c] = (*value as i32 + round_sixteenths(error)).clamp(0, 255) as u8;
+            }
+
+            let reduced = nearest(&pixel[..channels]);
+            for (c, value) in bytes.iter_mut().enumerate() {
+                let error = pixel[c] as i32 - reduced[c] as i32;
+                *value = reduced[c];
+
+                current[ahead * channels + c] += error * 7;
+                next[behind * channels + c] += error * 3;
+                next[here * channels + c] += error * 5;
+                next[ahead * channels + c] += error;
+            }
+        }
+
+        core::mem::swap(&mut current, &mut next);
+        next.fill(0);
+    }
+}
+
+/// Divide an error in sixteenths, rounding halves away from zero so that
+/// positive and negative errors are treated alike.
+fn round_sixteenths(error: i32) -> i32 {
+    if error >= 0 { (error + 8) / 16 } else { (error - 8) / 16 }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use super::*;
+    use crate::palette::PaletteAlpha;
+
+    /// A 16×16 gray gradient, dark at the top left and light at the bottom
+    /// right.
+    fn gradient() -> SquishyPicture {
+        SquishyPicture::from_fn(16, 16, ColorFormat::Gray8, |x, y, pixel| pixel[0] = ((x + y) * 8) as u8)
+    }
+
+    /// Draw a 1 bit image as rows of `#` for white and `.` for black.
+    fn draw(picture: &SquishyPicture) -> Vec<String> {
+        picture.as_raw()
+            .chunks(picture.width() as usize)
+            .map(|row| row.iter().map(|v| if *v == 255 { '#' } else { '.' }).collect())
+            .collect()
+    }
+
+    /// Draw a 16 pixel wide image as rows of digits, one for each value.
+    fn levels(values: &[u8], digit: impl Fn(u8) -> u8) -> Vec<String> {
+        values.chunks(16)
+            .map(|row| row.iter().map(|v| char::from(b'0' + digit(*v))).collect())
+            .collect()
+    }
+
+    #[test]
+    fn gradient_one_bit() {
+        let mut sqp = gradient();
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(1)).unwrap();
+
+        assert!(sqp.as_raw().iter().all(|v| *v == 0 || *v == 255));
+        assert_eq!(draw(&sqp), [
+            "..........#..#.#",
+            ".......#.#..#.#.",
+            "...#.#....#.#.#.",
+            ".......#.#.#.#.#",
+            ".#..#.#..#..#.#.",
+            "...#....#.##.#.#",
+            "....#.#.#..#.###",
+            "#.#..#.#.##.##.#",
+            "...#..#.#.#.#.##",
+            ".#.#.#.#.#.###.#",
+            "..#..#.#.##.#.##",
+            ".#.#.#.#.#.#####",
+            ".#.#.#.##.##.##.",
+            "#..#.##.##.#####",
+            ".##.#.##.###.###",
+            "#..#.#.###.#####",
+        ]);
+    }
+
+    #[test]
+    fn gradient_two_bits() {
+        let mut sqp = gradient();
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(2)).unwrap();
+
+        assert!(sqp.as_raw().iter().all(|v| v % 85 == 0));
+        assert_eq!(levels(sqp.as_raw(), |v| v / 85), [
+            "0000101111111112",
+            "0010101011111121",
+            "0001011111112122",
+            "0110101111121212",
+            "0101111111112122",
+            "0101111111221222",
+            "1111111121212222",
+            "0101111112122222",
+            "1111112121221222",
+            "1111112122222222",
+            "1111212121222232",
+            "1112121222222222",
+            "1121212222223233",
+            "2112122222222323",
+            "1212221222323233",
+            "1122122222232323",
+        ]);
+    }
+
+    #[test]
+    fn gradient_palette() {
+        let mut sqp = SquishyPicture::from_fn(16, 16, ColorFormat::Rgb8, |x, y, pixel| {
+            pixel.copy_from_slice(&[(x * 16) as u8, (y * 16) as u8, 128]);
+        });
+        let palette = sqp.quantize(4, PaletteAlpha::Quantize).unwrap().palette().clone();
+        floyd_steinberg(&mut sqp, DitherTarget::Palette(&palette)).unwrap();
+
+        let indices: Vec<u8> = sqp.as_raw().chunks(3).map(|p| palette.nearest([p[0], p[1], p[2], 255])).collect();
+        assert_eq!(palette.colors(), [[56, 56, 128, 255], [56, 184, 128, 255], [184, 56, 128, 255], [184, 184, 128, 255]]);
+        assert_eq!(levels(&indices, |i| i), [
+            "0000000202222222",
+            "0000002020222222",
+            "0000000202222222",
+            "0000000202222222",
+            "0000002020222222",
+            "0000000202222222",
+            "0100010203222322",
+            "0101012121232323",
+            "1010101212323232",
+            "0111111313333333",
+            "1101012121232323",
+            "1111111313333333",
+            "1111111313333333",
+            "1111113131333333",
+            "1111111313333333",
+            "1111111313333333",
+        ]);
+    }
+
+    #[test]
+    fn keeps_the_mean() {
+        // Dithering moves error around rather than losing it, so each
+        // channel's mean hardly changes
+        let image = || SquishyPicture::from_fn(64, 64, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8, 200]);
+        });
+        let (mut sqp, original) = (image(), image());
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(2)).unwrap();
+
+        for c in 0..3 {
+            let mean = |p: &SquishyPicture| p.as_raw().chunks(4).map(|p| p[c] as f64).sum::<f64>() / 4096.0;
+            assert!((mean(&sqp) - mean(&original)).abs() < 1.0, "channel {c}");
+        }
+
+        // Alpha isn't touched
+        assert!(sqp.as_raw().chunks(4).all(|p| p[3] == 200));
+    }
+
+    #[test]
+    fn bright_edges_dont_bloom() {
+        // A white block on black, dithered to 1 bit, stays exact, as the
+        // white pixels have no error to spread
+        let image = || SquishyPicture::from_fn(32, 32, ColorFormat::Gray8, |x, y, pixel| {
+            pixel[0] = if (8..24).contains(&x) && (8..24).contains(&y) { 255 } else { 0 };
+        });
+        let (mut sqp, original) = (image(), image());
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(1)).unwrap();
+        assert_eq!(sqp.as_raw(), original.as_raw());
+
+        // Error from a row just below white is clamped away rather than
+        // piling up, so the dark area after it isn't speckled with white
+        let mut sqp = SquishyPicture::from_fn(32, 32, ColorFormat::Gray8, |_, y, pixel| {
+            pixel[0] = if y < 16 { 250 } else { 4 };
+        });
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(1)).unwrap();
+        let white_below = sqp.as_raw()[18 * 32..].iter().filter(|v| **v == 255).count();
+        assert_eq!(white_below, 0);
+    }
+
+    #[test]
+    fn gray4() {
+        let mut sqp = gradient().convert_color_format(ColorFormat::Gray4);
+        floyd_steinberg(&mut sqp, DitherTarget::BitDepth(1)).unwrap();
+        assert_eq!(sqp.color_format(), ColorFormat::Gray4);
+
+        let mut expected = gradient().convert_color_format(ColorFormat::Gray4).convert_color_format(ColorFormat::Gray8);
+        floyd_steinberg(&mut expected, DitherTarget::BitDepth(1)).unwrap();
+        assert_eq!(sqp.convert_color_format(ColorFormat::Gray8).as_raw(), expected.as_raw());
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut first = gradient().convert_color_format(ColorFormat::Rgb8);
+        let mut second = gradient().convert_color_format(ColorFormat::Rgb8);
+        floyd_steinberg(&mut first, DitherTarget::BitDepth(3)).unwrap();
+        floyd_steinberg(&mut second, DitherTarget::BitDepth(3)).unwrap();
+
+        assert_eq!(first.as_raw(), second.as_raw());
+    }
+
+    #[test]
+    fn invalid_targets() {
+        let mut sqp = gradient();
+        assert!(matches!(floyd_steinberg(&mut sqp, DitherTarget::BitDepth(0)), Err(Error::InvalidBitDepth(0))));
+        assert!(matches!(floyd_steinberg(&mut sqp, DitherTarget::BitDepth(9)), Err(Error::InvalidBitDepth(9))));
+
+        let rgb = gradient().convert_color_format(ColorFormat::Rgb8);
+        let palette = rgb.quantize(4, PaletteAlpha::Quantize).unwrap().palette().clone();
+        assert!(matches!(
+            floyd_steinberg(&mut sqp, DitherTarget::Palette(&palette)),
+            Err(Error::PaletteUnsupported(ColorFormat::Gray8)),
+        ));
+    }
+}
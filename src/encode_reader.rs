@@ -0,0 +1,270 @@
+//! An adapter which encodes a [`SquishyPicture`] as it is read from.
+
+use alloc::{borrow::Cow, vec::Vec};
+
+use crate::{
+    compression::lossless::{compress_chunk_with_primer, Backend, ChunkInfo, CompressionError, CompressionInfo},
+    container::{shared_dictionary, write_chunk_table, write_footer},
+    io::{self, Read, Write},
+    lossless::Dictionary,
+    options::EncodeOptions,
+    picture::Error,
+    threads, SquishyPicture,
+};
+
+/// Produces the encoded form of a [`SquishyPicture`] through [`Read`].
+///
+/// The header and chunk table are produced first, then each chunk is
+/// compressed when the reader reaches it, so only one compressed chunk is
+/// held in memory at a time. The output is identical to
+/// [`SquishyPicture::encode`].
+///
+/// Because the chunk table holds the compressed size of every chunk,
+/// creating the reader compresses the image once to find them. The chunks
+/// are compressed again as they are read, trading CPU time for memory.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use std::io::Read;
+/// use sqp::{encode_reader::SqpEncodeReader, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+///
+/// let mut encoded = Vec::new();
+/// SqpEncodeReader::new(&sqp).unwrap().read_to_end(&mut encoded).unwrap();
+///
+/// assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+/// # }
+/// ```
+pub struct SqpEncodeReader<'a> {
+    /// The filtered image data which gets compressed.
+    data: Cow<'a, [u8]>,
+
+    /// Size of each chunk, found when the reader was created.
+    chunks: Vec<ChunkInfo>,
+
+    /// The algorithm the chunks are compressed with.
+    backend: Backend,
+
+    /// The shared dictionary each chunk starts from, if any.
+    dictionary: Option<Dictionary>,
+
+    /// Bytes which are ready to be read.
+    buffer: Vec<u8>,
+    position: usize,
+
+    /// The next chunk to compress, and where it starts in `data`.
+    next_chunk: usize,
+    offset: usize,
+
+    /// The footer which follows the last chunk, until it is moved into the
+    /// buffer.
+    footer: Vec<u8>,
+}
+
+impl<'a> SqpEncodeReader<'a> {
+    /// Create a reader which encodes the given image.
+    pub fn new(picture: &'a SquishyPicture) -> Result<Self, Error> {
+        Self::with_options(picture, &EncodeOptions::default())
+    }
+
+    /// Create a reader which encodes the given image using the given
+    /// [`EncodeOptions`].
+    pub fn with_options(picture: &'a SquishyPicture, options: &EncodeOptions) -> Result<Self, Error> {
+        threads::run(&options.threads, || {
+            picture.check_encodable()?;
+
+            match picture.prepare(options)?.0 {
+                Some(prepared) => Ok(SqpEncodeReader::prepared(&prepared, options)?.into_owned()),
+                None => Self::prepared(picture, options),
+            }
+        })
+    }
+
+    /// Create a reader for an image which has already been through
+    /// [`SquishyPicture::prepare`] and had its size checked.
+    pub(crate) fn prepared(picture: &'a SquishyPicture, options: &EncodeOptions) -> Result<Self, Error> {
+        let data = picture.filtered_bitmap(options);
+        let backend = Backend::from(&picture.header);
+        let dictionary = shared_dictionary(&picture.header, options).cloned();
+
+        let mut compression_info = CompressionInfo::default();
+        let mut offset = 0;
+        loop {
+            let (count, compressed) = compress_chunk_with_primer(&data[offset..], backend, dictionary.as_ref().map(Dictionary::primer));
+            if count == 0 {
+                break;
+            }
+            offset += count;
+
+            compression_info.chunks.push(ChunkInfo {
+                size_compressed: compressed.len(),
+                size_raw: count,
+            });
+            compression_info.chunk_count += 1;
+        }
+
+        if compression_info.chunk_count == 0 {
+            return Err(CompressionError::NoChunks.into())
+        }
+
+        let mut buffer = Vec::new();
+        picture.header.write_into(&mut buffer)?;
+        write_chunk_table(&mut buffer, picture, &compression_info, options)?;
+
+        let image_len = buffer.len() + compression_info.chunks.iter().map(|c| c.size_compressed).sum::<usize>();
+        let mut footer = Vec::new();
+        write_footer(&mut footer, &picture.header, image_len as u64)?;
+
+        Ok(Self {
+            data,
+            chunks: compression_info.chunks,
+            backend,
+            dictionary,
+            buffer,
+            position: 0,
+            next_chunk: 0,
+            offset: 0,
+            footer,
+        })
+    }
+
+    /// The total size of the encoded image in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        let written: usize = self.chunks[..self.next_chunk].iter().map(|c| c.size_compressed).sum();
+        let unread = self.buffer.len() - self.position;
+        let pending: usize = self.chunks[self.next_chunk..].iter().map(|c| c.size_compressed).sum();
+
+        written + unread + pending + self.footer.len()
+    }
+
+    /// Take ownership of the data, so the reader no longer borrows the
+    /// picture.
+    fn into_owned(self) -> SqpEncodeReader<'static> {
+        SqpEncodeReader {
+            data: Cow::Owned(self.data.into_owned()),
+            chunks: self.chunks,
+            backend: self.backend,
+            dictionary: self.dictionary,
+            buffer: self.buffer,
+            position: self.position,
+            next_chunk: self.next_chunk,
+            offset: self.offset,
+            footer: self.footer,
+        }
+    }
+
+    /// Write the rest of the encoded image into `output`, returning the
+    /// number of bytes written.
+    ///
+    /// Each chunk is written as soon as it is compressed, without going
+    /// through an intermediate buffer.
+    pub fn write_into<W: Write>(mut self, output: &mut W) -> Result<usize, io::Error> {
+        let mut count = 0;
+
+        loop {
+            let pending = &self.buffer[self.position..];
+            output.write_all(pending)?;
+            count += pending.len();
+
+            if !self.fill_buffer() {
+                break;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Compress the next chunk into the buffer, or move the footer there
+    /// after the last one. Returns `false` once everything has been
+    /// produced.
+    fn fill_buffer(&mut self) -> bool {
+        let Some(chunk) = self.chunks.get(self.next_chunk) else {
+            if self.footer.is_empty() {
+                return false;
+            }
+
+            self.buffer = core::mem::take(&mut self.footer);
+            self.position = 0;
+            return true;
+        };
+
+        let primer = self.dictionary.as_ref().map(Dictionary::primer);
+        let (count, compressed) = compress_chunk_with_primer(&self.data[self.offset..], self.backend, primer);
+        debug_assert_eq!(count, chunk.size_raw);
+        debug_assert_eq!(compressed.len(), chunk.size_compressed);
+
+        self.offset += count;
+        self.next_chunk += 1;
+        self.buffer = compressed;
+        self.position = 0;
+
+        true
+    }
+}
+
+impl Read for SqpEncodeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.position == self.buffer.len() && !self.fill_buffer() {
+            return Ok(0);
+        }
+
+        let available = &self.buffer[self.position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ColorFormat, CompressionType};
+
+    #[test]
+    fn tiny_reads_match_encode() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            // Large enough to need more than one chunk without DCT
+            let mut state = 0x2545F491u32;
+            let bitmap = (0..400 * 400 * 4).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            }).collect();
+            let sqp = SquishyPicture::from_raw(400, 400, ColorFormat::Rgba8, compression_type, quality, bitmap);
+
+            let mut reader = SqpEncodeReader::new(&sqp).unwrap();
+            if compression_type != CompressionType::LossyDct {
+                assert!(reader.chunks.len() > 1);
+            }
+
+            let mut output = Vec::new();
+            let mut buf = [0u8; 7];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => output.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            assert_eq!(output, sqp.encode_to_vec().unwrap());
+
+            let mut streamed = Vec::new();
+            let count = sqp.encode_streaming(&mut streamed).unwrap();
+            assert_eq!(count, output.len());
+            assert_eq!(streamed, output);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! A reusable encoder for encoding many images in a row.
+
+use alloc::vec::Vec;
+
+use crate::{
+    compression::{
+        dct::dct_compress_into,
+        lossless::{compress_into, Backend, Dictionary},
+    },
+    container::{chunk_table_len, shared_dictionary},
+    io::Write,
+    options::EncodeOptions,
+    picture::{check_encoded_size, coefficient_stream_into, dct_parameters, filter_rows, EncodeStats, Error},
+    threads, CompressionType, SquishyPicture,
+};
+
+/// Encodes many images with the same [`EncodeOptions`], keeping its
+/// working buffers between images.
+///
+/// Encoding an image needs several buffers about the size of the image,
+/// plus the compression dictionary. [`SquishyPicture::encode`] allocates
+/// these fresh every time, while an `SqpEncoder` grows them as needed and
+/// keeps them for the next image, which saves a lot of time when encoding
+/// many small images. The buffers only ever grow, so they stay as large as
+/// the largest image encoded so far until [`SqpEncoder::shrink_to_fit`] is
+/// called.
+///
+/// The output is identical to [`SquishyPicture::encode_with_options`].
+/// The encoder is [`Send`], so each worker thread can own one.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use sqp::{encoder::SqpEncoder, options::EncodeOptions, ColorFormat, SquishyPicture};
+///
+/// let mut encoder = SqpEncoder::new(EncodeOptions::new());
+///
+/// for shade in [0, 128, 255] {
+///     let sqp = SquishyPicture::from_raw_lossless(16, 16, ColorFormat::Gray8, vec![shade; 16 * 16]);
+///
+///     let mut encoded = Vec::new();
+///     encoder.encode(&sqp, &mut encoded).unwrap();
+///
+///     assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+/// }
+/// # }
+/// ```
+pub struct SqpEncoder {
+    options: EncodeOptions,
+
+    /// The LZW dictionary, which is reset for every chunk.
+    dictionary: Dictionary,
+
+    /// The de-interleaved planes of a lossy image.
+    planes: Vec<u8>,
+
+    /// The quantized coefficients of each channel of a lossy image.
+    coefficients: Vec<Vec<i16>>,
+
+    /// The filtered image data which gets compressed.
+    filtered: Vec<u8>,
+
+    /// The compressed image data.
+    compressed: Vec<u8>,
+}
+
+impl SqpEncoder {
+    /// Create an encoder which uses the given options for every image.
+    ///
+    /// Nothing is allocated until the first image is encoded.
+    pub fn new(options: EncodeOptions) -> Self {
+        Self {
+            options,
+            dictionary: Dictionary::default(),
+            planes: Vec::new(),
+            coefficients: Vec::new(),
+            filtered: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+
+    /// The options used for every image.
+    pub fn options(&self) -> &EncodeOptions {
+        &self.options
+    }
+
+    /// Encode the image into anything that implements [`Write`].
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// With [`EncodeOptions::low_memory`] set, this is the same as
+    /// [`SquishyPicture::encode_with_options`] and no buffers are kept.
+    pub fn encode<O: Write>(&mut self, picture: &SquishyPicture, output: O) -> Result<usize, Error> {
+        Ok(self.encode_with_stats(picture, output)?.bytes_written)
+    }
+
+    /// Encode the image into anything that implements [`Write`], returning
+    /// [`EncodeStats`] describing how it was encoded.
+    ///
+    /// The same as [`SquishyPicture::encode_with_stats`], apart from
+    /// reusing the encoder's buffers.
+    pub fn encode_with_stats<O: Write>(&mut self, picture: &SquishyPicture, mut output: O) -> Result<EncodeStats, Error> {
+        if self.options.low_memory {
+            return picture.encode_with_stats(output, &self.options)
+        }
+
+        threads::run(&self.options.threads, || {
+            picture.check_encodable()?;
+
+            let (prepared, psnr) = picture.prepare(&self.options)?;
+            let picture = prepared.as_ref().unwrap_or(picture);
+            let header = &picture.header;
+
+            let filtered = match header.compression_type {
+                CompressionType::None | CompressionType::Predictive => &picture.bitmap,
+                CompressionType::Lossless | CompressionType::LosslessV2 | CompressionType::LosslessBwt => {
+                    filter_rows(header, &picture.bitmap, &self.options, &mut self.filtered);
+
+                    &self.filtered
+                },
+                CompressionType::LossyDct => {
+                    dct_compress_into(
+                        &picture.bitmap,
+                        dct_parameters(header),
+                        &mut self.planes,
+                        &mut self.coefficients,
+                    );
+                    coefficient_stream_into(header, &self.coefficients, &mut self.filtered);
+
+                    &self.filtered
+                },
+            };
+
+            self.compressed.clear();
+            let primer = shared_dictionary(header, &self.options).map(|d| d.primer());
+            let compression_info = compress_into(filtered, Backend::from(header), &mut self.dictionary, &mut self.compressed, primer)?;
+            check_encoded_size(
+                header.len() + chunk_table_len(header, &compression_info, &self.options) + self.compressed.len() + header.footer_len(),
+                &self.options,
+            )?;
+
+            let bytes_written = picture.write_compressed(&mut output, &self.compressed, &compression_info, &self.options)?;
+
+            Ok(picture.encode_stats(bytes_written, psnr, &self.options))
+        })
+    }
+
+    /// Free the buffers kept from earlier images.
+    ///
+    /// The next image encoded allocates them again.
+    pub fn shrink_to_fit(&mut self) {
+        self.dictionary = Dictionary::default();
+        self.planes = Vec::new();
+        self.coefficients = Vec::new();
+        self.filtered = Vec::new();
+        self.compressed = Vec::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::ColorFormat;
+
+    fn pattern(width: u32, height: u32, format: ColorFormat, seed: u32) -> Vec<u8> {
+        let len = (width * height) as usize * format.pbc();
+        (0..len as u32).map(|i| ((i * 7 + seed) ^ (i / 13)) as u8).collect()
+    }
+
+    #[test]
+    fn send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<SqpEncoder>();
+    }
+
+    #[test]
+    fn matches_stateless_encode() {
+        let mut encoder = SqpEncoder::new(EncodeOptions::new());
+
+        // Large then small, so the buffers hold leftovers from bigger images
+        let sizes = [(67, 45), (8, 8), (1, 1), (33, 9), (67, 45)];
+        for (seed, (width, height)) in sizes.into_iter().enumerate() {
+            for format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8] {
+                let bitmap = pattern(width, height, format, seed as u32);
+                let pictures = [
+                    SquishyPicture::from_raw(width, height, format, CompressionType::None, None, bitmap.clone()),
+                    SquishyPicture::from_raw_lossless(width, height, format, bitmap.clone()),
+                    SquishyPicture::from_raw_lossy(width, height, format, 70, bitmap),
+                ];
+
+                for sqp in pictures {
+                    let mut encoded = Vec::new();
+                    let written = encoder.encode(&sqp, &mut encoded).unwrap();
+
+                    assert_eq!(written, encoded.len());
+                    assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+                }
+            }
+        }
+
+        encoder.shrink_to_fit();
+        let sqp = SquishyPicture::from_raw_lossy(9, 9, ColorFormat::Rgb8, 50, pattern(9, 9, ColorFormat::Rgb8, 3));
+        let mut encoded = Vec::new();
+        encoder.encode(&sqp, &mut encoded).unwrap();
+        assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+    }
+
+    #[test]
+    fn matches_with_options() {
+        let options = EncodeOptions::new()
+            .auto_optimize_format(true)
+            .target_psnr(35.0);
+        let mut encoder = SqpEncoder::new(options.clone());
+
+        let gray = vec![90; 24 * 24 * 4];
+        let sqp = SquishyPicture::from_raw_lossy(24, 24, ColorFormat::Rgba8, 80, gray);
+
+        let mut encoded = Vec::new();
+        encoder.encode(&sqp, &mut encoded).unwrap();
+
+        let mut expected = Vec::new();
+        sqp.encode_with_options(&mut expected, &options).unwrap();
+        assert_eq!(encoded, expected);
+
+        let mut encoded = Vec::new();
+        assert_eq!(
+            encoder.encode_with_stats(&sqp, &mut encoded).unwrap(),
+            sqp.encode_with_stats(Vec::new(), &options).unwrap(),
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        let sqp = SquishyPicture::from_raw_lossless(32, 32, ColorFormat::Rgb8, pattern(32, 32, ColorFormat::Rgb8, 1));
+        let mut encoder = SqpEncoder::new(EncodeOptions::new().max_encoded_size(10));
+
+        let mut encoded = Vec::new();
+        assert!(matches!(
+            encoder.encode(&sqp, &mut encoded),
+            Err(Error::CannotMeetSizeTarget { .. })
+        ));
+        assert!(encoded.is_empty());
+    }
+}
@@ -1,16 +1,146 @@
 //! Structs and enums which are included in the header of SQP files.
 
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use std::io::{self, Read, Write};
+use core::ops::RangeInclusive;
+
+use alloc::{format, string::String};
+
+use crate::{
+    compression::dct::quantization_matrix,
+    container::FOOTER_LEN,
+    io::{self, Read, ReadExt, Write, WriteExt},
+    picture::{Error, FileSection},
+};
+
+/// The newest version of the format, which is written when encoding.
+///
+/// - `0`: The original format, which has no version number in the header.
+/// - `1`: Lossy images store the number of DCT coefficients before them.
+/// - `2`: A byte of flags follows the version, which can mark a transparent
+///   color for `Rgb8` images, or range coded coefficients for lossy ones.
+/// - `3`: The chunk table and compressed chunks are stored in typed
+///   sections after the header, see [`container`].
+/// - `4`: The header ends with the offset of the chunk table section, see
+///   [`Header::data_offset`].
+/// - `5`: Chunks compressed with LZW start with a byte saying whether their
+///   codes are Huffman coded.
+/// - `6`: Lossy images can be split into tiles whose coefficients are coded
+///   independently, see [`Header::tile_size`].
+/// - `7`: Lossless images can choose a filter for each row, see
+///   [`Header::row_filters`].
+/// - `8`: The chunk table gives the offset of each chunk from the start of
+///   the file, see [`ChunkIndex`].
+/// - `9`: Uncompressed images store their bitmap in chunks as it is, rather
+///   than compressing it with LZW, see [`CompressionType::None`].
+/// - `10`: The file ends with a footer after the pixel data, so a file which
+///   was cut short can be told from a complete one, see [`container`].
+///
+/// [`container`]: crate::container
+/// [`ChunkIndex`]: crate::container::ChunkIndex
+pub const CURRENT_VERSION: u8 = 10;
+
+/// Set in the compression type byte when a version number follows the
+/// color format. Files without it are version 0.
+///
+/// Decoders which predate versioning see an unknown compression type and
+/// reject the file, rather than misreading it.
+pub(crate) const VERSION_FLAG: u8 = 0x80;
+
+/// Set in the flags byte when the three bytes of a transparent color follow
+/// it.
+pub(crate) const TRANSPARENT_COLOR_FLAG: u8 = 0x01;
+
+/// Set in the flags byte when the coefficients of a lossy image are range
+/// coded.
+pub(crate) const RANGE_CODED_FLAG: u8 = 0x02;
+
+/// Set in the flags byte when a lossy image has its own quantization matrix
+/// for each channel, which follow the data offset at the end of the header.
+pub(crate) const QUANTIZATION_MATRICES_FLAG: u8 = 0x04;
+
+/// Set in the flags byte when the coefficients of a lossy image are split
+/// into tiles, whose size follows the data offset at the end of the header.
+pub(crate) const TILED_FLAG: u8 = 0x08;
+
+/// Set in the flags byte when each row of a lossless image starts with a
+/// byte choosing its filter.
+pub(crate) const ROW_FILTERS_FLAG: u8 = 0x10;
+
+/// The first format version which stores the offset of the chunk table.
+pub(crate) const FIRST_DATA_OFFSET_VERSION: u8 = 4;
+
+/// The first format version which can Huffman code the LZW codes.
+pub(crate) const FIRST_HUFFMAN_VERSION: u8 = 5;
+
+/// The first format version which can split lossy images into tiles.
+pub(crate) const FIRST_TILED_VERSION: u8 = 6;
+
+/// The first format version which can choose a filter for each row.
+pub(crate) const FIRST_ROW_FILTER_VERSION: u8 = 7;
+
+/// The first format version which stores the offset of each chunk.
+pub(crate) const FIRST_CHUNK_OFFSET_VERSION: u8 = 8;
+
+/// The first format version which stores uncompressed images as they are.
+pub(crate) const FIRST_STORED_NONE_VERSION: u8 = 9;
+
+/// The first format version which ends with a footer.
+pub(crate) const FIRST_FOOTER_VERSION: u8 = 10;
+
+/// The length of the longest possible header in bytes, which is tiled and
+/// has a quantization matrix for each of four channels.
+pub(crate) const MAX_HEADER_LEN: usize = 32 + TILE_SIZE_LEN + 4 * MATRIX_LEN;
+
+/// The length of the stored tile size in bytes, a little endian `u16`.
+const TILE_SIZE_LEN: usize = 2;
+
+/// The length of a stored quantization matrix in bytes, 64 little endian
+/// `u16`s in the same order as the coefficients of a block.
+const MATRIX_LEN: usize = 64 * 2;
+
+/// The quality levels a lossy image can have. Higher values give better
+/// results, and 100 is still lossy.
+///
+/// Images which are not lossy always have a quality of 0.
+pub const QUALITY_RANGE: RangeInclusive<u8> = 1..=100;
+
+/// The largest `near` a [`CompressionType::Predictive`] image can have, the
+/// most any sample may differ from the original. Past this every sample is
+/// predicted to within it anyway.
+pub const MAX_NEAR: u8 = 127;
+
+/// The width and height of the tiles large lossy images are split into
+/// unless told otherwise, see [`Header::tile_size`].
+pub const DEFAULT_TILE_SIZE: u16 = 256;
+
+/// Clamp a quality level into [`QUALITY_RANGE`].
+pub(crate) fn clamp_quality(quality: u32) -> u8 {
+    quality.clamp(*QUALITY_RANGE.start() as u32, *QUALITY_RANGE.end() as u32) as u8
+}
+
+/// Whether tiles can be this size, which must be a whole number of 8x8
+/// blocks.
+pub(crate) fn tile_size_is_valid(size: u16) -> bool {
+    size != 0 && size.is_multiple_of(8)
+}
 
-use crate::picture::Error;
+/// The tile size a lossy image gets unless told otherwise, so only images
+/// larger than one [`DEFAULT_TILE_SIZE`] tile are split up.
+pub(crate) fn default_tile_size(width: u32, height: u32) -> Option<u16> {
+    let limit = DEFAULT_TILE_SIZE as u32;
+    (width > limit || height > limit).then_some(DEFAULT_TILE_SIZE)
+}
 
 /// A DPF file header. This must be included at the beginning
 /// of a valid DPF file.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Header {
     /// Identifier. Must be set to "dangoimg".
     pub magic: [u8; 8],
 
+    /// Version of the format the file uses, see [`CURRENT_VERSION`].
+    pub version: u8,
+
     /// Width of the image in pixels.
     pub width: u32,
 
@@ -20,23 +150,101 @@ pub struct Header {
     /// Type of compression used on the data.
     pub compression_type: CompressionType,
 
-    /// Level of compression. Only applies in Lossy mode, otherwise this value
-    /// should be set to 0, and ignored.
+    /// Level of compression. Only applies in Lossy mode, where it must be in
+    /// [`QUALITY_RANGE`], and Predictive mode, where it is `near` and must be
+    /// at most [`MAX_NEAR`]. Otherwise this value must be set to 0.
     pub quality: u8,
 
     /// Format of color data in the image.
     pub color_format: ColorFormat,
+
+    /// A color which marks a pixel as fully transparent, for `Rgb8` images
+    /// which only need on or off transparency.
+    ///
+    /// This is only stored from version 2 on, and is left out of older
+    /// versions.
+    pub transparent_color: Option<[u8; 3]>,
+
+    /// Whether the DCT coefficients of a lossy image are range coded, see
+    /// [`SquishyPicture::set_range_coding`], rather than written as varints
+    /// and compressed with LZW.
+    ///
+    /// This is only stored from version 2 on, and is always `false` for
+    /// images which aren't lossy.
+    ///
+    /// [`SquishyPicture::set_range_coding`]: crate::SquishyPicture::set_range_coding
+    pub range_coded: bool,
+
+    /// The quantization matrix for each channel of a lossy image, in the
+    /// order they are interleaved, when they don't all use the matrix for
+    /// its quality. Entries past the last channel of the color format are
+    /// unused.
+    ///
+    /// These are only stored when they differ from the matrix for the
+    /// quality, from version 4 on, and are left out of older versions. They
+    /// are always [`None`] for images which aren't
+    /// [`CompressionType::LossyDct`].
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_matrices"))]
+    pub quantization_matrices: Option<[[u16; 64]; 4]>,
+
+    /// The width and height in pixels of the tiles the coefficients of a
+    /// lossy image are split into, which must be a multiple of 8. Each tile
+    /// is coded on its own, so tiles can be decoded in parallel, or only
+    /// those covering a region, see [`SquishyPicture::decode_region`].
+    ///
+    /// This is only stored from version 6 on, and is always [`None`] for
+    /// images which aren't [`CompressionType::LossyDct`].
+    ///
+    /// [`SquishyPicture::decode_region`]: crate::SquishyPicture::decode_region
+    pub tile_size: Option<u16>,
+
+    /// Whether the filter for each row of a lossless image is chosen by the
+    /// encoder and stored before the image data, see
+    /// [`EncodeOptions::filter_strategy`]. Otherwise every row but the
+    /// first of each third of the image is the difference from the row
+    /// above.
+    ///
+    /// This is only stored from version 7 on, and is always `false` for
+    /// images which aren't [`CompressionType::Lossless`],
+    /// [`CompressionType::LosslessV2`] or [`CompressionType::LosslessBwt`].
+    ///
+    /// [`EncodeOptions::filter_strategy`]: crate::options::EncodeOptions::filter_strategy
+    pub row_filters: bool,
+
+    /// Offset from the start of the file to the chunk table section, as
+    /// read from the file, so readers which can seek may go straight to it.
+    ///
+    /// This is only stored from version 4 on. Encoders always write the
+    /// chunk table directly after the header, so this is ignored when
+    /// writing, and the length of the header is stored instead.
+    pub data_offset: Option<u64>,
+}
+
+/// Serialize the quantization matrices of a [`Header`] as lists, which
+/// serde can't do for arrays this long.
+#[cfg(feature = "serde")]
+fn serialize_matrices<S: serde::Serializer>(matrices: &Option<[[u16; 64]; 4]>, serializer: S) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+
+    matrices.as_ref().map(|matrices| matrices.each_ref().map(|matrix| matrix.as_slice())).serialize(serializer)
 }
 
 impl Default for Header {
     fn default() -> Self {
         Self {
             magic: *b"dangoimg",
+            version: CURRENT_VERSION,
             width: 0,
             height: 0,
             compression_type: CompressionType::Lossless,
             quality: 0,
             color_format: ColorFormat::Rgba8,
+            transparent_color: None,
+            range_coded: false,
+            quantization_matrices: None,
+            tile_size: None,
+            row_filters: false,
+            data_offset: None,
         }
     }
 }
@@ -45,15 +253,20 @@ impl Header {
     /// Write the header into a byte stream implementing [`Write`].
     ///
     /// Returns the number of bytes written.
-    pub fn write_into<W: Write + WriteBytesExt>(&self, output: &mut W) -> Result<usize, io::Error> {
+    pub fn write_into<W: Write>(&self, output: &mut W) -> Result<usize, io::Error> {
         let mut count = 0;
         output.write_all(&self.magic)?;
-        output.write_u32::<LE>(self.width)?;
-        output.write_u32::<LE>(self.height)?;
+        output.write_u32_le(self.width)?;
+        output.write_u32_le(self.height)?;
         count += 16;
 
         // Write compression info
-        output.write_u8(self.compression_type.into())?;
+        let compression_type: u8 = self.compression_type.into();
+        if self.version == 0 {
+            output.write_u8(compression_type)?;
+        } else {
+            output.write_u8(compression_type | VERSION_FLAG)?;
+        }
         output.write_u8(self.quality)?;
         count += 2;
 
@@ -61,40 +274,385 @@ impl Header {
         output.write_u8(self.color_format as u8)?;
         count += 1;
 
+        if self.version != 0 {
+            output.write_u8(self.version)?;
+            count += 1;
+        }
+
+        let matrices = self.stored_matrices();
+        let tile_size = self.stored_tile_size();
+        if self.version >= 2 {
+            let mut flags = if self.range_coded { RANGE_CODED_FLAG } else { 0 };
+            if matrices.is_some() {
+                flags |= QUANTIZATION_MATRICES_FLAG;
+            }
+            if tile_size.is_some() {
+                flags |= TILED_FLAG;
+            }
+            if self.stored_row_filters() {
+                flags |= ROW_FILTERS_FLAG;
+            }
+
+            match self.transparent_color {
+                Some(color) => {
+                    output.write_u8(TRANSPARENT_COLOR_FLAG | flags)?;
+                    output.write_all(&color)?;
+                    count += 4;
+                },
+                None => {
+                    output.write_u8(flags)?;
+                    count += 1;
+                },
+            }
+        }
+
+        if self.version >= FIRST_DATA_OFFSET_VERSION {
+            output.write_u64_le(self.len() as u64)?;
+            count += 8;
+        }
+
+        if let Some(tile_size) = tile_size {
+            output.write_u16_le(tile_size)?;
+            count += TILE_SIZE_LEN;
+        }
+
+        for matrix in matrices.unwrap_or_default() {
+            for step in matrix {
+                output.write_u16_le(*step)?;
+            }
+            count += MATRIX_LEN;
+        }
+
         Ok(count)
     }
 
     /// Length of the header in bytes.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
-        19
+        let len = match (self.version, self.transparent_color) {
+            (0, _) => 19,
+            (1, _) => 20,
+            (2 | 3, None) => 21,
+            (2 | 3, Some(_)) => 24,
+            (_, None) => 29,
+            (_, Some(_)) => 32,
+        };
+
+        len + self.stored_tile_size().map_or(0, |_| TILE_SIZE_LEN)
+            + self.stored_matrices().map_or(0, |m| m.len() * MATRIX_LEN)
+    }
+
+    /// The size of the tiles which is written to the file, if the version
+    /// can store it and the image is lossy.
+    pub(crate) fn stored_tile_size(&self) -> Option<u16> {
+        if self.version < FIRST_TILED_VERSION || self.compression_type != CompressionType::LossyDct {
+            return None
+        }
+
+        self.tile_size
+    }
+
+    /// Whether the filter for each row is written to the file, if the
+    /// version can store it and the image is filtered by rows.
+    pub(crate) fn stored_row_filters(&self) -> bool {
+        self.row_filters && self.version >= FIRST_ROW_FILTER_VERSION && self.compression_type.is_row_filtered()
+    }
+
+    /// Whether the chunk table gives the offset of each chunk.
+    pub(crate) fn stored_chunk_offsets(&self) -> bool {
+        self.version >= FIRST_CHUNK_OFFSET_VERSION
+    }
+
+    /// The length of the footer which ends the file, or 0 if the version
+    /// has none.
+    pub(crate) fn footer_len(&self) -> usize {
+        match self.version >= FIRST_FOOTER_VERSION {
+            true => FOOTER_LEN,
+            false => 0,
+        }
+    }
+
+    /// The quantization matrices which are written to the file, one for
+    /// each channel, if the version can store them and any channel doesn't
+    /// use the matrix for the quality.
+    fn stored_matrices(&self) -> Option<&[[u16; 64]]> {
+        if self.version < FIRST_DATA_OFFSET_VERSION || self.compression_type != CompressionType::LossyDct {
+            return None
+        }
+
+        let matrices = &self.quantization_matrices.as_ref()?[..self.color_format.channels() as usize];
+        let default = quantization_matrix(self.quality as u32);
+        matrices.iter().any(|m| *m != default).then_some(matrices)
+    }
+
+    /// How many more bytes of the header there are after the given start
+    /// of one, which is known from the fields read so far.
+    ///
+    /// Once this returns 0, the whole header has been read.
+    pub(crate) fn remaining_len(start: &[u8]) -> usize {
+        match start.len() {
+            len if len < 19 => 19 - len,
+            19 if start[16] & VERSION_FLAG != 0 => 1,
+            20 if (2..=CURRENT_VERSION).contains(&start[19]) => 1,
+            21 if start[20] & TRANSPARENT_COLOR_FLAG != 0 => 3,
+            21 | 24 if start[19] >= FIRST_DATA_OFFSET_VERSION => 8,
+            len @ 29.. => {
+                let end = if start[20] & TRANSPARENT_COLOR_FLAG != 0 { 32 } else { 29 };
+                (end + Self::lossy_fields_len(start)).saturating_sub(len)
+            },
+            _ => 0,
+        }
+    }
+
+    /// The length of the fields of a lossy image which follow the data
+    /// offset, going by the flags.
+    fn lossy_fields_len(start: &[u8]) -> usize {
+        if start[16] & !VERSION_FLAG != CompressionType::LossyDct as u8 {
+            return 0
+        }
+
+        let mut len = 0;
+        if start[20] & TILED_FLAG != 0 && start[19] >= FIRST_TILED_VERSION {
+            len += TILE_SIZE_LEN;
+        }
+        if start[20] & QUANTIZATION_MATRICES_FLAG != 0 {
+            len += ColorFormat::try_from(start[18]).map_or(0, |f| f.channels() as usize * MATRIX_LEN);
+        }
+
+        len
+    }
+
+    /// Length of the raw bitmap described by this header in bytes, or
+    /// [`None`] if it would overflow.
+    pub(crate) fn bitmap_len(&self) -> Option<usize> {
+        self.color_format.bitmap_len(self.width, self.height)
+    }
+
+    /// Length of the data which is compressed for a lossless image after
+    /// filtering, the bitmap and the filter chosen for each row, or [`None`]
+    /// if it would overflow.
+    pub(crate) fn filtered_len(&self) -> Option<usize> {
+        let filters = if self.stored_row_filters() { self.height as usize } else { 0 };
+        self.bitmap_len()?.checked_add(filters)
+    }
+
+    /// Whether the quality level is valid for the compression type.
+    pub(crate) fn quality_is_valid(&self) -> bool {
+        match self.compression_type {
+            CompressionType::LossyDct => QUALITY_RANGE.contains(&self.quality),
+            CompressionType::Predictive => self.quality <= MAX_NEAR,
+            CompressionType::None
+            | CompressionType::Lossless
+            | CompressionType::LosslessV2
+            | CompressionType::LosslessBwt => self.quality == 0,
+        }
+    }
+
+    /// The most any sample of the decoded image can differ from the image
+    /// which was encoded, or [`None`] if there is no bound.
+    ///
+    /// This is 0 for lossless images and `near` for
+    /// [`CompressionType::Predictive`] ones, which the encoder guarantees.
+    /// [`CompressionType::LossyDct`] images have no bound.
+    pub fn max_error(&self) -> Option<u8> {
+        match self.compression_type {
+            CompressionType::LossyDct => None,
+            CompressionType::Predictive => Some(self.quality),
+            CompressionType::None
+            | CompressionType::Lossless
+            | CompressionType::LosslessV2
+            | CompressionType::LosslessBwt => Some(0),
+        }
+    }
+
+    /// Whether the decoded image may differ from the one which was encoded.
+    pub(crate) fn is_lossy(&self) -> bool {
+        self.max_error() != Some(0)
     }
 
     /// Create a header from a byte stream implementing [`Read`].
-    pub fn read_from<R: Read + ReadBytesExt>(input: &mut R) -> Result<Self, Error> {
+    ///
+    /// Fails if the header is incomplete, has unknown values, has a quality
+    /// level which doesn't fit the compression type, or describes an image
+    /// with no pixels or too many to address.
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self, Error> {
+        // Each part of the header says whether more follows it
+        let mut bytes = [0u8; MAX_HEADER_LEN];
+        let mut len = 0;
+        loop {
+            let remaining = Self::remaining_len(&bytes[..len]);
+            if remaining == 0 {
+                break
+            }
+
+            input.read_exact(&mut bytes[len..len + remaining])
+                .map_err(|e| Error::from_read(e, FileSection::Header))?;
+            len += remaining;
+        }
+
+        Self::parse(&bytes[..len])
+    }
+
+    /// Create a header from all of its bytes, as found by
+    /// [`Header::remaining_len`].
+    pub(crate) fn parse(mut bytes: &[u8]) -> Result<Self, Error> {
+
         let mut magic = [0u8; 8];
-        input.read_exact(&mut magic).unwrap();
+        bytes.read_exact(&mut magic)?;
 
         if magic != *b"dangoimg" {
             let bad_id = String::from_utf8_lossy(&magic).into_owned();
             return Err(Error::InvalidIdentifier(bad_id));
         }
 
-        Ok(Header {
+        let width = bytes.read_u32_le()?;
+        let height = bytes.read_u32_le()?;
+        let compression_type = bytes.read_u8()?;
+        let quality = bytes.read_u8()?;
+        let color_format = bytes.read_u8()?;
+
+        let version = if compression_type & VERSION_FLAG != 0 {
+            match bytes.read_u8()? {
+                v @ 1..=CURRENT_VERSION => v,
+                v => return Err(Error::UnsupportedVersion(v)),
+            }
+        } else {
+            0
+        };
+
+        let flags = match version {
+            2.. => bytes.read_u8()?,
+            _ => 0,
+        };
+
+        if flags & !(TRANSPARENT_COLOR_FLAG | RANGE_CODED_FLAG | QUANTIZATION_MATRICES_FLAG | TILED_FLAG | ROW_FILTERS_FLAG) != 0 {
+            return Err(Error::InvalidFlags(flags));
+        }
+
+        // The matrices and tile size follow the data offset, so older
+        // versions can't have them
+        if flags & QUANTIZATION_MATRICES_FLAG != 0 && version < FIRST_DATA_OFFSET_VERSION
+            || flags & TILED_FLAG != 0 && version < FIRST_TILED_VERSION
+            || flags & ROW_FILTERS_FLAG != 0 && version < FIRST_ROW_FILTER_VERSION
+        {
+            return Err(Error::InvalidFlags(flags));
+        }
+
+        let transparent_color = match flags & TRANSPARENT_COLOR_FLAG {
+            0 => None,
+            _ => {
+                let mut color = [0u8; 3];
+                bytes.read_exact(&mut color)?;
+                Some(color)
+            },
+        };
+
+        let data_offset = match version {
+            FIRST_DATA_OFFSET_VERSION.. => Some(bytes.read_u64_le()?),
+            _ => None,
+        };
+
+        let compression_type: CompressionType = {
+            let value = compression_type & !VERSION_FLAG;
+            value.try_into().map_err(|_| Error::InvalidCompressionType(value))?
+        };
+        let color_format: ColorFormat = color_format
+            .try_into()
+            .map_err(|_| Error::InvalidColorFormat(color_format))?;
+
+        // Only the coefficients of lossy images are quantized or tiled
+        if flags & (QUANTIZATION_MATRICES_FLAG | TILED_FLAG) != 0 && compression_type != CompressionType::LossyDct {
+            return Err(Error::InvalidFlags(flags));
+        }
+
+        // Only lossless images are filtered by rows
+        if flags & ROW_FILTERS_FLAG != 0 && !compression_type.is_row_filtered() {
+            return Err(Error::InvalidFlags(flags));
+        }
+
+        let tile_size = match flags & TILED_FLAG {
+            0 => None,
+            _ => match bytes.read_u16_le()? {
+                size if tile_size_is_valid(size) => Some(size),
+                size => return Err(Error::InvalidTileSize(size)),
+            },
+        };
+
+        let quantization_matrices = match flags & QUANTIZATION_MATRICES_FLAG {
+            0 => None,
+            _ => {
+                // Unused channels keep the matrix for the quality
+                let mut matrices = [quantization_matrix(quality as u32); 4];
+                for (channel, matrix) in matrices[..color_format.channels() as usize].iter_mut().enumerate() {
+                    for step in matrix.iter_mut() {
+                        *step = bytes.read_u16_le()?;
+                    }
+
+                    if matrix.contains(&0) {
+                        return Err(Error::InvalidQuantizationMatrix(channel as u8));
+                    }
+                }
+
+                Some(matrices)
+            },
+        };
+
+        let header = Header {
             magic,
-            width: input.read_u32::<LE>()?,
-            height: input.read_u32::<LE>()?,
+            version,
+            width,
+            height,
+            compression_type,
+            quality,
+            color_format,
+            transparent_color,
+            range_coded: flags & RANGE_CODED_FLAG != 0,
+            quantization_matrices,
+            tile_size,
+            row_filters: flags & ROW_FILTERS_FLAG != 0,
+            data_offset,
+        };
+
+        if header.transparent_color.is_some() && header.color_format != ColorFormat::Rgb8 {
+            return Err(Error::TransparencyUnsupported(header.color_format));
+        }
 
-            compression_type: input.read_u8()?.try_into().unwrap(),
-            quality: input.read_u8()?,
-            color_format: input.read_u8()?.try_into().unwrap(),
-        })
+        if header.is_lossy() && !header.color_format.supports_lossy() {
+            return Err(Error::LossyUnsupported(header.color_format));
+        }
+
+        // Only the coefficients of lossy images can be range coded
+        if header.range_coded && header.compression_type != CompressionType::LossyDct {
+            return Err(Error::InvalidFlags(flags));
+        }
+
+        if !header.quality_is_valid() {
+            return Err(Error::InvalidQuality(header.quality));
+        }
+
+        if !matches!(header.bitmap_len(), Some(1..)) {
+            return Err(Error::InvalidDimensions {
+                width: header.width,
+                height: header.height,
+            });
+        }
+
+        debug!(
+            "read a version {} header for a {}x{} {:?} image, compressed with {:?}",
+            header.version,
+            header.width,
+            header.height,
+            header.color_format,
+            header.compression_type,
+        );
+        Ok(header)
     }
 }
 
 /// The format of bytes in the image.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ColorFormat {
     /// RGBA, 8 bits per channel
     Rgba8 = 0,
@@ -107,6 +665,21 @@ pub enum ColorFormat {
 
     /// Grayscale, 8 bits per channel
     Gray8 = 3,
+
+    /// Grayscale, 4 bits per channel, with two pixels packed into each
+    /// byte.
+    ///
+    /// The left pixel of each pair is in the high nibble, and every row is
+    /// padded out to a whole byte, see [`ColorFormat::row_len`]. Where a
+    /// single pixel is handled on its own, such as in
+    /// [`SquishyPicture::from_fn`], it is an 8 bit gray value which is
+    /// rounded to the nearest of the 16 levels when packed.
+    ///
+    /// Lossy compression is not supported, as it would ruin the dithering
+    /// which images with so few levels usually rely on.
+    ///
+    /// [`SquishyPicture::from_fn`]: crate::SquishyPicture::from_fn
+    Gray4 = 4,
 }
 
 impl ColorFormat {
@@ -119,6 +692,7 @@ impl ColorFormat {
             Self::Rgb8 => 8,
             Self::GrayA8 => 8,
             Self::Gray8 => 8,
+            Self::Gray4 => 4,
         }
     }
 
@@ -131,6 +705,7 @@ impl ColorFormat {
             Self::Rgb8 => 24,
             Self::GrayA8 => 16,
             Self::Gray8 => 8,
+            Self::Gray4 => 4,
         }
     }
 
@@ -143,6 +718,7 @@ impl ColorFormat {
             Self::Rgb8 => 3,
             Self::GrayA8 => 2,
             Self::Gray8 => 1,
+            Self::Gray4 => 1,
         }
     }
 
@@ -155,14 +731,38 @@ impl ColorFormat {
             Self::Rgb8 => None,
             Self::GrayA8 => Some(1),
             Self::Gray8 => None,
+            Self::Gray4 => None,
         }
     }
 
-    /// Pixel Byte Count, The number of bytes per pixel.
+    /// Pixel Byte Count, The number of bytes per pixel, rounded up.
     ///
-    /// Convenience method over [`Self::bpp`]
+    /// Convenience method over [`Self::bpp`]. `Gray4` pixels only take
+    /// half a byte each, so use [`Self::row_len`] for the size of a row.
     pub fn pbc(&self) -> usize {
-        (self.bpp() / 8).into()
+        self.bpp().div_ceil(8).into()
+    }
+
+    /// The number of bytes in a row of `width` pixels, which is padded out
+    /// to a whole byte.
+    ///
+    /// Ex. a row of 3 `Gray4` pixels takes 2 bytes
+    pub fn row_len(&self, width: u32) -> usize {
+        (width as usize * self.bpp() as usize).div_ceil(8)
+    }
+
+    /// The number of bytes in a bitmap of `width` by `height` pixels, or
+    /// [`None`] if it would overflow.
+    pub(crate) fn bitmap_len(&self, width: u32, height: u32) -> Option<usize> {
+        (width as usize)
+            .checked_mul(self.bpp() as usize)?
+            .div_ceil(8)
+            .checked_mul(height as usize)
+    }
+
+    /// Whether images in this format can use lossy compression.
+    pub(crate) fn supports_lossy(&self) -> bool {
+        *self != Self::Gray4
     }
 }
 
@@ -175,6 +775,7 @@ impl TryFrom<u8> for ColorFormat {
             1 => Self::Rgb8,
             2 => Self::GrayA8,
             3 => Self::Gray8,
+            4 => Self::Gray4,
             v => return Err(format!("invalid color format {v}")),
         })
     }
@@ -183,8 +784,13 @@ impl TryFrom<u8> for ColorFormat {
 /// The type of compression used in the image
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum CompressionType {
     /// No compression at all, raw bitmap
+    ///
+    /// From format version 9 on, the chunks hold the bitmap as it is, one
+    /// after another, so it can be read straight out of the file. Older
+    /// versions still compress it with LZW.
     None = 0,
 
     /// Lossless compression
@@ -192,6 +798,26 @@ pub enum CompressionType {
 
     /// Lossy Discrete Cosine Transform compression
     LossyDct = 2,
+
+    /// Lossless compression with matches in a sliding window rather than
+    /// LZW, which is usually smaller but slower to encode
+    LosslessV2 = 3,
+
+    /// Lossless compression with the Burrows–Wheeler transform, like
+    /// bzip2, which is much slower but can be smaller still
+    LosslessBwt = 4,
+
+    /// Prediction from neighbouring pixels with Golomb–Rice codes, like
+    /// JPEG-LS. The quality level is `near`, the most any sample may differ
+    /// from the original, and at 0 it is lossless
+    Predictive = 5,
+}
+
+impl CompressionType {
+    /// Whether the rows of the bitmap are filtered before it is compressed.
+    pub(crate) fn is_row_filtered(self) -> bool {
+        matches!(self, Self::Lossless | Self::LosslessV2 | Self::LosslessBwt)
+    }
 }
 
 impl TryFrom<u8> for CompressionType {
@@ -202,6 +828,9 @@ impl TryFrom<u8> for CompressionType {
             0 => Self::None,
             1 => Self::Lossless,
             2 => Self::LossyDct,
+            3 => Self::LosslessV2,
+            4 => Self::LosslessBwt,
+            5 => Self::Predictive,
             v => return Err(format!("invalid compression type {v}"))
         })
     }
@@ -213,6 +842,9 @@ impl From<CompressionType> for u8 {
             CompressionType::None => 0,
             CompressionType::Lossless => 1,
             CompressionType::LossyDct => 2,
+            CompressionType::LosslessV2 => 3,
+            CompressionType::LosslessBwt => 4,
+            CompressionType::Predictive => 5,
         }
     }
 }
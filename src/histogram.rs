@@ -0,0 +1,234 @@
+//! Counts of how often each value appears in the channels of an image.
+
+use alloc::{vec, vec::Vec};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{
+    operations::{luma, unpack_gray4},
+    ColorFormat,
+};
+
+/// Number of rows counted together when computing a histogram in parallel.
+const BAND_HEIGHT: usize = 64;
+
+/// A histogram of each channel of an image, returned by
+/// [`SquishyPicture::histogram`].
+///
+/// [`SquishyPicture::histogram`]: crate::SquishyPicture::histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    color_format: ColorFormat,
+    channels: Vec<[u32; 256]>,
+}
+
+impl Histogram {
+    /// Count every value in each channel of a bitmap in the given format.
+    ///
+    /// [`ColorFormat::Gray4`] bitmaps are unpacked first, so they are
+    /// counted as [`ColorFormat::Gray8`].
+    pub(crate) fn from_bitmap(color_format: ColorFormat, width: u32, bitmap: &[u8]) -> Self {
+        if color_format == ColorFormat::Gray4 {
+            return Self::from_bitmap(ColorFormat::Gray8, width, &unpack_gray4(width, bitmap))
+        }
+
+        let band_len = (width as usize * color_format.pbc() * BAND_HEIGHT).max(1);
+
+        #[cfg(feature = "parallel")]
+        let channels = bitmap.par_chunks(band_len)
+            .map(|band| count(color_format, band))
+            .reduce(|| empty(color_format), merge);
+        #[cfg(not(feature = "parallel"))]
+        let channels = bitmap.chunks(band_len)
+            .map(|band| count(color_format, band))
+            .fold(empty(color_format), merge);
+
+        Self {
+            color_format,
+            channels,
+        }
+    }
+
+    /// Count the luminance of every pixel in a bitmap in the given format.
+    ///
+    /// The result is a single channel histogram in [`ColorFormat::Gray8`].
+    pub(crate) fn luminance(color_format: ColorFormat, width: u32, bitmap: &[u8]) -> Self {
+        match color_format {
+            ColorFormat::Gray8 | ColorFormat::Gray4 => Self::from_bitmap(color_format, width, bitmap),
+            ColorFormat::GrayA8 => {
+                let mut histogram = Self::from_bitmap(color_format, width, bitmap);
+                histogram.channels.truncate(1);
+                histogram.color_format = ColorFormat::Gray8;
+                histogram
+            }
+            ColorFormat::Rgba8 | ColorFormat::Rgb8 => {
+                let mut channel = [0; 256];
+                for pixel in bitmap.chunks_exact(color_format.pbc()) {
+                    channel[luma(pixel[0], pixel[1], pixel[2]) as usize] += 1;
+                }
+
+                Self {
+                    color_format: ColorFormat::Gray8,
+                    channels: vec![channel],
+                }
+            }
+        }
+    }
+
+    /// The color format of the image, which determines what each channel
+    /// is.
+    pub fn color_format(&self) -> ColorFormat {
+        self.color_format
+    }
+
+    /// The histograms of every channel, in the order they appear in a
+    /// pixel.
+    pub fn channels(&self) -> &[[u32; 256]] {
+        &self.channels
+    }
+
+    /// The histogram of a single channel, where each index is a value and
+    /// each element is the number of pixels with it.
+    ///
+    /// # Panics
+    /// If the channel is not less than [`ColorFormat::channels`].
+    pub fn channel(&self, channel: usize) -> &[u32; 256] {
+        &self.channels[channel]
+    }
+
+    /// The smallest value in a channel which at least `percent` percent of
+    /// the pixels are less than or equal to, or [`None`] if there are no
+    /// pixels.
+    ///
+    /// The percentage is clamped to `0.0..=100.0`, so `0.0` gives the
+    /// smallest value present and `100.0` the largest.
+    ///
+    /// # Panics
+    /// If the channel is not less than [`ColorFormat::channels`].
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(4, 1, ColorFormat::Gray8, vec![10, 20, 30, 40]);
+    /// let histogram = sqp.histogram();
+    ///
+    /// assert_eq!(histogram.percentile(0, 0.0), Some(10));
+    /// assert_eq!(histogram.percentile(0, 50.0), Some(20));
+    /// assert_eq!(histogram.percentile(0, 100.0), Some(40));
+    /// ```
+    pub fn percentile(&self, channel: usize, percent: f64) -> Option<u8> {
+        let channel = &self.channels[channel];
+        let total: u64 = channel.iter().map(|c| *c as u64).sum();
+
+        let target = libm::ceil(total as f64 * percent.clamp(0.0, 100.0) / 100.0) as u64;
+        let target = target.max(1);
+
+        let mut seen = 0;
+        channel.iter()
+            .position(|count| {
+                seen += *count as u64;
+                seen >= target
+            })
+            .map(|value| value as u8)
+    }
+}
+
+/// A histogram with nothing counted for each channel of the format.
+fn empty(color_format: ColorFormat) -> Vec<[u32; 256]> {
+    vec![[0; 256]; color_format.channels() as usize]
+}
+
+/// Count the values in each channel of some whole pixels.
+fn count(color_format: ColorFormat, bitmap: &[u8]) -> Vec<[u32; 256]> {
+    let mut channels = empty(color_format);
+    for pixel in bitmap.chunks_exact(color_format.pbc()) {
+        for (channel, value) in channels.iter_mut().zip(pixel) {
+            channel[*value as usize] += 1;
+        }
+    }
+
+    channels
+}
+
+/// Add the counts of one histogram to another.
+fn merge(mut a: Vec<[u32; 256]>, b: Vec<[u32; 256]>) -> Vec<[u32; 256]> {
+    for (a, b) in a.iter_mut().zip(&b) {
+        a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+    }
+
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_format() {
+        let cases: [(ColorFormat, &[u8]); 4] = [
+            (ColorFormat::Rgba8, &[1, 2, 3, 255, 1, 5, 3, 0, 9, 2, 3, 255]),
+            (ColorFormat::Rgb8, &[1, 2, 3, 1, 5, 3, 9, 2, 3]),
+            (ColorFormat::GrayA8, &[1, 255, 1, 0, 9, 255]),
+            (ColorFormat::Gray8, &[1, 1, 9]),
+        ];
+
+        for (color_format, bitmap) in cases {
+            let histogram = Histogram::from_bitmap(color_format, 3, bitmap);
+            assert_eq!(histogram.channels().len(), color_format.channels() as usize);
+
+            for (ch, channel) in histogram.channels().iter().enumerate() {
+                assert_eq!(channel.iter().sum::<u32>(), 3);
+
+                for value in bitmap.iter().skip(ch).step_by(color_format.pbc()) {
+                    let expected = bitmap.iter().skip(ch).step_by(color_format.pbc()).filter(|v| *v == value).count();
+                    assert_eq!(channel[*value as usize], expected as u32);
+                }
+            }
+
+            assert_eq!(histogram.channel(0)[1], 2);
+            assert_eq!(histogram.channel(0)[9], 1);
+        }
+    }
+
+    #[test]
+    fn bands_match_single_pass() {
+        let bitmap: Vec<u8> = (0..37u32 * 150 * 3).map(|i| (i * 31 % 253) as u8).collect();
+        let histogram = Histogram::from_bitmap(ColorFormat::Rgb8, 37, &bitmap);
+
+        assert_eq!(histogram.channels, count(ColorFormat::Rgb8, &bitmap));
+        for channel in histogram.channels() {
+            assert_eq!(channel.iter().sum::<u32>(), 37 * 150);
+        }
+    }
+
+    #[test]
+    fn luminance() {
+        let rgb = Histogram::luminance(ColorFormat::Rgb8, 2, &[255, 0, 0, 10, 10, 10]);
+        assert_eq!(rgb.color_format(), ColorFormat::Gray8);
+        assert_eq!(rgb.channels().len(), 1);
+        assert_eq!(rgb.channel(0)[luma(255, 0, 0) as usize], 1);
+        assert_eq!(rgb.channel(0)[10], 1);
+
+        let gray_alpha = Histogram::luminance(ColorFormat::GrayA8, 2, &[7, 0, 7, 255]);
+        assert_eq!(gray_alpha.channels().len(), 1);
+        assert_eq!(gray_alpha.channel(0)[7], 2);
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::from_bitmap(ColorFormat::Gray8, 10, &[0, 0, 0, 0, 0, 100, 100, 100, 200, 255]);
+
+        assert_eq!(histogram.percentile(0, -5.0), Some(0));
+        assert_eq!(histogram.percentile(0, 50.0), Some(0));
+        assert_eq!(histogram.percentile(0, 51.0), Some(100));
+        assert_eq!(histogram.percentile(0, 80.0), Some(100));
+        assert_eq!(histogram.percentile(0, 90.0), Some(200));
+        assert_eq!(histogram.percentile(0, 100.0), Some(255));
+        assert_eq!(histogram.percentile(0, 250.0), Some(255));
+
+        let empty = Histogram::from_bitmap(ColorFormat::Gray8, 0, &[]);
+        assert_eq!(empty.percentile(0, 50.0), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Conversions between [`SquishyPicture`] and the [`image`] crate's types.
+
+use image::{DynamicImage, ImageBuffer, Pixel};
+
+use crate::{picture::Error, ColorFormat, SquishyPicture};
+
+impl TryFrom<DynamicImage> for SquishyPicture {
+    type Error = Error;
+
+    /// Convert a [`DynamicImage`] into a lossless [`SquishyPicture`] with the
+    /// matching [`ColorFormat`].
+    ///
+    /// Only 8 bit per channel images are supported.
+    fn try_from(value: DynamicImage) -> Result<Self, Self::Error> {
+        let (width, height) = (value.width(), value.height());
+
+        let (color_format, bitmap) = match value {
+            DynamicImage::ImageRgba8(img) => (ColorFormat::Rgba8, img.into_raw()),
+            DynamicImage::ImageRgb8(img) => (ColorFormat::Rgb8, img.into_raw()),
+            DynamicImage::ImageLumaA8(img) => (ColorFormat::GrayA8, img.into_raw()),
+            DynamicImage::ImageLuma8(img) => (ColorFormat::Gray8, img.into_raw()),
+            img => return Err(Error::UnsupportedColorType(format!("{:?}", img.color()))),
+        };
+
+        Ok(SquishyPicture::from_raw_lossless(width, height, color_format, bitmap))
+    }
+}
+
+impl TryFrom<&SquishyPicture> for DynamicImage {
+    type Error = Error;
+
+    /// Convert a [`SquishyPicture`] into the [`DynamicImage`] variant
+    /// matching its [`ColorFormat`].
+    ///
+    /// Images with a transparent color become [`DynamicImage::ImageRgba8`],
+    /// with the color expanded into the alpha channel.
+    ///
+    /// Fails with [`Error::SizeMismatch`] if the bitmap is smaller than the
+    /// dimensions describe.
+    fn try_from(value: &SquishyPicture) -> Result<Self, Self::Error> {
+        if value.transparency().is_some() {
+            return Ok(DynamicImage::ImageRgba8(image_buffer(&value.expand_transparency())?))
+        }
+
+        Ok(match value.color_format() {
+            ColorFormat::Rgba8 => DynamicImage::ImageRgba8(image_buffer(value)?),
+            ColorFormat::Rgb8 => DynamicImage::ImageRgb8(image_buffer(value)?),
+            ColorFormat::GrayA8 => DynamicImage::ImageLumaA8(image_buffer(value)?),
+            ColorFormat::Gray8 | ColorFormat::Gray4 => DynamicImage::ImageLuma8(image_buffer(value)?),
+        })
+    }
+}
+
+/// Copy a picture into an [`ImageBuffer`] of a pixel type with the same
+/// channel count as its format, failing if the bitmap is too small.
+fn image_buffer<P: Pixel<Subpixel = u8>>(picture: &SquishyPicture) -> Result<ImageBuffer<P, Vec<u8>>, Error> {
+    picture.to_image_buffer().ok_or_else(|| Error::SizeMismatch {
+        expected: picture.header.bitmap_len().unwrap_or(usize::MAX),
+        actual: picture.bitmap.len(),
+    })
+}
+
+impl SquishyPicture {
+    /// Copy the image into an [`ImageBuffer`] of the given [`Pixel`] type.
+    ///
+    /// Returns [`None`] if the pixel type's channel count doesn't match the
+    /// image's [`ColorFormat`], or if the bitmap is smaller than the
+    /// dimensions describe. [`ColorFormat::Gray4`] images are unpacked into
+    /// one byte per pixel.
+    ///
+    /// # Example
+    /// ```
+    /// use image::Rgb;
+    /// use sqp::{SquishyPicture, ColorFormat};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(
+    ///     1,
+    ///     1,
+    ///     ColorFormat::Rgb8,
+    ///     vec![0x10, 0x20, 0x30]
+    /// );
+    ///
+    /// let buffer = sqp.to_image_buffer::<Rgb<u8>>().unwrap();
+    /// assert_eq!(buffer.get_pixel(0, 0), &Rgb([0x10, 0x20, 0x30]));
+    /// ```
+    pub fn to_image_buffer<P: Pixel<Subpixel = u8>>(&self) -> Option<ImageBuffer<P, Vec<u8>>> {
+        if P::CHANNEL_COUNT as u16 != self.color_format().channels() {
+            return None
+        }
+
+        let bitmap = match self.unpacked() {
+            Some(gray) => gray.bitmap,
+            None => self.as_raw().clone(),
+        };
+
+        ImageBuffer::from_raw(self.width(), self.height(), bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+
+    use super::*;
+
+    fn gradient(color_format: ColorFormat) -> Vec<u8> {
+        (0..5 * 3 * color_format.pbc()).map(|i| (i * 11 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn dynamic_image_round_trip() {
+        let images = [
+            DynamicImage::ImageRgba8(RgbaImage::from_raw(5, 3, gradient(ColorFormat::Rgba8)).unwrap()),
+            DynamicImage::ImageRgb8(RgbImage::from_raw(5, 3, gradient(ColorFormat::Rgb8)).unwrap()),
+            DynamicImage::ImageLumaA8(GrayAlphaImage::from_raw(5, 3, gradient(ColorFormat::GrayA8)).unwrap()),
+            DynamicImage::ImageLuma8(GrayImage::from_raw(5, 3, gradient(ColorFormat::Gray8)).unwrap()),
+        ];
+
+        for (image, color_format) in images.into_iter().zip([
+            ColorFormat::Rgba8,
+            ColorFormat::Rgb8,
+            ColorFormat::GrayA8,
+            ColorFormat::Gray8,
+        ]) {
+            let sqp = SquishyPicture::try_from(image.clone()).unwrap();
+            assert_eq!(sqp.color_format(), color_format);
+            assert_eq!((sqp.width(), sqp.height()), (5, 3));
+            assert_eq!(sqp.as_raw(), image.as_bytes());
+
+            let converted = DynamicImage::try_from(&sqp).unwrap();
+            assert_eq!(converted, image);
+        }
+    }
+
+    #[test]
+    fn transparent_color_becomes_alpha() {
+        let mut sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgb8, vec![1, 2, 3, 4, 5, 6]);
+        sqp.set_transparent_color(Some([4, 5, 6])).unwrap();
+
+        let expected = RgbaImage::from_raw(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 0]).unwrap();
+        assert_eq!(DynamicImage::try_from(&sqp).unwrap(), DynamicImage::ImageRgba8(expected));
+    }
+
+    #[test]
+    fn unsupported_dynamic_image() {
+        let image = DynamicImage::new_rgb16(2, 2);
+
+        assert!(matches!(
+            SquishyPicture::try_from(image),
+            Err(Error::UnsupportedColorType(_))
+        ));
+    }
+
+    #[test]
+    fn mismatched_image_buffer() {
+        let sqp = SquishyPicture::from_raw_lossless(1, 1, ColorFormat::Rgb8, vec![0; 3]);
+
+        assert!(sqp.to_image_buffer::<image::Rgba<u8>>().is_none());
+    }
+
+    #[test]
+    fn short_bitmap_to_dynamic_image() {
+        let mut sqp = SquishyPicture::from_raw_lossless(2, 2, ColorFormat::Rgb8, vec![0; 12]);
+        sqp.bitmap.truncate(9);
+
+        assert!(matches!(
+            DynamicImage::try_from(&sqp),
+            Err(Error::SizeMismatch { expected: 12, actual: 9 })
+        ));
+    }
+}
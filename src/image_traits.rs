@@ -0,0 +1,276 @@
+//! Implementations of the [`image`] crate's encoder and decoder traits, so
+//! SQP can be used anywhere those abstractions are accepted.
+//!
+//! # Example
+//! ```
+//! use image::{ImageDecoder, ImageEncoder, ExtendedColorType};
+//! use sqp::image_traits::{SqpDecoder, SqpEncoder};
+//!
+//! let bitmap = vec![0x80; 4 * 4 * 3];
+//!
+//! let mut encoded = Vec::new();
+//! SqpEncoder::new(&mut encoded)
+//!     .write_image(&bitmap, 4, 4, ExtendedColorType::Rgb8)
+//!     .unwrap();
+//!
+//! let decoder = SqpDecoder::new(encoded.as_slice()).unwrap();
+//! assert_eq!(decoder.dimensions(), (4, 4));
+//!
+//! let mut decoded = vec![0; decoder.total_bytes() as usize];
+//! decoder.read_image(&mut decoded).unwrap();
+//! assert_eq!(decoded, bitmap);
+//! ```
+
+use std::io::{Read, Write};
+
+use image::{
+    error::{
+        DecodingError, EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind,
+        UnsupportedError, UnsupportedErrorKind,
+    },
+    ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageResult,
+};
+
+use crate::{
+    header::Header,
+    options::DecodeOptions,
+    picture::{Error, SquishyPicture, Warnings},
+    ColorFormat, CompressionType,
+};
+
+/// The format hint attached to errors produced by this module.
+fn format_hint() -> ImageFormatHint {
+    ImageFormatHint::Name("SQP".to_string())
+}
+
+/// Convert an SQP [`Error`] into an [`ImageError`], keeping I/O errors as
+/// they are.
+fn decoding_error(err: Error) -> ImageError {
+    match err {
+        Error::IoError(err) => ImageError::IoError(err),
+        err => ImageError::Decoding(DecodingError::new(format_hint(), err)),
+    }
+}
+
+/// Convert an SQP [`Error`] into an [`ImageError`], keeping I/O errors as
+/// they are.
+fn encoding_error(err: Error) -> ImageError {
+    match err {
+        Error::IoError(err) => ImageError::IoError(err),
+        err => ImageError::Encoding(EncodingError::new(format_hint(), err)),
+    }
+}
+
+impl From<ColorFormat> for ColorType {
+    fn from(value: ColorFormat) -> Self {
+        match value {
+            ColorFormat::Rgba8 => ColorType::Rgba8,
+            ColorFormat::Rgb8 => ColorType::Rgb8,
+            ColorFormat::GrayA8 => ColorType::La8,
+            ColorFormat::Gray8 | ColorFormat::Gray4 => ColorType::L8,
+        }
+    }
+}
+
+impl TryFrom<ExtendedColorType> for ColorFormat {
+    type Error = ImageError;
+
+    fn try_from(value: ExtendedColorType) -> Result<Self, Self::Error> {
+        Ok(match value {
+            ExtendedColorType::Rgba8 => ColorFormat::Rgba8,
+            ExtendedColorType::Rgb8 => ColorFormat::Rgb8,
+            ExtendedColorType::La8 => ColorFormat::GrayA8,
+            ExtendedColorType::L8 => ColorFormat::Gray8,
+            color_type => return Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    format_hint(),
+                    UnsupportedErrorKind::Color(color_type),
+                )
+            )),
+        })
+    }
+}
+
+/// An [`ImageDecoder`] for SQP images.
+///
+/// The header is read when the decoder is created, and the rest of the
+/// image is decoded by [`ImageDecoder::read_image`].
+pub struct SqpDecoder<R: Read> {
+    header: Header,
+    input: R,
+}
+
+impl<R: Read> SqpDecoder<R> {
+    /// Create a new decoder, reading the header from the input.
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let header = Header::read_from(&mut input)?;
+
+        Ok(Self { header, input })
+    }
+}
+
+impl<R: Read> ImageDecoder for SqpDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.header.width, self.header.height)
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.header.color_format.into()
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        if buf.len() as u64 != self.total_bytes() {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch
+            )))
+        }
+
+        let picture = SquishyPicture::decode_with_header(self.header, self.input, &DecodeOptions::new(), &mut Warnings::ignored())
+            .map_err(decoding_error)?;
+
+        // Gray4 images are handed out as L8
+        let unpacked = picture.unpacked();
+        let bitmap = unpacked.as_ref().unwrap_or(&picture).as_raw();
+        if bitmap.len() < buf.len() {
+            return Err(decoding_error(Error::SizeMismatch {
+                expected: buf.len(),
+                actual: bitmap.len(),
+            }))
+        }
+
+        buf.copy_from_slice(&bitmap[..buf.len()]);
+
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+/// An [`ImageEncoder`] for SQP images.
+///
+/// Images are compressed losslessly unless the encoder is created with
+/// [`SqpEncoder::new_lossy`].
+pub struct SqpEncoder<W: Write> {
+    output: W,
+    compression_type: CompressionType,
+    quality: Option<u8>,
+}
+
+impl<W: Write> SqpEncoder<W> {
+    /// Create a new encoder which compresses images losslessly.
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            compression_type: CompressionType::Lossless,
+            quality: None,
+        }
+    }
+
+    /// Create a new encoder which compresses images lossily with the given
+    /// quality, from 1-100.
+    pub fn new_lossy(output: W, quality: u8) -> Self {
+        Self {
+            output,
+            compression_type: CompressionType::LossyDct,
+            quality: Some(quality),
+        }
+    }
+}
+
+impl<W: Write> ImageEncoder for SqpEncoder<W> {
+    fn write_image(
+        self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        let color_format = ColorFormat::try_from(color_type)?;
+
+        let expected = width as u64 * height as u64 * color_format.pbc() as u64;
+        if buf.len() as u64 != expected {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch
+            )))
+        }
+
+        let picture = SquishyPicture::from_raw(
+            width,
+            height,
+            color_format,
+            self.compression_type,
+            self.quality,
+            buf.to_vec(),
+        );
+
+        picture.encode(self.output).map_err(encoding_error)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_with<E: ImageEncoder>(encoder: E, bitmap: &[u8], color_type: ExtendedColorType) -> ImageResult<()> {
+        encoder.write_image(bitmap, 13, 11, color_type)
+    }
+
+    fn gradient(color_format: ColorFormat) -> Vec<u8> {
+        (0..13 * 11 * color_format.pbc()).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip_trait_objects() {
+        for (color_type, color_format) in [
+            (ExtendedColorType::Rgba8, ColorFormat::Rgba8),
+            (ExtendedColorType::Rgb8, ColorFormat::Rgb8),
+            (ExtendedColorType::La8, ColorFormat::GrayA8),
+            (ExtendedColorType::L8, ColorFormat::Gray8),
+        ] {
+            let bitmap = gradient(color_format);
+
+            let mut encoded = Vec::new();
+            write_with(SqpEncoder::new(&mut encoded), &bitmap, color_type).unwrap();
+
+            let decoder: Box<dyn ImageDecoder> = Box::new(SqpDecoder::new(encoded.as_slice()).unwrap());
+            assert_eq!(decoder.dimensions(), (13, 11));
+            assert_eq!(ExtendedColorType::from(decoder.color_type()), color_type);
+
+            let mut decoded = vec![0; decoder.total_bytes() as usize];
+            decoder.read_image_boxed(&mut decoded).unwrap();
+            assert_eq!(decoded, bitmap);
+        }
+    }
+
+    #[test]
+    fn lossy_encoder() {
+        let bitmap = gradient(ColorFormat::Rgb8);
+
+        let mut encoded = Vec::new();
+        SqpEncoder::new_lossy(&mut encoded, 80)
+            .write_image(&bitmap, 13, 11, ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let picture = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert_eq!(picture.width(), 13);
+        assert_eq!(picture.color_format(), ColorFormat::Rgb8);
+    }
+
+    #[test]
+    fn unsupported_color_type() {
+        let mut encoded = Vec::new();
+        let result = SqpEncoder::new(&mut encoded)
+            .write_image(&[0; 4 * 4 * 6], 4, 4, ExtendedColorType::Rgb16);
+
+        assert!(matches!(
+            result,
+            Err(ImageError::Unsupported(err))
+                if err.kind() == UnsupportedErrorKind::Color(ExtendedColorType::Rgb16)
+        ));
+        assert!(encoded.is_empty());
+    }
+}
@@ -0,0 +1,435 @@
+//! Checks that a picture, or an encoded file, is consistent with itself.
+//!
+//! [`SquishyPicture::check_integrity`] checks a picture in memory, and
+//! [`verify`] or [`verify_file`] check an encoded image by decoding it.
+//! Each returns every problem it finds, rather than stopping at the first.
+
+#[cfg(feature = "std")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use alloc::vec::Vec;
+use thiserror::Error;
+
+use crate::{
+    header::{tile_size_is_valid, ColorFormat, CompressionType, Header},
+    io::Read,
+    options::{DecodeOptions, Limits},
+    picture::{DecodeWarning, Error},
+    SquishyPicture,
+};
+
+/// A problem found by [`SquishyPicture::check_integrity`], [`verify`] or
+/// [`verify_file`].
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum IntegrityIssue {
+    /// The image has no pixels, or too many to address.
+    #[error("invalid dimensions {width}x{height}")]
+    InvalidDimensions {
+        width: u32,
+        height: u32,
+    },
+
+    /// The image is larger than the limits allow.
+    #[error("{name} of {requested} exceeds the limit of {limit}")]
+    LimitExceeded {
+        name: &'static str,
+        requested: u64,
+        limit: u64,
+    },
+
+    /// The bitmap is not the size the width, height and color format
+    /// describe.
+    #[error("bitmap is {actual} bytes, expected {expected}")]
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// The quality doesn't fit the compression type, see
+    /// [`Header::quality`].
+    #[error("quality {quality} is invalid for {compression_type:?}")]
+    InvalidQuality {
+        compression_type: CompressionType,
+        quality: u8,
+    },
+
+    /// The image is lossy, which the color format doesn't support.
+    #[error("lossy compression is not supported for {0:?}")]
+    LossyUnsupported(ColorFormat),
+
+    /// The image has a transparent color, which only `Rgb8` supports.
+    #[error("a transparent color is not supported for {0:?}")]
+    TransparencyUnsupported(ColorFormat),
+
+    /// The image is range coded, which the compression type doesn't support.
+    #[error("range coding is not supported for {0:?}")]
+    RangeCodingUnsupported(CompressionType),
+
+    /// The image has quantization matrices, which only
+    /// [`CompressionType::LossyDct`] uses.
+    #[error("quantization matrices are not supported for {0:?}")]
+    MatricesUnsupported(CompressionType),
+
+    /// The quantization matrix of a channel has a step of 0.
+    #[error("quantization matrix of channel {0} has a step of 0")]
+    InvalidQuantizationMatrix(u8),
+
+    /// The image is tiled, which only [`CompressionType::LossyDct`]
+    /// supports.
+    #[error("tiling is not supported for {0:?}")]
+    TilingUnsupported(CompressionType),
+
+    /// The tile size is not a whole number of 8x8 blocks.
+    #[error("invalid tile size {0}")]
+    InvalidTileSize(u16),
+
+    /// The image has row filters, which the compression type doesn't use.
+    #[error("row filters are not supported for {0:?}")]
+    RowFiltersUnsupported(CompressionType),
+
+    /// Decoding the file carried on past a problem, like a corrupted chunk
+    /// or a content hash which doesn't match.
+    #[error(transparent)]
+    Warning(DecodeWarning),
+
+    /// The file could not be decoded at all, so nothing after the problem
+    /// was checked.
+    #[error(transparent)]
+    Decode(Error),
+}
+
+/// Find every problem with a picture, against the given limits.
+pub(crate) fn picture_issues(picture: &SquishyPicture, limits: &Limits) -> Vec<IntegrityIssue> {
+    let mut issues = header_issues(&picture.header, limits);
+
+    if let Some(expected) = picture.header.bitmap_len() {
+        if picture.bitmap.len() != expected {
+            issues.push(IntegrityIssue::SizeMismatch { expected, actual: picture.bitmap.len() });
+        }
+    }
+
+    issues
+}
+
+/// Find every problem with a header, like [`Header::read_from`] does for
+/// the first.
+fn header_issues(header: &Header, limits: &Limits) -> Vec<IntegrityIssue> {
+    let mut issues = Vec::new();
+    let compression_type = header.compression_type;
+
+    match header.bitmap_len() {
+        Some(1..) => (),
+        _ => issues.push(IntegrityIssue::InvalidDimensions { width: header.width, height: header.height }),
+    }
+
+    let bitmap_len = header.bitmap_len().map_or(u64::MAX, |len| len as u64);
+    for (name, requested, limit) in [
+        ("image width", header.width as u64, limits.max_image_width as u64),
+        ("image height", header.height as u64, limits.max_image_height as u64),
+        ("allocation size", bitmap_len, limits.max_alloc_bytes as u64),
+    ] {
+        if requested > limit {
+            issues.push(IntegrityIssue::LimitExceeded { name, requested, limit });
+        }
+    }
+
+    if !header.quality_is_valid() {
+        issues.push(IntegrityIssue::InvalidQuality { compression_type, quality: header.quality });
+    }
+
+    if header.is_lossy() && !header.color_format.supports_lossy() {
+        issues.push(IntegrityIssue::LossyUnsupported(header.color_format));
+    }
+
+    if header.transparent_color.is_some() && header.color_format != ColorFormat::Rgb8 {
+        issues.push(IntegrityIssue::TransparencyUnsupported(header.color_format));
+    }
+
+    // Only the coefficients of lossy images are range coded, quantized or
+    // tiled, and only lossless images are filtered by rows
+    let lossy = compression_type == CompressionType::LossyDct;
+    if header.range_coded && !lossy {
+        issues.push(IntegrityIssue::RangeCodingUnsupported(compression_type));
+    }
+
+    if let Some(matrices) = &header.quantization_matrices {
+        if !lossy {
+            issues.push(IntegrityIssue::MatricesUnsupported(compression_type));
+        }
+
+        let channels = header.color_format.channels() as usize;
+        for (channel, matrix) in matrices[..channels].iter().enumerate() {
+            if matrix.contains(&0) {
+                issues.push(IntegrityIssue::InvalidQuantizationMatrix(channel as u8));
+            }
+        }
+    }
+
+    if let Some(tile_size) = header.tile_size {
+        if !lossy {
+            issues.push(IntegrityIssue::TilingUnsupported(compression_type));
+        }
+        if !tile_size_is_valid(tile_size) {
+            issues.push(IntegrityIssue::InvalidTileSize(tile_size));
+        }
+    }
+
+    if header.row_filters && !compression_type.is_row_filtered() {
+        issues.push(IntegrityIssue::RowFiltersUnsupported(compression_type));
+    }
+
+    issues
+}
+
+/// Check an encoded image from anything that implements [`Read`],
+/// returning every problem found.
+///
+/// The image is decoded with the default [`DecodeOptions`], reading the
+/// input to its end. The chunk table must agree with the header and the
+/// data, and with the `content-hash` feature, the decoded bitmap must match
+/// any hash stored with it. Problems which decoding carries on past are
+/// each an [`IntegrityIssue::Warning`], and the decoded picture is checked
+/// with [`SquishyPicture::check_integrity`]. A file which can't be decoded
+/// gives only the [`IntegrityIssue::Decode`] which stopped it.
+///
+/// # Example
+/// ```
+/// use sqp::{integrity::{verify, IntegrityIssue}, picture::DecodeWarning, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+/// let mut encoded = sqp.encode_to_vec().unwrap();
+/// assert!(verify(encoded.as_slice()).is_ok());
+///
+/// encoded.extend_from_slice(b"extra");
+/// let issues = verify(encoded.as_slice()).unwrap_err();
+/// assert!(matches!(issues[..], [IntegrityIssue::Warning(DecodeWarning::TrailingBytes { len: 5, .. })]));
+/// ```
+pub fn verify<I: Read>(input: I) -> Result<(), Vec<IntegrityIssue>> {
+    verify_with_options(input, &DecodeOptions::new())
+}
+
+/// Check an encoded image in a file like [`verify`].
+///
+/// The file's length is known, so a chunk table which claims more data
+/// than the file has is found before any of it is decompressed.
+#[cfg(feature = "std")]
+pub fn verify_file<P: AsRef<Path>>(path: P) -> Result<(), Vec<IntegrityIssue>> {
+    let file = File::open(path).map_err(|e| vec![IntegrityIssue::Decode(e.into())])?;
+    let mut options = DecodeOptions::new();
+    options.input_len = file.metadata().ok().map(|m| m.len());
+
+    verify_with_options(BufReader::new(file), &options)
+}
+
+fn verify_with_options<I: Read>(input: I, options: &DecodeOptions) -> Result<(), Vec<IntegrityIssue>> {
+    let issues = match SquishyPicture::decode_with_report(input, options) {
+        Ok((picture, warnings)) => {
+            let mut issues: Vec<_> = warnings.into_iter().map(IntegrityIssue::Warning).collect();
+            issues.extend(picture_issues(&picture, &options.limits));
+            issues
+        },
+        Err(e) => alloc::vec![IntegrityIssue::Decode(e)],
+    };
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{
+        compression::lossless::{compress, Backend},
+        container::{write_chunk_table, write_footer},
+        options::EncodeOptions,
+    };
+
+    fn issues(picture: &SquishyPicture) -> Vec<IntegrityIssue> {
+        picture.check_integrity().err().unwrap_or_default()
+    }
+
+    fn gray(compression_type: CompressionType, quality: Option<u8>) -> SquishyPicture {
+        SquishyPicture::from_raw(16, 8, ColorFormat::Gray8, compression_type, quality, vec![0x40; 16 * 8])
+    }
+
+    #[test]
+    fn consistent_pictures() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LossyDct, Some(70)),
+            (CompressionType::Predictive, Some(2)),
+        ] {
+            assert!(gray(compression_type, quality).check_integrity().is_ok());
+        }
+
+        let mut tiled = gray(CompressionType::LossyDct, Some(70));
+        tiled.set_range_coding(true).unwrap();
+        tiled.set_tile_size(Some(8)).unwrap();
+        assert!(tiled.check_integrity().is_ok());
+    }
+
+    #[test]
+    fn bitmap_length() {
+        let mut picture = gray(CompressionType::Lossless, None);
+        picture.bitmap.pop();
+
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::SizeMismatch { expected: 128, actual: 127 }]));
+    }
+
+    #[test]
+    fn dimensions() {
+        let picture = SquishyPicture::from_raw(0, 8, ColorFormat::Gray8, CompressionType::Lossless, None, vec![]);
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::InvalidDimensions { width: 0, height: 8 }]));
+
+        let limits = Limits { max_image_height: 4, max_alloc_bytes: 100, ..Limits::default() };
+        let found = gray(CompressionType::Lossless, None).check_integrity_with_limits(limits).unwrap_err();
+        assert!(matches!(found[..], [
+            IntegrityIssue::LimitExceeded { name: "image height", requested: 8, limit: 4 },
+            IntegrityIssue::LimitExceeded { name: "allocation size", requested: 128, limit: 100 },
+        ]));
+    }
+
+    #[test]
+    fn quality() {
+        let mut picture = gray(CompressionType::Lossless, None);
+        picture.header.quality = 50;
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::InvalidQuality { compression_type: CompressionType::Lossless, quality: 50 }]));
+
+        let mut picture = gray(CompressionType::LossyDct, Some(70));
+        picture.header.quality = 0;
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::InvalidQuality { compression_type: CompressionType::LossyDct, quality: 0 }]));
+    }
+
+    #[test]
+    fn color_format() {
+        let mut picture = SquishyPicture::from_raw(16, 8, ColorFormat::Gray4, CompressionType::Lossless, None, vec![0; 64]);
+        picture.header.compression_type = CompressionType::LossyDct;
+        picture.header.quality = 70;
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::LossyUnsupported(ColorFormat::Gray4)]));
+
+        let mut picture = gray(CompressionType::Lossless, None);
+        picture.header.transparent_color = Some([1, 2, 3]);
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::TransparencyUnsupported(ColorFormat::Gray8)]));
+    }
+
+    #[test]
+    fn lossy_fields() {
+        // Every field which only lossy or lossless images use, set for the
+        // other, is reported at once
+        let mut picture = gray(CompressionType::Lossless, None);
+        picture.header.range_coded = true;
+        picture.header.quantization_matrices = Some([[1; 64]; 4]);
+        picture.header.tile_size = Some(16);
+        assert!(matches!(issues(&picture)[..], [
+            IntegrityIssue::RangeCodingUnsupported(CompressionType::Lossless),
+            IntegrityIssue::MatricesUnsupported(CompressionType::Lossless),
+            IntegrityIssue::TilingUnsupported(CompressionType::Lossless),
+        ]));
+
+        let mut picture = gray(CompressionType::LossyDct, Some(70));
+        picture.header.row_filters = true;
+        picture.header.tile_size = Some(12);
+        picture.header.quantization_matrices = Some([[1; 64], [0; 64], [0; 64], [0; 64]]);
+        assert!(matches!(issues(&picture)[..], [
+            IntegrityIssue::InvalidTileSize(12),
+            IntegrityIssue::RowFiltersUnsupported(CompressionType::LossyDct),
+        ]));
+
+        let mut picture = SquishyPicture::from_raw(16, 8, ColorFormat::Rgb8, CompressionType::LossyDct, Some(70), vec![0; 16 * 8 * 3]);
+        picture.header.quantization_matrices = Some([[1; 64], [1; 64], [0; 64], [0; 64]]);
+        assert!(matches!(issues(&picture)[..], [IntegrityIssue::InvalidQuantizationMatrix(2)]));
+    }
+
+    #[test]
+    fn every_issue_reported() {
+        let mut picture = gray(CompressionType::None, None);
+        picture.header.quality = 3;
+        picture.header.transparent_color = Some([0; 3]);
+        picture.bitmap.push(0);
+
+        assert!(matches!(issues(&picture)[..], [
+            IntegrityIssue::InvalidQuality { .. },
+            IntegrityIssue::TransparencyUnsupported(_),
+            IntegrityIssue::SizeMismatch { .. },
+        ]));
+    }
+
+    #[test]
+    fn files() {
+        let picture = gray(CompressionType::Lossless, None);
+        let encoded = picture.encode_to_vec().unwrap();
+        assert!(verify(encoded.as_slice()).is_ok());
+
+        // Problems decoding carries on past are all reported
+        let mut trailing = encoded.clone();
+        trailing.extend_from_slice(&[0; 3]);
+        assert!(matches!(verify(trailing.as_slice()).unwrap_err()[..], [
+            IntegrityIssue::Warning(DecodeWarning::TrailingBytes { len: 3, .. }),
+        ]));
+
+        let truncated = &encoded[..encoded.len() - 1];
+        assert!(matches!(verify(truncated).unwrap_err()[..], [IntegrityIssue::Decode(Error::TruncatedFile { .. })]));
+
+        let mut corrupt = encoded.clone();
+        corrupt[0] ^= 0xFF;
+        assert!(matches!(verify(corrupt.as_slice()).unwrap_err()[..], [IntegrityIssue::Decode(Error::InvalidIdentifier(_))]));
+    }
+
+    #[test]
+    fn chunk_table() {
+        // A chunk table which claims a row more than the image has
+        let picture = gray(CompressionType::None, None);
+        let (data, compression_info) = compress(&[0; 16 * 9], Backend::from(&picture.header)).unwrap();
+        let mut encoded = Vec::new();
+        picture.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &picture, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &picture.header, image_len).unwrap();
+
+        assert!(matches!(verify(encoded.as_slice()).unwrap_err()[..], [
+            IntegrityIssue::Decode(Error::ChunkTableMismatch { claimed: 144, min: 128, max: 128 }),
+        ]));
+    }
+
+    #[cfg(feature = "content-hash")]
+    #[test]
+    fn content_hash() {
+        let picture = gray(CompressionType::None, None);
+        let mut encoded = Vec::new();
+        picture.encode_with_options(&mut encoded, &EncodeOptions::new().content_hash(true)).unwrap();
+        assert!(verify(encoded.as_slice()).is_ok());
+
+        // Uncompressed pixels are stored as they are, so the last byte
+        // before the footer is the last pixel
+        let last = encoded.len() - crate::container::FOOTER_LEN - 1;
+        encoded[last] ^= 1;
+        assert!(matches!(verify(encoded.as_slice()).unwrap_err()[..], [
+            IntegrityIssue::Warning(DecodeWarning::ContentHashMismatch { .. }),
+        ]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_on_disk() {
+        let path = std::env::temp_dir().join(format!("sqp-verify-{}.sqp", std::process::id()));
+        let encoded = gray(CompressionType::Lossless, None).encode_to_vec().unwrap();
+
+        std::fs::write(&path, &encoded).unwrap();
+        let complete = verify_file(&path);
+        std::fs::write(&path, &encoded[..encoded.len() - 4]).unwrap();
+        let truncated = verify_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(complete.is_ok());
+        assert!(matches!(truncated.unwrap_err()[..], [IntegrityIssue::Decode(Error::TruncatedFile { .. })]));
+        assert!(matches!(verify_file(&path).unwrap_err()[..], [IntegrityIssue::Decode(Error::IoError(_))]));
+    }
+}
@@ -0,0 +1,227 @@
+//! Minimal I/O abstractions, so the codec also works without `std`.
+//!
+//! With the `std` feature these are the standard library's traits. Without
+//! it they are small stand-ins implemented for byte slices and [`Vec`].
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, Read, Write};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_io::{Error, ErrorKind, Read, Write};
+
+#[cfg(not(feature = "std"))]
+mod core_io {
+    use core::fmt;
+
+    use alloc::vec::Vec;
+
+    /// The kind of an I/O [`Error`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        /// The input ended before all the requested bytes were read.
+        UnexpectedEof,
+
+        /// The output stopped accepting bytes before everything was written.
+        WriteZero,
+
+        /// The operation isn't supported by this reader or writer.
+        Unsupported,
+    }
+
+    /// An error which occurred while reading or writing.
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+    }
+
+    impl Error {
+        /// The kind of error which occurred.
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Self { kind }
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.kind {
+                ErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+                ErrorKind::WriteZero => write!(f, "failed to write whole buffer"),
+                ErrorKind::Unsupported => write!(f, "unsupported"),
+            }
+        }
+    }
+
+    impl core::error::Error for Error {}
+
+    /// A source of bytes.
+    pub trait Read {
+        /// Read some bytes into `buf`, returning how many were read.
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+
+        /// Read exactly enough bytes to fill `buf`.
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error> {
+            while !buf.is_empty() {
+                match self.read(buf)? {
+                    0 => return Err(ErrorKind::UnexpectedEof.into()),
+                    n => buf = &mut buf[n..],
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let len = buf.len().min(self.len());
+            let (data, rest) = self.split_at(len);
+
+            buf[..len].copy_from_slice(data);
+            *self = rest;
+
+            Ok(len)
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            (**self).read(buf)
+        }
+    }
+
+    /// A sink for bytes.
+    pub trait Write {
+        /// Write some bytes from `buf`, returning how many were written.
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
+
+        /// Write all of `buf`.
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            (**self).write(buf)
+        }
+    }
+}
+
+/// Little endian integer reading on top of [`Read`].
+pub trait ReadExt: Read {
+    /// Read a single byte.
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+
+        Ok(buf[0])
+    }
+
+    /// Read a little endian [`u16`].
+    fn read_u16_le(&mut self) -> Result<u16, Error> {
+        let mut buf = [0u8; 2];
+        self.read_exact(&mut buf)?;
+
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Read a little endian [`u32`].
+    fn read_u32_le(&mut self) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Read a little endian [`u64`].
+    fn read_u64_le(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+impl<R: Read + ?Sized> ReadExt for R {}
+
+/// Little endian integer writing on top of [`Write`].
+pub trait WriteExt: Write {
+    /// Write a single byte.
+    fn write_u8(&mut self, value: u8) -> Result<(), Error> {
+        self.write_all(&[value])
+    }
+
+    /// Write a little endian [`u16`].
+    fn write_u16_le(&mut self, value: u16) -> Result<(), Error> {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    /// Write a little endian [`u32`].
+    fn write_u32_le(&mut self, value: u32) -> Result<(), Error> {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    /// Write a little endian [`u64`].
+    fn write_u64_le(&mut self, value: u64) -> Result<(), Error> {
+        self.write_all(&value.to_le_bytes())
+    }
+}
+
+impl<W: Write + ?Sized> WriteExt for W {}
+
+/// Read exactly `len` bytes into a new [`Vec`].
+///
+/// The buffer grows as data actually arrives, so a lying length can't force
+/// a large allocation before the input runs out.
+pub fn read_vec<R: Read + ?Sized>(input: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    const STEP: usize = 64 * 1024;
+
+    let mut buffer = Vec::with_capacity(len.min(STEP));
+    while buffer.len() < len {
+        let start = buffer.len();
+        let step = (len - start).min(STEP);
+
+        buffer.resize(start + step, 0);
+        input.read_exact(&mut buffer[start..])?;
+    }
+
+    Ok(buffer)
+}
+
+/// Read the input to its end, returning how many bytes were left in it.
+pub fn count_remaining<R: Read + ?Sized>(input: &mut R) -> Result<u64, Error> {
+    let mut buffer = [0u8; 4096];
+    let mut count = 0;
+    loop {
+        match input.read(&mut buffer) {
+            Ok(0) => return Ok(count),
+            Ok(n) => count += n as u64,
+            #[cfg(feature = "std")]
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
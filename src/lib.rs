@@ -10,36 +10,72 @@
 //! using a more standard one such as those supported by the
 //! [image crate](https://docs.rs/image/latest/image/).
 //!
+//! # Features
+//! - `std` *(default)*: Use the standard library's I/O traits and enable
+//!   the file based conveniences like [`open`]. Without it the crate only
+//!   needs `alloc`, and images can be encoded into a [`Vec`] and decoded
+//!   from a byte slice.
+//! - `parallel` *(default)*: Use [rayon](https://docs.rs/rayon) to encode
+//!   and decode using multiple threads. Without it everything runs on the
+//!   calling thread, which is useful for targets like `wasm32-unknown-unknown`.
+//!   Output is identical either way. The only rayon type in the public API
+//!   is the pool taken by [`Threads::Pool`].
+//! - `image`: Conversions between [`SquishyPicture`] and the
+//!   [image crate](https://docs.rs/image/latest/image/)'s types.
+//! - `image-traits`: Implementations of the image crate's encoder and
+//!   decoder traits.
+//! - `capi`: A C interface, see the `capi` module.
+//! - `async`: [`SquishyPicture::decode_async`] and
+//!   [`SquishyPicture::encode_async`] for tokio's `AsyncRead` and
+//!   `AsyncWrite`.
+//! - `mmap`: [`open_mmap`], which memory maps the file instead of reading
+//!   it.
+//! - `content-hash`: Store a hash of the raw bitmap with
+//!   [`EncodeOptions::content_hash`], and check decoded images against it
+//!   with [`SquishyPicture::verify_content`] or strict decoding.
+//! - `log`: Log what the codec is doing through the
+//!   [log crate](https://docs.rs/log), under the `sqp` target. Chunk
+//!   tables, filters, the DCT of each channel and anything decoding
+//!   carries on past are logged at the debug and trace levels. Without it
+//!   nothing is logged, and none of it is compiled in.
+//! - `unstable`: The `low_level` module, which exposes the DCT,
+//!   quantization, row filtering, LZW and bit I/O the codec is built
+//!   from. It is exempt from semver and may change in any release.
+//! - `serde`: Implement serde's `Serialize` for the [`FileReport`] from
+//!   [`analyze`] and everything in it, so it can be written out as JSON or
+//!   any other format serde supports.
+//!
+//! [`EncodeOptions::content_hash`]: options::EncodeOptions::content_hash
+//! [`Threads::Pool`]: options::Threads::Pool
+//! [`FileReport`]: analysis::FileReport
+//!
 //! # Example
 //! ## Creating and writing an SQP
 //! ```no_run
+//! # #[cfg(feature = "std")] {
 //! use sqp::{SquishyPicture, ColorFormat};
 //!
-//! let width = 2;
-//! let height = 2;
-//! let bitmap = vec![
-//!     0xFF, 0xFF, 0xFF, 0xFF,
-//!     0x00, 0x80, 0x00, 0x80,
-//!     0xFF, 0xFF, 0xFF, 0xFF,
-//!     0x00, 0x80, 0x00, 0x80,
-//! ];
+//! // Create a 256×256 checkerboard in memory. Nothing is compressed or
+//! // encoded at this point.
+//! let sqp_image = SquishyPicture::from_fn(256, 256, ColorFormat::Rgba8, |x, y, pixel| {
+//!     if (x / 32 + y / 32) % 2 == 0 {
+//!         pixel.copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+//!     } else {
+//!         pixel.copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+//!     }
+//! });
 //!
-//! // Create a 2×2 image in memory. Nothing is compressed or encoded
-//! // at this point.
-//! let sqp_image = SquishyPicture::from_raw_lossless(
-//!     width,
-//!     height,
-//!     ColorFormat::Rgba8,
-//!     bitmap
-//! );
+//! // Images made this way are lossless, `set_compression` can change that.
 //!
 //! // Write it out to a file. This performs compression and encoding.
 //! sqp_image.save("my_image.sqp").expect("Could not save the image");
+//! # }
 //! ```
 //!
 //! ## Reading an SQP from a file.
 //! ```no_run
-//! use std::fs::File;
+//! # #[cfg(feature = "std")] {
+//! use std::{fs::File, io::BufReader};
 //! use sqp::SquishyPicture;
 //!
 //! // Load it directly with the `open` function...
@@ -47,18 +83,65 @@
 //!
 //! // ...or from something implementing Read.
 //! let input_file = File::open("my_image.sqp").expect("Could not open image file");
-//! let image2 = SquishyPicture::decode(&input_file);
+//! let image2 = SquishyPicture::decode(BufReader::new(input_file));
+//! # }
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod diagnostics;
+
 mod compression {
+    pub mod bwt;
     pub mod dct;
     pub mod lossless;
+    pub mod lz77;
+    pub mod predictive;
+    pub mod range_coder;
 }
 mod binio;
+mod io;
+mod math;
 mod operations;
+mod row_filter;
+mod threads;
+
+#[cfg(feature = "image")]
+mod image_conversions;
+
+#[cfg(feature = "async")]
+mod async_io;
 
 pub mod picture;
 pub mod header;
+pub mod container;
+pub mod encode_reader;
+pub mod stream_decoder;
+pub mod encoder;
+pub mod options;
+pub mod dither;
+pub mod histogram;
+pub mod palette;
+pub mod pixels;
+pub mod analysis;
+pub mod metrics;
+pub mod lossless;
+pub mod integrity;
+
+#[cfg(feature = "std")]
+pub mod batch;
+
+#[cfg(feature = "image-traits")]
+pub mod image_traits;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(feature = "unstable")]
+pub mod low_level;
 
 // ----------------------- //
 // INLINED USEFUL FEATURES //
@@ -67,10 +150,32 @@ pub mod header;
 pub use picture::SquishyPicture;
 
 #[doc(inline)]
-pub use picture::open;
+pub use picture::decode_to_rgba8;
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use picture::{open, open_with_limits};
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use integrity::verify_file;
+
+#[doc(inline)]
+pub use options::Limits;
+
+#[cfg(feature = "mmap")]
+#[doc(inline)]
+pub use picture::{open_mmap, open_mmap_with_limits};
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use batch::{encode_many, encode_many_to};
 
 #[doc(inline)]
 pub use header::ColorFormat;
 
 #[doc(inline)]
 pub use header::CompressionType;
+
+#[doc(inline)]
+pub use analysis::analyze;
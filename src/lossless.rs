@@ -0,0 +1,289 @@
+//! Shared dictionaries, which let small related images start compressing
+//! from what they have in common.
+//!
+//! LZW builds its dictionary of strings from nothing for every chunk, so
+//! the chunk of a tiny image, like a sprite or a tile, ends before many of
+//! its strings are learned. A [`Dictionary`] trained on samples of a
+//! collection gives each chunk those strings up front. It is given to
+//! [`EncodeOptions::shared_dictionary`] and
+//! [`DecodeOptions::shared_dictionary`], and isn't stored in the file, so
+//! it has to be kept alongside the images, such as with
+//! [`Dictionary::as_bytes`].
+//!
+//! # Example
+//! ```
+//! use sqp::{lossless::Dictionary, options::{DecodeOptions, EncodeOptions}, ColorFormat, SquishyPicture};
+//!
+//! let tiles: Vec<SquishyPicture> = (0..8u8)
+//!     .map(|i| SquishyPicture::from_fn(16, 16, ColorFormat::Gray8, |x, y, pixel| {
+//!         pixel[0] = ((x ^ y) as u8 & 0xF) * 16 + i
+//!     }))
+//!     .collect();
+//! let dictionary = Dictionary::train_from_pictures(&tiles.iter().collect::<Vec<_>>());
+//!
+//! let mut encoded = Vec::new();
+//! tiles[3].encode_with_options(&mut encoded, &EncodeOptions::new().shared_dictionary(&dictionary)).unwrap();
+//!
+//! // Decoding needs the same dictionary
+//! assert!(SquishyPicture::decode(encoded.as_slice()).is_err());
+//!
+//! let options = DecodeOptions::new().shared_dictionary(&dictionary);
+//! let decoded = SquishyPicture::decode_with_options(encoded.as_slice(), &options).unwrap();
+//! assert_eq!(decoded.as_raw(), tiles[3].as_raw());
+//! ```
+//!
+//! [`EncodeOptions::shared_dictionary`]: crate::options::EncodeOptions::shared_dictionary
+//! [`DecodeOptions::shared_dictionary`]: crate::options::DecodeOptions::shared_dictionary
+
+use core::fmt;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{compression::lossless::{Primer, MAX_PRIMER_LEN}, options::EncodeOptions, SquishyPicture};
+
+/// Content which every LZW chunk of an image starts compressing from.
+///
+/// Cloning a dictionary is cheap, as clones share the content and the
+/// strings built from it.
+#[derive(Clone)]
+pub struct Dictionary {
+    inner: Arc<DictionaryInner>,
+}
+
+struct DictionaryInner {
+    content: Vec<u8>,
+    id: u64,
+    primer: Primer,
+}
+
+impl Dictionary {
+    /// The most content a dictionary holds, in bytes.
+    pub const MAX_LEN: usize = MAX_PRIMER_LEN;
+
+    /// Build a dictionary from samples of the data which gets compressed.
+    ///
+    /// Repeated samples are only used once. If the samples are longer than
+    /// [`Dictionary::MAX_LEN`] altogether, each one gets an equal share of
+    /// it, with what shorter samples leave over going to the longer ones,
+    /// and only the start of a longer sample is used.
+    ///
+    /// The data which gets compressed is the row filtered bitmap of a
+    /// lossless image, rather than its pixels, so
+    /// [`Dictionary::train_from_pictures`] is usually what's wanted.
+    pub fn train(samples: &[&[u8]]) -> Self {
+        let mut unique: Vec<&[u8]> = Vec::new();
+        for sample in samples {
+            if !unique.contains(sample) {
+                unique.push(sample);
+            }
+        }
+
+        // Share out the room from the shortest sample up
+        let mut order: Vec<usize> = (0..unique.len()).collect();
+        order.sort_by_key(|i| unique[*i].len());
+
+        let mut lengths = alloc::vec![0; unique.len()];
+        let mut remaining = Self::MAX_LEN;
+        for (done, i) in order.into_iter().enumerate() {
+            lengths[i] = unique[i].len().min(remaining / (unique.len() - done));
+            remaining -= lengths[i];
+        }
+
+        let content: Vec<u8> = unique.iter().zip(lengths).flat_map(|(sample, len)| &sample[..len]).copied().collect();
+        Self::from_bytes(content)
+    }
+
+    /// Build a dictionary from pictures like the ones which will be encoded
+    /// with it, as they would be compressed with the default
+    /// [`EncodeOptions`].
+    ///
+    /// The pictures should be in the same [`ColorFormat`] as the images to
+    /// be encoded, as the bytes of each pixel depend on it.
+    ///
+    /// [`ColorFormat`]: crate::ColorFormat
+    pub fn train_from_pictures(pictures: &[&SquishyPicture]) -> Self {
+        let options = EncodeOptions::default();
+        let samples: Vec<_> = pictures.iter().map(|p| p.filtered_bitmap(&options)).collect();
+
+        Self::train(&samples.iter().map(|s| s.as_ref()).collect::<Vec<_>>())
+    }
+
+    /// Use `content` as a dictionary as it is, such as one saved from
+    /// [`Dictionary::as_bytes`]. Only the first [`Dictionary::MAX_LEN`]
+    /// bytes are kept.
+    pub fn from_bytes(content: impl Into<Vec<u8>>) -> Self {
+        let mut content = content.into();
+        content.truncate(Self::MAX_LEN);
+
+        Self {
+            inner: Arc::new(DictionaryInner {
+                id: content_id(&content),
+                primer: Primer::new(&content),
+                content,
+            }),
+        }
+    }
+
+    /// The content of the dictionary, to be saved and given back to
+    /// [`Dictionary::from_bytes`].
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner.content
+    }
+
+    /// The ID stored in images encoded with this dictionary, which is a
+    /// 64-bit FNV-1a hash of its content.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    /// The LZW dictionary built from the content.
+    pub(crate) fn primer(&self) -> &Primer {
+        &self.inner.primer
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &format_args!("{:#018x}", self.id()))
+            .field("len", &self.inner.content.len())
+            .finish()
+    }
+}
+
+/// The 64-bit FNV-1a hash of a dictionary's content.
+fn content_id(content: &[u8]) -> u64 {
+    content.iter().fold(0xCBF29CE484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001B3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::SqpEncoder,
+        options::DecodeOptions,
+        picture::Error,
+        stream_decoder::{DecodeProgress, SqpStreamDecoder},
+        ColorFormat, CompressionType,
+    };
+
+    /// Sprites of the same few colors, with a circle of a different size
+    /// and shade in each.
+    fn tiles() -> Vec<SquishyPicture> {
+        (0..24).map(|i: u32| {
+            SquishyPicture::from_fn(32, 32, ColorFormat::Rgba8, |x, y, pixel| {
+                let (dx, dy) = (x as i32 - 16, y as i32 - 16);
+                let radius = 6 + (i % 9) as i32;
+                let color = match dx * dx + dy * dy {
+                    d if d < (radius - 2).pow(2) => [220, 40 + (i % 4) as u8 * 30, 60, 255],
+                    d if d < radius.pow(2) => [30, 20, 40, 255],
+                    _ => [0, 0, 0, 0],
+                };
+                pixel.copy_from_slice(&color);
+            })
+        }).collect()
+    }
+
+    fn encode(picture: &SquishyPicture, options: &EncodeOptions) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        picture.encode_with_options(&mut encoded, options).unwrap();
+
+        encoded
+    }
+
+    #[test]
+    fn training_shares_room() {
+        let dictionary = Dictionary::train(&[b"abc", b"de", b"abc"]);
+        assert_eq!(dictionary.as_bytes(), b"abcde");
+
+        let long = [1; 50_000];
+        let dictionary = Dictionary::train(&[&long, &[2; 100], &[3; 50_000]]);
+        let content = dictionary.as_bytes();
+        assert_eq!(content.len(), Dictionary::MAX_LEN);
+        assert_eq!(content.iter().filter(|b| **b == 2).count(), 100);
+        assert_eq!(content.iter().filter(|b| **b == 1).count(), (Dictionary::MAX_LEN - 100) / 2);
+
+        let saved = Dictionary::from_bytes(content);
+        assert_eq!(saved.id(), dictionary.id());
+        assert_ne!(Dictionary::from_bytes(&content[1..]).id(), dictionary.id());
+    }
+
+    #[test]
+    fn round_trip() {
+        let tiles = tiles();
+        let dictionary = Dictionary::train_from_pictures(&tiles.iter().step_by(2).collect::<Vec<_>>());
+        let options = EncodeOptions::new().shared_dictionary(&dictionary);
+        let decode_options = DecodeOptions::new().shared_dictionary(&dictionary);
+
+        let mut encoder = SqpEncoder::new(options.clone());
+        for tile in &tiles {
+            let encoded = encode(tile, &options);
+            let decoded = SquishyPicture::decode_with_options(encoded.as_slice(), &decode_options).unwrap();
+            assert_eq!(decoded.as_raw(), tile.as_raw());
+
+            // Every way of encoding gives the same output
+            let mut reused = Vec::new();
+            encoder.encode(tile, &mut reused).unwrap();
+            assert_eq!(reused, encoded);
+            assert_eq!(encode(tile, &options.clone().low_memory(true)), encoded);
+
+            let mut decoder = SqpStreamDecoder::with_options(&decode_options);
+            match decoder.feed(&encoded).unwrap() {
+                DecodeProgress::Finished { picture, .. } => assert_eq!(picture.as_raw(), tile.as_raw()),
+                _ => panic!("the whole image was fed"),
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_needs_the_dictionary() {
+        let tiles = tiles();
+        let dictionary = Dictionary::train_from_pictures(&[&tiles[0]]);
+        let encoded = encode(&tiles[1], &EncodeOptions::new().shared_dictionary(&dictionary));
+        let id = dictionary.id();
+
+        assert!(matches!(SquishyPicture::decode(encoded.as_slice()), Err(Error::MissingDictionary { id: i }) if i == id));
+        assert!(matches!(
+            SquishyPicture::decode_region(encoded.as_slice(), 0, 0, 8, 8),
+            Err(Error::MissingDictionary { .. })
+        ));
+        assert!(matches!(SqpStreamDecoder::new().feed(&encoded), Err(Error::MissingDictionary { .. })));
+
+        let other = Dictionary::train_from_pictures(&[&tiles[2]]);
+        let result = SquishyPicture::decode_with_options(encoded.as_slice(), &DecodeOptions::new().shared_dictionary(&other));
+        assert!(matches!(result, Err(Error::DictionaryMismatch { expected, actual }) if expected == id && actual == other.id()));
+
+        // Images without a dictionary don't need one given
+        let plain = encode(&tiles[1], &EncodeOptions::new());
+        let options = DecodeOptions::new().shared_dictionary(&dictionary);
+        assert!(SquishyPicture::decode_with_options(plain.as_slice(), &options).is_ok());
+    }
+
+    #[test]
+    fn smaller_than_independent() {
+        let tiles = tiles();
+        let dictionary = Dictionary::train_from_pictures(&tiles.iter().step_by(2).collect::<Vec<_>>());
+        let options = EncodeOptions::new().shared_dictionary(&dictionary);
+
+        // Only the tiles which weren't trained on
+        let independent: usize = tiles.iter().skip(1).step_by(2).map(|t| encode(t, &EncodeOptions::new()).len()).sum();
+        let shared: usize = tiles.iter().skip(1).step_by(2).map(|t| encode(t, &options).len()).sum();
+        assert!(shared * 20 < independent * 17, "{shared} vs {independent}");
+    }
+
+    #[test]
+    fn other_compression_unaffected() {
+        let tiles = tiles();
+        let dictionary = Dictionary::train_from_pictures(&[&tiles[0]]);
+        let options = EncodeOptions::new().shared_dictionary(&dictionary);
+
+        for (compression_type, quality) in [(CompressionType::LosslessV2, None), (CompressionType::LossyDct, Some(80))] {
+            let mut tile = SquishyPicture::from_raw_lossless(32, 32, ColorFormat::Rgba8, tiles[1].as_raw().clone());
+            tile.set_compression(compression_type, quality);
+
+            let encoded = encode(&tile, &options);
+            assert_eq!(encoded, encode(&tile, &EncodeOptions::new()));
+            assert!(SquishyPicture::decode(encoded.as_slice()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,598 @@
+//! The primitives the codec is built from, for experimenting with them
+//! directly. Only available with the `unstable` feature.
+//!
+//! **Nothing in this module is covered by semver.** Any of it may change
+//! or go away in any release, including patch releases. The supported API
+//! is [`SquishyPicture`] and the rest of the crate.
+//!
+//! These are the same functions the encoder and decoder use, with their
+//! inputs checked up front so that mistakes are returned as an [`Error`]
+//! instead of panicking or producing garbage.
+//!
+//! The primitives can't be reached any other way:
+//! ```compile_fail
+//! use sqp::compression::dct::dct;
+//! ```
+//! ```compile_fail
+//! use sqp::binio::BitReader;
+//! ```
+//! ```compile_fail
+//! use sqp::operations::add_rows;
+//! ```
+//!
+//! [`SquishyPicture`]: crate::SquishyPicture
+
+use alloc::vec::Vec;
+
+use crate::{
+    binio,
+    compression::{dct, lossless::{self, total_size_raw, Backend, CompressionInfo}},
+    header::QUALITY_RANGE,
+    io::ReadExt,
+    operations,
+    options::Limits,
+    picture::{Error, FileSection},
+    ColorFormat,
+};
+
+/// Writes individual bits, and whole bytes, to a [`Vec`].
+///
+/// Writing to a [`Vec`] can't fail, so the only errors are lengths the
+/// writer can't handle, which are [`Error::InvalidBitLength`] and
+/// [`Error::InvalidRiceParameter`].
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{BitReader, BitWriter};
+///
+/// let mut output = Vec::new();
+/// let mut writer = BitWriter::new(&mut output);
+/// writer.write_bit(0b101, 3).unwrap();
+/// writer.write_rice(13, 2).unwrap();
+/// writer.flush();
+/// assert!(writer.write_bit(0, 65).is_err());
+///
+/// let mut input = output.as_slice();
+/// let mut reader = BitReader::new(&mut input);
+/// assert_eq!(reader.read_bit(3).unwrap(), 0b101);
+/// assert_eq!(reader.read_rice(2).unwrap(), 13);
+/// ```
+pub struct BitWriter<'a>(binio::BitWriter<'a, Vec<u8>>);
+
+impl<'a> BitWriter<'a> {
+    /// Create a writer which appends to `output`.
+    pub fn new(output: &'a mut Vec<u8>) -> Self {
+        Self(binio::BitWriter::new(output))
+    }
+
+    /// The total number of bits written since the writer was created,
+    /// including any padding from [`BitWriter::flush`].
+    pub fn bit_position(&self) -> u64 {
+        self.0.bit_position()
+    }
+
+    /// Align the writer to the nearest byte by padding with zero bits.
+    ///
+    /// This always writes the current byte, even if it is empty.
+    pub fn flush(&mut self) {
+        self.0.flush()
+    }
+
+    /// Write the low `bit_len` bits of `data`, from the lowest up.
+    ///
+    /// Fails if `bit_len` isn't between 1 and 64.
+    pub fn write_bit(&mut self, data: u64, bit_len: usize) -> Result<(), Error> {
+        check_len(bit_len, 64)?;
+        self.0.write_bit(data, bit_len);
+
+        Ok(())
+    }
+
+    /// Write the low `byte_len` bytes of `data`, in little endian order,
+    /// which is the same as writing `byte_len * 8` bits.
+    ///
+    /// Fails if `byte_len` isn't between 1 and 8.
+    pub fn write(&mut self, data: u64, byte_len: usize) -> Result<(), Error> {
+        check_len(byte_len, 8)?;
+        self.0.write_bit(data, byte_len * 8);
+
+        Ok(())
+    }
+
+    /// Write `n` in unary, as `n` zero bits followed by a one bit.
+    pub fn write_unary(&mut self, n: u64) {
+        self.0.write_unary(n)
+    }
+
+    /// Write a Golomb–Rice code with parameter `k`, the quotient
+    /// `value >> k` in unary followed by the low `k` bits of `value`.
+    ///
+    /// Fails if `k` isn't below 64.
+    pub fn write_rice(&mut self, value: u64, k: u32) -> Result<(), Error> {
+        check_rice(k)?;
+        self.0.write_rice(value, k);
+
+        Ok(())
+    }
+}
+
+/// Reads individual bits, and whole bytes, from a byte slice.
+///
+/// Reading past the end of the input fails with [`Error::IoError`], and
+/// lengths the reader can't handle fail in the same ways as [`BitWriter`].
+///
+/// # Example
+/// ```
+/// use sqp::low_level::BitReader;
+///
+/// let mut input: &[u8] = &[0b1011_0010, 0xFF];
+/// let mut reader = BitReader::new(&mut input);
+///
+/// // Bits are read from the lowest up
+/// assert_eq!(reader.read_bit(4).unwrap(), 0b0010);
+/// assert_eq!(reader.read_bit(8).unwrap(), 0b1111_1011);
+/// assert!(reader.read_bit(8).is_err());
+///
+/// // Any bit can be read again
+/// reader.seek_to_bit(2).unwrap();
+/// assert_eq!(reader.read_bit(2).unwrap(), 0b00);
+/// assert_eq!(reader.bit_position(), 4);
+/// ```
+pub struct BitReader<'a, 'b>(binio::BitReader<'a, &'b [u8]>);
+
+impl<'a, 'b> BitReader<'a, 'b> {
+    /// Create a reader which takes bytes from the start of `input`.
+    pub fn new(input: &'a mut &'b [u8]) -> Self {
+        Self(binio::BitReader::seekable(input))
+    }
+
+    /// The total number of bits read since the reader was created, or the
+    /// position last sought to.
+    pub fn bit_position(&self) -> u64 {
+        self.0.bit_position()
+    }
+
+    /// Read `bit_len` bits, from the lowest up.
+    ///
+    /// Fails if `bit_len` isn't between 1 and 64.
+    pub fn read_bit(&mut self, bit_len: usize) -> Result<u64, Error> {
+        check_len(bit_len, 64)?;
+
+        Ok(self.0.read_bit(bit_len)?)
+    }
+
+    /// Read `byte_len` bytes in little endian order, which is the same as
+    /// reading `byte_len * 8` bits.
+    ///
+    /// Fails if `byte_len` isn't between 1 and 8.
+    pub fn read(&mut self, byte_len: usize) -> Result<u64, Error> {
+        check_len(byte_len, 8)?;
+
+        Ok(self.0.read_bit(byte_len * 8)?)
+    }
+
+    /// Read a number written by [`BitWriter::write_unary`].
+    pub fn read_unary(&mut self) -> Result<u64, Error> {
+        Ok(self.0.read_unary()?)
+    }
+
+    /// Read a Golomb–Rice code written by [`BitWriter::write_rice`] with
+    /// the same `k`. Quotient bits which don't fit in a [`u64`] are lost.
+    ///
+    /// Fails if `k` isn't below 64.
+    pub fn read_rice(&mut self, k: u32) -> Result<u64, Error> {
+        check_rice(k)?;
+
+        Ok(self.0.read_rice(k)?)
+    }
+
+    /// Move to `position` bits from the start of the input, so the next
+    /// bit read is the one there. Seeking past the end of the input fails,
+    /// and leaves the reader where it was.
+    pub fn seek_to_bit(&mut self, position: u64) -> Result<(), Error> {
+        Ok(self.0.seek_to_bit(position)?)
+    }
+}
+
+/// Picks the Golomb–Rice parameter for values whose average drifts, as the
+/// log of the running mean of the values so far. The writer and reader
+/// each keep one and update it with the same values, so `k` is never
+/// stored.
+///
+/// The totals are halved every [`AdaptiveRice::RESET`] values, so older
+/// values count for less.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{AdaptiveRice, BitReader, BitWriter};
+///
+/// let values = [3, 40, 41, 38, 500, 2, 0, 7];
+///
+/// let mut output = Vec::new();
+/// let mut writer = BitWriter::new(&mut output);
+/// let mut rice = AdaptiveRice::new(4);
+/// for value in values {
+///     rice.write(&mut writer, value);
+/// }
+/// writer.flush();
+///
+/// let mut input = output.as_slice();
+/// let mut reader = BitReader::new(&mut input);
+/// let mut rice = AdaptiveRice::new(4);
+/// for value in values {
+///     assert_eq!(rice.read(&mut reader).unwrap(), value);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveRice(binio::AdaptiveRice);
+
+impl AdaptiveRice {
+    /// How many values are seen before the totals are halved.
+    pub const RESET: u64 = binio::AdaptiveRice::RESET;
+
+    /// Start from a guess at the mean, as if one value of it had been seen.
+    pub fn new(mean: u64) -> Self {
+        Self(binio::AdaptiveRice::new(mean))
+    }
+
+    /// The parameter for the next value, about the log of the mean. It is
+    /// always below 64.
+    pub fn k(&self) -> u32 {
+        self.0.k()
+    }
+
+    /// Count a value towards the mean.
+    pub fn update(&mut self, value: u64) {
+        self.0.update(value)
+    }
+
+    /// Write a value with [`BitWriter::write_rice`] and the current
+    /// parameter, then update the mean.
+    pub fn write(&mut self, writer: &mut BitWriter, value: u64) {
+        self.0.write(&mut writer.0, value)
+    }
+
+    /// Read a value written by [`AdaptiveRice::write`], then update the
+    /// mean.
+    pub fn read(&mut self, reader: &mut BitReader) -> Result<u64, Error> {
+        Ok(self.0.read(&mut reader.0)?)
+    }
+}
+
+/// The Discrete Cosine Transform of a `size` by `size` matrix of samples,
+/// given in rows, with 128 taken from each sample first.
+///
+/// The coefficients are in rows from the DC coefficient at the top left.
+/// Fails with [`Error::SizeMismatch`] if `input` isn't `size * size`
+/// samples.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::dct;
+///
+/// let coefficients = dct(&[200; 64], 8).unwrap();
+///
+/// // A flat block only has a DC coefficient, 8 times its distance from 128
+/// assert!((coefficients[0] - 576.0).abs() < 0.01);
+/// assert!(coefficients[1..].iter().all(|c| c.abs() < 0.01));
+/// ```
+pub fn dct(input: &[u8], size: usize) -> Result<Vec<f32>, Error> {
+    check_square(input.len(), size)?;
+
+    Ok(dct::dct(input, size, size))
+}
+
+/// The inverse of [`dct`], turning `size` by `size` coefficients back into
+/// samples, rounded and clamped to the range of a byte.
+///
+/// Fails with [`Error::SizeMismatch`] if `input` isn't `size * size`
+/// coefficients.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{dct, idct};
+///
+/// let block: Vec<u8> = (0..64).map(|i| i * 3).collect();
+/// assert_eq!(idct(&dct(&block, 8).unwrap(), 8).unwrap(), block);
+/// ```
+pub fn idct(input: &[f32], size: usize) -> Result<Vec<u8>, Error> {
+    check_square(input.len(), size)?;
+
+    Ok(dct::idct(input, size, size))
+}
+
+/// The 8x8 quantization matrix lossy images use at a quality level, in
+/// rows from the step for the DC coefficient at the top left.
+///
+/// Fails with [`Error::InvalidQuality`] if the quality isn't within
+/// [`QUALITY_RANGE`].
+///
+/// # Example
+/// ```
+/// use sqp::low_level::quantization_matrix;
+///
+/// let matrix = quantization_matrix(50).unwrap();
+/// assert_eq!(matrix[0], 16);
+///
+/// // Higher qualities quantize more finely
+/// assert!(quantization_matrix(90).unwrap()[0] < matrix[0]);
+/// assert!(quantization_matrix(0).is_err());
+/// ```
+pub fn quantization_matrix(quality: u8) -> Result<[u16; 64], Error> {
+    if !QUALITY_RANGE.contains(&quality) {
+        return Err(Error::InvalidQuality(quality))
+    }
+
+    Ok(dct::quantization_matrix(quality as u32))
+}
+
+/// Divide each 8x8 block of coefficients by the steps of a quantization
+/// matrix, rounding to the nearest whole number.
+///
+/// Fails with [`Error::SizeMismatch`] if `input` isn't a whole number of
+/// blocks of 64 coefficients, and [`Error::InvalidQuantizationMatrix`] if
+/// the matrix has a step of zero.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{dct, quantization_matrix, quantize};
+///
+/// let coefficients = dct(&[200; 64], 8).unwrap();
+/// let quantized = quantize(&coefficients, quantization_matrix(50).unwrap()).unwrap();
+///
+/// assert_eq!(quantized[0], 36);
+/// assert!(quantized[1..].iter().all(|c| *c == 0));
+/// ```
+pub fn quantize(input: &[f32], matrix: [u16; 64]) -> Result<Vec<i16>, Error> {
+    check_blocks(input.len(), matrix)?;
+
+    Ok(input.chunks_exact(64).flat_map(|block| dct::quantize(block, matrix)).collect())
+}
+
+/// The inverse of [`quantize`], multiplying each 8x8 block of quantized
+/// coefficients by the steps of a quantization matrix.
+///
+/// Fails in the same ways as [`quantize`].
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{dequantize, quantization_matrix};
+///
+/// let mut quantized = [0; 64];
+/// quantized[0] = 36;
+/// let coefficients = dequantize(&quantized, quantization_matrix(50).unwrap()).unwrap();
+///
+/// assert_eq!(coefficients[0], 576.0);
+/// ```
+pub fn dequantize(input: &[i16], matrix: [u16; 64]) -> Result<Vec<f32>, Error> {
+    check_blocks(input.len(), matrix)?;
+
+    Ok(input.chunks_exact(64).flat_map(|block| dct::dequantize(block, matrix)).collect())
+}
+
+/// Take the difference of each row of a bitmap from the row above, as the
+/// lossless formats did before each row stored its own filter.
+///
+/// Every third of the image starts with a row which is left as it is. The
+/// result has each channel in turn rather than interleaved, and is the
+/// same size as the bitmap. Fails with [`Error::SizeMismatch`] if the
+/// bitmap isn't the size the dimensions and format describe.
+///
+/// # Example
+/// ```
+/// use sqp::{low_level::{add_rows, sub_rows}, ColorFormat};
+///
+/// let bitmap: Vec<u8> = (0..4 * 6).map(|i| i * 5).collect();
+/// let filtered = sub_rows(4, 6, ColorFormat::Gray8, &bitmap).unwrap();
+///
+/// // The second row, minus the first
+/// assert_eq!(filtered[4..8], [20; 4]);
+/// assert_eq!(add_rows(4, 6, ColorFormat::Gray8, &filtered).unwrap(), bitmap);
+/// ```
+pub fn sub_rows(width: u32, height: u32, color_format: ColorFormat, bitmap: &[u8]) -> Result<Vec<u8>, Error> {
+    check_bitmap(width, height, color_format, bitmap.len())?;
+
+    let mut output = Vec::new();
+    operations::sub_rows_into(width, height, color_format, bitmap, &mut output);
+
+    Ok(output)
+}
+
+/// The inverse of [`sub_rows`].
+///
+/// Fails with [`Error::SizeMismatch`] if the filtered data isn't the size
+/// of the bitmap the dimensions and format describe.
+///
+/// # Example
+/// ```
+/// use sqp::{low_level::add_rows, ColorFormat};
+///
+/// assert!(add_rows(4, 6, ColorFormat::Rgb8, &[0; 10]).is_err());
+/// ```
+pub fn add_rows(width: u32, height: u32, color_format: ColorFormat, filtered: &[u8]) -> Result<Vec<u8>, Error> {
+    check_bitmap(width, height, color_format, filtered.len())?;
+
+    Ok(operations::add_rows(width, height, color_format, filtered))
+}
+
+/// Compress some data with the LZW coding lossless images use, where each
+/// chunk's codes are Huffman coded if that makes them smaller.
+///
+/// The output starts with the table of chunk sizes the format stores, so
+/// [`decompress`] needs nothing else to reverse it. Fails with
+/// [`Error::CompressionError`] if `data` is empty.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{compress, decompress};
+///
+/// let data = b"squishy squishy squishy squishy squishy".repeat(50);
+/// let compressed = compress(&data).unwrap();
+///
+/// assert!(compressed.len() < data.len() / 4);
+/// assert_eq!(decompress(&compressed).unwrap(), data);
+/// ```
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let (chunks, compression_info) = lossless::compress(data, Backend::LzwHuffman)?;
+
+    let mut output = Vec::with_capacity(compression_info.len() + chunks.len());
+    compression_info.write_into(&mut output)?;
+    output.extend_from_slice(&chunks);
+
+    Ok(output)
+}
+
+/// Decompress data from [`compress`].
+///
+/// [`Limits::default`] applies to the size of the chunk table and the
+/// decompressed data. Fails with [`Error::TruncatedFile`] if the data
+/// ends early, and [`Error::Strict`] if any chunk is corrupted or
+/// decompresses to the wrong size.
+///
+/// # Example
+/// ```
+/// use sqp::low_level::{compress, decompress};
+///
+/// let mut compressed = compress(b"abracadabra").unwrap();
+/// compressed.pop();
+///
+/// assert!(decompress(&compressed).is_err());
+/// ```
+pub fn decompress(mut data: &[u8]) -> Result<Vec<u8>, Error> {
+    let limits = Limits::default();
+
+    let chunk_count = data.read_u32_le()
+        .map_err(|e| Error::from_read(e, FileSection::ChunkTable))?;
+    limits.check_chunk_count(chunk_count as usize)?;
+    let compression_info = CompressionInfo::read_chunks(&mut data, chunk_count as usize)
+        .map_err(|e| Error::from_read(e, FileSection::ChunkTable))?;
+    limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+    let mut warnings = Vec::new();
+    let output = lossless::decompress(&mut data, &compression_info, Backend::LzwHuffman, &mut warnings)
+        .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+
+    match warnings.into_iter().next() {
+        Some(warning) => Err(Error::Strict(warning)),
+        None => Ok(output),
+    }
+}
+
+/// Check that a number of bits or bytes to read or write at once is between
+/// 1 and `max`.
+fn check_len(len: usize, max: usize) -> Result<(), Error> {
+    if !(1..=max).contains(&len) {
+        return Err(Error::InvalidBitLength { len, max })
+    }
+
+    Ok(())
+}
+
+/// Check that a Golomb–Rice parameter leaves room for a quotient bit.
+fn check_rice(k: u32) -> Result<(), Error> {
+    if k >= 64 {
+        return Err(Error::InvalidRiceParameter(k))
+    }
+
+    Ok(())
+}
+
+/// Check that there are `size * size` values of a square matrix.
+fn check_square(len: usize, size: usize) -> Result<(), Error> {
+    let expected = size.saturating_mul(size);
+    if len != expected {
+        return Err(Error::SizeMismatch { expected, actual: len })
+    }
+
+    Ok(())
+}
+
+/// Check that there are whole 8x8 blocks of values, and that a
+/// quantization matrix can divide them.
+fn check_blocks(len: usize, matrix: [u16; 64]) -> Result<(), Error> {
+    if !len.is_multiple_of(64) {
+        return Err(Error::SizeMismatch { expected: len.next_multiple_of(64), actual: len })
+    }
+    if matrix.contains(&0) {
+        return Err(Error::InvalidQuantizationMatrix(0))
+    }
+
+    Ok(())
+}
+
+/// Check that a bitmap is the size the dimensions and format describe.
+fn check_bitmap(width: u32, height: u32, color_format: ColorFormat, len: usize) -> Result<(), Error> {
+    let expected = color_format.bitmap_len(width, height).unwrap_or(usize::MAX);
+    if len != expected {
+        return Err(Error::SizeMismatch { expected, actual: len })
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_are_checked() {
+        assert!(matches!(dct(&[0; 63], 8), Err(Error::SizeMismatch { expected: 64, actual: 63 })));
+        assert!(matches!(idct(&[0.0; 64], 4), Err(Error::SizeMismatch { expected: 16, actual: 64 })));
+        assert!(matches!(quantization_matrix(101), Err(Error::InvalidQuality(101))));
+
+        let matrix = quantization_matrix(80).unwrap();
+        assert!(matches!(quantize(&[0.0; 100], matrix), Err(Error::SizeMismatch { expected: 128, actual: 100 })));
+        assert!(matches!(dequantize(&[0; 64], [0; 64]), Err(Error::InvalidQuantizationMatrix(0))));
+        assert_eq!(dequantize(&[1; 128], matrix).unwrap().len(), 128);
+
+        assert!(matches!(sub_rows(3, 3, ColorFormat::Gray4, &[0; 9]), Err(Error::SizeMismatch { expected: 6, actual: 9 })));
+        assert!(matches!(compress(&[]), Err(Error::CompressionError(_))));
+    }
+
+    #[test]
+    fn bit_io_lengths_are_checked() {
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        assert!(matches!(writer.write_bit(0, 0), Err(Error::InvalidBitLength { len: 0, max: 64 })));
+        assert!(matches!(writer.write(0, 9), Err(Error::InvalidBitLength { len: 9, max: 8 })));
+        assert!(matches!(writer.write_rice(0, 64), Err(Error::InvalidRiceParameter(64))));
+
+        // Bytes don't have to be aligned
+        writer.write_bit(0b1, 1).unwrap();
+        writer.write(0xABCD, 2).unwrap();
+        writer.write_rice(1000, 63).unwrap();
+        assert_eq!(writer.bit_position(), 81);
+        writer.flush();
+
+        let mut input = output.as_slice();
+        let mut reader = BitReader::new(&mut input);
+        assert!(matches!(reader.read_bit(65), Err(Error::InvalidBitLength { len: 65, max: 64 })));
+        assert!(matches!(reader.read(0), Err(Error::InvalidBitLength { len: 0, max: 8 })));
+        assert!(matches!(reader.read_rice(100), Err(Error::InvalidRiceParameter(100))));
+        assert_eq!(reader.read_bit(1).unwrap(), 0b1);
+        assert_eq!(reader.read(2).unwrap(), 0xABCD);
+        assert_eq!(reader.read_rice(63).unwrap(), 1000);
+
+        assert!(matches!(reader.read(8), Err(Error::IoError(_))));
+        assert!(reader.seek_to_bit(89).is_err());
+        reader.seek_to_bit(1).unwrap();
+        assert_eq!(reader.read(1).unwrap(), 0xCD);
+    }
+
+    #[test]
+    fn decompress_corrupted() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        let mut compressed = compress(&data).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0xFF;
+        compressed[last - 20] ^= 0xFF;
+        assert!(decompress(&compressed).is_err());
+
+        assert!(matches!(
+            decompress(&compressed[..6]),
+            Err(Error::TruncatedFile { section: FileSection::ChunkTable })
+        ));
+    }
+}
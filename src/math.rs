@@ -0,0 +1,74 @@
+//! Float functions which are only part of `std`, falling back to [libm]
+//! without it.
+//!
+//! [libm]: https://docs.rs/libm
+
+#[cfg(feature = "std")]
+pub fn sqrt(x: f32) -> f32 {
+    x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn sqrt(x: f32) -> f32 {
+    libm::sqrtf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn cos(x: f32) -> f32 {
+    x.cos()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cos(x: f32) -> f32 {
+    libm::cosf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn round(x: f32) -> f32 {
+    x.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn round(x: f32) -> f32 {
+    libm::roundf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn floor(x: f32) -> f32 {
+    x.floor()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn floor(x: f32) -> f32 {
+    libm::floorf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn ceil(x: f32) -> f32 {
+    x.ceil()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn ceil(x: f32) -> f32 {
+    libm::ceilf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn log10(x: f64) -> f64 {
+    x.log10()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn log10(x: f64) -> f64 {
+    libm::log10(x)
+}
+
+#[cfg(feature = "std")]
+pub fn log2(x: f64) -> f64 {
+    x.log2()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn log2(x: f64) -> f64 {
+    libm::log2(x)
+}
@@ -0,0 +1,228 @@
+//! Measuring where and by how much two images differ.
+
+use alloc::{borrow::Cow, vec, vec::Vec};
+
+use crate::{header::ColorFormat, picture::Error, SquishyPicture};
+
+/// Options for [`diff`].
+///
+/// The defaults count every pixel which differs at all, and don't make an
+/// error map.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions {
+    threshold: u8,
+    gain: Option<u8>,
+}
+
+impl DiffOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only count pixels in [`DiffResult::differing_pixels`] when a channel
+    /// differs by more than this. 0 by default.
+    pub fn threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Make a [`DiffResult::error_map`], with each pixel's largest error
+    /// multiplied by `gain` so small differences show up.
+    pub fn error_map(mut self, gain: u8) -> Self {
+        self.gain = Some(gain);
+        self
+    }
+}
+
+/// How two images differ, returned by [`diff`].
+///
+/// The errors are absolute differences between the values of a channel, in
+/// the order of [`DiffResult::color_format`].
+#[non_exhaustive]
+pub struct DiffResult {
+    /// The format the images were compared in.
+    pub color_format: ColorFormat,
+
+    /// The largest error in each channel.
+    pub max_error: Vec<u8>,
+
+    /// The mean error in each channel, over every pixel.
+    pub mean_error: Vec<f64>,
+
+    /// The number of pixels with a channel whose error is more than
+    /// [`DiffOptions::threshold`].
+    pub differing_pixels: u64,
+
+    /// A [`ColorFormat::Gray8`] image the size of the inputs, with the
+    /// largest error of each pixel's channels times the gain, saturating at
+    /// 255. Only made when asked for with [`DiffOptions::error_map`].
+    pub error_map: Option<SquishyPicture>,
+}
+
+/// Compare two images pixel for pixel.
+///
+/// Images of the same format are compared as they are, apart from
+/// [`ColorFormat::Gray4`] images, which are compared as
+/// [`ColorFormat::Gray8`]. Images of different formats are both converted
+/// to [`ColorFormat::Rgba8`] first, as with
+/// [`SquishyPicture::convert_color_format`], so a gray image matches an RGB
+/// one of the same grays, and missing alpha counts as 255.
+///
+/// Fails with [`Error::DimensionMismatch`] if the images aren't the same
+/// size.
+///
+/// # Example
+/// ```
+/// use sqp::{metrics::{diff, DiffOptions}, ColorFormat, SquishyPicture};
+///
+/// let a = SquishyPicture::from_raw_lossless(4, 4, ColorFormat::Gray8, vec![100; 16]);
+/// let mut b = SquishyPicture::from_raw_lossless(4, 4, ColorFormat::Gray8, vec![100; 16]);
+/// b.map_pixels_with_position(|x, y, pixel| if (x, y) == (2, 1) { pixel[0] = 110 });
+///
+/// let result = diff(&a, &b, DiffOptions::new().error_map(10)).unwrap();
+/// assert_eq!(result.max_error, [10]);
+/// assert_eq!(result.differing_pixels, 1);
+/// assert_eq!(result.error_map.unwrap().as_raw()[1 * 4 + 2], 100);
+/// ```
+pub fn diff(a: &SquishyPicture, b: &SquishyPicture, options: DiffOptions) -> Result<DiffResult, Error> {
+    let (width, height) = (a.header.width, a.header.height);
+    if (width, height) != (b.header.width, b.header.height) {
+        return Err(Error::DimensionMismatch {
+            width,
+            height,
+            other_width: b.header.width,
+            other_height: b.header.height,
+        })
+    }
+
+    let color_format = match (a.header.color_format, b.header.color_format) {
+        (ColorFormat::Gray4, ColorFormat::Gray4) => ColorFormat::Gray8,
+        (a, b) if a == b => a,
+        _ => ColorFormat::Rgba8,
+    };
+    let a = bitmap_as(a, color_format);
+    let b = bitmap_as(b, color_format);
+
+    let channels = color_format.pbc();
+    let pixels = width as usize * height as usize;
+    let mut max_error = vec![0; channels];
+    let mut sums = vec![0u64; channels];
+    let mut differing_pixels = 0;
+    let mut map = Vec::with_capacity(if options.gain.is_some() { pixels } else { 0 });
+
+    for (a, b) in a.chunks_exact(channels).zip(b.chunks_exact(channels)) {
+        let mut largest = 0;
+        for (channel, (a, b)) in a.iter().zip(b).enumerate() {
+            let error = a.abs_diff(*b);
+            max_error[channel] = max_error[channel].max(error);
+            sums[channel] += error as u64;
+            largest = largest.max(error);
+        }
+
+        if largest > options.threshold {
+            differing_pixels += 1;
+        }
+        if let Some(gain) = options.gain {
+            map.push((largest as u32 * gain as u32).min(255) as u8);
+        }
+    }
+
+    Ok(DiffResult {
+        color_format,
+        max_error,
+        mean_error: sums.iter().map(|sum| *sum as f64 / pixels.max(1) as f64).collect(),
+        differing_pixels,
+        error_map: options.gain.map(|_| SquishyPicture::from_raw_lossless(width, height, ColorFormat::Gray8, map)),
+    })
+}
+
+/// The bitmap of `picture` in `color_format`, only converted if it isn't
+/// already.
+fn bitmap_as(picture: &SquishyPicture, color_format: ColorFormat) -> Cow<'_, [u8]> {
+    if picture.header.color_format == color_format {
+        Cow::Borrowed(&picture.bitmap)
+    } else {
+        Cow::Owned(picture.convert_color_format(color_format).bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(color_format: ColorFormat) -> SquishyPicture {
+        SquishyPicture::from_fn(24, 16, color_format, |x, y, pixel| {
+            pixel.fill((x * 10 + y) as u8);
+        })
+    }
+
+    #[test]
+    fn identical_images() {
+        for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8, ColorFormat::Gray4] {
+            let result = diff(&gradient(color_format), &gradient(color_format), DiffOptions::new().error_map(255)).unwrap();
+            let channels = result.color_format.pbc();
+
+            assert_eq!(result.max_error, vec![0; channels]);
+            assert_eq!(result.mean_error, vec![0.0; channels]);
+            assert_eq!(result.differing_pixels, 0);
+
+            let map = result.error_map.unwrap();
+            assert_eq!((map.width(), map.height(), map.color_format()), (24, 16, ColorFormat::Gray8));
+            assert!(map.as_raw().iter().all(|v| *v == 0));
+        }
+    }
+
+    #[test]
+    fn single_pixel_located() {
+        let a = gradient(ColorFormat::Rgb8);
+        let mut b = gradient(ColorFormat::Rgb8);
+        b.map_pixels_with_position(|x, y, pixel| {
+            if (x, y) == (17, 9) {
+                pixel[1] = pixel[1].wrapping_add(6);
+            }
+        });
+
+        let result = diff(&a, &b, DiffOptions::new().error_map(3)).unwrap();
+        assert_eq!(result.color_format, ColorFormat::Rgb8);
+        assert_eq!(result.max_error, [0, 6, 0]);
+        assert_eq!(result.mean_error[1], 6.0 / (24.0 * 16.0));
+        assert_eq!(result.differing_pixels, 1);
+
+        let map = result.error_map.unwrap();
+        for (i, value) in map.as_raw().iter().enumerate() {
+            let expected = if i == 9 * 24 + 17 { 18 } else { 0 };
+            assert_eq!(*value, expected, "pixel {i}");
+        }
+
+        // Differences within the threshold aren't counted
+        let result = diff(&a, &b, DiffOptions::new().threshold(6)).unwrap();
+        assert_eq!(result.differing_pixels, 0);
+        assert!(result.error_map.is_none());
+    }
+
+    #[test]
+    fn formats_converted() {
+        // The same grays, with and without color and alpha
+        let gray = gradient(ColorFormat::Gray8);
+        let result = diff(&gray, &gray.convert_color_format(ColorFormat::Rgba8), DiffOptions::new()).unwrap();
+        assert_eq!(result.color_format, ColorFormat::Rgba8);
+        assert_eq!(result.max_error, [0; 4]);
+
+        let transparent = SquishyPicture::from_raw_lossless(24, 16, ColorFormat::GrayA8, vec![0; 24 * 16 * 2]);
+        let result = diff(&transparent, &gray, DiffOptions::new()).unwrap();
+        assert_eq!(result.max_error[3], 255);
+        assert_eq!(result.differing_pixels, 24 * 16);
+    }
+
+    #[test]
+    fn dimensions_must_match() {
+        let a = gradient(ColorFormat::Gray8);
+        let b = SquishyPicture::from_raw_lossless(16, 24, ColorFormat::Gray8, vec![0; 16 * 24]);
+
+        assert!(matches!(
+            diff(&a, &b, DiffOptions::new()),
+            Err(Error::DimensionMismatch { width: 24, height: 16, other_width: 16, other_height: 24 })
+        ));
+    }
+}
@@ -1,93 +1,951 @@
-use crate::ColorFormat;
+use alloc::{collections::VecDeque, vec, vec::Vec};
 
-pub fn sub_rows(width: u32, height: u32, color_format: ColorFormat, input: &[u8]) -> Vec<u8> {
-    let mut data = Vec::with_capacity(width as usize * color_format.pbc());
+use crate::{
+    compression::lossless::{compress_chunk, Backend},
+    math,
+    options::{Filter, FilterStrategy, TransparentCleanup},
+    picture::Error,
+    row_filter,
+    ColorFormat,
+};
 
-    let block_height = f32::ceil(height as f32 / 3.0) as u32;
-    let line_byte_count = (width * color_format.pbc() as u32) as usize;
+/// How many rows [`FilterStrategy::TryAllMeasureCompressed`] chooses one
+/// filter for.
+const TRY_ALL_BAND_ROWS: usize = 16;
 
-    let mut curr_line: Vec<u8>;
-    let mut prev_line: Vec<u8> = Vec::new();
+/// How many rows at the start of each band
+/// [`FilterStrategy::TryAllMeasureCompressed`] compresses with each filter.
+const TRY_ALL_SAMPLE_ROWS: usize = 4;
 
-    let mut i = 0;
-    for y in 0..height {
-        curr_line = input[i..i + line_byte_count].to_vec();
+/// Take the difference of each row from the row above, except for the
+/// first row of each third of the image, overwriting `data` with the
+/// result.
+pub(crate) fn sub_rows_into(
+    width: u32,
+    height: u32,
+    color_format: ColorFormat,
+    input: &[u8],
+    data: &mut Vec<u8>,
+) {
+    // Packed rows are filtered a byte at a time, as if each were a pixel
+    if color_format == ColorFormat::Gray4 {
+        let row_len = color_format.row_len(width) as u32;
+        return sub_rows_into(row_len, height, ColorFormat::Gray8, input, data)
+    }
+
+    let planes = RowPlanes::new(width, height, color_format);
+    let line_len = planes.line_len();
+    let block_height = math::ceil(height as f32 / 3.0) as usize;
 
+    // Each row is filtered and then moved into place while it is still in
+    // the cache, rather than de-interleaving the whole image afterwards
+    data.clear();
+    data.resize(planes.pixel_count() * planes.pbc, 0);
+    let mut filtered = vec![0; line_len];
+    for y in 0..planes.height {
+        let row = &input[y * line_len..][..line_len];
         if y % block_height != 0 {
-            curr_line.iter_mut()
-                .zip(prev_line.iter_mut())
-                .for_each(|(curr, prev)| {
-                    *curr = curr.wrapping_sub(*prev);
-                    *prev = prev.wrapping_add(*curr);
-                });
+            row_filter::up(row, &input[(y - 1) * line_len..][..line_len], &mut filtered);
+            planes.scatter(&filtered, y, data);
         } else {
-            prev_line.clone_from(&curr_line);
+            planes.scatter(row, y, data);
         }
+    }
+}
 
-        data.extend_from_slice(&curr_line);
-        i += line_byte_count;
+/// Reverse [`sub_rows_into`].
+pub fn add_rows(width: u32, height: u32, color_format: ColorFormat, data: &[u8]) -> Vec<u8> {
+    if color_format == ColorFormat::Gray4 {
+        return add_rows(color_format.row_len(width) as u32, height, ColorFormat::Gray8, data)
     }
 
-    if color_format.alpha_channel().is_some() {
-        let (pixels, alpha): (Vec<&[u8]>, Vec<u8>) =
-            data.chunks(color_format.pbc())
-                .map(|i| (
-                    &i[..color_format.pbc() - 1],
-                    i[color_format.alpha_channel().unwrap()]
-                ))
-                .unzip();
+    let planes = RowPlanes::new(width, height, color_format);
+    let line_len = planes.line_len();
+    let block_height = math::ceil(height as f32 / 3.0) as usize;
 
-        pixels.into_iter().flatten().copied().chain(alpha).collect()
-    } else {
-        data
+    let mut output = vec![0; planes.pixel_count() * planes.pbc];
+    for y in 0..planes.height {
+        let (done, rest) = output.split_at_mut(y * line_len);
+        let row = &mut rest[..line_len];
+        planes.gather(data, y, row);
+        if y % block_height != 0 {
+            row_filter::undo_up(row, &done[(y - 1) * line_len..]);
+        }
     }
+
+    output
 }
 
-pub fn add_rows(width: u32, height: u32, color_format: ColorFormat, data: &[u8]) -> Vec<u8> {
-    let mut output_buf = Vec::with_capacity((width * height * color_format.pbc() as u32) as usize);
-
-    let block_height = f32::ceil(height as f32 / 3.0) as u32;
-
-    let mut curr_line: Vec<u8>;
-    let mut prev_line = Vec::new();
-
-    let mut rgb_index = 0;
-    let mut alpha_index = (width * height * (color_format.pbc() - 1) as u32) as usize;
-    for y in 0..height {
-        curr_line = if color_format.alpha_channel().is_some() {
-            // Interleave the offset alpha into the RGB bytes
-            data[rgb_index..rgb_index + width as usize * (color_format.pbc() - 1)]
-                .chunks(color_format.pbc() - 1)
-                .zip(data[alpha_index..alpha_index + width as usize].iter())
-                .flat_map(|(a, b)| {
-                    a.iter().chain(vec![b])
-                })
-                .copied()
-                .collect()
-        } else {
-            data[rgb_index..rgb_index + width as usize * color_format.pbc()].to_vec()
-        };
+/// Filter each row of a bitmap with a [`Filter`] chosen by `strategy`,
+/// overwriting `data` with the ID of each row's filter followed by the
+/// filtered rows.
+///
+/// As with [`sub_rows_into`], alpha is moved after all of the color data. The
+/// samples tried by [`FilterStrategy::TryAllMeasureCompressed`] are
+/// compressed with `backend`.
+pub(crate) fn filter_rows_into(
+    width: u32,
+    height: u32,
+    color_format: ColorFormat,
+    input: &[u8],
+    strategy: FilterStrategy,
+    backend: Backend,
+    data: &mut Vec<u8>,
+) {
+    if color_format == ColorFormat::Gray4 {
+        let row_len = color_format.row_len(width) as u32;
+        return filter_rows_into(row_len, height, ColorFormat::Gray8, input, strategy, backend, data)
+    }
 
-        if y % block_height != 0 {
-            curr_line
-                .iter_mut()
-                .zip(&prev_line)
-                .for_each(|(curr_p, prev_p)| {
-                    *curr_p = curr_p.wrapping_add(*prev_p);
-                });
+    let planes = RowPlanes::new(width, height, color_format);
+    let line_len = planes.line_len();
+    let zeros = vec![0; line_len];
+    let row = |y: usize| &input[y * line_len..][..line_len];
+    let prev_row = |y: usize| if y == 0 { &zeros[..] } else { row(y - 1) };
+
+    data.clear();
+    data.resize(planes.height + planes.pixel_count() * planes.pbc, 0);
+    let (filters, rows) = data.split_at_mut(planes.height);
+
+    let mut filtered = vec![0; line_len];
+    let mut apply = |y: usize, filter: Filter, filtered: &mut [u8]| {
+        filter_row(filter, row(y), prev_row(y), planes.pbc, filtered);
+        filters[y] = filter as u8;
+        planes.scatter(filtered, y, rows);
+    };
+
+    match strategy {
+        FilterStrategy::Fixed(filter) => {
+            for y in 0..planes.height {
+                apply(y, filter, &mut filtered);
+            }
+        },
+        FilterStrategy::MinSumAbs => {
+            let mut candidate = vec![0; line_len];
+            for y in 0..planes.height {
+                let mut best = (Filter::None, u64::MAX);
+                for filter in Filter::ALL {
+                    filter_row(filter, row(y), prev_row(y), planes.pbc, &mut candidate);
+                    let sum = candidate.iter().map(|b| (*b as i8).unsigned_abs() as u64).sum();
+                    if sum < best.1 {
+                        best = (filter, sum);
+                    }
+                }
+
+                apply(y, best.0, &mut filtered);
+            }
+        },
+        FilterStrategy::TryAllMeasureCompressed => {
+            let mut sample = Vec::new();
+            for band in (0..planes.height).step_by(TRY_ALL_BAND_ROWS) {
+                let sample_rows = band..(band + TRY_ALL_SAMPLE_ROWS).min(planes.height);
+                let sample_planes = RowPlanes { height: sample_rows.len(), ..planes };
+
+                let mut best = (Filter::None, usize::MAX);
+                for filter in Filter::ALL {
+                    sample.clear();
+                    sample.resize(sample_planes.pixel_count() * planes.pbc, 0);
+                    for (i, y) in sample_rows.clone().enumerate() {
+                        filter_row(filter, row(y), prev_row(y), planes.pbc, &mut filtered);
+                        sample_planes.scatter(&filtered, i, &mut sample);
+                    }
+
+                    let len = compressed_len(&sample, backend);
+                    if len < best.1 {
+                        best = (filter, len);
+                    }
+                }
+
+                for y in band..(band + TRY_ALL_BAND_ROWS).min(planes.height) {
+                    apply(y, best.0, &mut filtered);
+                }
+            }
+        },
+    }
+
+    debug!(
+        "chose row filters with {:?}, rows using each: {:?}",
+        strategy,
+        Filter::ALL.map(|filter| (filter, filters.iter().filter(|f| **f == filter as u8).count())),
+    );
+}
+
+/// Reverse [`filter_rows_into`], failing if a row has an unknown filter.
+///
+/// `data` must be the length of the bitmap plus one byte for each row.
+pub(crate) fn unfilter_rows(width: u32, height: u32, color_format: ColorFormat, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if color_format == ColorFormat::Gray4 {
+        return unfilter_rows(color_format.row_len(width) as u32, height, ColorFormat::Gray8, data)
+    }
+
+    let planes = RowPlanes::new(width, height, color_format);
+    let line_len = planes.line_len();
+    let (filters, rows) = data.split_at(planes.height);
+
+    let mut output = vec![0; planes.pixel_count() * planes.pbc];
+    let zeros = vec![0; line_len];
+    for (y, filter) in filters.iter().enumerate() {
+        let filter = Filter::try_from(*filter)
+            .map_err(|filter| Error::InvalidRowFilter { row: y as u32, filter })?;
+
+        let (done, rest) = output.split_at_mut(y * line_len);
+        let prev = if y == 0 { &zeros[..] } else { &done[(y - 1) * line_len..] };
+        let row = &mut rest[..line_len];
+
+        planes.gather(rows, y, row);
+        unfilter_row(filter, row, prev, planes.pbc);
+    }
+
+    Ok(output)
+}
+
+/// The layout of filtered rows, with the color of every row followed by
+/// the alpha of every row.
+#[derive(Clone, Copy)]
+struct RowPlanes {
+    width: usize,
+    height: usize,
+    pbc: usize,
+    alpha_channel: Option<usize>,
+}
+
+impl RowPlanes {
+    fn new(width: u32, height: u32, color_format: ColorFormat) -> Self {
+        Self {
+            width: width as usize,
+            height: height as usize,
+            pbc: color_format.pbc(),
+            alpha_channel: color_format.alpha_channel(),
         }
+    }
+
+    fn line_len(&self) -> usize {
+        self.width * self.pbc
+    }
 
-        // Write the decoded RGBA data to the final buffer
-        output_buf.extend_from_slice(&curr_line);
+    fn pixel_count(&self) -> usize {
+        self.width * self.height
+    }
 
-        prev_line.clone_from(&curr_line);
-        rgb_index += if color_format.alpha_channel().is_some() {
-            width as usize * (color_format.pbc() - 1)
-        } else {
-            width as usize * color_format.pbc()
+    fn color_pbc(&self) -> usize {
+        if self.alpha_channel.is_some() { self.pbc - 1 } else { self.pbc }
+    }
+
+    /// The color and alpha parts of row `y` in `planes`.
+    fn split<'a>(&self, planes: &'a mut [u8], y: usize) -> (&'a mut [u8], &'a mut [u8]) {
+        let color_len = self.width * self.color_pbc();
+        let (color, alpha) = planes.split_at_mut(self.pixel_count() * self.color_pbc());
+        let alpha = match self.alpha_channel {
+            Some(_) => &mut alpha[y * self.width..][..self.width],
+            None => &mut [],
         };
-        alpha_index += width as usize;
+
+        (&mut color[y * color_len..][..color_len], alpha)
     }
 
-    output_buf
+    /// Write the interleaved `row` into row `y` of `planes`.
+    fn scatter(&self, row: &[u8], y: usize, planes: &mut [u8]) {
+        let (color, alpha) = self.split(planes, y);
+        match (self.pbc, self.alpha_channel) {
+            (_, None) => color.copy_from_slice(row),
+            (4, Some(3)) => scatter_last_alpha::<4>(row, color, alpha),
+            (2, Some(1)) => scatter_last_alpha::<2>(row, color, alpha),
+            (pbc, Some(a)) => {
+                for ((pixel, color), alpha) in row.chunks_exact(pbc).zip(color.chunks_exact_mut(pbc - 1)).zip(alpha) {
+                    color[..a].copy_from_slice(&pixel[..a]);
+                    color[a..].copy_from_slice(&pixel[a + 1..]);
+                    *alpha = pixel[a];
+                }
+            },
+        }
+    }
+
+    /// Read row `y` of `planes` into the interleaved `row`.
+    fn gather(&self, planes: &[u8], y: usize, row: &mut [u8]) {
+        let color_len = self.width * self.color_pbc();
+        let (color, alpha) = planes.split_at(self.pixel_count() * self.color_pbc());
+        let color = &color[y * color_len..][..color_len];
+        let alpha = match self.alpha_channel {
+            Some(_) => &alpha[y * self.width..][..self.width],
+            None => &[],
+        };
+
+        match (self.pbc, self.alpha_channel) {
+            (_, None) => row.copy_from_slice(color),
+            (4, Some(3)) => gather_last_alpha::<4>(color, alpha, row),
+            (2, Some(1)) => gather_last_alpha::<2>(color, alpha, row),
+            (pbc, Some(a)) => {
+                for ((pixel, color), alpha) in row.chunks_exact_mut(pbc).zip(color.chunks_exact(pbc - 1)).zip(alpha) {
+                    pixel[..a].copy_from_slice(&color[..a]);
+                    pixel[a] = *alpha;
+                    pixel[a + 1..].copy_from_slice(&color[a..]);
+                }
+            },
+        }
+    }
+}
+
+/// [`RowPlanes::scatter`] for pixels of `N` bytes with alpha last, which
+/// the compiler can unroll.
+fn scatter_last_alpha<const N: usize>(row: &[u8], color: &mut [u8], alpha: &mut [u8]) {
+    for ((pixel, color), alpha) in row.chunks_exact(N).zip(color.chunks_exact_mut(N - 1)).zip(alpha) {
+        color.copy_from_slice(&pixel[..N - 1]);
+        *alpha = pixel[N - 1];
+    }
+}
+
+/// [`RowPlanes::gather`] for pixels of `N` bytes with alpha last, which the
+/// compiler can unroll.
+fn gather_last_alpha<const N: usize>(color: &[u8], alpha: &[u8], row: &mut [u8]) {
+    for ((pixel, color), alpha) in row.chunks_exact_mut(N).zip(color.chunks_exact(N - 1)).zip(alpha) {
+        pixel[..N - 1].copy_from_slice(color);
+        pixel[N - 1] = *alpha;
+    }
+}
+
+/// Filter `row`, whose pixels are `bpp` bytes, into `output`. `prev` is
+/// the row above, or zeros for the first row.
+fn filter_row(filter: Filter, row: &[u8], prev: &[u8], bpp: usize, output: &mut [u8]) {
+    match filter {
+        Filter::None => row_filter::none(row, output),
+        Filter::Sub => row_filter::sub_left(row, bpp, output),
+        Filter::Up => row_filter::up(row, prev, output),
+        Filter::Average => row_filter::average_left_up(row, prev, bpp, output),
+        Filter::Paeth => row_filter::paeth(row, prev, bpp, output),
+    }
+}
+
+/// Reverse [`filter_row`] in place.
+fn unfilter_row(filter: Filter, row: &mut [u8], prev: &[u8], bpp: usize) {
+    match filter {
+        Filter::None => (),
+        Filter::Sub => row_filter::undo_sub_left(row, bpp),
+        Filter::Up => row_filter::undo_up(row, prev),
+        Filter::Average => row_filter::undo_average_left_up(row, prev, bpp),
+        Filter::Paeth => row_filter::undo_paeth(row, prev, bpp),
+    }
+}
+
+/// The total length of `data` compressed with `backend`.
+fn compressed_len(mut data: &[u8], backend: Backend) -> usize {
+    let mut len = 0;
+    loop {
+        let (count, compressed) = compress_chunk(data, backend);
+        if count == 0 {
+            return len
+        }
+        len += compressed.len();
+        data = &data[count..];
+    }
+}
+
+/// Pack a [`ColorFormat::Gray8`] bitmap into [`ColorFormat::Gray4`],
+/// rounding each value to the nearest of the 16 levels.
+///
+/// Rows which end in half a byte have the low nibble of their last byte
+/// left as zero.
+pub fn pack_gray4(width: u32, input: &[u8]) -> Vec<u8> {
+    let row_len = ColorFormat::Gray4.row_len(width);
+    let rows = input.chunks_exact(width.max(1) as usize);
+
+    let mut output = Vec::with_capacity(rows.len() * row_len);
+    for row in rows {
+        output.extend(row.chunks(2).map(|pair| {
+            let high = (pair[0] as u32 + 8) / 17;
+            let low = pair.get(1).map_or(0, |v| (*v as u32 + 8) / 17);
+            (high << 4 | low) as u8
+        }));
+    }
+
+    output
+}
+
+/// Unpack a [`ColorFormat::Gray4`] bitmap into [`ColorFormat::Gray8`],
+/// spreading the 16 levels evenly from 0 to 255.
+///
+/// The padding at the end of each row is left out.
+pub fn unpack_gray4(width: u32, input: &[u8]) -> Vec<u8> {
+    let row_len = ColorFormat::Gray4.row_len(width);
+    if row_len == 0 {
+        return Vec::new()
+    }
+
+    let rows = input.chunks_exact(row_len);
+    let mut output = Vec::with_capacity(rows.len() * width as usize);
+    for row in rows {
+        let levels = row.iter().flat_map(|byte| [byte >> 4, byte & 0x0F]);
+        output.extend(levels.take(width as usize).map(|level| level * 17));
+    }
+
+    output
+}
+
+/// Convert a bitmap from one [`ColorFormat`] to another.
+///
+/// Packed [`ColorFormat::Gray4`] bitmaps aren't handled here, convert them
+/// with [`pack_gray4`] and [`unpack_gray4`].
+///
+/// Alpha is dropped when the target has none, and filled with 255 when the
+/// source has none. Color is converted to gray using the Rec. 601 luma
+/// weights, and gray is replicated into every color channel.
+pub fn convert_color_format(from: ColorFormat, to: ColorFormat, input: &[u8]) -> Vec<u8> {
+    if from == to {
+        return input.to_vec()
+    }
+
+    let mut output = vec![0; (input.len() / from.pbc()) * to.pbc()];
+    for (pixel, out) in input.chunks_exact(from.pbc()).zip(output.chunks_exact_mut(to.pbc())) {
+        from_rgba(to, to_rgba(from, pixel), out);
+    }
+
+    output
+}
+
+/// Convert an `Rgb8` bitmap to `Rgba8`, with an alpha of 0 for pixels which
+/// are exactly `key` and 255 for the rest.
+pub fn expand_color_key(input: &[u8], key: [u8; 3]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 3 * 4);
+    for pixel in input.chunks_exact(3) {
+        let alpha = if pixel == key { 0 } else { 255 };
+        output.extend_from_slice(&[pixel[0], pixel[1], pixel[2], alpha]);
+    }
+
+    output
+}
+
+/// Expand a bitmap in any [`ColorFormat`] to `Rgba8` in a single pass, a
+/// row at a time.
+///
+/// Gray is replicated into every color channel and missing alpha is filled
+/// with 255, apart from `Rgb8` pixels which are exactly `key`, which get an
+/// alpha of 0. Packed [`ColorFormat::Gray4`] rows are unpacked as they go,
+/// spreading the levels evenly from 0 to 255.
+pub fn expand_to_rgba8(width: u32, color_format: ColorFormat, key: Option<[u8; 3]>, input: &[u8]) -> Vec<u8> {
+    let row_len = color_format.row_len(width);
+    if row_len == 0 {
+        return Vec::new()
+    }
+
+    let rows = input.chunks_exact(row_len);
+    let mut output = Vec::with_capacity(rows.len() * width as usize * 4);
+    for row in rows {
+        if color_format == ColorFormat::Gray4 {
+            let levels = row.iter().flat_map(|byte| [byte >> 4, byte & 0x0F]);
+            for level in levels.take(width as usize) {
+                let value = level * 17;
+                output.extend_from_slice(&[value, value, value, 0xFF]);
+            }
+
+            continue
+        }
+
+        for pixel in row.chunks_exact(color_format.pbc()) {
+            let mut rgba = to_rgba(color_format, pixel);
+            if key.is_some_and(|key| color_format == ColorFormat::Rgb8 && pixel == key) {
+                rgba[3] = 0;
+            }
+
+            output.extend_from_slice(&rgba);
+        }
+    }
+
+    output
+}
+
+/// Rewrite the color of every pixel with an alpha of 0 as `cleanup` says,
+/// leaving every other pixel and all of the alpha as it is. Formats
+/// without alpha are left alone.
+pub fn clean_transparent(width: u32, color_format: ColorFormat, cleanup: TransparentCleanup, data: &mut [u8]) {
+    let Some(alpha) = color_format.alpha_channel() else {
+        return
+    };
+    let channels = color_format.pbc();
+
+    let value = match cleanup {
+        TransparentCleanup::Constant(value) => value,
+        TransparentCleanup::Bleed => {
+            if bleed_transparent(width as usize, channels, alpha, data) {
+                return
+            }
+
+            // Nothing can be seen to take the color from
+            0
+        }
+    };
+
+    data.chunks_exact_mut(channels)
+        .filter(|pixel| pixel[alpha] == 0)
+        .for_each(|pixel| pixel[..alpha].fill(value));
+}
+
+/// Fill in the color of each pixel with an alpha of 0 from the nearest one
+/// which isn't, in a breadth first search out from all of those at once.
+/// Alpha must be the last channel.
+///
+/// Returns false without changing anything if every pixel is transparent.
+fn bleed_transparent(width: usize, channels: usize, alpha: usize, data: &mut [u8]) -> bool {
+    let len = data.len() / channels;
+    let mut filled: Vec<bool> = data.chunks_exact(channels).map(|pixel| pixel[alpha] != 0).collect();
+    let mut queue: VecDeque<usize> = (0..len).filter(|i| filled[*i]).collect();
+    if queue.is_empty() {
+        return false
+    }
+
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = (i % width, i / width);
+        let neighbours = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < width).then(|| i + 1),
+            (y > 0).then(|| i - width),
+            (i + width < len).then(|| i + width),
+        ];
+
+        for n in neighbours.into_iter().flatten() {
+            if !filled[n] {
+                filled[n] = true;
+                data.copy_within(i * channels..i * channels + alpha, n * channels);
+                queue.push_back(n);
+            }
+        }
+    }
+
+    true
+}
+
+/// Shrink a bitmap by `denominator` on each side, averaging each square of
+/// pixels into one.
+///
+/// The output is `width / denominator` by `height / denominator`, rounded
+/// up. Squares on the right and bottom edges which are cut off by the image
+/// average just the pixels they cover. Every channel, including alpha, is
+/// averaged on its own. Packed [`ColorFormat::Gray4`] bitmaps must be
+/// unpacked first.
+pub fn downscale(width: u32, height: u32, color_format: ColorFormat, denominator: u32, input: &[u8]) -> Vec<u8> {
+    let (width, height, denominator) = (width as usize, height as usize, denominator.max(1) as usize);
+    let channels = color_format.pbc();
+    let (new_width, new_height) = (width.div_ceil(denominator), height.div_ceil(denominator));
+
+    let mut output = vec![0; new_width * new_height * channels];
+    let mut sums = vec![0u32; new_width * channels];
+    for (out_row, rows) in output.chunks_exact_mut(new_width * channels).zip(input.chunks(width * denominator * channels)) {
+        sums.fill(0);
+        let row_count = rows.len() / (width * channels);
+
+        for row in rows.chunks_exact(width * channels) {
+            for (x, pixel) in row.chunks_exact(channels).enumerate() {
+                let sum = &mut sums[(x / denominator) * channels..][..channels];
+                sum.iter_mut().zip(pixel).for_each(|(s, p)| *s += *p as u32);
+            }
+        }
+
+        for (x, (out, sum)) in out_row.chunks_exact_mut(channels).zip(sums.chunks_exact(channels)).enumerate() {
+            let count = ((width - x * denominator).min(denominator) * row_count) as u32;
+            out.iter_mut().zip(sum).for_each(|(o, s)| *o = ((s + count / 2) / count) as u8);
+        }
+    }
+
+    output
+}
+
+/// Alpha blend the `src` bitmap over the `dst` bitmap with its top left
+/// corner at `x` and `y`, using straight alpha "over" compositing.
+///
+/// Each pixel is converted to RGBA to be blended, so the formats don't need
+/// to match. Anything which falls outside of `dst` is left out. Packed
+/// [`ColorFormat::Gray4`] bitmaps must be unpacked first.
+#[allow(clippy::too_many_arguments)]
+pub fn overlay(
+    dst: &mut [u8],
+    dst_width: u32,
+    dst_format: ColorFormat,
+    src: &[u8],
+    src_width: u32,
+    src_format: ColorFormat,
+    x: u32,
+    y: u32,
+) {
+    let dst_height = dst.len().checked_div(dst_width as usize * dst_format.pbc()).unwrap_or(0);
+    let src_height = src.len().checked_div(src_width as usize * src_format.pbc()).unwrap_or(0);
+
+    let width = (src_width as usize).min((dst_width as usize).saturating_sub(x as usize));
+    let height = src_height.min(dst_height.saturating_sub(y as usize));
+    if width == 0 || height == 0 {
+        return
+    }
+
+    let dst_rows = dst.chunks_exact_mut(dst_width as usize * dst_format.pbc()).skip(y as usize);
+    let src_rows = src.chunks_exact(src_width as usize * src_format.pbc());
+    for (dst_row, src_row) in dst_rows.zip(src_rows).take(height) {
+        let dst_pixels = dst_row[x as usize * dst_format.pbc()..].chunks_exact_mut(dst_format.pbc());
+        let src_pixels = src_row.chunks_exact(src_format.pbc());
+
+        for (dst_pixel, src_pixel) in dst_pixels.zip(src_pixels).take(width) {
+            let source = to_rgba(src_format, src_pixel);
+            match source[3] {
+                0 => (),
+                0xFF => from_rgba(dst_format, source, dst_pixel),
+                _ => {
+                    let blended = blend(source, to_rgba(dst_format, dst_pixel));
+                    from_rgba(dst_format, blended, dst_pixel)
+                }
+            }
+        }
+    }
+}
+
+/// Straight alpha "over" compositing of one RGBA pixel onto another.
+fn blend(src: [u8; 4], dst: [u8; 4]) -> [u8; 4] {
+    let src_alpha = src[3] as u32;
+
+    // Both alphas are scaled by 255 so they can be combined exactly
+    let dst_weight = dst[3] as u32 * (0xFF - src_alpha);
+    let out_alpha = src_alpha * 0xFF + dst_weight;
+    if out_alpha == 0 {
+        return [0; 4]
+    }
+
+    let channel = |s: u8, d: u8| {
+        ((s as u32 * src_alpha * 0xFF + d as u32 * dst_weight + out_alpha / 2) / out_alpha) as u8
+    };
+
+    [
+        channel(src[0], dst[0]),
+        channel(src[1], dst[1]),
+        channel(src[2], dst[2]),
+        ((out_alpha + 0x7F) / 0xFF) as u8,
+    ]
+}
+
+/// Read a pixel in the given format as RGBA.
+///
+/// A `Gray4` pixel on its own is an unpacked 8 bit value, like `Gray8`.
+fn to_rgba(format: ColorFormat, pixel: &[u8]) -> [u8; 4] {
+    match format {
+        ColorFormat::Rgba8 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        ColorFormat::Rgb8 => [pixel[0], pixel[1], pixel[2], 0xFF],
+        ColorFormat::GrayA8 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+        ColorFormat::Gray8 | ColorFormat::Gray4 => [pixel[0], pixel[0], pixel[0], 0xFF],
+    }
+}
+
+/// Write an RGBA pixel into a pixel of the given format.
+fn from_rgba(format: ColorFormat, [r, g, b, a]: [u8; 4], output: &mut [u8]) {
+    match format {
+        ColorFormat::Rgba8 => output.copy_from_slice(&[r, g, b, a]),
+        ColorFormat::Rgb8 => output.copy_from_slice(&[r, g, b]),
+        ColorFormat::GrayA8 => output.copy_from_slice(&[luma(r, g, b), a]),
+        ColorFormat::Gray8 | ColorFormat::Gray4 => output[0] = luma(r, g, b),
+    }
+}
+
+/// Rec. 601 luma of an RGB triple, in fixed point.
+pub(crate) fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32 + 128) >> 8) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_sum_abs_filter_choice() {
+        // Every row is the same ramp, so the first row is best taken from
+        // the left and the rest from above
+        let ramp: Vec<u8> = (0..8).flat_map(|_| 0..16).collect();
+        let mut data = Vec::new();
+        filter_rows_into(16, 8, ColorFormat::Gray8, &ramp, FilterStrategy::MinSumAbs, Backend::Lzw, &mut data);
+
+        assert_eq!(data[..8], [1, 2, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(data[8..24], [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert!(data[24..].iter().all(|b| *b == 0));
+        assert_eq!(unfilter_rows(16, 8, ColorFormat::Gray8, &data).unwrap(), ramp);
+    }
+
+    #[test]
+    fn filters_round_trip() {
+        let mut state = 0x2545F491u32;
+        let input: Vec<u8> = (0..13 * 5 * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+
+        for filter in Filter::ALL {
+            for color_format in [ColorFormat::Rgba8, ColorFormat::GrayA8, ColorFormat::Gray4] {
+                let len = color_format.bitmap_len(13, 5).unwrap();
+                let mut data = Vec::new();
+                filter_rows_into(13, 5, color_format, &input[..len], FilterStrategy::Fixed(filter), Backend::Lzw, &mut data);
+
+                assert_eq!(data.len(), len + 5);
+                assert!(data[..5].iter().all(|f| *f == filter as u8));
+                assert_eq!(unfilter_rows(13, 5, color_format, &data).unwrap(), input[..len], "{filter:?} {color_format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn clean_transparent_modes() {
+        // Two colors with junk between them, a partly transparent pixel,
+        // and a row below which is all transparent
+        let input = [
+            10, 20, 30, 255,  1, 2, 3, 0,  4, 5, 6, 0,  7, 8, 9, 0,  50, 60, 70, 128,
+            11, 12, 13, 0,    14, 15, 16, 0,  17, 18, 19, 0,  21, 22, 23, 0,  24, 25, 26, 0,
+        ];
+
+        let mut constant = input;
+        clean_transparent(5, ColorFormat::Rgba8, TransparentCleanup::Constant(0), &mut constant);
+        for (pixel, original) in constant.chunks_exact(4).zip(input.chunks_exact(4)) {
+            let expected = if original[3] == 0 { [0, 0, 0, 0] } else { original.try_into().unwrap() };
+            assert_eq!(pixel, expected);
+        }
+
+        // Ties go to the pixel found first, which is the one to the left
+        let mut bled = input;
+        clean_transparent(5, ColorFormat::Rgba8, TransparentCleanup::Bleed, &mut bled);
+        let (left, right) = ([10, 20, 30], [50, 60, 70]);
+        let expected: Vec<[u8; 3]> = [left, left, left, right, right, left, left, left, right, right].into();
+        for (i, (pixel, original)) in bled.chunks_exact(4).zip(input.chunks_exact(4)).enumerate() {
+            assert_eq!(pixel[..3], expected[i], "pixel {i}");
+            assert_eq!(pixel[3], original[3]);
+        }
+
+        // Nothing to bleed from
+        let mut invisible = [9, 0, 9, 0];
+        clean_transparent(2, ColorFormat::GrayA8, TransparentCleanup::Bleed, &mut invisible);
+        assert_eq!(invisible, [0, 0, 0, 0]);
+
+        let mut gray = [200, 255, 9, 0, 7, 1];
+        clean_transparent(3, ColorFormat::GrayA8, TransparentCleanup::Bleed, &mut gray);
+        assert_eq!(gray, [200, 255, 200, 0, 7, 1]);
+
+        // No alpha, nothing to do
+        let mut rgb = [1, 2, 3, 4, 5, 6];
+        clean_transparent(2, ColorFormat::Rgb8, TransparentCleanup::Constant(0), &mut rgb);
+        assert_eq!(rgb, [1, 2, 3, 4, 5, 6]);
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545F491u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    type Kernel = fn(&[u8], &[u8], usize, &mut [u8]);
+    type Undo = fn(&mut [u8], &[u8], usize);
+
+    #[test]
+    fn row_filter_kernels_match_scalar() {
+        use row_filter::scalar;
+
+        // Every pixel size, and row lengths on either side of whole words
+        for bpp in 1..=6 {
+            for len in (0..=40).filter(|len| len % bpp == 0) {
+                let (row, prev) = (noise(len * 2), noise(len * 2 + 1));
+                let (row, prev) = (&row[len..], &prev[len + 1..]);
+                let kernels: [(&str, Kernel, Kernel); 4] = [
+                    ("Sub", |r, _, b, o| row_filter::sub_left(r, b, o), |r, _, b, o| scalar::sub_left(r, b, o)),
+                    ("Up", |r, p, _, o| row_filter::up(r, p, o), |r, p, _, o| scalar::up(r, p, o)),
+                    ("Average", row_filter::average_left_up, scalar::average_left_up),
+                    ("Paeth", row_filter::paeth, scalar::paeth),
+                ];
+
+                for (name, fast, reference) in kernels {
+                    let mut expected = vec![0; len];
+                    reference(row, prev, bpp, &mut expected);
+                    let mut filtered = vec![0; len];
+                    fast(row, prev, bpp, &mut filtered);
+                    assert_eq!(filtered, expected, "{name} bpp {bpp} len {len}");
+                }
+
+                let undos: [(&str, Undo, Undo); 4] = [
+                    ("Sub", |r, _, b| row_filter::undo_sub_left(r, b), |r, _, b| scalar::undo_sub_left(r, b)),
+                    ("Up", |r, p, _| row_filter::undo_up(r, p), |r, p, _| scalar::undo_up(r, p)),
+                    ("Average", row_filter::undo_average_left_up, scalar::undo_average_left_up),
+                    ("Paeth", row_filter::undo_paeth, scalar::undo_paeth),
+                ];
+
+                for (name, fast, reference) in undos {
+                    let mut expected = row.to_vec();
+                    reference(&mut expected, prev, bpp);
+                    let mut undone = row.to_vec();
+                    fast(&mut undone, prev, bpp);
+                    assert_eq!(undone, expected, "undo {name} bpp {bpp} len {len}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sub_rows_match_scalar() {
+        for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8, ColorFormat::Gray4] {
+            for width in 1..=17 {
+                let height = 7;
+                let input = noise(color_format.bitmap_len(width, height).unwrap());
+
+                // Byte at a time, as rows were always filtered before
+                let (width, color_format) = match color_format {
+                    ColorFormat::Gray4 => (color_format.row_len(width) as u32, ColorFormat::Gray8),
+                    _ => (width, color_format),
+                };
+                let (pbc, alpha) = (color_format.pbc(), color_format.alpha_channel());
+                let line_len = width as usize * pbc;
+                let mut color = Vec::new();
+                let mut alphas = Vec::new();
+                for y in 0..height as usize {
+                    for (i, value) in input[y * line_len..][..line_len].iter().enumerate() {
+                        let value = match y % 3 {
+                            0 => *value,
+                            _ => value.wrapping_sub(input[(y - 1) * line_len + i]),
+                        };
+                        if Some(i % pbc) == alpha { alphas.push(value) } else { color.push(value) }
+                    }
+                }
+                color.extend(alphas);
+
+                let mut filtered = Vec::new();
+                sub_rows_into(width, height, color_format, &input, &mut filtered);
+                assert_eq!(filtered, color, "{color_format:?} {width}");
+                assert_eq!(add_rows(width, height, color_format, &filtered), input);
+            }
+        }
+    }
+
+    #[test]
+    fn convert_rgba_to_gray() {
+        let result = convert_color_format(
+            ColorFormat::Rgba8,
+            ColorFormat::Gray8,
+            &[
+                0xFF, 0xFF, 0xFF, 0x80,
+                0xFF, 0x00, 0x00, 0xFF,
+                0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+
+        assert_eq!(result, [0xFF, 0x4D, 0x00]);
+    }
+
+    #[test]
+    fn convert_gray_to_rgba() {
+        let result = convert_color_format(
+            ColorFormat::Gray8,
+            ColorFormat::Rgba8,
+            &[0x10, 0xF0]
+        );
+
+        assert_eq!(result, [0x10, 0x10, 0x10, 0xFF, 0xF0, 0xF0, 0xF0, 0xFF]);
+    }
+
+    #[test]
+    fn blend_known_results() {
+        let red = [0xFF, 0x00, 0x00, 0xFF];
+        let white = |alpha| [0xFF, 0xFF, 0xFF, alpha];
+
+        assert_eq!(blend(white(127), red), [0xFF, 0x7F, 0x7F, 0xFF]);
+        assert_eq!(blend(white(1), red), [0xFF, 0x01, 0x01, 0xFF]);
+        assert_eq!(blend(white(254), red), [0xFF, 0xFE, 0xFE, 0xFF]);
+
+        // Onto transparent or partly transparent pixels
+        assert_eq!(blend([200, 100, 50, 127], [0; 4]), [200, 100, 50, 127]);
+        assert_eq!(blend([0xFF, 0x00, 0x00, 127], [0x00, 0x00, 0xFF, 127]), [0xAA, 0x00, 0x55, 0xBF]);
+        assert_eq!(blend([0; 4], [0; 4]), [0; 4]);
+    }
+
+    #[test]
+    fn overlay_alpha_levels() {
+        for (alpha, expected) in [
+            (0, [0xFF, 0x00, 0x00, 0xFF]),
+            (127, [0xFF, 0x7F, 0x7F, 0xFF]),
+            (255, [0xFF, 0xFF, 0xFF, 0xFF]),
+        ] {
+            let mut dst = [0xFF, 0x00, 0x00, 0xFF];
+            overlay(&mut dst, 1, ColorFormat::Rgba8, &[0xFF, 0xFF, 0xFF, alpha], 1, ColorFormat::Rgba8, 0, 0);
+            assert_eq!(dst, expected);
+        }
+
+        // Different formats on each side
+        let mut dst = [0x00, 0x00, 0x00];
+        overlay(&mut dst, 1, ColorFormat::Rgb8, &[0xFF, 127], 1, ColorFormat::GrayA8, 0, 0);
+        assert_eq!(dst, [0x7F, 0x7F, 0x7F]);
+
+        let mut dst = [0x00];
+        overlay(&mut dst, 1, ColorFormat::Gray8, &[0xFF, 0x00, 0x00, 0xFF], 1, ColorFormat::Rgba8, 0, 0);
+        assert_eq!(dst, [luma(0xFF, 0x00, 0x00)]);
+    }
+
+    #[test]
+    fn overlay_clipped() {
+        let src = [1; 3 * 3];
+
+        // Hanging off the bottom right corner
+        let mut dst = [0; 4 * 4];
+        overlay(&mut dst, 4, ColorFormat::Gray8, &src, 3, ColorFormat::Gray8, 2, 2);
+        assert_eq!(dst, [
+            0, 0, 0, 0,
+            0, 0, 0, 0,
+            0, 0, 1, 1,
+            0, 0, 1, 1,
+        ]);
+
+        // Entirely outside
+        let mut dst = [0; 4 * 4];
+        overlay(&mut dst, 4, ColorFormat::Gray8, &src, 3, ColorFormat::Gray8, 4, 0);
+        overlay(&mut dst, 4, ColorFormat::Gray8, &src, 3, ColorFormat::Gray8, 0, 10);
+        overlay(&mut dst, 4, ColorFormat::Gray8, &src, 3, ColorFormat::Gray8, u32::MAX, u32::MAX);
+        assert_eq!(dst, [0; 4 * 4]);
+
+        // Larger than the destination
+        let mut dst = [0; 2 * 2];
+        overlay(&mut dst, 2, ColorFormat::Gray8, &src, 3, ColorFormat::Gray8, 1, 0);
+        assert_eq!(dst, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn downscale_averages() {
+        let input = [
+            0, 2, 4, 6, 8,
+            2, 4, 6, 8, 10,
+            9, 9, 9, 9, 9,
+        ];
+
+        assert_eq!(downscale(5, 3, ColorFormat::Gray8, 1, &input), input);
+        assert_eq!(downscale(5, 3, ColorFormat::Gray8, 2, &input), [2, 6, 9, 9, 9, 9]);
+        assert_eq!(downscale(5, 3, ColorFormat::Gray8, 8, &input), [6]);
+
+        // Each channel on its own
+        let input = [10, 255, 20, 0, 30, 255, 41, 0];
+        assert_eq!(downscale(2, 2, ColorFormat::GrayA8, 2, &input), [25, 128]);
+    }
+
+    #[test]
+    fn pack_gray4_rows() {
+        // Values round to the nearest level, and odd rows end in padding
+        let gray = [0, 8, 9, 255, 17, 0, 127, 128, 136];
+        let packed = pack_gray4(3, &gray);
+        assert_eq!(packed, [0x00, 0x10, 0xF1, 0x00, 0x78, 0x80]);
+
+        assert_eq!(unpack_gray4(3, &packed), [0, 0, 17, 255, 17, 0, 119, 136, 136]);
+
+        // Padding is ignored when unpacking
+        assert_eq!(unpack_gray4(1, &[0x3F, 0xA5]), [51, 170]);
+        assert!(unpack_gray4(0, &[]).is_empty());
+    }
+
+    #[test]
+    fn gray4_rows_filter_whole_bytes() {
+        for width in [1, 3, 7, 8] {
+            let row_len = ColorFormat::Gray4.row_len(width);
+            let packed: Vec<u8> = (0..row_len * 5).map(|i| (i * 37) as u8).collect();
+
+            let mut filtered = Vec::new();
+            sub_rows_into(width, 5, ColorFormat::Gray4, &packed, &mut filtered);
+            assert_eq!(filtered.len(), packed.len());
+            assert_eq!(add_rows(width, 5, ColorFormat::Gray4, &filtered), packed);
+        }
+    }
 }
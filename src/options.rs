@@ -0,0 +1,591 @@
+//! Options which change how a [`SquishyPicture`] is encoded or decoded.
+//!
+//! [`SquishyPicture`]: crate::SquishyPicture
+
+use alloc::string::String;
+
+#[cfg(feature = "parallel")]
+use alloc::sync::Arc;
+
+use crate::{header::Header, lossless::Dictionary, picture::Error};
+
+/// Options for [`SquishyPicture::encode_with_options`].
+///
+/// The defaults produce the same output as [`SquishyPicture::encode`].
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use sqp::{options::EncodeOptions, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossy(64, 64, ColorFormat::Rgba8, 80, vec![0; 64 * 64 * 4]);
+///
+/// let mut encoded = Vec::new();
+/// sqp.encode_with_options(&mut encoded, &EncodeOptions::new().low_memory(true)).unwrap();
+///
+/// assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+/// # }
+/// ```
+///
+/// [`SquishyPicture::encode_with_options`]: crate::SquishyPicture::encode_with_options
+/// [`SquishyPicture::encode`]: crate::SquishyPicture::encode
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    pub(crate) low_memory: bool,
+    pub(crate) auto_optimize_format: bool,
+    pub(crate) target_psnr: Option<f64>,
+    pub(crate) max_encoded_size: Option<usize>,
+    pub(crate) encoder_name: Option<String>,
+    pub(crate) omit_encoder_info: bool,
+    pub(crate) filter_strategy: Option<FilterStrategy>,
+    pub(crate) clean_transparent: Option<TransparentCleanup>,
+    pub(crate) threads: Threads,
+    #[cfg(feature = "content-hash")]
+    pub(crate) content_hash: bool,
+    pub(crate) shared_dictionary: Option<Dictionary>,
+}
+
+impl EncodeOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lower peak memory use at the cost of encoding more slowly.
+    ///
+    /// Normally every channel of a lossy image is transformed at once, and
+    /// the whole compressed image is built up before anything is written.
+    /// In low memory mode, channels are transformed one at a time on the
+    /// calling thread, and the output is written one chunk at a time as in
+    /// [`SquishyPicture::encode_streaming`], which compresses the image
+    /// twice. The output is the same either way. Tiled lossy images still
+    /// transform every channel at once, as each tile holds all of them.
+    ///
+    /// Peak memory use on top of the image itself, as a multiple of the raw
+    /// image size, is roughly:
+    ///
+    /// | Compression | Default | Low memory |
+    /// |-------------|---------|------------|
+    /// | Lossless    | 3–4.5×  | 2.3–2.8×   |
+    /// | Lossy       | 5×      | 3.8–4.3×   |
+    ///
+    /// Much of what remains is the compression dictionary, which grows with
+    /// the size of each chunk rather than the image.
+    ///
+    /// [`SquishyPicture::encode_streaming`]: crate::SquishyPicture::encode_streaming
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    /// Store the image in a smaller [`ColorFormat`] when that loses
+    /// nothing. Off by default.
+    ///
+    /// Before compressing, the image is checked with
+    /// [`SquishyPicture::analyze_content`]. An alpha channel which is 255
+    /// everywhere is dropped, and color channels which are identical in
+    /// every pixel are stored as gray. The header records the smaller
+    /// format, so decoding the image gives back a picture in that format.
+    ///
+    /// Only the exact input is checked, so a single pixel with an alpha of
+    /// 254 keeps the alpha channel. Images with a transparent color are
+    /// left in their format, as gray can't store it.
+    ///
+    /// [`ColorFormat`]: crate::ColorFormat
+    /// [`SquishyPicture::analyze_content`]: crate::SquishyPicture::analyze_content
+    pub fn auto_optimize_format(mut self, auto_optimize_format: bool) -> Self {
+        self.auto_optimize_format = auto_optimize_format;
+        self
+    }
+
+    /// Choose the quality of lossy images automatically, picking the lowest
+    /// one whose peak signal to noise ratio is at least `psnr` decibels.
+    /// Has no effect on other images.
+    ///
+    /// Candidate qualities are found with a binary search, transforming
+    /// and restoring the image in memory for each one, so expect encoding
+    /// to take several times longer. The chosen quality is written to the
+    /// header in place of the picture's own, and is reported along with
+    /// the PSNR it reached by [`SquishyPicture::encode_with_stats`]. If
+    /// even the highest quality falls short of the target, that is used.
+    ///
+    /// [`SquishyPicture::encode_with_stats`]: crate::SquishyPicture::encode_with_stats
+    pub fn target_psnr(mut self, psnr: f64) -> Self {
+        self.target_psnr = Some(psnr);
+        self
+    }
+
+    /// Make sure the encoded image is at most `max_size` bytes.
+    ///
+    /// For lossy images, the quality is lowered as far as needed with a
+    /// binary search. The image is transformed once, and each candidate
+    /// quality is quantized and compressed to measure it without keeping
+    /// the output. This takes memory for the unquantized transform, about
+    /// four times the coefficients themselves, and time for several
+    /// compressions on top of the real one.
+    ///
+    /// Fails with [`Error::CannotMeetSizeTarget`] if the image can't fit,
+    /// even at the lowest quality for lossy images. Nothing is written in
+    /// that case.
+    ///
+    /// [`Error::CannotMeetSizeTarget`]: crate::picture::Error::CannotMeetSizeTarget
+    pub fn max_encoded_size(mut self, max_size: usize) -> Self {
+        self.max_encoded_size = Some(max_size);
+        self
+    }
+
+    /// Name the application writing the image, such as `"myapp 1.2"`, in
+    /// the encoder info stored with it.
+    ///
+    /// The encoder info always gives the version of this crate, and the
+    /// name is put in front of it, as in `"myapp 1.2 (sqp 0.1.1)"`. The
+    /// whole record is kept within 256 bytes, so a longer name is cut
+    /// short. Decoders read it back with [`SquishyPicture::encoder_info`].
+    ///
+    /// [`SquishyPicture::encoder_info`]: crate::SquishyPicture::encoder_info
+    pub fn encoder_name(mut self, name: impl Into<String>) -> Self {
+        self.encoder_name = Some(name.into());
+        self
+    }
+
+    /// Leave out the encoder info, so the output doesn't change between
+    /// versions of this crate, for reproducible builds. Off by default.
+    ///
+    /// Only images from format version 3 on can store encoder info, so it
+    /// is always left out of older versions.
+    pub fn omit_encoder_info(mut self, omit_encoder_info: bool) -> Self {
+        self.omit_encoder_info = omit_encoder_info;
+        self
+    }
+
+    /// Choose a filter for each row of a lossless image, rather than taking
+    /// the difference from the row above everywhere. Off by default.
+    ///
+    /// The filter for each row is stored in a byte before the image data,
+    /// which needs format version 7, so older images are upgraded. Images
+    /// which aren't [`CompressionType::Lossless`], [`LosslessV2`] or
+    /// [`LosslessBwt`] are unaffected. Images decoded with chosen filters
+    /// keep choosing them when encoded again, with
+    /// [`FilterStrategy::MinSumAbs`] unless told otherwise.
+    ///
+    /// [`CompressionType::Lossless`]: crate::CompressionType::Lossless
+    /// [`LosslessV2`]: crate::CompressionType::LosslessV2
+    /// [`LosslessBwt`]: crate::CompressionType::LosslessBwt
+    pub fn filter_strategy(mut self, strategy: FilterStrategy) -> Self {
+        self.filter_strategy = Some(strategy);
+        self
+    }
+
+    /// Rewrite the color of fully transparent pixels so it compresses
+    /// better, before anything else is done to the image. Off by default.
+    ///
+    /// Pixels with an alpha of 0 often hold leftover color which can't be
+    /// seen but still has to be filtered, transformed and compressed. This
+    /// replaces it as `cleanup` says. Only pixels with an alpha of exactly
+    /// 0 are changed, so the image looks the same, but **their color isn't
+    /// preserved**: decoding gives back different bytes under them.
+    /// Formats without an alpha channel are left alone, including images
+    /// with a transparent color.
+    pub fn clean_transparent(mut self, cleanup: TransparentCleanup) -> Self {
+        self.clean_transparent = Some(cleanup);
+        self
+    }
+
+    /// Choose the threads which the parallel parts of encoding run on.
+    /// Defaults to [`Threads::Global`].
+    ///
+    /// The output is the same whichever threads are used.
+    pub fn threads(mut self, threads: Threads) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Store an xxHash64 of the raw bitmap, so decoders can check that they
+    /// reproduced the exact pixels which were encoded. Off by default.
+    ///
+    /// The hash is of the bitmap as it is encoded, after
+    /// [`EncodeOptions::auto_optimize_format`] and
+    /// [`EncodeOptions::clean_transparent`], and before any filtering or
+    /// transform. Hashing costs one pass over the bitmap. Like the encoder
+    /// info, it needs format version 3 or later, and is left out of older
+    /// versions.
+    ///
+    /// See [`SquishyPicture::verify_content`] and
+    /// [`SquishyPicture::read_content_hash`].
+    ///
+    /// [`SquishyPicture::verify_content`]: crate::SquishyPicture::verify_content
+    /// [`SquishyPicture::read_content_hash`]: crate::SquishyPicture::read_content_hash
+    #[cfg(feature = "content-hash")]
+    pub fn content_hash(mut self, content_hash: bool) -> Self {
+        self.content_hash = content_hash;
+        self
+    }
+
+    /// Start compressing from a [`Dictionary`] trained on related images,
+    /// so small images don't each have to build up the same strings.
+    ///
+    /// Only [`CompressionType::Lossless`] images from format version 3 on
+    /// use the dictionary, other images are encoded as they would be
+    /// without it. The file stores the dictionary's [`Dictionary::id`], and
+    /// can only be decoded with the same dictionary given to
+    /// [`DecodeOptions::shared_dictionary`].
+    ///
+    /// [`CompressionType::Lossless`]: crate::CompressionType::Lossless
+    pub fn shared_dictionary(mut self, dictionary: &Dictionary) -> Self {
+        self.shared_dictionary = Some(dictionary.clone());
+        self
+    }
+}
+
+/// A filter which predicts each byte of a row from its neighbours, leaving
+/// the difference to be compressed. These are the same as the filters of
+/// PNG.
+///
+/// Neighbours are the same channel of the pixel to the left, above, and
+/// above and to the left, with anything outside of the image being 0.
+/// [`ColorFormat::Gray4`] images are filtered a byte at a time.
+///
+/// [`ColorFormat::Gray4`]: crate::ColorFormat::Gray4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Filter {
+    /// The row as it is.
+    None = 0,
+
+    /// The difference from the pixel to the left.
+    Sub = 1,
+
+    /// The difference from the pixel above.
+    Up = 2,
+
+    /// The difference from the average of the pixels to the left and
+    /// above, rounded down.
+    Average = 3,
+
+    /// The difference from whichever of the pixels to the left, above, and
+    /// above and to the left is closest to left + above - above left.
+    Paeth = 4,
+}
+
+impl Filter {
+    /// Every filter, in the order of their IDs.
+    pub const ALL: [Filter; 5] = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth];
+}
+
+impl TryFrom<u8> for Filter {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Filter::ALL.get(value as usize).copied().ok_or(value)
+    }
+}
+
+/// How [`EncodeOptions::filter_strategy`] chooses the [`Filter`] for each
+/// row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    /// Use the same filter for every row.
+    Fixed(Filter),
+
+    /// Use the filter whose output has the smallest sum of absolute
+    /// values, with each byte taken as signed. This is the usual choice of
+    /// PNG encoders, and costs a pass over each row per filter.
+    MinSumAbs,
+
+    /// Use the filter which actually compresses best, trying each one on a
+    /// sample of rows with the image's compressor.
+    ///
+    /// Rows are taken in bands of 16, and each filter is tried on the
+    /// first 4 rows of a band, which then all use the one which compressed
+    /// smallest. Expect encoding to take more than twice as long.
+    TryAllMeasureCompressed,
+}
+
+/// How [`EncodeOptions::clean_transparent`] rewrites the color of fully
+/// transparent pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentCleanup {
+    /// Set every color channel to the given value.
+    ///
+    /// Long runs of the same value suit lossless images best.
+    Constant(u8),
+
+    /// Take the color of the nearest pixel which isn't fully transparent,
+    /// counting steps left, right, up or down, so the color carries on
+    /// smoothly past the edges of what can be seen.
+    ///
+    /// This suits lossy images best, as blocks on the edges of shapes
+    /// don't have to reproduce a sharp change in color. Images which are
+    /// transparent everywhere are set to 0.
+    Bleed,
+}
+
+/// Which threads the parallel parts of encoding and decoding run on, set
+/// with [`EncodeOptions::threads`] and [`DecodeOptions::threads`].
+///
+/// This covers the transforms of lossy images and the compression stages,
+/// along with anything they call which runs in parallel. Without the
+/// `parallel` feature, everything runs on the calling thread whichever of
+/// these is chosen.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "parallel")] {
+/// use std::sync::Arc;
+/// use sqp::{options::{EncodeOptions, Threads}, ColorFormat, SquishyPicture};
+///
+/// // Two threads for background image work
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+/// let options = EncodeOptions::new().threads(Threads::Pool(Arc::new(pool)));
+///
+/// let sqp = SquishyPicture::from_raw_lossy(64, 64, ColorFormat::Gray8, 80, vec![0x80; 64 * 64]);
+/// let mut encoded = Vec::new();
+/// sqp.encode_with_options(&mut encoded, &options).unwrap();
+///
+/// assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub enum Threads {
+    /// Use rayon's global pool, or the pool of the thread doing the
+    /// encoding or decoding if it belongs to one.
+    #[default]
+    Global,
+
+    /// Do the work one piece at a time, on a single thread shared by
+    /// everything using this, in the same order every time. Meant for
+    /// tracking down problems which only show up when work is spread
+    /// across threads.
+    ///
+    /// Falls back on the global pool if that thread can't be started.
+    Single,
+
+    /// Use a pool of the application's own, such as one with a few threads
+    /// set aside for background image work.
+    #[cfg(feature = "parallel")]
+    Pool(Arc<rayon::ThreadPool>),
+}
+
+/// Options for [`SquishyPicture::decode_with_options`].
+///
+/// The defaults decode the same way as [`SquishyPicture::decode`].
+///
+/// [`SquishyPicture::decode_with_options`]: crate::SquishyPicture::decode_with_options
+/// [`SquishyPicture::decode`]: crate::SquishyPicture::decode
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub(crate) low_memory: bool,
+    pub(crate) limits: Limits,
+    pub(crate) strict: bool,
+    pub(crate) deblock: u8,
+    pub(crate) threads: Threads,
+
+    pub(crate) shared_dictionary: Option<Dictionary>,
+
+    /// Length of the whole input, when it's known up front, such as for a
+    /// file opened by path.
+    pub(crate) input_len: Option<u64>,
+}
+
+impl DecodeOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lower peak memory use for lossy images at the cost of decoding more
+    /// slowly. Has no effect on other images.
+    ///
+    /// Normally every chunk is decompressed, in parallel, before the inverse
+    /// DCT runs. In low memory mode, one chunk at a time is read and
+    /// decompressed on the calling thread as the inverse DCT needs it, and
+    /// the tiles of tiled images are decoded one at a time.
+    ///
+    /// Peak memory use for a lossy image, as a multiple of the raw image
+    /// size and including the decoded image, is roughly 2–2.6× by default
+    /// and 1.7–2.3× in low memory mode.
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    /// Limit the resources decoding may use. [`Limits::default`] unless
+    /// set.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Fail on any problem with the file, rather than decoding what can be
+    /// decoded. Off by default.
+    ///
+    /// Each [`DecodeWarning`] which
+    /// [`SquishyPicture::decode_with_report`] would return becomes an
+    /// [`Error::Strict`] instead. To find trailing bytes after the image,
+    /// the input is read to its end.
+    ///
+    /// [`DecodeWarning`]: crate::picture::DecodeWarning
+    /// [`SquishyPicture::decode_with_report`]: crate::SquishyPicture::decode_with_report
+    /// [`Error::Strict`]: crate::picture::Error::Strict
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Smooth the edges between the 8x8 blocks of lossy images after
+    /// decoding them, with [`SquishyPicture::deblock`]. Has no effect on
+    /// other images. 0, the default, leaves the decoded pixels exactly as
+    /// they are.
+    ///
+    /// Block edges show at qualities below around 40. The filter only
+    /// blends steps which are small for the image's quantization, leaving
+    /// real edges and alpha alone. 1 is a light pass, and higher strengths
+    /// smooth larger steps. Any content hash is checked before this.
+    ///
+    /// [`SquishyPicture::deblock`]: crate::SquishyPicture::deblock
+    pub fn deblock(mut self, strength: u8) -> Self {
+        self.deblock = strength;
+        self
+    }
+
+    /// Choose the threads which the parallel parts of decoding run on.
+    /// Defaults to [`Threads::Global`].
+    pub fn threads(mut self, threads: Threads) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Give the [`Dictionary`] which images were encoded with by
+    /// [`EncodeOptions::shared_dictionary`].
+    ///
+    /// Images which need a dictionary fail to decode with
+    /// [`Error::MissingDictionary`] without one, and with
+    /// [`Error::DictionaryMismatch`] if it isn't the one they were encoded
+    /// with. Other images ignore it. Only
+    /// [`SquishyPicture::decode_with_options`],
+    /// [`SquishyPicture::decode_with_report`] and [`SqpStreamDecoder`] can
+    /// use a dictionary, any other way of decoding such an image fails.
+    ///
+    /// [`SquishyPicture::decode_with_options`]: crate::SquishyPicture::decode_with_options
+    /// [`SquishyPicture::decode_with_report`]: crate::SquishyPicture::decode_with_report
+    /// [`SqpStreamDecoder`]: crate::stream_decoder::SqpStreamDecoder
+    pub fn shared_dictionary(mut self, dictionary: &Dictionary) -> Self {
+        self.shared_dictionary = Some(dictionary.clone());
+        self
+    }
+}
+
+/// Caps on the resources used to decode an image, so that a small crafted
+/// file can't make the decoder allocate huge amounts of memory.
+///
+/// The limits are checked as soon as the header and chunk table have been
+/// read, before any large buffer is allocated. Exceeding one fails with
+/// [`Error::LimitExceeded`].
+///
+/// The chunk table is also checked against the image itself, and against
+/// the length of the input when that is known, as it is for slices and
+/// files opened by path. Input read through [`Read`] has no known length,
+/// so a table which claims more chunk data than there is is only caught
+/// once the data runs out, and only these limits bound what is allocated
+/// before then.
+///
+/// Every decode function applies [`Limits::default`] unless told
+/// otherwise. To decode larger images, pass different limits to
+/// [`SquishyPicture::decode_with_limits`], [`open_with_limits`] or the
+/// `_with_limits` version of any other decode function, or use
+/// [`Limits::unlimited`] for input which is trusted.
+///
+/// # Example
+/// ```
+/// use sqp::{ColorFormat, Limits, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(64, 64, ColorFormat::Gray8, vec![0; 64 * 64]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let mut limits = Limits::default();
+/// limits.max_image_width = 32;
+///
+/// assert!(SquishyPicture::decode_with_limits(encoded.as_slice(), limits).is_err());
+/// ```
+///
+/// [`Error::LimitExceeded`]: crate::picture::Error::LimitExceeded
+/// [`Read`]: crate::io::Read
+/// [`SquishyPicture::decode_with_limits`]: crate::SquishyPicture::decode_with_limits
+/// [`open_with_limits`]: crate::open_with_limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Limits {
+    /// The widest image allowed, in pixels.
+    pub max_image_width: u32,
+
+    /// The tallest image allowed, in pixels.
+    pub max_image_height: u32,
+
+    /// The largest single buffer allowed, in bytes.
+    ///
+    /// This is checked against the decoded image, and against the
+    /// decompressed data, which for lossy images holds the DCT
+    /// coefficients. In low memory mode, lossy images are decompressed one
+    /// chunk at a time, so only the largest chunk is checked.
+    pub max_alloc_bytes: usize,
+
+    /// The most compressed chunks allowed.
+    pub max_chunk_count: usize,
+}
+
+impl Limits {
+    /// No limits at all. Only use this for input which is trusted.
+    pub const fn unlimited() -> Self {
+        Self {
+            max_image_width: u32::MAX,
+            max_image_height: u32::MAX,
+            max_alloc_bytes: usize::MAX,
+            max_chunk_count: usize::MAX,
+        }
+    }
+
+    /// Check the image a header describes.
+    pub(crate) fn check_header(&self, header: &Header) -> Result<(), Error> {
+        check_limit("image width", header.width as usize, self.max_image_width as usize)?;
+        check_limit("image height", header.height as usize, self.max_image_height as usize)?;
+        self.check_alloc(header.bitmap_len().unwrap_or(usize::MAX))
+    }
+
+    /// Check the number of chunks, before the chunk table is read.
+    pub(crate) fn check_chunk_count(&self, chunk_count: usize) -> Result<(), Error> {
+        check_limit("chunk count", chunk_count, self.max_chunk_count)
+    }
+
+    /// Check the size of a buffer before allocating it.
+    pub(crate) fn check_alloc(&self, bytes: usize) -> Result<(), Error> {
+        check_limit("allocation size", bytes, self.max_alloc_bytes)
+    }
+}
+
+impl Default for Limits {
+    /// Room for images of up to 16384 pixels on each side, within 1 GiB
+    /// per buffer.
+    fn default() -> Self {
+        Self {
+            max_image_width: 16384,
+            max_image_height: 16384,
+            max_alloc_bytes: 1 << 30,
+
+            // Every chunk but the last holds almost 256 KiB of data, so
+            // this is plenty for the allocation limit
+            max_chunk_count: 8192,
+        }
+    }
+}
+
+fn check_limit(name: &'static str, requested: usize, limit: usize) -> Result<(), Error> {
+    if requested > limit {
+        return Err(Error::LimitExceeded {
+            name,
+            requested: requested as u64,
+            limit: limit as u64,
+        })
+    }
+
+    Ok(())
+}
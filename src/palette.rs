@@ -0,0 +1,458 @@
+//! Reducing the colors of an image to a palette, with median cut.
+//!
+//! The format has no indexed color format to store the result in, so an
+//! [`IndexedPicture`] is only held in memory. Expanded back into its
+//! original color format, an image with few colors compresses much better,
+//! as every row filter and backend sees far fewer distinct values.
+
+use alloc::{vec, vec::Vec};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::{header::{ColorFormat, Header}, picture::Error, SquishyPicture};
+
+/// The most colors a palette can have, so that every index fits in a byte.
+pub const MAX_PALETTE_LEN: u16 = 256;
+
+/// How a palette handles the alpha channel of [`ColorFormat::Rgba8`]
+/// images. Other images are opaque, and ignore this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaletteAlpha {
+    /// Alpha is quantized along with the color channels, so entries are
+    /// reserved for each level of transparency as needed.
+    #[default]
+    Quantize,
+
+    /// Pixels with alpha below the threshold become fully transparent,
+    /// sharing a single entry, and the rest become fully opaque.
+    Threshold(u8),
+}
+
+/// The colors of an [`IndexedPicture`], each as RGBA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 4]>,
+}
+
+impl Palette {
+    /// The colors, in the order their indices refer to them.
+    pub fn colors(&self) -> &[[u8; 4]] {
+        &self.colors
+    }
+
+    /// The number of colors.
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Whether there are no colors, which is never the case for a palette
+    /// made by [`SquishyPicture::quantize`].
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// The index of the color closest to `color`, by the squared
+    /// difference of each channel, preferring the lowest index.
+    pub fn nearest(&self, color: [u8; 4]) -> u8 {
+        let distance = |entry: &[u8; 4]| -> u32 {
+            entry.iter().zip(color).map(|(a, b)| (*a as i32 - b as i32).pow(2) as u32).sum()
+        };
+
+        let nearest = self.colors.iter().enumerate().min_by_key(|(_, entry)| distance(entry));
+        nearest.map_or(0, |(i, _)| i as u8)
+    }
+}
+
+/// An image made of one byte indices into a [`Palette`], like an 8 bit
+/// indexed color format.
+///
+/// Made by [`SquishyPicture::quantize`].
+#[derive(Debug, Clone)]
+pub struct IndexedPicture {
+    /// The header of the image this was made from.
+    header: Header,
+
+    palette: Palette,
+
+    /// The index of each pixel, in row-major order.
+    indices: Vec<u8>,
+}
+
+impl IndexedPicture {
+    /// Width of the image in pixels.
+    pub fn width(&self) -> u32 {
+        self.header.width
+    }
+
+    /// Height of the image in pixels.
+    pub fn height(&self) -> u32 {
+        self.header.height
+    }
+
+    /// The colors the indices refer to.
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// The index of each pixel, in row-major order.
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    /// Replace each index with its color, in the color format and with the
+    /// compression settings of the image this was made from.
+    pub fn expand(&self) -> SquishyPicture {
+        let color_format = self.header.color_format;
+        let pbc = color_format.pbc();
+
+        let mut bitmap = Vec::with_capacity(self.indices.len() * pbc);
+        for index in &self.indices {
+            bitmap.extend_from_slice(&self.palette.colors[*index as usize][..pbc]);
+        }
+
+        SquishyPicture {
+            header: self.header,
+            bitmap,
+            encoder_info: None,
+        }
+    }
+}
+
+/// Each distinct color of an image, packed big endian as RGBA so that
+/// sorting orders them by channel, along with how many pixels have it.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    color: u32,
+    count: u32,
+}
+
+impl Entry {
+    fn channel(&self, channel: usize) -> u8 {
+        self.color.to_be_bytes()[channel]
+    }
+}
+
+/// A range of the entries which share a palette color.
+#[derive(Debug, Clone)]
+struct ColorBox {
+    start: usize,
+    end: usize,
+
+    /// The total number of pixels in the box.
+    pixels: u64,
+
+    /// The channel with the widest range of values, and its range.
+    widest: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(entries: &[Entry], start: usize, end: usize) -> Self {
+        let mut min = [u8::MAX; 4];
+        let mut max = [0; 4];
+        let mut pixels = 0;
+        for entry in &entries[start..end] {
+            for (channel, value) in entry.color.to_be_bytes().into_iter().enumerate() {
+                min[channel] = min[channel].min(value);
+                max[channel] = max[channel].max(value);
+            }
+            pixels += entry.count as u64;
+        }
+
+        let (widest, range) = (0..4)
+            .map(|channel| (channel, max[channel].saturating_sub(min[channel])))
+            .rev()
+            .max_by_key(|(_, range)| *range)
+            .unwrap();
+
+        Self { start, end, pixels, widest, range }
+    }
+
+    /// The mean color of the box, weighted by the pixels of each entry.
+    fn mean(&self, entries: &[Entry]) -> [u8; 4] {
+        let mut sums = [0u64; 4];
+        for entry in &entries[self.start..self.end] {
+            for (sum, value) in sums.iter_mut().zip(entry.color.to_be_bytes()) {
+                *sum += value as u64 * entry.count as u64;
+            }
+        }
+
+        sums.map(|sum| ((sum + self.pixels / 2) / self.pixels) as u8)
+    }
+}
+
+/// Split the entries into at most `max_colors` boxes with median cut,
+/// returning the palette color of each box.
+///
+/// The box with the widest range in any channel is split at the median
+/// pixel of that channel, until there are enough boxes or every box has a
+/// single color. Afterwards each entry's count is replaced by the index of
+/// its box.
+fn median_cut(entries: &mut [Entry], max_colors: usize) -> Vec<[u8; 4]> {
+    if entries.is_empty() {
+        return Vec::new()
+    }
+
+    let mut boxes = vec![ColorBox::new(entries, 0, entries.len())];
+    while boxes.len() < max_colors {
+        let widest = boxes.iter()
+            .enumerate()
+            .filter(|(_, b)| b.end - b.start > 1)
+            .max_by_key(|(_, b)| (b.range, b.pixels));
+        let Some((i, _)) = widest else {
+            break
+        };
+
+        let color_box = boxes.swap_remove(i);
+        let entries_in_box = &mut entries[color_box.start..color_box.end];
+        entries_in_box.sort_unstable_by_key(|e| (e.channel(color_box.widest), e.color));
+
+        // The first entry past half of the pixels, leaving at least one
+        // entry on each side
+        let mut seen = 0;
+        let half = color_box.pixels.div_ceil(2);
+        let median = entries_in_box.iter().position(|e| {
+            seen += e.count as u64;
+            seen >= half
+        });
+        let split = color_box.start + median.unwrap_or(0).min(entries_in_box.len() - 2) + 1;
+
+        boxes.push(ColorBox::new(entries, color_box.start, split));
+        boxes.push(ColorBox::new(entries, split, color_box.end));
+    }
+
+    // Boxes in the order of their entries, so the palette doesn't depend on
+    // the order they were split in
+    boxes.sort_unstable_by_key(|b| b.start);
+    let colors = boxes.iter().map(|b| b.mean(entries)).collect();
+    for (index, color_box) in boxes.iter().enumerate() {
+        for entry in &mut entries[color_box.start..color_box.end] {
+            entry.count = index as u32;
+        }
+    }
+
+    colors
+}
+
+impl SquishyPicture {
+    /// Reduce the image to at most `max_colors` colors, the same as
+    /// [`SquishyPicture::quantize`] with [`PaletteAlpha::Quantize`], and
+    /// expand it back into its own color format.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_fn(64, 64, ColorFormat::Rgb8, |x, y, pixel| {
+    ///     pixel.copy_from_slice(&[x as u8 * 4, y as u8 * 4, 128]);
+    /// });
+    /// let reduced = sqp.quantize_to_palette(16).unwrap();
+    ///
+    /// let mut colors: Vec<_> = reduced.as_raw().chunks(3).collect();
+    /// colors.sort();
+    /// colors.dedup();
+    /// assert!(colors.len() <= 16);
+    /// ```
+    pub fn quantize_to_palette(&self, max_colors: u16) -> Result<SquishyPicture, Error> {
+        Ok(self.quantize(max_colors, PaletteAlpha::Quantize)?.expand())
+    }
+
+    /// Reduce an [`ColorFormat::Rgb8`] or [`ColorFormat::Rgba8`] image to a
+    /// palette of at most `max_colors` colors, chosen with median cut.
+    ///
+    /// Images with no more than `max_colors` distinct colors keep every
+    /// color exactly, unless alpha is thresholded. A transparent color of
+    /// an RGB image is treated like any other color.
+    ///
+    /// Fails with [`Error::InvalidPaletteSize`] unless `max_colors` is
+    /// between 1 and [`MAX_PALETTE_LEN`], and with
+    /// [`Error::PaletteUnsupported`] for other color formats.
+    pub fn quantize(&self, max_colors: u16, alpha: PaletteAlpha) -> Result<IndexedPicture, Error> {
+        if !(1..=MAX_PALETTE_LEN).contains(&max_colors) {
+            return Err(Error::InvalidPaletteSize(max_colors))
+        }
+
+        let color_format = self.header.color_format;
+        if !matches!(color_format, ColorFormat::Rgb8 | ColorFormat::Rgba8) {
+            return Err(Error::PaletteUnsupported(color_format))
+        }
+
+        let threshold = match (color_format, alpha) {
+            (ColorFormat::Rgba8, PaletteAlpha::Threshold(threshold)) => Some(threshold),
+            _ => None,
+        };
+        let key = |pixel: &[u8]| -> u32 {
+            let alpha = pixel.get(3).copied().unwrap_or(u8::MAX);
+            match threshold {
+                Some(threshold) if alpha < threshold => 0,
+                Some(_) => u32::from_be_bytes([pixel[0], pixel[1], pixel[2], u8::MAX]),
+                None => u32::from_be_bytes([pixel[0], pixel[1], pixel[2], alpha]),
+            }
+        };
+
+        // The histogram is every pixel's color, sorted and counted
+        let pbc = color_format.pbc();
+        #[cfg(feature = "parallel")]
+        let mut keys: Vec<u32> = self.bitmap.par_chunks_exact(pbc).map(key).collect();
+        #[cfg(feature = "parallel")]
+        keys.par_sort_unstable();
+
+        #[cfg(not(feature = "parallel"))]
+        let mut keys: Vec<u32> = self.bitmap.chunks_exact(pbc).map(key).collect();
+        #[cfg(not(feature = "parallel"))]
+        keys.sort_unstable();
+
+        let mut entries: Vec<Entry> = Vec::new();
+        for color in keys {
+            match entries.last_mut() {
+                Some(last) if last.color == color => last.count += 1,
+                _ => entries.push(Entry { color, count: 1 }),
+            }
+        }
+
+        // Thresholded transparent pixels share an entry of their own, after
+        // the rest
+        let transparent = threshold.is_some() && entries.first().is_some_and(|e| e.color == 0);
+        let opaque = if transparent { &mut entries[1..] } else { &mut entries[..] };
+        let mut colors = median_cut(opaque, max_colors as usize - transparent as usize);
+        if transparent {
+            entries[0].count = colors.len() as u32;
+            colors.push([0; 4]);
+        }
+
+        // Splitting reordered the entries, and each one's count is now its
+        // index
+        entries.sort_unstable_by_key(|e| e.color);
+        let index = |pixel: &[u8]| -> u8 {
+            let i = entries.binary_search_by_key(&key(pixel), |e| e.color).unwrap();
+            entries[i].count as u8
+        };
+
+        #[cfg(feature = "parallel")]
+        let indices = self.bitmap.par_chunks_exact(pbc).map(index).collect();
+        #[cfg(not(feature = "parallel"))]
+        let indices = self.bitmap.chunks_exact(pbc).map(index).collect();
+
+        Ok(IndexedPicture {
+            header: self.header,
+            palette: Palette { colors },
+            indices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionType;
+
+    /// An image with exactly `count` distinct colors, in stripes.
+    fn striped(count: u32, color_format: ColorFormat) -> SquishyPicture {
+        SquishyPicture::from_fn(97, 61, color_format, |x, y, pixel| {
+            let color = (x / 3 + y * 7) % count;
+            let rgba = [(color * 37) as u8, ((color * 101) >> 2) as u8, (color * 13) as u8, 255 - color as u8];
+            pixel.copy_from_slice(&rgba[..pixel.len()]);
+        })
+    }
+
+    fn distinct_colors(picture: &SquishyPicture) -> usize {
+        let mut colors: Vec<_> = picture.as_raw().chunks(picture.color_format().pbc()).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        colors.len()
+    }
+
+    #[test]
+    fn few_colors_are_lossless() {
+        for color_format in [ColorFormat::Rgb8, ColorFormat::Rgba8] {
+            for count in [1, 2, 17, 200, 256] {
+                let sqp = striped(count, color_format);
+                assert_eq!(distinct_colors(&sqp), count as usize);
+
+                let indexed = sqp.quantize(256, PaletteAlpha::Quantize).unwrap();
+                assert_eq!(indexed.palette().len(), count as usize);
+                assert_eq!(indexed.indices().len(), 97 * 61);
+                assert_eq!(indexed.expand().as_raw(), sqp.as_raw());
+
+                let indexed = sqp.quantize(count as u16, PaletteAlpha::Quantize).unwrap();
+                assert_eq!(indexed.expand().as_raw(), sqp.as_raw());
+            }
+        }
+    }
+
+    #[test]
+    fn many_colors_are_reduced() {
+        let sqp = SquishyPicture::from_fn(128, 128, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[(x * 2) as u8, (y * 2) as u8, (x + y) as u8, 255]);
+        });
+
+        for max_colors in [1, 2, 16, 100, 256] {
+            let reduced = sqp.quantize_to_palette(max_colors).unwrap();
+            assert_eq!(reduced.color_format(), ColorFormat::Rgba8);
+            assert!(distinct_colors(&reduced) <= max_colors as usize);
+
+            // Each pixel should land close to its original color
+            let error = reduced.as_raw().iter().zip(sqp.as_raw()).map(|(a, b)| a.abs_diff(*b) as u64).sum::<u64>();
+            let mean_error = error as f64 / sqp.as_raw().len() as f64;
+            if max_colors >= 100 {
+                assert!(mean_error < 6.0, "{max_colors} colors: {mean_error}");
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_matches_indices_of_exact_colors() {
+        let sqp = striped(40, ColorFormat::Rgb8);
+        let indexed = sqp.quantize(64, PaletteAlpha::Quantize).unwrap();
+
+        for (pixel, index) in sqp.as_raw().chunks(3).zip(indexed.indices()) {
+            let color = [pixel[0], pixel[1], pixel[2], 255];
+            assert_eq!(indexed.palette().nearest(color), *index);
+        }
+    }
+
+    #[test]
+    fn alpha_threshold() {
+        let sqp = SquishyPicture::from_fn(32, 32, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[x as u8 * 8, y as u8 * 8, 7, (x * 8) as u8]);
+        });
+
+        let indexed = sqp.quantize(8, PaletteAlpha::Threshold(128)).unwrap();
+        assert!(indexed.palette().len() <= 8);
+        assert_eq!(indexed.palette().colors().iter().filter(|c| **c == [0; 4]).count(), 1);
+
+        for (original, reduced) in sqp.as_raw().chunks(4).zip(indexed.expand().as_raw().chunks(4)) {
+            if original[3] < 128 {
+                assert_eq!(reduced, [0; 4]);
+            } else {
+                assert_eq!(reduced[3], 255);
+            }
+        }
+
+        // Quantized alpha keeps the levels when there is room
+        let sqp = striped(30, ColorFormat::Rgba8);
+        let reduced = sqp.quantize_to_palette(32).unwrap();
+        assert_eq!(reduced.as_raw(), sqp.as_raw());
+    }
+
+    #[test]
+    fn keeps_compression_settings() {
+        let mut sqp = striped(10, ColorFormat::Rgb8);
+        sqp.set_compression(CompressionType::LosslessV2, None);
+        let reduced = sqp.quantize_to_palette(4).unwrap();
+
+        assert_eq!(reduced.compression_type(), CompressionType::LosslessV2);
+        assert_eq!((reduced.width(), reduced.height()), (97, 61));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let sqp = striped(10, ColorFormat::Rgb8);
+        assert!(matches!(sqp.quantize(0, PaletteAlpha::Quantize), Err(Error::InvalidPaletteSize(0))));
+        assert!(matches!(sqp.quantize(257, PaletteAlpha::Quantize), Err(Error::InvalidPaletteSize(257))));
+
+        let gray = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+        assert!(matches!(gray.quantize(16, PaletteAlpha::Quantize), Err(Error::PaletteUnsupported(ColorFormat::Gray8))));
+    }
+}
@@ -1,45 +1,540 @@
 //! Functions and other utilities surrounding the [`SquishyPicture`] type.
 
-use std::{fs::File, io::{self, BufWriter, Read, Write}, path::Path};
+#[cfg(feature = "std")]
+use std::{fs::File, io::{BufReader, BufWriter}, path::Path};
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
-use integer_encoding::VarInt;
+use core::fmt;
+
+use alloc::{borrow::Cow, string::String, vec, vec::Vec};
 use thiserror::Error;
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
 use crate::{
-    compression::{dct::{dct_compress, dct_decompress, DctParameters},
-    lossless::{compress, decompress, CompressionError, CompressionInfo}},
-    header::{ColorFormat, CompressionType, Header},
-    operations::{add_rows, sub_rows},
+    binio::{read_varint, write_varint},
+    compression::{dct::{dct_compress, dct_compress_sequential, dct_deblock, dct_decompress, dct_decompress_channel, dct_decompress_region, dct_preview, quality_for_psnr, scaled_size, BlockRegion, DctCoefficients, DctParameters},
+    lossless::{compress_chunk, compress_with_primer, decompress, decompress_chunk, decompress_chunks, decompress_with_primer, total_size_raw, Backend, ChunkInfo, CompressionError, CompressionInfo},
+    range_coder::{CoefficientDecoder, CoefficientEncoder}},
+    analysis::ContentReport,
+    container::{
+        chunk_table_len, footer_matches, pixel_data_len, shared_dictionary, skip, write_chunk_table, write_footer,
+        ChunkTable, ChunkTableReader, SectionType, TablePart, FOOTER_LEN,
+    },
+    encode_reader::SqpEncodeReader,
+    histogram::Histogram,
+    integrity::{picture_issues, IntegrityIssue},
+    header::{
+        clamp_quality, default_tile_size, tile_size_is_valid, ColorFormat, CompressionType, Header, CURRENT_VERSION,
+        FIRST_DATA_OFFSET_VERSION, FIRST_ROW_FILTER_VERSION, FIRST_TILED_VERSION, MAX_NEAR, QUALITY_RANGE,
+    },
+    io::{self, count_remaining, read_vec, Read, Write},
+    lossless::Dictionary,
+    operations::{
+        add_rows, clean_transparent, convert_color_format, downscale, expand_color_key, expand_to_rgba8,
+        filter_rows_into, overlay, pack_gray4, sub_rows_into, unfilter_rows, unpack_gray4,
+    },
+    options::{DecodeOptions, EncodeOptions, FilterStrategy, Limits},
+    threads,
 };
 
 /// An error which occured while manipulating a [`SquishyPicture`].
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// The file signature was invalid. Must be "dangoimg".
     #[error("incorrect signature, expected \"dangoimg\" got {0:?}")]
     InvalidIdentifier(String),
 
     /// Any I/O operation failed.
-    #[error("io operation failed: {0}")]
+    #[error("io operation failed")]
     IoError(#[from] io::Error),
 
     /// There was an error while compressing or decompressing.
-    #[error("compression operation failed: {0}")]
+    #[error("compression operation failed")]
     CompressionError(#[from] CompressionError),
+
+    /// The input ended partway through a section of the file.
+    #[error("file is truncated, it ends within the {section}")]
+    TruncatedFile {
+        section: FileSection,
+    },
+
+    /// The color format in the header was not a known value.
+    #[error("invalid color format {0}")]
+    InvalidColorFormat(u8),
+
+    /// The quality level in the header doesn't fit the compression type.
+    #[error("invalid quality level {0}")]
+    InvalidQuality(u8),
+
+    /// The compression type in the header was not a known value.
+    #[error("invalid compression type {0}")]
+    InvalidCompressionType(u8),
+
+    /// The width and height in the header don't describe a usable image.
+    #[error("invalid image dimensions {width}x{height}")]
+    InvalidDimensions {
+        width: u32,
+        height: u32,
+    },
+
+    /// The color type of an image being converted has no [`ColorFormat`]
+    /// equivalent.
+    #[error("unsupported color type {0}")]
+    UnsupportedColorType(String),
+
+    /// The decompressed image data was not the size the header describes.
+    #[error("image data size mismatch, expected {expected} got {actual}")]
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// The number of DCT coefficients in a lossy image doesn't match the
+    /// number its dimensions need.
+    #[error("DCT coefficient count mismatch, expected {expected} got {actual}")]
+    CoefficientCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// The chunk table claims a total uncompressed size which the image
+    /// can't have. This is found before anything is allocated for it.
+    #[error("chunk table claims {claimed} bytes of uncompressed data, expected between {min} and {max}")]
+    ChunkTableMismatch {
+        claimed: u64,
+        min: u64,
+        max: u64,
+    },
+
+    /// Decoding would need more of some resource than allowed.
+    #[error("{name} of {requested} exceeds the limit of {limit}")]
+    LimitExceeded {
+        name: &'static str,
+        requested: u64,
+        limit: u64,
+    },
+
+    /// The file uses a version of the format which is not supported.
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The flags byte in the header has unknown bits set.
+    #[error("invalid header flags {0:#04x}")]
+    InvalidFlags(u8),
+
+    /// The image's [`ColorFormat`] can't be compressed lossily.
+    #[error("lossy compression is not supported for {0:?} images")]
+    LossyUnsupported(ColorFormat),
+
+    /// A transparent color was given for an image which isn't
+    /// [`ColorFormat::Rgb8`].
+    #[error("transparent colors are only supported for Rgb8 images, not {0:?}")]
+    TransparencyUnsupported(ColorFormat),
+
+    /// Range coding was turned on for an image which isn't
+    /// [`CompressionType::LossyDct`].
+    #[error("range coding is only supported for lossy images, not {0:?}")]
+    RangeCodingUnsupported(CompressionType),
+
+    /// Only lossy images can have their own quantization matrices.
+    #[error("quantization matrices are only supported for lossy images, not {0:?}")]
+    QuantizationMatricesUnsupported(CompressionType),
+
+    /// A quantization matrix has a step of zero.
+    #[error("quantization matrix for channel {0} has a step of zero")]
+    InvalidQuantizationMatrix(u8),
+
+    /// Only lossy images can be split into tiles.
+    #[error("tiling is only supported for lossy images, not {0:?}")]
+    TilingUnsupported(CompressionType),
+
+    /// The tile size is zero or not a multiple of 8.
+    #[error("invalid tile size {0}, must be a nonzero multiple of 8")]
+    InvalidTileSize(u16),
+
+    /// The coefficients of a tile ran out before all of its blocks were
+    /// decoded, or its length in the tile index runs past the end of the
+    /// data.
+    #[error("tile {tile} is missing coefficients")]
+    TileCoefficientsMissing {
+        tile: usize,
+    },
+
+    /// The region to decode is empty or reaches outside of the image.
+    #[error("region {width}x{height} at ({x}, {y}) is not within the image")]
+    InvalidRegion {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+
+    /// A row of a lossless image names a filter which doesn't exist.
+    #[error("row {row} has unknown filter {filter}")]
+    InvalidRowFilter {
+        row: u32,
+        filter: u8,
+    },
+
+    /// The scale to decode at is not one of 1, 2, 4 or 8.
+    #[error("invalid scale denominator {0}, must be 1, 2, 4 or 8")]
+    InvalidScale(u8),
+
+    /// The channel to decode is past the last channel of the image's color
+    /// format.
+    #[error("channel {channel} is out of range for an image with {channels} channels")]
+    InvalidChannel {
+        channel: usize,
+        channels: usize,
+    },
+
+    /// The chunks to read are empty or go past the last chunk of the image.
+    #[error("chunks {start}..{end} are out of range for an image with {chunk_count} chunks")]
+    InvalidChunkRange {
+        start: usize,
+        end: usize,
+        chunk_count: usize,
+    },
+
+    /// Only lossy images are stored as blocks of DCT coefficients.
+    #[error("only lossy images have DCT blocks, not {0:?}")]
+    NoDctBlocks(CompressionType),
+
+    /// Decoding found a problem with the file, and
+    /// [`DecodeOptions::strict`] was set.
+    #[error("strict decoding failed: {0}")]
+    Strict(DecodeWarning),
+
+    /// A section type which decoders must understand is not one this
+    /// decoder knows.
+    #[error("unknown critical section {0}")]
+    UnknownCriticalSection(SectionType),
+
+    /// The chunk table or pixel data section is repeated, or the pixel
+    /// data comes before the chunk table.
+    #[error("unexpected {0} section")]
+    UnexpectedSection(SectionType),
+
+    /// The image was compressed with a shared dictionary, which wasn't
+    /// given with [`DecodeOptions::shared_dictionary`] or can't be used
+    /// when decoding this way.
+    #[error("image needs shared dictionary {id:#018x}, which wasn't given")]
+    MissingDictionary {
+        id: u64,
+    },
+
+    /// The image was compressed with a different shared dictionary than
+    /// the one given.
+    #[error("image needs shared dictionary {expected:#018x}, not {actual:#018x}")]
+    DictionaryMismatch {
+        expected: u64,
+        actual: u64,
+    },
+
+    /// A section's length doesn't match its contents.
+    #[error("{section} section is {actual} bytes long, expected {expected}")]
+    SectionSizeMismatch {
+        section: SectionType,
+        expected: u64,
+        actual: u64,
+    },
+
+    /// A section is too long for its length to be stored.
+    #[error("{section} section of {len} bytes is too large")]
+    SectionTooLarge {
+        section: SectionType,
+        len: u64,
+    },
+
+    /// The header gives a different offset for the chunk table than where
+    /// it was found.
+    #[error("chunk table is at offset {actual}, but the header says {expected}")]
+    DataOffsetMismatch {
+        expected: u64,
+        actual: u64,
+    },
+
+    /// The chunk table gives a different offset for a chunk than where it
+    /// is, going by the sizes of the chunks before it.
+    #[error("chunk {chunk} is at offset {actual}, but the chunks before it end at {expected}")]
+    ChunkOffsetMismatch {
+        chunk: usize,
+        expected: u64,
+        actual: u64,
+    },
+
+    /// Pixels were asked for as a different number of bytes than the
+    /// image's color format has.
+    #[error("{color_format:?} pixels are {expected} bytes, not {actual}")]
+    PixelSizeMismatch {
+        color_format: ColorFormat,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// A palette must have between 1 and 256 colors.
+    #[error("invalid palette size {0}, must be between 1 and 256")]
+    InvalidPaletteSize(u16),
+
+    /// Only RGB and RGBA images can be reduced to a palette.
+    #[error("reducing to a palette is not supported for {0:?} images")]
+    PaletteUnsupported(ColorFormat),
+
+    /// Dithering can only reduce to between 1 and 8 bits per channel.
+    #[error("invalid bit depth {0}, must be between 1 and 8")]
+    InvalidBitDepth(u8),
+
+    /// Two images which are compared pixel for pixel aren't the same size.
+    #[error("images of {width}x{height} and {other_width}x{other_height} can't be compared")]
+    DimensionMismatch {
+        width: u32,
+        height: u32,
+        other_width: u32,
+        other_height: u32,
+    },
+
+    /// The image can't be encoded within [`EncodeOptions::max_encoded_size`]
+    /// bytes.
+    #[error("encoded image can't fit the size limit, the smallest is {best} bytes")]
+    CannotMeetSizeTarget {
+        best: usize,
+    },
+
+    /// A number of bits or bytes to read or write at once is zero, or more
+    /// than fit in a [`u64`].
+    #[error("invalid length {len}, must be between 1 and {max}")]
+    InvalidBitLength {
+        len: usize,
+        max: usize,
+    },
+
+    /// A Golomb–Rice parameter is 64 or more.
+    #[error("invalid Rice parameter {0}, must be below 64")]
+    InvalidRiceParameter(u32),
+
+    /// A [`SqpStreamDecoder`] was fed after it had already returned the
+    /// image, or an error.
+    ///
+    /// [`SqpStreamDecoder`]: crate::stream_decoder::SqpStreamDecoder
+    #[error("stream decoder was fed after it had {}", if *.failed { "failed" } else { "finished" })]
+    InvalidState {
+        failed: bool,
+    },
+}
+
+impl Error {
+    /// Convert an I/O error which occured while reading a `section` of the
+    /// file, treating an early end of input as a truncated file.
+    pub(crate) fn from_read(error: io::Error, section: FileSection) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Self::TruncatedFile { section },
+            _ => Self::IoError(error),
+        }
+    }
+}
+
+/// Errors are serialized as their message.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A section of an SQP file, in the order they appear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileSection {
+    /// The [`Header`].
+    Header,
+
+    /// The table of compressed chunk sizes.
+    ChunkTable,
+
+    /// The compressed chunks themselves.
+    ChunkData,
+
+    /// The headers of the sections around the chunk table and chunks, and
+    /// any sections which are skipped, from format version 3 on.
+    Sections,
+
+    /// The footer after the compressed chunks, from format version 10 on.
+    Footer,
+}
+
+impl fmt::Display for FileSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header => write!(f, "header"),
+            Self::ChunkTable => write!(f, "chunk table"),
+            Self::ChunkData => write!(f, "chunk data"),
+            Self::Sections => write!(f, "sections"),
+            Self::Footer => write!(f, "footer"),
+        }
+    }
+}
+
+/// A problem with a file which doesn't stop it from being decoded,
+/// returned by [`SquishyPicture::decode_with_report`].
+///
+/// Decoding normally carries on past these. With
+/// [`DecodeOptions::strict`], each one is an [`Error::Strict`] instead.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeWarning {
+    /// A chunk decompressed to a different size than the chunk table gives.
+    /// Its data was cut off or padded with zeroes to fit.
+    #[error("chunk {chunk} decompressed to {actual} bytes instead of {expected}")]
+    ChunkSizeMismatch {
+        chunk: usize,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// A chunk's compressed data is corrupted, starting in the byte
+    /// `offset` bytes into the chunk. Whatever decompressed before that was
+    /// kept, and the rest of the chunk was filled with zeroes.
+    #[error("chunk {chunk} is corrupted at byte {offset}")]
+    CorruptChunk {
+        chunk: usize,
+        offset: usize,
+    },
+
+    /// A lossy image has `len` bytes of data after its last DCT
+    /// coefficient, which were ignored.
+    #[error("{len} bytes of data after the last DCT coefficient")]
+    TrailingCoefficients {
+        len: usize,
+    },
+
+    /// The footer at the end of the image, `offset` bytes in, isn't the one
+    /// an encoder writes there, so the end of the file is damaged.
+    #[error("damaged footer at offset {offset}")]
+    DamagedFooter {
+        offset: u64,
+    },
+
+    /// The input continues for `len` bytes after the end of the image,
+    /// starting `offset` bytes in.
+    #[error("{len} bytes after the end of the image at offset {offset}")]
+    TrailingBytes {
+        offset: u64,
+        len: u64,
+    },
+
+    /// The decoded bitmap of a lossless or uncompressed image doesn't match
+    /// the content hash stored with it, so the pixels aren't the ones which
+    /// were encoded.
+    ///
+    /// This is only checked with the `content-hash` feature.
+    #[error("decoded content hash {actual:#018x} doesn't match the stored {expected:#018x}")]
+    ContentHashMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+/// Warnings are serialized as their message.
+#[cfg(feature = "serde")]
+impl serde::Serialize for DecodeWarning {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The outcome of [`SquishyPicture::verify_content`].
+#[cfg(feature = "content-hash")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContentCheck {
+    /// The decoded bitmap matches the stored hash.
+    Match,
+
+    /// The decoded bitmap doesn't match the stored hash.
+    Mismatch {
+        expected: u64,
+        actual: u64,
+    },
+
+    /// The image is lossy, so decoding isn't expected to reproduce the
+    /// hashed bitmap, and it wasn't checked. The hash still identifies
+    /// the image which was encoded.
+    Lossy,
+
+    /// The file has no content hash.
+    Missing,
+}
+
+/// The data produced partway through encoding a [`SquishyPicture`],
+/// returned by [`SquishyPicture::encode_with_intermediates`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Intermediates {
+    /// The bitmap after filtering or transforming it according to the
+    /// compression type, exactly as it is passed to the compressor.
+    ///
+    /// For lossless images these are the row-filtered bytes, and for lossy
+    /// images the varint encoded DCT coefficients. Uncompressed images are
+    /// passed through unchanged.
+    pub filtered: Vec<u8>,
+
+    /// The chunk table of the compressed data.
+    pub compression_info: CompressionInfo,
+}
+
+/// How an image was encoded, returned by
+/// [`SquishyPicture::encode_with_stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct EncodeStats {
+    /// The number of bytes written.
+    pub bytes_written: usize,
+
+    /// The format the image was stored in, which may be smaller than the
+    /// picture's with [`EncodeOptions::auto_optimize_format`].
+    pub color_format: ColorFormat,
+
+    /// The quality the image was encoded with, or [`None`] if the
+    /// compression type is not lossy.
+    pub quality: Option<u8>,
+
+    /// The peak signal to noise ratio reached in decibels, if it was
+    /// measured to choose the quality for [`EncodeOptions::target_psnr`].
+    pub psnr: Option<f64>,
+
+    /// Whether the PSNR reached [`EncodeOptions::target_psnr`]. Always true
+    /// when there is no target.
+    pub target_met: bool,
 }
 
 /// The basic Squishy Picture type for manipulation in-memory.
 pub struct SquishyPicture {
-    header: Header,
-    bitmap: Vec<u8>,
+    pub(crate) header: Header,
+    pub(crate) bitmap: Vec<u8>,
+
+    /// The encoder info of the file the picture was decoded from.
+    pub(crate) encoder_info: Option<String>,
 }
 
 impl SquishyPicture {
     /// Create a DPF from raw bytes in a particular [`ColorFormat`].
     ///
     /// The quality parameter does nothing if the compression type is not
-    /// lossy, so it must be set to None.
+    /// lossy, so it must be set to None. Lossy quality levels are clamped
+    /// into [`QUALITY_RANGE`], and a quality of 100 is still lossy.
+    ///
+    /// For [`CompressionType::Predictive`] the quality is instead `near`,
+    /// the most any sample may differ from the original, clamped to
+    /// [`MAX_NEAR`]. [`None`] or 0 is lossless.
+    ///
+    /// [`QUALITY_RANGE`]: crate::header::QUALITY_RANGE
+    /// [`MAX_NEAR`]: crate::header::MAX_NEAR
+    ///
+    /// The bitmap must be `width * height` pixels in the given format,
+    /// otherwise encoding fails with [`Error::SizeMismatch`]. Images with a
+    /// width or height of 0 fail to encode with [`Error::InvalidDimensions`].
     ///
     /// # Example
     /// ```
@@ -60,29 +555,247 @@ impl SquishyPicture {
         quality: Option<u8>,
         bitmap: Vec<u8>,
     ) -> Self {
-        if quality.is_none() && compression_type == CompressionType::LossyDct {
-            panic!("compression level must not be `None` when compression type is lossy")
-        }
-
         let header = Header {
             magic: *b"dangoimg",
+            version: CURRENT_VERSION,
 
             width,
             height,
 
             compression_type,
-            quality: match quality {
-                Some(level) => level.clamp(1, 100),
-                None => 0,
-            },
+            quality: quality_level(compression_type, quality),
 
             color_format,
+            transparent_color: None,
+            range_coded: false,
+            quantization_matrices: None,
+            tile_size: tile_size(compression_type, width, height),
+            row_filters: false,
+            data_offset: None,
         };
 
         Self {
             header,
             bitmap,
+            encoder_info: None,
+        }
+    }
+
+    /// Create a lossless image by calling a function for each pixel.
+    ///
+    /// The function is called in row-major order with the `x` and `y`
+    /// position of the pixel, and a slice of [`ColorFormat::pbc`] bytes to
+    /// fill in. Use [`SquishyPicture::set_compression`] to encode it some
+    /// other way.
+    ///
+    /// For [`ColorFormat::Gray4`], each pixel is filled in as an 8 bit gray
+    /// value, and rounded to the nearest level once the image is packed.
+    ///
+    /// # Example
+    /// ```
+    /// // Red increases going right, and green going down
+    /// let sqp = sqp::SquishyPicture::from_fn(256, 256, sqp::ColorFormat::Rgb8, |x, y, pixel| {
+    ///     pixel.copy_from_slice(&[x as u8, y as u8, 0]);
+    /// });
+    ///
+    /// assert_eq!(&sqp.as_raw()[..6], &[0, 0, 0, 1, 0, 0]);
+    /// ```
+    pub fn from_fn<F: FnMut(u32, u32, &mut [u8])>(
+        width: u32,
+        height: u32,
+        color_format: ColorFormat,
+        mut f: F,
+    ) -> Self {
+        if color_format == ColorFormat::Gray4 {
+            return Self::from_fn(width, height, ColorFormat::Gray8, f).convert_color_format(color_format)
+        }
+
+        let mut picture = Self::from_raw_lossless(width, height, color_format, Vec::new());
+
+        let len = picture.header.bitmap_len().expect("image dimensions are too large");
+        picture.bitmap = vec![0; len];
+        picture.map_pixels_with_position(&mut f);
+
+        picture
+    }
+
+    /// Change how the image will be compressed when it is encoded.
+    ///
+    /// The quality is treated the same as in [`SquishyPicture::from_raw`].
+    ///
+    /// Range coding is turned off for anything but
+    /// [`CompressionType::LossyDct`], every channel goes back to the
+    /// quantization matrix for the quality, see
+    /// [`Header::quantization_matrices`], and the tiles go back to the
+    /// default, see [`SquishyPicture::set_tile_size`].
+    pub fn set_compression(&mut self, compression_type: CompressionType, quality: Option<u8>) {
+        self.header.quality = quality_level(compression_type, quality);
+        self.header.compression_type = compression_type;
+        self.header.quantization_matrices = None;
+        self.header.tile_size = tile_size(compression_type, self.header.width, self.header.height);
+        if compression_type != CompressionType::LossyDct {
+            self.header.range_coded = false;
+        }
+    }
+
+    /// Compress the image so that every sample decodes to within
+    /// `max_error` of the original, where 0 is bit-exact.
+    ///
+    /// Unlike [`CompressionType::LossyDct`], whose artifacts have no bound,
+    /// the bound is guaranteed and stored in the header, see
+    /// [`Header::max_error`]. This uses [`CompressionType::Predictive`] with
+    /// a `near` of `max_error`, or [`MAX_NEAR`] if that is smaller. A
+    /// `max_error` of 0 leaves an image which is already lossless as it is.
+    ///
+    /// [`ColorFormat::Gray4`] packs two samples into each byte, so it is
+    /// compressed losslessly instead, which is within any bound.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_fn(64, 64, ColorFormat::Gray8, |x, y, pixel| {
+    ///     pixel[0] = (x * 3 + y * 2) as u8 ^ (x * y % 5) as u8;
+    /// });
+    /// let original = sqp.as_raw().clone();
+    /// sqp.set_max_error(2);
+    ///
+    /// let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+    /// assert_eq!(decoded.max_error(), Some(2));
+    /// assert!(decoded.as_raw().iter().zip(&original).all(|(a, b)| a.abs_diff(*b) <= 2));
+    /// ```
+    pub fn set_max_error(&mut self, max_error: u8) {
+        if max_error == 0 && self.header.max_error() == Some(0) {
+            return
+        }
+
+        let near = if self.header.color_format.supports_lossy() { max_error } else { 0 };
+        self.set_compression(CompressionType::Predictive, Some(near));
+    }
+
+    /// Range code the DCT coefficients of a lossy image, rather than
+    /// writing them as varints and compressing them with LZW.
+    ///
+    /// Each bit of the coefficients is coded with a probability which
+    /// adapts to its neighbours and frequency, which makes images around
+    /// half the size at the same quality. This is experimental. Images which
+    /// aren't lossy fail with [`Error::RangeCodingUnsupported`].
+    ///
+    /// The flag is stored from version 2 of the format on, so this
+    /// upgrades older headers.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_fn(64, 64, ColorFormat::Rgb8, |x, y, pixel| {
+    ///     pixel.copy_from_slice(&[x as u8 * 4, y as u8 * 4, 128]);
+    /// });
+    /// sqp.set_compression(sqp::CompressionType::LossyDct, Some(80));
+    /// let varints = sqp.encode_to_vec().unwrap();
+    ///
+    /// sqp.set_range_coding(true).unwrap();
+    /// let range_coded = sqp.encode_to_vec().unwrap();
+    /// assert!(range_coded.len() < varints.len());
+    ///
+    /// let decoded = SquishyPicture::decode(range_coded.as_slice()).unwrap();
+    /// assert!(decoded.range_coded());
+    /// ```
+    pub fn set_range_coding(&mut self, range_coded: bool) -> Result<(), Error> {
+        if range_coded {
+            if self.header.compression_type != CompressionType::LossyDct {
+                return Err(Error::RangeCodingUnsupported(self.header.compression_type))
+            }
+
+            self.header.version = self.header.version.max(2);
+        }
+
+        self.header.range_coded = range_coded;
+        Ok(())
+    }
+
+    /// Quantize each channel of a lossy image with its own matrix, in the
+    /// order the channels are interleaved, instead of the one for the
+    /// quality. Entries past the last channel of the format are unused, and
+    /// [`None`] goes back to the matrix for the quality.
+    ///
+    /// Images which aren't lossy fail with
+    /// [`Error::QuantizationMatricesUnsupported`], and a step of zero with
+    /// [`Error::InvalidQuantizationMatrix`]. The matrices are stored from
+    /// version 4 of the format on, so this upgrades older headers.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, CompressionType, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_fn(64, 64, ColorFormat::GrayA8, |x, y, pixel| {
+    ///     pixel.copy_from_slice(&[(x ^ y) as u8 * 4, 255]);
+    /// });
+    /// sqp.set_compression(CompressionType::LossyDct, Some(80));
+    /// sqp.set_quantization_matrices(Some([[1; 64], [64; 64], [1; 64], [1; 64]])).unwrap();
+    ///
+    /// let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+    /// assert_eq!(decoded.quantization_matrices().unwrap()[1], [64; 64]);
+    /// ```
+    pub fn set_quantization_matrices(&mut self, matrices: Option<[[u16; 64]; 4]>) -> Result<(), Error> {
+        if let Some(matrices) = &matrices {
+            if self.header.compression_type != CompressionType::LossyDct {
+                return Err(Error::QuantizationMatricesUnsupported(self.header.compression_type))
+            }
+
+            if let Some(channel) = matrices.iter().position(|m| m.contains(&0)) {
+                return Err(Error::InvalidQuantizationMatrix(channel as u8))
+            }
+
+            self.header.version = self.header.version.max(FIRST_DATA_OFFSET_VERSION);
+        }
+
+        self.header.quantization_matrices = matrices;
+        Ok(())
+    }
+
+    /// Split the coefficients of a lossy image into square tiles of
+    /// `tile_size` pixels, each of which is coded on its own, or with
+    /// [`None`], keep them as one stream.
+    ///
+    /// Tiles are decoded in parallel, and [`SquishyPicture::decode_region`]
+    /// only decodes the tiles a region covers. Lossy images larger than a
+    /// single [`DEFAULT_TILE_SIZE`] tile are tiled with that size unless
+    /// this says otherwise.
+    ///
+    /// Images which aren't lossy fail with [`Error::TilingUnsupported`],
+    /// and sizes which aren't a nonzero multiple of 8 with
+    /// [`Error::InvalidTileSize`]. Tiles are stored from version 6 of the
+    /// format on, so this upgrades older headers.
+    ///
+    /// [`DEFAULT_TILE_SIZE`]: crate::header::DEFAULT_TILE_SIZE
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_raw_lossy(100, 60, ColorFormat::Gray8, 80, vec![128; 100 * 60]);
+    /// assert_eq!(sqp.tile_size(), None);
+    ///
+    /// sqp.set_tile_size(Some(32)).unwrap();
+    /// let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+    /// assert_eq!(decoded.tile_size(), Some(32));
+    /// ```
+    pub fn set_tile_size(&mut self, tile_size: Option<u16>) -> Result<(), Error> {
+        if let Some(size) = tile_size {
+            if self.header.compression_type != CompressionType::LossyDct {
+                return Err(Error::TilingUnsupported(self.header.compression_type))
+            }
+
+            if !tile_size_is_valid(size) {
+                return Err(Error::InvalidTileSize(size))
+            }
+
+            self.header.version = self.header.version.max(FIRST_TILED_VERSION);
         }
+
+        self.header.tile_size = tile_size;
+        Ok(())
     }
 
     /// Convenience method over [`SquishyPicture::from_raw`] which creates a
@@ -115,6 +828,34 @@ impl SquishyPicture {
         )
     }
 
+    /// Convenience method over [`SquishyPicture::from_raw`] which creates an
+    /// image where every sample decodes to within `max_error` of the
+    /// original, see [`SquishyPicture::set_max_error`].
+    ///
+    /// # Example
+    /// ```
+    /// let sqp = sqp::SquishyPicture::from_raw_near_lossless(
+    ///     1920,
+    ///     1080,
+    ///     sqp::ColorFormat::Rgba8,
+    ///     2,
+    ///     vec![0u8; (1920 * 1080) * 4]
+    /// );
+    /// assert_eq!(sqp.max_error(), Some(2));
+    /// ```
+    pub fn from_raw_near_lossless(
+        width: u32,
+        height: u32,
+        color_format: ColorFormat,
+        max_error: u8,
+        bitmap: Vec<u8>,
+    ) -> Self {
+        let mut picture = Self::from_raw_lossless(width, height, color_format, bitmap);
+        picture.set_max_error(max_error);
+
+        picture
+    }
+
     /// Convenience method over [`SquishyPicture::from_raw`] which creates a
     /// lossless image.
     ///
@@ -146,75 +887,979 @@ impl SquishyPicture {
     /// Encode the image into anything that implements [`Write`].
     ///
     /// Returns the number of bytes written.
-    pub fn encode<O: Write + WriteBytesExt>(&self, mut output: O) -> Result<usize, Error> {
-        let mut count = 0;
+    pub fn encode<O: Write>(&self, output: O) -> Result<usize, Error> {
+        self.encode_with_options(output, &EncodeOptions::default())
+    }
 
-        // Write out the header
-        count += self.header.write_into(&mut output)?;
+    /// Encode the image into anything that implements [`Write`], using the
+    /// given [`EncodeOptions`].
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode_with_options<O: Write>(&self, output: O, options: &EncodeOptions) -> Result<usize, Error> {
+        Ok(self.encode_with_stats(output, options)?.bytes_written)
+    }
 
-        // Based on the compression type, modify the data accordingly
-        let modified_data = match self.header.compression_type {
-            CompressionType::None => &self.bitmap,
-            CompressionType::Lossless => {
-                &sub_rows(
-                    self.header.width,
-                    self.header.height,
-                    self.header.color_format,
-                    &self.bitmap
-                )
-            },
-            CompressionType::LossyDct => {
-                &dct_compress(
-                    &self.bitmap,
-                    DctParameters {
-                        quality: self.header.quality as u32,
-                        format: self.header.color_format,
-                        width: self.header.width as usize,
-                        height: self.header.height as usize,
-                    }
-                )
-                .concat()
-                .into_iter()
-                .flat_map(VarInt::encode_var_vec)
-                .collect()
+    /// Encode the image into anything that implements [`Write`], using the
+    /// given [`EncodeOptions`], and report how it was encoded.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{options::EncodeOptions, ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossy(64, 64, ColorFormat::Gray8, 80, vec![0x80; 64 * 64]);
+    ///
+    /// let mut encoded = Vec::new();
+    /// let stats = sqp.encode_with_stats(&mut encoded, &EncodeOptions::new().target_psnr(42.0)).unwrap();
+    ///
+    /// assert_eq!(stats.bytes_written, encoded.len());
+    /// assert!(stats.target_met);
+    /// ```
+    pub fn encode_with_stats<O: Write>(&self, mut output: O, options: &EncodeOptions) -> Result<EncodeStats, Error> {
+        threads::run(&options.threads, || {
+            self.check_encodable()?;
+
+            let (prepared, psnr) = self.prepare(options)?;
+            let picture = prepared.as_ref().unwrap_or(self);
+
+            let bytes_written = if options.low_memory {
+                let reader = SqpEncodeReader::prepared(picture, options)?;
+                check_encoded_size(reader.len(), options)?;
+
+                reader.write_into(&mut output)?
+            } else {
+                let (compressed_data, compression_info) = picture.compress_bitmap(options)?;
+                check_encoded_size(
+                    picture.header.len()
+                        + chunk_table_len(&picture.header, &compression_info, options)
+                        + compressed_data.len()
+                        + picture.header.footer_len(),
+                    options,
+                )?;
+
+                picture.write_compressed(&mut output, &compressed_data, &compression_info, options)?
+            };
+
+            Ok(picture.encode_stats(bytes_written, psnr, options))
+        })
+    }
+
+    /// The stats for having encoded this picture, as prepared for the given
+    /// options.
+    pub(crate) fn encode_stats(&self, bytes_written: usize, psnr: Option<f64>, options: &EncodeOptions) -> EncodeStats {
+        EncodeStats {
+            bytes_written,
+            color_format: self.header.color_format,
+            quality: self.quality(),
+            psnr,
+            target_met: match (psnr, options.target_psnr) {
+                (Some(psnr), Some(target)) => psnr >= target,
+                _ => true,
             },
-        };
+        }
+    }
+
+    /// Encode the image into anything that implements [`Write`], also
+    /// returning the [`Intermediates`] produced along the way.
+    ///
+    /// The output is the same as [`SquishyPicture::encode_with_options`].
+    /// This is meant for studying how the filtering and transforms affect
+    /// compression, and nothing else is written anywhere.
+    ///
+    /// Returns the number of bytes written and the intermediate data.
+    pub fn encode_with_intermediates<O: Write>(
+        &self,
+        mut output: O,
+        options: &EncodeOptions,
+    ) -> Result<(usize, Intermediates), Error> {
+        threads::run(&options.threads, || {
+            self.check_encodable()?;
+
+            let (prepared, _) = self.prepare(options)?;
+            let picture = prepared.as_ref().unwrap_or(self);
+
+            let filtered = picture.filtered_bitmap(options).into_owned();
+            let primer = shared_dictionary(&picture.header, options).map(Dictionary::primer);
+            let (compressed_data, compression_info) = compress_with_primer(&filtered, Backend::from(&picture.header), primer)?;
+
+            let count = picture.write_compressed(&mut output, &compressed_data, &compression_info, options)?;
+
+            Ok((count, Intermediates { filtered, compression_info }))
+        })
+    }
 
-        // Compress the final image data using the basic LZW scheme
-        let (compressed_data, compression_info) = compress(modified_data)?;
+    /// Write out the header followed by already compressed image data.
+    pub(crate) fn write_compressed<O: Write>(
+        &self,
+        output: &mut O,
+        compressed_data: &[u8],
+        compression_info: &CompressionInfo,
+        options: &EncodeOptions,
+    ) -> Result<usize, Error> {
+        let mut count = 0;
+
+        // Write out the header
+        count += self.header.write_into(output)?;
 
         // Write out compression info
-        count += compression_info.write_into(&mut output).unwrap();
+        count += write_chunk_table(output, self, compression_info, options)?;
 
         // Write out compressed data
-        output.write_all(&compressed_data).unwrap();
+        output.write_all(compressed_data)?;
         count += compressed_data.len();
 
+        count += write_footer(output, &self.header, count as u64)?;
+
         Ok(count)
     }
 
-    /// Encode and write the image out to a file.
+    /// Encode the image into anything that implements [`Write`], writing
+    /// each compressed chunk as soon as it is ready.
+    ///
+    /// The output is identical to [`SquishyPicture::encode`], but the
+    /// compressed image is never held in memory all at once, which lowers
+    /// peak memory use for large images. The chunk table comes before the
+    /// chunks, so the image is compressed twice: once to measure the chunks
+    /// and once to write them. Expect encoding to take about twice as long.
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode_streaming<O: Write>(&self, mut output: O) -> Result<usize, Error> {
+        let reader = SqpEncodeReader::new(self)?;
+
+        Ok(reader.write_into(&mut output)?)
+    }
+
+    /// Encode the image into a new [`Vec`].
     ///
     /// Convenience method over [`SquishyPicture::encode`]
-    pub fn save<P: ?Sized + AsRef<std::path::Path>>(&self, path: &P) -> Result<(), Error> {
-        let mut out_file = BufWriter::new(File::create(path.as_ref())?);
+    pub fn encode_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        self.encode(&mut output)?;
 
-        self.encode(&mut out_file)?;
+        Ok(output)
+    }
 
-        Ok(())
+    /// Filter or transform the bitmap according to the compression type and
+    /// compress the result, without writing anything out.
+    pub(crate) fn compress_bitmap(&self, options: &EncodeOptions) -> Result<(Vec<u8>, CompressionInfo), Error> {
+        self.check_encodable()?;
+
+        let primer = shared_dictionary(&self.header, options).map(Dictionary::primer);
+        Ok(compress_with_primer(&self.filtered_bitmap(options), Backend::from(&self.header), primer)?)
+    }
+
+    /// The image as it should actually be encoded with the given options,
+    /// if that differs from this one, along with the PSNR measured when
+    /// choosing a quality.
+    ///
+    /// The image may be converted to a smaller format and have the color
+    /// under its transparent pixels cleaned up, and its quality chosen to
+    /// reach a target PSNR, then lowered to fit a size limit.
+    pub(crate) fn prepare(&self, options: &EncodeOptions) -> Result<(Option<Self>, Option<f64>), Error> {
+        let mut prepared = None;
+        // Converting to gray would lose the transparent color
+        if options.auto_optimize_format && self.header.transparent_color.is_none() {
+            let suggested_format = self.analyze_content().suggested_format;
+            if suggested_format != self.header.color_format {
+                debug!("converting from {:?} to {:?} before encoding", self.header.color_format, suggested_format);
+                prepared = Some(self.convert_color_format(suggested_format));
+            }
+        }
+
+        let picture = prepared.as_ref().unwrap_or(self);
+        if let (Some(cleanup), Some(_)) = (options.clean_transparent, picture.header.color_format.alpha_channel()) {
+            let mut picture = prepared.unwrap_or_else(|| Self {
+                header: self.header,
+                bitmap: self.bitmap.clone(),
+                encoder_info: None,
+            });
+            clean_transparent(picture.header.width, picture.header.color_format, cleanup, &mut picture.bitmap);
+            prepared = Some(picture);
+        }
+
+        let mut psnr = None;
+        if let (Some(target), CompressionType::LossyDct) = (options.target_psnr, self.header.compression_type) {
+            let picture = prepared.as_ref().unwrap_or(self);
+            let (quality, measured) = quality_for_psnr(&picture.bitmap, dct_parameters(&picture.header), target);
+            debug!("chose quality {quality} for a PSNR of {measured:.2} dB, aiming for {target}");
+            psnr = Some(measured);
+
+            // The search is over the matrices for each quality, so any
+            // matrices of its own are replaced
+            if quality != picture.header.quality || picture.header.quantization_matrices.is_some() {
+                let mut picture = prepared.unwrap_or_else(|| Self {
+                    header: self.header,
+                    bitmap: self.bitmap.clone(),
+                    encoder_info: None,
+                });
+                picture.header.quality = quality;
+                picture.header.quantization_matrices = None;
+                prepared = Some(picture);
+            }
+        }
+
+        if let (Some(max_size), CompressionType::LossyDct) = (options.max_encoded_size, self.header.compression_type) {
+            let picture = prepared.as_ref().unwrap_or(self);
+            let quality = quality_for_size(&picture.header, &picture.bitmap, max_size)?;
+            debug!("chose quality {quality} to fit in {max_size} bytes");
+
+            if quality != picture.header.quality || picture.header.quantization_matrices.is_some() {
+                let mut picture = prepared.unwrap_or_else(|| Self {
+                    header: self.header,
+                    bitmap: self.bitmap.clone(),
+                    encoder_info: None,
+                });
+                picture.header.quality = quality;
+                picture.header.quantization_matrices = None;
+                prepared = Some(picture);
+            }
+        }
+
+        // The filters for each row are stored from version 7 on
+        let picture = prepared.as_ref().unwrap_or(self);
+        if options.filter_strategy.is_some() && picture.header.compression_type.is_row_filtered() && !picture.header.stored_row_filters() {
+            let mut picture = prepared.unwrap_or_else(|| Self {
+                header: self.header,
+                bitmap: self.bitmap.clone(),
+                encoder_info: None,
+            });
+            picture.header.row_filters = true;
+            picture.header.version = picture.header.version.max(FIRST_ROW_FILTER_VERSION);
+            prepared = Some(picture);
+        }
+
+        Ok((prepared, psnr))
+    }
+
+    /// Check that the image can be encoded as the header describes, so an
+    /// image which could never decode is not written.
+    pub(crate) fn check_encodable(&self) -> Result<(), Error> {
+        let header = &self.header;
+
+        // Decoders reject images without pixels, so they are never written,
+        // and the compressors never see them
+        if !matches!(header.bitmap_len(), Some(1..)) {
+            return Err(Error::InvalidDimensions { width: header.width, height: header.height })
+        }
+
+        if header.is_lossy() && !header.color_format.supports_lossy() {
+            return Err(Error::LossyUnsupported(header.color_format))
+        }
+
+        self.check_bitmap_len()
+    }
+
+    /// Check that the picture is consistent with itself, returning every
+    /// problem found rather than only the first.
+    ///
+    /// The bitmap must be the size the width, height and color format
+    /// describe, the image must have pixels and fit within
+    /// [`Limits::default`], and the quality, transparent color and the
+    /// fields only lossy or lossless images use must fit the compression
+    /// type and color format. Use [`check_integrity_with_limits`] for other
+    /// limits, and [`verify_file`] to check an encoded file.
+    ///
+    /// [`check_integrity_with_limits`]: Self::check_integrity_with_limits
+    /// [`verify_file`]: crate::integrity::verify_file
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{integrity::IntegrityIssue, ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 2, ColorFormat::Gray8, vec![0, 255, 255]);
+    /// let issues = sqp.check_integrity().unwrap_err();
+    /// assert!(matches!(issues[..], [IntegrityIssue::SizeMismatch { expected: 4, actual: 3 }]));
+    /// ```
+    pub fn check_integrity(&self) -> Result<(), Vec<IntegrityIssue>> {
+        self.check_integrity_with_limits(Limits::default())
+    }
+
+    /// Check that the picture is consistent with itself like
+    /// [`check_integrity`](Self::check_integrity), with different
+    /// [`Limits`] than the default.
+    pub fn check_integrity_with_limits(&self, limits: Limits) -> Result<(), Vec<IntegrityIssue>> {
+        let issues = picture_issues(self, &limits);
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    /// Check that the bitmap is exactly the size the header describes.
+    pub(crate) fn check_bitmap_len(&self) -> Result<(), Error> {
+        let expected = self.header.bitmap_len().unwrap_or(usize::MAX);
+        if self.bitmap.len() != expected {
+            return Err(Error::SizeMismatch { expected, actual: self.bitmap.len() })
+        }
+
+        Ok(())
+    }
+
+    /// Filter or transform the bitmap according to the compression type,
+    /// producing the data which gets compressed.
+    pub(crate) fn filtered_bitmap(&self, options: &EncodeOptions) -> Cow<'_, [u8]> {
+        // Based on the compression type, modify the data accordingly
+        match self.header.compression_type {
+            CompressionType::None | CompressionType::Predictive => Cow::Borrowed(&self.bitmap),
+            CompressionType::Lossless | CompressionType::LosslessV2 | CompressionType::LosslessBwt => {
+                let mut output = Vec::new();
+                filter_rows(&self.header, &self.bitmap, options, &mut output);
+
+                Cow::Owned(output)
+            },
+            CompressionType::LossyDct => {
+                let parameters = dct_parameters(&self.header);
+
+                // Tiles need the coefficients of every channel at once
+                if options.low_memory && self.header.stored_tile_size().is_none() {
+                    let mut output = Vec::new();
+                    write_coefficient_count(&self.header, &mut output);
+                    if self.header.range_coded {
+                        let mut encoder = CoefficientEncoder::new(&mut output);
+                        dct_compress_sequential(&self.bitmap, parameters, |channel| encoder.encode(channel));
+                        encoder.finish();
+                    } else {
+                        dct_compress_sequential(&self.bitmap, parameters, |channel| {
+                            channel.iter().for_each(|c| write_varint(&mut output, *c))
+                        });
+                    }
+
+                    Cow::Owned(output)
+                } else {
+                    Cow::Owned(coefficient_stream(&self.header, &dct_compress(&self.bitmap, parameters)))
+                }
+            },
+        }
+    }
+
+    /// Encode and write the image out to a file.
+    ///
+    /// Convenience method over [`SquishyPicture::encode`]
+    #[cfg(feature = "std")]
+    pub fn save<P: ?Sized + AsRef<Path>>(&self, path: &P) -> Result<(), Error> {
+        let mut out_file = BufWriter::new(File::create(path.as_ref())?);
+
+        self.encode(&mut out_file)?;
+
+        Ok(())
     }
 
     /// Decode the image from anything that implements [`Read`]
-    pub fn decode<I: Read + ReadBytesExt>(mut input: I) -> Result<Self, Error> {
+    ///
+    /// The header and chunk table are read a few bytes at a time, so the
+    /// input should be buffered if small reads are slow, such as with a
+    /// [`File`]. Wrap it in a [`BufReader`], or use [`open`] which does so.
+    ///
+    /// Images larger than [`Limits::default`] allows fail to decode, use
+    /// [`SquishyPicture::decode_with_limits`] for those.
+    pub fn decode<I: Read>(input: I) -> Result<Self, Error> {
+        Self::decode_with_options(input, &DecodeOptions::default())
+    }
+
+    /// Decode the image from anything that implements [`Read`], using the
+    /// given [`DecodeOptions`].
+    pub fn decode_with_options<I: Read>(input: I, options: &DecodeOptions) -> Result<Self, Error> {
+        Self::decode_with_warnings(input, options, &mut Warnings::new(options, false))
+    }
+
+    /// Decode the image from anything that implements [`Read`], using the
+    /// given [`DecodeOptions`], also returning the problems found with the
+    /// file along the way.
+    ///
+    /// The input is read to its end, to find any trailing bytes after the
+    /// image. Only problems which let decoding carry on are returned this
+    /// way, anything worse is still an [`Error`](enum@crate::picture::Error).
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{options::DecodeOptions, picture::DecodeWarning, ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+    /// let mut encoded = sqp.encode_to_vec().unwrap();
+    /// let end = encoded.len() as u64;
+    /// encoded.extend_from_slice(b"extra");
+    ///
+    /// let (decoded, warnings) = SquishyPicture::decode_with_report(encoded.as_slice(), &DecodeOptions::new()).unwrap();
+    /// assert_eq!(decoded.as_raw(), &[0, 255]);
+    /// assert_eq!(warnings, [DecodeWarning::TrailingBytes { offset: end, len: 5 }]);
+    /// ```
+    pub fn decode_with_report<I: Read>(input: I, options: &DecodeOptions) -> Result<(Self, Vec<DecodeWarning>), Error> {
+        let mut warnings = Warnings::new(options, true);
+        let picture = Self::decode_with_warnings(input, options, &mut warnings)?;
+
+        Ok((picture, warnings.found))
+    }
+
+    fn decode_with_warnings<I: Read>(mut input: I, options: &DecodeOptions, warnings: &mut Warnings) -> Result<Self, Error> {
+        threads::run(&options.threads, || {
+            let header = Header::read_from(&mut input)?;
+
+            let mut picture = if options.low_memory && header.compression_type == CompressionType::LossyDct {
+                Self::decode_lossy_streamed(header, input, options, warnings)?
+            } else {
+                Self::decode_with_header(header, input, options, warnings)?
+            };
+            picture.deblock(options.deblock)?;
+
+            Ok(picture)
+        })
+    }
+
+    /// Decode the image from anything that implements [`Read`], with
+    /// different [`Limits`] than the default.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{Limits, SquishyPicture};
+    ///
+    /// let data = include_bytes!("../test_images/test-lossless.sqp");
+    /// let sqp = SquishyPicture::decode_with_limits(data.as_slice(), Limits::unlimited()).unwrap();
+    ///
+    /// assert_eq!((sqp.width(), sqp.height()), (1123, 639));
+    /// ```
+    pub fn decode_with_limits<I: Read>(input: I, limits: Limits) -> Result<Self, Error> {
+        Self::decode_with_options(input, &DecodeOptions::new().limits(limits))
+    }
+
+    /// Decode the image from a byte slice.
+    ///
+    /// Unlike [`SquishyPicture::decode`], the compressed chunks are
+    /// decompressed directly from the slice without being copied first.
+    /// [`Limits::default`] applies the same way, see
+    /// [`SquishyPicture::decode_slice_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// let data = include_bytes!("../test_images/test-lossless.sqp");
+    /// let sqp = sqp::SquishyPicture::decode_slice(data).unwrap();
+    ///
+    /// assert_eq!((sqp.width(), sqp.height()), (1123, 639));
+    /// ```
+    pub fn decode_slice(data: &[u8]) -> Result<Self, Error> {
+        Self::decode_slice_with_limits(data, Limits::default())
+    }
+
+    /// Decode the image from a byte slice like
+    /// [`decode_slice`](Self::decode_slice), with different [`Limits`]
+    /// than the default.
+    pub fn decode_slice_with_limits(data: &[u8], limits: Limits) -> Result<Self, Error> {
+        let mut input = data;
+
+        let header = Header::read_from(&mut input)?;
+        let table = read_chunk_table(&mut input, &header, &limits)?;
+        limits.check_alloc(total_size_raw(&table.compression_info.chunks))?;
+        table.check_input_len(data.len() as u64)?;
+        let ChunkTable { compression_info, encoder_info, .. } = table;
+
+        let mut chunks = Vec::new();
+        for chunk in &compression_info.chunks {
+            if input.len() < chunk.size_compressed {
+                return Err(Error::TruncatedFile { section: FileSection::ChunkData })
+            }
+
+            let (compressed, rest) = input.split_at(chunk.size_compressed);
+            chunks.push((compressed, chunk.size_raw));
+            input = rest;
+        }
+
+        let image_len = (data.len() - input.len()) as u64;
+        let mut warnings = Warnings::ignored();
+        warnings.check_footer(&mut input, &header, image_len)?;
+
+        let pre_bitmap = decompress_chunks(&chunks, Backend::from(&header), &mut Vec::new());
+
+        let mut picture = Self::from_decompressed(header, pre_bitmap, &mut warnings)?;
+        picture.encoder_info = encoder_info;
+
+        Ok(picture)
+    }
+
+    /// Read the content hash stored with an image, without decoding it.
+    ///
+    /// This is an xxHash64, with a seed of 0, of the raw bitmap which was
+    /// encoded, written with [`EncodeOptions::content_hash`]. Only the
+    /// header and the sections before the pixel data are read. Returns
+    /// [`None`] if the file has no hash, or one from an unknown algorithm.
+    ///
+    /// [`EncodeOptions::content_hash`]: crate::options::EncodeOptions::content_hash
+    pub fn read_content_hash<I: Read>(mut input: I) -> Result<Option<u64>, Error> {
+        let header = Header::read_from(&mut input)?;
+        let ChunkTable { content_hash, .. } = read_chunk_table(&mut input, &header, &Limits::default())?;
+
+        Ok(content_hash)
+    }
+
+    /// Decode the image from anything that implements [`Read`] and check
+    /// the result against its stored content hash.
+    ///
+    /// Lossy images are never decoded to the exact bitmap which was
+    /// hashed, so they aren't decoded at all and give
+    /// [`ContentCheck::Lossy`]. Files without a hash give
+    /// [`ContentCheck::Missing`].
+    ///
+    /// Decoding with [`DecodeOptions::strict`] does the same check, and
+    /// fails on a mismatch. [`Limits::default`] applies, see
+    /// [`SquishyPicture::verify_content_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{options::EncodeOptions, picture::ContentCheck, ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+    /// let mut encoded = Vec::new();
+    /// sqp.encode_with_options(&mut encoded, &EncodeOptions::new().content_hash(true)).unwrap();
+    ///
+    /// let check = SquishyPicture::verify_content(encoded.as_slice()).unwrap();
+    /// assert_eq!(check, ContentCheck::Match);
+    /// ```
+    #[cfg(feature = "content-hash")]
+    pub fn verify_content<I: Read>(input: I) -> Result<ContentCheck, Error> {
+        Self::verify_content_with_limits(input, Limits::default())
+    }
+
+    /// Check an image against its stored content hash like
+    /// [`verify_content`](Self::verify_content), with different [`Limits`]
+    /// than the default.
+    #[cfg(feature = "content-hash")]
+    pub fn verify_content_with_limits<I: Read>(mut input: I, limits: Limits) -> Result<ContentCheck, Error> {
+        let header = Header::read_from(&mut input)?;
+        let ChunkTable { compression_info, data_offset, content_hash, .. } = read_chunk_table(&mut input, &header, &limits)?;
+
+        let Some(expected) = content_hash else {
+            return Ok(ContentCheck::Missing)
+        };
+        if header.is_lossy() {
+            return Ok(ContentCheck::Lossy)
+        }
+
+        limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+        let pre_bitmap = decompress(&mut input, &compression_info, Backend::from(&header), &mut Vec::new())
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        Warnings::ignored().check_footer(&mut input, &header, image_len)?;
+        let picture = Self::from_decompressed(header, pre_bitmap, &mut Warnings::ignored())?;
+
+        let actual = crate::container::content_hash(&picture.bitmap);
+        Ok(if actual == expected {
+            ContentCheck::Match
+        } else {
+            ContentCheck::Mismatch { expected, actual }
+        })
+    }
+
+    /// Decode the image from anything that implements [`Read`], scaled down
+    /// to 1/`denominator` of its width and height, rounded up.
+    ///
+    /// The denominator must be 1, 2, 4 or 8. Lossy images are scaled while
+    /// decoding, using only the lowest frequencies of each DCT block, so
+    /// the full size image is never built and the inverse DCT does a
+    /// fraction of the work. Other images are decoded in full and then
+    /// shrunk by averaging each square of pixels.
+    ///
+    /// The header of the returned picture has the reduced dimensions.
+    /// [`Limits::default`] applies to the full size image, see
+    /// [`SquishyPicture::decode_scaled_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossy(100, 60, ColorFormat::Gray8, 80, vec![128; 100 * 60]);
+    /// let encoded = sqp.encode_to_vec().unwrap();
+    ///
+    /// let thumbnail = SquishyPicture::decode_scaled(encoded.as_slice(), 4).unwrap();
+    /// assert_eq!((thumbnail.width(), thumbnail.height()), (25, 15));
+    /// ```
+    pub fn decode_scaled<I: Read>(input: I, denominator: u8) -> Result<Self, Error> {
+        Self::decode_scaled_with_limits(input, denominator, Limits::default())
+    }
+
+    /// Decode the image scaled down like
+    /// [`decode_scaled`](Self::decode_scaled), with different [`Limits`]
+    /// than the default.
+    pub fn decode_scaled_with_limits<I: Read>(input: I, denominator: u8, limits: Limits) -> Result<Self, Error> {
+        if !matches!(denominator, 1 | 2 | 4 | 8) {
+            return Err(Error::InvalidScale(denominator))
+        }
+
+        Self::decode_reduced(input, denominator, limits, |blocks, parameters, region| {
+            dct_decompress_region(blocks, parameters, region, denominator as usize)
+        })
+    }
+
+    /// Decode a quick preview of the image from anything that implements
+    /// [`Read`], with one pixel for every 8x8 square of the image.
+    ///
+    /// For lossy images, each pixel is the average of a DCT block, taken
+    /// from its DC coefficient alone, so no inverse DCT runs at all. Other
+    /// images are decoded in full and shrunk by averaging each square of
+    /// pixels. Either way, the preview has the same dimensions as
+    /// [`SquishyPicture::decode_scaled`] with a denominator of 8.
+    /// [`Limits::default`] applies to the full size image, see
+    /// [`SquishyPicture::decode_preview_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossy(100, 60, ColorFormat::Gray8, 80, vec![128; 100 * 60]);
+    /// let encoded = sqp.encode_to_vec().unwrap();
+    ///
+    /// let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+    /// assert_eq!((preview.width(), preview.height()), (13, 8));
+    /// ```
+    pub fn decode_preview<I: Read>(input: I) -> Result<Self, Error> {
+        Self::decode_preview_with_limits(input, Limits::default())
+    }
+
+    /// Decode a preview of the image like
+    /// [`decode_preview`](Self::decode_preview), with different [`Limits`]
+    /// than the default.
+    pub fn decode_preview_with_limits<I: Read>(input: I, limits: Limits) -> Result<Self, Error> {
+        Self::decode_reduced(input, 8, limits, |blocks, parameters, region| dct_preview(blocks, parameters, region))
+    }
+
+    /// Decode a region of the image from anything that implements [`Read`],
+    /// `width` by `height` pixels with its top left corner at `x`, `y`.
+    ///
+    /// For lossy images split into tiles, see
+    /// [`SquishyPicture::set_tile_size`], only the tiles which the region
+    /// covers are decoded. Other images are decoded in full and cropped.
+    /// Either way, the pixels are the same as in the full image.
+    ///
+    /// The region must not be empty and must be within the image, or this
+    /// fails with [`Error::InvalidRegion`]. The header of the returned
+    /// picture has the dimensions of the region. [`Limits::default`]
+    /// applies to the full size image, see
+    /// [`SquishyPicture::decode_region_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_raw_lossy(100, 60, ColorFormat::Gray8, 80, vec![128; 100 * 60]);
+    /// sqp.set_tile_size(Some(32)).unwrap();
+    /// let encoded = sqp.encode_to_vec().unwrap();
+    ///
+    /// let region = SquishyPicture::decode_region(encoded.as_slice(), 40, 10, 30, 20).unwrap();
+    /// assert_eq!((region.width(), region.height()), (30, 20));
+    /// ```
+    pub fn decode_region<I: Read>(input: I, x: u32, y: u32, width: u32, height: u32) -> Result<Self, Error> {
+        Self::decode_region_with_limits(input, x, y, width, height, Limits::default())
+    }
+
+    /// Decode a region of the image like
+    /// [`decode_region`](Self::decode_region), with different [`Limits`]
+    /// than the default.
+    pub fn decode_region_with_limits<I: Read>(
+        mut input: I,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        limits: Limits,
+    ) -> Result<Self, Error> {
+        let mut header = Header::read_from(&mut input)?;
+        let within = |start: u32, len: u32, size: u32| len > 0 && start.checked_add(len).is_some_and(|end| end <= size);
+        if !within(x, width, header.width) || !within(y, height, header.height) {
+            return Err(Error::InvalidRegion { x, y, width, height })
+        }
+
+        let rect = (x as usize, y as usize, width as usize, height as usize);
+
+        let Some(tile_size) = header.stored_tile_size() else {
+            let options = DecodeOptions::new().limits(limits);
+            let picture = Self::decode_with_header(header, input, &options, &mut Warnings::ignored())?;
+            return Ok(picture.crop(rect))
+        };
+
+        let ChunkTable { compression_info, data_offset, encoder_info, .. } = read_chunk_table(&mut input, &header, &limits)?;
+        limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+        let pre_bitmap = decompress(&mut input, &compression_info, Backend::from(&header), &mut Vec::new())
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        Warnings::ignored().check_footer(&mut input, &header, image_len)?;
+        let stream = strip_coefficient_count(&header, &pre_bitmap)?;
+
+        // Every block the region touches
+        let parameters = dct_parameters(&header);
+        let wanted = BlockRegion {
+            x: rect.0 / 8,
+            y: rect.1 / 8,
+            width: (rect.0 + rect.2).div_ceil(8) - rect.0 / 8,
+            height: (rect.1 + rect.3).div_ceil(8) - rect.1 / 8,
+        };
+        let decode = |blocks: &mut CoefficientBlocks, parameters, region| {
+            dct_decompress_region(blocks, parameters, region, 1)
+        };
+        let channels = header.color_format.channels() as usize;
+        let covered = decode_tiles(&header, tile_size, stream, wanted, 1, channels, decode, &mut Warnings::ignored())?;
+
+        let mut bitmap = vec![0; rect.2 * rect.3 * channels];
+        copy_overlap(&covered, scaled_rect(parameters, wanted, 1), &mut bitmap, rect, channels);
+
+        header.width = width;
+        header.height = height;
+
+        Ok(Self { header, bitmap, encoder_info })
+    }
+
+    /// Decode a single channel of the image from anything that implements
+    /// [`Read`], along with its [`Header`]. The channel is a plane of one
+    /// byte per pixel, in rows from the top left.
+    ///
+    /// For lossy images, the blocks of the other channels are skipped
+    /// without their inverse DCT, which is most of the work of decoding
+    /// them. Other images have their channels filtered together, so they
+    /// are decoded in full before the channel is taken out.
+    /// [`ColorFormat::Gray4`] samples are unpacked into a byte each.
+    ///
+    /// Fails with [`Error::InvalidChannel`] if the image doesn't have the
+    /// channel. [`Limits::default`] applies, see
+    /// [`SquishyPicture::decode_channel_with_limits`] for others.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_fn(20, 10, ColorFormat::GrayA8, |x, _, pixel| {
+    ///     pixel.copy_from_slice(&[90, x as u8 * 10]);
+    /// });
+    /// let encoded = sqp.encode_to_vec().unwrap();
+    ///
+    /// let (header, alpha) = SquishyPicture::decode_channel(encoded.as_slice(), 1).unwrap();
+    /// assert_eq!((header.width, header.height), (20, 10));
+    /// assert_eq!(alpha[..3], [0, 10, 20]);
+    /// ```
+    pub fn decode_channel<I: Read>(input: I, channel: usize) -> Result<(Header, Vec<u8>), Error> {
+        Self::decode_channel_with_limits(input, channel, Limits::default())
+    }
+
+    /// Decode a single channel of the image like
+    /// [`decode_channel`](Self::decode_channel), with different [`Limits`]
+    /// than the default.
+    pub fn decode_channel_with_limits<I: Read>(mut input: I, channel: usize, limits: Limits) -> Result<(Header, Vec<u8>), Error> {
         let header = Header::read_from(&mut input)?;
+        let channels = header.color_format.channels() as usize;
+        if channel >= channels {
+            return Err(Error::InvalidChannel { channel, channels })
+        }
+
+        if header.compression_type != CompressionType::LossyDct {
+            let options = DecodeOptions::new().limits(limits);
+            let picture = Self::decode_with_header(header, input, &options, &mut Warnings::ignored())?;
+            let picture = picture.unpacked().unwrap_or(picture);
+            let plane = picture.bitmap.iter().skip(channel).step_by(channels).copied().collect();
+            return Ok((header, plane))
+        }
+
+        let ChunkTable { compression_info, data_offset, .. } = read_chunk_table(&mut input, &header, &limits)?;
+        limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+        let pre_bitmap = decompress(&mut input, &compression_info, Backend::from(&header), &mut Vec::new())
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        Warnings::ignored().check_footer(&mut input, &header, image_len)?;
+        let decode = |blocks: &mut CoefficientBlocks, parameters, region| {
+            dct_decompress_channel(blocks, parameters, region, channel)
+        };
+        let plane = decode_coefficients(&header, &pre_bitmap, 1, 1, decode, &mut Warnings::ignored())?;
+
+        Ok((header, plane))
+    }
+
+    /// Cut the picture down to a rectangle within it.
+    fn crop(self, rect: Rect) -> Self {
+        if let Some(gray) = self.unpacked() {
+            let mut cropped = gray.crop(rect).convert_color_format(ColorFormat::Gray4);
+            cropped.encoder_info = self.encoder_info;
+            return cropped
+        }
+
+        let channels = self.header.color_format.channels() as usize;
+        let mut bitmap = vec![0; rect.2 * rect.3 * channels];
+        let whole = (0, 0, self.header.width as usize, self.header.height as usize);
+        copy_overlap(&self.bitmap, whole, &mut bitmap, rect, channels);
+
+        Self {
+            header: Header { width: rect.2 as u32, height: rect.3 as u32, ..self.header },
+            bitmap,
+            encoder_info: self.encoder_info,
+        }
+    }
+
+    /// Decode the image shrunk by `denominator` on each side, using
+    /// `decode` to build lossy images from their coefficients.
+    fn decode_reduced<I, F>(mut input: I, denominator: u8, limits: Limits, decode: F) -> Result<Self, Error>
+    where
+        I: Read,
+        F: Fn(&mut CoefficientBlocks, DctParameters, BlockRegion) -> Option<Vec<u8>> + Sync,
+    {
+        let mut header = Header::read_from(&mut input)?;
+
+        if header.compression_type != CompressionType::LossyDct {
+            let options = DecodeOptions::new().limits(limits);
+            let picture = Self::decode_with_header(header, input, &options, &mut Warnings::ignored())?;
+            return Ok(picture.downscale(denominator))
+        }
+
+        let ChunkTable { compression_info, data_offset, encoder_info, .. } = read_chunk_table(&mut input, &header, &limits)?;
+        limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+        let pre_bitmap = decompress(&mut input, &compression_info, Backend::from(&header), &mut Vec::new())
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        Warnings::ignored().check_footer(&mut input, &header, image_len)?;
+        let channels = header.color_format.channels() as usize;
+        let bitmap = decode_coefficients(&header, &pre_bitmap, denominator as usize, channels, decode, &mut Warnings::ignored())?;
+
+        header.width = scaled_size(header.width as usize, denominator as usize) as u32;
+        header.height = scaled_size(header.height as usize, denominator as usize) as u32;
+
+        Ok(Self { header, bitmap, encoder_info })
+    }
+
+    /// Shrink the picture by `denominator` on each side with [`downscale`].
+    fn downscale(mut self, denominator: u8) -> Self {
+        if denominator == 1 {
+            return self
+        }
+
+        if let Some(gray) = self.unpacked() {
+            let mut scaled = gray.downscale(denominator).convert_color_format(ColorFormat::Gray4);
+            scaled.encoder_info = self.encoder_info;
+            return scaled
+        }
+
+        let header = &mut self.header;
+        self.bitmap = downscale(header.width, header.height, header.color_format, denominator as u32, &self.bitmap);
+        header.width = header.width.div_ceil(denominator as u32);
+        header.height = header.height.div_ceil(denominator as u32);
+
+        self
+    }
+
+    /// Decode the rest of the image from anything that implements [`Read`],
+    /// after its [`Header`] has already been read.
+    pub(crate) fn decode_with_header<I: Read>(
+        header: Header,
+        mut input: I,
+        options: &DecodeOptions,
+        warnings: &mut Warnings,
+    ) -> Result<Self, Error> {
+        let limits = &options.limits;
+        let mut reader = ChunkTableReader::new(&header, limits)?
+            .with_shared_dictionary(options.shared_dictionary.as_ref());
+        read_table_parts(&mut input, &mut reader)?;
+        let table = reader.finish();
+        limits.check_alloc(total_size_raw(&table.compression_info.chunks))?;
+        if let Some(input_len) = options.input_len {
+            table.check_input_len(input_len)?;
+        }
+
+        let ChunkTable { compression_info, data_offset, encoder_info, content_hash, shared_dictionary } = table;
+
+        // The dictionary has been checked against the one the image needs
+        let primer = shared_dictionary.and(options.shared_dictionary.as_ref()).map(Dictionary::primer);
+        let mut chunk_warnings = Vec::new();
+        let pre_bitmap = decompress_with_primer(&mut input, &compression_info, Backend::from(&header), primer, &mut chunk_warnings)
+            .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+        warnings.add_all(chunk_warnings)?;
+        let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+        let footer_len = warnings.check_footer(&mut input, &header, image_len)?;
+
+        let mut picture = Self::from_decompressed(header, pre_bitmap, warnings)?;
+        picture.encoder_info = encoder_info;
+        warnings.check_content_hash(&picture, content_hash)?;
+        warnings.check_trailing_bytes(&mut input, image_len + footer_len)?;
+
+        Ok(picture)
+    }
+
+    /// Decode the rest of a lossy image, decompressing one chunk at a time
+    /// as the inverse DCT needs more coefficients.
+    fn decode_lossy_streamed<I: Read>(
+        header: Header,
+        mut input: I,
+        options: &DecodeOptions,
+        warnings: &mut Warnings,
+    ) -> Result<Self, Error> {
+        let table = read_chunk_table(&mut input, &header, &options.limits)?;
+        let largest_chunk = table.compression_info.chunks.iter().map(|c| c.size_raw).max();
+        options.limits.check_alloc(largest_chunk.unwrap_or(0))?;
+        if let Some(input_len) = options.input_len {
+            table.check_input_len(input_len)?;
+        }
+
+        let ChunkTable { compression_info, data_offset, encoder_info, .. } = table;
+
+        let parameters = dct_parameters(&header);
+        let expected = parameters.coefficient_count().unwrap_or(usize::MAX);
+        let mut blocks = StreamedCoefficientBlocks::new(
+            input,
+            &compression_info.chunks,
+            Backend::from(&header),
+            header.range_coded,
+        );
+
+        if header.version >= 1 {
+            let stored = blocks.read_coefficient_count();
+            if let Some(error) = blocks.error.take() {
+                return Err(Error::from_read(error, FileSection::ChunkData))
+            }
+
+            check_coefficient_count(expected, stored)?;
+        }
+
+        let bitmap = match header.stored_tile_size() {
+            Some(tile_size) => decode_tiles_streamed(&mut blocks, parameters, tile_size)?,
+            None => dct_decompress(&mut blocks, parameters),
+        };
+        if let Some(error) = blocks.error.take() {
+            return Err(Error::from_read(error, FileSection::ChunkData))
+        }
+        warnings.add_all(core::mem::take(&mut blocks.warnings))?;
 
-        let compression_info = CompressionInfo::read_from(&mut input);
+        let Some(bitmap) = bitmap else {
+            return Err(coefficients_missing(&header, expected, blocks.count))
+        };
+
+        let trailing = blocks.remaining_len();
+        if trailing > 0 {
+            warnings.add(DecodeWarning::TrailingCoefficients { len: trailing })?;
+        }
+
+        // The rest of the chunks are only read for the footer after them
+        if warnings.wanted() || header.footer_len() > 0 {
+            let mut input = blocks.skip_rest()
+                .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+            let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+            let footer_len = warnings.check_footer(&mut input, &header, image_len)?;
+            warnings.check_trailing_bytes(&mut input, image_len + footer_len)?;
+        }
+
+        Ok(Self { header, bitmap, encoder_info })
+    }
 
-        let pre_bitmap = decompress(&mut input, &compression_info);
+    /// Reverse the filtering or transform applied by
+    /// [`SquishyPicture::compress_bitmap`] on decompressed image data.
+    pub(crate) fn from_decompressed(header: Header, pre_bitmap: Vec<u8>, warnings: &mut Warnings) -> Result<Self, Error> {
+        // Filtering doesn't change the size, so both must be exactly the
+        // size of the bitmap
+        if matches!(header.compression_type, CompressionType::None | CompressionType::Lossless | CompressionType::LosslessV2 | CompressionType::LosslessBwt | CompressionType::Predictive) {
+            let expected = header.filtered_len().unwrap_or(usize::MAX);
+            if pre_bitmap.len() != expected {
+                return Err(Error::SizeMismatch { expected, actual: pre_bitmap.len() })
+            }
+        }
 
         let bitmap = match header.compression_type {
-            CompressionType::None => pre_bitmap,
-            CompressionType::Lossless => {
+            CompressionType::None | CompressionType::Predictive => pre_bitmap,
+            CompressionType::Lossless | CompressionType::LosslessV2 | CompressionType::LosslessBwt if header.stored_row_filters() => {
+                unfilter_rows(header.width, header.height, header.color_format, &pre_bitmap)?
+            },
+            CompressionType::Lossless | CompressionType::LosslessV2 | CompressionType::LosslessBwt => {
                 add_rows(
                     header.width,
                     header.height,
@@ -223,46 +1868,4381 @@ impl SquishyPicture {
                 )
             },
             CompressionType::LossyDct => {
-                dct_decompress(
-                    &decode_varint_stream(&pre_bitmap),
-                    DctParameters {
-                        quality: header.quality as u32,
-                        format: header.color_format,
-                        width: header.width as usize,
-                        height: header.height as usize,
-                    }
-                )
+                let decode = |blocks: &mut CoefficientBlocks, parameters, region| {
+                    dct_decompress_region(blocks, parameters, region, 1)
+                };
+                let channels = header.color_format.channels() as usize;
+                decode_coefficients(&header, &pre_bitmap, 1, channels, decode, warnings)?
             },
         };
 
-        Ok(Self { header, bitmap })
+        Ok(Self { header, bitmap, encoder_info: None })
+    }
+
+    /// A copy of a [`ColorFormat::Gray4`] image unpacked into
+    /// [`ColorFormat::Gray8`], or [`None`] for any other format.
+    pub(crate) fn unpacked(&self) -> Option<Self> {
+        if self.header.color_format != ColorFormat::Gray4 {
+            return None
+        }
+
+        Some(Self {
+            header: Header { color_format: ColorFormat::Gray8, ..self.header },
+            bitmap: unpack_gray4(self.header.width, &self.bitmap),
+            encoder_info: None,
+        })
     }
 
     /// Get the underlying raw buffer as a reference
     pub fn as_raw(&self) -> &Vec<u8> {
         &self.bitmap
     }
-}
 
-/// Decode a stream encoded as varints.
-fn decode_varint_stream(stream: &[u8]) -> Vec<i16> {
-    let mut output = Vec::new();
-    let mut offset = 0;
+    /// Width of the image in pixels.
+    pub fn width(&self) -> u32 {
+        self.header.width
+    }
+
+    /// Height of the image in pixels.
+    pub fn height(&self) -> u32 {
+        self.header.height
+    }
+
+    /// The [`ColorFormat`] of the underlying raw buffer.
+    pub fn color_format(&self) -> ColorFormat {
+        self.header.color_format
+    }
+
+    /// The type of compression used when encoding the image.
+    pub fn compression_type(&self) -> CompressionType {
+        self.header.compression_type
+    }
+
+    /// The quality level used when encoding the image, or [`None`] if the
+    /// compression type is not lossy.
+    pub fn quality(&self) -> Option<u8> {
+        match self.header.compression_type {
+            CompressionType::LossyDct => Some(self.header.quality),
+            _ => None,
+        }
+    }
+
+    /// The most any sample can differ from the original once decoded, or
+    /// [`None`] if the image is [`CompressionType::LossyDct`] and there is
+    /// no bound.
+    ///
+    /// See [`SquishyPicture::set_max_error`].
+    pub fn max_error(&self) -> Option<u8> {
+        self.header.max_error()
+    }
+
+    /// The most any sample may differ from the original for a
+    /// [`CompressionType::Predictive`] image, where 0 is lossless, or
+    /// [`None`] for other compression types.
+    pub fn near(&self) -> Option<u8> {
+        match self.header.compression_type {
+            CompressionType::Predictive => Some(self.header.quality),
+            _ => None,
+        }
+    }
+
+    /// The color which marks transparent pixels, if any.
+    ///
+    /// See [`SquishyPicture::set_transparent_color`].
+    pub fn transparency(&self) -> Option<[u8; 3]> {
+        self.header.transparent_color
+    }
+
+    /// Whether the DCT coefficients are range coded when encoding.
+    ///
+    /// See [`SquishyPicture::set_range_coding`].
+    pub fn range_coded(&self) -> bool {
+        self.header.range_coded
+    }
+
+    /// The quantization matrix for each channel of a lossy image, if they
+    /// don't all use the one for the quality.
+    ///
+    /// See [`SquishyPicture::set_quantization_matrices`].
+    pub fn quantization_matrices(&self) -> Option<[[u16; 64]; 4]> {
+        self.header.quantization_matrices
+    }
+
+    /// The size of the tiles the coefficients of a lossy image are split
+    /// into, if they are.
+    ///
+    /// See [`SquishyPicture::set_tile_size`].
+    pub fn tile_size(&self) -> Option<u16> {
+        self.header.stored_tile_size()
+    }
+
+    /// What wrote the file this picture was decoded from, such as
+    /// `"myapp 1.2 (sqp 0.1.1)"`, if it said.
+    ///
+    /// This is [`None`] for pictures made in memory, for files from before
+    /// format version 3, and for files written with
+    /// [`EncodeOptions::omit_encoder_info`]. Encoding always writes the
+    /// info for the current encoder rather than this.
+    pub fn encoder_info(&self) -> Option<&str> {
+        self.encoder_info.as_deref()
+    }
+
+    /// Mark pixels of exactly `color` as fully transparent, or remove the
+    /// mark with [`None`].
+    ///
+    /// This gives [`ColorFormat::Rgb8`] images on or off transparency for
+    /// the cost of a few bytes in the header, rather than a whole alpha
+    /// channel. Other formats fail with [`Error::TransparencyUnsupported`].
+    ///
+    /// The pixels themselves are left as they are, use
+    /// [`SquishyPicture::expand_transparency`] to turn the color into an
+    /// alpha channel. Lossy compression changes colors slightly, so pixels
+    /// may no longer match the color exactly after decoding. Use lossless
+    /// compression for images with a transparent color.
+    ///
+    /// The color is stored from version 2 of the format on, so this
+    /// upgrades older headers.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgb8, vec![
+    ///     0xFF, 0x00, 0xFF,
+    ///     0x10, 0x20, 0x30,
+    /// ]);
+    /// sqp.set_transparent_color(Some([0xFF, 0x00, 0xFF])).unwrap();
+    ///
+    /// let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+    /// assert_eq!(decoded.transparency(), Some([0xFF, 0x00, 0xFF]));
+    /// assert_eq!(decoded.expand_transparency().as_raw(), &[
+    ///     0xFF, 0x00, 0xFF, 0x00,
+    ///     0x10, 0x20, 0x30, 0xFF,
+    /// ]);
+    /// ```
+    pub fn set_transparent_color(&mut self, color: Option<[u8; 3]>) -> Result<(), Error> {
+        if color.is_some() {
+            if self.header.color_format != ColorFormat::Rgb8 {
+                return Err(Error::TransparencyUnsupported(self.header.color_format))
+            }
+
+            self.header.version = self.header.version.max(2);
+        }
+
+        self.header.transparent_color = color;
+        Ok(())
+    }
+
+    /// Create a copy of the image in [`ColorFormat::Rgba8`], with the
+    /// transparent color turned into an alpha of 0 and every other pixel
+    /// given an alpha of 255.
+    ///
+    /// This is the same as converting to `Rgba8` with
+    /// [`SquishyPicture::convert_color_format`], and is what should be
+    /// shown for an image with a transparent color.
+    pub fn expand_transparency(&self) -> Self {
+        self.convert_color_format(ColorFormat::Rgba8)
+    }
+
+    /// Create a copy of the image with its pixels converted to another
+    /// [`ColorFormat`]. Compression settings are kept as they are.
+    ///
+    /// Converting to a format without alpha drops the alpha channel, and
+    /// converting from one fills it with 255. Color is reduced to gray
+    /// using the Rec. 601 luma weights.
+    ///
+    /// An image with a transparent color keeps it when converted to `Rgb8`.
+    /// Converting to a format with alpha gives pixels of that color an
+    /// alpha of 0, and any other format loses the transparency.
+    ///
+    /// Converting to [`ColorFormat::Gray4`] rounds each gray value to the
+    /// nearest of its 16 levels, and converting from it spreads the levels
+    /// evenly from 0 to 255.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{SquishyPicture, ColorFormat};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(
+    ///     1,
+    ///     1,
+    ///     ColorFormat::Gray8,
+    ///     vec![0x80]
+    /// );
+    ///
+    /// let rgba = sqp.convert_color_format(ColorFormat::Rgba8);
+    /// assert_eq!(rgba.as_raw(), &[0x80, 0x80, 0x80, 0xFF]);
+    /// ```
+    pub fn convert_color_format(&self, color_format: ColorFormat) -> Self {
+        if self.header.color_format != color_format {
+            if let Some(gray) = self.unpacked() {
+                return gray.convert_color_format(color_format)
+            }
+
+            if color_format == ColorFormat::Gray4 {
+                let gray = self.convert_color_format(ColorFormat::Gray8);
+                return Self {
+                    header: Header { color_format, quantization_matrices: None, ..gray.header },
+                    bitmap: pack_gray4(self.header.width, &gray.bitmap),
+                    encoder_info: None,
+                }
+            }
+        }
+
+        let bitmap = match self.header.transparent_color {
+            Some(key) if color_format.alpha_channel().is_some() => {
+                let rgba = expand_color_key(&self.bitmap, key);
+                convert_color_format(ColorFormat::Rgba8, color_format, &rgba)
+            },
+            _ => convert_color_format(self.header.color_format, color_format, &self.bitmap),
+        };
+
+        let transparent_color = match color_format {
+            ColorFormat::Rgb8 => self.header.transparent_color,
+            _ => None,
+        };
+
+        Self {
+            header: Header {
+                color_format,
+                transparent_color,
+                // The matrices are for the channels of the old format
+                quantization_matrices: self.header.quantization_matrices
+                    .filter(|_| color_format == self.header.color_format),
+                ..self.header
+            },
+            bitmap,
+            encoder_info: None,
+        }
+    }
+
+    /// A copy of the pixels as `Rgba8`, whatever format the image is in,
+    /// such as for uploading to a GPU texture.
+    ///
+    /// Gray is replicated into every color channel and missing alpha is
+    /// filled with 255, apart from pixels of the transparent color, which
+    /// get an alpha of 0. This is the same as converting to
+    /// [`ColorFormat::Rgba8`] with [`SquishyPicture::convert_color_format`],
+    /// without making a whole new picture or any buffer in between.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{SquishyPicture, ColorFormat};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::GrayA8, vec![0x10, 0x80, 0xF0, 0xFF]);
+    /// assert_eq!(sqp.to_rgba8(), [0x10, 0x10, 0x10, 0x80, 0xF0, 0xF0, 0xF0, 0xFF]);
+    /// ```
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        match self.header.color_format {
+            ColorFormat::Rgba8 => self.bitmap.clone(),
+            color_format => expand_to_rgba8(self.header.width, color_format, self.header.transparent_color, &self.bitmap),
+        }
+    }
+
+    /// Create a picture in any [`ColorFormat`] from `Rgba8` pixels, the
+    /// inverse of [`SquishyPicture::to_rgba8`].
+    ///
+    /// The pixels are packed down to `color_format` the same way as by
+    /// [`SquishyPicture::convert_color_format`], and the other arguments
+    /// are the same as for [`SquishyPicture::from_raw`].
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{SquishyPicture, ColorFormat, CompressionType};
+    ///
+    /// let rgba = vec![0x40, 0x40, 0x40, 0xFF, 0xC0, 0xC0, 0xC0, 0xFF];
+    /// let sqp = SquishyPicture::from_rgba8_with_format(2, 1, ColorFormat::Gray8, CompressionType::Lossless, None, rgba.clone());
+    ///
+    /// assert_eq!(sqp.as_raw(), &[0x40, 0xC0]);
+    /// assert_eq!(sqp.to_rgba8(), rgba);
+    /// ```
+    pub fn from_rgba8_with_format(
+        width: u32,
+        height: u32,
+        color_format: ColorFormat,
+        compression_type: CompressionType,
+        quality: Option<u8>,
+        rgba: Vec<u8>,
+    ) -> Self {
+        let rgba = Self::from_raw(width, height, ColorFormat::Rgba8, compression_type, quality, rgba);
+        if color_format == ColorFormat::Rgba8 {
+            return rgba
+        }
+
+        rgba.convert_color_format(color_format)
+    }
+
+    /// Find the smallest [`ColorFormat`] which can store the image without
+    /// losing anything.
+    ///
+    /// Pass the result to [`SquishyPicture::convert_color_format`] to act
+    /// on it. Scanning stops early once the image is known to need color
+    /// and alpha.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgba8, vec![
+    ///     0x10, 0x10, 0x10, 0xFF,
+    ///     0x80, 0x80, 0x80, 0xFF,
+    /// ]);
+    ///
+    /// let report = sqp.analyze_content();
+    /// assert_eq!(report.suggested_format, ColorFormat::Gray8);
+    ///
+    /// let gray = sqp.convert_color_format(report.suggested_format);
+    /// assert_eq!(gray.as_raw(), &[0x10, 0x80]);
+    /// ```
+    pub fn analyze_content(&self) -> ContentReport {
+        self.analyze_content_with_tolerance(0)
+    }
+
+    /// Like [`SquishyPicture::analyze_content`], but pixels count as gray
+    /// when their red, green and blue are all within `tolerance` of each
+    /// other.
+    ///
+    /// [`ColorFormat::Gray4`] images are already as small as they can be.
+    pub fn analyze_content_with_tolerance(&self, tolerance: u8) -> ContentReport {
+        ContentReport::from_bitmap(self.header.color_format, self.header.width, &self.bitmap, tolerance)
+    }
+
+    /// The parameters of the DCT the encoder would use for this image at
+    /// `quality`, along with the quantized coefficients of each channel, as
+    /// [`dct_compress`] produces them.
+    pub(crate) fn quantized_blocks(&self, quality: u8) -> Result<(DctParameters, Vec<Vec<i16>>), Error> {
+        if !self.header.color_format.supports_lossy() {
+            return Err(Error::LossyUnsupported(self.header.color_format))
+        }
+        self.check_bitmap_len()?;
+
+        let header = Header {
+            compression_type: CompressionType::LossyDct,
+            quality: quality_level(CompressionType::LossyDct, Some(quality)),
+            quantization_matrices: None,
+            ..self.header
+        };
+        let parameters = dct_parameters(&header);
+
+        Ok((parameters, dct_compress(&self.bitmap, parameters)))
+    }
+
+    /// Count how often each value appears in every channel of the image.
+    ///
+    /// [`ColorFormat::Gray4`] images are counted as [`ColorFormat::Gray8`].
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::GrayA8, vec![10, 255, 10, 0]);
+    /// let histogram = sqp.histogram();
+    ///
+    /// assert_eq!(histogram.channel(0)[10], 2);
+    /// assert_eq!(histogram.channel(1)[255], 1);
+    /// ```
+    pub fn histogram(&self) -> Histogram {
+        Histogram::from_bitmap(self.header.color_format, self.header.width, &self.bitmap)
+    }
+
+    /// Count how often each luminance value appears in the image.
+    ///
+    /// The result has a single channel. For color images the luminance is
+    /// computed the same way as in [`SquishyPicture::convert_color_format`],
+    /// and for gray images it is the gray channel.
+    pub fn luminance_histogram(&self) -> Histogram {
+        Histogram::luminance(self.header.color_format, self.header.width, &self.bitmap)
+    }
+
+    /// Alpha blend another image on top of this one, with its top left
+    /// corner at `x` and `y`.
+    ///
+    /// This uses straight alpha "over" compositing. If the formats differ,
+    /// each pixel of `other` is converted to this image's format as it is
+    /// blended, the same way as in [`SquishyPicture::convert_color_format`].
+    /// Any part of `other` which falls outside of this image is left out.
+    ///
+    /// Fails with [`Error::SizeMismatch`] if either bitmap is not the size
+    /// its dimensions describe.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let mut background = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgb8, vec![0; 2 * 3]);
+    /// let logo = SquishyPicture::from_raw_lossless(1, 1, ColorFormat::GrayA8, vec![0xFF, 0x80]);
+    ///
+    /// background.overlay(&logo, 1, 0).unwrap();
+    /// assert_eq!(background.as_raw(), &[0, 0, 0, 0x80, 0x80, 0x80]);
+    /// ```
+    pub fn overlay(&mut self, other: &SquishyPicture, x: u32, y: u32) -> Result<(), Error> {
+        self.check_bitmap_len()?;
+        other.check_bitmap_len()?;
+
+        // Blend packed images a pixel at a time, then pack the result again
+        if let Some(mut gray) = self.unpacked() {
+            gray.overlay(other, x, y)?;
+            *self = gray.convert_color_format(ColorFormat::Gray4);
+
+            return Ok(())
+        }
+        let unpacked = other.unpacked();
+        let other = unpacked.as_ref().unwrap_or(other);
+
+        overlay(
+            &mut self.bitmap,
+            self.header.width,
+            self.header.color_format,
+            &other.bitmap,
+            other.header.width,
+            other.header.color_format,
+            x,
+            y,
+        );
+
+        Ok(())
+    }
+
+    /// Smooth the edges between the 8x8 blocks of a lossy image, which show
+    /// at low qualities. Other images are left alone.
+    ///
+    /// Steps across an edge which are small for the quantization the header
+    /// describes are blended away, and larger ones are kept as real edges.
+    /// A higher `strength` smooths larger steps, and 0 does nothing. Alpha
+    /// is never changed. This is what [`DecodeOptions::deblock`] does after
+    /// decoding.
+    ///
+    /// Fails with [`Error::SizeMismatch`] if the bitmap is not the size its
+    /// dimensions describe.
+    ///
+    /// [`DecodeOptions::deblock`]: crate::options::DecodeOptions::deblock
+    pub fn deblock(&mut self, strength: u8) -> Result<(), Error> {
+        self.check_bitmap_len()?;
+        if self.header.compression_type == CompressionType::LossyDct {
+            dct_deblock(&mut self.bitmap, dct_parameters(&self.header), strength);
+        }
+
+        Ok(())
+    }
+
+    /// Call a function on every pixel of the image, letting it change the
+    /// pixel in place.
+    ///
+    /// Each pixel is a slice of [`ColorFormat::pbc`] bytes, visited in
+    /// row-major order. [`ColorFormat::Gray4`] images are unpacked into 8
+    /// bit gray values first, and packed again afterwards.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// // Swap red and blue
+    /// let mut sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgb8, vec![1, 2, 3, 4, 5, 6]);
+    /// sqp.map_pixels_in_place(|pixel| pixel.swap(0, 2));
+    ///
+    /// assert_eq!(sqp.as_raw(), &[3, 2, 1, 6, 5, 4]);
+    /// ```
+    pub fn map_pixels_in_place<F: FnMut(&mut [u8])>(&mut self, mut f: F) {
+        self.map_pixels_with_position(|_, _, pixel| f(pixel))
+    }
+
+    /// Like [`SquishyPicture::map_pixels_in_place`], but also passes the
+    /// `x` and `y` position of each pixel, the same way as
+    /// [`SquishyPicture::from_fn`].
+    pub fn map_pixels_with_position<F: FnMut(u32, u32, &mut [u8])>(&mut self, mut f: F) {
+        if let Some(mut gray) = self.unpacked() {
+            gray.map_pixels_with_position(f);
+            *self = gray.convert_color_format(ColorFormat::Gray4);
+
+            return
+        }
+
+        let pbc = self.header.color_format.pbc();
+        let row_len = self.header.width as usize * pbc;
+        if row_len == 0 {
+            return
+        }
+
+        for (y, row) in self.bitmap.chunks_exact_mut(row_len).enumerate() {
+            for (x, pixel) in row.chunks_exact_mut(pbc).enumerate() {
+                f(x as u32, y as u32, pixel);
+            }
+        }
+    }
+
+    /// Like [`SquishyPicture::map_pixels_in_place`], but runs in parallel
+    /// over the rows of the image, so pixels are visited in no particular
+    /// order.
+    #[cfg(feature = "parallel")]
+    pub fn par_map_pixels_in_place<F: Fn(&mut [u8]) + Sync>(&mut self, f: F) {
+        self.par_map_pixels_with_position(|_, _, pixel| f(pixel))
+    }
+
+    /// Like [`SquishyPicture::map_pixels_with_position`], but runs in
+    /// parallel over the rows of the image, so pixels are visited in no
+    /// particular order.
+    #[cfg(feature = "parallel")]
+    pub fn par_map_pixels_with_position<F: Fn(u32, u32, &mut [u8]) + Sync>(&mut self, f: F) {
+        if let Some(mut gray) = self.unpacked() {
+            gray.par_map_pixels_with_position(f);
+            *self = gray.convert_color_format(ColorFormat::Gray4);
+
+            return
+        }
+
+        let pbc = self.header.color_format.pbc();
+        let row_len = self.header.width as usize * pbc;
+        if row_len == 0 {
+            return
+        }
+
+        self.bitmap.par_chunks_exact_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(pbc).enumerate() {
+                f(x as u32, y as u32, pixel);
+            }
+        });
+    }
+}
+
+impl TryFrom<&[u8]> for SquishyPicture {
+    type Error = Error;
+
+    /// Decode an image from a slice, the same as
+    /// [`SquishyPicture::decode_slice`].
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+    /// let encoded: Vec<u8> = (&sqp).try_into().unwrap();
+    ///
+    /// let decoded: SquishyPicture = encoded.as_slice().try_into().unwrap();
+    /// assert_eq!(decoded.as_raw(), &[0, 255]);
+    /// ```
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Self::decode_slice(value)
+    }
+}
+
+impl TryFrom<Vec<u8>> for SquishyPicture {
+    type Error = Error;
+
+    /// Decode an image from a [`Vec`], the same as
+    /// [`SquishyPicture::decode_slice`].
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::decode_slice(&value)
+    }
+}
+
+impl TryFrom<&SquishyPicture> for Vec<u8> {
+    type Error = Error;
+
+    /// Encode an image, the same as [`SquishyPicture::encode_to_vec`].
+    ///
+    /// The image is encoded with its own compression settings, such as its
+    /// [`CompressionType`] and quality, and the default [`EncodeOptions`].
+    fn try_from(value: &SquishyPicture) -> Result<Self, Self::Error> {
+        value.encode_to_vec()
+    }
+}
+
+/// The tile size an image gets when its compression type is set, which is
+/// [`default_tile_size`] for lossy images.
+fn tile_size(compression_type: CompressionType, width: u32, height: u32) -> Option<u16> {
+    match compression_type {
+        CompressionType::LossyDct => default_tile_size(width, height),
+        _ => None,
+    }
+}
+
+/// The quality level to store in the header for the given compression.
+fn quality_level(compression_type: CompressionType, quality: Option<u8>) -> u8 {
+    match (compression_type, quality) {
+        (CompressionType::LossyDct, Some(level)) => clamp_quality(level.into()),
+        (CompressionType::LossyDct, None) => {
+            panic!("compression level must not be `None` when compression type is lossy")
+        }
+        (CompressionType::Predictive, near) => near.unwrap_or(0).min(MAX_NEAR),
+        _ => 0,
+    }
+}
 
-    while let Some(num) = i16::decode_var(&stream[offset..]) {
-        offset += num.1;
-        output.push(num.0);
+/// Check an encoded size against [`EncodeOptions::max_encoded_size`].
+pub(crate) fn check_encoded_size(size: usize, options: &EncodeOptions) -> Result<(), Error> {
+    match options.max_encoded_size {
+        Some(max_size) if size > max_size => Err(Error::CannotMeetSizeTarget { best: size }),
+        _ => Ok(()),
     }
+}
+
+/// Filter the rows of a lossless bitmap into `output`, choosing a filter
+/// for each row if the header stores them, and otherwise taking the
+/// difference from the row above.
+pub(crate) fn filter_rows(header: &Header, bitmap: &[u8], options: &EncodeOptions, output: &mut Vec<u8>) {
+    if header.stored_row_filters() {
+        let strategy = options.filter_strategy.unwrap_or(FilterStrategy::MinSumAbs);
+        filter_rows_into(header.width, header.height, header.color_format, bitmap, strategy, Backend::from(header), output);
+    } else {
+        sub_rows_into(header.width, header.height, header.color_format, bitmap, output);
+    }
+}
+
+/// The data which gets compressed for a lossy image, starting with the
+/// coefficient count if the version stores it, followed by the coefficients
+/// of each channel, either range coded or as varints.
+///
+/// Tiled images instead have the length of each tile's data after the
+/// count, then the data of each tile, see [`write_tiles`].
+fn coefficient_stream(header: &Header, channels: &[Vec<i16>]) -> Vec<u8> {
+    let mut output = Vec::new();
+    coefficient_stream_into(header, channels, &mut output);
 
     output
 }
 
-/// Open an SQP from a given path. Convenience method around
-/// [`SquishyPicture::decode`]. Returns a [`Result<SquishyPicture>`].
+/// Like [`coefficient_stream`], but overwrites `output` in place.
+pub(crate) fn coefficient_stream_into(header: &Header, channels: &[Vec<i16>], output: &mut Vec<u8>) {
+    output.clear();
+    write_coefficient_count(header, output);
+
+    if let Some(tile_size) = header.stored_tile_size() {
+        write_tiles(header, tile_size, channels, output);
+    } else if header.range_coded {
+        let mut encoder = CoefficientEncoder::new(output);
+        channels.iter().for_each(|channel| encoder.encode(channel));
+        encoder.finish();
+    } else {
+        channels.iter()
+            .flatten()
+            .for_each(|c| write_varint(output, *c));
+    }
+}
+
+/// Append the tile index of a tiled lossy image to `output`, followed by
+/// the coefficients of each tile.
 ///
-/// If you are loading from memory, use [`SquishyPicture::decode`] instead.
-pub fn open<P: AsRef<Path>>(path: P) -> Result<SquishyPicture, Error> {
-    let input = File::open(path)?;
+/// The index is the length in bytes of each tile's data as a little endian
+/// `u32`, in the order of [`BlockRegion::tiles`]. Each tile holds the
+/// blocks of every channel in turn which fall within it, in rows, coded
+/// the same way as a whole image but starting afresh, so any tile can be
+/// decoded without the others.
+fn write_tiles(header: &Header, tile_size: u16, channels: &[Vec<i16>], output: &mut Vec<u8>) {
+    let parameters = dct_parameters(header);
+    let tiles: Vec<BlockRegion> = BlockRegion::tiles(parameters, tile_size as usize / 8).collect();
+
+    let write_tile = |tile: &BlockRegion| {
+        let mut data = Vec::new();
+        let rows = channels.iter().flat_map(|channel| tile.rows(parameters, channel));
+        if header.range_coded {
+            let mut encoder = CoefficientEncoder::new(&mut data);
+            rows.for_each(|row| encoder.encode(row));
+            encoder.finish();
+        } else {
+            rows.flatten().for_each(|c| write_varint(&mut data, *c));
+        }
+
+        data
+    };
+
+    #[cfg(feature = "parallel")]
+    let tile_data: Vec<Vec<u8>> = threads::install(|| tiles.par_iter().map(write_tile).collect());
+    #[cfg(not(feature = "parallel"))]
+    let tile_data: Vec<Vec<u8>> = tiles.iter().map(write_tile).collect();
+
+    for data in &tile_data {
+        output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    }
+    tile_data.iter().for_each(|data| output.extend_from_slice(data));
+}
+
+/// Append the coefficient count to a lossy image's data, if the version
+/// stores it.
+fn write_coefficient_count(header: &Header, output: &mut Vec<u8>) {
+    if header.version >= 1 {
+        let count = dct_parameters(header).coefficient_count().unwrap_or(usize::MAX);
+        output.extend_from_slice(&(count as u64).to_le_bytes());
+    }
+}
+
+/// The size of the encoded image with this header and filtered data,
+/// compressing one chunk at a time without keeping the output.
+///
+/// Stops early once the size is known to be over `limit`, returning the
+/// size so far.
+fn encoded_size(header: &Header, filtered: &[u8], limit: usize) -> usize {
+    let mut size = header.len() + 4 + header.footer_len();
+    let mut offset = 0;
+    loop {
+        let (count, compressed) = compress_chunk(&filtered[offset..], Backend::from(header));
+        if count == 0 || size > limit {
+            break size
+        }
+        offset += count;
 
-    SquishyPicture::decode(input)
+        size += 8 + compressed.len();
+    }
+}
+
+/// Find the highest quality up to the header's whose encoded size is at
+/// most `max_size`, with a binary search that transforms the image only
+/// once.
+///
+/// Fails if even the lowest quality is too large.
+fn quality_for_size(header: &Header, bitmap: &[u8], max_size: usize) -> Result<u8, Error> {
+    let coefficients = DctCoefficients::new(bitmap, dct_parameters(header));
+    let size_at = |quality: u8, limit: usize| {
+        let header = Header { quality, quantization_matrices: None, ..*header };
+        let filtered = coefficient_stream(&header, &coefficients.quantize(quality as u32));
+        encoded_size(&header, &filtered, limit)
+    };
+
+    let (mut low, mut high) = (*QUALITY_RANGE.start(), header.quality);
+
+    // The full size at the lowest quality is needed if nothing fits
+    #[cfg(feature = "parallel")]
+    let (high_size, low_size) = threads::install(|| rayon::join(|| size_at(high, max_size), || size_at(low, usize::MAX)));
+    #[cfg(not(feature = "parallel"))]
+    let (high_size, low_size) = (size_at(high, max_size), size_at(low, usize::MAX));
+
+    if high_size <= max_size {
+        return Ok(high)
+    } else if low_size > max_size {
+        return Err(Error::CannotMeetSizeTarget { best: low_size })
+    }
+
+    // The low end always fits and the high end never does
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if size_at(middle, max_size) <= max_size {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    Ok(low)
+}
+
+/// Restore a lossy image from its decompressed coefficient stream, using
+/// `decode` to turn the blocks of coefficients in a region into pixels
+/// scaled down by `denominator`, with `channels` interleaved channels.
+fn decode_coefficients<F>(
+    header: &Header,
+    pre_bitmap: &[u8],
+    denominator: usize,
+    channels: usize,
+    decode: F,
+    warnings: &mut Warnings,
+) -> Result<Vec<u8>, Error>
+where
+    F: Fn(&mut CoefficientBlocks, DctParameters, BlockRegion) -> Option<Vec<u8>> + Sync,
+{
+    let parameters = dct_parameters(header);
+    let expected = parameters.coefficient_count().unwrap_or(usize::MAX);
+    let stream = strip_coefficient_count(header, pre_bitmap)?;
+
+    let all = BlockRegion::all(parameters);
+    if let Some(tile_size) = header.stored_tile_size() {
+        return decode_tiles(header, tile_size, stream, all, denominator, channels, decode, warnings)
+    }
+
+    let mut blocks = CoefficientBlocks::new(stream, header.range_coded);
+    let bitmap = match decode(&mut blocks, parameters, all) {
+        Some(bitmap) => bitmap,
+        None => return Err(coefficients_missing(header, expected, blocks.count)),
+    };
+
+    if !blocks.stream.is_empty() {
+        warnings.add(DecodeWarning::TrailingCoefficients { len: blocks.stream.len() })?;
+    }
+
+    Ok(bitmap)
+}
+
+/// The coefficients of a lossy image after the coefficient count, once the
+/// count is checked, if the version stores it.
+fn strip_coefficient_count<'a>(header: &Header, pre_bitmap: &'a [u8]) -> Result<&'a [u8], Error> {
+    if header.version == 0 {
+        return Ok(pre_bitmap)
+    }
+
+    let expected = dct_parameters(header).coefficient_count().unwrap_or(usize::MAX);
+    let (stored, stream) = match pre_bitmap.split_first_chunk() {
+        Some((count, rest)) => (Some(parse_coefficient_count(*count)), rest),
+        None => (None, pre_bitmap),
+    };
+    check_coefficient_count(expected, stored)?;
+
+    Ok(stream)
+}
+
+/// Decode the tiles of a tiled lossy image which overlap `wanted`, from its
+/// coefficient stream after the count, using `decode` to turn each tile's
+/// blocks into pixels scaled down by `denominator`, with `channels`
+/// interleaved channels. The tiles are decoded in parallel.
+///
+/// Returns the part of the scaled image `wanted` covers.
+#[allow(clippy::too_many_arguments)]
+fn decode_tiles<F>(
+    header: &Header,
+    tile_size: u16,
+    stream: &[u8],
+    wanted: BlockRegion,
+    denominator: usize,
+    channels: usize,
+    decode: F,
+    warnings: &mut Warnings,
+) -> Result<Vec<u8>, Error>
+where
+    F: Fn(&mut CoefficientBlocks, DctParameters, BlockRegion) -> Option<Vec<u8>> + Sync,
+{
+    let parameters = dct_parameters(header);
+    let tiles: Vec<BlockRegion> = BlockRegion::tiles(parameters, tile_size as usize / 8).collect();
+    let (tile_data, mut trailing) = split_tiles(parameters, tiles.len(), stream)?;
+
+    let decode_tile = |(i, (tile, data)): (usize, (&BlockRegion, &&[u8]))| {
+        let mut blocks = CoefficientBlocks::new(data, header.range_coded);
+        match decode(&mut blocks, parameters, *tile) {
+            Some(pixels) => Ok((*tile, pixels, blocks.stream.len())),
+            None => Err(Error::TileCoefficientsMissing { tile: i }),
+        }
+    };
+
+    let wanted_tiles = tiles.iter().zip(&tile_data).enumerate().filter(|(_, (tile, _))| tile.overlaps(&wanted));
+    #[cfg(feature = "parallel")]
+    let decoded: Vec<_> = threads::install(|| wanted_tiles.collect::<Vec<_>>().into_par_iter().map(decode_tile).collect());
+    #[cfg(not(feature = "parallel"))]
+    let decoded: Vec<_> = wanted_tiles.map(decode_tile).collect();
+
+    let wanted_rect = scaled_rect(parameters, wanted, denominator);
+    let mut output = vec![0; wanted_rect.2 * wanted_rect.3 * channels];
+    for result in decoded {
+        let (tile, pixels, left) = result?;
+        copy_overlap(&pixels, scaled_rect(parameters, tile, denominator), &mut output, wanted_rect, channels);
+        trailing += left;
+    }
+
+    if trailing > 0 {
+        warnings.add(DecodeWarning::TrailingCoefficients { len: trailing })?;
+    }
+
+    Ok(output)
+}
+
+/// Split the coefficient stream of a tiled lossy image with `tile_count`
+/// tiles, after the count, into the coefficients of each tile using the
+/// tile index. Also returns the number of bytes after the last tile.
+fn split_tiles(parameters: DctParameters, tile_count: usize, stream: &[u8]) -> Result<(Vec<&[u8]>, usize), Error> {
+    let Some((index, mut data)) = stream.split_at_checked(tile_count * TILE_LEN_SIZE) else {
+        let expected = parameters.coefficient_count().unwrap_or(usize::MAX);
+        return Err(Error::CoefficientCountMismatch { expected, actual: 0 })
+    };
+
+    let mut tile_data = Vec::with_capacity(tile_count);
+    for (tile, len) in index.chunks_exact(TILE_LEN_SIZE).enumerate() {
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let Some((coefficients, rest)) = data.split_at_checked(len) else {
+            return Err(Error::TileCoefficientsMissing { tile })
+        };
+
+        tile_data.push(coefficients);
+        data = rest;
+    }
+
+    Ok((tile_data, data.len()))
+}
+
+/// Read a lossy image from anything that implements [`Read`] as far as its
+/// quantized coefficients, returning them in the same layout as
+/// [`dct_compress`] along with the parameters of the DCT, without
+/// dequantizing or transforming them back.
+///
+/// Tiled images have the blocks of their tiles put back in place.
+pub(crate) fn read_quantized_blocks<I: Read>(mut input: I, limits: Limits) -> Result<(DctParameters, Vec<Vec<i16>>), Error> {
+    let header = Header::read_from(&mut input)?;
+    if header.compression_type != CompressionType::LossyDct {
+        return Err(Error::NoDctBlocks(header.compression_type))
+    }
+
+    let ChunkTable { compression_info, data_offset, .. } = read_chunk_table(&mut input, &header, &limits)?;
+    limits.check_alloc(total_size_raw(&compression_info.chunks))?;
+
+    let pre_bitmap = decompress(&mut input, &compression_info, Backend::from(&header), &mut Vec::new())
+        .map_err(|e| Error::from_read(e, FileSection::ChunkData))?;
+    let image_len = data_offset + pixel_data_len(&compression_info) as u64;
+    Warnings::ignored().check_footer(&mut input, &header, image_len)?;
+
+    quantized_blocks_from_stream(&header, &pre_bitmap)
+}
+
+/// Put the coefficients decompressed from a lossy image back into blocks,
+/// like [`read_quantized_blocks`].
+pub(crate) fn quantized_blocks_from_stream(header: &Header, pre_bitmap: &[u8]) -> Result<(DctParameters, Vec<Vec<i16>>), Error> {
+    let stream = strip_coefficient_count(header, pre_bitmap)?;
+
+    let parameters = dct_parameters(header);
+    let expected = parameters.coefficient_count().unwrap_or(usize::MAX);
+    let (blocks_wide, blocks_high) = parameters.blocks();
+    let mut channels = vec![vec![0; blocks_wide * blocks_high * 64]; header.color_format.channels() as usize];
+
+    // Copy the blocks of a region of every channel in turn, in rows
+    let fill = |blocks: &mut CoefficientBlocks, region: BlockRegion, channels: &mut [Vec<i16>]| {
+        for channel in channels.iter_mut() {
+            for y in region.y..region.y + region.height {
+                for x in region.x..region.x + region.width {
+                    let block = blocks.next()?;
+                    channel[(y * blocks_wide + x) * 64..][..64].copy_from_slice(&block);
+                }
+            }
+        }
+
+        Some(())
+    };
+
+    let Some(tile_size) = header.stored_tile_size() else {
+        let mut blocks = CoefficientBlocks::new(stream, header.range_coded);
+        if fill(&mut blocks, BlockRegion::all(parameters), &mut channels).is_none() {
+            return Err(coefficients_missing(header, expected, blocks.count))
+        }
+
+        return Ok((parameters, channels))
+    };
+
+    let tiles: Vec<BlockRegion> = BlockRegion::tiles(parameters, tile_size as usize / 8).collect();
+    let (tile_data, _) = split_tiles(parameters, tiles.len(), stream)?;
+    for (i, (tile, data)) in tiles.iter().zip(tile_data).enumerate() {
+        let mut blocks = CoefficientBlocks::new(data, header.range_coded);
+        if fill(&mut blocks, *tile, &mut channels).is_none() {
+            return Err(Error::TileCoefficientsMissing { tile: i })
+        }
+    }
+
+    Ok((parameters, channels))
+}
+
+/// Like [`decode_tiles`], but decodes every tile in turn as its
+/// coefficients are read from `blocks`, so only one tile's coefficients
+/// are held in memory at once.
+///
+/// Returns [`None`] if the tile index runs out, and any trailing
+/// coefficients within tiles are left for `blocks` to count.
+fn decode_tiles_streamed<I: Read>(
+    blocks: &mut StreamedCoefficientBlocks<I>,
+    parameters: DctParameters,
+    tile_size: u16,
+) -> Result<Option<Vec<u8>>, Error> {
+    let tiles: Vec<BlockRegion> = BlockRegion::tiles(parameters, tile_size as usize / 8).collect();
+
+    let mut lengths = Vec::with_capacity(tiles.len());
+    for _ in &tiles {
+        let mut len = [0u8; TILE_LEN_SIZE];
+        for byte in &mut len {
+            match blocks.next_byte() {
+                Some(value) => *byte = value,
+                None => return Ok(None),
+            }
+        }
+        lengths.push(u32::from_le_bytes(len) as usize);
+    }
+
+    let channels = parameters.format.channels() as usize;
+    let all = scaled_rect(parameters, BlockRegion::all(parameters), 1);
+    let mut output = vec![0; all.2 * all.3 * channels];
+
+    let mut data = Vec::new();
+    for (i, (tile, len)) in tiles.iter().zip(lengths).enumerate() {
+        data.clear();
+        while data.len() < len {
+            match blocks.next_byte() {
+                Some(byte) => data.push(byte),
+                None if blocks.error.is_some() => return Ok(None),
+                None => return Err(Error::TileCoefficientsMissing { tile: i }),
+            }
+        }
+
+        let mut tile_blocks = CoefficientBlocks::new(&data, blocks.range_coded);
+        let Some(pixels) = dct_decompress_region(&mut tile_blocks, parameters, *tile, 1) else {
+            return Err(Error::TileCoefficientsMissing { tile: i })
+        };
+        blocks.count += tile_blocks.count;
+        blocks.trailing += tile_blocks.stream.len();
+
+        copy_overlap(&pixels, scaled_rect(parameters, *tile, 1), &mut output, all, channels);
+    }
+
+    Ok(Some(output))
+}
+
+/// A rectangle of pixels, as its left and top edges, width and height.
+type Rect = (usize, usize, usize, usize);
+
+/// The pixels a region of blocks covers in the image scaled down by
+/// `denominator`.
+fn scaled_rect(parameters: DctParameters, region: BlockRegion, denominator: usize) -> Rect {
+    let block_size = 8 / denominator;
+    let (width, height) = region.scaled_size(parameters, denominator);
+
+    (region.x * block_size, region.y * block_size, width, height)
+}
+
+/// Copy the pixels where two rectangles of an image overlap from `input`,
+/// which holds the pixels of `from`, to `output`, which holds those of `to`.
+fn copy_overlap(input: &[u8], from: Rect, output: &mut [u8], to: Rect, channels: usize) {
+    let (left, right) = (from.0.max(to.0), (from.0 + from.2).min(to.0 + to.2));
+    let (top, bottom) = (from.1.max(to.1), (from.1 + from.3).min(to.1 + to.3));
+    if left >= right {
+        return
+    }
+
+    let len = (right - left) * channels;
+    for y in top..bottom {
+        let source = ((y - from.1) * from.2 + left - from.0) * channels;
+        let destination = ((y - to.1) * to.2 + left - to.0) * channels;
+        output[destination..destination + len].copy_from_slice(&input[source..source + len]);
+    }
+}
+
+/// The parameters for the DCT of the image the header describes.
+pub(crate) fn dct_parameters(header: &Header) -> DctParameters {
+    DctParameters {
+        quality: header.quality as u32,
+        format: header.color_format,
+        width: header.width as usize,
+        height: header.height as usize,
+        matrices: header.quantization_matrices,
+    }
+}
+
+/// Size of the coefficient count which lossy images store before their
+/// coefficients, since version 1.
+const COEFFICIENT_COUNT_LEN: usize = 8;
+
+/// Size of the length of each tile's coefficients in the tile index of a
+/// tiled lossy image.
+const TILE_LEN_SIZE: usize = 4;
+
+/// Size of the shortest range coded stream of coefficients, which is just
+/// the bytes written when it ends.
+const RANGE_CODED_MIN_LEN: usize = 4;
+
+/// The most bytes each coefficient may take in a lossy image, not counting
+/// the count of them, the tile index or the end of a range coded stream.
+///
+/// Varints take at most 3 bytes. Range coding has no hard bound, but even
+/// noise at quality 100 takes around 1.1 bytes per coefficient, so it is
+/// held to the same.
+const MAX_COEFFICIENT_LEN: usize = 3;
+
+fn parse_coefficient_count(bytes: [u8; COEFFICIENT_COUNT_LEN]) -> usize {
+    usize::try_from(u64::from_le_bytes(bytes)).unwrap_or(usize::MAX)
+}
+
+/// Check the coefficient count stored in a lossy image against the number
+/// its dimensions need. A missing count is treated as zero.
+fn check_coefficient_count(expected: usize, stored: Option<usize>) -> Result<(), Error> {
+    match stored {
+        Some(count) if count == expected => Ok(()),
+        stored => Err(Error::CoefficientCountMismatch { expected, actual: stored.unwrap_or(0) }),
+    }
+}
+
+/// The error for a lossy image whose coefficients ran out early.
+///
+/// Images from before version 1 have no stored count, so this is reported
+/// as the decompressed data being the wrong size.
+fn coefficients_missing(header: &Header, expected: usize, actual: usize) -> Error {
+    if header.version >= 1 {
+        Error::CoefficientCountMismatch { expected, actual }
+    } else {
+        Error::SizeMismatch { expected, actual }
+    }
+}
+
+/// Check that the chunk table's total uncompressed size is plausible for the
+/// image the header describes, before any space is allocated for it.
+/// Collects the [`DecodeWarning`]s found while decoding, or turns them into
+/// errors in strict mode.
+pub(crate) struct Warnings {
+    strict: bool,
+
+    /// Whether the warnings will be returned to the caller.
+    reported: bool,
+
+    found: Vec<DecodeWarning>,
+}
+
+impl Warnings {
+    pub(crate) fn new(options: &DecodeOptions, reported: bool) -> Self {
+        Self { strict: options.strict, reported, found: Vec::new() }
+    }
+
+    /// Warnings which nobody will see.
+    pub(crate) fn ignored() -> Self {
+        Self { strict: false, reported: false, found: Vec::new() }
+    }
+
+    /// Whether anything comes of a warning, so it's worth extra work, like
+    /// reading the input to its end, to look for them.
+    pub(crate) fn wanted(&self) -> bool {
+        self.strict || self.reported
+    }
+
+    pub(crate) fn add(&mut self, warning: DecodeWarning) -> Result<(), Error> {
+        if self.strict {
+            return Err(Error::Strict(warning))
+        }
+
+        debug!("carrying on past a problem: {warning}");
+        self.found.push(warning);
+        Ok(())
+    }
+
+    fn add_all(&mut self, warnings: Vec<DecodeWarning>) -> Result<(), Error> {
+        warnings.into_iter().try_for_each(|w| self.add(w))
+    }
+
+    /// Read the footer which follows an image of `image_len` bytes, if its
+    /// version has one, adding a warning if it is damaged.
+    ///
+    /// Returns the length of the footer. Fails with
+    /// [`Error::TruncatedFile`] if the input ends before the footer does.
+    pub(crate) fn check_footer<I: Read>(&mut self, input: &mut I, header: &Header, image_len: u64) -> Result<u64, Error> {
+        if header.footer_len() == 0 {
+            return Ok(0)
+        }
+
+        let mut footer = [0; FOOTER_LEN];
+        input.read_exact(&mut footer).map_err(|e| Error::from_read(e, FileSection::Footer))?;
+        if !footer_matches(&footer, image_len) {
+            self.add(DecodeWarning::DamagedFooter { offset: image_len })?;
+        }
+
+        Ok(FOOTER_LEN as u64)
+    }
+
+    /// Read the input to its end if the warnings are wanted, adding a warning
+    /// if anything was left after the image, which ended at `offset`.
+    fn check_trailing_bytes<I: Read>(&mut self, input: &mut I, offset: u64) -> Result<(), Error> {
+        if !self.wanted() {
+            return Ok(())
+        }
+
+        match count_remaining(input)? {
+            0 => Ok(()),
+            len => self.add(DecodeWarning::TrailingBytes { offset, len }),
+        }
+    }
+
+    /// Hash the decoded bitmap if the warnings are wanted, adding a warning
+    /// if it doesn't match the stored hash. Lossy images aren't checked.
+    #[cfg(feature = "content-hash")]
+    pub(crate) fn check_content_hash(&mut self, picture: &SquishyPicture, expected: Option<u64>) -> Result<(), Error> {
+        let Some(expected) = expected else {
+            return Ok(())
+        };
+        if !self.wanted() || picture.header.is_lossy() {
+            return Ok(())
+        }
+
+        match crate::container::content_hash(&picture.bitmap) {
+            actual if actual == expected => Ok(()),
+            actual => self.add(DecodeWarning::ContentHashMismatch { expected, actual }),
+        }
+    }
+
+    #[cfg(not(feature = "content-hash"))]
+    pub(crate) fn check_content_hash(&mut self, _picture: &SquishyPicture, _expected: Option<u64>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Read the chunk table which follows the header, checking it against the
+/// header and the chunk count limit.
+///
+/// From version 3 on, the encoder info is read and unknown ancillary
+/// sections are skipped until the start of the pixel data. Either way the
+/// input is left at the first compressed chunk.
+///
+/// Also checks the header against the rest of the limits, as this is the
+/// last step before anything large is allocated.
+pub(crate) fn read_chunk_table<I: Read>(input: &mut I, header: &Header, limits: &Limits) -> Result<ChunkTable, Error> {
+    let mut reader = ChunkTableReader::new(header, limits)?;
+    read_table_parts(input, &mut reader)?;
+
+    Ok(reader.finish())
+}
+
+/// Give `reader` each part of the chunk table and the sections around it in
+/// turn, until it has read them all.
+pub(crate) fn read_table_parts<I: Read>(input: &mut I, reader: &mut ChunkTableReader) -> Result<(), Error> {
+    loop {
+        match reader.next_part() {
+            TablePart::Read(len) => {
+                let bytes = read_vec(input, len).map_err(|e| Error::from_read(e, reader.file_section()))?;
+                reader.advance(&bytes)?;
+            },
+            TablePart::Skip(len) => {
+                skip(input, len as u32).map_err(|e| Error::from_read(e, reader.file_section()))?;
+                reader.advance(&[])?;
+            },
+            TablePart::Done => return Ok(()),
+        }
+    }
+}
+
+/// Check the total uncompressed size the chunk table claims against what
+/// the header allows, before anything is allocated for it.
+///
+/// Images other than lossy ones decompress to exactly their filtered size.
+/// Lossy images decompress to a stream of coefficients, which can only be
+/// between one and [`MAX_COEFFICIENT_LEN`] bytes for each of them.
+pub(crate) fn check_chunk_table(header: &Header, chunks: &[ChunkInfo]) -> Result<(), Error> {
+    let total = chunks.iter().map(|c| c.size_raw as u64).fold(0, u64::saturating_add);
+
+    let (min, max) = match header.compression_type {
+        CompressionType::None
+        | CompressionType::Lossless
+        | CompressionType::LosslessV2
+        | CompressionType::LosslessBwt
+        | CompressionType::Predictive => {
+            let len = header.filtered_len().unwrap_or(usize::MAX);
+            (len, len)
+        },
+        CompressionType::LossyDct => {
+            let parameters = dct_parameters(header);
+
+            // Each coefficient is a varint of between 1 and 3 bytes, after
+            // the count of them
+            let count = parameters.coefficient_count().unwrap_or(usize::MAX);
+            let count_len = if header.version >= 1 { COEFFICIENT_COUNT_LEN } else { 0 };
+
+            // Tiled images have a tile index, and each tile is a stream of
+            // its own
+            let tiles = match header.stored_tile_size() {
+                Some(tile_size) => BlockRegion::tile_count(parameters, tile_size as usize / 8),
+                None => 0,
+            };
+            let mut extra_len = count_len.saturating_add(tiles.saturating_mul(TILE_LEN_SIZE));
+            let max = count.saturating_mul(MAX_COEFFICIENT_LEN);
+            if header.range_coded {
+                // A range coded stream can be as small as its last bytes
+                extra_len = extra_len.saturating_add(tiles.max(1).saturating_mul(RANGE_CODED_MIN_LEN));
+                (extra_len, max.saturating_add(extra_len))
+            } else {
+                (count.saturating_add(extra_len), max.saturating_add(extra_len))
+            }
+        },
+    };
+
+    let (min, max) = (min as u64, max as u64);
+    if total < min || total > max {
+        return Err(Error::ChunkTableMismatch { claimed: total, min, max })
+    }
+
+    Ok(())
+}
+
+/// Decodes varint encoded or range coded DCT coefficients on the fly, one
+/// 8x8 block at a time.
+struct CoefficientBlocks<'a> {
+    stream: &'a [u8],
+
+    /// Number of coefficients decoded so far.
+    count: usize,
+
+    range_coded: bool,
+
+    /// The range decoder, once the first range coded block is read.
+    decoder: Option<CoefficientDecoder>,
+}
+
+impl<'a> CoefficientBlocks<'a> {
+    fn new(stream: &'a [u8], range_coded: bool) -> Self {
+        Self { stream, count: 0, range_coded, decoder: None }
+    }
+}
+
+impl Iterator for CoefficientBlocks<'_> {
+    type Item = [i16; 64];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.range_coded {
+            let stream = &mut self.stream;
+            let mut next_byte = || {
+                let (byte, rest) = stream.split_first()?;
+                *stream = rest;
+                Some(*byte)
+            };
+
+            let decoder = match &mut self.decoder {
+                Some(decoder) => decoder,
+                None => self.decoder.insert(CoefficientDecoder::new(&mut next_byte)?),
+            };
+            let block = decoder.decode_block(&mut next_byte)?;
+            self.count += 64;
+
+            return Some(block)
+        }
+
+        let mut block = [0; 64];
+        for coefficient in &mut block {
+            let (value, len) = read_varint(self.stream)?;
+            self.stream = &self.stream[len..];
+            self.count += 1;
+
+            *coefficient = value;
+        }
+
+        Some(block)
+    }
+}
+
+/// Like [`CoefficientBlocks`], but reads and decompresses one chunk at a
+/// time as more coefficients are needed, so the whole stream is never held
+/// in memory.
+struct StreamedCoefficientBlocks<'a, I> {
+    input: I,
+    chunks: core::iter::Enumerate<core::slice::Iter<'a, ChunkInfo>>,
+    backend: Backend,
+    range_coded: bool,
+
+    /// The current decompressed chunk, of which everything before
+    /// `position` has already been decoded.
+    buffer: Vec<u8>,
+    position: usize,
+
+    /// Number of coefficients decoded so far.
+    count: usize,
+
+    /// Number of bytes left over at the ends of the tiles of a tiled
+    /// image, which were read but not decoded.
+    trailing: usize,
+
+    /// The range decoder, once the first range coded block is read.
+    decoder: Option<CoefficientDecoder>,
+
+    /// The error which stopped reading, if any.
+    error: Option<io::Error>,
+
+    /// Problems with the chunks read so far.
+    warnings: Vec<DecodeWarning>,
+}
+
+impl<'a, I: Read> StreamedCoefficientBlocks<'a, I> {
+    fn new(input: I, chunks: &'a [ChunkInfo], backend: Backend, range_coded: bool) -> Self {
+        Self {
+            input,
+            chunks: chunks.iter().enumerate(),
+            backend,
+            range_coded,
+            buffer: Vec::new(),
+            position: 0,
+            count: 0,
+            trailing: 0,
+            decoder: None,
+            error: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The number of decompressed bytes which haven't been decoded, going by
+    /// the chunk table for chunks which haven't been read.
+    fn remaining_len(&self) -> usize {
+        let unread = self.chunks.clone().map(|(_, c)| c.size_raw).fold(0, usize::saturating_add);
+
+        (self.buffer.len() - self.position).saturating_add(unread).saturating_add(self.trailing)
+    }
+
+    /// Read past the chunks which haven't been read, without decompressing
+    /// them, returning the input.
+    fn skip_rest(mut self) -> Result<I, io::Error> {
+        for (_, chunk) in self.chunks {
+            read_vec(&mut self.input, chunk.size_compressed)?;
+        }
+
+        Ok(self.input)
+    }
+
+    /// Replace the current chunk with the next one. Returns `false` if there
+    /// are no chunks left or reading failed.
+    fn refill(&mut self) -> bool {
+        // Free the old chunk first, so two are never held at once
+        self.buffer = Vec::new();
+        self.position = 0;
+
+        let Some((i, chunk)) = self.chunks.next() else {
+            return false;
+        };
+
+        match read_vec(&mut self.input, chunk.size_compressed) {
+            Ok(compressed) => {
+                let (buffer, problem) = decompress_chunk(&compressed, chunk.size_raw, i, self.backend);
+                self.buffer = buffer;
+                self.warnings.extend(problem);
+                true
+            },
+            Err(error) => {
+                self.error = Some(error);
+                false
+            }
+        }
+    }
+
+    /// Read the coefficient count stored before the coefficients, or
+    /// [`None`] if the stream ends first.
+    fn read_coefficient_count(&mut self) -> Option<usize> {
+        let mut bytes = [0u8; COEFFICIENT_COUNT_LEN];
+        for byte in &mut bytes {
+            *byte = self.next_byte()?;
+        }
+
+        Some(parse_coefficient_count(bytes))
+    }
+
+    /// Read the next byte, moving on to the next chunk when the current one
+    /// runs out.
+    fn next_byte(&mut self) -> Option<u8> {
+        while self.position == self.buffer.len() {
+            if !self.refill() {
+                return None
+            }
+        }
+
+        self.position += 1;
+        Some(self.buffer[self.position - 1])
+    }
+
+    /// Decode the next range coded block.
+    fn next_range_coded(&mut self) -> Option<[i16; 64]> {
+        let decoder = self.decoder.take();
+        let mut next_byte = || self.next_byte();
+
+        let mut decoder = match decoder {
+            Some(decoder) => decoder,
+            None => CoefficientDecoder::new(&mut next_byte)?,
+        };
+        let block = decoder.decode_block(&mut next_byte)?;
+        self.decoder = Some(decoder);
+        self.count += 64;
+
+        Some(block)
+    }
+
+    /// Decode the next coefficient, moving on to the next chunk when the
+    /// current one runs out.
+    fn next_coefficient(&mut self) -> Option<i16> {
+        // The start of a varint which continues in the next chunk
+        let mut carry = [0u8; 10];
+        let mut carry_len = 0;
+
+        loop {
+            let remaining = &self.buffer[self.position..];
+            let take = remaining.len().min(carry.len() - carry_len);
+            carry[carry_len..carry_len + take].copy_from_slice(&remaining[..take]);
+
+            let input = if carry_len == 0 { remaining } else { &carry[..carry_len + take] };
+            match read_varint(input) {
+                Some((value, len)) => {
+                    self.position += len - carry_len;
+                    return Some(value)
+                },
+                None if input.len() < carry.len() && input.iter().all(|b| b & 0x80 != 0) => {
+                    carry_len += take;
+                },
+                None => return None,
+            }
+
+            if !self.refill() {
+                return None
+            }
+        }
+    }
+}
+
+impl<I: Read> Iterator for StreamedCoefficientBlocks<'_, I> {
+    type Item = [i16; 64];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.range_coded {
+            return self.next_range_coded()
+        }
+
+        let mut block = [0; 64];
+        for coefficient in &mut block {
+            *coefficient = self.next_coefficient()?;
+            self.count += 1;
+        }
+
+        Some(block)
+    }
+}
+
+/// Open an SQP from a given path. Convenience method around
+/// [`SquishyPicture::decode`]. Returns a [`Result<SquishyPicture>`].
+///
+/// The file is buffered, so there is no need to wrap it yourself.
+/// Images larger than [`Limits::default`] allows fail to decode, use
+/// [`open_with_limits`] for those.
+///
+/// If you are loading from memory, use [`SquishyPicture::decode_slice`]
+/// instead.
+#[cfg(feature = "std")]
+pub fn open<P: AsRef<Path>>(path: P) -> Result<SquishyPicture, Error> {
+    open_with_limits(path, Limits::default())
+}
+
+/// Open an SQP from a given path like [`open`], with different [`Limits`]
+/// than the default.
+#[cfg(feature = "std")]
+pub fn open_with_limits<P: AsRef<Path>>(path: P, limits: Limits) -> Result<SquishyPicture, Error> {
+    let file = File::open(path)?;
+    let len = file.metadata().ok().map(|m| m.len());
+
+    decode_buffered(file, len, limits)
+}
+
+/// Decode an opened file of `len` bytes through a [`BufReader`], as
+/// [`open_with_limits`] does.
+#[cfg(feature = "std")]
+fn decode_buffered<R: std::io::Read>(file: R, len: Option<u64>, limits: Limits) -> Result<SquishyPicture, Error> {
+    let mut options = DecodeOptions::new().limits(limits);
+    options.input_len = len;
+
+    SquishyPicture::decode_with_options(BufReader::new(file), &options)
+}
+
+/// Decode an image from anything that implements [`Read`] straight to
+/// `Rgba8`, whatever format it is stored in, returning its width, height
+/// and pixels.
+///
+/// The pixels are expanded as by [`SquishyPicture::to_rgba8`], and the
+/// decoded image is dropped as soon as they have been. Images stored as
+/// `Rgba8` are returned without copying at all.
+///
+/// # Example
+/// ```
+/// use sqp::{SquishyPicture, ColorFormat};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(1, 1, ColorFormat::Gray8, vec![0x80]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let (width, height, rgba) = sqp::decode_to_rgba8(encoded.as_slice()).unwrap();
+/// assert_eq!((width, height, rgba), (1, 1, vec![0x80, 0x80, 0x80, 0xFF]));
+/// ```
+pub fn decode_to_rgba8<R: Read>(input: R) -> Result<(u32, u32, Vec<u8>), Error> {
+    let picture = SquishyPicture::decode(input)?;
+    let (width, height) = (picture.width(), picture.height());
+
+    let rgba = match picture.header.color_format {
+        ColorFormat::Rgba8 => picture.bitmap,
+        _ => picture.to_rgba8(),
+    };
+
+    Ok((width, height, rgba))
+}
+
+/// Open an SQP from a given path by memory mapping it, and decode it
+/// with [`SquishyPicture::decode_slice`].
+///
+/// This avoids copying the file into memory before decoding, which helps
+/// with very large images. If the file can't be mapped, this falls back to
+/// reading it like [`open`]. Images larger than [`Limits::default`] allows
+/// fail to decode, use [`open_mmap_with_limits`] for those.
+///
+/// The file must not be modified by another process while it is being
+/// decoded.
+#[cfg(feature = "mmap")]
+pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<SquishyPicture, Error> {
+    open_mmap_with_limits(path, Limits::default())
+}
+
+/// Open an SQP from a given path by memory mapping it like [`open_mmap`],
+/// with different [`Limits`] than the default.
+#[cfg(feature = "mmap")]
+pub fn open_mmap_with_limits<P: AsRef<Path>>(path: P, limits: Limits) -> Result<SquishyPicture, Error> {
+    let file = File::open(path.as_ref())?;
+
+    // SAFETY: The map is only read during this function, and callers are
+    // told not to modify the file in the meantime.
+    match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(map) => SquishyPicture::decode_slice_with_limits(&map, limits),
+        Err(_) => {
+            let len = file.metadata().ok().map(|m| m.len());
+            decode_buffered(file, len, limits)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::ToString, vec};
+
+    use super::*;
+    use crate::{
+        compression::{dct::{dct_round_trip_psnr, psnr, quantization_matrix}, lossless::{compress, compress_chunk}},
+        container::{ChunkIndex, SectionHeader, MAX_ENCODER_INFO_LEN},
+        header::{FIRST_CHUNK_OFFSET_VERSION, FIRST_FOOTER_VERSION, FIRST_STORED_NONE_VERSION},
+        options::TransparentCleanup,
+    };
+
+    fn test_image(compression_type: CompressionType, quality: Option<u8>) -> Vec<u8> {
+        let bitmap = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap);
+
+        // Without the encoder info, so offsets into the file don't change
+        // with the version of the crate
+        let mut output = Vec::new();
+        sqp.encode_with_options(&mut output, &EncodeOptions::new().omit_encoder_info(true)).unwrap();
+        output
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decode_fixtures() {
+        for path in ["test_images/test-lossless.sqp", "test_images/test-lossy.sqp"] {
+            let sqp = open(path).unwrap();
+            assert_eq!((sqp.width(), sqp.height()), (1123, 639));
+            assert_eq!(sqp.color_format(), ColorFormat::Rgba8);
+            assert_eq!(sqp.as_raw().len(), 1123 * 639 * 4);
+
+            // The fixtures predate versioning
+            assert_eq!(sqp.header.version, 0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn lossless_backends_fixture_round_trip() {
+        let mut sqp = open("test_images/test-lossless.sqp").unwrap();
+        let lzw = sqp.encode_to_vec().unwrap();
+
+        for compression_type in [CompressionType::LosslessV2, CompressionType::LosslessBwt] {
+            sqp.set_compression(compression_type, None);
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert!(encoded.len() < lzw.len(), "{} >= {}", encoded.len(), lzw.len());
+
+            let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.compression_type(), compression_type);
+            assert_eq!(decoded.as_raw(), sqp.as_raw());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn predictive_fixture_round_trip() {
+        let mut sqp = open("test_images/test-lossless.sqp").unwrap();
+        let lzw = sqp.encode_to_vec().unwrap();
+
+        sqp.set_compression(CompressionType::Predictive, None);
+        let lossless = sqp.encode_to_vec().unwrap();
+        let decoded = SquishyPicture::decode(lossless.as_slice()).unwrap();
+        assert_eq!(decoded.near(), Some(0));
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+
+        // The fixture is flat graphics which suit LZW, but allowing any
+        // error at all makes up for that. Each sample stays within `near`,
+        // and larger ones are smaller still.
+        let mut previous = lzw.len();
+        for near in [1, 2, 4] {
+            sqp.set_compression(CompressionType::Predictive, Some(near));
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert!(encoded.len() < previous, "{} at {near} vs {previous}", encoded.len());
+            previous = encoded.len();
+
+            let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.near(), Some(near));
+            let error = decoded.as_raw().iter().zip(sqp.as_raw()).map(|(a, b)| a.abs_diff(*b)).max();
+            assert!(error <= Some(near), "{error:?} at {near}");
+        }
+    }
+
+    /// Encode and decode `sqp`, checking the decoded image states `bound`
+    /// and that every sample is within it.
+    fn assert_within_max_error(sqp: &SquishyPicture, bound: u8) {
+        let decoded = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.max_error(), Some(bound));
+        if bound == 0 {
+            assert_eq!(decoded.as_raw(), sqp.as_raw());
+        }
+
+        let error = decoded.as_raw().iter().zip(sqp.as_raw()).map(|(a, b)| a.abs_diff(*b)).max();
+        assert!(error <= Some(bound), "{error:?} with a bound of {bound}");
+    }
+
+    #[test]
+    fn max_error_bound_holds() {
+        let mut state = 0x2545F491u32;
+        for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8, ColorFormat::Gray4] {
+            for (width, height) in [(1, 1), (13, 7), (70, 45)] {
+                let len = color_format.bitmap_len(width, height).unwrap();
+                let noise: Vec<u8> = (0..len).map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                }).collect();
+                let smooth: Vec<u8> = (0..len).map(|i| ((i % 97) * 2 + i / 300 + noise[i] as usize % 3) as u8).collect();
+
+                for bitmap in [noise, smooth] {
+                    for max_error in [0, 1, 2, 5, 40, 255] {
+                        let sqp = SquishyPicture::from_raw_near_lossless(width, height, color_format, max_error, bitmap.clone());
+
+                        // Packed samples are always lossless
+                        let bound = match color_format {
+                            ColorFormat::Gray4 => 0,
+                            _ => max_error.min(MAX_NEAR),
+                        };
+                        assert_within_max_error(&sqp, bound);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_error_header() {
+        let bitmap = vec![0; 4 * 4 * 3];
+        let mut sqp = SquishyPicture::from_raw_lossy(4, 4, ColorFormat::Rgb8, 80, bitmap);
+        assert_eq!(sqp.max_error(), None);
+
+        sqp.set_max_error(0);
+        assert_eq!((sqp.compression_type(), sqp.max_error()), (CompressionType::Predictive, Some(0)));
+        sqp.set_max_error(3);
+        assert_eq!(sqp.near(), Some(3));
+
+        // Lossless images already meet a bound of 0
+        for compression_type in [CompressionType::None, CompressionType::Lossless, CompressionType::LosslessBwt] {
+            sqp.set_compression(compression_type, None);
+            sqp.set_max_error(0);
+            assert_eq!((sqp.compression_type(), sqp.max_error()), (compression_type, Some(0)));
+        }
+
+        // The bound is read back from the header alone
+        sqp.set_max_error(2);
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(Header::read_from(&mut encoded.as_slice()).unwrap().max_error(), Some(2));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn max_error_fixtures() {
+        for path in ["test_images/test-lossless.sqp", "test_images/test-lossy.sqp"] {
+            let mut sqp = open(path).unwrap();
+            for max_error in [0, 1, 2, 5] {
+                sqp.set_max_error(max_error);
+                assert_within_max_error(&sqp, max_error);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn huffman_coded_fixtures_are_smaller() {
+        // The Huffman stage takes about 5% off lossless images, and a bit
+        // more off the coefficients of lossy ones
+        use crate::header::FIRST_HUFFMAN_VERSION;
+
+        for (path, percent) in [("test_images/test-lossless.sqp", 95), ("test_images/test-lossy.sqp", 94)] {
+            let mut sqp = open(path).unwrap();
+            sqp.header.version = FIRST_HUFFMAN_VERSION - 1;
+            let plain = sqp.encode_to_vec().unwrap();
+
+            sqp.header.version = FIRST_HUFFMAN_VERSION;
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert!(encoded.len() * 100 < plain.len() * percent, "{} vs {}", encoded.len(), plain.len());
+
+            let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+            assert_eq!(decoded.as_raw(), SquishyPicture::decode_slice(&plain).unwrap().as_raw());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn range_coded_fixture_is_smaller() {
+        let mut sqp = open("test_images/test-lossy.sqp").unwrap();
+        sqp.header.version = CURRENT_VERSION;
+
+        for quality in [30, 60, 90] {
+            sqp.set_compression(CompressionType::LossyDct, Some(quality));
+            sqp.set_range_coding(false).unwrap();
+            let varints = sqp.encode_to_vec().unwrap();
+
+            sqp.set_range_coding(true).unwrap();
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert!(encoded.len() * 100 < varints.len() * 70, "{} vs {}", encoded.len(), varints.len());
+
+            // The same coefficients decode to the same pixels
+            let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+            assert!(decoded.range_coded());
+            assert_eq!(decoded.as_raw(), SquishyPicture::decode_slice(&varints).unwrap().as_raw());
+        }
+    }
+
+    #[test]
+    fn try_from_conversions() {
+        for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+            let mut sqp = keyed_image(19, 11);
+            sqp.set_compression(compression_type, quality);
+
+            let encoded: Vec<u8> = (&sqp).try_into().unwrap();
+            assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+
+            let expected = SquishyPicture::decode_slice(&encoded).unwrap();
+            let from_slice = SquishyPicture::try_from(encoded.as_slice()).unwrap();
+            let from_vec = SquishyPicture::try_from(encoded).unwrap();
+            for decoded in [from_slice, from_vec] {
+                assert_eq!(decoded.as_raw(), expected.as_raw());
+                assert_eq!(decoded.transparency(), sqp.transparency());
+                assert_eq!(decoded.compression_type(), compression_type);
+            }
+        }
+
+        // Failures are the same errors as decoding directly
+        assert!(matches!(SquishyPicture::try_from(&b"notanimg"[..]), Err(Error::TruncatedFile { section: FileSection::Header })));
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[0] = b'D';
+        assert!(matches!(SquishyPicture::try_from(encoded.clone()), Err(Error::InvalidIdentifier(_))));
+        assert!(matches!(SquishyPicture::decode_slice(&encoded), Err(Error::InvalidIdentifier(_))));
+
+        let truncated = test_image(CompressionType::Lossless, None)[..40].to_vec();
+        assert!(matches!(
+            SquishyPicture::try_from(truncated.as_slice()),
+            Err(Error::TruncatedFile { section: FileSection::ChunkTable | FileSection::ChunkData })
+        ));
+
+        let mismatched = SquishyPicture::from_raw_lossless(4, 4, ColorFormat::Gray8, vec![0; 3]);
+        assert!(matches!(Vec::<u8>::try_from(&mismatched), Err(Error::SizeMismatch { expected: 16, actual: 3 })));
+    }
+
+    #[test]
+    fn decode_unsupported_version() {
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[19] = CURRENT_VERSION + 1;
+
+        assert!(matches!(
+            SquishyPicture::decode(encoded.as_slice()),
+            Err(Error::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn from_fn() {
+        for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8] {
+            let mut calls = Vec::new();
+            let sqp = SquishyPicture::from_fn(5, 3, color_format, |x, y, pixel| {
+                assert_eq!(pixel.len(), color_format.pbc());
+                calls.push((x, y));
+                pixel.fill((y * 5 + x) as u8);
+            });
+
+            let expected: Vec<u8> = (0..15).flat_map(|i| vec![i; color_format.pbc()]).collect();
+            assert_eq!(*sqp.as_raw(), expected);
+            assert_eq!(calls, (0..3).flat_map(|y| (0..5).map(move |x| (x, y))).collect::<Vec<_>>());
+            assert_eq!(sqp.compression_type(), CompressionType::Lossless);
+        }
+
+        assert!(SquishyPicture::from_fn(0, 3, ColorFormat::Rgba8, |_, _, _| unreachable!()).as_raw().is_empty());
+        assert!(SquishyPicture::from_fn(3, 0, ColorFormat::Rgba8, |_, _, _| unreachable!()).as_raw().is_empty());
+    }
+
+    #[test]
+    fn set_compression() {
+        let mut sqp = SquishyPicture::from_fn(9, 7, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[x as u8 * 20, y as u8 * 30, 0, 255]);
+        });
+
+        sqp.set_compression(CompressionType::LossyDct, Some(90));
+        let decoded = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.compression_type(), CompressionType::LossyDct);
+        assert_eq!(decoded.quality(), Some(90));
+
+        sqp.set_compression(CompressionType::None, Some(90));
+        let decoded = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.compression_type(), CompressionType::None);
+        assert_eq!(decoded.quality(), None);
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+    }
+
+    #[test]
+    fn overlay_clipped() {
+        let mut sqp = SquishyPicture::from_fn(4, 3, ColorFormat::Rgb8, |_, _, pixel| pixel.fill(0));
+        let logo = SquishyPicture::from_fn(2, 2, ColorFormat::Rgba8, |x, _, pixel| {
+            pixel.copy_from_slice(&[0xFF, 0xFF, 0xFF, [0xFF, 127][x as usize]]);
+        });
+
+        sqp.overlay(&logo, 3, 2).unwrap();
+
+        let mut expected = vec![0; 4 * 3 * 3];
+        expected[(2 * 4 + 3) * 3..].fill(0xFF);
+        assert_eq!(*sqp.as_raw(), expected);
+
+        sqp.overlay(&logo, 2, 0).unwrap();
+        expected[2 * 3..3 * 3].fill(0xFF);
+        expected[3 * 3..4 * 3].fill(127);
+        expected[(4 + 2) * 3..(4 + 3) * 3].fill(0xFF);
+        expected[(4 + 3) * 3..(4 + 4) * 3].fill(127);
+        assert_eq!(*sqp.as_raw(), expected);
+
+        let short = SquishyPicture::from_raw_lossless(2, 2, ColorFormat::Rgba8, vec![0; 15]);
+        assert!(matches!(sqp.overlay(&short, 0, 0), Err(Error::SizeMismatch { expected: 16, actual: 15 })));
+    }
+
+    #[test]
+    fn auto_optimize_format() {
+        let opaque = SquishyPicture::from_fn(64, 64, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[x as u8 * 4, y as u8 * 4, (x ^ y) as u8, 0xFF]);
+        });
+        let options = EncodeOptions::new().auto_optimize_format(true);
+
+        let mut encoded = Vec::new();
+        opaque.encode_with_options(&mut encoded, &options).unwrap();
+        assert!(encoded.len() < opaque.encode_to_vec().unwrap().len());
+
+        let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+        assert_eq!(decoded.color_format(), ColorFormat::Rgb8);
+        assert_eq!(*decoded.as_raw(), *opaque.convert_color_format(ColorFormat::Rgb8).as_raw());
+
+        // Every way of encoding agrees
+        let mut low_memory = Vec::new();
+        opaque.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+        assert_eq!(low_memory, encoded);
+
+        let mut intermediate = Vec::new();
+        opaque.encode_with_intermediates(&mut intermediate, &options).unwrap();
+        assert_eq!(intermediate, encoded);
+
+        // Off by default
+        let mut default = Vec::new();
+        opaque.encode_with_options(&mut default, &EncodeOptions::default()).unwrap();
+        assert_eq!(default, opaque.encode_to_vec().unwrap());
+
+        // One pixel which isn't quite opaque keeps the alpha
+        let mut nearly_opaque = opaque.bitmap.clone();
+        nearly_opaque[4 * 100 + 3] = 254;
+        let nearly_opaque = SquishyPicture::from_raw_lossy(64, 64, ColorFormat::Rgba8, 80, nearly_opaque);
+
+        let mut encoded = Vec::new();
+        nearly_opaque.encode_with_options(&mut encoded, &options).unwrap();
+        assert_eq!(encoded, nearly_opaque.encode_to_vec().unwrap());
+
+        // Gray with alpha
+        let gray = SquishyPicture::from_fn(8, 8, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[x as u8, x as u8, x as u8, y as u8]);
+        });
+        let mut encoded = Vec::new();
+        gray.encode_with_options(&mut encoded, &options).unwrap();
+        assert_eq!(SquishyPicture::decode_slice(&encoded).unwrap().color_format(), ColorFormat::GrayA8);
+    }
+
+    #[test]
+    fn clean_transparent() {
+        // A sprite with a soft edge, on a background of junk color
+        let mut state = 0x2545F491u32;
+        let sprite = SquishyPicture::from_fn(64, 64, ColorFormat::Rgba8, |x, y, pixel| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            let distance = x.abs_diff(32).max(y.abs_diff(32));
+            let alpha = match distance {
+                0..16 => 255,
+                16..20 => (20 - distance) as u8 * 60,
+                _ => 0,
+            };
+            pixel.copy_from_slice(&[x as u8 * 4, y as u8 * 4, (state >> 24) as u8, alpha]);
+        });
+
+        for (cleanup, compression_type) in [
+            (TransparentCleanup::Constant(0), CompressionType::LosslessV2),
+            (TransparentCleanup::Bleed, CompressionType::LossyDct),
+        ] {
+            let mut sprite = SquishyPicture { header: sprite.header, bitmap: sprite.bitmap.clone(), encoder_info: None };
+            sprite.set_compression(compression_type, Some(80));
+            let options = EncodeOptions::new().clean_transparent(cleanup);
+
+            let mut encoded = Vec::new();
+            sprite.encode_with_options(&mut encoded, &options).unwrap();
+            assert!(encoded.len() < sprite.encode_to_vec().unwrap().len(), "{cleanup:?}");
+
+            let mut low_memory = Vec::new();
+            sprite.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+            assert_eq!(low_memory, encoded);
+
+            // Only the color under an alpha of 0 changes
+            let mut cleaned = sprite.bitmap.clone();
+            crate::operations::clean_transparent(64, ColorFormat::Rgba8, cleanup, &mut cleaned);
+            for (pixel, original) in cleaned.chunks_exact(4).zip(sprite.bitmap.chunks_exact(4)) {
+                if original[3] != 0 {
+                    assert_eq!(pixel, original);
+                }
+            }
+
+            if compression_type == CompressionType::LosslessV2 {
+                assert_eq!(*SquishyPicture::decode_slice(&encoded).unwrap().as_raw(), cleaned);
+            }
+        }
+
+        // Off by default
+        let lossless = SquishyPicture::from_raw_lossless(64, 64, ColorFormat::Rgba8, sprite.bitmap.clone());
+        let mut default = Vec::new();
+        lossless.encode_with_options(&mut default, &EncodeOptions::default()).unwrap();
+        assert_eq!(*SquishyPicture::decode_slice(&default).unwrap().as_raw(), sprite.bitmap);
+    }
+
+    #[test]
+    fn deblock_on_decode() {
+        let gradient = SquishyPicture::from_fn(96, 64, ColorFormat::Rgba8, |x, y, pixel| {
+            pixel.copy_from_slice(&[(x * 2) as u8 + 20, (y * 3) as u8 + 10, (x + y) as u8, 255 - x as u8]);
+        });
+        let lossy = SquishyPicture::from_raw_lossy(96, 64, ColorFormat::Rgba8, 20, gradient.bitmap.clone());
+        let encoded = lossy.encode_to_vec().unwrap();
+
+        // Off by default, so the output doesn't change
+        let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+        let undeblocked = SquishyPicture::decode_with_options(encoded.as_slice(), &DecodeOptions::new().deblock(0)).unwrap();
+        assert_eq!(undeblocked.bitmap, decoded.bitmap);
+
+        let options = DecodeOptions::new().deblock(2);
+        let deblocked = SquishyPicture::decode_with_options(encoded.as_slice(), &options).unwrap();
+        let low_memory = SquishyPicture::decode_with_options(encoded.as_slice(), &options.clone().low_memory(true)).unwrap();
+        assert_eq!(low_memory.bitmap, deblocked.bitmap);
+
+        let mut standalone = SquishyPicture::decode_slice(&encoded).unwrap();
+        standalone.deblock(2).unwrap();
+        assert_eq!(standalone.bitmap, deblocked.bitmap);
+
+        let mut stream = crate::stream_decoder::SqpStreamDecoder::with_options(&options);
+        let Ok(crate::stream_decoder::DecodeProgress::Finished { picture, .. }) = stream.feed(&encoded) else {
+            panic!("the whole image was fed")
+        };
+        assert_eq!(picture.bitmap, deblocked.bitmap);
+
+        // The steps between blocks shrink, and alpha stays as it was
+        let edge_steps = |sqp: &SquishyPicture, ch: usize| -> u32 {
+            let value = |x: usize, y: usize| sqp.bitmap[(y * 96 + x) * 4 + ch] as i32;
+            let columns = (0..64).flat_map(|y| (8..96).step_by(8).map(move |x| (value(x, y) - value(x - 1, y)).unsigned_abs()));
+            let rows = (8..64).step_by(8).flat_map(|y| (0..96).map(move |x| (value(x, y) - value(x, y - 1)).unsigned_abs()));
+            columns.chain(rows).sum()
+        };
+        for ch in 0..3 {
+            assert!(edge_steps(&deblocked, ch) < edge_steps(&decoded, ch), "channel {ch}");
+        }
+        let alpha = |sqp: &SquishyPicture| -> Vec<u8> { sqp.bitmap.iter().skip(3).step_by(4).copied().collect() };
+        assert_eq!(alpha(&deblocked), alpha(&decoded));
+
+        // Lossless images have no blocks
+        let lossless = SquishyPicture::from_raw_lossless(96, 64, ColorFormat::Rgba8, gradient.bitmap.clone());
+        let encoded = lossless.encode_to_vec().unwrap();
+        assert_eq!(SquishyPicture::decode_with_options(encoded.as_slice(), &options).unwrap().bitmap, gradient.bitmap);
+    }
+
+    #[test]
+    fn target_psnr() {
+        let mut state = 0x2545F491u32;
+        let noisy = SquishyPicture::from_fn(64, 64, ColorFormat::Rgb8, |x, y, pixel| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = state % 64;
+            pixel.copy_from_slice(&[(x * 2 + noise) as u8, (y * 2 + noise) as u8, (noise * 3) as u8]);
+        });
+        let flat = SquishyPicture::from_fn(64, 64, ColorFormat::Rgb8, |_, _, pixel| pixel.copy_from_slice(&[40, 120, 200]));
+
+        let options = EncodeOptions::new().target_psnr(42.0);
+        let mut chosen = Vec::new();
+        for sqp in [noisy, flat] {
+            let mut sqp = sqp;
+            sqp.set_compression(CompressionType::LossyDct, Some(50));
+
+            let mut encoded = Vec::new();
+            let stats = sqp.encode_with_stats(&mut encoded, &options).unwrap();
+            assert_eq!(stats.bytes_written, encoded.len());
+            assert!(stats.target_met);
+            assert!(stats.psnr.unwrap() >= 42.0);
+
+            // The chosen quality is the lowest which meets the target
+            let quality = stats.quality.unwrap();
+            let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+            assert_eq!(decoded.quality(), Some(quality));
+            assert!(psnr(&sqp.bitmap, &decoded.bitmap) >= 42.0);
+            if quality > 1 {
+                let parameters = DctParameters { quality: quality as u32 - 1, ..dct_parameters(&decoded.header) };
+                assert!(dct_round_trip_psnr(&sqp.bitmap, parameters) < 42.0);
+            }
+
+            let mut low_memory = Vec::new();
+            sqp.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+            assert_eq!(low_memory, encoded);
+
+            chosen.push(quality);
+        }
+        assert!(chosen[0] >= chosen[1] + 50, "chose {chosen:?}");
+
+        // Out of reach, so the best effort is used
+        let mut sqp = SquishyPicture::from_fn(64, 64, ColorFormat::Gray8, |x, y, pixel| pixel[0] = ((x * 31) ^ (y * 17)) as u8);
+        sqp.set_compression(CompressionType::LossyDct, Some(50));
+        let stats = sqp.encode_with_stats(Vec::new(), &EncodeOptions::new().target_psnr(200.0)).unwrap();
+        assert_eq!(stats.quality, Some(100));
+        assert!(!stats.target_met);
+
+        // Nothing to choose for other compression types
+        sqp.set_compression(CompressionType::Lossless, None);
+        let stats = sqp.encode_with_stats(Vec::new(), &EncodeOptions::new().target_psnr(42.0)).unwrap();
+        assert_eq!((stats.quality, stats.psnr, stats.target_met), (None, None, true));
+    }
+
+    #[test]
+    fn max_encoded_size() {
+        let mut state = 0x2545F491u32;
+        let bitmap: Vec<u8> = (0..96 * 64 * 3).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (i / 7 + state % 32) as u8
+        }).collect();
+        let sqp = SquishyPicture::from_raw_lossy(96, 64, ColorFormat::Rgb8, 90, bitmap.clone());
+
+        let full_size = sqp.encode_to_vec().unwrap().len();
+        let max_size = full_size / 2;
+        let options = EncodeOptions::new().max_encoded_size(max_size);
+
+        let mut encoded = Vec::new();
+        let stats = sqp.encode_with_stats(&mut encoded, &options).unwrap();
+        assert!(encoded.len() <= max_size, "{} > {max_size}", encoded.len());
+        assert_eq!(stats.bytes_written, encoded.len());
+
+        // The highest quality which fits was chosen
+        let quality = stats.quality.unwrap();
+        assert!(quality < 90);
+        assert_eq!(SquishyPicture::decode_slice(&encoded).unwrap().quality(), Some(quality));
+        let higher = SquishyPicture::from_raw_lossy(96, 64, ColorFormat::Rgb8, quality + 1, bitmap.clone());
+        assert!(higher.encode_to_vec().unwrap().len() > max_size);
+
+        // The same every time, and the same in low memory mode
+        let mut again = Vec::new();
+        sqp.encode_with_options(&mut again, &options).unwrap();
+        assert_eq!(again, encoded);
+        let mut low_memory = Vec::new();
+        sqp.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+        assert_eq!(low_memory, encoded);
+
+        // Already small enough
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &EncodeOptions::new().max_encoded_size(full_size)).unwrap();
+        assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+
+        // Too small for any quality, and nothing is written
+        let mut encoded = Vec::new();
+        let result = sqp.encode_with_options(&mut encoded, &EncodeOptions::new().max_encoded_size(100));
+        assert!(matches!(result, Err(Error::CannotMeetSizeTarget { best }) if best > 100));
+        assert!(encoded.is_empty());
+
+        // Other compression types are only checked
+        let lossless = SquishyPicture::from_raw_lossless(96, 64, ColorFormat::Rgb8, bitmap);
+        let lossless_size = lossless.encode_to_vec().unwrap().len();
+        for low_memory in [false, true] {
+            let options = EncodeOptions::new().low_memory(low_memory);
+            assert!(lossless.encode_with_options(Vec::new(), &options.clone().max_encoded_size(lossless_size)).is_ok());
+
+            let mut encoded = Vec::new();
+            let result = lossless.encode_with_options(&mut encoded, &options.max_encoded_size(lossless_size - 1));
+            assert!(matches!(result, Err(Error::CannotMeetSizeTarget { best }) if best == lossless_size));
+            assert!(encoded.is_empty());
+        }
+    }
+
+    #[test]
+    fn quality_levels() {
+        let bitmap = vec![0; 9 * 7 * 4];
+        let quality = |compression_type, quality| {
+            SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap.clone()).header.quality
+        };
+
+        assert_eq!(quality(CompressionType::LossyDct, Some(0)), 1);
+        assert_eq!(quality(CompressionType::LossyDct, Some(1)), 1);
+        assert_eq!(quality(CompressionType::LossyDct, Some(100)), 100);
+        assert_eq!(quality(CompressionType::LossyDct, Some(101)), 100);
+        assert_eq!(quality(CompressionType::Lossless, Some(80)), 0);
+        assert_eq!(quality(CompressionType::None, Some(80)), 0);
+        assert_eq!(quality(CompressionType::Predictive, None), 0);
+        assert_eq!(quality(CompressionType::Predictive, Some(3)), 3);
+        assert_eq!(quality(CompressionType::Predictive, Some(255)), MAX_NEAR);
+
+        // Quality 100 is still lossy
+        let sqp = SquishyPicture::from_raw_lossy(9, 7, ColorFormat::Rgba8, 100, bitmap.clone());
+        let decoded = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.compression_type(), CompressionType::LossyDct);
+        assert_eq!(decoded.quality(), Some(100));
+
+        assert_eq!(quantization_matrix(0), quantization_matrix(1));
+        assert_eq!(quantization_matrix(101), quantization_matrix(100));
+    }
+
+    #[test]
+    fn decode_invalid_quality() {
+        for (compression_type, quality, valid) in [
+            (CompressionType::LossyDct, 0, false),
+            (CompressionType::LossyDct, 1, true),
+            (CompressionType::LossyDct, 100, true),
+            (CompressionType::LossyDct, 101, false),
+            (CompressionType::Lossless, 0, true),
+            (CompressionType::Lossless, 1, false),
+            (CompressionType::None, 0, true),
+            (CompressionType::None, 100, false),
+            (CompressionType::Predictive, 0, true),
+            (CompressionType::Predictive, MAX_NEAR, true),
+            (CompressionType::Predictive, MAX_NEAR + 1, false),
+        ] {
+            let mut encoded = test_image(compression_type, (compression_type == CompressionType::LossyDct).then_some(80));
+            encoded[17] = quality;
+
+            match SquishyPicture::decode_slice(&encoded) {
+                Ok(sqp) => assert!(valid && sqp.header.quality == quality),
+                Err(Error::InvalidQuality(q)) => assert!(!valid && q == quality),
+                Err(e) => panic!("unexpected error {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn version_0_round_trip() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let bitmap: Vec<u8> = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+            let mut sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap);
+            let current = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+
+            sqp.header.version = 0;
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert_eq!(&encoded[..8], b"dangoimg");
+            assert_eq!(encoded[16], compression_type as u8);
+
+            let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+            assert_eq!(decoded.header.version, 0);
+            assert_eq!(decoded.as_raw(), current.as_raw());
+        }
+    }
+
+    #[test]
+    fn sections_only_from_version_3() {
+        let bitmap: Vec<u8> = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let mut sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, CompressionType::Lossless, None, bitmap);
+        let options = EncodeOptions::new().omit_encoder_info(true);
+
+        // Version 5 adds a byte to each chunk, so compare with version 4
+        sqp.header.version = 4;
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &options).unwrap();
+        assert_eq!(&encoded[29..33], b"CINF");
+        assert_eq!(&encoded[49..53], b"PIXD");
+
+        // Older versions have the chunk count straight after the header
+        for (version, header_len) in [(1, 20), (2, 21)] {
+            sqp.header.version = version;
+            let legacy = sqp.encode_to_vec().unwrap();
+            assert_eq!(legacy.len(), encoded.len() - 16 - (29 - header_len));
+            assert_eq!(legacy[header_len..header_len + 4], 1u32.to_le_bytes());
+
+            let decoded = SquishyPicture::decode_slice(&legacy).unwrap();
+            assert_eq!(decoded.header.version, version);
+            assert_eq!(decoded.as_raw(), sqp.as_raw());
+        }
+    }
+
+    #[test]
+    fn none_stored_as_is() {
+        // Large enough for several stored chunks
+        let bitmap: Vec<u8> = (0..400 * 400 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let mut sqp = SquishyPicture::from_raw(400, 400, ColorFormat::Rgba8, CompressionType::None, None, bitmap);
+
+        let encoded = sqp.encode_to_vec().unwrap();
+        let table = ChunkIndex::read_from(encoded.as_slice()).unwrap();
+        assert!(table.chunk_count() > 1);
+        let start = encoded.len() - FOOTER_LEN - sqp.bitmap.len();
+        assert_eq!(table.chunk_range(0).unwrap().start as usize, start);
+        assert_eq!(&encoded[start..encoded.len() - FOOTER_LEN], sqp.as_raw());
+
+        let options = DecodeOptions::new();
+        let (decoded, warnings) = SquishyPicture::decode_with_report(encoded.as_slice(), &options).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+        let region = SquishyPicture::decode_region(encoded.as_slice(), 390, 300, 10, 100).unwrap();
+        assert_eq!(region.as_raw()[..40], sqp.as_raw()[(300 * 400 + 390) * 4..][..40]);
+
+        // Older versions still compress with LZW
+        sqp.header.version = FIRST_STORED_NONE_VERSION - 1;
+        let legacy = sqp.encode_to_vec().unwrap();
+        assert!(legacy.len() < encoded.len() / 2);
+
+        let decoded = SquishyPicture::decode_slice(&legacy).unwrap();
+        assert_eq!(decoded.header.version, FIRST_STORED_NONE_VERSION - 1);
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+    }
+
+    /// Insert a section with the given type and payload at `offset`, moving
+    /// the data offset in the header and the offsets of the chunks along if
+    /// it comes before them.
+    fn insert_section(encoded: &[u8], offset: usize, kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut section = kind.to_vec();
+        section.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        section.extend_from_slice(payload);
+
+        let mut inserted = [&encoded[..offset], &section, &encoded[offset..]].concat();
+        let mut data_offset = u64::from_le_bytes(inserted[21..29].try_into().unwrap());
+        if offset as u64 <= data_offset {
+            data_offset += section.len() as u64;
+            inserted[21..29].copy_from_slice(&data_offset.to_le_bytes());
+        }
+
+        // From version 8 on the chunks have their offsets in the table too
+        if encoded[19] >= FIRST_CHUNK_OFFSET_VERSION {
+            let table = data_offset as usize + SectionHeader::LEN;
+            let chunk_count = u32::from_le_bytes(inserted[table..table + 4].try_into().unwrap()) as usize;
+            for entry in (0..chunk_count).map(|i| table + 4 + i * 16 + 8) {
+                let chunk_offset = u64::from_le_bytes(inserted[entry..entry + 8].try_into().unwrap());
+                if offset as u64 <= chunk_offset {
+                    inserted[entry..entry + 8].copy_from_slice(&(chunk_offset + section.len() as u64).to_le_bytes());
+                }
+            }
+        }
+
+        // From version 10 on the footer ends with the length of the image
+        if encoded[19] >= FIRST_FOOTER_VERSION {
+            let image_len = inserted.len() - FOOTER_LEN;
+            inserted[image_len + 4..].copy_from_slice(&(image_len as u64).to_le_bytes());
+        }
+
+        inserted
+    }
+
+    #[test]
+    fn sections_ancillary_skipped() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let encoded = test_image(compression_type, quality);
+            let expected = SquishyPicture::decode_slice(&encoded).unwrap();
+
+            // One between the chunk table and pixel data, and one larger
+            // than the skip buffer before the chunk table
+            let with_text = insert_section(&encoded, 57, b"teXt", b"hello");
+            let mut with_sections = insert_section(&with_text, 29, b"zzzz", &[0xAA; 5000]);
+
+            let decoded = SquishyPicture::decode_slice(&with_sections).unwrap();
+            assert_eq!(decoded.as_raw(), expected.as_raw());
+            let decoded = SquishyPicture::decode(with_sections.as_slice()).unwrap();
+            assert_eq!(decoded.as_raw(), expected.as_raw());
+
+            for low_memory in [false, true] {
+                assert_warnings(&with_sections, low_memory, &[]);
+            }
+
+            // Trailing bytes are reported after the pixel data, past the
+            // skipped sections
+            let offset = with_sections.len() as u64;
+            with_sections.extend_from_slice(&[1, 2, 3]);
+            for low_memory in [false, true] {
+                assert_warnings(&with_sections, low_memory, &[DecodeWarning::TrailingBytes { offset, len: 3 }]);
+            }
+        }
+    }
+
+    #[test]
+    fn data_offset() {
+        let encoded = test_image(CompressionType::Lossless, None);
+        let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+        assert_eq!(decoded.header.data_offset, Some(29));
+        assert_eq!(&encoded[29..33], b"CINF");
+
+        // Jumping straight to the offset finds the chunk table
+        let mut input = &encoded[29..];
+        let section = SectionHeader::read_from(&mut input).unwrap();
+        assert_eq!(section.kind, SectionType::CHUNK_TABLE);
+        assert_eq!(CompressionInfo::read_from(&mut input).unwrap().chunks.len(), 1);
+
+        // Sections spliced in before the chunk table move it
+        let spliced = insert_section(&encoded, 29, b"teXt", b"hello");
+        let decoded = SquishyPicture::decode_slice(&spliced).unwrap();
+        assert_eq!(decoded.header.data_offset, Some(29 + 13));
+
+        // Version 3 has no offset
+        let mut sqp = decoded;
+        sqp.header.version = 3;
+        let version_3 = sqp.encode_to_vec().unwrap();
+        assert_eq!(&version_3[21..25], b"CINF");
+        assert_eq!(SquishyPicture::decode_slice(&version_3).unwrap().header.data_offset, None);
+
+        // Including when a section was spliced in without moving it
+        for (offset, mut file) in [
+            (0, encoded.clone()),
+            (30, encoded.clone()),
+            (encoded.len() as u64, encoded.clone()),
+            (u64::MAX, encoded.clone()),
+            (29, spliced),
+        ] {
+            file[21..29].copy_from_slice(&offset.to_le_bytes());
+            let actual = if file.len() == encoded.len() { 29 } else { 29 + 13 };
+
+            for result in [SquishyPicture::decode(file.as_slice()), SquishyPicture::decode_slice(&file)] {
+                match result {
+                    Err(Error::DataOffsetMismatch { expected, actual: a }) => assert_eq!((expected, a), (offset, actual)),
+                    other => panic!("expected a data offset mismatch, got {:?}", other.err()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_info_round_trip() {
+        let library = concat!("sqp ", env!("CARGO_PKG_VERSION"));
+
+        for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+            let bitmap = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+            let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap);
+            assert_eq!(sqp.encoder_info(), None);
+
+            for (options, expected) in [
+                (EncodeOptions::new(), Some(library.to_string())),
+                (EncodeOptions::new().encoder_name("myapp 1.2"), Some(format!("myapp 1.2 ({library})"))),
+                (EncodeOptions::new().encoder_name("myapp 1.2").omit_encoder_info(true), None),
+            ] {
+                let mut encoded = Vec::new();
+                sqp.encode_with_options(&mut encoded, &options).unwrap();
+
+                let mut low_memory = Vec::new();
+                sqp.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+                assert_eq!(low_memory, encoded);
+
+                for decoded in [
+                    SquishyPicture::decode(encoded.as_slice()).unwrap(),
+                    SquishyPicture::decode_slice(&encoded).unwrap(),
+                    SquishyPicture::decode_with_options(encoded.as_slice(), &DecodeOptions::new().low_memory(true)).unwrap(),
+                    SquishyPicture::decode_scaled(encoded.as_slice(), 2).unwrap(),
+                ] {
+                    assert_eq!(decoded.encoder_info(), expected.as_deref());
+                }
+            }
+        }
+
+        // Older versions have nowhere to store it
+        let mut sqp = SquishyPicture::from_raw_lossless(2, 2, ColorFormat::Gray8, vec![1, 2, 3, 4]);
+        sqp.header.version = 2;
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(SquishyPicture::decode_slice(&encoded).unwrap().encoder_info(), None);
+    }
+
+    #[test]
+    fn encoder_info_long_name() {
+        let sqp = SquishyPicture::from_raw_lossless(2, 2, ColorFormat::Gray8, vec![1, 2, 3, 4]);
+        let options = EncodeOptions::new().encoder_name("é".repeat(200));
+
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &options).unwrap();
+
+        let decoded = SquishyPicture::decode_slice(&encoded).unwrap();
+        let info = decoded.encoder_info().unwrap();
+        assert!(info.len() <= MAX_ENCODER_INFO_LEN);
+        assert!(info.starts_with("éé"));
+        assert!(info.ends_with(concat!(" (sqp ", env!("CARGO_PKG_VERSION"), ")")));
+    }
+
+    #[test]
+    fn encoder_info_invalid_ignored() {
+        let encoded = test_image(CompressionType::Lossless, None);
+
+        // Invalid UTF-8 and records longer than any encoder writes are
+        // skipped like other ancillary sections
+        for payload in [&[0xFF, 0xFE][..], &[b'a'; MAX_ENCODER_INFO_LEN + 1]] {
+            let file = insert_section(&encoded, 57, b"encI", payload);
+
+            for low_memory in [false, true] {
+                assert_warnings(&file, low_memory, &[]);
+            }
+            assert_eq!(SquishyPicture::decode_slice(&file).unwrap().encoder_info(), None);
+            assert_eq!(SquishyPicture::decode(file.as_slice()).unwrap().encoder_info(), None);
+        }
+    }
+
+    #[test]
+    fn content_hash_read_without_decoding() {
+        let encoded = test_image(CompressionType::Lossless, None);
+        assert_eq!(SquishyPicture::read_content_hash(encoded.as_slice()).unwrap(), None);
+
+        let hash = 0x0123_4567_89AB_CDEFu64;
+        for (algorithm, len, expected) in [(1, 9, Some(hash)), (2, 9, None), (1, 8, None)] {
+            let payload = [&[algorithm][..], &hash.to_le_bytes()].concat();
+            let file = insert_section(&encoded, 57, b"hasH", &payload[..len]);
+
+            assert_eq!(SquishyPicture::read_content_hash(file.as_slice()).unwrap(), expected);
+            assert!(SquishyPicture::decode_slice(&file).is_ok());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "content-hash")]
+    fn content_hash_round_trip() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let bitmap = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+            let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap);
+            let options = EncodeOptions::new().content_hash(true);
+
+            let mut encoded = Vec::new();
+            sqp.encode_with_options(&mut encoded, &options).unwrap();
+
+            let mut low_memory = Vec::new();
+            sqp.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+            assert_eq!(low_memory, encoded);
+
+            // Lossy images keep the hash of the bitmap that went in
+            let hash = xxhash_rust::xxh64::xxh64(sqp.as_raw(), 0);
+            assert_eq!(SquishyPicture::read_content_hash(encoded.as_slice()).unwrap(), Some(hash));
+
+            let expected = match compression_type {
+                CompressionType::LossyDct => ContentCheck::Lossy,
+                _ => ContentCheck::Match,
+            };
+            assert_eq!(SquishyPicture::verify_content(encoded.as_slice()).unwrap(), expected);
+
+            for low_memory in [false, true] {
+                assert_warnings(&encoded, low_memory, &[]);
+            }
+
+            let without = sqp.encode_to_vec().unwrap();
+            assert_eq!(SquishyPicture::verify_content(without.as_slice()).unwrap(), ContentCheck::Missing);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "content-hash")]
+    fn content_hash_mismatch() {
+        let sqp = SquishyPicture::from_raw_lossless(9, 7, ColorFormat::Rgba8, vec![0x55; 9 * 7 * 4]);
+        let other = SquishyPicture::from_raw_lossless(9, 7, ColorFormat::Rgba8, vec![0x56; 9 * 7 * 4]);
+        let options = EncodeOptions::new().content_hash(true);
+
+        // Pixels which decode cleanly but aren't the ones that were hashed,
+        // as a codec bug would give
+        let (data, compression_info) = sqp.compress_bitmap(&options).unwrap();
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &other, &compression_info, &options).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &other.header, image_len).unwrap();
+
+        let expected = xxhash_rust::xxh64::xxh64(other.as_raw(), 0);
+        let actual = xxhash_rust::xxh64::xxh64(sqp.as_raw(), 0);
+        assert_eq!(
+            SquishyPicture::verify_content(encoded.as_slice()).unwrap(),
+            ContentCheck::Mismatch { expected, actual },
+        );
+        assert_warnings(&encoded, false, &[DecodeWarning::ContentHashMismatch { expected, actual }]);
+
+        // A corrupted hash is caught the same way
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &options).unwrap();
+        let end = encoded.len() - FOOTER_LEN - data.len() - SectionHeader::LEN;
+        encoded[end - 1] ^= 0x80;
+
+        let expected = actual ^ (0x80 << 56);
+        assert_eq!(
+            SquishyPicture::verify_content(encoded.as_slice()).unwrap(),
+            ContentCheck::Mismatch { expected, actual },
+        );
+        assert_warnings(&encoded, false, &[DecodeWarning::ContentHashMismatch { expected, actual }]);
+    }
+
+    #[test]
+    fn sections_invalid() {
+        let encoded = test_image(CompressionType::Lossless, None);
+        let pixel_data_len = u32::from_le_bytes(encoded[61..65].try_into().unwrap());
+
+        let mut chunk_table_too_long = encoded.clone();
+        chunk_table_too_long[33..37].copy_from_slice(&21u32.to_le_bytes());
+        let mut pixel_data_too_short = encoded.clone();
+        pixel_data_too_short[61..65].copy_from_slice(&(pixel_data_len - 1).to_le_bytes());
+
+        for (file, check) in [
+            (
+                insert_section(&encoded, 29, b"XTRA", b""),
+                &(|e: &Error| matches!(e, Error::UnknownCriticalSection(SectionType(t)) if t == b"XTRA"))
+                    as &dyn Fn(&Error) -> bool,
+            ),
+            (
+                insert_section(&encoded, 57, b"CINF", &encoded[37..57]),
+                &|e: &Error| matches!(e, Error::UnexpectedSection(SectionType::CHUNK_TABLE)),
+            ),
+            (
+                [&encoded[..29], &encoded[57..]].concat(),
+                &|e: &Error| matches!(e, Error::UnexpectedSection(SectionType::PIXEL_DATA)),
+            ),
+            (
+                chunk_table_too_long,
+                &|e: &Error| matches!(e, Error::SectionSizeMismatch {
+                    section: SectionType::CHUNK_TABLE,
+                    expected: 20,
+                    actual: 21,
+                }),
+            ),
+            (
+                pixel_data_too_short,
+                &|e: &Error| matches!(e, Error::SectionSizeMismatch { section: SectionType::PIXEL_DATA, .. }),
+            ),
+            (
+                insert_section(&encoded, 29, b"teXt", b"hello")[..38].to_vec(),
+                &|e: &Error| matches!(e, Error::TruncatedFile { section: FileSection::Sections }),
+            ),
+        ] {
+            let error = SquishyPicture::decode_slice(&file).err().unwrap();
+            assert!(check(&error), "{error:?}");
+            let error = SquishyPicture::decode(file.as_slice()).err().unwrap();
+            assert!(check(&error), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn decode_slice_matches_decode() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let encoded = test_image(compression_type, quality);
+
+            let from_reader = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let from_slice = SquishyPicture::decode_slice(&encoded).unwrap();
+            assert_eq!(from_reader.as_raw(), from_slice.as_raw());
+
+            for len in 0..encoded.len() {
+                assert!(SquishyPicture::decode_slice(&encoded[..len]).is_err());
+            }
+        }
+    }
+
+    /// An RGB image where every third pixel is magenta.
+    fn keyed_image(width: u32, height: u32) -> SquishyPicture {
+        let mut sqp = SquishyPicture::from_fn(width, height, ColorFormat::Rgb8, |x, y, pixel| {
+            match (x + y * width) % 3 {
+                0 => pixel.copy_from_slice(&[0xFF, 0x00, 0xFF]),
+                _ => pixel.copy_from_slice(&[x as u8, y as u8, 0x80]),
+            }
+        });
+        sqp.set_transparent_color(Some([0xFF, 0x00, 0xFF])).unwrap();
+        sqp
+    }
+
+    #[test]
+    fn transparent_color_round_trip() {
+        for width in 1..=17 {
+            for height in [1, 3] {
+                for compression_type in [CompressionType::None, CompressionType::Lossless] {
+                    let mut sqp = keyed_image(width, height);
+                    sqp.set_compression(compression_type, None);
+
+                    let encoded = sqp.encode_to_vec().unwrap();
+                    assert_eq!(sqp.header.len(), 32);
+
+                    let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+                    assert_eq!(decoded.transparency(), Some([0xFF, 0x00, 0xFF]));
+                    assert_eq!(decoded.as_raw(), sqp.as_raw());
+
+                    let rgba = decoded.expand_transparency();
+                    assert_eq!(rgba.color_format(), ColorFormat::Rgba8);
+                    assert_eq!(rgba.transparency(), None);
+                    for (i, pixel) in rgba.as_raw().chunks_exact(4).enumerate() {
+                        let alpha = if i % 3 == 0 { 0 } else { 255 };
+                        assert_eq!(pixel[3], alpha, "pixel {i} of {width}x{height}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_color_header() {
+        let mut sqp = keyed_image(4, 4);
+        sqp.header.version = 1;
+
+        // Setting a color upgrades the header, and removing it keeps the
+        // version
+        sqp.set_transparent_color(Some([1, 2, 3])).unwrap();
+        assert_eq!(sqp.header.version, 2);
+        sqp.set_transparent_color(None).unwrap();
+        assert_eq!(sqp.header.len(), 21);
+
+        let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.transparency(), None);
+
+        let mut gray = SquishyPicture::from_raw_lossless(1, 1, ColorFormat::GrayA8, vec![0, 0]);
+        assert!(matches!(
+            gray.set_transparent_color(Some([0; 3])),
+            Err(Error::TransparencyUnsupported(ColorFormat::GrayA8))
+        ));
+        assert_eq!(gray.transparency(), None);
+
+        // A color on a format which can't have one, and unknown flags
+        let mut encoded = keyed_image(4, 4).encode_to_vec().unwrap();
+        encoded[18] = ColorFormat::Gray8 as u8;
+        assert!(matches!(
+            SquishyPicture::decode(encoded.as_slice()),
+            Err(Error::TransparencyUnsupported(ColorFormat::Gray8))
+        ));
+
+        let mut encoded = keyed_image(4, 4).encode_to_vec().unwrap();
+        encoded[20] = 0x03;
+        assert!(matches!(SquishyPicture::decode(encoded.as_slice()), Err(Error::InvalidFlags(0x03))));
+
+        // Ending within the color or the data offset
+        let encoded = keyed_image(4, 4).encode_to_vec().unwrap();
+        for len in 20..32 {
+            assert!(matches!(
+                SquishyPicture::decode(&encoded[..len]),
+                Err(Error::TruncatedFile { section: FileSection::Header })
+            ));
+        }
+    }
+
+    #[test]
+    fn transparent_color_conversion() {
+        let sqp = keyed_image(3, 1);
+
+        let gray_alpha = sqp.convert_color_format(ColorFormat::GrayA8);
+        assert_eq!(gray_alpha.transparency(), None);
+        assert_eq!(gray_alpha.as_raw().iter().skip(1).step_by(2).collect::<Vec<_>>(), [&0, &255, &255]);
+
+        assert_eq!(sqp.convert_color_format(ColorFormat::Gray8).transparency(), None);
+        assert_eq!(sqp.convert_color_format(ColorFormat::Rgb8).transparency(), sqp.transparency());
+
+        // The color would be lost in gray, so the format isn't optimized
+        let mut keyed = SquishyPicture::from_fn(8, 8, ColorFormat::Rgb8, |x, _, pixel| pixel.fill(x as u8));
+        keyed.set_transparent_color(Some([0; 3])).unwrap();
+
+        let mut encoded = Vec::new();
+        keyed.encode_with_options(&mut encoded, &EncodeOptions::new().auto_optimize_format(true)).unwrap();
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.color_format(), ColorFormat::Rgb8);
+        assert_eq!(decoded.transparency(), Some([0; 3]));
+    }
+
+    /// A lossy image with partial blocks at the edges, some detail and a
+    /// transparent corner, encoded with and without range coding.
+    fn range_coded_images() -> (SquishyPicture, Vec<u8>, Vec<u8>) {
+        let mut sqp = SquishyPicture::from_fn(67, 45, ColorFormat::Rgba8, |x, y, pixel| {
+            let detail = ((x * 7) ^ (y * 13)) as u8 % 32;
+            let alpha = if x < 10 && y < 10 { 0 } else { 255 };
+            pixel.copy_from_slice(&[(x * 3) as u8 + detail, (y * 5) as u8, ((x + y) * 2) as u8, alpha]);
+        });
+        sqp.set_compression(CompressionType::LossyDct, Some(80));
+        let varints = sqp.encode_to_vec().unwrap();
+
+        sqp.set_range_coding(true).unwrap();
+        let encoded = sqp.encode_to_vec().unwrap();
+
+        (sqp, encoded, varints)
+    }
+
+    #[test]
+    fn range_coded_round_trip() {
+        let (sqp, encoded, varints) = range_coded_images();
+        assert!(encoded.len() < varints.len(), "{} >= {}", encoded.len(), varints.len());
+
+        let expected = SquishyPicture::decode(varints.as_slice()).unwrap();
+        let low_memory = DecodeOptions::new().low_memory(true);
+        for decoded in [
+            SquishyPicture::decode(encoded.as_slice()).unwrap(),
+            SquishyPicture::decode_slice(&encoded).unwrap(),
+            SquishyPicture::decode_with_options(encoded.as_slice(), &low_memory).unwrap(),
+        ] {
+            assert!(decoded.range_coded());
+            assert_eq!(decoded.as_raw(), expected.as_raw());
+        }
+
+        for denominator in [2, 8] {
+            let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), denominator).unwrap();
+            let expected = SquishyPicture::decode_scaled(varints.as_slice(), denominator).unwrap();
+            assert_eq!(scaled.as_raw(), expected.as_raw());
+        }
+        let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+        assert_eq!(preview.as_raw(), SquishyPicture::decode_preview(varints.as_slice()).unwrap().as_raw());
+
+        // Every way of encoding gives the same file
+        let mut streamed = Vec::new();
+        sqp.encode_with_options(&mut streamed, &EncodeOptions::new().low_memory(true)).unwrap();
+        assert_eq!(streamed, encoded);
+
+        let mut reused = Vec::new();
+        crate::encoder::SqpEncoder::new(EncodeOptions::new()).encode(&sqp, &mut reused).unwrap();
+        assert_eq!(reused, encoded);
+
+        for len in [encoded.len() - 1, encoded.len() - 20] {
+            for options in [&DecodeOptions::new(), &low_memory] {
+                assert!(SquishyPicture::decode_with_options(&encoded[..len], options).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn range_coded_trailing_coefficients() {
+        let (sqp, _, _) = range_coded_images();
+
+        let mut stream = sqp.filtered_bitmap(&EncodeOptions::default()).into_owned();
+        stream.extend_from_slice(&[1, 2, 3]);
+
+        let (data, compression_info) = compress(&stream, Backend::from(&sqp.header)).unwrap();
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+
+        for low_memory in [false, true] {
+            assert_warnings(&encoded, low_memory, &[DecodeWarning::TrailingCoefficients { len: 3 }]);
+        }
+    }
+
+    #[test]
+    fn range_coding_header() {
+        let (mut sqp, _, _) = range_coded_images();
+        sqp.header.version = 1;
+        sqp.set_range_coding(true).unwrap();
+        assert_eq!(sqp.header.version, 2);
+
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(encoded[20], 0x02);
+        assert!(SquishyPicture::decode(encoded.as_slice()).unwrap().range_coded());
+
+        // Along with a transparent color
+        let mut keyed = keyed_image(16, 16);
+        keyed.set_compression(CompressionType::LossyDct, Some(90));
+        keyed.set_range_coding(true).unwrap();
+        let encoded = keyed.encode_to_vec().unwrap();
+        assert_eq!(encoded[20], 0x03);
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert!(decoded.range_coded());
+        assert_eq!(decoded.transparency(), keyed.transparency());
+
+        // Only lossy images can be range coded
+        sqp.set_compression(CompressionType::Lossless, None);
+        assert!(!sqp.range_coded());
+        assert!(matches!(
+            sqp.set_range_coding(true),
+            Err(Error::RangeCodingUnsupported(CompressionType::Lossless))
+        ));
+
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[20] = 0x02;
+        assert!(matches!(SquishyPicture::decode(encoded.as_slice()), Err(Error::InvalidFlags(0x02))));
+    }
+
+    /// A different matrix for each channel, from fine to coarse.
+    fn channel_matrices() -> [[u16; 64]; 4] {
+        let mut matrices = [[0; 64]; 4];
+        for (channel, matrix) in matrices.iter_mut().enumerate() {
+            *matrix = quantization_matrix([95, 60, 30, 5][channel]);
+        }
+        matrices
+    }
+
+    #[test]
+    fn quantization_matrices_round_trip() {
+        let (mut sqp, _, _) = range_coded_images();
+        let matrices = channel_matrices();
+
+        for range_coded in [false, true] {
+            sqp.set_range_coding(range_coded).unwrap();
+            sqp.set_quantization_matrices(Some(matrices)).unwrap();
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert_eq!(encoded[20] & 0x04, 0x04);
+
+            let low_memory = DecodeOptions::new().low_memory(true);
+            let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let streamed = SquishyPicture::decode_with_options(encoded.as_slice(), &low_memory).unwrap();
+            assert_eq!(decoded.quantization_matrices(), Some(matrices));
+            assert_eq!(streamed.as_raw(), decoded.as_raw());
+
+            // Each channel decodes the same as when every channel uses its
+            // matrix. Alpha keeps its own, as it decides which blocks of
+            // color are left out.
+            for (channel, matrix) in matrices.iter().enumerate() {
+                let mut uniform = [*matrix; 4];
+                uniform[3] = matrices[3];
+                sqp.set_quantization_matrices(Some(uniform)).unwrap();
+                let uniform = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+
+                let channel_of = |p: &SquishyPicture| -> Vec<u8> { p.as_raw().iter().skip(channel).step_by(4).copied().collect() };
+                assert_eq!(channel_of(&decoded), channel_of(&uniform), "channel {channel}");
+
+                let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+                let uniform_preview = SquishyPicture::decode_preview(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+                assert_eq!(channel_of(&preview), channel_of(&uniform_preview), "channel {channel}");
+            }
+        }
+
+        // Matrices which are all the one for the quality aren't stored
+        sqp.set_quantization_matrices(Some([quantization_matrix(80); 4])).unwrap();
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(encoded.len(), {
+            sqp.set_quantization_matrices(None).unwrap();
+            sqp.encode_to_vec().unwrap().len()
+        });
+        assert_eq!(SquishyPicture::decode(encoded.as_slice()).unwrap().quantization_matrices(), None);
+    }
+
+    #[test]
+    fn quantization_matrices_header() {
+        let mut sqp = keyed_image(16, 16);
+        sqp.set_compression(CompressionType::LossyDct, Some(90));
+        sqp.header.version = 2;
+        sqp.set_quantization_matrices(Some(channel_matrices())).unwrap();
+        assert_eq!(sqp.header.version, FIRST_DATA_OFFSET_VERSION);
+
+        // Only the matrices of the three channels are stored
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(encoded[20], 0x05);
+        assert_eq!(sqp.header.len(), 32 + 3 * 128);
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.transparency(), sqp.transparency());
+        assert_eq!(decoded.quantization_matrices().unwrap()[..3], channel_matrices()[..3]);
+
+        // A step of zero can't be set or decoded
+        let mut zero = channel_matrices();
+        zero[2][5] = 0;
+        assert!(matches!(sqp.set_quantization_matrices(Some(zero)), Err(Error::InvalidQuantizationMatrix(2))));
+
+        let mut corrupt = encoded.clone();
+        let step = 32 + 2 * 128 + 5 * 2;
+        corrupt[step..step + 2].copy_from_slice(&[0, 0]);
+        assert!(matches!(SquishyPicture::decode(corrupt.as_slice()), Err(Error::InvalidQuantizationMatrix(2))));
+
+        // Changing the compression or format drops them
+        let mut gray = sqp.convert_color_format(ColorFormat::GrayA8);
+        assert_eq!(gray.quantization_matrices(), None);
+        gray.set_quantization_matrices(Some(channel_matrices())).unwrap();
+        gray.set_compression(CompressionType::LossyDct, Some(50));
+        assert_eq!(gray.quantization_matrices(), None);
+
+        // Only lossy images can have them
+        sqp.set_compression(CompressionType::Lossless, None);
+        assert!(matches!(
+            sqp.set_quantization_matrices(Some(channel_matrices())),
+            Err(Error::QuantizationMatricesUnsupported(CompressionType::Lossless))
+        ));
+
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[20] = 0x04;
+        assert!(matches!(SquishyPicture::decode(encoded.as_slice()), Err(Error::InvalidFlags(0x04))));
+    }
+
+    /// A lossy image whose sides aren't multiples of 8 or of the tiles,
+    /// encoded untiled and with tiles of `tile_size`.
+    fn tiled_images(tile_size: u16, range_coded: bool) -> (SquishyPicture, Vec<u8>, Vec<u8>) {
+        let mut sqp = SquishyPicture::from_fn(83, 61, ColorFormat::Rgba8, |x, y, pixel| {
+            let detail = ((x * 7) ^ (y * 13)) as u8 % 32;
+            pixel.copy_from_slice(&[(x * 2) as u8 + detail, (y * 4) as u8, ((x + y) * 2) as u8, 255 - detail]);
+        });
+        sqp.set_compression(CompressionType::LossyDct, Some(80));
+        sqp.set_range_coding(range_coded).unwrap();
+        let untiled = sqp.encode_to_vec().unwrap();
+
+        sqp.set_tile_size(Some(tile_size)).unwrap();
+        let tiled = sqp.encode_to_vec().unwrap();
+
+        (sqp, tiled, untiled)
+    }
+
+    #[test]
+    fn tiled_round_trip() {
+        // Many tiles cut off by the edges, and a single tile
+        for (tile_size, range_coded) in [(32, false), (32, true), (24, false), (256, true)] {
+            let (sqp, tiled, untiled) = tiled_images(tile_size, range_coded);
+            assert_eq!(tiled[20] & 0x08, 0x08);
+
+            let expected = SquishyPicture::decode(untiled.as_slice()).unwrap();
+            let low_memory = DecodeOptions::new().low_memory(true);
+            for decoded in [
+                SquishyPicture::decode(tiled.as_slice()).unwrap(),
+                SquishyPicture::decode_slice(&tiled).unwrap(),
+                SquishyPicture::decode_with_options(tiled.as_slice(), &low_memory).unwrap(),
+            ] {
+                assert_eq!(decoded.tile_size(), Some(tile_size));
+                assert_eq!(decoded.as_raw(), expected.as_raw(), "{tile_size}");
+            }
+
+            for denominator in [2, 8] {
+                let scaled = SquishyPicture::decode_scaled(tiled.as_slice(), denominator).unwrap();
+                let expected = SquishyPicture::decode_scaled(untiled.as_slice(), denominator).unwrap();
+                assert_eq!(scaled.as_raw(), expected.as_raw());
+            }
+            let preview = SquishyPicture::decode_preview(tiled.as_slice()).unwrap();
+            assert_eq!(preview.as_raw(), SquishyPicture::decode_preview(untiled.as_slice()).unwrap().as_raw());
+
+            // Every way of encoding gives the same file
+            let mut streamed = Vec::new();
+            sqp.encode_with_options(&mut streamed, &EncodeOptions::new().low_memory(true)).unwrap();
+            assert_eq!(streamed, tiled);
+
+            let mut reused = Vec::new();
+            crate::encoder::SqpEncoder::new(EncodeOptions::new()).encode(&sqp, &mut reused).unwrap();
+            assert_eq!(reused, tiled);
+
+            for len in [tiled.len() - 1, tiled.len() - 20] {
+                for options in [&DecodeOptions::new(), &low_memory] {
+                    assert!(SquishyPicture::decode_with_options(&tiled[..len], options).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_region_matches_full() {
+        let (mut sqp, tiled, untiled) = tiled_images(32, true);
+        let expected = SquishyPicture::decode(untiled.as_slice()).unwrap();
+
+        sqp.set_compression(CompressionType::Lossless, None);
+        let lossless = sqp.encode_to_vec().unwrap();
+
+        let regions = [(0, 0, 83, 61), (0, 0, 1, 1), (82, 60, 1, 1), (30, 20, 5, 30), (31, 31, 2, 2), (7, 9, 70, 40)];
+        for (x, y, width, height) in regions {
+            let crop = |picture: &SquishyPicture| -> Vec<u8> {
+                (y..y + height).flat_map(|row| {
+                    let start = ((row * 83 + x) * 4) as usize;
+                    picture.as_raw()[start..start + width as usize * 4].to_vec()
+                }).collect()
+            };
+
+            for (encoded, full) in [(&tiled, &expected), (&untiled, &expected), (&lossless, &sqp)] {
+                let region = SquishyPicture::decode_region(encoded.as_slice(), x, y, width, height).unwrap();
+                assert_eq!((region.width(), region.height()), (width, height));
+                assert_eq!(*region.as_raw(), crop(full), "{width}x{height} at ({x}, {y})");
+            }
+        }
+
+        for (x, y, width, height) in [(0, 0, 0, 1), (0, 0, 84, 1), (83, 0, 1, 1), (10, 50, 5, 12), (u32::MAX, 0, 2, 1)] {
+            assert!(matches!(
+                SquishyPicture::decode_region(tiled.as_slice(), x, y, width, height),
+                Err(Error::InvalidRegion { .. })
+            ));
+        }
+
+        // Gray4 packs two pixels into a byte
+        let gray4 = SquishyPicture::from_fn(9, 5, ColorFormat::Gray4, |x, y, pixel| pixel[0] = (x * 30 + y * 7) as u8)
+            .convert_color_format(ColorFormat::Gray4);
+        let region = SquishyPicture::decode_region(gray4.encode_to_vec().unwrap().as_slice(), 3, 1, 5, 3).unwrap();
+        let expected = SquishyPicture::from_fn(5, 3, ColorFormat::Gray4, |x, y, pixel| pixel[0] = ((x + 3) * 30 + (y + 1) * 7) as u8);
+        assert_eq!(region.as_raw(), expected.as_raw());
+    }
+
+    #[test]
+    fn decode_channel_matches_full() {
+        let (mut sqp, tiled, untiled) = tiled_images(32, false);
+        let (_, tiled_range_coded, _) = tiled_images(16, true);
+        let lossy = SquishyPicture::decode(untiled.as_slice()).unwrap();
+
+        sqp.set_compression(CompressionType::Lossless, None);
+        let lossless = sqp.encode_to_vec().unwrap();
+        sqp.set_max_error(3);
+        let predictive = sqp.encode_to_vec().unwrap();
+        let near_lossless = SquishyPicture::decode(predictive.as_slice()).unwrap();
+
+        let images = [(&untiled, &lossy), (&tiled, &lossy), (&tiled_range_coded, &lossy), (&lossless, &sqp), (&predictive, &near_lossless)];
+        for (encoded, full) in images {
+            for channel in 0..4 {
+                let (header, plane) = SquishyPicture::decode_channel(encoded.as_slice(), channel).unwrap();
+                assert_eq!((header.width, header.height), (83, 61));
+
+                let expected: Vec<u8> = full.as_raw().iter().skip(channel).step_by(4).copied().collect();
+                assert!(plane == expected, "{:?} channel {channel}", header.compression_type);
+            }
+
+            assert!(matches!(
+                SquishyPicture::decode_channel(encoded.as_slice(), 4),
+                Err(Error::InvalidChannel { channel: 4, channels: 4 })
+            ));
+        }
+
+        // Gray4 is unpacked
+        let gray4 = SquishyPicture::from_fn(9, 5, ColorFormat::Gray4, |x, y, pixel| pixel[0] = (x * 30 + y * 7) as u8)
+            .convert_color_format(ColorFormat::Gray4);
+        let (_, plane) = SquishyPicture::decode_channel(gray4.encode_to_vec().unwrap().as_slice(), 0).unwrap();
+        assert_eq!(plane, *gray4.unpacked().unwrap().as_raw());
+    }
+
+    #[test]
+    fn decode_entry_points_take_limits() {
+        // Wider than the default limits allow
+        let mut sqp = SquishyPicture::from_fn(20000, 9, ColorFormat::Gray8, |x, y, pixel| pixel[0] = (x / 50 + y * 9) as u8);
+        let lossless = sqp.encode_to_vec().unwrap();
+        sqp.set_compression(CompressionType::LossyDct, Some(80));
+        let lossy = sqp.encode_to_vec().unwrap();
+
+        let raised = Limits { max_image_width: 20000, ..Limits::default() };
+        let lowered = Limits { max_image_height: 8, ..raised };
+        let exceeded = |result: Result<_, Error>, expected| match result {
+            Err(Error::LimitExceeded { name, .. }) => assert_eq!(name, expected),
+            Err(e) => panic!("expected {expected} to be exceeded, got {e}"),
+            Ok(_) => panic!("expected {expected} to be exceeded"),
+        };
+
+        for encoded in [&lossless, &lossy] {
+            exceeded(SquishyPicture::decode_slice(encoded).map(drop), "image width");
+            let decoded = SquishyPicture::decode_slice_with_limits(encoded, raised).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (20000, 9));
+            exceeded(SquishyPicture::decode_slice_with_limits(encoded, lowered).map(drop), "image height");
+
+            exceeded(SquishyPicture::decode_scaled(encoded.as_slice(), 2).map(drop), "image width");
+            assert_eq!(SquishyPicture::decode_scaled_with_limits(encoded.as_slice(), 2, raised).unwrap().width(), 10000);
+            exceeded(SquishyPicture::decode_scaled_with_limits(encoded.as_slice(), 2, lowered).map(drop), "image height");
+
+            exceeded(SquishyPicture::decode_preview(encoded.as_slice()).map(drop), "image width");
+            assert_eq!(SquishyPicture::decode_preview_with_limits(encoded.as_slice(), raised).unwrap().width(), 2500);
+
+            exceeded(SquishyPicture::decode_channel(encoded.as_slice(), 0).map(drop), "image width");
+            let (_, plane) = SquishyPicture::decode_channel_with_limits(encoded.as_slice(), 0, raised).unwrap();
+            assert_eq!(plane, *decoded.as_raw());
+
+            exceeded(SquishyPicture::decode_region(encoded.as_slice(), 19000, 0, 1000, 9).map(drop), "image width");
+            let region = SquishyPicture::decode_region_with_limits(encoded.as_slice(), 19000, 0, 1000, 9, raised).unwrap();
+            assert!(region.as_raw()[..1000] == decoded.as_raw()[19000..20000]);
+            exceeded(SquishyPicture::decode_region_with_limits(encoded.as_slice(), 0, 0, 8, 8, lowered).map(drop), "image height");
+        }
+
+        exceeded(crate::analysis::stored_dct_blocks(lossy.as_slice()).map(drop), "image width");
+        assert!(crate::analysis::stored_dct_blocks_with_limits(lossy.as_slice(), raised).is_ok());
+
+        #[cfg(feature = "content-hash")]
+        {
+            sqp.set_compression(CompressionType::Lossless, None);
+            let mut hashed = Vec::new();
+            sqp.encode_with_options(&mut hashed, &EncodeOptions::new().content_hash(true)).unwrap();
+
+            exceeded(SquishyPicture::verify_content(hashed.as_slice()).map(drop), "image width");
+            let check = SquishyPicture::verify_content_with_limits(hashed.as_slice(), raised).unwrap();
+            assert_eq!(check, ContentCheck::Match);
+        }
+    }
+
+    #[test]
+    fn tiling_header() {
+        // Only images larger than a tile are tiled by default
+        let large = SquishyPicture::from_raw_lossy(257, 10, ColorFormat::Gray8, 80, vec![0; 257 * 10]);
+        assert_eq!(large.tile_size(), Some(crate::header::DEFAULT_TILE_SIZE));
+        let small = SquishyPicture::from_raw_lossy(256, 256, ColorFormat::Gray8, 80, vec![0; 256 * 256]);
+        assert_eq!(small.tile_size(), None);
+
+        let (mut sqp, _, _) = tiled_images(32, false);
+        sqp.header.version = 4;
+        sqp.set_tile_size(Some(64)).unwrap();
+        assert_eq!(sqp.header.version, FIRST_TILED_VERSION);
+
+        let encoded = sqp.encode_to_vec().unwrap();
+        assert_eq!(encoded[20], 0x08);
+        assert_eq!(encoded[29..31], 64u16.to_le_bytes());
+        assert_eq!(sqp.header.len(), 31);
+
+        // Along with a transparent color and quantization matrices
+        let mut keyed = keyed_image(40, 24);
+        keyed.set_compression(CompressionType::LossyDct, Some(90));
+        keyed.set_tile_size(Some(16)).unwrap();
+        keyed.set_quantization_matrices(Some(channel_matrices())).unwrap();
+        let encoded = keyed.encode_to_vec().unwrap();
+        assert_eq!(encoded[20], 0x0D);
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.tile_size(), Some(16));
+        assert_eq!(decoded.transparency(), keyed.transparency());
+        assert_eq!(decoded.quantization_matrices().unwrap()[..3], channel_matrices()[..3]);
+
+        for size in [0, 12, 65535] {
+            assert!(matches!(sqp.set_tile_size(Some(size)), Err(Error::InvalidTileSize(s)) if s == size));
+        }
+
+        let mut corrupt = sqp.encode_to_vec().unwrap();
+        corrupt[29..31].copy_from_slice(&20u16.to_le_bytes());
+        assert!(matches!(SquishyPicture::decode(corrupt.as_slice()), Err(Error::InvalidTileSize(20))));
+
+        // A tile's length running past the end of the data
+        let mut stream = sqp.filtered_bitmap(&EncodeOptions::default()).into_owned();
+        stream[COEFFICIENT_COUNT_LEN + TILE_LEN_SIZE + 2] = 0x7F;
+        let (data, compression_info) = compress(&stream, Backend::from(&sqp.header)).unwrap();
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+        for options in [DecodeOptions::new(), DecodeOptions::new().low_memory(true)] {
+            assert!(matches!(
+                SquishyPicture::decode_with_options(encoded.as_slice(), &options),
+                Err(Error::TileCoefficientsMissing { tile: 1 })
+            ));
+        }
+
+        // Older versions and other compression types can't be tiled
+        let mut old = sqp.encode_to_vec().unwrap();
+        old[19] = FIRST_TILED_VERSION - 1;
+        assert!(matches!(SquishyPicture::decode(old.as_slice()), Err(Error::InvalidFlags(0x08))));
+
+        sqp.set_compression(CompressionType::Lossless, None);
+        assert_eq!(sqp.tile_size(), None);
+        assert!(matches!(
+            sqp.set_tile_size(Some(64)),
+            Err(Error::TilingUnsupported(CompressionType::Lossless))
+        ));
+
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[20] = 0x08;
+        assert!(matches!(SquishyPicture::decode(encoded.as_slice()), Err(Error::InvalidFlags(0x08))));
+    }
+
+    fn row_filtered_image(color_format: ColorFormat, compression_type: CompressionType) -> SquishyPicture {
+        let mut sqp = SquishyPicture::from_fn(37, 21, color_format, |x, y, pixel| {
+            for (c, value) in pixel.iter_mut().enumerate() {
+                *value = (x * 5 + y * 3 + c as u32 * 40) as u8 ^ ((x * y) as u8 & 0x1F);
+            }
+        });
+        sqp.set_compression(compression_type, None);
+        sqp
+    }
+
+    #[test]
+    fn row_filters_round_trip() {
+        let strategies = crate::options::Filter::ALL.map(FilterStrategy::Fixed)
+            .into_iter()
+            .chain([FilterStrategy::MinSumAbs, FilterStrategy::TryAllMeasureCompressed]);
+        for strategy in strategies {
+            let options = EncodeOptions::new().filter_strategy(strategy);
+            for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8, ColorFormat::Gray4] {
+                for compression_type in [CompressionType::Lossless, CompressionType::LosslessV2, CompressionType::LosslessBwt] {
+                    let sqp = row_filtered_image(color_format, compression_type);
+
+                    let mut encoded = Vec::new();
+                    sqp.encode_with_options(&mut encoded, &options).unwrap();
+                    let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+                    assert!(decoded.header.row_filters);
+                    assert_eq!(decoded.as_raw(), sqp.as_raw(), "{strategy:?} {color_format:?} {compression_type:?}");
+
+                    // Every way of encoding chooses the same filters
+                    let mut reused = Vec::new();
+                    crate::encoder::SqpEncoder::new(options.clone()).encode(&sqp, &mut reused).unwrap();
+                    assert_eq!(reused, encoded);
+
+                    let mut low_memory = Vec::new();
+                    sqp.encode_with_options(&mut low_memory, &options.clone().low_memory(true)).unwrap();
+                    assert_eq!(low_memory, encoded);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn row_filters_header() {
+        let mut sqp = row_filtered_image(ColorFormat::Rgba8, CompressionType::Lossless);
+        let options = EncodeOptions::new().filter_strategy(FilterStrategy::MinSumAbs);
+
+        // Only stored when asked for, upgrading older versions
+        assert_eq!(sqp.encode_to_vec().unwrap()[20], 0x00);
+        sqp.header.version = 4;
+        let mut encoded = Vec::new();
+        sqp.encode_with_options(&mut encoded, &options).unwrap();
+        assert_eq!(encoded[19], FIRST_ROW_FILTER_VERSION);
+        assert_eq!(encoded[20], 0x10);
+
+        // Decoded images keep choosing filters
+        let decoded = SquishyPicture::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.encode_to_vec().unwrap()[20], 0x10);
+
+        // Lossy and predictive images aren't filtered by rows
+        for (compression_type, quality) in [(CompressionType::LossyDct, Some(80)), (CompressionType::Predictive, Some(0))] {
+            sqp.set_compression(compression_type, quality);
+            let mut encoded = Vec::new();
+            sqp.encode_with_options(&mut encoded, &options).unwrap();
+            assert_eq!(encoded[20] & 0x10, 0);
+        }
+
+        let mut lossy = test_image(CompressionType::LossyDct, Some(80));
+        lossy[20] = 0x10;
+        assert!(matches!(SquishyPicture::decode(lossy.as_slice()), Err(Error::InvalidFlags(0x10))));
+
+        let mut old = encoded.clone();
+        old[19] = FIRST_ROW_FILTER_VERSION - 1;
+        assert!(matches!(SquishyPicture::decode(old.as_slice()), Err(Error::InvalidFlags(0x10))));
+
+        // A filter ID which doesn't exist
+        let sqp = decoded;
+        let mut stream = sqp.filtered_bitmap(&EncodeOptions::default()).into_owned();
+        stream[3] = 5;
+        let (data, compression_info) = compress(&stream, Backend::from(&sqp.header)).unwrap();
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+        assert!(matches!(
+            SquishyPicture::decode(encoded.as_slice()),
+            Err(Error::InvalidRowFilter { row: 3, filter: 5 })
+        ));
+    }
+
+    #[test]
+    fn map_pixels_visits_every_pixel() {
+        for (width, height) in [(0, 0), (0, 3), (1, 1), (7, 5), (33, 2)] {
+            for color_format in [ColorFormat::Rgba8, ColorFormat::Rgb8, ColorFormat::GrayA8, ColorFormat::Gray8, ColorFormat::Gray4] {
+                let mut sqp = SquishyPicture::from_fn(width, height, color_format, |_, _, _| ());
+                let expected_len = if color_format == ColorFormat::Gray4 { 1 } else { color_format.pbc() };
+
+                let mut calls = 0;
+                sqp.map_pixels_in_place(|pixel| {
+                    assert_eq!(pixel.len(), expected_len);
+                    calls += 1;
+                });
+                assert_eq!(calls, width * height);
+
+                let mut positions = Vec::new();
+                sqp.map_pixels_with_position(|x, y, _| positions.push((x, y)));
+                let expected: Vec<_> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).collect();
+                assert_eq!(positions, expected);
+
+                #[cfg(feature = "parallel")]
+                {
+                    let calls = core::sync::atomic::AtomicU32::new(0);
+                    sqp.par_map_pixels_in_place(|_| {
+                        calls.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                    });
+                    assert_eq!(calls.into_inner(), width * height);
+
+                    // Write each position into the pixel, to check it lines up
+                    let mut sequential = SquishyPicture::from_fn(width, height, color_format, |_, _, _| ());
+                    sequential.map_pixels_with_position(|x, y, pixel| pixel.fill((x * 16 + y) as u8));
+                    sqp.par_map_pixels_with_position(|x, y, pixel| pixel.fill((x * 16 + y) as u8));
+                    assert_eq!(sqp.as_raw(), sequential.as_raw());
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn map_pixels_channel_swap_round_trip() {
+        let dir = std::env::temp_dir().join(format!("sqp-map-pixels-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for color_format in [ColorFormat::Rgb8, ColorFormat::Rgba8] {
+            let original = SquishyPicture::from_fn(13, 7, color_format, |x, y, pixel| {
+                for (c, value) in pixel.iter_mut().enumerate() {
+                    *value = (x * 19 + y * 7 + c as u32 * 50) as u8;
+                }
+            });
+
+            let mut swapped = SquishyPicture::from_raw_lossless(13, 7, color_format, original.as_raw().clone());
+            swapped.map_pixels_in_place(|pixel| pixel.swap(0, 2));
+
+            let path = dir.join(format!("{color_format:?}.sqp"));
+            swapped.save(&path).unwrap();
+            let mut opened = open(&path).unwrap();
+            assert_eq!(opened.as_raw(), swapped.as_raw());
+
+            // Swapping back gives the original, and alpha is untouched
+            opened.map_pixels_in_place(|pixel| pixel.swap(0, 2));
+            assert_eq!(opened.as_raw(), original.as_raw());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gray4_round_trip() {
+        for width in [1, 2, 3, 7, 8, 17] {
+            let gray = SquishyPicture::from_fn(width, 5, ColorFormat::Gray8, |x, y, pixel| {
+                pixel[0] = ((x * 31 + y * 57) % 16 * 17) as u8
+            });
+            let packed = gray.convert_color_format(ColorFormat::Gray4);
+            assert_eq!(packed.as_raw().len(), width.div_ceil(2) as usize * 5);
+
+            for compression_type in [CompressionType::None, CompressionType::Lossless] {
+                let mut sqp = packed.convert_color_format(ColorFormat::Gray4);
+                sqp.set_compression(compression_type, None);
+
+                let decoded = SquishyPicture::decode(sqp.encode_to_vec().unwrap().as_slice()).unwrap();
+                assert_eq!(decoded.color_format(), ColorFormat::Gray4);
+                assert_eq!(decoded.as_raw(), packed.as_raw(), "{width} wide, {compression_type:?}");
+
+                // Every value was already a level, so nothing is lost
+                assert_eq!(decoded.convert_color_format(ColorFormat::Gray8).as_raw(), gray.as_raw());
+            }
+        }
+    }
+
+    #[test]
+    fn to_rgba8() {
+        let cases = [
+            (ColorFormat::Rgba8, 1, 1, vec![1, 2, 3, 4], vec![1, 2, 3, 4]),
+            (ColorFormat::Rgb8, 1, 1, vec![1, 2, 3], vec![1, 2, 3, 255]),
+            (ColorFormat::GrayA8, 1, 1, vec![9, 40], vec![9, 9, 9, 40]),
+            (ColorFormat::Gray8, 1, 1, vec![9], vec![9, 9, 9, 255]),
+            (ColorFormat::Gray4, 1, 1, vec![0xA0], vec![170, 170, 170, 255]),
+            (ColorFormat::Rgb8, 2, 2, vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3], vec![
+                0, 0, 0, 255, 1, 1, 1, 255,
+                2, 2, 2, 255, 3, 3, 3, 255,
+            ]),
+            // Each row of three ends in half a byte of padding
+            (ColorFormat::Gray4, 3, 2, vec![0x0F, 0x10, 0x34, 0x50], vec![
+                0, 0, 0, 255, 255, 255, 255, 255, 17, 17, 17, 255,
+                51, 51, 51, 255, 68, 68, 68, 255, 85, 85, 85, 255,
+            ]),
+        ];
+
+        for (color_format, width, height, bitmap, expected) in cases {
+            let sqp = SquishyPicture::from_raw_lossless(width, height, color_format, bitmap.clone());
+            assert_eq!(sqp.to_rgba8(), expected, "{color_format:?}");
+            assert_eq!(sqp.to_rgba8(), *sqp.convert_color_format(ColorFormat::Rgba8).as_raw());
+
+            let encoded = sqp.encode_to_vec().unwrap();
+            assert_eq!(crate::decode_to_rgba8(encoded.as_slice()).unwrap(), (width, height, expected.clone()));
+
+            // Packing back down gives the same pixels
+            let packed = SquishyPicture::from_rgba8_with_format(
+                width,
+                height,
+                color_format,
+                CompressionType::Lossless,
+                None,
+                expected,
+            );
+            assert_eq!(*packed.as_raw(), bitmap, "{color_format:?}");
+        }
+
+        // Pixels of the transparent color get an alpha of 0
+        let keyed = keyed_image(3, 1);
+        assert_eq!(keyed.to_rgba8(), *keyed.convert_color_format(ColorFormat::Rgba8).as_raw());
+        assert_eq!(keyed.to_rgba8().iter().skip(3).step_by(4).collect::<Vec<_>>(), [&0, &255, &255]);
+    }
+
+    #[test]
+    fn gray4_conversion() {
+        let rgb = SquishyPicture::from_raw_lossless(3, 1, ColorFormat::Rgb8, vec![255, 255, 255, 0, 0, 0, 100, 100, 100]);
+
+        let packed = rgb.convert_color_format(ColorFormat::Gray4);
+        assert_eq!(packed.as_raw(), &[0xF0, 0x60]);
+
+        let rgba = packed.convert_color_format(ColorFormat::Rgba8);
+        assert_eq!(rgba.as_raw(), &[255, 255, 255, 255, 0, 0, 0, 255, 102, 102, 102, 255]);
+
+        // from_fn writes whole bytes, which are then rounded
+        let sqp = SquishyPicture::from_fn(3, 1, ColorFormat::Gray4, |x, _, pixel| pixel[0] = x as u8 * 100);
+        assert_eq!(sqp.as_raw(), &[0x06, 0xC0]);
+
+        assert_eq!(sqp.histogram().channel(0)[102], 1);
+        assert_eq!(sqp.luminance_histogram().channel(0)[204], 1);
+        assert_eq!(ColorFormat::Gray4.pbc(), 1);
+        assert_eq!(ColorFormat::Gray4.row_len(7), 4);
+    }
+
+    #[test]
+    fn gray4_lossy_unsupported() {
+        let sqp = SquishyPicture::from_raw_lossy(4, 4, ColorFormat::Gray4, 80, vec![0; 8]);
+        assert!(matches!(
+            sqp.encode_to_vec(),
+            Err(Error::LossyUnsupported(ColorFormat::Gray4))
+        ));
+
+        // A lossy header claiming to be packed
+        let mut encoded = SquishyPicture::from_raw_lossy(4, 4, ColorFormat::Gray8, 80, vec![0; 16])
+            .encode_to_vec()
+            .unwrap();
+        encoded[18] = ColorFormat::Gray4 as u8;
+        assert!(matches!(
+            SquishyPicture::decode(encoded.as_slice()),
+            Err(Error::LossyUnsupported(ColorFormat::Gray4))
+        ));
+
+        // Predictive images are only lossy with a `near` above 0
+        let mut sqp = SquishyPicture::from_raw(4, 4, ColorFormat::Gray4, CompressionType::Predictive, Some(2), vec![0; 8]);
+        assert!(matches!(
+            sqp.encode_to_vec(),
+            Err(Error::LossyUnsupported(ColorFormat::Gray4))
+        ));
+        sqp.set_compression(CompressionType::Predictive, None);
+        let decoded = SquishyPicture::decode_slice(&sqp.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded.near(), Some(0));
+    }
+
+    #[test]
+    fn decode_scaled_full_size() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let encoded = test_image(compression_type, quality);
+
+            let full = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), 1).unwrap();
+            assert_eq!((scaled.width(), scaled.height()), (full.width(), full.height()));
+            assert_eq!(scaled.as_raw(), full.as_raw());
+        }
+    }
+
+    #[test]
+    fn decode_scaled_lossless_is_box_filtered() {
+        let encoded = test_image(CompressionType::Lossless, None);
+        let full = SquishyPicture::decode(encoded.as_slice()).unwrap();
+
+        for denominator in [2, 4, 8] {
+            let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), denominator).unwrap();
+
+            assert_eq!((scaled.width(), scaled.height()), (9u32.div_ceil(denominator as u32), 7u32.div_ceil(denominator as u32)));
+            assert_eq!(scaled.compression_type(), CompressionType::Lossless);
+            assert_eq!(scaled.as_raw(), &downscale(9, 7, ColorFormat::Rgba8, denominator as u32, full.as_raw()));
+        }
+    }
+
+    #[test]
+    fn decode_scaled_lossy() {
+        // A smooth image, with sizes which leave partial blocks at the edges
+        let (width, height) = (67, 45);
+        let mut sqp = SquishyPicture::from_fn(width, height, ColorFormat::Rgb8, |x, y, pixel| {
+            pixel.copy_from_slice(&[(x * 3) as u8, (y * 5) as u8, ((x + y) * 2) as u8]);
+        });
+        sqp.set_compression(CompressionType::LossyDct, Some(80));
+        let encoded = sqp.encode_to_vec().unwrap();
+        let full = SquishyPicture::decode(encoded.as_slice()).unwrap();
+
+        for (denominator, size) in [(2, (34, 23)), (4, (17, 12)), (8, (9, 6))] {
+            let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), denominator).unwrap();
+            assert_eq!((scaled.width(), scaled.height()), size);
+            assert_eq!(scaled.quality(), Some(80));
+            assert_eq!(scaled.as_raw().len(), (size.0 * size.1 * 3) as usize);
+
+            let resized = downscale(width, height, ColorFormat::Rgb8, denominator as u32, full.as_raw());
+            let psnr = psnr(&resized, scaled.as_raw());
+            assert!(psnr > 30.0, "1/{denominator} scale has a PSNR of {psnr}");
+        }
+    }
+
+    #[test]
+    fn decode_scaled_invalid() {
+        let encoded = test_image(CompressionType::LossyDct, Some(80));
+
+        for denominator in [0, 3, 16] {
+            assert!(matches!(
+                SquishyPicture::decode_scaled(encoded.as_slice(), denominator),
+                Err(Error::InvalidScale(d)) if d == denominator
+            ));
+        }
+
+        for len in 0..encoded.len() {
+            assert!(SquishyPicture::decode_scaled(&encoded[..len], 2).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_preview_solid_color() {
+        let color = [200, 30, 90, 255];
+
+        // Edge blocks with only a few visible pixels magnify the error of
+        // their quantized DC coefficient, so only test those at higher
+        // qualities
+        let cases = [
+            (8, 8, &[10, 50, 80, 100][..]),
+            (64, 64, &[10, 50, 80, 100]),
+            (20, 12, &[80, 100]),
+            (67, 45, &[80, 100]),
+        ];
+
+        for (width, height, qualities) in cases {
+            for &quality in qualities {
+                let mut sqp = SquishyPicture::from_fn(width, height, ColorFormat::Rgba8, |_, _, pixel| {
+                    pixel.copy_from_slice(&color);
+                });
+                sqp.set_compression(CompressionType::LossyDct, Some(quality));
+                let encoded = sqp.encode_to_vec().unwrap();
+
+                let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+                assert_eq!(preview.color_format(), ColorFormat::Rgba8);
+
+                for pixel in preview.as_raw().chunks_exact(4) {
+                    for (value, expected) in pixel.iter().zip(color) {
+                        assert!(value.abs_diff(expected) <= 2, "{width}x{height} at quality {quality}: {pixel:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_preview_dimensions() {
+        for (width, height, size) in [(1, 1, (1, 1)), (8, 8, (1, 1)), (9, 7, (2, 1)), (64, 64, (8, 8)), (20, 12, (3, 2)), (67, 45, (9, 6))] {
+            let sqp = SquishyPicture::from_raw_lossy(width, height, ColorFormat::GrayA8, 80, vec![60; (width * height * 2) as usize]);
+            let encoded = sqp.encode_to_vec().unwrap();
+
+            let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+            assert_eq!((preview.width(), preview.height()), size);
+            assert_eq!(preview.as_raw().len(), (size.0 * size.1 * 2) as usize);
+        }
+    }
+
+    #[test]
+    fn decode_preview_lossless_is_box_filtered() {
+        for compression_type in [CompressionType::None, CompressionType::Lossless] {
+            let encoded = test_image(compression_type, None);
+
+            let preview = SquishyPicture::decode_preview(encoded.as_slice()).unwrap();
+            let scaled = SquishyPicture::decode_scaled(encoded.as_slice(), 8).unwrap();
+            assert_eq!((preview.width(), preview.height()), (2, 1));
+            assert_eq!(preview.as_raw(), scaled.as_raw());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn open_mmap_matches_open() {
+        for path in ["test_images/test-lossless.sqp", "test_images/test-lossy.sqp"] {
+            assert_eq!(open_mmap(path).unwrap().as_raw(), open(path).unwrap().as_raw());
+        }
+
+        let limits = Limits { max_image_width: 1000, ..Limits::default() };
+        assert!(matches!(
+            open_mmap_with_limits("test_images/test-lossless.sqp", limits),
+            Err(Error::LimitExceeded { name: "image width", .. })
+        ));
+
+        // Raised limits let wider images through
+        let wide = SquishyPicture::from_raw_lossless(20000, 2, ColorFormat::Gray8, vec![90; 40000]);
+        let path = std::env::temp_dir().join(format!("sqp-mmap-limits-{}.sqp", std::process::id()));
+        wide.save(&path).unwrap();
+        let default = open_mmap(&path);
+        let raised = open_mmap_with_limits(&path, Limits { max_image_width: 20000, ..Limits::default() });
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(default, Err(Error::LimitExceeded { name: "image width", .. })));
+        assert_eq!(raised.unwrap().as_raw(), wide.as_raw());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn open_reads_in_large_blocks() {
+        /// Counts the reads made of the file which ask for less than a
+        /// buffer's worth.
+        struct CountingReader<'a> {
+            inner: &'a [u8],
+            small_reads: usize,
+        }
+
+        impl std::io::Read for CountingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if buf.len() < 8 * 1024 {
+                    self.small_reads += 1;
+                }
+                self.inner.read(buf)
+            }
+        }
+
+        // Enough chunks and sections that reading them unbuffered would
+        // take many small reads
+        let bitmap = (0..512 * 512 * 4).map(|i: u32| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let data = SquishyPicture::from_raw_lossless(512, 512, ColorFormat::Rgba8, bitmap).encode_to_vec().unwrap();
+
+        let mut file = CountingReader { inner: &data, small_reads: 0 };
+        decode_buffered(&mut file, Some(data.len() as u64), Limits::default()).unwrap();
+        assert_eq!(file.small_reads, 0);
+    }
+
+    #[test]
+    fn error_messages_contain_values() {
+        let cases = [
+            (Error::InvalidIdentifier("dangoimh".into()), &["dangoimh"][..]),
+            (Error::TruncatedFile { section: FileSection::ChunkTable }, &["chunk table"]),
+            (Error::InvalidColorFormat(17), &["17"]),
+            (Error::InvalidCompressionType(42), &["42"]),
+            (Error::InvalidQuality(101), &["101"]),
+            (Error::InvalidDimensions { width: 1234, height: 0 }, &["1234", "0"]),
+            (Error::SizeMismatch { expected: 4096, actual: 1024 }, &["4096", "1024"]),
+            (Error::ChunkTableMismatch { claimed: 300, min: 252, max: 252 }, &["300", "252"]),
+            (Error::CoefficientCountMismatch { expected: 6144, actual: 6100 }, &["6144", "6100"]),
+            (Error::LimitExceeded { name: "pixel count", requested: 9000, limit: 8000 }, &["pixel count", "9000", "8000"]),
+            (Error::UnsupportedVersion(3), &["3"]),
+            (Error::InvalidScale(3), &["3"]),
+            (Error::InvalidFlags(0x42), &["0x42"]),
+            (Error::LossyUnsupported(ColorFormat::Gray4), &["Gray4"]),
+            (Error::TransparencyUnsupported(ColorFormat::GrayA8), &["GrayA8"]),
+            (Error::RangeCodingUnsupported(CompressionType::LosslessV2), &["LosslessV2"]),
+            (Error::CannotMeetSizeTarget { best: 5123 }, &["5123"]),
+            (Error::Strict(DecodeWarning::TrailingBytes { offset: 41, len: 7 }), &["41", "7"]),
+            (Error::UnknownCriticalSection(SectionType(*b"XTRA")), &["XTRA"]),
+            (Error::UnexpectedSection(SectionType::PIXEL_DATA), &["PIXD"]),
+            (Error::SectionSizeMismatch { section: SectionType::CHUNK_TABLE, expected: 12, actual: 13 }, &["CINF", "12", "13"]),
+            (Error::SectionTooLarge { section: SectionType::PIXEL_DATA, len: 5_000_000_000 }, &["PIXD", "5000000000"]),
+            (Error::DataOffsetMismatch { expected: 30, actual: 29 }, &["30", "29"]),
+            (Error::ChunkOffsetMismatch { chunk: 3, expected: 812, actual: 790 }, &["3", "812", "790"]),
+            (Error::InvalidChunkRange { start: 2, end: 9, chunk_count: 5 }, &["2", "9", "5"]),
+            (Error::MissingDictionary { id: 0x1234 }, &["0x0000000000001234"]),
+            (
+                Error::DictionaryMismatch { expected: 0xABC, actual: 0xDEF },
+                &["0x0000000000000abc", "0x0000000000000def"],
+            ),
+            (
+                Error::DimensionMismatch { width: 640, height: 480, other_width: 320, other_height: 240 },
+                &["640x480", "320x240"],
+            ),
+            (
+                Error::Strict(DecodeWarning::ContentHashMismatch { expected: 0xABC, actual: 0xDEF }),
+                &["0x0000000000000abc", "0x0000000000000def"],
+            ),
+        ];
+
+        for (error, values) in cases {
+            let message = error.to_string();
+            for value in values {
+                assert!(message.contains(value), "{message:?} does not contain {value:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn error_source_is_chained() {
+        use core::error::Error as _;
+
+        let error = Error::from(CompressionError::NoChunks);
+        assert_eq!(error.source().unwrap().to_string(), "no chunks compressed");
+    }
+
+    #[test]
+    fn decode_truncated_reports_section() {
+        let encoded = test_image(CompressionType::Lossless, None);
+
+        for (len, section) in [
+            (0, FileSection::Header),
+            (19, FileSection::Header),
+            (20, FileSection::Header),
+            (28, FileSection::Header),
+            (29, FileSection::Sections),
+            (36, FileSection::Sections),
+            (37, FileSection::ChunkTable),
+            (52, FileSection::ChunkTable),
+            (57, FileSection::Sections),
+            (64, FileSection::Sections),
+            (encoded.len() - FOOTER_LEN - 1, FileSection::ChunkData),
+            (encoded.len() - 1, FileSection::Footer),
+        ] {
+            for result in [
+                SquishyPicture::decode(&encoded[..len]),
+                SquishyPicture::decode_slice(&encoded[..len]),
+            ] {
+                match result {
+                    Err(Error::TruncatedFile { section: s }) => assert_eq!(s, section),
+                    other => panic!("expected a truncated {section}, got {:?}", other.err()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_invalid_dimensions() {
+        for compression_type in [
+            CompressionType::None,
+            CompressionType::Lossless,
+            CompressionType::LossyDct,
+            CompressionType::LosslessV2,
+            CompressionType::LosslessBwt,
+            CompressionType::Predictive,
+        ] {
+            let quality = match compression_type {
+                CompressionType::LossyDct => Some(80),
+                CompressionType::Predictive => Some(2),
+                _ => None,
+            };
+
+            for (width, height) in [(0, 8), (8, 0), (0, 0)] {
+                let sqp = SquishyPicture::from_raw(width, height, ColorFormat::Rgba8, compression_type, quality, vec![]);
+
+                for result in [
+                    sqp.encode_to_vec(),
+                    sqp.encode_with_options(Vec::new(), &EncodeOptions::new().low_memory(true)).map(|_| Vec::new()),
+                    sqp.encode_streaming(Vec::new()).map(|_| Vec::new()),
+                    sqp.encode_with_intermediates(Vec::new(), &EncodeOptions::new()).map(|_| Vec::new()),
+                ] {
+                    assert!(
+                        matches!(result, Err(Error::InvalidDimensions { width: w, height: h }) if (w, h) == (width, height)),
+                        "{compression_type:?} {width}x{height} gave {:?}", result.err(),
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_invalid_dimensions() {
+        let mut encoded = test_image(CompressionType::Lossless, None);
+        encoded[8..12].copy_from_slice(&0u32.to_le_bytes());
+
+        assert!(matches!(
+            SquishyPicture::decode(encoded.as_slice()),
+            Err(Error::InvalidDimensions { width: 0, height: 7 })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn decode_many_chunks_on_one_thread() {
+        let mut state = 0x2545F491u32;
+        let bitmap: Vec<u8> = (0..400 * 400 * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+
+        let encoded = SquishyPicture::from_raw_lossless(400, 400, ColorFormat::Rgba8, bitmap.clone())
+            .encode_to_vec()
+            .unwrap();
+
+        // The reading thread must help decompress instead of waiting on
+        // chunks which would never get a thread to run on
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let decoded = pool.install(|| SquishyPicture::decode(encoded.as_slice())).unwrap();
+
+        assert_eq!(decoded.as_raw(), &bitmap);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn same_output_on_any_threads() {
+        use alloc::sync::Arc;
+
+        use crate::options::Threads;
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let choices = [Threads::Global, Threads::Single, Threads::Pool(Arc::new(pool))];
+
+        let bitmap: Vec<u8> = (0..150 * 90 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        for (compression_type, quality, tile_size) in [
+            (CompressionType::Lossless, None, None),
+            (CompressionType::LossyDct, Some(80), None),
+            (CompressionType::LossyDct, Some(80), Some(32)),
+        ] {
+            let mut sqp = SquishyPicture::from_raw(150, 90, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+            sqp.set_tile_size(tile_size).unwrap();
+            let expected = sqp.encode_to_vec().unwrap();
+            let decoded = SquishyPicture::decode(expected.as_slice()).unwrap();
+
+            for threads in &choices {
+                for low_memory in [false, true] {
+                    let options = EncodeOptions::new().low_memory(low_memory).threads(threads.clone());
+                    let mut encoded = Vec::new();
+                    sqp.encode_with_options(&mut encoded, &options).unwrap();
+                    assert_eq!(encoded, expected);
+
+                    let options = DecodeOptions::new().low_memory(low_memory).threads(threads.clone());
+                    let result = SquishyPicture::decode_with_options(expected.as_slice(), &options).unwrap();
+                    assert_eq!(result.as_raw(), decoded.as_raw());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn coefficient_blocks() {
+        let mut stream = vec![0x02; 64];
+        stream.extend_from_slice(&[0x01, 0x01, 0x01]);
+
+        let mut blocks = CoefficientBlocks::new(&stream, false);
+        assert_eq!(blocks.next(), Some([1; 64]));
+        assert_eq!(blocks.next(), None);
+        assert_eq!(blocks.count, 67);
+    }
+
+    #[test]
+    fn decode_wrong_chunk_size() {
+        for compression_type in [CompressionType::None, CompressionType::Lossless] {
+            let encoded = test_image(compression_type, None);
+            let expected = 9 * 7 * 4;
+
+            // The raw size of the only chunk, after the header, the chunk
+            // table's section header and the count
+            let size_raw = 29 + 8 + 4 + 4;
+            assert_eq!(encoded[size_raw..size_raw + 4], (expected as u32).to_le_bytes());
+
+            for actual in [expected - 1, expected + 1] {
+                let mut encoded = encoded.clone();
+                encoded[size_raw..size_raw + 4].copy_from_slice(&(actual as u32).to_le_bytes());
+
+                for result in [
+                    SquishyPicture::decode(encoded.as_slice()),
+                    SquishyPicture::decode_slice(&encoded),
+                ] {
+                    match result {
+                        Err(Error::ChunkTableMismatch { claimed, min, max }) => {
+                            assert_eq!((claimed, min, max), (actual as u64, expected as u64, expected as u64));
+                        },
+                        other => panic!("expected a chunk table mismatch, got {:?}", other.err()),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_uncompressed_wrong_payload() {
+        let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, CompressionType::None, None, vec![0; 9 * 7 * 4]);
+
+        // One byte too few, and one row too many
+        for actual in [9 * 7 * 4 - 1, 9 * 8 * 4] {
+            let (data, compression_info) = compress(&vec![0x55; actual], Backend::from(&sqp.header)).unwrap();
+            let mut encoded = Vec::new();
+            sqp.header.write_into(&mut encoded).unwrap();
+            write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+            encoded.extend_from_slice(&data);
+            let image_len = encoded.len() as u64;
+            write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+
+            for result in [
+                SquishyPicture::decode(encoded.as_slice()),
+                SquishyPicture::decode_slice(&encoded),
+            ] {
+                assert!(matches!(result, Err(Error::ChunkTableMismatch { claimed, min: 252, max: 252 }) if claimed == actual as u64));
+            }
+        }
+    }
+
+    #[test]
+    fn encode_with_intermediates() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let bitmap = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+            let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, bitmap);
+
+            let mut encoded = Vec::new();
+            let (count, intermediates) = sqp.encode_with_intermediates(&mut encoded, &EncodeOptions::default()).unwrap();
+
+            assert_eq!(count, encoded.len());
+            assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+            assert_eq!(*intermediates.filtered, *sqp.filtered_bitmap(&EncodeOptions::default()));
+            assert_eq!(intermediates.compression_info.chunks.iter().map(|c| c.size_raw).sum::<usize>(), intermediates.filtered.len());
+        }
+    }
+
+    #[test]
+    fn encode_wrong_bitmap_size() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            for actual in [9 * 7 * 4 - 1, 9 * 8 * 4] {
+                let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, quality, vec![0; actual]);
+
+                let low_memory = EncodeOptions::new().low_memory(true);
+                for result in [
+                    sqp.encode(Vec::new()),
+                    sqp.encode_streaming(Vec::new()),
+                    sqp.encode_with_options(Vec::new(), &low_memory),
+                ] {
+                    assert!(matches!(result, Err(Error::SizeMismatch { expected: 252, actual: a }) if a == actual));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_decompressed_wrong_size() {
+        for compression_type in [CompressionType::None, CompressionType::Lossless] {
+            let sqp = SquishyPicture::from_raw(9, 7, ColorFormat::Rgba8, compression_type, None, vec![0; 9 * 7 * 4]);
+
+            for actual in [9 * 7 * 4 - 1, 9 * 7 * 4 + 1] {
+                let result = SquishyPicture::from_decompressed(sqp.header, vec![0; actual], &mut Warnings::ignored());
+                assert!(matches!(result, Err(Error::SizeMismatch { expected: 252, actual: a }) if a == actual));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_lossy_short_coefficients() {
+        let mut sqp = SquishyPicture::from_raw_lossy(9, 7, ColorFormat::Gray8, 80, vec![0; 9 * 7]);
+        sqp.header.version = 0;
+
+        let result = SquishyPicture::from_decompressed(sqp.header, vec![0; 100], &mut Warnings::ignored());
+        assert!(matches!(result, Err(Error::SizeMismatch { expected: 128, actual: 100 })));
+
+        // Without a stored count, trailing bytes are taken as coefficients
+        let result = SquishyPicture::from_decompressed(sqp.header, vec![0; 150], &mut Warnings::ignored());
+        assert_eq!(result.unwrap().width(), 9);
+    }
+
+    #[test]
+    fn decode_lossy_coefficient_count() {
+        let sqp = SquishyPicture::from_raw_lossy(9, 7, ColorFormat::Gray8, 80, vec![0; 9 * 7]);
+
+        let stream = |count: u64, coefficients: usize| {
+            let mut stream = count.to_le_bytes().to_vec();
+            stream.resize(stream.len() + coefficients, 0);
+            stream
+        };
+
+        let result = SquishyPicture::from_decompressed(sqp.header, stream(128, 128), &mut Warnings::ignored());
+        assert_eq!(result.unwrap().width(), 9);
+
+        let result = SquishyPicture::from_decompressed(sqp.header, stream(128, 100), &mut Warnings::ignored());
+        assert!(matches!(result, Err(Error::CoefficientCountMismatch { expected: 128, actual: 100 })));
+
+        let result = SquishyPicture::from_decompressed(sqp.header, stream(150, 150), &mut Warnings::ignored());
+        assert!(matches!(result, Err(Error::CoefficientCountMismatch { expected: 128, actual: 150 })));
+
+        let result = SquishyPicture::from_decompressed(sqp.header, vec![0; 4], &mut Warnings::ignored());
+        assert!(matches!(result, Err(Error::CoefficientCountMismatch { expected: 128, actual: 0 })));
+    }
+
+    /// A lossy image with five bytes of extra coefficients after the real
+    /// ones, along with the image without them.
+    fn lossy_trailing_garbage() -> (Vec<u8>, Vec<u8>) {
+        let bitmap: Vec<u8> = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let sqp = SquishyPicture::from_raw_lossy(9, 7, ColorFormat::Rgba8, 80, bitmap);
+
+        // Append coefficients which would shift the image if they were read
+        let mut stream = sqp.filtered_bitmap(&EncodeOptions::default()).into_owned();
+        stream.extend_from_slice(&[0x7F, 0x01, 0xFF, 0x03, 0x20]);
+
+        let (data, compression_info) = compress(&stream, Backend::from(&sqp.header)).unwrap();
+        let mut encoded = Vec::new();
+        sqp.header.write_into(&mut encoded).unwrap();
+        write_chunk_table(&mut encoded, &sqp, &compression_info, &EncodeOptions::default()).unwrap();
+        encoded.extend_from_slice(&data);
+        let image_len = encoded.len() as u64;
+        write_footer(&mut encoded, &sqp.header, image_len).unwrap();
+
+        (encoded, sqp.encode_to_vec().unwrap())
+    }
+
+    #[test]
+    fn decode_lossy_trailing_garbage() {
+        let (encoded, valid) = lossy_trailing_garbage();
+        let expected = SquishyPicture::decode_slice(&valid).unwrap();
+
+        for decoded in [
+            SquishyPicture::decode_slice(&encoded).unwrap(),
+            SquishyPicture::decode_with_options(encoded.as_slice(), &DecodeOptions::new().low_memory(true)).unwrap(),
+        ] {
+            assert_eq!(decoded.as_raw(), expected.as_raw());
+        }
+    }
+
+    fn decode_report(encoded: &[u8], low_memory: bool) -> Vec<DecodeWarning> {
+        let options = DecodeOptions::new().low_memory(low_memory);
+        SquishyPicture::decode_with_report(encoded, &options).unwrap().1
+    }
+
+    /// Check that a file decodes with exactly the `expected` warnings, or
+    /// fails with the first of them in strict mode.
+    fn assert_warnings(encoded: &[u8], low_memory: bool, expected: &[DecodeWarning]) {
+        assert_eq!(decode_report(encoded, low_memory), expected);
+
+        let options = DecodeOptions::new().low_memory(low_memory);
+        assert!(SquishyPicture::decode_with_options(encoded, &options).is_ok());
+
+        let strict = SquishyPicture::decode_with_options(encoded, &options.strict(true));
+        match (strict, expected.first()) {
+            (Err(Error::Strict(warning)), Some(expected)) => assert_eq!(&warning, expected),
+            (Ok(_), None) => (),
+            (result, _) => panic!("unexpected strict result {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn decode_report_valid_files() {
+        for low_memory in [false, true] {
+            for fixture in [
+                include_bytes!("../test_images/test-lossless.sqp").as_slice(),
+                include_bytes!("../test_images/test-lossy.sqp").as_slice(),
+            ] {
+                assert_warnings(fixture, low_memory, &[]);
+            }
+
+            for (compression_type, quality) in [
+                (CompressionType::None, None),
+                (CompressionType::Lossless, None),
+                (CompressionType::LosslessV2, None),
+                (CompressionType::LosslessBwt, None),
+                (CompressionType::Predictive, None),
+                (CompressionType::LossyDct, Some(80)),
+            ] {
+                assert_warnings(&test_image(compression_type, quality), low_memory, &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_report_trailing_bytes() {
+        for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+            let mut encoded = test_image(compression_type, quality);
+            let offset = encoded.len() as u64;
+            encoded.extend_from_slice(&[1, 2, 3]);
+
+            for low_memory in [false, true] {
+                assert_warnings(&encoded, low_memory, &[DecodeWarning::TrailingBytes { offset, len: 3 }]);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_report_damaged_footer() {
+        for (compression_type, quality) in [(CompressionType::Lossless, None), (CompressionType::LossyDct, Some(80))] {
+            let encoded = test_image(compression_type, quality);
+            let offset = (encoded.len() - FOOTER_LEN) as u64;
+
+            // A wrong magic, and a wrong length
+            for byte in [offset as usize, encoded.len() - 1] {
+                let mut damaged = encoded.clone();
+                damaged[byte] ^= 1;
+
+                for low_memory in [false, true] {
+                    assert_warnings(&damaged, low_memory, &[DecodeWarning::DamagedFooter { offset }]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_without_footer() {
+        let bitmap: Vec<u8> = (0..9 * 7 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let mut sqp = SquishyPicture::from_raw_lossless(9, 7, ColorFormat::Rgba8, bitmap);
+        let current = sqp.encode_to_vec().unwrap();
+
+        // Files from before the footer end with the last chunk
+        sqp.header.version = FIRST_FOOTER_VERSION - 1;
+        let legacy = sqp.encode_to_vec().unwrap();
+        assert_eq!(legacy.len() + FOOTER_LEN, current.len());
+
+        for low_memory in [false, true] {
+            assert_warnings(&legacy, low_memory, &[]);
+        }
+        let decoded = SquishyPicture::decode_slice(&legacy).unwrap();
+        assert_eq!(decoded.header.version, FIRST_FOOTER_VERSION - 1);
+        assert_eq!(decoded.as_raw(), sqp.as_raw());
+    }
+
+    #[test]
+    fn decode_report_trailing_coefficients() {
+        let (encoded, _) = lossy_trailing_garbage();
+
+        for low_memory in [false, true] {
+            assert_warnings(&encoded, low_memory, &[DecodeWarning::TrailingCoefficients { len: 5 }]);
+        }
+    }
+
+    #[test]
+    fn decode_report_chunk_size_mismatch() {
+        let mut encoded = test_image(CompressionType::LossyDct, Some(80));
+
+        // Claim the only chunk holds five more bytes than it does, which
+        // end up as zeroes after the coefficients
+        let size_raw = u32::from_le_bytes(encoded[45..49].try_into().unwrap()) as usize;
+        encoded[45..49].copy_from_slice(&(size_raw as u32 + 5).to_le_bytes());
+
+        for low_memory in [false, true] {
+            assert_warnings(&encoded, low_memory, &[
+                DecodeWarning::ChunkSizeMismatch { chunk: 0, expected: size_raw + 5, actual: size_raw },
+                DecodeWarning::TrailingCoefficients { len: 5 },
+            ]);
+        }
+    }
+
+    #[test]
+    fn decode_report_corrupt_chunk() {
+        let mut encoded = test_image(CompressionType::Lossless, None);
+
+        // The first code becomes one far past the end of the dictionary
+        encoded[65..68].fill(0xFF);
+
+        let warnings = decode_report(&encoded, false);
+        assert!(matches!(warnings.as_slice(), [DecodeWarning::CorruptChunk { chunk: 0, .. }]));
+        assert_warnings(&encoded, false, &warnings);
+    }
+
+    #[test]
+    fn streamed_coefficients_across_chunks() {
+        let mut stream = Vec::new();
+        for i in 0..128 {
+            write_varint(&mut stream, i * 100 - 6400);
+        }
+        let expected: Vec<_> = CoefficientBlocks::new(&stream, false).collect();
+
+        // Small chunks, so varints are split between them
+        for chunk_size in [1, 7] {
+            let mut compressed = Vec::new();
+            let mut chunks = Vec::new();
+            for part in stream.chunks(chunk_size) {
+                let (count, data) = compress_chunk(part, Backend::LzwHuffman);
+                chunks.push(ChunkInfo { size_compressed: data.len(), size_raw: count });
+                compressed.extend_from_slice(&data);
+            }
+
+            let mut blocks = StreamedCoefficientBlocks::new(compressed.as_slice(), &chunks, Backend::LzwHuffman, false);
+            assert_eq!(blocks.by_ref().collect::<Vec<_>>(), expected);
+            assert_eq!(blocks.count, 128);
+        }
+    }
+
+    #[test]
+    fn low_memory_round_trip() {
+        let mut state = 0x2545F491u32;
+        let bitmap: Vec<u8> = (0..256 * 256 * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+
+        let encode_options = EncodeOptions::new().low_memory(true);
+        let decode_options = DecodeOptions::new().low_memory(true);
+
+        // Range coded noise takes more than one stored chunk
+        for (compression_type, quality, range_coded) in [
+            (CompressionType::None, None, false),
+            (CompressionType::Lossless, None, false),
+            (CompressionType::LosslessV2, None, false),
+            (CompressionType::LosslessBwt, None, false),
+            (CompressionType::Predictive, None, false),
+            (CompressionType::LossyDct, Some(100), false),
+            (CompressionType::LossyDct, Some(100), true),
+        ] {
+            let mut sqp = SquishyPicture::from_raw(256, 256, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+            sqp.set_range_coding(range_coded).unwrap();
+
+            let mut encoded = Vec::new();
+            sqp.encode_with_options(&mut encoded, &encode_options).unwrap();
+            assert_eq!(encoded, sqp.encode_to_vec().unwrap());
+
+            let decoded = SquishyPicture::decode_with_options(encoded.as_slice(), &decode_options).unwrap();
+            assert_eq!(decoded.as_raw(), SquishyPicture::decode(encoded.as_slice()).unwrap().as_raw());
+            if range_coded {
+                let mut input = encoded.as_slice();
+                let header = Header::read_from(&mut input).unwrap();
+                let table = read_chunk_table(&mut input, &header, &Limits::default()).unwrap();
+                assert!(table.compression_info.chunks.len() > 1);
+            }
+
+            let truncated = SquishyPicture::decode_with_options(&encoded[..encoded.len() - FOOTER_LEN - 1], &decode_options);
+            assert!(matches!(truncated, Err(Error::TruncatedFile { section: FileSection::ChunkData })));
+            let truncated = SquishyPicture::decode_with_options(&encoded[..encoded.len() - 1], &decode_options);
+            assert!(matches!(truncated, Err(Error::TruncatedFile { section: FileSection::Footer })));
+        }
+    }
+
+    #[test]
+    fn decode_truncated_at_chunk_boundary() {
+        let mut state = 0x2545F491u32;
+        let bitmap: Vec<u8> = (0..600 * 600 * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+
+        for compression_type in [CompressionType::None, CompressionType::Lossless] {
+            let sqp = SquishyPicture::from_raw(600, 600, ColorFormat::Rgba8, compression_type, None, bitmap.clone());
+            let encoded = sqp.encode_to_vec().unwrap();
+
+            // The chunk table gives every chunk's size up front, so a file
+            // missing whole chunks can't be mistaken for a complete one, and
+            // the footer must follow the last of them
+            let index = ChunkIndex::read_from(encoded.as_slice()).unwrap();
+            assert!(index.chunk_count() >= 3);
+
+            let mut boundary = 0;
+            for i in 0..index.chunk_count() {
+                boundary = index.chunk_range(i).unwrap().end as usize;
+                let section = match i + 1 == index.chunk_count() {
+                    true => FileSection::Footer,
+                    false => FileSection::ChunkData,
+                };
+
+                let truncated = &encoded[..boundary];
+                for result in [
+                    SquishyPicture::decode(truncated),
+                    SquishyPicture::decode_slice(truncated),
+                    SquishyPicture::decode_with_options(truncated, &DecodeOptions::new().low_memory(true)),
+                ] {
+                    assert!(
+                        matches!(result, Err(Error::TruncatedFile { section: s }) if s == section),
+                        "cut at {boundary}, got {:?}", result.err(),
+                    );
+                }
+            }
+            assert_eq!(boundary + FOOTER_LEN, encoded.len());
+        }
+    }
+
+    #[test]
+    fn decode_truncated_does_not_panic() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let encoded = test_image(compression_type, quality);
+            assert!(SquishyPicture::decode(encoded.as_slice()).is_ok());
+
+            for len in 0..encoded.len() {
+                let _ = SquishyPicture::decode(&encoded[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_corrupted_does_not_panic() {
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, None),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let encoded = test_image(compression_type, quality);
+
+            for i in 0..encoded.len() {
+                for value in [0x00, 0x7F, 0xFF] {
+                    let mut corrupted = encoded.clone();
+                    corrupted[i] = value;
+                    let _ = SquishyPicture::decode(corrupted.as_slice());
+                }
+            }
+        }
+    }
 }
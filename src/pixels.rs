@@ -0,0 +1,379 @@
+//! Typed iterators over the pixels of a [`SquishyPicture`].
+
+use core::iter::FusedIterator;
+
+use crate::{header::ColorFormat, picture::Error, SquishyPicture};
+
+/// A single pixel, in the shape of the [`ColorFormat`] it came from.
+///
+/// [`ColorFormat::Gray4`] pixels are 8 bit gray values, scaled up from
+/// the 16 levels the same way as everywhere else they are unpacked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pixel {
+    /// A [`ColorFormat::Rgba8`] pixel.
+    Rgba([u8; 4]),
+
+    /// A [`ColorFormat::Rgb8`] pixel.
+    Rgb([u8; 3]),
+
+    /// A [`ColorFormat::GrayA8`] pixel.
+    GrayA([u8; 2]),
+
+    /// A [`ColorFormat::Gray8`] or [`ColorFormat::Gray4`] pixel.
+    Gray(u8),
+}
+
+impl Pixel {
+    /// The channels of the pixel, in the order they are stored.
+    pub fn channels(&self) -> &[u8] {
+        match self {
+            Self::Rgba(pixel) => pixel,
+            Self::Rgb(pixel) => pixel,
+            Self::GrayA(pixel) => pixel,
+            Self::Gray(gray) => core::slice::from_ref(gray),
+        }
+    }
+}
+
+/// A fixed size pixel which [`SquishyPicture::pixels_as`] can yield.
+///
+/// This is implemented for `[u8; N]`, and can't be implemented outside of
+/// this crate.
+pub trait PixelArray: Copy + private::Sealed {
+    /// Number of bytes in the pixel.
+    const LEN: usize;
+
+    /// Create the pixel from exactly [`PixelArray::LEN`] bytes.
+    #[doc(hidden)]
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+impl<const N: usize> PixelArray for [u8; N] {
+    const LEN: usize = N;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut pixel = [0; N];
+        pixel.copy_from_slice(bytes);
+        pixel
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl<const N: usize> Sealed for [u8; N] {}
+}
+
+/// Walks the positions of a bitmap in row-major order, reading the bytes
+/// of each pixel.
+#[derive(Debug, Clone)]
+struct Positions<'a> {
+    bitmap: &'a [u8],
+    color_format: ColorFormat,
+    width: u32,
+    row_len: usize,
+
+    /// The position of the next pixel.
+    x: u32,
+    y: u32,
+
+    remaining: usize,
+}
+
+impl<'a> Positions<'a> {
+    fn new(picture: &'a SquishyPicture) -> Self {
+        let (width, height) = (picture.width(), picture.height());
+        let color_format = picture.color_format();
+
+        Self {
+            bitmap: picture.as_raw(),
+            color_format,
+            width,
+            row_len: color_format.row_len(width),
+            x: 0,
+            y: 0,
+            remaining: width as usize * height as usize,
+        }
+    }
+
+    /// The position of the next pixel, and where its bytes start in the
+    /// bitmap. For [`ColorFormat::Gray4`] this is the byte holding it.
+    fn next(&mut self) -> Option<(u32, u32, usize)> {
+        if self.remaining == 0 {
+            return None
+        }
+        self.remaining -= 1;
+
+        let (x, y) = (self.x, self.y);
+        self.x += 1;
+        if self.x == self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+
+        let offset = y as usize * self.row_len + self.color_format.bpp() as usize * x as usize / 8;
+        Some((x, y, offset))
+    }
+
+    /// The pixel at `offset` and column `x`.
+    fn pixel(&self, x: u32, offset: usize) -> Pixel {
+        let b = self.bitmap;
+        match self.color_format {
+            ColorFormat::Rgba8 => Pixel::Rgba([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]]),
+            ColorFormat::Rgb8 => Pixel::Rgb([b[offset], b[offset + 1], b[offset + 2]]),
+            ColorFormat::GrayA8 => Pixel::GrayA([b[offset], b[offset + 1]]),
+            ColorFormat::Gray8 => Pixel::Gray(b[offset]),
+            ColorFormat::Gray4 => Pixel::Gray(gray4_level(b[offset], x) * 17),
+        }
+    }
+}
+
+/// The level of the pixel in column `x` from the byte holding it. The
+/// left pixel of each pair is in the high nibble.
+fn gray4_level(byte: u8, x: u32) -> u8 {
+    if x.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F }
+}
+
+/// An iterator over the [`Pixel`]s of an image, in row-major order.
+///
+/// Created by [`SquishyPicture::pixels`].
+#[derive(Debug, Clone)]
+pub struct Pixels<'a> {
+    positions: Positions<'a>,
+}
+
+impl Iterator for Pixels<'_> {
+    type Item = Pixel;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x, _, offset) = self.positions.next()?;
+        Some(self.positions.pixel(x, offset))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.positions.remaining, Some(self.positions.remaining))
+    }
+}
+
+impl ExactSizeIterator for Pixels<'_> {}
+impl FusedIterator for Pixels<'_> {}
+
+/// An iterator over the position and [`Pixel`] of every pixel of an image,
+/// in row-major order.
+///
+/// Created by [`SquishyPicture::enumerate_pixels`].
+#[derive(Debug, Clone)]
+pub struct EnumeratePixels<'a> {
+    positions: Positions<'a>,
+}
+
+impl Iterator for EnumeratePixels<'_> {
+    type Item = (u32, u32, Pixel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x, y, offset) = self.positions.next()?;
+        Some((x, y, self.positions.pixel(x, offset)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.positions.remaining, Some(self.positions.remaining))
+    }
+}
+
+impl ExactSizeIterator for EnumeratePixels<'_> {}
+impl FusedIterator for EnumeratePixels<'_> {}
+
+/// An iterator over the pixels of an image as arrays of bytes, in
+/// row-major order.
+///
+/// Created by [`SquishyPicture::pixels_as`].
+#[derive(Debug, Clone)]
+pub struct PixelsAs<'a, P> {
+    positions: Positions<'a>,
+    pixel: core::marker::PhantomData<P>,
+}
+
+impl<P: PixelArray> Iterator for PixelsAs<'_, P> {
+    type Item = P;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x, _, offset) = self.positions.next()?;
+        let bitmap = self.positions.bitmap;
+
+        Some(match self.positions.color_format {
+            ColorFormat::Gray4 => P::from_bytes(&[gray4_level(bitmap[offset], x) * 17]),
+            _ => P::from_bytes(&bitmap[offset..offset + P::LEN]),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.positions.remaining, Some(self.positions.remaining))
+    }
+}
+
+impl<P: PixelArray> ExactSizeIterator for PixelsAs<'_, P> {}
+impl<P: PixelArray> FusedIterator for PixelsAs<'_, P> {}
+
+impl SquishyPicture {
+    /// Iterate over every pixel of the image in row-major order, as a
+    /// [`Pixel`] matching the image's [`ColorFormat`].
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{pixels::Pixel, ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::GrayA8, vec![10, 255, 20, 128]);
+    /// let pixels: Vec<Pixel> = sqp.pixels().collect();
+    ///
+    /// assert_eq!(pixels, [Pixel::GrayA([10, 255]), Pixel::GrayA([20, 128])]);
+    /// ```
+    pub fn pixels(&self) -> Pixels<'_> {
+        Pixels { positions: Positions::new(self) }
+    }
+
+    /// Like [`SquishyPicture::pixels`], but also gives the `x` and `y`
+    /// position of each pixel.
+    pub fn enumerate_pixels(&self) -> EnumeratePixels<'_> {
+        EnumeratePixels { positions: Positions::new(self) }
+    }
+
+    /// Iterate over every pixel of the image in row-major order, as an
+    /// array of [`ColorFormat::pbc`] bytes, for when the format is known
+    /// ahead of time.
+    ///
+    /// [`ColorFormat::Gray4`] pixels are single 8 bit gray values, the same
+    /// as with [`SquishyPicture::pixels`].
+    ///
+    /// Fails with [`Error::PixelSizeMismatch`] if the array is a different
+    /// size.
+    ///
+    /// # Example
+    /// ```
+    /// use sqp::{ColorFormat, SquishyPicture};
+    ///
+    /// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Rgb8, vec![1, 2, 3, 4, 5, 6]);
+    ///
+    /// let pixels: Vec<[u8; 3]> = sqp.pixels_as::<[u8; 3]>().unwrap().collect();
+    /// assert_eq!(pixels, [[1, 2, 3], [4, 5, 6]]);
+    ///
+    /// assert!(sqp.pixels_as::<[u8; 4]>().is_err());
+    /// ```
+    pub fn pixels_as<P: PixelArray>(&self) -> Result<PixelsAs<'_, P>, Error> {
+        let color_format = self.color_format();
+        if P::LEN != color_format.pbc() {
+            return Err(Error::PixelSizeMismatch {
+                color_format,
+                expected: color_format.pbc(),
+                actual: P::LEN,
+            })
+        }
+
+        Ok(PixelsAs { positions: Positions::new(self), pixel: core::marker::PhantomData })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const FORMATS: [ColorFormat; 5] = [
+        ColorFormat::Rgba8,
+        ColorFormat::Rgb8,
+        ColorFormat::GrayA8,
+        ColorFormat::Gray8,
+        ColorFormat::Gray4,
+    ];
+
+    /// An image where the first channel of each pixel is its index, within
+    /// the levels of the format.
+    fn numbered(width: u32, height: u32, color_format: ColorFormat) -> SquishyPicture {
+        SquishyPicture::from_fn(width, height, color_format, |x, y, pixel| {
+            let index = (y * width + x) as u8;
+            pixel[0] = if color_format == ColorFormat::Gray4 { (index % 16) * 17 } else { index };
+            for (i, channel) in pixel.iter_mut().enumerate().skip(1) {
+                *channel = i as u8;
+            }
+        })
+    }
+
+    #[test]
+    fn pixels_are_row_major() {
+        for color_format in FORMATS {
+            // An odd width leaves half a byte of padding on each Gray4 row
+            let (width, height) = (5, 3);
+            let sqp = numbered(width, height, color_format);
+
+            let pixels: Vec<_> = sqp.enumerate_pixels().collect();
+            assert_eq!(pixels.len(), 15);
+
+            for (i, (x, y, pixel)) in pixels.into_iter().enumerate() {
+                assert_eq!((x, y), (i as u32 % width, i as u32 / width));
+
+                let expected = if color_format == ColorFormat::Gray4 { (i % 16) as u8 * 17 } else { i as u8 };
+                assert_eq!(pixel.channels()[0], expected, "{color_format:?}");
+                assert_eq!(pixel.channels().len(), color_format.channels() as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_variants_match_format() {
+        let sqp = numbered(2, 1, ColorFormat::Rgba8);
+        assert_eq!(sqp.pixels().nth(1), Some(Pixel::Rgba([1, 1, 2, 3])));
+
+        let sqp = numbered(2, 1, ColorFormat::Rgb8);
+        assert_eq!(sqp.pixels().nth(1), Some(Pixel::Rgb([1, 1, 2])));
+
+        let sqp = numbered(2, 1, ColorFormat::GrayA8);
+        assert_eq!(sqp.pixels().nth(1), Some(Pixel::GrayA([1, 1])));
+
+        let sqp = numbered(2, 1, ColorFormat::Gray8);
+        assert_eq!(sqp.pixels().nth(1), Some(Pixel::Gray(1)));
+
+        let sqp = numbered(2, 1, ColorFormat::Gray4);
+        assert_eq!(sqp.pixels().nth(1), Some(Pixel::Gray(17)));
+    }
+
+    #[test]
+    fn counts_match_dimensions() {
+        for color_format in FORMATS {
+            for (width, height) in [(1, 1), (7, 4), (16, 9), (33, 1)] {
+                let sqp = numbered(width, height, color_format);
+                let count = width as usize * height as usize;
+
+                let mut pixels = sqp.pixels();
+                assert_eq!(pixels.len(), count);
+                pixels.next();
+                assert_eq!(pixels.len(), count - 1);
+                assert_eq!(pixels.count(), count - 1);
+
+                assert_eq!(sqp.enumerate_pixels().len(), count);
+                assert_eq!(sqp.enumerate_pixels().last().map(|(x, y, _)| (x, y)), Some((width - 1, height - 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn pixels_as_arrays() {
+        for color_format in FORMATS {
+            let sqp = numbered(5, 3, color_format);
+            let expected: Vec<_> = sqp.pixels().map(|p| p.channels().to_vec()).collect();
+
+            let arrays: Vec<Vec<u8>> = match color_format.pbc() {
+                4 => sqp.pixels_as::<[u8; 4]>().unwrap().map(|p| p.to_vec()).collect(),
+                3 => sqp.pixels_as::<[u8; 3]>().unwrap().map(|p| p.to_vec()).collect(),
+                2 => sqp.pixels_as::<[u8; 2]>().unwrap().map(|p| p.to_vec()).collect(),
+                _ => sqp.pixels_as::<[u8; 1]>().unwrap().map(|p| p.to_vec()).collect(),
+            };
+            assert_eq!(arrays, expected);
+            assert_eq!(sqp.pixels_as::<[u8; 1]>().map(|p| p.len()).ok(), (color_format.pbc() == 1).then_some(15));
+
+            assert!(matches!(
+                sqp.pixels_as::<[u8; 5]>(),
+                Err(Error::PixelSizeMismatch { expected, actual: 5, .. }) if expected == color_format.pbc(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,481 @@
+//! A decoder which is given the encoded image a piece at a time, rather
+//! than reading it.
+
+use alloc::vec::Vec;
+
+use crate::{
+    compression::lossless::{decompress_chunk_with_primer, total_size_raw, Backend},
+    container::{pixel_data_len, ChunkTable, ChunkTableReader, TablePart},
+    header::Header,
+    options::DecodeOptions,
+    picture::{Error, Warnings},
+    threads, SquishyPicture,
+};
+
+/// How far a [`SqpStreamDecoder`] got with the bytes it was fed.
+pub enum DecodeProgress {
+    /// Everything fed so far has been used, and more is needed.
+    NeedMoreData,
+
+    /// The header was completed by the last feed, and more is needed for
+    /// the rest of the image.
+    HeaderReady(Header),
+
+    /// The image was completed by the last feed.
+    Finished {
+        picture: SquishyPicture,
+
+        /// How many bytes at the end of the last feed came after the image,
+        /// and weren't used.
+        unused: usize,
+    },
+}
+
+/// Decodes an image from bytes which are pushed into it as they arrive,
+/// such as from a network socket or an event loop, rather than from
+/// something implementing [`Read`].
+///
+/// Only what can't be used yet is buffered: the header, the chunk table
+/// and the sections around it, and at most the current compressed chunk.
+/// Each chunk is decompressed as soon as all of it has arrived, on the
+/// calling thread. The result is the same as [`SquishyPicture::decode`],
+/// and the same [`DecodeOptions`] apply, apart from
+/// [`DecodeOptions::low_memory`].
+///
+/// Anything after the image is left to the caller, so trailing bytes are
+/// never a [`DecodeWarning`] here.
+///
+/// [`Read`]: crate::io::Read
+/// [`DecodeWarning`]: crate::picture::DecodeWarning
+///
+/// # Example
+/// ```
+/// use sqp::{stream_decoder::{DecodeProgress, SqpStreamDecoder}, ColorFormat, SquishyPicture};
+///
+/// let sqp = SquishyPicture::from_raw_lossless(2, 1, ColorFormat::Gray8, vec![0, 255]);
+/// let encoded = sqp.encode_to_vec().unwrap();
+///
+/// let mut decoder = SqpStreamDecoder::new();
+/// for piece in encoded.chunks(3) {
+///     match decoder.feed(piece).unwrap() {
+///         DecodeProgress::NeedMoreData => (),
+///         DecodeProgress::HeaderReady(header) => assert_eq!(header.width, 2),
+///         DecodeProgress::Finished { picture, unused } => {
+///             assert_eq!(picture.as_raw(), &[0, 255]);
+///             assert_eq!(unused, 0);
+///         },
+///     }
+/// }
+/// ```
+pub struct SqpStreamDecoder {
+    options: DecodeOptions,
+    warnings: Warnings,
+    state: State,
+
+    /// The start of the part being read, which is all the decoder holds on
+    /// to between feeds.
+    buffer: Vec<u8>,
+}
+
+/// What a [`SqpStreamDecoder`] is reading.
+enum State {
+    Header {
+        /// The end of the part of the header being read.
+        part_end: usize,
+    },
+
+    Table {
+        reader: ChunkTableReader,
+
+        /// How much of a section which isn't needed has been skipped.
+        skipped: usize,
+    },
+
+    Chunks {
+        header: Header,
+        table: ChunkTable,
+
+        /// The chunk being read, or the footer once every chunk has been.
+        index: usize,
+
+        /// The chunks decompressed so far.
+        pre_bitmap: Vec<u8>,
+    },
+
+    /// The image has been returned.
+    Finished,
+
+    /// An error has been returned.
+    Failed,
+}
+
+impl SqpStreamDecoder {
+    /// Create a decoder with the default [`DecodeOptions`].
+    pub fn new() -> Self {
+        Self::with_options(&DecodeOptions::default())
+    }
+
+    /// Create a decoder with the given [`DecodeOptions`].
+    pub fn with_options(options: &DecodeOptions) -> Self {
+        Self {
+            options: options.clone(),
+            warnings: Warnings::new(options, false),
+            state: State::Header { part_end: 0 },
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Decode as much as possible with the next bytes of the image.
+    ///
+    /// Bytes are always used up to the end of the image, so anything which
+    /// isn't is after it, and [`DecodeProgress::Finished`] says how much.
+    /// If the same feed completes both the header and the image, only
+    /// [`DecodeProgress::Finished`] is returned.
+    ///
+    /// Once the image or an error has been returned, feeding the decoder
+    /// again fails with [`Error::InvalidState`].
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Result<DecodeProgress, Error> {
+        if matches!(self.state, State::Finished | State::Failed) {
+            return Err(Error::InvalidState { failed: matches!(self.state, State::Failed) })
+        }
+
+        let result = self.advance(&mut bytes);
+        let state = core::mem::replace(&mut self.state, State::Failed);
+
+        let header_ready = match (result, state) {
+            (Err(e), _) => return Err(e),
+            (Ok(_), State::Chunks { header, table, index, pre_bitmap }) if index > table.compression_info.chunks.len() => {
+                let picture = threads::run(&self.options.threads, || {
+                    let mut picture = SquishyPicture::from_decompressed(header, pre_bitmap, &mut self.warnings)?;
+                    picture.encoder_info = table.encoder_info;
+                    self.warnings.check_content_hash(&picture, table.content_hash)?;
+                    picture.deblock(self.options.deblock)?;
+
+                    Ok::<_, Error>(picture)
+                })?;
+
+                self.state = State::Finished;
+                return Ok(DecodeProgress::Finished { picture, unused: bytes.len() })
+            },
+            (Ok(header_ready), state) => {
+                self.state = state;
+                header_ready
+            },
+        };
+
+        match header_ready {
+            Some(header) => Ok(DecodeProgress::HeaderReady(header)),
+            None => Ok(DecodeProgress::NeedMoreData),
+        }
+    }
+
+    /// Use up `bytes` until either they or the compressed chunks run out,
+    /// returning the header if it was completed.
+    fn advance(&mut self, bytes: &mut &[u8]) -> Result<Option<Header>, Error> {
+        let mut header_ready = None;
+        loop {
+            match &mut self.state {
+                State::Header { part_end } => {
+                    if !fill(&mut self.buffer, bytes, *part_end) {
+                        return Ok(header_ready)
+                    }
+
+                    // Each part of the header says whether more follows it
+                    let remaining = Header::remaining_len(&self.buffer);
+                    if remaining > 0 {
+                        *part_end += remaining;
+                        continue
+                    }
+
+                    let header = Header::parse(&self.buffer)?;
+                    self.buffer.clear();
+
+                    let reader = ChunkTableReader::new(&header, &self.options.limits)?
+                        .with_shared_dictionary(self.options.shared_dictionary.as_ref());
+                    self.state = State::Table { reader, skipped: 0 };
+                    header_ready = Some(header);
+                },
+                State::Table { reader, skipped } => match reader.next_part() {
+                    TablePart::Read(len) => {
+                        if !fill(&mut self.buffer, bytes, len) {
+                            return Ok(header_ready)
+                        }
+
+                        reader.advance(&self.buffer)?;
+                        self.buffer.clear();
+                    },
+                    TablePart::Skip(len) => {
+                        let step = (len - *skipped).min(bytes.len());
+                        *bytes = &bytes[step..];
+                        *skipped += step;
+                        if *skipped < len {
+                            return Ok(header_ready)
+                        }
+
+                        reader.advance(&[])?;
+                        *skipped = 0;
+                    },
+                    TablePart::Done => {
+                        let State::Table { reader, .. } = core::mem::replace(&mut self.state, State::Failed) else {
+                            unreachable!()
+                        };
+
+                        let header = *reader.header();
+                        let table = reader.finish();
+                        let total = total_size_raw(&table.compression_info.chunks);
+                        self.options.limits.check_alloc(total)?;
+
+                        self.state = State::Chunks { header, table, index: 0, pre_bitmap: Vec::with_capacity(total) };
+                    },
+                },
+                State::Chunks { header, table, index, pre_bitmap } => {
+                    let chunks = &table.compression_info.chunks;
+                    if *index == chunks.len() {
+                        if !fill(&mut self.buffer, bytes, header.footer_len()) {
+                            return Ok(header_ready)
+                        }
+
+                        let image_len = table.data_offset + pixel_data_len(&table.compression_info) as u64;
+                        self.warnings.check_footer(&mut self.buffer.as_slice(), header, image_len)?;
+                        self.buffer.clear();
+                        *index += 1;
+                    }
+                    let Some(chunk) = chunks.get(*index) else {
+                        return Ok(header_ready)
+                    };
+
+                    // Chunks which arrive whole are decompressed where they
+                    // are, without being copied first
+                    let compressed = if self.buffer.is_empty() && bytes.len() >= chunk.size_compressed {
+                        let (compressed, rest) = bytes.split_at(chunk.size_compressed);
+                        *bytes = rest;
+                        compressed
+                    } else if fill(&mut self.buffer, bytes, chunk.size_compressed) {
+                        &self.buffer
+                    } else {
+                        return Ok(header_ready)
+                    };
+
+                    // The dictionary has been checked against the one the
+                    // image needs
+                    let primer = table.shared_dictionary.and(self.options.shared_dictionary.as_ref()).map(|d| d.primer());
+                    let (chunk, warning) = decompress_chunk_with_primer(
+                        compressed,
+                        chunk.size_raw,
+                        *index,
+                        Backend::from(&*header),
+                        primer,
+                    );
+                    if let Some(warning) = warning {
+                        self.warnings.add(warning)?;
+                    }
+                    pre_bitmap.extend_from_slice(&chunk);
+
+                    self.buffer.clear();
+                    *index += 1;
+                },
+                State::Finished | State::Failed => unreachable!("checked by feed"),
+            }
+        }
+    }
+}
+
+impl Default for SqpStreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move bytes from the start of `input` into `buffer` until it holds `len`,
+/// returning whether it does.
+fn fill(buffer: &mut Vec<u8>, input: &mut &[u8], len: usize) -> bool {
+    let step = len.saturating_sub(buffer.len()).min(input.len());
+    buffer.extend_from_slice(&input[..step]);
+    *input = &input[step..];
+
+    buffer.len() == len
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{picture::DecodeWarning, ColorFormat, CompressionType};
+
+    /// Images which together cover every compression type, with and
+    /// without sections, and with more than one chunk.
+    fn encoded_images() -> Vec<Vec<u8>> {
+        let mut state = 0x2545F491u32;
+        let bitmap: Vec<u8> = (0..300 * 300 * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 7) as u8 * 30
+        }).collect();
+
+        let mut images = Vec::new();
+        for (compression_type, quality) in [
+            (CompressionType::None, None),
+            (CompressionType::Lossless, None),
+            (CompressionType::LosslessV2, None),
+            (CompressionType::LosslessBwt, None),
+            (CompressionType::Predictive, Some(1)),
+            (CompressionType::LossyDct, Some(80)),
+        ] {
+            let sqp = SquishyPicture::from_raw(300, 300, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+            images.push(sqp.encode_to_vec().unwrap());
+        }
+
+        images.push(include_bytes!("../test_images/test-lossless.sqp").to_vec());
+        images.push(include_bytes!("../test_images/test-lossy.sqp").to_vec());
+
+        images
+    }
+
+    /// The offsets at which each compressed chunk starts, and the start of
+    /// the footer.
+    fn chunk_boundaries(encoded: &[u8]) -> Vec<usize> {
+        let mut input = encoded;
+        let header = Header::read_from(&mut input).unwrap();
+        let mut reader = ChunkTableReader::new(&header, &DecodeOptions::default().limits).unwrap();
+        while let TablePart::Read(len) | TablePart::Skip(len) = reader.next_part() {
+            reader.advance(&input[..len]).unwrap();
+            input = &input[len..];
+        }
+
+        let table = reader.finish();
+        let mut boundaries = vec![table.data_offset as usize];
+        for chunk in &table.compression_info.chunks {
+            boundaries.push(boundaries.last().unwrap() + chunk.size_compressed);
+        }
+
+        boundaries
+    }
+
+    /// Feed every piece to a new decoder, checking that only the last
+    /// finishes it and returning the image and unused length.
+    fn feed_all<'a>(pieces: impl IntoIterator<Item = &'a [u8]>) -> (SquishyPicture, usize) {
+        let mut decoder = SqpStreamDecoder::new();
+        let mut header = None;
+        let mut finished = None;
+        for piece in pieces {
+            assert!(finished.is_none(), "fed after finishing");
+            match decoder.feed(piece).unwrap() {
+                DecodeProgress::NeedMoreData => (),
+                DecodeProgress::HeaderReady(ready) => {
+                    assert!(header.replace(ready).is_none());
+                },
+                DecodeProgress::Finished { picture, unused } => finished = Some((picture, unused)),
+            }
+        }
+
+        let (picture, unused) = finished.expect("the decoder finished");
+        if let Some(header) = header {
+            assert_eq!((header.width, header.height), (picture.width(), picture.height()));
+        }
+
+        (picture, unused)
+    }
+
+    #[test]
+    fn one_byte_feeds() {
+        for encoded in encoded_images() {
+            let expected = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let (picture, unused) = feed_all(encoded.chunks(1));
+
+            assert_eq!(picture.as_raw(), expected.as_raw());
+            assert_eq!(picture.encoder_info(), expected.encoder_info());
+            assert_eq!(unused, 0);
+        }
+    }
+
+    #[test]
+    fn chunk_aligned_feeds() {
+        let mut multiple_chunks = false;
+        for encoded in encoded_images() {
+            let expected = SquishyPicture::decode(encoded.as_slice()).unwrap();
+            let boundaries = chunk_boundaries(&encoded);
+            multiple_chunks |= boundaries.len() > 2;
+
+            let mut pieces = vec![&encoded[..boundaries[0]]];
+            pieces.extend(boundaries.windows(2).map(|w| &encoded[w[0]..w[1]]));
+            let footer = &encoded[*boundaries.last().unwrap()..];
+            if !footer.is_empty() {
+                pieces.push(footer);
+            }
+            let (picture, unused) = feed_all(pieces);
+
+            assert_eq!(picture.as_raw(), expected.as_raw());
+            assert_eq!(unused, 0);
+        }
+
+        assert!(multiple_chunks);
+    }
+
+    #[test]
+    fn over_long_final_feed() {
+        for encoded in encoded_images() {
+            let expected = SquishyPicture::decode(encoded.as_slice()).unwrap();
+
+            let mut extended = encoded.clone();
+            extended.extend_from_slice(b"trailing");
+
+            // All at once, and with the end of the image split across feeds
+            let (picture, unused) = feed_all([extended.as_slice()]);
+            assert_eq!(picture.as_raw(), expected.as_raw());
+            assert_eq!(unused, 8);
+
+            let split = encoded.len() - 3;
+            let (picture, unused) = feed_all([&extended[..split], &extended[split..]]);
+            assert_eq!(picture.as_raw(), expected.as_raw());
+            assert_eq!(unused, 8);
+        }
+    }
+
+    #[test]
+    fn header_ready_before_chunks() {
+        let encoded = &encoded_images()[1];
+        let header = Header::read_from(&mut encoded.as_slice()).unwrap();
+
+        let mut decoder = SqpStreamDecoder::new();
+        assert!(matches!(decoder.feed(&encoded[..header.len() - 1]), Ok(DecodeProgress::NeedMoreData)));
+        let Ok(DecodeProgress::HeaderReady(ready)) = decoder.feed(&encoded[header.len() - 1..header.len() + 2]) else {
+            panic!("the header wasn't ready");
+        };
+        assert_eq!((ready.width, ready.height, ready.compression_type), (300, 300, CompressionType::Lossless));
+    }
+
+    #[test]
+    fn errors_match_blocking_decoder() {
+        let mut encoded = encoded_images().swap_remove(1);
+        encoded[0] = b'x';
+        assert!(matches!(SqpStreamDecoder::new().feed(&encoded), Err(Error::InvalidIdentifier(_))));
+
+        let encoded = include_bytes!("../test_images/test-lossless.sqp");
+        let limits = crate::Limits { max_image_width: 1000, ..Default::default() };
+        let mut decoder = SqpStreamDecoder::with_options(&DecodeOptions::new().limits(limits));
+        assert!(matches!(decoder.feed(encoded), Err(Error::LimitExceeded { .. })));
+
+        let mut encoded = encoded_images().swap_remove(1);
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(matches!(SqpStreamDecoder::new().feed(&encoded), Ok(DecodeProgress::Finished { unused: 0, .. })));
+        let mut decoder = SqpStreamDecoder::with_options(&DecodeOptions::new().strict(true));
+        assert!(matches!(decoder.feed(&encoded), Err(Error::Strict(DecodeWarning::DamagedFooter { .. }))));
+    }
+
+    #[test]
+    fn feed_after_end() {
+        let encoded = include_bytes!("../test_images/test-lossless.sqp");
+        let mut decoder = SqpStreamDecoder::new();
+        assert!(matches!(decoder.feed(encoded), Ok(DecodeProgress::Finished { .. })));
+        assert!(matches!(decoder.feed(&[]), Err(Error::InvalidState { failed: false })));
+        assert!(matches!(decoder.feed(encoded), Err(Error::InvalidState { failed: false })));
+
+        let mut corrupted = encoded.to_vec();
+        corrupted[0] = b'x';
+        let mut decoder = SqpStreamDecoder::new();
+        assert!(matches!(decoder.feed(&corrupted), Err(Error::InvalidIdentifier(_))));
+        assert!(matches!(decoder.feed(encoded), Err(Error::InvalidState { failed: true })));
+    }
+}
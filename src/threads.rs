@@ -0,0 +1,142 @@
+//! Directing the parallel parts of encoding and decoding to the threads
+//! chosen with [`Threads`].
+//!
+//! Each entry point which takes options calls [`run`], which records the
+//! chosen pool for the calling thread until it returns. The parallel
+//! sections themselves go through [`install`], so nothing in between has
+//! to carry the pool along. Work which is already running on a pool's
+//! thread stays on that pool.
+
+use crate::options::Threads;
+
+#[cfg(feature = "parallel")]
+use std::{cell::RefCell, sync::{Arc, OnceLock}};
+
+#[cfg(feature = "parallel")]
+use rayon::ThreadPool;
+
+#[cfg(feature = "parallel")]
+std::thread_local! {
+    /// The pool chosen by the innermost call to [`run`] on this thread.
+    static CURRENT: RefCell<Option<Arc<ThreadPool>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with the parallel work it does going to `threads`.
+#[cfg(feature = "parallel")]
+pub(crate) fn run<R>(threads: &Threads, f: impl FnOnce() -> R) -> R {
+    /// Puts back the pool from before, even if `f` panics.
+    struct Restore(Option<Arc<ThreadPool>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.set(self.0.take());
+        }
+    }
+
+    let pool = match threads {
+        Threads::Global => None,
+        Threads::Single => single_pool(),
+        Threads::Pool(pool) => Some(pool.clone()),
+    };
+    let _restore = Restore(CURRENT.replace(pool));
+
+    f()
+}
+
+/// Run `f` with the parallel work it does going to `threads`.
+#[cfg(not(feature = "parallel"))]
+pub(crate) fn run<R>(_threads: &Threads, f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Run `f`, which does parallel work, on the pool chosen for this thread.
+#[cfg(feature = "parallel")]
+pub(crate) fn install<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    match current() {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// Run `f`, which does parallel work, on the pool chosen for this thread.
+#[cfg(not(feature = "parallel"))]
+pub(crate) fn install<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Like [`rayon::in_place_scope`], with tasks spawned on the pool chosen for
+/// this thread.
+#[cfg(feature = "parallel")]
+pub(crate) fn in_place_scope<'s, R>(f: impl FnOnce(&rayon::Scope<'s>) -> R) -> R {
+    match current() {
+        Some(pool) => pool.in_place_scope(f),
+        None => rayon::in_place_scope(f),
+    }
+}
+
+/// The number of threads in the pool chosen for this thread.
+#[cfg(feature = "parallel")]
+pub(crate) fn current_num_threads() -> usize {
+    match current() {
+        Some(pool) => pool.current_num_threads(),
+        None => rayon::current_num_threads(),
+    }
+}
+
+#[cfg(feature = "parallel")]
+fn current() -> Option<Arc<ThreadPool>> {
+    CURRENT.with_borrow(Clone::clone)
+}
+
+/// A pool of one thread shared by everything using [`Threads::Single`], or
+/// [`None`] to fall back on the global pool if it couldn't be created.
+#[cfg(feature = "parallel")]
+fn single_pool() -> Option<Arc<ThreadPool>> {
+    static SINGLE: OnceLock<Option<Arc<ThreadPool>>> = OnceLock::new();
+
+    SINGLE.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name(|_| "sqp-single".into())
+            .build()
+            .ok()
+            .map(Arc::new)
+    }).clone()
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use alloc::string::String;
+    use std::thread;
+
+    use super::*;
+
+    fn install_thread_name() -> Option<String> {
+        install(|| thread::current().name().map(String::from))
+    }
+
+    #[test]
+    fn work_lands_on_chosen_pool() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| std::format!("sqp-test-{i}"))
+            .build()
+            .unwrap();
+        let pool = Threads::Pool(Arc::new(pool));
+
+        let name = run(&pool, install_thread_name).unwrap();
+        assert!(name.starts_with("sqp-test-"), "ran on {name}");
+        assert_eq!(run(&pool, || in_place_scope(|_| current_num_threads())), 2);
+
+        assert_eq!(run(&Threads::Single, install_thread_name).as_deref(), Some("sqp-single"));
+
+        // The choice only lasts as long as the call, and calls can nest
+        let outer = run(&pool, || {
+            let inner = run(&Threads::Single, install_thread_name);
+            (inner, install_thread_name())
+        });
+        assert_eq!(outer.0.as_deref(), Some("sqp-single"));
+        assert!(outer.1.unwrap().starts_with("sqp-test-"));
+        assert!(current().is_none());
+    }
+}
@@ -0,0 +1,45 @@
+//! Checks that encoding performs no file I/O of its own.
+//!
+//! This changes the working directory of the process, so it lives in its
+//! own test binary.
+
+use std::{env, fs};
+
+use sqp::{options::EncodeOptions, ColorFormat, CompressionType, SquishyPicture};
+
+#[test]
+fn encode_writes_no_files() {
+    let dir = env::temp_dir().join(format!("sqp-encode-io-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut permissions = fs::metadata(&dir).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&dir, permissions.clone()).unwrap();
+
+    let previous = env::current_dir().unwrap();
+    env::set_current_dir(&dir).unwrap();
+
+    let bitmap: Vec<u8> = (0..64 * 64 * 4).map(|i| (i * 37 % 251) as u8).collect();
+    for (compression_type, quality) in [
+        (CompressionType::None, None),
+        (CompressionType::Lossless, None),
+        (CompressionType::LossyDct, Some(80)),
+    ] {
+        let sqp = SquishyPicture::from_raw(64, 64, ColorFormat::Rgba8, compression_type, quality, bitmap.clone());
+
+        sqp.encode_to_vec().unwrap();
+        sqp.encode_streaming(Vec::new()).unwrap();
+        sqp.encode_with_options(Vec::new(), &EncodeOptions::new().low_memory(true)).unwrap();
+    }
+
+    // Permissions don't stop every user from writing, so check as well
+    let written: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+
+    env::set_current_dir(previous).unwrap();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(&dir, permissions).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(written.is_empty(), "encoding wrote {written:?}");
+}
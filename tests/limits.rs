@@ -0,0 +1,147 @@
+//! Checks that crafted headers and chunk tables can't make decoding
+//! allocate huge amounts of memory.
+//!
+//! This installs a global allocator to measure peak heap usage, so it lives
+//! in its own test binary.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sqp::{picture::Error, ColorFormat, Limits, SquishyPicture};
+
+/// Wraps the system allocator to track the current and peak heap size.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(current, Ordering::Relaxed);
+
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A file with the given header fields, followed by a chunk table where
+/// every chunk claims the same raw size and no chunk data.
+fn crafted(width: u32, height: u32, compression: u8, color: u8, chunk_count: u32, size_raw: u32) -> Vec<u8> {
+    let quality = if compression == 2 { 80 } else { 0 };
+
+    let mut file = b"dangoimg".to_vec();
+    file.extend_from_slice(&width.to_le_bytes());
+    file.extend_from_slice(&height.to_le_bytes());
+    file.extend_from_slice(&[compression | 0x80, quality, color, 1]);
+
+    file.extend_from_slice(&chunk_count.to_le_bytes());
+    for _ in 0..chunk_count.min(64) {
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&size_raw.to_le_bytes());
+    }
+
+    file
+}
+
+fn limit_name(result: Result<SquishyPicture, Error>) -> &'static str {
+    match result {
+        Err(Error::LimitExceeded { name, .. }) => name,
+        Err(e) => panic!("expected a limit to be exceeded, got {e}"),
+        Ok(_) => panic!("expected a limit to be exceeded, decoding succeeded"),
+    }
+}
+
+#[test]
+fn crafted_files_stay_within_limits() {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+
+    // Huge images with chunk tables which claim to hold all of their data
+    let cases = [
+        (crafted(65535, 65535, 1, 3, 4, u32::MAX), "image width"),
+        (crafted(100, 0xFFFF_FFFF, 0, 3, 4, u32::MAX), "image height"),
+        (crafted(16384, 16384, 2, 0, 2, 1_500_000_000), "allocation size"),
+        (crafted(16, 16, 1, 0, u32::MAX, 1), "chunk count"),
+    ];
+    for (file, expected) in &cases {
+        assert_eq!(limit_name(SquishyPicture::decode(file.as_slice())), *expected);
+        assert_eq!(limit_name(SquishyPicture::decode_slice(file)), *expected);
+    }
+
+    // Lower limits reject images which would otherwise decode
+    let sqp = SquishyPicture::from_raw_lossless(64, 48, ColorFormat::Rgb8, vec![7; 64 * 48 * 3]);
+    let encoded = sqp.encode_to_vec().unwrap();
+    let check = |change: fn(&mut Limits), expected| {
+        let mut limits = Limits::default();
+        change(&mut limits);
+        assert_eq!(limit_name(SquishyPicture::decode_with_limits(encoded.as_slice(), limits)), expected);
+    };
+    check(|l| l.max_image_width = 63, "image width");
+    check(|l| l.max_image_height = 47, "image height");
+    check(|l| l.max_alloc_bytes = 64 * 48 * 3 - 1, "allocation size");
+    check(|l| l.max_chunk_count = 0, "chunk count");
+    assert!(SquishyPicture::decode_with_limits(encoded.as_slice(), Limits::unlimited()).is_ok());
+
+    // Random headers and chunk tables, which must all fail without using
+    // much memory
+    let mut state = 0x2545F491u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    for _ in 0..2000 {
+        let file = crafted(
+            next() >> (next() % 32),
+            next() >> (next() % 32),
+            (next() % 3) as u8,
+            (next() % 4) as u8,
+            next() >> (next() % 32),
+            next() >> (next() % 32),
+        );
+
+        assert!(SquishyPicture::decode(file.as_slice()).is_err());
+        assert!(SquishyPicture::decode_slice(&file).is_err());
+    }
+
+    // Chunk tables which add up to the right size but claim more chunk
+    // data than the input has, which is only known for slices and files
+    let truncated = crafted(8192, 8192, 1, 0, 4, 8192 * 8192);
+    assert!(matches!(SquishyPicture::decode_slice(&truncated), Err(Error::TruncatedFile { .. })));
+
+    #[cfg(feature = "std")]
+    {
+        let path = std::env::temp_dir().join(format!("sqp-limits-{}.sqp", std::process::id()));
+        std::fs::write(&path, &truncated).unwrap();
+        let opened = sqp::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(opened, Err(Error::TruncatedFile { .. })));
+    }
+
+    // Chunk tables which claim more uncompressed data than the image can
+    // have, with and without range coding
+    let oversized = crafted(64, 64, 2, 3, 1, 1_000_000);
+    let mut range_coded = oversized.clone();
+    range_coded[19] = 2;
+    range_coded.insert(20, 0x02);
+    for file in [oversized, range_coded] {
+        for result in [SquishyPicture::decode(file.as_slice()), SquishyPicture::decode_slice(&file)] {
+            assert!(matches!(result, Err(Error::ChunkTableMismatch { claimed: 1_000_000, .. })));
+        }
+    }
+
+    let peak = PEAK.load(Ordering::Relaxed) - base;
+    assert!(peak < 64 * 1024 * 1024, "peak heap growth was {peak} bytes");
+}
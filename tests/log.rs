@@ -0,0 +1,72 @@
+//! Checks what is logged during a round trip, with and without the `log`
+//! feature.
+//!
+//! This installs a global logger, so it lives in its own test binary.
+
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use sqp::{
+    options::{DecodeOptions, EncodeOptions, FilterStrategy},
+    ColorFormat, SquishyPicture,
+};
+
+/// Keeps every record logged, along with its level and target.
+struct Capture(Mutex<Vec<(Level, String, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let entry = (record.level(), record.target().to_string(), record.args().to_string());
+        self.0.lock().unwrap().push(entry);
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+#[test]
+fn round_trip_logging() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let bitmap: Vec<u8> = (0..40 * 30 * 4).map(|i| (i * 37 % 251) as u8).collect();
+    let options = EncodeOptions::new().filter_strategy(FilterStrategy::MinSumAbs);
+
+    let lossless = SquishyPicture::from_raw_lossless(40, 30, ColorFormat::Rgba8, bitmap.clone());
+    let mut encoded = Vec::new();
+    lossless.encode_with_options(&mut encoded, &options).unwrap();
+
+    // Trailing bytes are something decoding carries on past
+    encoded.extend_from_slice(b"extra");
+    let (decoded, warnings) = SquishyPicture::decode_with_report(encoded.as_slice(), &DecodeOptions::new()).unwrap();
+    assert_eq!(decoded.as_raw(), &bitmap);
+    assert_eq!(warnings.len(), 1);
+
+    let lossy = SquishyPicture::from_raw_lossy(40, 30, ColorFormat::Rgba8, 80, bitmap);
+    SquishyPicture::decode(lossy.encode_to_vec().unwrap().as_slice()).unwrap();
+
+    let records = CAPTURE.0.lock().unwrap();
+    if !cfg!(feature = "log") {
+        assert!(records.is_empty(), "logged without the feature: {records:?}");
+        return
+    }
+
+    assert!(records.iter().all(|(level, target, _)| *level >= Level::Debug && target == "sqp"));
+
+    let logged = |level: Level, start: &str| {
+        records.iter().any(|(l, _, message)| *l == level && message.starts_with(start))
+    };
+    assert!(logged(Level::Debug, "read a version"));
+    assert!(logged(Level::Debug, "chunk table lists"));
+    assert!(logged(Level::Debug, "compressed"));
+    assert!(logged(Level::Trace, "chunk 0 compressed"));
+    assert!(logged(Level::Debug, "chose row filters with MinSumAbs"));
+    assert!(logged(Level::Trace, "transforming channel 3 of 4"));
+    assert!(logged(Level::Trace, "decoding channel 3 of 4"));
+    assert!(logged(Level::Debug, "carrying on past a problem"));
+}
@@ -0,0 +1,36 @@
+//! A `#![no_std]` consumer of sqp, used to check that decoding works with
+//! only `alloc`. Build it for a bare metal target to be sure nothing pulls
+//! in `std`:
+//!
+//! ```sh
+//! cargo build -p sqp-no-std-test --target thumbv7em-none-eabihf
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use sqp::{picture::Error, SquishyPicture};
+
+/// Decode an image which is already in memory.
+pub fn decode(data: &[u8]) -> Result<SquishyPicture, Error> {
+    SquishyPicture::decode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_fixture() {
+        let image = decode(include_bytes!("../../../test_images/test-lossless.sqp")).unwrap();
+
+        assert_eq!((image.width(), image.height()), (1123, 639));
+        assert_eq!(image.as_raw().len(), 1123 * 639 * 4);
+    }
+
+    #[test]
+    fn decode_garbage() {
+        assert!(decode(b"not an image").is_err());
+    }
+}